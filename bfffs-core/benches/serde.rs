@@ -56,6 +56,12 @@ impl DML for FakeDDML {
         unimplemented!()
     }
 
+    fn put_ditto<T: Cacheable>(&self, _: T, _: Compression, _: TxgT)
+        -> Pin<Box<dyn Future<Output=Result<(Self::Addr, Self::Addr)>> + Send>>
+    {
+        unimplemented!()
+    }
+
     fn repay(&self, _: Credit) {
         unimplemented!()
     }
@@ -104,6 +110,12 @@ impl DML for FakeIDML {
         unimplemented!()
     }
 
+    fn put_ditto<T: Cacheable>(&self, _: T, _: Compression, _: TxgT)
+        -> Pin<Box<dyn Future<Output=Result<(Self::Addr, Self::Addr)>> + Send>>
+    {
+        unimplemented!()
+    }
+
     fn repay(&self, _: Credit) {
         unimplemented!()
     }
@@ -156,7 +168,8 @@ fn fs_leaf(c: &mut Criterion) {
 
     g.bench_function("deserialize", |b| b.iter(|| {
         let dbs = DivBufShared::from(&db[..]);
-        let _: Arc<Node<RID, FSKey, FSValue>> = Cacheable::deserialize(dbs);
+        let _: Arc<Node<RID, FSKey, FSValue>> =
+            Cacheable::deserialize(dbs).unwrap();
     }));
 }
 
@@ -188,7 +201,8 @@ fn alloct_leaf(c: &mut Criterion) {
     let db = node.serialize();
     g.bench_function("deserialize", |b| b.iter(|| {
         let dbs = DivBufShared::from(&db[..]);
-        let _: Arc<Node<DRP, PBA, RID>> = Cacheable::deserialize(dbs);
+        let _: Arc<Node<DRP, PBA, RID>> =
+            Cacheable::deserialize(dbs).unwrap();
     }));
     g.finish();
 }
@@ -224,7 +238,8 @@ fn ridt_leaf(c: &mut Criterion) {
     let db = node.serialize();
     g.bench_function("deserialize", |b| b.iter(|| {
         let dbs = DivBufShared::from(&db[..]);
-        let _: Arc<Node<DRP, RID, RidtEntry>> = Cacheable::deserialize(dbs);
+        let _: Arc<Node<DRP, RID, RidtEntry>> =
+            Cacheable::deserialize(dbs).unwrap();
     }));
 }
 
@@ -0,0 +1,120 @@
+//! Benchmarks for symlink-heavy workloads, like ports trees and node_modules
+//! directories, which are dominated by lookup and readlink.
+use std::{
+    ffi::OsString,
+    sync::{Arc, Mutex}
+};
+use bfffs_core::{
+    cache::Cache,
+    cluster::Cluster,
+    database::{Database, TreeID},
+    ddml::DDML,
+    fs::{FileDataMut, Fs},
+    idml::IDML,
+    mirror::Mirror,
+    pool::Pool,
+    raid
+};
+use criterion::{
+    BatchSize,
+    Criterion,
+    Throughput,
+    criterion_group,
+    criterion_main
+};
+use tempfile::{Builder, TempDir};
+use tokio::runtime::Runtime;
+
+/// Number of symlinks in the benchmark's directory
+const NLINKS: u64 = 1000;
+
+struct Harness {
+    _tempdir: TempDir,
+    db: Arc<Database>,
+    fs: Fs,
+    tree_id: TreeID,
+    /// Directory full of symlinks
+    dir: FileDataMut,
+    names: Vec<OsString>,
+}
+
+async fn harness() -> Harness {
+    let len = 1 << 30; // 1 GB
+    let tempdir = Builder::new()
+        .prefix("bfffs_symlink_bench")
+        .tempdir()
+        .unwrap();
+    let path = tempdir.path().join("vdev");
+    std::fs::File::create(&path).unwrap().set_len(len).unwrap();
+    let mirror = Mirror::create(&[&path], None).unwrap();
+    let raid = raid::create(None, 1, 0, vec![mirror]);
+    let pool = Pool::create(String::from("bench"), vec![Cluster::create(raid)]);
+    let cache = Arc::new(Mutex::new(Cache::with_capacity(64_000_000)));
+    let ddml = Arc::new(DDML::new(pool, cache.clone()));
+    let idml = IDML::create(ddml, cache);
+    let db = Arc::new(Database::create(Arc::new(idml)));
+    let tree_id = db.create_fs(None, "").await.unwrap();
+    let fs = Fs::new(db.clone(), tree_id).await;
+
+    let root = fs.root();
+    let dir = fs.mkdir(&root.handle(), &OsString::from(".bin"), 0o755, 0, 0)
+        .await
+        .unwrap();
+    let mut names = Vec::with_capacity(NLINKS as usize);
+    for i in 0..NLINKS {
+        let name = OsString::from(format!("cmd{i}"));
+        let target = OsString::from(format!("../pkg{i}/bin/cmd{i}.js"));
+        let fd = fs.symlink(&dir.handle(), &name, 0o755, 0, 0, &target)
+            .await
+            .unwrap();
+        fs.inactive(fd).await;
+        names.push(name);
+    }
+    fs.sync().await;
+
+    Harness{_tempdir: tempdir, db, fs, tree_id, dir, names}
+}
+
+fn readlink(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let h = rt.block_on(harness());
+    let fd = rt.block_on(h.fs.lookup(None, &h.dir.handle(), &h.names[0]))
+        .unwrap();
+
+    let mut g = c.benchmark_group("readlink");
+    g.bench_function("cached", |b| b.iter(|| {
+        rt.block_on(h.fs.readlink(&fd.handle())).unwrap();
+    }));
+    // Mount a fresh Fs for each iteration so its symlink cache is cold
+    g.bench_function("uncached", |b| b.iter_batched(
+        || rt.block_on(Fs::new(h.db.clone(), h.tree_id)),
+        |fs| rt.block_on(fs.readlink(&fd.handle())).unwrap(),
+        BatchSize::PerIteration
+    ));
+}
+
+/// Resolve every symlink in a directory, as a shell searching $PATH would
+fn resolve_dir(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let h = rt.block_on(harness());
+
+    let mut g = c.benchmark_group("resolve_dir");
+    g.throughput(Throughput::Elements(NLINKS));
+    g.bench_function("lookup+readlink", |b| b.iter(|| {
+        rt.block_on(async {
+            for name in h.names.iter() {
+                let fd = h.fs.lookup(None, &h.dir.handle(), name)
+                    .await
+                    .unwrap();
+                h.fs.readlink(&fd.handle()).await.unwrap();
+            }
+        })
+    }));
+}
+
+criterion_group!(
+    benches,
+    readlink,
+    resolve_dir,
+);
+criterion_main!(benches);
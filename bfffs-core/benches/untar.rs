@@ -0,0 +1,119 @@
+//! Benchmarks for extracting a large archive, which creates and writes many
+//! files spread across many directories.
+use std::{
+    ffi::OsString,
+    sync::{Arc, Mutex}
+};
+use bfffs_core::{
+    cache::Cache,
+    cluster::Cluster,
+    database::Database,
+    ddml::DDML,
+    fs::Fs,
+    idml::IDML,
+    mirror::Mirror,
+    pool::Pool,
+    raid
+};
+use criterion::{
+    BatchSize,
+    Criterion,
+    Throughput,
+    criterion_group,
+    criterion_main
+};
+use futures::{StreamExt, stream::FuturesUnordered};
+use tempfile::{Builder, TempDir};
+use tokio::runtime::{Builder as RtBuilder, Runtime};
+
+/// Number of directories in the archive
+const NDIRS: u64 = 16;
+/// Number of files in each directory
+const NFILES: u64 = 64;
+/// Size of each file, in bytes
+const FSIZE: usize = 8192;
+
+struct Harness {
+    _tempdir: TempDir,
+    fs: Arc<Fs>,
+}
+
+async fn harness() -> Harness {
+    let len = 1 << 30; // 1 GB
+    let tempdir = Builder::new()
+        .prefix("bfffs_untar_bench")
+        .tempdir()
+        .unwrap();
+    let path = tempdir.path().join("vdev");
+    std::fs::File::create(&path).unwrap().set_len(len).unwrap();
+    let mirror = Mirror::create(&[&path], None).unwrap();
+    let raid = raid::create(None, 1, 0, vec![mirror]);
+    let pool = Pool::create(String::from("bench"), vec![Cluster::create(raid)]);
+    let cache = Arc::new(Mutex::new(Cache::with_capacity(64_000_000)));
+    let ddml = Arc::new(DDML::new(pool, cache.clone()));
+    let idml = IDML::create(ddml, cache);
+    let db = Arc::new(Database::create(Arc::new(idml)));
+    let tree_id = db.create_fs(None, "").await.unwrap();
+    let fs = Arc::new(Fs::new(db, tree_id).await);
+    Harness{_tempdir: tempdir, fs}
+}
+
+/// Extract one directory of the archive: create it, then create and write
+/// each of its files in turn, as tar would.
+async fn extract_dir(fs: Arc<Fs>, d: u64) {
+    let buf = vec![0x42u8; FSIZE];
+    let root = fs.root();
+    let dname = OsString::from(format!("dir{d}"));
+    let dir = fs.mkdir(&root.handle(), &dname, 0o755, 0, 0).await.unwrap();
+    for f in 0..NFILES {
+        let fname = OsString::from(format!("file{f}"));
+        let fd = fs.create(&dir.handle(), &fname, 0o644, 0, 0).await.unwrap();
+        fs.write(&fd.handle(), 0, &buf[..], 0).await.unwrap();
+        fs.inactive(fd).await;
+    }
+    fs.inactive(dir).await;
+}
+
+fn runtime() -> Runtime {
+    RtBuilder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+fn untar(c: &mut Criterion) {
+    let rt = runtime();
+
+    let mut g = c.benchmark_group("untar");
+    g.throughput(Throughput::Elements(NDIRS * NFILES));
+    g.sample_size(10);
+    // One directory at a time, like a single-threaded tar
+    g.bench_function("sequential", |b| b.iter_batched(
+        || rt.block_on(harness()),
+        |h| rt.block_on(async {
+            for d in 0..NDIRS {
+                extract_dir(h.fs.clone(), d).await;
+            }
+            h
+        }),
+        BatchSize::PerIteration
+    ));
+    // Every directory at once, each in its own task, like a parallel tar
+    g.bench_function("concurrent", |b| b.iter_batched(
+        || rt.block_on(harness()),
+        |h| rt.block_on(async {
+            (0..NDIRS).map(|d| tokio::spawn(extract_dir(h.fs.clone(), d)))
+                .collect::<FuturesUnordered<_>>()
+                .for_each(|r| async move { r.unwrap() })
+                .await;
+            h
+        }),
+        BatchSize::PerIteration
+    ));
+}
+
+criterion_group!(
+    benches,
+    untar,
+);
+criterion_main!(benches);
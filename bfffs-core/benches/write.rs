@@ -0,0 +1,92 @@
+//! Benchmarks for large sequential writes, like those that FUSE delivers when
+//! copying big files.
+use std::{
+    ffi::OsString,
+    sync::{Arc, Mutex}
+};
+use bfffs_core::{
+    cache::Cache,
+    cluster::Cluster,
+    database::Database,
+    ddml::DDML,
+    fs::Fs,
+    idml::IDML,
+    mirror::Mirror,
+    pool::Pool,
+    raid
+};
+use criterion::{
+    BenchmarkId,
+    Criterion,
+    Throughput,
+    criterion_group,
+    criterion_main
+};
+use tempfile::{Builder, TempDir};
+use tokio::runtime::Runtime;
+
+/// Total amount of data written by each iteration
+const FILESIZE: usize = 8 << 20;
+
+struct Harness {
+    _tempdir: TempDir,
+    fs: Fs,
+}
+
+async fn harness() -> Harness {
+    let len = 1 << 30; // 1 GB
+    let tempdir = Builder::new()
+        .prefix("bfffs_write_bench")
+        .tempdir()
+        .unwrap();
+    let path = tempdir.path().join("vdev");
+    std::fs::File::create(&path).unwrap().set_len(len).unwrap();
+    let mirror = Mirror::create(&[&path], None).unwrap();
+    let raid = raid::create(None, 1, 0, vec![mirror]);
+    let pool = Pool::create(String::from("bench"), vec![Cluster::create(raid)]);
+    let cache = Arc::new(Mutex::new(Cache::with_capacity(64_000_000)));
+    let ddml = Arc::new(DDML::new(pool, cache.clone()));
+    let idml = IDML::create(ddml, cache);
+    let db = Arc::new(Database::create(Arc::new(idml)));
+    let tree_id = db.create_fs(None, "").await.unwrap();
+    let fs = Fs::new(db, tree_id).await;
+    Harness{_tempdir: tempdir, fs}
+}
+
+/// Write a new file sequentially, using a variety of I/O sizes.  Sizes smaller
+/// than the record size exercise the partial-record paths; larger ones split
+/// into whole records.
+fn sequential(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let h = rt.block_on(harness());
+    let root = h.fs.root();
+    let mut i = 0u64;
+
+    let mut g = c.benchmark_group("sequential");
+    g.throughput(Throughput::Bytes(FILESIZE as u64));
+    g.sample_size(10);
+    for iosize in [65_536usize, 131_072, 1_048_576] {
+        let buf = vec![0xa5u8; iosize];
+        g.bench_with_input(BenchmarkId::from_parameter(iosize), &buf, |b, buf|
+            b.iter(|| rt.block_on(async {
+                i += 1;
+                let name = OsString::from(format!("f{i}"));
+                let fd = h.fs.create(&root.handle(), &name, 0o644, 0, 0)
+                    .await
+                    .unwrap();
+                for ofs in (0..FILESIZE).step_by(iosize) {
+                    h.fs.write(&fd.handle(), ofs as u64, &buf[..], 0)
+                        .await
+                        .unwrap();
+                }
+                h.fs.inactive(fd).await;
+            }))
+        );
+    }
+}
+
+criterion_group!(
+    benches,
+    sequential,
+);
+criterion_main!(benches);
@@ -174,6 +174,13 @@ impl DML for FakeDDML {
         future::ok(drp).boxed()
     }
 
+    fn put_ditto<T: Cacheable>(&self, _cacheable: T,
+                               _compression: Compression, _txg: TxgT)
+        -> Pin<Box<dyn Future<Output=Result<(Self::Addr, Self::Addr)>> + Send>>
+    {
+        unimplemented!()
+    }
+
     fn repay(&self, credit: Credit) {
         debug_assert!(credit.is_null());
         mem::forget(credit);
@@ -312,6 +319,13 @@ impl DML for FakeIDML {
         fut.boxed()
     }
 
+    fn put_ditto<T: Cacheable>(&self, _cacheable: T,
+                               _compression: Compression, _txg: TxgT)
+        -> Pin<Box<dyn Future<Output=Result<(Self::Addr, Self::Addr)>> + Send>>
+    {
+        unimplemented!()
+    }
+
     fn repay(&self, credit: Credit) {
         self.writeback.repay(credit);
     }
@@ -6,7 +6,8 @@ use std::{
     hash::BuildHasherDefault
 };
 use tracing::{Level, event};
-use super::{Cacheable, CacheRef, Key};
+use crate::memory::{Consumer, MemoryBudget};
+use super::{Cacheable, CacheRef, EntryClass, Key};
 
 struct LruEntry {
     buf: Box<dyn Cacheable>,
@@ -24,12 +25,23 @@ impl Debug for LruEntry {
 }
 
 /// Basic LRU cache.
+///
+/// Metadata entries get a second chance: while the total size of cached
+/// metadata is within `metadata_reserve`, a metadata entry that reaches the
+/// LRU position will be moved back to the MRU position instead of expired.
 #[derive(Debug)]
 pub struct LruCache {
+    /// Shared with other subsystems.  If set, the cache will shrink below its
+    /// capacity when they need the memory.
+    budget: Option<MemoryBudget>,
     /// Capacity of the `LruCache` in bytes, not number of entries
     capacity: usize,
     /// Pointer to the least recently used entry
     lru: Option<Key>,
+    /// Number of bytes reserved for metadata entries
+    metadata_reserve: usize,
+    /// Current memory consumption of all metadata entries
+    metadata_size: usize,
     /// Pointer to the most recently used entry
     mru: Option<Key>,
     /// Current memory consumption of all cache entries, excluding overhead
@@ -44,19 +56,36 @@ impl LruCache {
     }
 
     pub fn drop_cache(&mut self) {
+        if let Some(budget) = &self.budget {
+            budget.release(Consumer::Cache, self.size);
+        }
         self.store = HashMap::with_hasher(MetroBuildHasher::default());
         self.lru = None;
         self.mru = None;
         self.size = 0;
+        self.metadata_size = 0;
     }
 
     fn expire(&mut self) {
-        let key = self.lru;
-        assert!(key.is_some(),
-            "Can't find an entry to expire. \
-            capacity={:?} size={:?} entries={:?}",
-            self.capacity, self.size, self.store.len());
-        self.remove(&key.unwrap());
+        loop {
+            let key = self.lru;
+            assert!(key.is_some(),
+                "Can't find an entry to expire. \
+                capacity={:?} size={:?} entries={:?}",
+                self.capacity, self.size, self.store.len());
+            let key = key.unwrap();
+            let buf = self.remove(&key).unwrap();
+            // Spare metadata within the reservation, as long as there is
+            // some data that can be expired instead.
+            if buf.class() == EntryClass::Metadata &&
+                self.metadata_size + buf.cache_space() <= self.metadata_reserve
+                && self.metadata_size < self.size
+            {
+                self.push_mru(key, buf);
+            } else {
+                return;
+            }
+        }
     }
 
     pub fn get<T: CacheRef>(&mut self, key: &Key) -> Option<Box<T>> {
@@ -98,9 +127,31 @@ impl LruCache {
     pub fn insert(&mut self, key: Key, buf: Box<dyn Cacheable>) {
         let cache_space = buf.cache_space();
         assert!(cache_space <= self.capacity);
-        while self.size + cache_space > self.capacity {
+        let limit = self.limit();
+        while self.size > 0 && self.size + cache_space > limit {
             self.expire();
         }
+        self.push_mru(key, buf);
+    }
+
+    /// The most that the cache may hold right now: its capacity, or its
+    /// allowance from the memory budget, whichever is less.
+    fn limit(&self) -> usize {
+        match &self.budget {
+            Some(budget) => budget.allowance(Consumer::Cache)
+                .min(self.capacity),
+            None => self.capacity
+        }
+    }
+
+    pub fn metadata_size(&self) -> usize {
+        self.metadata_size
+    }
+
+    /// Insert an entry in the MRU position, without regard to capacity.
+    fn push_mru(&mut self, key: Key, buf: Box<dyn Cacheable>) {
+        let cache_space = buf.cache_space();
+        let class = buf.class();
         let entry = LruEntry { buf, mru: None, lru: self.mru};
         if let Some(old_entry) = self.store.insert(key, entry) {
             // Inserting two different values with the same key is a bug, but
@@ -117,6 +168,12 @@ impl LruCache {
             return;
         } else {
             self.size += cache_space;
+            if class == EntryClass::Metadata {
+                self.metadata_size += cache_space;
+            }
+            if let Some(budget) = &self.budget {
+                budget.charge(Consumer::Cache, cache_space);
+            }
         }
         if self.mru.is_some() {
             if let Some(v) = self.store.get_mut(&self.mru.unwrap()) {
@@ -132,7 +189,14 @@ impl LruCache {
 
     pub fn remove(&mut self, key: &Key) -> Option<Box<dyn Cacheable>> {
         self.store.remove(key).map(|v| {
-            self.size -= v.buf.cache_space();
+            let cache_space = v.buf.cache_space();
+            self.size -= cache_space;
+            if v.buf.class() == EntryClass::Metadata {
+                self.metadata_size -= cache_space;
+            }
+            if let Some(budget) = &self.budget {
+                budget.release(Consumer::Cache, cache_space);
+            }
             if v.mru.is_some() {
                 self.store.get_mut(&v.mru.unwrap()).unwrap().lru = v.lru;
             } else {
@@ -149,13 +213,37 @@ impl LruCache {
         })
     }
 
+    pub fn set_memory_budget(&mut self, budget: MemoryBudget) {
+        if let Some(old) = self.budget.take() {
+            old.release(Consumer::Cache, self.size);
+        }
+        budget.charge(Consumer::Cache, self.size);
+        self.budget = Some(budget);
+    }
+
+    pub fn set_metadata_reserve(&mut self, fraction: f32) {
+        let fraction = fraction.clamp(0.0, 1.0);
+        self.metadata_reserve = (self.capacity as f64 * fraction as f64)
+            as usize;
+    }
+
     pub fn size(&self) -> usize {
         self.size
     }
 
+    /// Create a new `LruCache` with no space reserved for metadata.
     pub fn with_capacity(capacity: usize) -> Self {
         let store = HashMap::with_hasher(MetroBuildHasher::default());
-        LruCache{capacity, lru: None, mru: None, size: 0, store}
+        LruCache{budget: None, capacity, lru: None, metadata_reserve: 0,
+                 metadata_size: 0, mru: None, size: 0, store}
+    }
+}
+
+impl Drop for LruCache {
+    fn drop(&mut self) {
+        if let Some(budget) = &self.budget {
+            budget.release(Consumer::Cache, self.size);
+        }
     }
 }
 
@@ -167,6 +255,42 @@ use super::*;
 use crate::types::*;
 use divbuf::{DivBuf, DivBufShared};
 
+/// A stand-in for Tree nodes
+#[derive(Debug)]
+struct Meta(DivBufShared);
+
+impl Cacheable for Meta {
+    fn class(&self) -> EntryClass {
+        EntryClass::Metadata
+    }
+
+    fn deserialize(dbs: DivBufShared) -> Result<Self> {
+        Ok(Meta(dbs))
+    }
+
+    fn eq(&self, other: &dyn Cacheable) -> bool {
+        other.downcast_ref::<Meta>()
+            .map(|o| Cacheable::eq(&self.0, &o.0))
+            .unwrap_or(false)
+    }
+
+    fn cache_space(&self) -> usize {
+        self.0.len()
+    }
+
+    fn make_ref(&self) -> Box<dyn CacheRef> {
+        self.0.make_ref()
+    }
+
+    fn wb_space(&self) -> usize {
+        self.0.len()
+    }
+}
+
+fn meta(len: usize) -> Box<Meta> {
+    Box::new(Meta(DivBufShared::from(vec![0u8; len])))
+}
+
 // pet kcov
 #[test]
 fn debug() {
@@ -290,6 +414,93 @@ fn test_expire_two() {
     assert!(cache.get::<DivBuf>(&key2).is_none());
 }
 
+/// Metadata within the reservation should be spared from expiration
+#[test]
+fn test_expire_metadata_reserved() {
+    let mut cache = LruCache::with_capacity(100);
+    cache.set_metadata_reserve(0.5);
+    let key1 = Key::Rid(RID(1));
+    let key2 = Key::Rid(RID(2));
+    let key3 = Key::Rid(RID(3));
+    let key4 = Key::Rid(RID(4));
+    cache.insert(key1, meta(30));
+    cache.insert(key2, Box::new(DivBufShared::from(vec![0u8; 30])));
+    cache.insert(key3, Box::new(DivBufShared::from(vec![0u8; 30])));
+    cache.insert(key4, Box::new(DivBufShared::from(vec![0u8; 30])));
+
+    assert_eq!(cache.size(), 90);
+    assert_eq!(cache.metadata_size(), 30);
+    assert!(cache.get_ref(&key1).is_some());
+    assert!(cache.get_ref(&key2).is_none());
+    assert_eq!(cache.lru, Some(key3));
+}
+
+/// Metadata beyond the reservation is expired like anything else
+#[test]
+fn test_expire_metadata_over_reserve() {
+    let mut cache = LruCache::with_capacity(100);
+    cache.set_metadata_reserve(0.2);
+    let key1 = Key::Rid(RID(1));
+    let key2 = Key::Rid(RID(2));
+    let key3 = Key::Rid(RID(3));
+    let key4 = Key::Rid(RID(4));
+    cache.insert(key1, meta(30));
+    cache.insert(key2, Box::new(DivBufShared::from(vec![0u8; 30])));
+    cache.insert(key3, Box::new(DivBufShared::from(vec![0u8; 30])));
+    cache.insert(key4, Box::new(DivBufShared::from(vec![0u8; 30])));
+
+    assert_eq!(cache.size(), 90);
+    assert_eq!(cache.metadata_size(), 0);
+    assert!(cache.get_ref(&key1).is_none());
+    assert!(cache.get_ref(&key2).is_some());
+}
+
+/// If the cache holds nothing but metadata, then metadata must be expired even
+/// if it's within the reservation.
+#[test]
+fn test_expire_metadata_only() {
+    let mut cache = LruCache::with_capacity(100);
+    cache.set_metadata_reserve(1.0);
+    let key1 = Key::Rid(RID(1));
+    let key2 = Key::Rid(RID(2));
+    let key3 = Key::Rid(RID(3));
+    cache.insert(key1, meta(60));
+    cache.insert(key2, meta(30));
+    cache.insert(key3, Box::new(DivBufShared::from(vec![0u8; 50])));
+
+    assert_eq!(cache.size(), 80);
+    assert_eq!(cache.metadata_size(), 30);
+    assert!(cache.get_ref(&key1).is_none());
+    assert!(cache.get_ref(&key2).is_some());
+}
+
+/// When other subsystems need memory from a shared budget, the cache shrinks
+/// below its capacity.
+#[test]
+fn test_expire_memory_budget() {
+    let budget = MemoryBudget::new(100);
+    let mut cache = LruCache::with_capacity(100);
+    cache.set_memory_budget(budget.clone());
+    for i in 0..10 {
+        let dbs = Box::new(DivBufShared::from(vec![0u8; 10]));
+        cache.insert(Key::Rid(RID(i)), dbs);
+    }
+    assert_eq!(cache.size(), 100);
+    assert_eq!(budget.used(Consumer::Cache), 100);
+
+    // Writeback uses its share, so the cache must give up the overage
+    budget.charge(Consumer::Writeback, 30);
+    let dbs = Box::new(DivBufShared::from(vec![0u8; 10]));
+    cache.insert(Key::Rid(RID(10)), dbs);
+    assert_eq!(cache.size(), 70);
+    assert_eq!(budget.used(Consumer::Cache), 70);
+    assert!(cache.get_ref(&Key::Rid(RID(3))).is_none());
+    assert!(cache.get_ref(&Key::Rid(RID(4))).is_some());
+
+    cache.drop_cache();
+    assert_eq!(budget.used(Consumer::Cache), 0);
+}
+
 /// Get the most recently used entry
 #[test]
 fn test_get_mru() {
@@ -3,7 +3,10 @@
 // https://github.com/fkoep/downcast-rs/issues/6
 #![allow(clippy::missing_safety_doc)]
 
-use crate::types::{PBA, RID};
+use crate::{
+    memory::MemoryBudget,
+    types::{PBA, RID, Result}
+};
 use divbuf::{DivBuf, DivBufShared};
 use downcast::*;
 use futures::channel::oneshot;
@@ -24,10 +27,29 @@ pub enum Key {
     PBA(PBA),
 }
 
+/// Broad categories of cached objects, used by the replacement policy.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EntryClass {
+    /// File data, extended attributes, and other user-supplied blobs.
+    Data,
+    /// Tree nodes, including those of the RIDT and AllocT.
+    Metadata,
+}
+
+/// Default fraction of the cache's capacity that is reserved for metadata.
+pub const DEFAULT_METADATA_RESERVE: f32 = 0.25;
+
 /// Types that implement `Cacheable` may be stored in the cache
 pub trait Cacheable: Any + Debug + Send + Sync {
-    /// Deserialize a buffer into Self.  Will panic if deserialization fails.
-    fn deserialize(dbs: DivBufShared) -> Self where Self: Sized;
+    /// Which class of entry is this?
+    fn class(&self) -> EntryClass;
+
+    /// Deserialize a buffer into Self.
+    ///
+    /// Fails with `EOPNOTSUPP` if the buffer was written in a newer format than
+    /// this version of BFFFS understands, or `EINTEGRITY` if it is otherwise
+    /// malformed.
+    fn deserialize(dbs: DivBufShared) -> Result<Self> where Self: Sized;
 
     /// Returns true if the two `Cacheable`s' contents are equal
     // This doesn't implement PartialEq because the rhs is &Cacheable instead of
@@ -55,8 +77,9 @@ downcast!(dyn Cacheable);
 /// Types that implement `CacheRef` are read-only handles to cached objects.
 pub trait CacheRef: Any + Send {
     /// Deserialize a buffer into the kind of `Cacheable` that's associated with
-    /// this `CacheRef`.  Will panic if deserialization fails.
-    fn deserialize(dbs: DivBufShared) -> Box<dyn Cacheable> where Self: Sized;
+    /// this `CacheRef`.
+    fn deserialize(dbs: DivBufShared) -> Result<Box<dyn Cacheable>>
+        where Self: Sized;
 
     /// Serialize to a `DivBuf`.
     fn serialize(&self) -> DivBuf;
@@ -69,8 +92,12 @@ pub trait CacheRef: Any + Send {
 downcast!(dyn CacheRef);
 
 impl Cacheable for DivBufShared {
-    fn deserialize(dbs: DivBufShared) -> Self where Self: Sized {
-        dbs
+    fn class(&self) -> EntryClass {
+        EntryClass::Data
+    }
+
+    fn deserialize(dbs: DivBufShared) -> Result<Self> where Self: Sized {
+        Ok(dbs)
     }
 
     fn eq(&self, other: &dyn Cacheable) -> bool {
@@ -98,8 +125,10 @@ impl Cacheable for DivBufShared {
 }
 
 impl CacheRef for DivBuf {
-    fn deserialize(dbs: DivBufShared) -> Box<dyn Cacheable> where Self: Sized {
-        Box::new(dbs)
+    fn deserialize(dbs: DivBufShared) -> Result<Box<dyn Cacheable>>
+        where Self: Sized
+    {
+        Ok(Box::new(dbs))
     }
 
     fn serialize(&self) -> DivBuf {
@@ -123,6 +152,10 @@ impl Borrow<dyn CacheRef> for DivBuf {
 /// Caches on-disk blocks by either their address (cluster and LBA pair), or
 /// their Record ID.  The cache is read-only because any attempt to change a
 /// block would also require changing either its address or record ID.
+///
+/// A fraction of the cache's capacity is reserved for metadata.  As long as
+/// cached metadata fits within that reservation, only data will be evicted,
+/// so a large streaming read can't flush the entire metadata working set.
 #[derive(Debug)]
 pub struct Cache{
     cache:self::lru::LruCache,
@@ -143,6 +176,11 @@ impl Cache {
         self.cache.drop_cache()
     }
 
+    /// Get the current memory consumption of cached metadata, in bytes.
+    pub fn metadata_size(&self) -> usize {
+        self.cache.metadata_size()
+    }
+
     /// Get a read-only reference to a cached block.
     ///
     /// The block will be marked as the most recently used.
@@ -175,6 +213,21 @@ impl Cache {
         self.cache.remove(key)
     }
 
+    /// Account for the cache's memory in a budget shared with other
+    /// subsystems.
+    ///
+    /// The cache will still never exceed its capacity, but it may shrink below
+    /// it when the others need the memory.
+    pub fn set_memory_budget(&mut self, budget: MemoryBudget) {
+        self.cache.set_memory_budget(budget)
+    }
+
+    /// Set the fraction of the cache's capacity that is reserved for
+    /// metadata, from 0.0 to 1.0.
+    pub fn set_metadata_reserve(&mut self, fraction: f32) {
+        self.cache.set_metadata_reserve(fraction)
+    }
+
     /// Get the current memory consumption of the cache, in bytes.
     ///
     /// Only the cached blocks themselves are included, not the overhead of
@@ -184,9 +237,13 @@ impl Cache {
     }
 
     /// Create a new cache with the given capacity, in bytes.
+    ///
+    /// [`DEFAULT_METADATA_RESERVE`] of the capacity will be reserved for
+    /// metadata.
     pub fn with_capacity(capacity: usize) -> Self {
         let pending_insertions = Default::default();
-        let cache = self::lru::LruCache::with_capacity(capacity);
+        let mut cache = self::lru::LruCache::with_capacity(capacity);
+        cache.set_metadata_reserve(DEFAULT_METADATA_RESERVE);
         Self{cache, pending_insertions}
     }
 }
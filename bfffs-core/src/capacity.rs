@@ -0,0 +1,388 @@
+// vim: tw=80
+//! Capacity planning
+//!
+//! Predicts how a change to a pool's vdev configuration would affect its
+//! capacity and redundancy, and how much data would have to move, without
+//! actually making the change.  The predictions for new devices are estimates:
+//! they ignore zone alignment and label overhead.
+
+use crate::{types::*, util::*};
+use serde_derive::{Deserialize, Serialize};
+
+/// Layout of a single mirror, for capacity planning
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct MirrorShape {
+    /// Usable size in LBAs.  It's the minimum of the children's sizes.
+    pub size: LbaT,
+    /// UUID and size in LBAs of every child
+    pub children: Vec<(Uuid, LbaT)>,
+}
+
+/// Layout of a single cluster, for capacity planning
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ClusterShape {
+    /// Number of data plus parity chunks in each RAID stripe
+    pub disks_per_stripe: i16,
+    /// Number of mirrors that may fail before the cluster does
+    pub redundancy: i16,
+    pub mirrors: Vec<MirrorShape>,
+    /// Usable size in LBAs
+    pub size: LbaT,
+    /// Allocated space in LBAs
+    pub used: LbaT,
+}
+
+impl ClusterShape {
+    /// Estimate the shape of a new cluster.
+    ///
+    /// Each element of `mirrors` lists the sizes, in bytes, of one mirror's
+    /// children.
+    fn estimate(disks_per_stripe: i16, redundancy: i16, mirrors: &[Vec<u64>])
+        -> Result<Self>
+    {
+        let nmirrors = mirrors.len();
+        if nmirrors == 0 || mirrors.iter().any(Vec::is_empty) ||
+            disks_per_stripe < 1 || redundancy < 0 ||
+            redundancy >= disks_per_stripe ||
+            disks_per_stripe as usize > nmirrors ||
+            (nmirrors == 1 && disks_per_stripe != 1)
+        {
+            return Err(Error::EINVAL);
+        }
+        let mirrors = mirrors.iter()
+            .map(|sizes| {
+                let children = sizes.iter()
+                    .map(|s| (Uuid::nil(), s / BYTES_PER_LBA as u64))
+                    .collect::<Vec<_>>();
+                let size = children.iter().map(|c| c.1).min().unwrap();
+                MirrorShape{size, children}
+            }).collect::<Vec<_>>();
+        let smallest = mirrors.iter().map(|m| m.size).min().unwrap();
+        let dps = disks_per_stripe as LbaT;
+        let f = redundancy as LbaT;
+        let size = smallest * nmirrors as LbaT * (dps - f) / dps;
+        Ok(ClusterShape {
+            disks_per_stripe,
+            redundancy,
+            mirrors,
+            size,
+            used: 0
+        })
+    }
+
+    fn contains(&self, uuid: Uuid) -> bool {
+        self.mirrors.iter()
+            .flat_map(|m| m.children.iter())
+            .any(|c| c.0 == uuid)
+    }
+
+    /// How many leaf devices may fail without losing the cluster?
+    fn fault_tolerance(&self) -> u32 {
+        // The cluster fails once more than `redundancy` mirrors have, and the
+        // cheapest way to get there is to kill the narrowest mirrors.
+        let mut widths = self.mirrors.iter()
+            .map(|m| m.children.len() as u32)
+            .collect::<Vec<_>>();
+        widths.sort_unstable();
+        widths.iter()
+            .take(self.redundancy as usize + 1)
+            .sum::<u32>() - 1
+    }
+
+    /// Allocated space on each mirror, including parity, in LBAs
+    fn used_per_mirror(&self) -> LbaT {
+        let dps = self.disks_per_stripe as LbaT;
+        let f = self.redundancy as LbaT;
+        let nmirrors = self.mirrors.len() as LbaT;
+        div_roundup(self.used * dps, (dps - f) * nmirrors)
+    }
+}
+
+/// A hypothetical change to a pool's vdev configuration
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Change {
+    /// Add a new cluster.  Each element of `mirrors` lists the sizes, in
+    /// bytes, of one mirror's children.
+    AddCluster {
+        disks_per_stripe: i16,
+        redundancy: i16,
+        mirrors: Vec<Vec<u64>>
+    },
+    /// Remove the cluster containing the given leaf device, moving its data
+    /// elsewhere.
+    RemoveCluster(Uuid),
+    /// Replace the given leaf device with a new one of `size` bytes
+    ReplaceDisk {
+        uuid: Uuid,
+        size: u64
+    },
+}
+
+/// The predicted effect of a [`Change`]
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Plan {
+    /// Usable bytes before the change
+    pub size_before: u64,
+    /// Usable bytes after the change
+    pub size_after: u64,
+    /// Allocated bytes.  The same before and after.
+    pub used: u64,
+    /// Number of leaf devices that could fail without losing the pool, before
+    /// the change
+    pub redundancy_before: u32,
+    /// Number of leaf devices that could fail without losing the pool, after
+    /// the change
+    pub redundancy_after: u32,
+    /// Bytes that would have to be copied to carry out the change
+    pub moved: u64,
+}
+
+/// Predict the effect of `change` on a pool made of `clusters`.
+///
+/// Fails with `ENOENT` if the change refers to a device that isn't in the
+/// pool, `EINVAL` if the change is impossible, and `ENOSPC` if the pool
+/// wouldn't have enough room left for its data.
+pub fn plan(clusters: &[ClusterShape], change: &Change) -> Result<Plan> {
+    let size = |cs: &[ClusterShape]| cs.iter().map(|c| c.size).sum::<LbaT>();
+    let redundancy = |cs: &[ClusterShape]| {
+        cs.iter().map(ClusterShape::fault_tolerance).min().unwrap_or(0)
+    };
+    let used = clusters.iter().map(|c| c.used).sum::<LbaT>();
+    let mut after = clusters.to_vec();
+    let moved = match change {
+        Change::AddCluster{disks_per_stripe, redundancy, mirrors} => {
+            after.push(ClusterShape::estimate(*disks_per_stripe, *redundancy,
+                                              mirrors)?);
+            // Existing data stays where it is
+            0
+        },
+        Change::RemoveCluster(uuid) => {
+            let idx = clusters.iter()
+                .position(|c| c.contains(*uuid))
+                .ok_or(Error::ENOENT)?;
+            if clusters.len() == 1 {
+                // Can't remove the last cluster
+                return Err(Error::EINVAL);
+            }
+            let removed = after.remove(idx);
+            if size(&after) < used {
+                return Err(Error::ENOSPC);
+            }
+            removed.used
+        },
+        Change::ReplaceDisk{uuid, size} => {
+            let cluster = clusters.iter()
+                .find(|c| c.contains(*uuid))
+                .ok_or(Error::ENOENT)?;
+            let mirror = cluster.mirrors.iter()
+                .find(|m| m.children.iter().any(|c| c.0 == *uuid))
+                .unwrap();
+            if size / (BYTES_PER_LBA as u64) < mirror.size {
+                return Err(Error::EINVAL);
+            }
+            // The replacement must be resilvered.  A larger disk won't add any
+            // capacity, because a mirror can't grow in place.
+            cluster.used_per_mirror()
+        }
+    };
+    let bytes = |lbas: LbaT| lbas * BYTES_PER_LBA as u64;
+    Ok(Plan {
+        size_before: bytes(size(clusters)),
+        size_after: bytes(size(&after)),
+        used: bytes(used),
+        redundancy_before: redundancy(clusters),
+        redundancy_after: redundancy(&after),
+        moved: bytes(moved)
+    })
+}
+
+// LCOV_EXCL_START
+#[cfg(test)]
+mod t {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    const GB: u64 = 1 << 30;
+
+    fn mirror(sizes: &[LbaT]) -> MirrorShape {
+        let children = sizes.iter()
+            .map(|s| (Uuid::new_v4(), *s))
+            .collect::<Vec<_>>();
+        let size = *sizes.iter().min().unwrap();
+        MirrorShape{size, children}
+    }
+
+    /// A pool with a two-way mirror of 1 GB disks and a 3+1 RAID cluster
+    fn pool() -> Vec<ClusterShape> {
+        let lbas = GB / BYTES_PER_LBA as u64;
+        vec![
+            ClusterShape {
+                disks_per_stripe: 1,
+                redundancy: 0,
+                mirrors: vec![mirror(&[lbas, lbas])],
+                size: lbas,
+                used: lbas / 4
+            },
+            ClusterShape {
+                disks_per_stripe: 4,
+                redundancy: 1,
+                mirrors: (0..4).map(|_| mirror(&[lbas])).collect(),
+                size: 3 * lbas,
+                used: 3 * lbas / 4
+            },
+        ]
+    }
+
+    mod add_cluster {
+        use super::*;
+
+        #[test]
+        fn einval() {
+            let change = Change::AddCluster {
+                disks_per_stripe: 3,
+                redundancy: 1,
+                mirrors: vec![vec![GB], vec![GB]]
+            };
+            assert_eq!(plan(&pool(), &change), Err(Error::EINVAL));
+        }
+
+        #[test]
+        fn mirrored() {
+            let change = Change::AddCluster {
+                disks_per_stripe: 1,
+                redundancy: 0,
+                mirrors: vec![vec![2 * GB, 2 * GB, 3 * GB]]
+            };
+            let p = plan(&pool(), &change).unwrap();
+            assert_eq!(p, Plan {
+                size_before: 4 * GB,
+                size_after: 6 * GB,
+                used: GB,
+                redundancy_before: 1,
+                redundancy_after: 1,
+                moved: 0
+            });
+        }
+
+        /// Adding a non-redundant disk reduces the pool's redundancy
+        #[test]
+        fn single_disk() {
+            let change = Change::AddCluster {
+                disks_per_stripe: 1,
+                redundancy: 0,
+                mirrors: vec![vec![GB]]
+            };
+            let p = plan(&pool(), &change).unwrap();
+            assert_eq!(p.size_after, 5 * GB);
+            assert_eq!(p.redundancy_after, 0);
+        }
+
+        #[test]
+        fn raid() {
+            let change = Change::AddCluster {
+                disks_per_stripe: 5,
+                redundancy: 2,
+                mirrors: vec![vec![GB]; 5]
+            };
+            let p = plan(&pool(), &change).unwrap();
+            assert_eq!(p.size_after, 7 * GB);
+            assert_eq!(p.redundancy_after, 1);
+        }
+    }
+
+    mod remove_cluster {
+        use super::*;
+
+        #[test]
+        fn enoent() {
+            let change = Change::RemoveCluster(Uuid::new_v4());
+            assert_eq!(plan(&pool(), &change), Err(Error::ENOENT));
+        }
+
+        #[test]
+        fn enospc() {
+            let mut clusters = pool();
+            clusters[0].used = clusters[0].size;
+            clusters[1].used = clusters[1].size / 2;
+            let change = Change::RemoveCluster(clusters[1].mirrors[0]
+                                               .children[0].0);
+            assert_eq!(plan(&clusters, &change), Err(Error::ENOSPC));
+        }
+
+        /// The last cluster can't be removed
+        #[test]
+        fn last() {
+            let clusters = &pool()[0..1];
+            let change = Change::RemoveCluster(clusters[0].mirrors[0]
+                                               .children[1].0);
+            assert_eq!(plan(clusters, &change), Err(Error::EINVAL));
+        }
+
+        #[test]
+        fn ok() {
+            let clusters = pool();
+            let change = Change::RemoveCluster(clusters[1].mirrors[2]
+                                               .children[0].0);
+            let p = plan(&clusters, &change).unwrap();
+            assert_eq!(p, Plan {
+                size_before: 4 * GB,
+                size_after: GB,
+                used: GB,
+                redundancy_before: 1,
+                redundancy_after: 1,
+                moved: 3 * GB / 4
+            });
+        }
+    }
+
+    mod replace_disk {
+        use super::*;
+
+        /// The replacement may not be smaller than the disk it replaces
+        #[test]
+        fn einval() {
+            let clusters = pool();
+            let change = Change::ReplaceDisk {
+                uuid: clusters[0].mirrors[0].children[0].0,
+                size: GB / 2
+            };
+            assert_eq!(plan(&clusters, &change), Err(Error::EINVAL));
+        }
+
+        #[test]
+        fn enoent() {
+            let change = Change::ReplaceDisk {
+                uuid: Uuid::new_v4(),
+                size: GB
+            };
+            assert_eq!(plan(&pool(), &change), Err(Error::ENOENT));
+        }
+
+        /// Replacing a mirror child must copy all of the mirror's data
+        #[test]
+        fn mirror_child() {
+            let clusters = pool();
+            let change = Change::ReplaceDisk {
+                uuid: clusters[0].mirrors[0].children[1].0,
+                size: 2 * GB
+            };
+            let p = plan(&clusters, &change).unwrap();
+            assert_eq!(p.size_after, p.size_before);
+            assert_eq!(p.moved, GB / 4);
+        }
+
+        /// Replacing a RAID child must rebuild its share of the data and parity
+        #[test]
+        fn raid_child() {
+            let clusters = pool();
+            let change = Change::ReplaceDisk {
+                uuid: clusters[1].mirrors[3].children[0].0,
+                size: GB
+            };
+            let p = plan(&clusters, &change).unwrap();
+            assert_eq!(p.size_after, p.size_before);
+            assert_eq!(p.moved, GB / 4);
+        }
+    }
+}
+// LCOV_EXCL_STOP
@@ -1,8 +1,11 @@
 // vim: tw=80
 
 use crate::{
-    idml::{ClosedZone, IDML},
+    determinism,
+    idml::{ClosedZone, IDML, Temperature},
+    job::Progress,
     types::*,
+    util::BYTES_PER_LBA,
 };
 use futures::{
     Future,
@@ -14,13 +17,199 @@ use futures::{
     channel::{oneshot, mpsc},
     stream::self,
 };
-use std::sync::Arc;
+use serde_derive::{Deserialize, Serialize};
+use std::sync::{
+    Arc,
+    atomic::{AtomicU64, Ordering}
+};
 use tokio::task::JoinHandle;
 
+/// How the cleaner places the live records that it moves.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub enum CleanPolicy {
+    /// Move live records into the same zones as newly written data.
+    #[default]
+    Mixed,
+    /// Segregate records by age.  Records from zones that were closed at least
+    /// `cold_age` transactions before the most recently closed zone will be
+    /// moved into dedicated cold zones, so that stable data won't need to be
+    /// copied again every time its neighbors are freed.  Records from younger
+    /// zones will be mixed with newly written data.
+    Generational {
+        cold_age: u32
+    }
+}
+
+impl CleanPolicy {
+    /// Choose the temperature of the zones that `zone`'s records should be
+    /// moved into.  `newest` is the end of the most recently closed zone's
+    /// transaction range.
+    ///
+    /// Metadata always stays in metadata zones, regardless of policy.  Even
+    /// old tree nodes are likely to be rewritten soon.
+    fn temperature(&self, zone: &ClosedZone, newest: TxgT) -> Temperature {
+        if zone.temp == Temperature::Metadata {
+            return Temperature::Metadata;
+        }
+        match self {
+            CleanPolicy::Mixed => Temperature::Hot,
+            CleanPolicy::Generational{cold_age} => {
+                let age = u32::from(newest)
+                    .saturating_sub(u32::from(zone.txgs.end));
+                if age >= *cold_age {
+                    Temperature::Cold
+                } else {
+                    Temperature::Hot
+                }
+            }
+        }
+    }
+}
+
+/// How much one zone's cleaning would move and reclaim
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ZoneCleanStats {
+    pub cluster: ClusterT,
+    pub zid: ZoneT,
+    /// Bytes of live data to be moved out of the zone
+    pub moved: u64,
+    /// Bytes of freed space that cleaning the zone would reclaim
+    pub reclaimable: u64,
+    /// Bytes of freed space that can't be reclaimed until the pool's
+    /// checkpoint is discarded, because the checkpoint may still refer to it.
+    pub pinned: u64,
+}
+
+/// Summary of the work done by one round of cleaning
+///
+/// A record only counts as freed once the last reference to it, as recorded
+/// by the RIDT, is gone.  Records that are still shared are live, and will be
+/// moved rather than freed.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct CleanStats {
+    /// Number of zones to be cleaned
+    pub zones: u64,
+    /// Bytes of live data to be moved out of those zones
+    pub moved: u64,
+    /// Bytes of live data to be moved into cold zones.  A subset of `moved`.
+    pub cold: u64,
+    /// Bytes of live metadata to be moved into metadata zones.  A subset of
+    /// `moved`.
+    pub metadata: u64,
+    /// Bytes of freed space in those zones.  The sum of `reclaimable` and
+    /// `pinned`.
+    pub freed: u64,
+    /// Bytes of freed space that cleaning would actually reclaim
+    pub reclaimable: u64,
+    /// Bytes of freed space that the pool's checkpoint keeps from being
+    /// reclaimed
+    pub pinned: u64,
+    /// Breakdown of the above by zone, in the order that they'd be cleaned
+    pub by_zone: Vec<ZoneCleanStats>,
+    /// Total bytes written to the pool since it was imported, including those
+    /// written by the cleaner.
+    pub written: u64,
+    /// Bytes rewritten by the cleaner since the pool was imported
+    pub rewritten: u64,
+}
+
+impl CleanStats {
+    /// Summarize the cleaning of `zones`.  If `pinned`, then the pool has a
+    /// checkpoint, and none of their freed space can be reclaimed yet.
+    fn new(zones: &[(ClosedZone, Temperature)], pinned: bool) -> Self {
+        zones.iter().fold(CleanStats::default(), |mut stats, (z, temp)| {
+            let live = (z.total_blocks - z.freed_blocks) * BYTES_PER_LBA as u64;
+            let freed = z.freed_blocks * BYTES_PER_LBA as u64;
+            let (reclaimable, pinned) = if pinned {
+                (0, freed)
+            } else {
+                (freed, 0)
+            };
+            stats.zones += 1;
+            stats.moved += live;
+            match temp {
+                Temperature::Cold => stats.cold += live,
+                Temperature::Metadata => stats.metadata += live,
+                Temperature::Hot => ()
+            }
+            stats.freed += freed;
+            stats.reclaimable += reclaimable;
+            stats.pinned += pinned;
+            stats.by_zone.push(ZoneCleanStats {
+                cluster: z.pba.cluster,
+                zid: z.zid,
+                moved: live,
+                reclaimable,
+                pinned
+            });
+            stats
+        })
+    }
+
+    /// Measured write amplification: the ratio of all bytes written to the
+    /// pool to bytes written on behalf of clients.
+    pub fn write_amplification(&self) -> f64 {
+        let client = self.written.saturating_sub(self.rewritten);
+        if client == 0 {
+            1.0
+        } else {
+            self.written as f64 / client as f64
+        }
+    }
+}
+
+/// Select which zones to clean and the temperature to move each one's records
+/// into.  Return them sorted by cleanliness: dirtiest zones first.
+fn select_zones(idml: &IDML, threshold: f32, policy: CleanPolicy)
+    -> Vec<(ClosedZone, Temperature)>
+{
+    let closed = idml.list_closed_zones().collect::<Vec<ClosedZone>>();
+    // The most recently closed zone is our best idea of "now" that doesn't
+    // require taking the transaction lock.
+    let newest = closed.iter()
+        .map(|z| z.txgs.end)
+        .max()
+        .unwrap_or(TxgT::from(0));
+    let mut zones = closed.into_iter()
+    .filter(move |z| {
+        let dirtiness = z.freed_blocks as f32 / z.total_blocks as f32;
+        dirtiness >= threshold
+    }).map(|z| {
+        let temp = policy.temperature(&z, newest);
+        (z, temp)
+    }).collect::<Vec<_>>();
+    // Sort by highest percentage of free space to least
+    // TODO: optimize for the case where all zones have equal size,
+    // removing the division.
+    let seed = determinism::seed();
+    zones.sort_unstable_by(|(a, _), (b, _)| {
+        // Annoyingly, f32 only implements PartialOrd, not Ord.  So we
+        // have to define a comparator function.
+        let afrac = -(a.freed_blocks as f32 / a.total_blocks as f32);
+        let bfrac = -(b.freed_blocks as f32 / b.total_blocks as f32);
+        let ord = afrac.partial_cmp(&bfrac).unwrap();
+        match seed {
+            // Break ties in an order that depends only on the seed
+            Some(seed) => ord.then_with(|| {
+                let key = |z: &ClosedZone| {
+                    let cluster = u64::from(z.pba.cluster) << 32;
+                    determinism::choose(seed, cluster | u64::from(z.zid))
+                };
+                key(a).cmp(&key(b))
+            }),
+            None => ord
+        }
+    });
+    zones
+}
+
 struct SyncCleaner {
     /// Handle to the DML.
     idml: Arc<IDML>,
 
+    /// Total bytes of live data moved since the `Cleaner` was created
+    rewritten: Arc<AtomicU64>,
+
     /// Dirtiness threshold.  Zones with less than this percentage of freed
     /// space will not be cleaned.
     threshold: f32,
@@ -28,7 +217,11 @@ struct SyncCleaner {
 
 impl SyncCleaner {
     /// Clean zones in the foreground, blocking the task
-    pub fn clean_now(&self) -> impl Future<Output=Result<()>> + Send {
+    ///
+    /// The amount of live data moved will be reported to `progress`.
+    pub fn clean_now(&self, policy: CleanPolicy, progress: Progress)
+        -> impl Future<Output=Result<()>> + Send
+    {
         // Outline:
         // 1) Get a list of mostly-free zones
         // 2) For each zone:
@@ -38,58 +231,62 @@ impl SyncCleaner {
         //        idml.move(record)
         //        offset += sizeof(record)
         let idml2 = self.idml.clone();
-        self.select_zones()
+        let rewritten = self.rewritten.clone();
+        self.select_zones(policy)
         .and_then(move |zones| {
+            progress.set_total(CleanStats::new(&zones, false).moved);
             // Limit concurrency to 1.  To minimize HDD seeks, it's better to
             // clean one at a time.  Any in-zone concurrency can be managed by
             // IDML:::clean_zone.
             stream::iter(zones.into_iter())
             .map(Ok)
-            .try_for_each(move |zone| {
+            .try_for_each(move |(zone, temp)| {
                 let idml3 = idml2.clone();
-                idml2.txg()
-                .then(move |txg_guard| 
-                    idml3.clean_zone(zone, *txg_guard)
-                )
+                let idml4 = idml2.clone();
+                let progress2 = progress.clone();
+                let rewritten2 = rewritten.clone();
+                // Yield to foreground I/O before each zone.  Don't throttle
+                // within a zone, because that would hold up the transaction.
+                let live = (zone.total_blocks - zone.freed_blocks) *
+                    BYTES_PER_LBA as u64;
+                idml2.throttle_background(live)
+                .then(move |_| idml3.txg())
+                .then(move |txg_guard|
+                    idml4.clean_zone(zone, temp, *txg_guard)
+                ).map_ok(move |_| {
+                    rewritten2.fetch_add(live, Ordering::Relaxed);
+                    progress2.add(live)
+                })
             })
         })
     }
 
-    pub fn new(idml: Arc<IDML>, threshold: f32) -> Self {
-        SyncCleaner{idml, threshold}
+    pub fn new(idml: Arc<IDML>, threshold: f32, rewritten: Arc<AtomicU64>)
+        -> Self
+    {
+        SyncCleaner{idml, rewritten, threshold}
     }
 
-    /// Select which zones to clean and return them sorted by cleanliness:
-    /// dirtiest zones first.
-    fn select_zones(&self)
-        -> impl Future<Output=Result<Vec<ClosedZone>>> + Send
+    fn select_zones(&self, policy: CleanPolicy)
+        -> impl Future<Output=Result<Vec<(ClosedZone, Temperature)>>> + Send
     {
-        let threshold = self.threshold;
-        let mut zones = self.idml.list_closed_zones()
-        .filter(move |z| {
-            let dirtiness = z.freed_blocks as f32 / z.total_blocks as f32;
-            dirtiness >= threshold
-        }).collect::<Vec<ClosedZone>>();
-        // Sort by highest percentage of free space to least
-        // TODO: optimize for the case where all zones have equal size,
-        // removing the division.
-        zones.sort_unstable_by(|a, b| {
-            // Annoyingly, f32 only implements PartialOrd, not Ord.  So we
-            // have to define a comparator function.
-            let afrac = -(a.freed_blocks as f32 / a.total_blocks as f32);
-            let bfrac = -(b.freed_blocks as f32 / b.total_blocks as f32);
-            afrac.partial_cmp(&bfrac).unwrap()
-        });
-        future::ok(zones)
+        future::ok(select_zones(&self.idml, self.threshold, policy))
     }
 }
 
+/// A request to clean, as sent to the `Cleaner`'s background task
+type CleanRequest = (oneshot::Sender<()>, CleanPolicy, Progress);
+
 /// Garbage collector.
 ///
 /// Cleans old Zones by moving their data to empty zones and erasing them.
 pub struct Cleaner {
+    idml: Arc<IDML>,
     jh: JoinHandle<()>,
-    tx: Option<mpsc::Sender<oneshot::Sender<()>>>
+    /// Total bytes of live data moved since the `Cleaner` was created
+    rewritten: Arc<AtomicU64>,
+    threshold: f32,
+    tx: Option<mpsc::Sender<CleanRequest>>
 }
 
 impl Cleaner {
@@ -100,9 +297,15 @@ impl Cleaner {
     /// The returned `Receiver` will deliver notification when cleaning is
     /// complete.  However, there is no requirement to poll it.  The client may
     /// drop it, and cleaning will continue in the background.
-    pub fn clean(&self) -> oneshot::Receiver<()> {
+    ///
+    /// If cleaning is already pending, then this request will be merged with
+    /// it, and `policy` and `progress` will be dropped unused.
+    pub fn clean(&self, policy: CleanPolicy, progress: Progress)
+        -> oneshot::Receiver<()>
+    {
         let (tx, rx) = oneshot::channel();
-        if let Err(e) = self.tx.as_ref().unwrap().clone().try_send(tx) {
+        let req = (tx, policy, progress);
+        if let Err(e) = self.tx.as_ref().unwrap().clone().try_send(req) {
             if e.is_full() {
                 // No worries; cleaning is idempotent
             } else {
@@ -115,27 +318,50 @@ impl Cleaner {
     pub fn new(idml: Arc<IDML>, thresh: Option<f32>) -> Self
     {
         let (tx, rx) = mpsc::channel(1);
-        let jh = Cleaner::run(idml,
-                              thresh.unwrap_or(Cleaner::DEFAULT_THRESHOLD), rx);
-        Cleaner{jh, tx: Some(tx)}
+        let threshold = thresh.unwrap_or(Cleaner::DEFAULT_THRESHOLD);
+        let rewritten = Arc::new(AtomicU64::new(0));
+        let jh = Cleaner::run(idml.clone(), threshold, rewritten.clone(), rx);
+        Cleaner{idml, jh, rewritten, threshold, tx: Some(tx)}
+    }
+
+    /// Report what `clean` would do right now with the given `policy`, without
+    /// doing it.  Also report the write amplification measured so far.
+    ///
+    /// While the pool has a checkpoint, all freed space is reported as pinned.
+    pub fn plan(&self, policy: CleanPolicy) -> CleanStats {
+        let zones = select_zones(&self.idml, self.threshold, policy);
+        let pinned = self.idml.checkpoint_txg().is_some();
+        CleanStats {
+            written: self.idml.written() * BYTES_PER_LBA as u64,
+            rewritten: self.rewritten.load(Ordering::Relaxed),
+            ..CleanStats::new(&zones, pinned)
+        }
     }
 
     // Start a task that will clean the system in the background, whenever
     // requested.
-    fn run(idml: Arc<IDML>, thresh: f32,
-           rx: mpsc::Receiver<oneshot::Sender<()>>)
+    fn run(idml: Arc<IDML>, thresh: f32, rewritten: Arc<AtomicU64>,
+           rx: mpsc::Receiver<CleanRequest>)
         -> JoinHandle<()>
     {
         tokio::spawn(async move {
-            let sync_cleaner = SyncCleaner::new(idml, thresh);
-            rx.for_each(move |tx| {
-                sync_cleaner.clean_now()
-                    .map_err(Error::unhandled)
-                    .map_ok(move |_| {
-                        // Ignore errors.  An error here indicates that the
-                        // client doesn't want to be notified.
-                        let _result = tx.send(());
-                    }).map(drop)
+            let sync_cleaner = SyncCleaner::new(idml, thresh, rewritten);
+            rx.for_each(move |(tx, policy, progress)| {
+                sync_cleaner.clean_now(policy, progress.clone())
+                    .map(move |r| {
+                        progress.finish(r);
+                        match r {
+                            Ok(()) => {
+                                // Ignore errors.  An error here indicates that
+                                // the client doesn't want to be notified.
+                                let _result = tx.send(());
+                            },
+                            Err(e) => {
+                                // Dropping tx will notify the client.
+                                tracing::error!("Cleaning failed: {e:?}");
+                            }
+                        }
+                    })
             }).await
         })
     }
@@ -153,9 +379,12 @@ impl Cleaner {
 #[cfg(test)]
 mod t {
 
-use crate::util::basic_runtime;
+use crate::{
+    job::{JobKind, Jobs},
+    util::basic_runtime
+};
 use futures::future;
-use mockall::Sequence;
+use mockall::{Sequence, predicate::eq};
 use super::*;
 use tokio::runtime;
 
@@ -169,7 +398,8 @@ fn background() {
         .returning(|| {
             let czs = vec![
                 ClosedZone{freed_blocks: 0, total_blocks: 100, zid: 0,
-                    pba: PBA::new(0, 0), txgs: TxgT::from(0)..TxgT::from(1)}
+                    pba: PBA::new(0, 0), txgs: TxgT::from(0)..TxgT::from(1),
+                    temp: Temperature::Hot}
             ];
             Box::new(czs.into_iter())
         });
@@ -181,7 +411,7 @@ fn background() {
         .unwrap();
     rt.spawn(async {
         let cleaner = Cleaner::new(Arc::new(idml), None);
-        cleaner.clean()
+        cleaner.clean(CleanPolicy::Mixed, Progress::default())
             .map_err(Error::unhandled)
     });
     drop(rt);   // Implicitly waits for all tasks to complete
@@ -196,18 +426,185 @@ fn no_sufficiently_dirty_zones() {
         .returning(|| {
             let czs = vec![
                 ClosedZone{freed_blocks: 1, total_blocks: 100, zid: 0,
-                    pba: PBA::new(0, 0), txgs: TxgT::from(0)..TxgT::from(1)}
+                    pba: PBA::new(0, 0), txgs: TxgT::from(0)..TxgT::from(1),
+                    temp: Temperature::Hot}
             ];
             Box::new(czs.into_iter())
         });
     idml.expect_txg().never();
     idml.expect_clean_zone().never();
-    let cleaner = SyncCleaner::new(Arc::new(idml), 0.5);
+    let cleaner = SyncCleaner::new(Arc::new(idml), 0.5, Arc::default());
     basic_runtime().block_on(async {
-        cleaner.clean_now().await
+        cleaner.clean_now(CleanPolicy::Mixed, Progress::default()).await
     }).unwrap();
 }
 
+/// Planning a clean should report the selected zones without cleaning them
+#[test]
+fn plan() {
+    let mut idml = IDML::default();
+    idml.expect_list_closed_zones()
+        .once()
+        .returning(|| {
+            let czs = vec![
+                ClosedZone{freed_blocks: 55, total_blocks: 100, zid: 0,
+                    pba: PBA::new(0, 0), txgs: TxgT::from(0)..TxgT::from(1),
+                    temp: Temperature::Hot},
+                ClosedZone{freed_blocks: 25, total_blocks: 100, zid: 1,
+                    pba: PBA::new(1, 0), txgs: TxgT::from(0)..TxgT::from(1),
+                    temp: Temperature::Hot},
+                ClosedZone{freed_blocks: 75, total_blocks: 100, zid: 2,
+                    pba: PBA::new(2, 0), txgs: TxgT::from(1)..TxgT::from(2),
+                    temp: Temperature::Hot},
+            ];
+            Box::new(czs.into_iter())
+        });
+    idml.expect_throttle_background().never();
+    idml.expect_txg().never();
+    idml.expect_clean_zone().never();
+    idml.expect_checkpoint_txg()
+        .return_const(None);
+    idml.expect_written()
+        .once()
+        .return_const(1000u64);
+    basic_runtime().block_on(async {
+        let cleaner = Cleaner::new(Arc::new(idml), None);
+        let stats = cleaner.plan(CleanPolicy::Mixed);
+        assert_eq!(stats, CleanStats {
+            zones: 2,
+            moved: 70 * BYTES_PER_LBA as u64,
+            cold: 0,
+            metadata: 0,
+            freed: 130 * BYTES_PER_LBA as u64,
+            reclaimable: 130 * BYTES_PER_LBA as u64,
+            pinned: 0,
+            by_zone: vec![
+                ZoneCleanStats {
+                    cluster: 2,
+                    zid: 2,
+                    moved: 25 * BYTES_PER_LBA as u64,
+                    reclaimable: 75 * BYTES_PER_LBA as u64,
+                    pinned: 0
+                },
+                ZoneCleanStats {
+                    cluster: 0,
+                    zid: 0,
+                    moved: 45 * BYTES_PER_LBA as u64,
+                    reclaimable: 55 * BYTES_PER_LBA as u64,
+                    pinned: 0
+                },
+            ],
+            written: 1000 * BYTES_PER_LBA as u64,
+            rewritten: 0,
+        });
+        cleaner.shutdown().await;
+    });
+}
+
+/// While the pool has a checkpoint, none of the freed space can be reclaimed
+#[test]
+fn plan_checkpoint() {
+    let mut idml = IDML::default();
+    idml.expect_list_closed_zones()
+        .once()
+        .returning(|| {
+            let czs = vec![
+                ClosedZone{freed_blocks: 55, total_blocks: 100, zid: 0,
+                    pba: PBA::new(0, 0), txgs: TxgT::from(0)..TxgT::from(1),
+                    temp: Temperature::Hot},
+            ];
+            Box::new(czs.into_iter())
+        });
+    idml.expect_checkpoint_txg()
+        .return_const(Some(TxgT::from(1)));
+    idml.expect_written()
+        .return_const(0u64);
+    basic_runtime().block_on(async {
+        let cleaner = Cleaner::new(Arc::new(idml), None);
+        let stats = cleaner.plan(CleanPolicy::Mixed);
+        assert_eq!(stats.freed, 55 * BYTES_PER_LBA as u64);
+        assert_eq!(stats.reclaimable, 0);
+        assert_eq!(stats.pinned, 55 * BYTES_PER_LBA as u64);
+        assert_eq!(stats.by_zone[0].pinned, 55 * BYTES_PER_LBA as u64);
+        cleaner.shutdown().await;
+    });
+}
+
+/// With the generational policy, the plan should report how much data would be
+/// moved into cold zones.
+#[test]
+fn plan_generational() {
+    let mut idml = IDML::default();
+    idml.expect_list_closed_zones()
+        .once()
+        .returning(|| {
+            let czs = vec![
+                ClosedZone{freed_blocks: 55, total_blocks: 100, zid: 0,
+                    pba: PBA::new(0, 0), txgs: TxgT::from(0)..TxgT::from(1),
+                    temp: Temperature::Hot},
+                ClosedZone{freed_blocks: 75, total_blocks: 100, zid: 2,
+                    pba: PBA::new(2, 0), txgs: TxgT::from(9)..TxgT::from(10),
+                    temp: Temperature::Hot},
+            ];
+            Box::new(czs.into_iter())
+        });
+    idml.expect_checkpoint_txg()
+        .return_const(None);
+    idml.expect_written()
+        .return_const(0u64);
+    basic_runtime().block_on(async {
+        let cleaner = Cleaner::new(Arc::new(idml), None);
+        let policy = CleanPolicy::Generational{cold_age: 5};
+        let stats = cleaner.plan(policy);
+        assert_eq!(stats.moved, 70 * BYTES_PER_LBA as u64);
+        assert_eq!(stats.cold, 45 * BYTES_PER_LBA as u64);
+        cleaner.shutdown().await;
+    });
+}
+
+/// Metadata zones' records should be moved into metadata zones, even if they
+/// are old enough to be cold.
+#[test]
+fn plan_metadata() {
+    let mut idml = IDML::default();
+    idml.expect_list_closed_zones()
+        .once()
+        .returning(|| {
+            let czs = vec![
+                ClosedZone{freed_blocks: 55, total_blocks: 100, zid: 0,
+                    pba: PBA::new(0, 0), txgs: TxgT::from(0)..TxgT::from(1),
+                    temp: Temperature::Metadata},
+                ClosedZone{freed_blocks: 75, total_blocks: 100, zid: 1,
+                    pba: PBA::new(1, 0), txgs: TxgT::from(0)..TxgT::from(1),
+                    temp: Temperature::Hot},
+                ClosedZone{freed_blocks: 75, total_blocks: 100, zid: 2,
+                    pba: PBA::new(2, 0), txgs: TxgT::from(9)..TxgT::from(10),
+                    temp: Temperature::Hot},
+            ];
+            Box::new(czs.into_iter())
+        });
+    idml.expect_checkpoint_txg()
+        .return_const(None);
+    idml.expect_written()
+        .return_const(0u64);
+    basic_runtime().block_on(async {
+        let cleaner = Cleaner::new(Arc::new(idml), None);
+        let policy = CleanPolicy::Generational{cold_age: 5};
+        let stats = cleaner.plan(policy);
+        assert_eq!(stats.moved, 95 * BYTES_PER_LBA as u64);
+        assert_eq!(stats.cold, 25 * BYTES_PER_LBA as u64);
+        assert_eq!(stats.metadata, 45 * BYTES_PER_LBA as u64);
+        cleaner.shutdown().await;
+    });
+}
+
+#[test]
+fn write_amplification() {
+    assert_eq!(CleanStats::default().write_amplification(), 1.0);
+    let stats = CleanStats{written: 300, rewritten: 100, ..Default::default()};
+    assert_eq!(stats.write_amplification(), 1.5);
+}
+
 #[test]
 fn one_sufficiently_dirty_zone() {
     const TXG: TxgT = TxgT(42);
@@ -218,23 +615,113 @@ fn one_sufficiently_dirty_zone() {
         .returning(|| {
             let czs = vec![
                 ClosedZone{freed_blocks: 55, total_blocks: 100, zid: 0,
-                    pba: PBA::new(0, 0), txgs: TxgT::from(0)..TxgT::from(1)}
+                    pba: PBA::new(0, 0), txgs: TxgT::from(0)..TxgT::from(1),
+                    temp: Temperature::Hot}
             ];
             Box::new(czs.into_iter())
         });
+    idml.expect_throttle_background()
+        .once()
+        .with(eq(45 * BYTES_PER_LBA as u64))
+        .returning(|_| Box::pin(future::ready(())));
     idml.expect_txg()
         .once()
         .returning(|| Box::pin(future::ready::<&'static TxgT>(&TXG)));
     idml.expect_clean_zone()
         .once()
-        .withf(move |zone, txg| {
+        .withf(move |zone, temp, txg| {
             zone.pba == PBA::new(0, 0) &&
+            *temp == Temperature::Hot &&
             *txg == TXG
-        }).returning(|_, _| Box::pin(future::ok::<(), Error>(())));
-    let cleaner = SyncCleaner::new(Arc::new(idml), 0.5);
+        }).returning(|_, _, _| Box::pin(future::ok::<(), Error>(())));
+    let cleaner = SyncCleaner::new(Arc::new(idml), 0.5, Arc::default());
+    basic_runtime().block_on(async {
+        cleaner.clean_now(CleanPolicy::Mixed, Progress::default()).await
+    }).unwrap();
+}
+
+/// Cleaning should report the live data it moves to its job
+#[test]
+fn progress() {
+    const TXG: TxgT = TxgT(42);
+
+    let mut idml = IDML::default();
+    idml.expect_list_closed_zones()
+        .once()
+        .returning(|| {
+            let czs = vec![
+                ClosedZone{freed_blocks: 55, total_blocks: 100, zid: 0,
+                    pba: PBA::new(0, 0), txgs: TxgT::from(0)..TxgT::from(1),
+                    temp: Temperature::Hot},
+                ClosedZone{freed_blocks: 75, total_blocks: 100, zid: 2,
+                    pba: PBA::new(2, 0), txgs: TxgT::from(1)..TxgT::from(2),
+                    temp: Temperature::Hot},
+            ];
+            Box::new(czs.into_iter())
+        });
+    idml.expect_throttle_background()
+        .times(2)
+        .returning(|_| Box::pin(future::ready(())));
+    idml.expect_txg()
+        .times(2)
+        .returning(|| Box::pin(future::ready::<&'static TxgT>(&TXG)));
+    idml.expect_clean_zone()
+        .times(2)
+        .returning(|_, _, _| Box::pin(future::ok::<(), Error>(())));
+    let jobs = Jobs::default();
+    let (id, progress) = jobs.start(JobKind::Clean, "pool".to_owned());
+    let cleaner = SyncCleaner::new(Arc::new(idml), 0.5, Arc::default());
+    basic_runtime().block_on(async {
+        cleaner.clean_now(CleanPolicy::Mixed, progress).await
+    }).unwrap();
+    let status = jobs.status(id).unwrap();
+    assert_eq!(status.total, 70 * BYTES_PER_LBA as u64);
+    assert_eq!(status.processed, 70 * BYTES_PER_LBA as u64);
+}
+
+/// With the generational policy, old zones' records should be moved into cold
+/// zones, and young zones' records should be mixed with new data.
+#[test]
+fn generational() {
+    const TXG: TxgT = TxgT(42);
+
+    let mut idml = IDML::default();
+    idml.expect_list_closed_zones()
+        .once()
+        .returning(|| {
+            let czs = vec![
+                ClosedZone{freed_blocks: 55, total_blocks: 100, zid: 0,
+                    pba: PBA::new(0, 0), txgs: TxgT::from(0)..TxgT::from(1),
+                    temp: Temperature::Hot},
+                ClosedZone{freed_blocks: 75, total_blocks: 100, zid: 2,
+                    pba: PBA::new(2, 0), txgs: TxgT::from(9)..TxgT::from(10),
+                    temp: Temperature::Hot},
+            ];
+            Box::new(czs.into_iter())
+        });
+    idml.expect_throttle_background()
+        .times(2)
+        .returning(|_| Box::pin(future::ready(())));
+    idml.expect_txg()
+        .times(2)
+        .returning(|| Box::pin(future::ready::<&'static TxgT>(&TXG)));
+    idml.expect_clean_zone()
+        .once()
+        .withf(|zone, temp, _| {
+            zone.pba == PBA::new(0, 0) && *temp == Temperature::Cold
+        }).returning(|_, _, _| Box::pin(future::ok::<(), Error>(())));
+    idml.expect_clean_zone()
+        .once()
+        .withf(|zone, temp, _| {
+            zone.pba == PBA::new(2, 0) && *temp == Temperature::Hot
+        }).returning(|_, _, _| Box::pin(future::ok::<(), Error>(())));
+    let rewritten = Arc::new(AtomicU64::new(0));
+    let cleaner = SyncCleaner::new(Arc::new(idml), 0.5, rewritten.clone());
+    let policy = CleanPolicy::Generational{cold_age: 5};
     basic_runtime().block_on(async {
-        cleaner.clean_now().await
+        cleaner.clean_now(policy, Progress::default()).await
     }).unwrap();
+    assert_eq!(rewritten.load(Ordering::Relaxed), 70 * BYTES_PER_LBA as u64);
 }
 
 #[test]
@@ -248,37 +735,45 @@ fn two_sufficiently_dirty_zones() {
         .returning(|| {
             let czs = vec![
                 ClosedZone{freed_blocks: 55, total_blocks: 100, zid: 0,
-                    pba: PBA::new(0, 0), txgs: TxgT::from(0)..TxgT::from(1)},
+                    pba: PBA::new(0, 0), txgs: TxgT::from(0)..TxgT::from(1),
+                    temp: Temperature::Hot},
                 ClosedZone{freed_blocks: 25, total_blocks: 100, zid: 1,
-                    pba: PBA::new(1, 0), txgs: TxgT::from(0)..TxgT::from(1)},
+                    pba: PBA::new(1, 0), txgs: TxgT::from(0)..TxgT::from(1),
+                    temp: Temperature::Hot},
                 ClosedZone{freed_blocks: 75, total_blocks: 100, zid: 2,
-                    pba: PBA::new(2, 0), txgs: TxgT::from(1)..TxgT::from(2)},
+                    pba: PBA::new(2, 0), txgs: TxgT::from(1)..TxgT::from(2),
+                    temp: Temperature::Hot},
             ];
             Box::new(czs.into_iter())
         });
+    idml.expect_throttle_background()
+        .times(2)
+        .returning(|_| Box::pin(future::ready(())));
     idml.expect_txg()
         .once()
         .in_sequence(&mut seq)
         .returning(|| Box::pin(future::ready::<&'static TxgT>(&TXG)));
     idml.expect_clean_zone()
         .once()
-        .withf(move |zone, txg| {
+        .withf(move |zone, temp, txg| {
             zone.pba == PBA::new(2, 0) &&
+            *temp == Temperature::Hot &&
             *txg == TXG
-        }).returning(|_, _| Box::pin(future::ok::<(), Error>(())));
+        }).returning(|_, _, _| Box::pin(future::ok::<(), Error>(())));
     idml.expect_txg()
         .once()
         .in_sequence(&mut seq)
         .returning(|| Box::pin(future::ready::<&'static TxgT>(&TXG)));
     idml.expect_clean_zone()
         .once()
-        .withf(move |zone, txg| {
+        .withf(move |zone, temp, txg| {
             zone.pba == PBA::new(0, 0) &&
+            *temp == Temperature::Hot &&
             *txg == TXG
-        }).returning(|_, _| Box::pin(future::ok::<(), Error>(())));
-    let cleaner = SyncCleaner::new(Arc::new(idml), 0.5);
+        }).returning(|_, _, _| Box::pin(future::ok::<(), Error>(())));
+    let cleaner = SyncCleaner::new(Arc::new(idml), 0.5, Arc::default());
     basic_runtime().block_on(async {
-        cleaner.clean_now().await
+        cleaner.clean_now(CleanPolicy::Mixed, Progress::default()).await
     }).unwrap();
 }
 
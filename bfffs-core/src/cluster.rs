@@ -1,11 +1,13 @@
 // vim: tw=80
 
 use crate::{
+    capacity::ClusterShape,
     label::*,
     raid::VdevRaidApi,
     types::*,
     util::*,
-    vdev::BoxVdevFut
+    vdev::{BoxVdevFut, LeafStatus},
+    vdev_block::VdevLeaf
 };
 use divbuf::{DivBuf, DivBufShared};
 #[cfg(test)] use crate::raid::MockVdevRaid;
@@ -30,7 +32,7 @@ use std::{
     ops::Range,
     pin::Pin,
     sync::{
-        atomic::{AtomicU64, Ordering},
+        atomic::{AtomicBool, AtomicU64, Ordering},
         Arc,
         RwLock
     },
@@ -54,16 +56,57 @@ struct Zone {
     ///
     /// The end is invalid for open zones, and both start and end are invalid
     /// for empty zones.
-    pub txgs: Range<TxgT>
+    pub txgs: Range<TxgT>,
+    /// Temperature of the data written to this `Zone`.  Only valid for closed
+    /// zones, and not persisted.  Zones that were closed before the `Cluster`
+    /// was opened are always `Hot`.
+    pub temp: Temperature
 }
 
 impl Default for Zone {
     fn default() -> Self {
         let txgs = TxgT::from(0)..TxgT::from(0);
-        Zone{freed_blocks: 0, total_blocks: 0, txgs}
+        Zone{freed_blocks: 0, total_blocks: 0, txgs, temp: Temperature::Hot}
     }
 }
 
+/// Default maximum number of simultaneously open zones per `Cluster`.  Each open
+/// zone costs memory for RAID stripe buffers, and on SMR devices each costs
+/// device resources too.
+pub const DEFAULT_OPEN_ZONE_BUDGET: u32 = 8;
+
+/// Zone open/close statistics for a single `Cluster`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ZoneStats {
+    /// Number of zones opened since the `Cluster` was opened
+    pub opened: u64,
+    /// Number of zones finished since the `Cluster` was opened, for any reason
+    pub closed: u64,
+    /// Number of zones that were finished early, before they filled up,
+    /// because too many zones were open.
+    pub evicted: u64,
+}
+
+/// Expected lifetime of the data written to a zone.
+///
+/// Data of different temperatures is never mixed within the same open zone, so
+/// that long-lived data won't have to be repeatedly moved by the cleaner just
+/// because it shares a zone with short-lived data.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Temperature {
+    /// Newly written data, which may be freed soon
+    #[default]
+    Hot,
+    /// Data that has already survived for a long time, usually rewritten by
+    /// the cleaner
+    Cold,
+    /// Tree nodes.  Every transaction rewrites the nodes along the path to
+    /// each modified record, so they die much younger than the data they
+    /// index.  Keeping them out of data zones lets those zones stay full of
+    /// live data, rather than being riddled with freed nodes.
+    Metadata
+}
+
 /// Public representation of a closed zone
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct ClosedZone {
@@ -75,6 +118,8 @@ pub struct ClosedZone {
     pub total_blocks: LbaT,
     /// Range of transaction groups included within this Zone
     pub txgs: Range<TxgT>,
+    /// Temperature of the data in this Zone
+    pub temp: Temperature,
     /// Zone Id within this Cluster
     pub zid: ZoneT
 
@@ -87,6 +132,12 @@ struct OpenZone {
     pub start: LbaT,
     /// Number of LBAs that have been allocated within this `Zone` so far.
     pub allocated_blocks: u32,
+    /// Sequence number of the most recent allocation from this `Zone`.  Used
+    /// to choose which zone to finish when too many are open.
+    pub last_write: u64,
+    /// Temperature of the data being written to this `Zone`.  Only allocations
+    /// of the same temperature will be satisfied from it.
+    pub temp: Temperature,
 }
 
 impl OpenZone {
@@ -124,6 +175,9 @@ struct FreeSpaceMap {
     /// Total number of zones in the vdev
     total_zones: ZoneT,
 
+    /// Sequence number of the most recent allocation from any zone
+    write_seq: u64,
+
     /// `Vec` of all zones in the Vdev.  Any zones past the end of the `Vec` are
     /// implicitly Empty.  Any zones whose index is present in `empty_zones` are
     /// also Empty.  Any zones whose index is also present in `open_zones` are
@@ -176,8 +230,16 @@ impl<'a> FreeSpaceMap {
         self.dirty.clear();
     }
 
-    fn deserialize(vdev: Arc<dyn VdevRaidApi>, buf: DivBuf, zones: ZoneT)
-        -> Pin<Box<
+    /// Reconstruct a `FreeSpaceMap` from its on-disk representation.
+    ///
+    /// If `readonly` is set, open zones will be tracked in memory but not
+    /// reopened on disk, so that nothing will be written to the vdev.
+    fn deserialize(
+        vdev: Arc<dyn VdevRaidApi>,
+        buf: DivBuf,
+        zones: ZoneT,
+        readonly: bool
+    ) -> Pin<Box<
                 dyn Future<Output=Result<(Self, Arc<dyn VdevRaidApi>)>>
                 + Send
             >>
@@ -201,8 +263,18 @@ impl<'a> FreeSpaceMap {
                     } else {
                         // Zone is Open
                         let allocated = LbaT::from(zod.allocated_blocks);
-                        oz_futs.push(vdev.reopen_zone(zid, allocated));
-                        let azid = fsm.try_allocate(allocated).0.unwrap().0;
+                        if !readonly {
+                            let vdev2 = vdev.clone();
+                            oz_futs.push(async move {
+                                let ok = vdev2.check_write_pointers(zid,
+                                    allocated).await?;
+                                vdev2.reopen_zone(zid, allocated).await?;
+                                Ok::<_, Error>(if ok {None} else {Some(zid)})
+                            }.boxed());
+                        }
+                        // The temperature of reopened zones isn't persisted
+                        let azid = fsm.try_allocate(allocated, Temperature::Hot)
+                            .0.unwrap().0;
                         assert_eq!(azid, zid);
                     }
                     fsm.zones[zid as usize].freed_blocks = zod.freed_blocks;
@@ -215,10 +287,28 @@ impl<'a> FreeSpaceMap {
         }
         assert_eq!(zid, zones);
         fsm.clear_dirty_zones();
-        let fut = oz_futs.try_collect::<Vec<_>>().map(|_| Ok((fsm, vdev)));
+        let fut = oz_futs.try_collect::<Vec<_>>().map_ok(move |mismatched| {
+            for zid in mismatched.into_iter().flatten() {
+                // Probably something was written to the zone after the
+                // spacemap was.  Appending to the zone would violate its write
+                // pointer, so waste the rest of it instead.  It will be
+                // finished along with the other full zones.
+                tracing::warn!(zone = zid,
+                    "Write pointer doesn't match the spacemap");
+                let available = fsm.available(zid);
+                fsm.waste_space(zid, available);
+            }
+            (fsm, vdev)
+        });
         Box::pin(fut)
     }
 
+    /// Mark every zone as dirty, so the entire `FreeSpaceMap` will be
+    /// written on the next flush.
+    fn dirty_all(&mut self) {
+        self.dirty.insert_range(..);
+    }
+
     /// Mark zone `zone_id` as dirty
     fn dirty_zone(&mut self, zone_id: ZoneT) {
         let block = zone_id as usize / SPACEMAP_ZONES_PER_LBA;
@@ -271,8 +361,9 @@ impl<'a> FreeSpaceMap {
     fn finish_zone(&mut self, zone_id: ZoneT, txg: TxgT) -> LbaT {
         self.dirty_zone(zone_id);
         let available = self.available(zone_id);
-        assert!(self.open_zones.remove(&zone_id).is_some(),
-            "Can't finish a Zone that isn't open");
+        let oz = self.open_zones.remove(&zone_id)
+            .expect("Can't finish a Zone that isn't open");
+        self.zones[zone_id as usize].temp = oz.temp;
         self.zones[zone_id as usize].freed_blocks += available as u32;
         self.zones[zone_id as usize].txgs.end = txg + 1;
         available
@@ -354,7 +445,8 @@ impl<'a> FreeSpaceMap {
                         start: LbaT::max_value(),   // sentinel value
                         freed_blocks: LbaT::from(z.freed_blocks),
                         total_blocks: LbaT::from(z.total_blocks),
-                        txgs: z.txgs.clone()
+                        txgs: z.txgs.clone(),
+                        temp: z.temp
                     })
                 }
             }).next()
@@ -371,6 +463,7 @@ impl<'a> FreeSpaceMap {
             empty_zones: BTreeSet::new(),
             open_zones: BTreeMap::new(),
             total_zones,
+            write_seq: 0,
             zones: Vec::new()
         }
     }
@@ -415,7 +508,13 @@ impl<'a> FreeSpaceMap {
         self.zones[idx].total_blocks = space as u32;
         self.zones[idx].freed_blocks = 0;
         self.zones[idx].txgs = txg..TxgT(u32::max_value());
-        let oz = OpenZone{start, allocated_blocks: lbas as u32};
+        self.write_seq += 1;
+        let oz = OpenZone{
+            start,
+            allocated_blocks: lbas as u32,
+            last_write: self.write_seq,
+            temp: Temperature::Hot
+        };
         self.empty_zones.remove(&id);
         assert!(self.open_zones.insert(id, oz).is_none(),
             "Can only open empty zones");
@@ -427,7 +526,9 @@ impl<'a> FreeSpaceMap {
     }
 
     /// Open a FreeSpaceMap from an already-formatted `VdevRaid`.
-    async fn open(vdev: Arc<dyn VdevRaidApi>)
+    ///
+    /// `idx` is the index of the spacemap to read.
+    async fn open(vdev: Arc<dyn VdevRaidApi>, idx: u32, readonly: bool)
         -> Result<(Self, Arc<dyn VdevRaidApi + 'static>)>
     {
         let total_zones = vdev.zones();
@@ -436,10 +537,10 @@ impl<'a> FreeSpaceMap {
         let blocks = div_roundup(total_zones as usize, SPACEMAP_ZONES_PER_LBA);
         let dbs = DivBufShared::from(vec![0u8; blocks * BYTES_PER_LBA]);
         let dbm = dbs.try_mut().unwrap();
-        vdev.read_spacemap(dbm, 0)
+        vdev.read_spacemap(dbm, idx)
         .and_then(move |_| {
             FreeSpaceMap::deserialize(vdev, dbs.try_const().unwrap(),
-                                      total_zones)
+                                      total_zones, readonly)
         }).await
     }
 
@@ -448,6 +549,17 @@ impl<'a> FreeSpaceMap {
         self.open_zones.keys()
     }
 
+    /// Return the ids of all open zones not in `exclude`, ordered from least
+    /// recently written to most.
+    fn lru_open_zones(&self, exclude: &[ZoneT]) -> Vec<ZoneT> {
+        let mut v = self.open_zones.iter()
+            .filter(|(zid, _)| !exclude.contains(zid))
+            .map(|(zid, oz)| (oz.last_write, *zid))
+            .collect::<Vec<_>>();
+        v.sort_unstable();
+        v.into_iter().map(|(_, zid)| zid).collect()
+    }
+
     /// Serialize this `FreeSpaceMap` so it can be written to a disk's reserved
     /// area
     fn serialize(&'a self) -> impl Iterator<Item=(LbaT, DivBufShared)> + 'a {
@@ -488,20 +600,24 @@ impl<'a> FreeSpaceMap {
         })
     }
 
-    /// Try to allocate `space` worth of space in any open zone.  If no open
-    /// zones can satisfy the allocation, return `None` instead.
+    /// Try to allocate `space` worth of space in any open zone of temperature
+    /// `temp`.  If no such zones can satisfy the allocation, return `None`
+    /// instead.
     ///
     /// # Returns
     ///
     /// The Zone and LBA where the allocation happened, and a vector of Zone IDs
     /// of Zones which have too little space.
-    fn try_allocate(&mut self, space: LbaT)
+    fn try_allocate(&mut self, space: LbaT, temp: Temperature)
         -> (Option<(ZoneT, LbaT)>, Vec<ZoneT>)
     {
         let mut nearly_full_zones = Vec::with_capacity(1);
         let result = {
             let zones = &self.zones;
             self.open_zones.iter_mut().find(|&(zone_id, ref oz)| {
+                if oz.temp != temp {
+                    return false;
+                }
                 let zone = &zones[*zone_id as usize];
                 let avail_lbas = zone.total_blocks - oz.allocated_blocks;
                 // NB the next two lines can be replaced by
@@ -518,6 +634,8 @@ impl<'a> FreeSpaceMap {
         }.map(|(zone_id, oz)| {
             let lba = oz.write_pointer();
             oz.allocated_blocks += space as u32;
+            self.write_seq += 1;
+            oz.last_write = self.write_seq;
             (*zone_id, lba)
         });
         if let Some((zid, _)) = result {
@@ -693,8 +811,30 @@ pub struct Cluster {
     /// detailed information in the `FreeSpaceMap`.
     allocated_space: AtomicU64,
 
+    /// Does the pool have a checkpoint?  If so, no zones may be erased, and
+    /// the second spacemap may not be overwritten, because the checkpoint may
+    /// still refer to them.
+    checkpoint: AtomicBool,
+
     fsm: RwLock<FreeSpaceMap>,
 
+    /// Maximum number of zones that may be open at once.  It's the lesser of
+    /// the configured budget and any limit imposed by the underlying devices.
+    open_zone_budget: u32,
+
+    /// Was the `Cluster` opened read-only?  If so, all attempts to modify it
+    /// will fail with `EROFS`.
+    readonly: bool,
+
+    /// Number of zones opened, for statistical purposes
+    zones_opened: AtomicU64,
+
+    /// Number of zones closed, for statistical purposes
+    zones_closed: AtomicU64,
+
+    /// Number of zones closed early to stay within `open_zone_budget`
+    zones_evicted: AtomicU64,
+
     /// Underlying vdev (which may or may not use RAID)
     // The Arc is necessary in order for some methods to return futures with
     // 'static lifetimes
@@ -721,6 +861,7 @@ impl Cluster {
         -> FuturesUnordered<BoxVdevFut>
     {
         nearly_full_zones.iter().map(|&zone_id| {
+            self.zones_closed.fetch_add(1, Ordering::Relaxed);
             let blocks = self.fsm.write().unwrap().finish_zone(zone_id, txg);
             self.allocated_space.fetch_add(blocks, Ordering::Relaxed);
             let fut = self.vdev.finish_zone(zone_id);
@@ -728,6 +869,35 @@ impl Cluster {
         }).collect::<FuturesUnordered<BoxVdevFut>>()
     }
 
+    /// Preserve the `Cluster`'s current state for a pool checkpoint.
+    ///
+    /// Finishes every open zone, so nothing more will be written to any zone
+    /// that the checkpoint refers to, and writes a complete copy of the
+    /// `FreeSpaceMap` to the second spacemap.  Until the checkpoint is
+    /// discarded, no zones will be erased.  `txg` is the current transaction
+    /// group.
+    pub fn checkpoint(&self, txg: TxgT) -> BoxVdevFut
+    {
+        if self.readonly {
+            return Box::pin(future::err(Error::EROFS));
+        }
+        let zone_ids = self.fsm.read().unwrap()
+            .open_zone_ids()
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut futs = self.close_zones(&zone_ids, txg);
+        let mut fsm = self.fsm.write().unwrap();
+        // Write the entire FreeSpaceMap, but leave it dirty so the first
+        // spacemap will catch up on the next flush.
+        fsm.dirty_all();
+        futs.extend(self.write_spacemap(&fsm, 1));
+        drop(fsm);
+        self.checkpoint.store(true, Ordering::Relaxed);
+        let fut = futs.try_collect::<Vec<_>>()
+        .map_ok(drop);
+        Box::pin(fut)
+    }
+
     /// Create a new `Cluster` from unused files or devices
     ///
     /// * `raids`:              Already labeled raid vdev
@@ -752,6 +922,19 @@ impl Cluster {
         self.vdev.erase_zone(zone)
     }
 
+    /// Status of every leaf device, including its lifetime I/O error counts
+    pub fn leaf_status(&self) -> Vec<LeafStatus> {
+        self.vdev.leaf_status()
+    }
+
+    /// Bring a removed or faulted leaf device back online.  See
+    /// [`Mirror::online`].
+    ///
+    /// [`Mirror::online`]: crate::mirror::Mirror::online
+    pub async fn online(&self, leaf: VdevLeaf) -> Result<()> {
+        self.vdev.online(leaf).await
+    }
+
     /// Find the first closed zone whose index is greater than or equal to `zid`
     pub fn find_closed_zone(&self, zid: ZoneT) -> Option<ClosedZone> {
         self.fsm.read().unwrap().find_closed_zone(zid)
@@ -766,8 +949,10 @@ impl Cluster {
     /// the index of the label that is about to be written.
     pub fn flush(&self, idx: u32) -> BoxVdevFut
     {
+        if self.readonly {
+            return Box::pin(future::err(Error::EROFS));
+        }
         let mut fsm = self.fsm.write().unwrap();
-        let vdev2 = self.vdev.clone();
         let zone_ids = fsm.open_zone_ids().cloned().collect::<Vec<_>>();
         let mut futs = zone_ids.iter().map(|&zone_id| {
             let (gap, fut) = self.vdev.flush_zone(zone_id);
@@ -777,23 +962,7 @@ impl Cluster {
         }).collect::<FuturesUnordered<BoxVdevFut>>();
         // Since FreeSpaceMap::waste_space is synchronous, we can serialize the
         // FSM here; we don't need to copy it into a Future's continuation.
-        let sm_futs = fsm.serialize()
-        .map(|(block, dbs)| {
-            let db = dbs.try_const().unwrap();
-            // TODO: copy the last block's worth of buffer, rather than merely
-            // pad it, so that vdev_block won't have to.  Better to do it here,
-            // because vdev_raid duplicates the command for each disk.
-            let sglist = if db.len() % BYTES_PER_LBA != 0 {
-                // This can happen in the last block of the spacemap.  Pad out.
-                let padlen = BYTES_PER_LBA - db.len() % BYTES_PER_LBA;
-                let pad = ZERO_REGION.try_const().unwrap().slice_to(padlen);
-                vec![db, pad]
-            } else {
-                vec![db]
-            };
-            vdev2.write_spacemap(sglist, idx, block)
-        });
-        futs.extend(sm_futs);
+        futs.extend(self.write_spacemap(&fsm, idx));
         let fut = futs.try_collect::<Vec<_>>()
         .map_ok(drop);
         fsm.clear_dirty_zones();
@@ -808,6 +977,9 @@ impl Cluster {
     /// In particular, it is not allowed to delete across zone boundaries.
     pub fn free(&self, lba: LbaT, length: LbaT) -> BoxVdevFut
     {
+        if self.readonly {
+            return Box::pin(future::err(Error::EROFS));
+        }
         let start_zone = self.vdev.lba2zone(lba).expect(
             "Can't free from inter-zone padding");
         #[cfg(test)]
@@ -819,8 +991,11 @@ impl Cluster {
         }
         let mut fsm = self.fsm.write().unwrap();
         fsm.free(start_zone, length);
-        // Erase the zone if it is fully freed
-        if fsm.is_closed(start_zone) && fsm.in_use(start_zone) == 0 {
+        // Erase the zone if it is fully freed, unless a checkpoint still
+        // needs its contents.
+        if fsm.is_closed(start_zone) && fsm.in_use(start_zone) == 0 &&
+            !self.checkpoint.load(Ordering::Relaxed)
+        {
             drop(fsm);
             Box::pin(self.erase_zone(start_zone))
         } else {
@@ -833,7 +1008,20 @@ impl Cluster {
     fn new(args: (FreeSpaceMap, Arc<dyn VdevRaidApi>)) -> Self {
         let (fsm, vdev) = args;
         let allocated_space = fsm.allocated_total().into();
-        Cluster{allocated_space, fsm: RwLock::new(fsm), vdev}
+        let open_zone_budget = vdev.max_open_zones()
+            .unwrap_or(u32::MAX)
+            .min(DEFAULT_OPEN_ZONE_BUDGET);
+        Cluster{
+            allocated_space,
+            checkpoint: AtomicBool::new(false),
+            fsm: RwLock::new(fsm),
+            open_zone_budget,
+            readonly: false,
+            zones_opened: AtomicU64::new(0),
+            zones_closed: AtomicU64::new(0),
+            zones_evicted: AtomicU64::new(0),
+            vdev
+        }
     }
 
     /// Open a `Cluster` from an already opened
@@ -843,10 +1031,78 @@ impl Cluster {
     /// construct other vdevs stacked on top.
     pub async fn open(vdev_raid: Arc<dyn VdevRaidApi>) -> Result<Self>
     {
-        FreeSpaceMap::open(vdev_raid).await
+        FreeSpaceMap::open(vdev_raid, 0, false).await
             .map(Cluster::new)
     }
 
+    /// Open a `Cluster`, rewinding it to the state recorded by the second
+    /// label.  That's the pool's checkpoint, if it has one, or else the
+    /// previous transaction group.
+    ///
+    /// The `FreeSpaceMap` will be read from the second spacemap, and
+    /// any zones that were written since the checkpoint will be erased.
+    ///
+    /// If the current spacemap is unreadable, then there's no telling which
+    /// zones were written since, so every zone that is empty in the second
+    /// spacemap will be erased.
+    pub async fn open_rewind(vdev_raid: Arc<dyn VdevRaidApi>) -> Result<Self>
+    {
+        let (mut fsm, vdev) = FreeSpaceMap::open(vdev_raid, 1, false).await?;
+        let current = FreeSpaceMap::open(vdev.clone(), 0, true).await
+            .map(|(current, _)| current)
+            .ok();
+        (0..vdev.zones())
+            .filter(|&zid| match current {
+                Some(ref c) => fsm.is_empty(zid) && !c.is_empty(zid),
+                None => fsm.is_empty(zid)
+            }).map(|zid| vdev.erase_zone(zid))
+            .collect::<FuturesUnordered<_>>()
+            .try_collect::<Vec<_>>()
+            .await?;
+        // The first spacemap still describes the abandoned state, so it must
+        // be completely rewritten.
+        fsm.dirty_all();
+        Ok(Cluster::new((fsm, vdev)))
+    }
+
+    /// Open a `Cluster` for read-only access.
+    ///
+    /// Open zones will not be reopened, and any attempt to write to, free
+    /// from, or flush the `Cluster` will fail with `EROFS`.
+    pub async fn open_readonly(vdev_raid: Arc<dyn VdevRaidApi>)
+        -> Result<Self>
+    {
+        Cluster::open_readonly_idx(vdev_raid, 0).await
+    }
+
+    async fn open_readonly_idx(vdev_raid: Arc<dyn VdevRaidApi>, idx: u32)
+        -> Result<Self>
+    {
+        FreeSpaceMap::open(vdev_raid, idx, true).await
+            .map(|args| {
+                let mut cluster = Cluster::new(args);
+                cluster.readonly = true;
+                cluster
+            })
+    }
+
+    /// Open a `Cluster` for read-only access, using the spacemap from the
+    /// second label.
+    ///
+    /// Since nothing will be written, no zones need to be erased.
+    pub async fn open_readonly_rewind(vdev_raid: Arc<dyn VdevRaidApi>)
+        -> Result<Self>
+    {
+        Cluster::open_readonly_idx(vdev_raid, 1).await
+    }
+
+    /// If `lba` lies within an open zone, return the temperature of the data
+    /// being written to that zone.
+    pub fn open_zone_temperature(&self, lba: LbaT) -> Option<Temperature> {
+        let zid = self.vdev.lba2zone(lba)?;
+        self.fsm.read().unwrap().open_zones.get(&zid).map(|oz| oz.temp)
+    }
+
     /// Returns the "best" number of operations to queue to this `Cluster`.  A
     /// smaller number may result in inefficient use of resources, or even
     /// starvation.  A larger number won't hurt, but won't accrue any economies
@@ -861,17 +1117,65 @@ impl Cluster {
         self.vdev.read_at(buf, lba)
     }
 
+    /// Stop protecting the pool's checkpoint, after it has been discarded.
+    ///
+    /// Any zones that were fully freed while the checkpoint existed will be
+    /// erased.
+    pub fn release_checkpoint(&self) -> BoxVdevFut
+    {
+        self.checkpoint.store(false, Ordering::Relaxed);
+        let fsm = self.fsm.read().unwrap();
+        let unused = (0..fsm.zones.len() as ZoneT)
+            .filter(|&zid| fsm.is_closed(zid) && fsm.in_use(zid) == 0)
+            .collect::<Vec<_>>();
+        drop(fsm);
+        let fut = unused.into_iter()
+            .map(|zid| self.erase_zone(zid))
+            .collect::<FuturesUnordered<_>>()
+            .try_collect::<Vec<_>>()
+            .map_ok(drop);
+        Box::pin(fut)
+    }
+
     /// Return approximately the usable space of the Cluster in LBAs.
     pub fn size(&self) -> LbaT {
         self.vdev.size()
     }
 
+    /// Describe the cluster's layout and usage, for capacity planning.
+    pub fn shape(&self) -> ClusterShape {
+        ClusterShape {
+            used: self.used(),
+            ..self.vdev.shape()
+        }
+    }
+
     /// Sync the `Cluster`, ensuring that all data written so far reaches stable
     /// storage.
     pub fn sync_all(&self) -> BoxVdevFut {
         self.vdev.sync_all()
     }
 
+    /// Protect the pool's checkpoint, if it has one, after importing it.
+    ///
+    /// No zones will be erased until the checkpoint is discarded.
+    pub fn set_checkpoint(&self) {
+        self.checkpoint.store(true, Ordering::Relaxed);
+    }
+
+    /// Change the maximum number of simultaneously open zones.
+    ///
+    /// The budget can never exceed the limit imposed by the underlying
+    /// devices, nor be less than one.  Already open zones in excess of the new
+    /// budget will be finished lazily, on the next allocation that needs a
+    /// new zone.
+    pub fn set_open_zone_budget(&mut self, budget: u32) {
+        self.open_zone_budget = self.vdev.max_open_zones()
+            .unwrap_or(u32::MAX)
+            .min(budget)
+            .max(1);
+    }
+
     /// How many blocks are currently in use?
     pub fn used(&self) -> LbaT {
         self.fsm.read().unwrap().in_use_total()
@@ -882,24 +1186,56 @@ impl Cluster {
         self.vdev.uuid()
     }
 
+    /// Return statistics about zone churn
+    pub fn zone_stats(&self) -> ZoneStats {
+        ZoneStats {
+            opened: self.zones_opened.load(Ordering::Relaxed),
+            closed: self.zones_closed.load(Ordering::Relaxed),
+            evicted: self.zones_evicted.load(Ordering::Relaxed),
+        }
+    }
+
     /// Write a buffer to the cluster
     ///
+    /// The buffer will only be placed in a zone holding data of the same
+    /// `temp`erature.
+    ///
     /// # Returns
     ///
     /// The LBA where the data will be written, and a
     /// `Future` for the operation in progress.
-    pub fn write(&self, buf: IoVec, txg: TxgT)
+    pub fn write(&self, buf: IoVec, temp: Temperature, txg: TxgT)
         -> Result<(LbaT, BoxVdevFut)>
     {
         // Outline:
-        // 1) Try allocating in an open zone
+        // 1) Try allocating in an open zone of the same temperature
         // 2) If that doesn't work, try opening a new one, and allocating from
-        //    that.
+        //    that.  If that would exceed the open zone budget, first finish the
+        //    least recently written open zone.
         // 3) If that doesn't work, return ENOSPC
         // 4) write to the vdev
+        if self.readonly {
+            return Err(Error::EROFS);
+        }
         let space = div_roundup(buf.len(), BYTES_PER_LBA) as LbaT;
-        let (alloc_result, nearly_full_zones) =
-            self.fsm.write().unwrap().try_allocate(space);
+        let (alloc_result, mut nearly_full_zones) =
+            self.fsm.write().unwrap().try_allocate(space, temp);
+        if alloc_result.is_none() {
+            let fsm = self.fsm.read().unwrap();
+            let nopen = fsm.open_zones.len() - nearly_full_zones.len();
+            let budget = self.open_zone_budget as usize;
+            // Don't bother evicting anything if there's no zone to open
+            if nopen >= budget && fsm.find_empty().is_some() {
+                let excess = nopen + 1 - budget;
+                let victims = fsm.lru_open_zones(&nearly_full_zones)
+                    .into_iter()
+                    .take(excess)
+                    .collect::<Vec<_>>();
+                self.zones_evicted.fetch_add(victims.len() as u64,
+                    Ordering::Relaxed);
+                nearly_full_zones.extend(victims);
+            }
+        }
         let futs = self.close_zones(&nearly_full_zones, txg);
         let vdev2: Arc<dyn VdevRaidApi> = self.vdev.clone();
         let vdev3 = self.vdev.clone();
@@ -910,10 +1246,12 @@ impl Cluster {
             let empty_zone = self.fsm.read().unwrap().find_empty();
             empty_zone.and_then(move |zone_id| {
                 let zl = vdev2.zone_limits(zone_id);
-                let e = self.fsm.write().unwrap()
-                    .open_zone(zone_id, zl.0, zl.1, space, txg);
+                let mut fsm = self.fsm.write().unwrap();
+                let e = fsm.open_zone(zone_id, zl.0, zl.1, space, txg);
                 match e {
                     Ok(Some((zone_id, lba))) => {
+                        fsm.open_zones.get_mut(&zone_id).unwrap().temp = temp;
+                        self.zones_opened.fetch_add(1, Ordering::Relaxed);
                         let fut = Box::pin(vdev2.open_zone(zone_id)) as BoxVdevFut;
                         Some((zone_id, lba, fut))
                     },
@@ -943,6 +1281,27 @@ impl Cluster {
     {
         self.vdev.write_label(labeller)
     }
+
+    /// Write the dirty portions of `fsm` to the `idx`th spacemap.
+    fn write_spacemap(&self, fsm: &FreeSpaceMap, idx: u32) -> Vec<BoxVdevFut>
+    {
+        fsm.serialize()
+        .map(|(block, dbs)| {
+            let db = dbs.try_const().unwrap();
+            // TODO: copy the last block's worth of buffer, rather than merely
+            // pad it, so that vdev_block won't have to.  Better to do it here,
+            // because vdev_raid duplicates the command for each disk.
+            let sglist = if db.len() % BYTES_PER_LBA != 0 {
+                // This can happen in the last block of the spacemap.  Pad out.
+                let padlen = BYTES_PER_LBA - db.len() % BYTES_PER_LBA;
+                let pad = ZERO_REGION.try_const().unwrap().slice_to(padlen);
+                vec![db, pad]
+            } else {
+                vec![db]
+            };
+            self.vdev.write_spacemap(sglist, idx, block)
+        }).collect()
+    }
 }
 
 // LCOV_EXCL_START
@@ -955,7 +1314,8 @@ mod open_zone {
     // pet kcov
     #[test]
     fn debug() {
-        let oz = OpenZone{start: 0, allocated_blocks: 0};
+        let oz = OpenZone{start: 0, allocated_blocks: 0, last_write: 0,
+                          temp: Temperature::Hot};
         format!("{oz:?}");
     }
 }
@@ -967,6 +1327,7 @@ mod cluster {
     use itertools::Itertools;
     use mockall::{Sequence, predicate::*};
     use pretty_assertions::assert_eq;
+    use rstest::rstest;
     use std::iter;
 
     #[tokio::test]
@@ -1014,12 +1375,12 @@ mod cluster {
         let dbs = DivBufShared::from(vec![0u8; 4096]);
         let db0 = dbs.try_const().unwrap();
         let db1 = db0.clone();
-        let (lba, fut1) = cluster.write(db0, TxgT::from(0))
+        let (lba, fut1) = cluster.write(db0, Temperature::Hot, TxgT::from(0))
             .expect("write failed early");
         fut1.await.unwrap();
         assert_eq!(cluster.allocated(), 1);
         // Write a 2nd time so the first zone will get closed
-        let (_, fut1) = cluster.write(db1, TxgT::from(0))
+        let (_, fut1) = cluster.write(db1, Temperature::Hot, TxgT::from(0))
             .expect("write failed early");
         fut1.await.unwrap();
         assert_eq!(cluster.allocated(), 2);
@@ -1073,12 +1434,12 @@ mod cluster {
         let dbs1 = DivBufShared::from(vec![0u8; 8192]);
         let db0 = dbs0.try_const().unwrap();
         let db1 = dbs1.try_const().unwrap();
-        let (lba, fut1) = cluster.write(db0, TxgT::from(0))
+        let (lba, fut1) = cluster.write(db0, Temperature::Hot, TxgT::from(0))
             .expect("write failed early");
         fut1.await.unwrap();
         assert_eq!(cluster.allocated(), 1);
         // Write a larger buffer so the first zone will get closed
-        let (_, fut2) = cluster.write(db1, TxgT::from(0))
+        let (_, fut2) = cluster.write(db1, Temperature::Hot, TxgT::from(0))
                 .expect("write failed early");
         fut2.await.unwrap();
         assert_eq!(cluster.allocated(), 4);
@@ -1134,16 +1495,16 @@ mod cluster {
         let db0 = dbs0.try_const().unwrap();
         let db1 = dbs0.try_const().unwrap();
         let db2 = dbs1.try_const().unwrap();
-        let (lba, fut1) = cluster.write(db0, TxgT::from(0))
+        let (lba, fut1) = cluster.write(db0, Temperature::Hot, TxgT::from(0))
             .expect("write failed early");
         fut1.await.unwrap();
         assert_eq!(cluster.allocated(), 1);
-        let (_, fut2) = cluster.write(db1, TxgT::from(0))
+        let (_, fut2) = cluster.write(db1, Temperature::Hot, TxgT::from(0))
             .expect("write failed early");
         fut2.await.unwrap();
         assert_eq!(cluster.allocated(), 2);
         // Write a larger buffer so the first zone will get closed
-        let (_, fut3) = cluster.write(db2, TxgT::from(0))
+        let (_, fut3) = cluster.write(db2, Temperature::Hot, TxgT::from(0))
             .expect("write failed early");
         fut3.await.unwrap();
         assert_eq!(cluster.allocated(), 4);
@@ -1188,14 +1549,30 @@ mod cluster {
         drop(cluster.free(1000, 10));
     }
 
+    // A read-only Cluster must refuse to write, without touching the vdev
+    #[test]
+    fn write_readonly() {
+        let mut vr = MockVdevRaid::default();
+        vr.expect_zones()
+            .return_const(32768u32);
+        let fsm = FreeSpaceMap::new(vr.zones());
+        let mut cluster = Cluster::new((fsm, Arc::new(vr)));
+        cluster.readonly = true;
+        let dbs = DivBufShared::from(vec![0u8; BYTES_PER_LBA]);
+        let db = dbs.try_const().unwrap();
+        let r = cluster.write(db, Temperature::Hot, TxgT::from(0));
+        assert_eq!(r.err().unwrap(), Error::EROFS);
+    }
+
     // FreeSpaceMap::open with the following conditions:
     // A closed zone with no freed blocks
     // A closed zone with some freed blocks
     // An empty zone before the maximum open or full zone
     // An open zone with some freed blocks
     // A trailing empty zone
-    #[test]
-    fn freespacemap_open() {
+    // If readonly, the open zone should not be reopened on disk.
+    #[rstest]
+    fn freespacemap_open(#[values(false, true)] readonly: bool) {
         // Serialized spacemap
         const SPACEMAP: [u8; 96] = [
             0xb2, 0x99, 0x6a, 0xb1, 0xa0, 0x6f, 0xb4, 0x9c, // Checksum
@@ -1229,10 +1606,14 @@ mod cluster {
                  Box::pin(future::ok(()))
             });
 
+        vr.expect_check_write_pointers()
+            .times(usize::from(!readonly))
+            .with(eq(3), eq(77))
+            .returning(|_, _| Ok(true));
         vr.expect_reopen_zone()
-            .once()
+            .times(usize::from(!readonly))
             .with(eq(3), eq(77))
-            .return_once(|_, _| Box::pin(future::ok(())));
+            .returning(|_, _| Box::pin(future::ok(())));
         vr.expect_zone_limits()
             .with(eq(0))
             .return_const((4, 96));
@@ -1248,7 +1629,7 @@ mod cluster {
         vr.expect_zone_limits()
             .with(eq(4))
             .return_const((404, 496));
-        let (fsm, _mock_vr) = FreeSpaceMap::open(Arc::new(vr))
+        let (fsm, _mock_vr) = FreeSpaceMap::open(Arc::new(vr), 0, readonly)
             .now_or_never()
             .unwrap()
             .unwrap();
@@ -1270,6 +1651,54 @@ mod cluster {
         assert_eq!(0, fsm.dirty.count_ones(..));
     }
 
+    // If a device's write pointer for an open zone doesn't agree with the
+    // spacemap, the rest of the zone should be wasted rather than appended to.
+    #[test]
+    fn freespacemap_open_write_pointer_mismatch() {
+        const SPACEMAP: [u8; 48] = [
+            0xaf, 0x4d, 0xc5, 0x04, 0x15, 0xfb, 0x2b, 0xc8, // Checksum
+            2, 0, 0, 0, 0, 0, 0, 0,         // 2 entries
+            0, 0, 0, 0,                     // zone0: allocated_blocks
+            0, 0, 0, 0,                     // zone0: freed blocks
+            0, 0, 0, 0, 0, 0, 0, 0,         // zone0 txgs: 0..0
+            77, 0, 0, 0,                    // zone1: allocated_blocks
+            33, 0, 0, 0,                    // zone1: freed blocks
+            2, 0, 0, 0, 255, 255, 255, 255, // zone1 txgs: 2..u32::MAX
+        ];
+        let mut vr = MockVdevRaid::default();
+        vr.expect_zones()
+            .return_const(2u32);
+        vr.expect_read_spacemap()
+            .with(always(), eq(0))
+            .once()
+            .returning(|mut dbm, _idx| {
+                 dbm[0..48].copy_from_slice(&SPACEMAP[..]);
+                 dbm[48..4096].iter_mut().set_from(iter::repeat(0));
+                 Box::pin(future::ok(()))
+            });
+        vr.expect_check_write_pointers()
+            .once()
+            .with(eq(1), eq(77))
+            .returning(|_, _| Ok(false));
+        vr.expect_reopen_zone()
+            .once()
+            .with(eq(1), eq(77))
+            .returning(|_, _| Box::pin(future::ok(())));
+        vr.expect_zone_limits()
+            .with(eq(1))
+            .return_const((104, 196));
+        let (fsm, _mock_vr) = FreeSpaceMap::open(Arc::new(vr), 0, false)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        let oz = &fsm.open_zones[&1];
+        assert_eq!(oz.allocated_blocks, 92);
+        assert_eq!(fsm.zones[1].freed_blocks, 33 + 92 - 77);
+        assert_eq!(fsm.available(1), 0);
+        // The wasted space must be recorded in the next spacemap
+        assert!(fsm.dirty.count_ones(..) > 0);
+    }
+
     // FreeSpaceMap::open with more zones that can fit into a single block
     #[test]
     fn freespacemap_open_300_zones() {
@@ -1311,7 +1740,7 @@ mod cluster {
                  (100 * i + 4, 100 * i + 96)
              });
 
-        let (fsm, _mock_vr) = FreeSpaceMap::open(Arc::new(vr))
+        let (fsm, _mock_vr) = FreeSpaceMap::open(Arc::new(vr), 0, false)
             .now_or_never()
             .unwrap()
             .unwrap();
@@ -1347,7 +1776,9 @@ mod cluster {
                 Box::pin(future::ok(()))
             });
 
-        let r = FreeSpaceMap::open(Arc::new(vr)).now_or_never().unwrap();
+        let r = FreeSpaceMap::open(Arc::new(vr), 0, false)
+            .now_or_never()
+            .unwrap();
         assert_eq!(Error::EINTEGRITY, r.err().unwrap());
     }
 
@@ -1377,13 +1808,16 @@ mod cluster {
         let cluster = Cluster::new((fsm, Arc::new(vr)));
         assert_eq!(cluster.find_closed_zone(0).unwrap(),
             ClosedZone{zid: 0, start: 0, freed_blocks: 1, total_blocks: 1,
-                       txgs: TxgT::from(0)..TxgT::from(1)});
+                       txgs: TxgT::from(0)..TxgT::from(1),
+                       temp: Temperature::Hot});
         assert_eq!(cluster.find_closed_zone(1).unwrap(),
             ClosedZone{zid: 3, start: 3, freed_blocks: 1, total_blocks: 1,
-                       txgs: TxgT::from(1)..TxgT::from(4)});
+                       txgs: TxgT::from(1)..TxgT::from(4),
+                       temp: Temperature::Hot});
         assert_eq!(cluster.find_closed_zone(4).unwrap(),
             ClosedZone{zid: 4, start: 4, freed_blocks: 1, total_blocks: 1,
-                       txgs: TxgT::from(0)..TxgT::from(1)});
+                       txgs: TxgT::from(0)..TxgT::from(1),
+                       temp: Temperature::Hot});
         assert!(cluster.find_closed_zone(5).is_none());
     }
 
@@ -1413,7 +1847,8 @@ mod cluster {
 
         let dbs = DivBufShared::from(vec![0u8; 4096]);
         let db0 = dbs.try_const().unwrap();
-        let _ = cluster.write(db0, TxgT::from(0)).expect("write failed early");
+        let _ = cluster.write(db0, Temperature::Hot,
+            TxgT::from(0)).expect("write failed early");
     }
 
     // During transaction sync, Cluster::flush should flush all open VdevRaid
@@ -1464,7 +1899,7 @@ mod cluster {
 
         let dbs = DivBufShared::from(vec![0u8; 4096]);
         let db0 = dbs.try_const().unwrap();
-        let (_, fut) = cluster.write(db0, TxgT::from(0))
+        let (_, fut) = cluster.write(db0, Temperature::Hot, TxgT::from(0))
             .expect("write failed early");
         fut.await.unwrap();
         assert_eq!(cluster.allocated(), 1);
@@ -1489,7 +1924,8 @@ mod cluster {
         let cluster = Cluster::new((fsm, Arc::new(vr)));
 
         let dbs = DivBufShared::from(vec![0u8; 8192]);
-        let result = cluster.write(dbs.try_const().unwrap(), TxgT::from(0));
+        let result = cluster.write(dbs.try_const().unwrap(), Temperature::Hot,
+            TxgT::from(0));
         assert_eq!(result.err().unwrap(), Error::ENOSPC);
         assert_eq!(cluster.allocated(), 0);
     }
@@ -1507,7 +1943,8 @@ mod cluster {
         let cluster = Cluster::new((fsm, Arc::new(vr)));
 
         let dbs = DivBufShared::from(vec![0u8; 4096]);
-        let result = cluster.write(dbs.try_const().unwrap(), TxgT::from(0));
+        let result = cluster.write(dbs.try_const().unwrap(), Temperature::Hot,
+            TxgT::from(0));
         assert_eq!(result.err().unwrap(), Error::ENOSPC);
         assert_eq!(cluster.allocated(), 0);
     }
@@ -1536,7 +1973,7 @@ mod cluster {
 
         let dbs = DivBufShared::from(vec![0u8; 4096]);
         let db0 = dbs.try_const().unwrap();
-        let (lba, fut) = cluster.write(db0, TxgT::from(0))
+        let (lba, fut) = cluster.write(db0, Temperature::Hot, TxgT::from(0))
             .expect("write failed early");
         fut.await.unwrap();
         assert_eq!(cluster.allocated(), 1);
@@ -1576,17 +2013,63 @@ mod cluster {
         let db0 = dbs.try_const().unwrap();
         let db1 = dbs.try_const().unwrap();
         let cluster_ref = &cluster;
-        let (_, fut0) = cluster.write(db0, TxgT::from(0))
+        let (_, fut0) = cluster.write(db0, Temperature::Hot, TxgT::from(0))
             .expect("Cluster::write");
         fut0.await.unwrap();
         assert_eq!(cluster.allocated(), 1);
-        let (lba1, fut1) = cluster_ref.write(db1, TxgT::from(0))
+        let (lba1, fut1) = cluster_ref.write(db1, Temperature::Hot,
+            TxgT::from(0))
             .expect("Cluster::write");
         assert_eq!(lba1, 1);
         fut1.await.expect("write failed");
         assert_eq!(cluster.allocated(), 2);
     }
 
+    // Cold data should not be written into a zone that is open for hot data
+    #[tokio::test]
+    async fn write_cold_with_hot_open_zone() {
+        let mut vr = MockVdevRaid::default();
+        vr.expect_zones()
+            .return_const(32768u32);
+        vr.expect_zone_limits()
+            .with(eq(0))
+            .return_const((0, 1000));
+        vr.expect_zone_limits()
+            .with(eq(1))
+            .return_const((1000, 2000));
+        vr.expect_open_zone()
+            .once()
+            .with(eq(0))
+            .return_once(|_| Box::pin(future::ok(())));
+        vr.expect_open_zone()
+            .once()
+            .with(eq(1))
+            .return_once(|_| Box::pin(future::ok(())));
+        vr.expect_write_at()
+            .withf(|_, zone, lba| *zone == 0 && *lba == 0)
+            .once()
+            .return_once(|_, _, _| Box::pin(future::ok(())));
+        vr.expect_write_at()
+            .withf(|_, zone, lba| *zone == 1 && *lba == 1000)
+            .once()
+            .return_once(|_, _, _| Box::pin(future::ok(())));
+        let fsm = FreeSpaceMap::new(vr.zones());
+        let cluster = Cluster::new((fsm, Arc::new(vr)));
+
+        let dbs = DivBufShared::from(vec![0u8; 4096]);
+        let db0 = dbs.try_const().unwrap();
+        let db1 = dbs.try_const().unwrap();
+        let (lba0, fut0) = cluster.write(db0, Temperature::Hot, TxgT::from(0))
+            .expect("Cluster::write");
+        fut0.await.unwrap();
+        assert_eq!(lba0, 0);
+        let (lba1, fut1) = cluster.write(db1, Temperature::Cold, TxgT::from(0))
+            .expect("Cluster::write");
+        fut1.await.unwrap();
+        assert_eq!(lba1, 1000);
+        assert_eq!(cluster.zone_stats().opened, 2);
+    }
+
     // When one zone is too full to satisfy an allocation, it should be closed
     // and a new zone opened.
     #[tokio::test]
@@ -1633,16 +2116,101 @@ mod cluster {
         let db0 = dbs.try_const().unwrap();
         let db1 = dbs.try_const().unwrap();
         let cluster_ref = &cluster;
-        let (_, fut0) = cluster.write(db0, TxgT::from(0))
+        let (_, fut0) = cluster.write(db0, Temperature::Hot, TxgT::from(0))
             .expect("Cluster::write");
         fut0.await.unwrap();
         assert_eq!(cluster.allocated(), 2);
-        let (lba1, fut1) = cluster_ref.write(db1, TxgT::from(0))
+        let (lba1, fut1) = cluster_ref.write(db1, Temperature::Hot,
+            TxgT::from(0))
             .expect("Cluster::write");
         assert_eq!(lba1, 3);
         fut1.await.expect("write failed");
         assert_eq!(cluster.allocated(), 5);
     }
+
+    // The open zone budget may be raised or lowered, but never to zero
+    #[test]
+    fn set_open_zone_budget() {
+        let mut vr = MockVdevRaid::default();
+        vr.expect_zones()
+            .return_const(32768u32);
+        let fsm = FreeSpaceMap::new(vr.zones());
+        let mut cluster = Cluster::new((fsm, Arc::new(vr)));
+        cluster.set_open_zone_budget(16);
+        assert_eq!(cluster.open_zone_budget, 16);
+        cluster.set_open_zone_budget(0);
+        assert_eq!(cluster.open_zone_budget, 1);
+    }
+
+    // Opening a zone beyond the open zone budget should first finish the
+    // least recently written open zone, even if it isn't full.
+    #[tokio::test]
+    async fn write_over_budget() {
+        let mut vr = MockVdevRaid::default();
+        vr.expect_zones()
+            .return_const(32768u32);
+        vr.expect_zone_limits()
+            .with(eq(2))
+            .return_const((2000, 3000));
+        vr.expect_finish_zone()
+            .with(eq(0))
+            .once()
+            .return_once(|_| Box::pin(future::ok(())));
+        vr.expect_open_zone()
+            .with(eq(2))
+            .once()
+            .return_once(|_| Box::pin(future::ok(())));
+        vr.expect_write_at()
+            .withf(|_, zone, lba| *zone == 2 && *lba == 2000)
+            .once()
+            .return_once(|_, _, _| Box::pin(future::ok(())));
+        let txg = TxgT::from(0);
+        let mut fsm = FreeSpaceMap::new(vr.zones());
+        fsm.open_zone(0, 0, 1000, 0, txg).unwrap();
+        fsm.open_zone(1, 1000, 2000, 0, txg).unwrap();
+        fsm.open_zones.get_mut(&1).unwrap().temp = Temperature::Cold;
+        let mut cluster = Cluster::new((fsm, Arc::new(vr)));
+        cluster.open_zone_budget = 2;
+
+        let dbs = DivBufShared::from(vec![0u8; 4096]);
+        let db = dbs.try_const().unwrap();
+        let (lba, fut) = cluster.write(db, Temperature::Metadata, txg)
+            .expect("Cluster::write");
+        assert_eq!(lba, 2000);
+        fut.await.unwrap();
+        let stats = cluster.zone_stats();
+        assert_eq!(stats.opened, 1);
+        assert_eq!(stats.closed, 1);
+        assert_eq!(stats.evicted, 1);
+        let fsm = cluster.fsm.read().unwrap();
+        let open = fsm.open_zone_ids().cloned().collect::<Vec<_>>();
+        assert_eq!(open, vec![1, 2]);
+    }
+
+    // If there's no empty zone to open, then being over the open zone budget
+    // shouldn't cause any open zone to be finished.
+    #[test]
+    fn write_over_budget_enospc() {
+        let mut vr = MockVdevRaid::default();
+        vr.expect_zones()
+            .return_const(2u32);
+        vr.expect_finish_zone()
+            .never();
+        let txg = TxgT::from(0);
+        let mut fsm = FreeSpaceMap::new(vr.zones());
+        fsm.open_zone(0, 0, 1000, 0, txg).unwrap();
+        fsm.open_zone(1, 1000, 2000, 0, txg).unwrap();
+        fsm.open_zones.get_mut(&1).unwrap().temp = Temperature::Cold;
+        let mut cluster = Cluster::new((fsm, Arc::new(vr)));
+        cluster.open_zone_budget = 2;
+
+        let dbs = DivBufShared::from(vec![0u8; 4096]);
+        let db = dbs.try_const().unwrap();
+        let r = cluster.write(db, Temperature::Metadata, txg);
+        assert_eq!(r.err(), Some(Error::ENOSPC));
+        assert_eq!(cluster.zone_stats(), ZoneStats::default());
+        assert_eq!(cluster.fsm.read().unwrap().open_zones.len(), 2);
+    }
 }
 
 mod free_space_map {
@@ -1705,7 +2273,7 @@ mod free_space_map {
         assert_eq!(&[0b1], fsm.dirty.as_slice());
 
         // Allocating should dirty a zone, too
-        fsm.try_allocate(64);
+        fsm.try_allocate(64, Temperature::Hot);
         assert_eq!(&[0b1], fsm.dirty.as_slice());
 
         // Wasting space should dirty a zone, too
@@ -2013,6 +2581,20 @@ r#"FreeSpaceMap: 1 Zones: 1 Closed, 0 Empty, 0 Open
         assert_eq!(LbaT::from(fsm.zones[zid as usize].freed_blocks), space);
     }
 
+    #[test]
+    fn lru_open_zones() {
+        let txg = TxgT::from(0);
+        let mut fsm = FreeSpaceMap::new(32768);
+        fsm.open_zone(0, 0, 1000, 100, txg).unwrap();
+        fsm.open_zone(1, 1000, 2000, 900, txg).unwrap();
+        fsm.open_zone(2, 2000, 3000, 500, txg).unwrap();
+        assert_eq!(fsm.lru_open_zones(&[]), vec![0, 1, 2]);
+        // Allocating from zone 0 makes it the most recently written
+        assert_eq!(fsm.try_allocate(10, Temperature::Hot).0, Some((0, 100)));
+        assert_eq!(fsm.lru_open_zones(&[]), vec![1, 2, 0]);
+        assert_eq!(fsm.lru_open_zones(&[1]), vec![2, 0]);
+    }
+
     #[test]
     fn open() {
         let zid: ZoneT = 0;
@@ -2150,7 +2732,7 @@ r#"FreeSpaceMap: 1 Zones: 1 Closed, 0 Empty, 0 Open
         let mut fsm = FreeSpaceMap::new(32768);
         let txg = TxgT::from(0);
         assert!(fsm.open_zone(zid, 0, 1000, 0, txg).unwrap().is_none());
-        let (res, full_zones) = fsm.try_allocate(64);
+        let (res, full_zones) = fsm.try_allocate(64, Temperature::Hot);
         assert_eq!(res, Some((zid, 0)));
         assert!(full_zones.is_empty());
         assert_eq!(fsm.open_zones[&zid].write_pointer(), 64);
@@ -2163,7 +2745,7 @@ r#"FreeSpaceMap: 1 Zones: 1 Closed, 0 Empty, 0 Open
         let txg = TxgT::from(0);
         let mut fsm = FreeSpaceMap::new(32768);
         assert!(fsm.open_zone(zid, 0, 1000, 0, txg).unwrap().is_none());
-        assert!(fsm.try_allocate(2000).0.is_none());
+        assert!(fsm.try_allocate(2000, Temperature::Hot).0.is_none());
     }
 
     #[test]
@@ -2174,13 +2756,47 @@ r#"FreeSpaceMap: 1 Zones: 1 Closed, 0 Empty, 0 Open
         // Pretend that zone 0 is too small for our allocation, but zone 1 isn't
         assert!(fsm.open_zone(0, 0, 10, 0, txg).unwrap().is_none());
         assert!(fsm.open_zone(zid, 10, 1000, 0, txg).unwrap().is_none());
-        let (res, full_zones) = fsm.try_allocate(64);
+        let (res, full_zones) = fsm.try_allocate(64, Temperature::Hot);
         assert_eq!(res, Some((zid, 10)));
         assert_eq!(full_zones, vec![0]);
         assert_eq!(fsm.open_zones[&0].write_pointer(), 0);
         assert_eq!(fsm.open_zones[&zid].write_pointer(), 74);
     }
 
+    // Allocations should never be satisfied from zones of a different
+    // temperature, nor should such zones be reported as nearly full.
+    #[test]
+    fn try_allocate_other_temperature() {
+        let mut fsm = FreeSpaceMap::new(32768);
+        let txg = TxgT::from(0);
+        assert!(fsm.open_zone(0, 0, 1000, 0, txg).unwrap().is_none());
+        assert!(fsm.open_zone(1, 1000, 2000, 0, txg).unwrap().is_none());
+        fsm.open_zones.get_mut(&1).unwrap().temp = Temperature::Cold;
+        let (res, full_zones) = fsm.try_allocate(64, Temperature::Cold);
+        assert_eq!(res, Some((1, 1000)));
+        assert!(full_zones.is_empty());
+        let (res, full_zones) = fsm.try_allocate(64, Temperature::Hot);
+        assert_eq!(res, Some((0, 0)));
+        assert!(full_zones.is_empty());
+        assert_eq!(fsm.open_zones[&0].write_pointer(), 64);
+        assert_eq!(fsm.open_zones[&1].write_pointer(), 1064);
+    }
+
+    // A closed zone should remember the temperature it had while open
+    #[test]
+    fn finish_zone_temperature() {
+        let mut fsm = FreeSpaceMap::new(32768);
+        let txg = TxgT::from(0);
+        assert!(fsm.open_zone(0, 0, 1000, 0, txg).unwrap().is_none());
+        assert!(fsm.open_zone(1, 1000, 2000, 0, txg).unwrap().is_none());
+        fsm.open_zones.get_mut(&1).unwrap().temp = Temperature::Metadata;
+        fsm.finish_zone(0, txg);
+        fsm.finish_zone(1, txg);
+        assert_eq!(fsm.find_closed_zone(0).unwrap().temp, Temperature::Hot);
+        assert_eq!(fsm.find_closed_zone(1).unwrap().temp,
+                   Temperature::Metadata);
+    }
+
     #[test]
     fn try_allocate_only_closed_zones() {
         let zid: ZoneT = 0;
@@ -2188,13 +2804,13 @@ r#"FreeSpaceMap: 1 Zones: 1 Closed, 0 Empty, 0 Open
         assert!(
             fsm.open_zone(zid, 0, 1000, 0, TxgT::from(0)).unwrap().is_none());
         fsm.finish_zone(zid, TxgT::from(0));
-        assert!(fsm.try_allocate(64).0.is_none());
+        assert!(fsm.try_allocate(64, Temperature::Hot).0.is_none());
     }
 
     #[test]
     fn try_allocate_only_empty_zones() {
         let mut fsm = FreeSpaceMap::new(32768);
-        assert!(fsm.try_allocate(64).0.is_none());
+        assert!(fsm.try_allocate(64, Temperature::Hot).0.is_none());
     }
 
     #[test]
@@ -2246,7 +2862,8 @@ r#"FreeSpaceMap: 1 Zones: 1 Closed, 0 Empty, 0 Open
         let mut fsm = FreeSpaceMap::new(32768);
         let txg = TxgT::from(0);
         assert_eq!(fsm.open_zone(0, 0, 20, 5, txg).unwrap(), Some((0, 0)));
-        assert_eq!(fsm.try_allocate(6), (Some((0, 5)), vec![]));
+        assert_eq!(fsm.try_allocate(6, Temperature::Hot),
+                   (Some((0, 5)), vec![]));
         fsm.free(0, 5);
         assert_eq!(fsm.in_use_total(), 6);
     }
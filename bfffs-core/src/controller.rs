@@ -4,30 +4,62 @@
 
 use crate::{
     Error,
-    database::{self, Database},
-    fs::Fs,
-    property::{Property, PropertyName, PropertySource},
+    capacity::{Change, Plan},
+    cleaner::{CleanPolicy, CleanStats},
+    database::{self, Database, TxgStatus},
+    defrag::DefragStats,
+    feature::Feature,
+    fs::{FileDataMut, Fs, IoStats, OpenFile, SetAttr},
+    job::{JobID, JobKind, JobStatus, Jobs},
+    pool::ZoneStats,
+    pool_property::PoolProperty,
+    property::{Property, PropertyName, PropertySource, UserProperty},
+    vdev::LeafStatus,
+    vdev_block::VdevLeaf,
     Result
 };
 use futures::{
     Future,
     FutureExt,
     Stream,
+    TryStreamExt,
     channel::oneshot,
     future,
     task::{Context, Poll}
 };
 use futures_locks::RwLock;
+use num_traits::FromPrimitive;
+use serde_derive::{Deserialize, Serialize};
 use std::{
-    collections::BTreeMap,
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    ffi::OsStr,
     io,
     ops::Deref,
+    path::Path,
     pin::Pin,
-    sync::{Arc, Weak}
+    sync::{Arc, Mutex, Weak}
 };
 
+#[cfg(not(test))] use crate::cluster::Cluster;
+#[cfg(test)] use crate::cluster::MockCluster as Cluster;
+
 pub type TreeID = crate::database::TreeID;
 
+/// Name of the file, in a volume's root directory, that holds the volume's
+/// data.
+///
+/// Mounting a volume exposes this file, so it can be used as the backing store
+/// for ctld(8) or anything else that can serve a block device from a file.
+pub const VOLUME_FILE: &str = "volume";
+
+/// Volume sizes must be a multiple of this many bytes.
+pub const VOLBLOCKSIZE: u64 = 512;
+
+/// Default record size for volumes, log base 2.  It's smaller than for file
+/// systems, because I/O to block devices is usually small and random.
+const VOLUME_RECORDSIZE: u8 = 14;
+
 /// A directory entry in the Forest.
 ///
 /// Each dirent corresponds to one file system.
@@ -39,13 +71,72 @@ pub struct Dirent {
     pub offs: u64
 }
 
+/// A file that has suffered an unrecoverable read error
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct DataError {
+    /// File system name, including the pool
+    pub dataset: String,
+    /// The damaged file's inode number
+    pub ino: u64,
+    /// A path to the file, relative to the file system's root, if it's still
+    /// linked and could be found
+    pub path: Option<String>,
+}
+
+/// The health and activity of a pool, as reported by `bfffs pool status`
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PoolStatus {
+    /// Status of every leaf device, including its lifetime I/O error counts
+    pub leaves: Vec<LeafStatus>,
+    /// Zone open/close statistics since the pool was imported
+    pub zones: ZoneStats,
+}
+
+/// Cache of dataset names, without the pool name, and their parents' and
+/// their own IDs.
+///
+/// Only datasets that exist get cached, so creating one needn't invalidate
+/// anything.
+#[derive(Default)]
+struct NameCache {
+    /// Incremented whenever entries are invalidated, so a lookup that raced
+    /// with the invalidation won't cache a stale result.
+    generation: u64,
+    names: HashMap<String, (Option<TreeID>, TreeID)>
+}
+
 pub struct Controller {
     db: Arc<Database>,
     /// Collection of all currently-mounted file systems
     filesystems: RwLock<BTreeMap<TreeID, Weak<Fs>>>,
+    /// Long-running operations
+    jobs: Jobs,
+    names: Mutex<NameCache>,
 }
 
 impl Controller {
+    /// Grow the pool with a freshly created `Cluster`.
+    pub async fn add_cluster(&self, pool: &str, cluster: Cluster)
+        -> Result<()>
+    {
+        if pool != self.db.pool_name() {
+            Err(Error::ENOENT)
+        } else {
+            self.db.add_cluster(cluster).await
+        }
+    }
+
+    /// Predict how a change to the pool's vdev configuration would affect its
+    /// capacity and redundancy, and how much data would have to move.  Nothing
+    /// is actually changed.
+    pub fn capacity_plan(&self, pool: &str, change: &Change) -> Result<Plan> {
+        if pool != self.db.pool_name() {
+            Err(Error::ENOENT)
+        } else {
+            self.db.capacity_plan(change)
+        }
+    }
+
     /// Foreground consistency check.  Prints any irregularities to stderr
     ///
     /// # Returns
@@ -55,19 +146,124 @@ impl Controller {
         self.db.check()
     }
 
+    /// Take a checkpoint of the pool, or discard the existing one.
+    ///
+    /// While a checkpoint exists, the pool may be rewound to it at import
+    /// time, and no freed space will be reclaimed.
+    pub async fn checkpoint(&self, pool: &str, discard: bool) -> Result<()> {
+        if pool != self.db.pool_name() {
+            Err(Error::ENOENT)
+        } else if discard {
+            self.db.discard_checkpoint().await
+        } else {
+            self.db.checkpoint().await
+        }
+    }
+
     /// Clean zones immediately.  Does not wait for the result to be polled!
     ///
     /// The returned `Receiver` will deliver notification when cleaning is
     /// complete.  However, there is no requirement to poll it.  The client may
-    /// drop it, and cleaning will continue in the background.
-    pub fn clean(&self, pool: &str) -> Result<oneshot::Receiver<()>> {
-        if pool == self.db.pool_name() {
-            Ok(self.db.clean())
-        } else {
+    /// drop it, and cleaning will continue in the background.  Its progress
+    /// may be monitored through the returned job.
+    pub fn clean(&self, pool: &str, policy: CleanPolicy)
+        -> Result<(JobID, oneshot::Receiver<()>)>
+    {
+        self.check_clean(pool)?;
+        let (id, progress) = self.jobs.start(JobKind::Clean, pool.to_owned());
+        let rx = self.db.clean(policy, progress.clone())
+            .map_err(|e| {
+                progress.finish(Err(e));
+                e
+            })?;
+        Ok((id, rx))
+    }
+
+    /// Report what `clean` would do with the given `policy`, without doing it.
+    ///
+    /// Fails in all the same cases that `clean` would, except that a
+    /// checkpoint is allowed.  Then all of the freed space is reported as
+    /// pinned, to explain why cleaning can't proceed.
+    pub fn clean_plan(&self, pool: &str, policy: CleanPolicy)
+        -> Result<CleanStats>
+    {
+        match self.check_clean(pool) {
+            Ok(()) | Err(Error::EBUSY) => Ok(self.db.clean_plan(policy)),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Fail if the pool can't move records around.  Both cleaning and
+    /// defragmenting do.
+    fn check_clean(&self, pool: &str) -> Result<()> {
+        if pool != self.db.pool_name() {
             Err(Error::ENOENT)
+        } else if self.db.is_readonly() {
+            Err(Error::EROFS)
+        } else if self.db.checkpoint_txg().is_some() {
+            // Cleaning couldn't reclaim anything until the checkpoint is gone,
+            // and moving records would only consume more space.
+            Err(Error::EBUSY)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Rewrite a pool's fragmented files contiguously, in the background.
+    ///
+    /// Its progress may be monitored through the returned job.
+    pub fn defrag(&self, pool: &str) -> Result<JobID> {
+        self.check_clean(pool)?;
+        let (id, progress) = self.jobs.start(JobKind::Defrag, pool.to_owned());
+        let db = self.db.clone();
+        tokio::spawn(async move {
+            let r = db.defrag(progress.clone()).await;
+            if let Err(e) = r {
+                tracing::error!("Defragmentation failed: {e:?}");
+            }
+            progress.finish(r);
+        });
+        Ok(id)
+    }
+
+    /// Measure a pool's fragmentation, and report what `defrag` would do.
+    pub async fn defrag_plan(&self, pool: &str) -> Result<DefragStats> {
+        if pool != self.db.pool_name() {
+            return Err(Error::ENOENT);
+        }
+        self.db.defrag_plan().await
+    }
+
+    /// Forcibly close a client's file handle.  See [`Fs::force_close`].
+    ///
+    /// # Arguments
+    ///
+    /// - `name`    -   Name of the file system, including pool name
+    /// - `fh`      -   Handle number, as reported by [`Controller::open_files`]
+    pub async fn close_file(&self, name: &str, fh: u64) -> Result<()> {
+        match self.mounted_fs(name).await? {
+            Some(fs) => fs.force_close(fh).await,
+            // Only a mounted file system can have open files
+            None => Err(Error::EBADF)
         }
     }
 
+    /// Merge under-filled nodes in a file system's metadata tree.
+    ///
+    /// `range_delete` can leave long runs of nearly-empty nodes behind, which
+    /// slow down later operations.  This coalesces them, returning the number
+    /// of nodes that were merged away.
+    ///
+    /// # Arguments
+    ///
+    /// - `name`    -   Name of the file system to compact, including pool name
+    pub async fn compact_fs(&self, name: &str) -> Result<usize> {
+        let dsname = self.strip_pool_name(name)?;
+        let (_, tree_id) = self.lookup_fs(&dsname).await?;
+        let id = tree_id.ok_or(Error::ENOENT)?;
+        self.db.compact_fs(id).await
+    }
+
     /// Create a new, blank filesystem
     ///
     /// # Arguments
@@ -79,20 +275,115 @@ impl Controller {
         let fsname = self.strip_pool_name(name)?;
         let r = fsname.rsplit_once('/');
         if let Some((parent_name, dsname)) = r {
-            let (_, parent) = self.db.lookup_fs(parent_name).await?;
-            if parent.is_none() {
-                return Err(Error::ENOENT);
+            let (_, parent) = self.lookup_fs(parent_name).await?;
+            let parent_id = parent.ok_or(Error::ENOENT)?;
+            let (volsize, _) = Fs::get_prop_unmounted(parent_id,
+                self.db.clone(), PropertyName::Volsize).await?;
+            if volsize.as_u64() > 0 {
+                // Volumes can't have children
+                return Err(Error::ENOTDIR);
             }
             self.db.create_fs(parent, dsname.to_owned())
         } else if fsname.is_empty() {
             // Creating the pool's root file system
-            self.db.create_fs(None, fsname.to_owned())
+            self.db.create_fs(None, String::new())
         } else {
             // Creating a child of the root file system
-            self.db.create_fs(Some(database::TreeID(0)), fsname.to_owned())
+            self.db.create_fs(Some(database::TreeID(0)), fsname.into_owned())
         }.await
     }
 
+    /// Create a new volume: a dataset that holds a single, fixed-size block
+    /// device.
+    ///
+    /// The volume's data is stored in a sparse [`VOLUME_FILE`] in its root
+    /// directory.
+    ///
+    /// # Arguments
+    ///
+    /// - `name`    -   Name of the volume to create, including pool name
+    /// - `size`    -   Size of the volume in bytes.  Must be a nonzero
+    ///                 multiple of [`VOLBLOCKSIZE`].
+    /// - `props`   -   Properties to set on the new volume.  They're set before
+    ///                 the volume's data file is created, so they may include
+    ///                 `recordsize`.
+    pub async fn create_volume(&self, name: &str, size: u64,
+        props: Vec<Property>) -> Result<TreeID>
+    {
+        if size == 0 || size % VOLBLOCKSIZE != 0 {
+            return Err(Error::EINVAL);
+        }
+        if props.iter().any(|p| p.name() == PropertyName::Volsize) {
+            return Err(Error::EINVAL);
+        }
+        if self.strip_pool_name(name)?.is_empty() {
+            // The pool's root dataset must be a file system
+            return Err(Error::EINVAL);
+        }
+        let tree_id = self.create_fs(name).await?;
+        if !props.iter().any(|p| p.name() == PropertyName::RecordSize) {
+            let prop = Property::RecordSize(VOLUME_RECORDSIZE);
+            Fs::set_prop_unmounted(tree_id, &self.db, prop).await?;
+        }
+        for prop in props.into_iter() {
+            Fs::set_prop_unmounted(tree_id, &self.db, prop.inheritable())
+                .await?;
+        }
+        let prop = Property::Volsize(size);
+        Fs::set_prop_unmounted(tree_id, &self.db, prop).await?;
+
+        let errno = |e: i32| Error::from_i32(e).unwrap_or(Error::EUNKNOWN);
+        let fs = Fs::new(self.db.clone(), tree_id).await;
+        let root = fs.root();
+        let fd = fs.create(&root.handle(), OsStr::new(VOLUME_FILE), 0o600, 0,
+                           0)
+            .await
+            .map_err(errno)?;
+        let attr = SetAttr {
+            size: Some(size),
+            .. Default::default()
+        };
+        let r = fs.setattr(&fd.handle(), attr).await;
+        fs.inactive(fd).await;
+        r.map_err(errno)?;
+        Ok(tree_id)
+    }
+
+    /// List every file in the pool that has suffered an unrecoverable read
+    /// error.
+    ///
+    /// Finding the files' paths requires scanning their file systems, so this
+    /// may be slow.
+    pub async fn data_errors(&self, pool: &str) -> Result<Vec<DataError>> {
+        if pool != self.db.pool_name() {
+            return Err(Error::ENOENT);
+        }
+        let mut by_tree = BTreeMap::<TreeID, Vec<u64>>::new();
+        for e in self.db.errors() {
+            by_tree.entry(e.tree_id).or_default().push(e.ino);
+        }
+        let mut errors = Vec::new();
+        for (tree_id, inos) in by_tree {
+            let dataset = self.fs_name(tree_id).await?;
+            let (fs, _, was_open) = self.open_fs(&dataset).await?;
+            // The paths are only informational, so ignore errors finding them
+            let paths = fs.paths_of(&inos).await
+                .unwrap_or_else(|_| vec![None; inos.len()]);
+            drop(fs);
+            if !was_open {
+                self.forget_fs(tree_id).await;
+            }
+            for (ino, path) in inos.into_iter().zip(paths) {
+                errors.push(DataError {
+                    dataset: dataset.clone(),
+                    ino,
+                    path: path.map(|p| p.to_string_lossy().into_owned())
+                });
+            }
+        }
+        Ok(errors)
+    }
+
     /// Destroy a filesystem
     ///
     ///
@@ -103,19 +394,43 @@ impl Controller {
     {
         let dsname = self.strip_pool_name(name)?;
         let guard = self.filesystems.read().await;
-        let (parent, tree_id) = self.db.lookup_fs(dsname).await?;
+        let (parent, tree_id) = self.lookup_fs(&dsname).await?;
         match tree_id {
             Some(id) => {
                 if let Some(_fs) = guard.get(&id) {
                     Err(Error::EBUSY)
                 } else {
-                    self.db.destroy_fs(parent, id, dsname).await
+                    let r = self.db.destroy_fs(parent, id, &dsname).await;
+                    self.invalidate_names(&dsname);
+                    r
                 }
             }
             None => Err(Error::ENOENT)
         }
     }
 
+    /// Report which file systems `destroy_fs` would destroy, without
+    /// destroying them.
+    ///
+    /// Fails in all the same cases that `destroy_fs` would.
+    pub async fn destroy_fs_plan(&self, name: &str) -> Result<Vec<String>>
+    {
+        let dsname = self.strip_pool_name(name)?;
+        let guard = self.filesystems.read().await;
+        let (_, tree_id) = self.lookup_fs(&dsname).await?;
+        let id = tree_id.ok_or(Error::ENOENT)?;
+        if guard.contains_key(&id) {
+            Err(Error::EBUSY)
+        } else if self.db.is_readonly() {
+            Err(Error::EROFS)
+        } else if self.db.readdir(id, 0).try_next().await?.is_some() {
+            // Children must be destroyed first
+            Err(Error::EBUSY)
+        } else {
+            Ok(vec![self.full_name(&dsname)])
+        }
+    }
+
     /// Drop all data from the cache, for testing or benchmarking purposes
     pub fn drop_cache(&self) {
         self.db.drop_cache()
@@ -134,6 +449,82 @@ impl Controller {
         self.db.dump_fs(f, tree).await
     }
 
+    /// Status of every leaf device in the pool, including its lifetime I/O
+    /// error counts.
+    pub fn leaf_status(&self, pool: &str) -> Result<Vec<LeafStatus>> {
+        if pool != self.db.pool_name() {
+            Err(Error::ENOENT)
+        } else {
+            Ok(self.db.leaf_status())
+        }
+    }
+
+    /// Status of the pool's leaf devices, plus zone statistics
+    pub fn status(&self, pool: &str) -> Result<PoolStatus> {
+        let leaves = self.leaf_status(pool)?;
+        let zones = self.db.zone_stats();
+        Ok(PoolStatus{leaves, zones})
+    }
+
+    /// Stop tracking a file system that was opened by [`Controller::open_fs`],
+    /// unless somebody else has opened it since.
+    async fn forget_fs(&self, tree_id: TreeID) {
+        let mut guard = self.filesystems.write().await;
+        if guard.get(&tree_id).map(|w| w.strong_count() == 0).unwrap_or(false)
+        {
+            guard.remove(&tree_id);
+        }
+    }
+
+    /// Find a file system's full name, including the pool, from its ID.
+    async fn fs_name(&self, tree_id: TreeID) -> Result<String> {
+        let mut components = Vec::new();
+        let mut id = tree_id;
+        while let Some(parent) = self.db.lookup_parent(id).await? {
+            let mut children = Box::pin(self.db.readdir(parent, 0));
+            loop {
+                match children.try_next().await? {
+                    Some(de) if de.id == id => {
+                        components.push(de.name);
+                        break;
+                    },
+                    Some(_) => (),
+                    None => return Err(Error::ENOENT)
+                }
+            }
+            id = parent;
+        }
+        components.push(self.db.pool_name().to_owned());
+        components.reverse();
+        Ok(components.join("/"))
+    }
+
+    /// Freeze a file system, whether or not it's mounted.  See
+    /// [`Database::freeze_fs`].
+    pub async fn freeze_fs(&self, name: &str) -> Result<()> {
+        let dsname = self.strip_pool_name(name)?;
+        let (_, tree_id) = self.lookup_fs(&dsname).await?;
+        let id = tree_id.ok_or(Error::ENOENT)?;
+        let fs = self.filesystems.read().await
+            .get(&id)
+            .and_then(Weak::upgrade);
+        match fs {
+            // A mounted file system may have writes buffered in memory
+            Some(fs) => fs.freeze().await,
+            None => self.db.freeze_fs(id).await
+        }
+    }
+
+    /// Construct a dataset's full name from its name as returned by
+    /// [`Controller::strip_pool_name`].
+    fn full_name(&self, dsname: &str) -> String {
+        if dsname.is_empty() {
+            self.db.pool_name().to_owned()
+        } else {
+            format!("{}/{}", self.db.pool_name(), dsname)
+        }
+    }
+
     /// Get the value of the `propname` property on the given dataset
     #[tracing::instrument(skip(self))]
     pub async fn get_prop(&self, dataset: String, propname: PropertyName)
@@ -145,9 +536,11 @@ impl Controller {
         }
         let dsname = self.strip_pool_name(&dataset)?;
         let guard = self.filesystems.read().await;
-        match self.db.lookup_fs(dsname).await? {
+        match self.lookup_fs(&dsname).await? {
             (_parent, Some(tree_id)) => {
-                self.get_prop_locked(&guard, &dataset, tree_id, propname).await
+                let fullname = self.full_name(&dsname);
+                self.get_prop_locked(&guard, &fullname, tree_id, propname)
+                    .await
             }
             (_, None) => {
                 tracing::debug!("no property found");
@@ -156,6 +549,20 @@ impl Controller {
         }
     }
 
+    /// Get every user property that applies to the given dataset, whether
+    /// set locally or inherited.
+    pub async fn get_user_props(&self, dataset: &str)
+        -> Result<Vec<(UserProperty, PropertySource)>>
+    {
+        let dsname = self.strip_pool_name(dataset)?;
+        match self.lookup_fs(&dsname).await? {
+            (_parent, Some(tree_id)) => {
+                Fs::get_user_props_unmounted(tree_id, self.db.clone()).await
+            }
+            (_, None) => Err(Error::ENOENT)
+        }
+    }
+
     async fn get_prop_locked<T>(
         &self,
         guard: &T,
@@ -204,6 +611,33 @@ impl Controller {
         })
     }
 
+    /// Forget the cached IDs of a dataset and all of its descendants.
+    ///
+    /// Must be called whenever a dataset is destroyed or renamed.
+    fn invalidate_names(&self, dsname: &str) {
+        let mut cache = self.names.lock().unwrap();
+        cache.generation += 1;
+        if dsname.is_empty() {
+            cache.names.clear();
+        } else {
+            cache.names.retain(|k, _| {
+                k.strip_prefix(dsname)
+                    .map(|s| !s.is_empty() && !s.starts_with('/'))
+                    .unwrap_or(true)
+            });
+        }
+    }
+
+    /// Status of every running or recently finished job
+    pub fn job_list(&self) -> Vec<JobStatus> {
+        self.jobs.list()
+    }
+
+    /// Status of a single running or recently finished job
+    pub fn job_status(&self, id: JobID) -> Result<JobStatus> {
+        self.jobs.status(id)
+    }
+
     /// List a dataset's immediate childen
     ///
     /// # Arguments
@@ -268,18 +702,116 @@ impl Controller {
             }
         }
 
-        let fut = match self.strip_pool_name(dataset) {
-            Ok(fsname) => self.db.lookup_fs(fsname).boxed(),
-            Err(e) => future::err(e).boxed(),
+        let (fut, parentname) = match self.strip_pool_name(dataset) {
+            Ok(fsname) => {
+                (self.db.lookup_fs(&fsname).boxed(), self.full_name(&fsname))
+            },
+            Err(e) => (future::err(e).boxed(), dataset.to_owned())
         };
         let lol = LookupOrList::Lookup(fut);
-        ListFs{db: self.db.clone(), parentname: dataset.to_owned(), lol, offs}
+        ListFs{db: self.db.clone(), parentname, lol, offs}
+    }
+
+    /// Lookup a dataset's ID, consulting the name cache first.
+    ///
+    /// `dsname` must be normalized by [`Controller::strip_pool_name`].
+    /// Returns the same thing as [`Database::lookup_fs`].
+    async fn lookup_fs(&self, dsname: &str)
+        -> Result<(Option<TreeID>, Option<TreeID>)>
+    {
+        let generation = {
+            let cache = self.names.lock().unwrap();
+            if let Some((parent, id)) = cache.names.get(dsname) {
+                return Ok((*parent, Some(*id)));
+            }
+            cache.generation
+        };
+        let r = self.db.lookup_fs(dsname).await?;
+        if let (parent, Some(id)) = r {
+            let mut cache = self.names.lock().unwrap();
+            if cache.generation == generation {
+                cache.names.insert(dsname.to_owned(), (parent, id));
+            }
+        }
+        Ok(r)
+    }
+
+    /// Find the parent directory and final component of a path.
+    ///
+    /// `path` is relative to the file system's root.  "." and ".." components
+    /// are not allowed.
+    async fn lookup_parent<'a>(fs: &Fs, path: &'a str)
+        -> std::result::Result<(FileDataMut, &'a OsStr), i32>
+    {
+        let mut components = path.split('/')
+            .filter(|c| !c.is_empty())
+            .collect::<Vec<_>>();
+        let name = components.pop().ok_or(libc::EINVAL)?;
+        if name == "." || name == ".." || components.contains(&".") ||
+            components.contains(&"..")
+        {
+            return Err(libc::EINVAL);
+        }
+        let mut fd = fs.root();
+        for c in components {
+            fd = fs.lookup(None, &fd.handle(), OsStr::new(c)).await?;
+        }
+        Ok((fd, OsStr::new(name)))
+    }
+
+    /// Move a file from one file system to another in the same pool.
+    ///
+    /// Either file system may be mounted or not.  See [`Fs::move_to`] for the
+    /// details.
+    ///
+    /// # Arguments
+    ///
+    /// - `src_ds`      -   Source file system, including pool name
+    /// - `src_path`    -   Path of the file, relative to `src_ds`'s root
+    /// - `dst_ds`      -   Destination file system, including pool name
+    /// - `dst_path`    -   New path of the file, relative to `dst_ds`'s root
+    pub async fn move_file(&self, src_ds: &str, src_path: &str, dst_ds: &str,
+                           dst_path: &str) -> Result<()>
+    {
+        let errno = |e: i32| Error::from_i32(e).unwrap_or(Error::EUNKNOWN);
+        let (src, src_id, src_mounted) = self.open_fs(src_ds).await?;
+        let (dst, dst_id, dst_mounted) = self.open_fs(dst_ds).await?;
+        let r = async {
+            let (src_parent, src_name) = Self::lookup_parent(&src, src_path)
+                .await?;
+            let (dst_parent, dst_name) = Self::lookup_parent(&dst, dst_path)
+                .await?;
+            // If the source is mounted then it might be open.  In that case it
+            // must linger until inactive, or until the next mount.
+            let src_fd = if src_mounted {
+                Some(src.lookup(None, &src_parent.handle(), src_name).await?)
+            } else {
+                None
+            };
+            src.move_to(&src_parent.handle(),
+                        src_fd.as_ref().map(FileDataMut::handle).as_ref(),
+                        src_name, &dst, &dst_parent.handle(), dst_name)
+            .await
+        }.await.map(drop).map_err(errno);
+
+        // Forget any file systems that we opened just for this
+        drop(src);
+        drop(dst);
+        if !src_mounted {
+            self.forget_fs(src_id).await;
+        }
+        if !dst_mounted {
+            self.forget_fs(dst_id).await;
+        }
+        r
     }
 
     pub fn new(db: Database) -> Self {
         Controller{
             db: Arc::new(db),
-            filesystems: Default::default()
+            filesystems: Default::default(),
+            jobs: Default::default(),
+            names: Default::default()
         }
     }
 
@@ -305,7 +837,7 @@ impl Controller {
         // TODO: after switching to cryptographic GUIDs for Tree IDs, eliminate
         // the String::from by moving lookup_fs to outside of the rwlock
         // acquisition.
-        match self.strip_pool_name(name).map(String::from) {
+        match self.strip_pool_name(name).map(Cow::into_owned) {
             Ok(fsname) => {
                 let db2 = self.db.clone();
                 self.filesystems.write()
@@ -333,30 +865,197 @@ impl Controller {
         }
     }
 
+    /// Get a file system, if it's mounted.
+    async fn mounted_fs(&self, name: &str) -> Result<Option<Arc<Fs>>> {
+        let dsname = self.strip_pool_name(name)?;
+        let (_, tree_id) = self.lookup_fs(&dsname).await?;
+        let id = tree_id.ok_or(Error::ENOENT)?;
+        let fs = self.filesystems.read().await
+            .get(&id)
+            .and_then(Weak::upgrade);
+        Ok(fs)
+    }
+
+    /// Reattach a leaf device that was removed, after it has reappeared, or
+    /// clear the fault of one that failed a write.  Either way, resilver
+    /// whatever writes it missed.
+    ///
+    /// `path` is the device's current location, which may have changed.
+    pub async fn online<P>(&self, pool: &str, path: P) -> Result<()>
+        where P: AsRef<Path>
+    {
+        if pool != self.db.pool_name() {
+            return Err(Error::ENOENT);
+        }
+        if self.db.is_readonly() {
+            return Err(Error::EROFS);
+        }
+        let (leaf, _) = VdevLeaf::open(path).await?;
+        self.db.online(leaf).await
+    }
+
+    /// Report a file system's read and write counters, and optionally reset
+    /// them.  The counters are kept in memory, so an unmounted file system
+    /// reports all zeros.
+    pub async fn io_stats(&self, name: &str, reset: bool) -> Result<IoStats> {
+        Ok(self.mounted_fs(name).await?
+            .map(|fs| fs.io_stats(reset))
+            .unwrap_or_default())
+    }
+
+    /// List the file handles that clients have open on a file system, for
+    /// example to find out why it can't be unmounted.
+    pub async fn open_files(&self, name: &str) -> Result<Vec<OpenFile>> {
+        Ok(self.mounted_fs(name).await?
+            .map(|fs| fs.open_files())
+            .unwrap_or_default())
+    }
+
+    /// Get a file system, whether or not it's mounted.
+    ///
+    /// An unmounted file system will be opened and registered, so it can't be
+    /// mounted until the returned handle is dropped.  Also returns the file
+    /// system's ID and whether it was already open.
+    async fn open_fs(&self, name: &str) -> Result<(Arc<Fs>, TreeID, bool)> {
+        let dsname = self.strip_pool_name(name)?;
+        let tree_id = match self.lookup_fs(&dsname).await? {
+            (_parent, Some(tree_id)) => tree_id,
+            (_, None) => return Err(Error::ENOENT)
+        };
+        let mut guard = self.filesystems.write().await;
+        if let Some(fs) = guard.get(&tree_id).and_then(Weak::upgrade) {
+            Ok((fs, tree_id, true))
+        } else {
+            let fs = Arc::new(Fs::new(self.db.clone(), tree_id).await);
+            guard.insert(tree_id, Arc::downgrade(&fs));
+            Ok((fs, tree_id, false))
+        }
+    }
+
+    /// Record in a file system's mount history that it's just been mounted.
+    ///
+    /// Returns whether its previous mount, if any, was cleanly unmounted.
+    /// Nothing is recorded on a read-only pool.
+    pub async fn record_mount(&self, name: &str) -> Result<bool> {
+        if self.db.is_readonly() {
+            return Ok(true);
+        }
+        let dsname = self.strip_pool_name(name)?;
+        match self.lookup_fs(&dsname).await? {
+            (_parent, Some(tree_id)) =>
+                Fs::record_mount(tree_id, self.db.clone()).await,
+            (_, None) => Err(Error::ENOENT)
+        }
+    }
+
+    /// Record in a file system's mount history that it's been cleanly
+    /// unmounted.
+    pub async fn record_unmount(&self, name: &str) -> Result<()> {
+        if self.db.is_readonly() {
+            return Ok(());
+        }
+        let dsname = self.strip_pool_name(name)?;
+        match self.lookup_fs(&dsname).await? {
+            (_parent, Some(tree_id)) =>
+                Fs::record_unmount(tree_id, &self.db).await,
+            (_, None) => Err(Error::ENOENT)
+        }
+    }
+
+    /// Change the values of pool properties.
+    ///
+    /// They take effect immediately, and are recorded in the pool's label.
+    pub async fn set_pool_props(&self, pool: &str, props: Vec<PoolProperty>)
+        -> Result<()>
+    {
+        if pool != self.db.pool_name() {
+            Err(Error::ENOENT)
+        } else {
+            self.db.set_pool_props(props).await
+        }
+    }
+
     /// Set the value of a property on the given dataset.
-    // TODO: when setting a property, update the in-memory property on all of
-    // its child datasets.
+    ///
+    /// The change takes effect immediately on the dataset, if mounted, and on
+    /// any mounted descendants that inherit it.
     pub async fn set_prop(&self, dataset: &str, prop: Property) -> Result<()>
     {
         let prop = prop.inheritable();
+        let propname = prop.name();
+        if propname == PropertyName::Volsize {
+            // Volumes can't be resized
+            return Err(Error::EINVAL);
+        }
+        if propname.mount_history() {
+            // Only bfffsd may record mounts
+            return Err(Error::EINVAL);
+        }
         let dsname = self.strip_pool_name(dataset)?;
-        let tree_id = match self.db.lookup_fs(dsname).await? {
+        let tree_id = match self.lookup_fs(&dsname).await? {
             (_parent, Some(tree_id)) => tree_id,
             (_, None) => return Err(Error::ENOENT)
         };
         let guard = self.filesystems.read().await;
-        if let Some(weak) = guard.get(&tree_id) {
+        if let Some(fs) = guard.get(&tree_id).and_then(Weak::upgrade) {
+            fs.set_prop(prop).await?;
+        } else {
+            Fs::set_prop_unmounted(tree_id, &self.db, prop).await?;
+        }
+
+        // Notify every other mounted file system.  We don't bother to check
+        // which ones are descendants; for the others the effective value
+        // simply won't change.
+        for (other_id, weak) in guard.iter() {
+            if *other_id == tree_id {
+                continue;
+            }
             if let Some(fs) = weak.upgrade() {
-                return fs.set_prop(prop).await;
+                let (eprop, _source) = Fs::get_prop_unmounted(*other_id,
+                    self.db.clone(), propname).await?;
+                fs.apply_prop(&eprop);
             }
         }
-        Fs::set_prop_unmounted(tree_id, &self.db, prop).await
+        Ok(())
     }
 
-    // Strip the pool name.  For now, only one pool is supported.
-    fn strip_pool_name<'a>(&self, name: &'a str) -> Result<&'a str> {
-        match name.strip_prefix(self.db.pool_name()) {
-            Some(s) => Ok(s.strip_prefix('/').unwrap_or(s)),
+    /// Set a user property on the given dataset, or clear it if the value is
+    /// empty.
+    ///
+    /// User properties have no effect on the file system, so there is no
+    /// need to notify mounted file systems.
+    pub async fn set_user_prop(&self, dataset: &str, prop: UserProperty)
+        -> Result<()>
+    {
+        let dsname = self.strip_pool_name(dataset)?;
+        match self.lookup_fs(&dsname).await? {
+            (_parent, Some(tree_id)) => {
+                Fs::set_user_prop(tree_id, &self.db, prop).await
+            }
+            (_, None) => Err(Error::ENOENT)
+        }
+    }
+
+    /// Strip the pool name from a dataset name, and normalize the rest.
+    ///
+    /// Leading, trailing, and repeated separators are removed, so
+    /// "pool//foo/bar/" names the same dataset as "pool/foo/bar".  For now,
+    /// only one pool is supported.
+    fn strip_pool_name<'a>(&self, name: &'a str) -> Result<Cow<'a, str>> {
+        let stripped = name.strip_prefix(self.db.pool_name())
+            .filter(|s| s.is_empty() || s.starts_with('/'));
+        match stripped {
+            Some(s) => {
+                let s = s.trim_matches('/');
+                if s.contains("//") {
+                    Ok(Cow::Owned(s.split('/')
+                        .filter(|c| !c.is_empty())
+                        .collect::<Vec<_>>()
+                        .join("/")))
+                } else {
+                    Ok(Cow::Borrowed(s))
+                }
+            },
             None => {
                 tracing::debug!("pool name not provided");
                 Err(Error::ENOENT)
@@ -370,6 +1069,24 @@ impl Controller {
         self.db.sync_transaction().await
     }
 
+    /// Thaw a file system frozen by [`Controller::freeze_fs`].
+    pub async fn thaw_fs(&self, name: &str) -> Result<()> {
+        let dsname = self.strip_pool_name(name)?;
+        let (_, tree_id) = self.lookup_fs(&dsname).await?;
+        let id = tree_id.ok_or(Error::ENOENT)?;
+        self.db.thaw_fs(id)
+    }
+
+    /// Report the pool's current, last synced, and checkpoint transaction
+    /// groups.
+    pub async fn txgs(&self, pool: &str) -> Result<TxgStatus> {
+        if pool != self.db.pool_name() {
+            Err(Error::ENOENT)
+        } else {
+            Ok(self.db.txgs().await)
+        }
+    }
+
     pub async fn unmount(&self, name: &str, force: bool) -> Result<()>
     {
         use nix::mount::{unmount, MntFlags};
@@ -380,11 +1097,16 @@ impl Controller {
             flags.insert(MntFlags::MNT_FORCE);
         }
         let mut guard = self.filesystems.write().await;
-        let tree_id = match self.db.lookup_fs(dsname).await? {
+        let tree_id = match self.lookup_fs(&dsname).await? {
             (_, Some(id)) => id,
             (_, None) => return Err(Error::ENOENT)
         };
-        let (prop, _) = self.get_prop_locked(&guard, name, tree_id,
+        if self.db.is_frozen(tree_id) {
+            // Unmounting would flush buffered writes, which would block
+            return Err(Error::EBUSY);
+        }
+        let fullname = self.full_name(&dsname);
+        let (prop, _) = self.get_prop_locked(&guard, &fullname, tree_id,
                                              PropertyName::Mountpoint)
             .await?;
 
@@ -398,4 +1120,18 @@ impl Controller {
         guard.remove(&tree_id);
         Ok(())
     }
+
+    /// Enable on-disk format features on a pool.
+    ///
+    /// If `features` is empty, enable every feature that this version
+    /// supports.  Returns the features that weren't already enabled.
+    pub async fn upgrade(&self, pool: &str, features: &[Feature])
+        -> Result<Vec<Feature>>
+    {
+        if pool != self.db.pool_name() {
+            Err(Error::ENOENT)
+        } else {
+            self.db.upgrade(features).await
+        }
+    }
 }
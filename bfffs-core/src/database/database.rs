@@ -1,14 +1,25 @@
 // vim: tw=80
 
 use crate::{
+    capacity::{self, Change, Plan},
     cleaner::*,
-    dataset::{ITree, ReadOnlyDataset, ReadWriteDataset},
+    dataset::{ITree, ReadDataset, ReadOnlyDataset, ReadWriteDataset},
+    defrag::DefragStats,
+    determinism,
     dml::DML,
+    feature::{Feature, Features},
     fs_tree::{self, FSKey, FSValue, Inode, ObjKey, FileType, Timespec},
     idml::*,
+    job::Progress,
     label::*,
-    tree::TreeOnDisk,
+    pool::ZoneStats,
+    pool_property::PoolProperty,
+    tree::{DumpFormat, TreeOnDisk},
     types::*,
+    util::BYTES_PER_LBA,
+    vdev::LeafStatus,
+    vdev_block::VdevLeaf,
+    writeback::{Credit, WriteBack},
 };
 use futures::{
     Future,
@@ -23,27 +34,48 @@ use futures::{
     select,
     stream::{self, FuturesUnordered},
 };
-use futures_locks::RwLock;
+use futures_locks::{RwLock, RwLockWriteGuard};
 #[cfg(test)] use mockall::automock;
 use serde_derive::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::{
     ffi::{OsString, OsStr},
     io,
+    ops::{Bound, RangeFull},
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
+        Mutex,
     },
 };
-use super::{Forest, TreeID};
+use super::{Forest, TreeID, slop_space};
 use tokio::{
     task::JoinHandle,
     time::{Duration, Instant, sleep_until},
 };
 
+#[cfg(not(test))] use crate::cluster::Cluster;
+#[cfg(test)] use crate::cluster::MockCluster as Cluster;
+
 pub type ReadOnlyFilesystem = ReadOnlyDataset<FSKey, FSValue>;
 pub type ReadWriteFilesystem = ReadWriteDataset<FSKey, FSValue>;
 
+/// Maximum number of damaged files that the error log will remember.  Beyond
+/// this, new errors are still logged but not recorded.
+const ERROR_LOG_MAX: usize = 1024;
+
+/// How much of the pool's slop space an operation may use.  See
+/// [`slop_space`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum SlopClass {
+    /// Operations that consume space, like writes.  They may not use any of
+    /// the slop.
+    User,
+    /// Operations that free space, like unlink and truncate, and small
+    /// administrative writes.  They may use most of the slop.
+    Reclaim,
+}
+
 #[derive(Debug)]
 enum SyncerMsg {
     /// Tell the Syncer that we manually synced, and it can reset its timer
@@ -82,6 +114,9 @@ impl Syncer {
         let sync_duration = Duration::new(5, 0);
         // Fixed 0.1 second flush duration
         let flush_duration = Duration::new(0, 100_000_000);
+        // In deterministic mode, transactions only end when something
+        // explicitly syncs the database, so txg numbers don't depend on timing.
+        let timed = determinism::seed().is_none();
         let taskfut = async move {
             let mut sync_time = Instant::now() + sync_duration;
             loop {
@@ -89,6 +124,9 @@ impl Syncer {
                 let mut delay_fut = Box::pin(sleep_until(wakeup_time).fuse());
                 select! {
                     _ = delay_fut => {
+                        if !timed {
+                            continue;
+                        }
                         let now = Instant::now();
                         if now > sync_time {
                             //Time's up.  Sync the database
@@ -128,16 +166,105 @@ impl Syncer {
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-struct Label {
+pub(crate) struct Label {
+    forest: TreeOnDisk<RID>,
+    /// Files that have suffered unrecoverable read errors
+    errors: Vec<ErrorRecord>,
+}
+
+impl LabelLayer for Label {
+    type V0 = LabelV0;
+}
+
+/// `Label` as it was in version 0 labels, before the error log
+#[derive(Deserialize)]
+pub(crate) struct LabelV0 {
     forest: TreeOnDisk<RID>
 }
 
+impl From<LabelV0> for Label {
+    fn from(v0: LabelV0) -> Self {
+        Label {
+            forest: v0.forest,
+            errors: Vec::new()
+        }
+    }
+}
+
+/// Limits how much dirty data a single file system may hold.
+///
+/// Credit borrowed from the quota is held until the file system's tree gets
+/// flushed, rather than following the dirty nodes around like the IDML's
+/// credit does.  So the accounting is a little pessimistic, but it never
+/// lets a file system exceed its limit by more than one operation's worth.
+struct DirtyQuota {
+    wb: WriteBack,
+    /// Credit for operations that have finished, but whose dirty data may not
+    /// have been flushed yet.  `None` once the quota has been replaced.
+    owed: Mutex<Option<Credit>>,
+}
+
+impl DirtyQuota {
+    fn new(limit: usize) -> Self {
+        DirtyQuota {
+            wb: WriteBack::with_capacity(limit),
+            owed: Mutex::new(Some(Credit::null()))
+        }
+    }
+
+    /// Borrow credit for an operation that will dirty up to `size` bytes.
+    fn borrow(&self, size: usize) -> impl Future<Output=Credit> + Send {
+        // An operation larger than the whole quota can still proceed, as long
+        // as it has the quota to itself.
+        self.wb.borrow(size.min(self.wb.capacity()))
+    }
+
+    /// Hold onto an operation's credit until its data gets flushed.
+    fn owe(&self, credit: Credit) {
+        match self.owed.lock().unwrap().as_mut() {
+            Some(owed) => owed.extend(credit),
+            None => self.wb.repay(credit)
+        }
+    }
+
+    /// Stop tracking credit, because this quota has been replaced.
+    fn retire(&self) {
+        if let Some(owed) = self.owed.lock().unwrap().take() {
+            self.wb.repay(owed);
+        }
+    }
+
+    /// Take all of the credit owed so far.  Once the file system has been
+    /// flushed, it may be repaid.
+    fn take_owed(&self) -> Credit {
+        match self.owed.lock().unwrap().as_mut() {
+            Some(owed) => owed.take(),
+            None => Credit::null()
+        }
+    }
+}
+
+impl Drop for DirtyQuota {
+    fn drop(&mut self) {
+        self.retire();
+    }
+}
+
 struct Inner {
     /// Has any part of the database been modified since the last transaction
     /// sync?
     // NB: This is likely to be highly contended and very slow.  Better to
     // replace it with a per-cpu counter.
     dirty: AtomicBool,
+    /// Per-file system limits on dirty data, for those that have one.
+    dirty_quotas: Mutex<HashMap<TreeID, Arc<DirtyQuota>>>,
+    /// Files that have suffered unrecoverable read errors
+    errors: Mutex<BTreeSet<ErrorRecord>>,
+    /// Every modification to a file system holds its gate shared.  Freezing
+    /// the file system holds it exclusively.
+    freeze_gates: Mutex<HashMap<TreeID, RwLock<()>>>,
+    /// File systems that are currently frozen, and their gates' guards.
+    frozen: Mutex<HashMap<TreeID, RwLockWriteGuard<()>>>,
     // Owner for the file system trees.  They must be owned by the Database
     // rather than the Fs so that the Database may flush and sync them all.
     fs_trees: RwLock<BTreeMap<TreeID, Arc<ITree<FSKey, FSValue>>>>,
@@ -150,9 +277,65 @@ struct Inner {
     // TreeID>) or by (<parent name>, <name>) or by <parent TreeID, hash(name)>?
     forest: Forest,
     idml: Arc<IDML>,
+    /// Was the database opened read-only?  If so, it will never sync a
+    /// transaction, and any attempt to modify it will fail with `EROFS`.
+    readonly: bool,
+    /// The most recent transaction group whose labels reached the disk
+    synced: Mutex<Option<TxgT>>,
 }
 
 impl Inner {
+    async fn compact_fs(inner: Arc<Self>, tree_id: TreeID) -> Result<usize> {
+        if inner.readonly {
+            return Err(Error::EROFS);
+        }
+        let itree = Inner::open_filesystem(&inner, tree_id).await?;
+        let _gate = inner.freeze_gate(tree_id).read().await;
+        inner.dirty.store(true, Ordering::Relaxed);
+        let cr = itree.credit_requirements();
+        let mut total = 0;
+        // Each pass can only dirty as many nodes as its credit allows, so keep
+        // going until a pass makes no more progress.
+        loop {
+            let credit = inner.idml.borrow_credit(cr.range_delete).await;
+            let txg = inner.idml.txg().await;
+            let merged = itree.clone().compact(.., *txg, credit).await?;
+            drop(txg);
+            total += merged;
+            if merged == 0 {
+                break Ok(total);
+            }
+        }
+    }
+
+    async fn defrag(inner: Arc<Self>, progress: Progress) -> Result<()> {
+        /// Most records to move in a single transaction
+        const BATCH: usize = 64;
+
+        let (stats, files) = Inner::fragmented_files(&inner).await?;
+        progress.set_total(stats.moved);
+        for file in files.into_iter() {
+            for batch in file.chunks(BATCH) {
+                let bytes = batch.iter()
+                    .map(|(_rid, asize)| asize * BYTES_PER_LBA as u64)
+                    .sum::<u64>();
+                // Yield to foreground I/O between batches.  Don't throttle
+                // within one, because that would hold up the transaction.
+                inner.idml.throttle_background(bytes).await;
+                let txg = inner.idml.txg().await;
+                // Cold zones are reserved for background work, so moving a
+                // file's records one after the other places them adjacently.
+                for (rid, _asize) in batch.iter() {
+                    inner.idml.relocate(*rid, Temperature::Cold, *txg).await?;
+                }
+                drop(txg);
+                inner.dirty.store(true, Ordering::Relaxed);
+                progress.add(bytes);
+            }
+        }
+        Ok(())
+    }
+
     async fn destroy_fs(
         inner: Arc<Self>,
         parent: Option<TreeID>,
@@ -163,6 +346,12 @@ impl Inner {
         // First, remove the tree from the forest.  If this succeeds, delete it
         // from disk.  Ensure that it does not remain in the cache, too.  Do
         // this all in a single transaction.
+        if inner.readonly {
+            return Err(Error::EROFS);
+        }
+        if inner.frozen.lock().unwrap().contains_key(&tree_id) {
+            return Err(Error::EBUSY);
+        }
         let tname = name.split('/').last().unwrap();
         inner.dirty.store(true, Ordering::Relaxed);
 
@@ -177,6 +366,11 @@ impl Inner {
             wg.remove(&tree_id).unwrap()
         };
 
+        inner.errors.lock().unwrap().retain(|e| e.tree_id != tree_id);
+
+        inner.dirty_quotas.lock().unwrap().remove(&tree_id);
+        inner.freeze_gates.lock().unwrap().remove(&tree_id);
+
         // Finally delete its contents
         let cr = itree.credit_requirements();
         let credit = inner.idml.borrow_credit(cr.range_delete).await;
@@ -188,11 +382,74 @@ impl Inner {
         itree.range_delete(.., *txg, credit).await
     }
 
-    fn new(idml: Arc<IDML>, forest: Forest) -> Self
+    fn new(idml: Arc<IDML>, forest: Forest, readonly: bool) -> Self
     {
-        let dirty = AtomicBool::new(true);
+        let dirty = AtomicBool::new(!readonly);
+        let dirty_quotas = Mutex::new(HashMap::new());
+        let errors = Mutex::new(BTreeSet::new());
+        let freeze_gates = Mutex::new(HashMap::new());
+        let frozen = Mutex::new(HashMap::new());
         let fs_trees = RwLock::new(BTreeMap::new());
-        Inner{dirty, fs_trees, idml, forest}
+        let synced = Mutex::new(None);
+        Inner{dirty, dirty_quotas, errors, freeze_gates, frozen, fs_trees,
+              idml, forest, readonly, synced}
+    }
+
+    /// Construct a label describing the current state of the Database
+    fn label(&self) -> Label {
+        Label {
+            forest: self.forest.serialize(),
+            errors: self.errors.lock().unwrap().iter().cloned().collect()
+        }
+    }
+
+    fn dirty_quota(&self, tree_id: TreeID) -> Option<Arc<DirtyQuota>> {
+        self.dirty_quotas.lock().unwrap().get(&tree_id).cloned()
+    }
+
+    /// Fail with `ENOSPC` if the pool is too full for an operation of this
+    /// class.
+    fn check_space(&self, class: SlopClass) -> Result<()> {
+        let size = self.idml.size();
+        let slop = slop_space(size);
+        let reserve = match class {
+            SlopClass::User => slop,
+            // Leave the last quarter for tree flushes and the cleaner
+            SlopClass::Reclaim => slop / 4,
+        };
+        if size.saturating_sub(self.idml.used()) < reserve {
+            Err(Error::ENOSPC)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn freeze_gate(&self, tree_id: TreeID) -> RwLock<()> {
+        self.freeze_gates.lock().unwrap()
+            .entry(tree_id)
+            .or_insert_with(|| RwLock::new(()))
+            .clone()
+    }
+
+    /// Flush a file system's tree, then repay the dirty data quota for
+    /// everything that it flushed.
+    async fn flush_fs(
+        &self,
+        tree_id: TreeID,
+        itree: Arc<ITree<FSKey, FSValue>>,
+        txg: TxgT
+    ) -> Result<()> {
+        let quota = self.dirty_quota(tree_id);
+        let owed = quota.as_ref().map(|q| q.take_owed());
+        let r = itree.flush(txg).await;
+        if let (Some(q), Some(owed)) = (quota, owed) {
+            if r.is_ok() {
+                q.wb.repay(owed);
+            } else {
+                q.owe(owed);
+            }
+        }
+        r
     }
 
     fn new_filesystem(
@@ -214,6 +471,58 @@ impl Inner {
     }
 
     // Must be called from within a Tokio executor context
+    /// Measure the fragmentation of every file in the pool.
+    ///
+    /// Also returns the blob records of each file worth rewriting, in offset
+    /// order, along with their allocated sizes.
+    async fn fragmented_files(inner: &Arc<Self>)
+        -> Result<(DefragStats, Vec<Vec<(RID, LbaT)>>)>
+    {
+        let mut stats = DefragStats::default();
+        let mut files = Vec::new();
+        let tree_ids = inner.forest.trees()
+            .map_ok(|(tree_id, _tod)| tree_id)
+            .try_collect::<Vec<_>>()
+            .await?;
+        for tree_id in tree_ids.into_iter() {
+            let tree = Inner::open_filesystem(inner, tree_id).await?;
+            let mut entries = tree.range::<RangeFull, FSKey>(..).boxed();
+            // A file's extents are sorted by offset, and all of them come
+            // before the next file's.
+            let mut ino = None;
+            let mut file = Vec::<(RID, PBA, LbaT)>::new();
+            loop {
+                let entry = entries.try_next().await?;
+                let next_ino = entry.as_ref().map(|(key, _)| key.object());
+                if next_ino != ino {
+                    let extents = file.iter()
+                        .map(|(_rid, pba, asize)| (*pba, *asize))
+                        .collect::<Vec<_>>();
+                    if stats.add(&extents) {
+                        files.push(file.iter()
+                            .map(|(rid, _pba, asize)| (*rid, *asize))
+                            .collect());
+                    }
+                    file.clear();
+                    ino = next_ino;
+                }
+                let value = match entry {
+                    Some((_key, value)) => value,
+                    None => break
+                };
+                if let Some(fs_tree::Extent::Blob(be)) = value.as_extent() {
+                    // The record may have been freed since we read the tree
+                    if let Some((pba, asize)) =
+                        inner.idml.locate(be.rid).await?
+                    {
+                        file.push((be.rid, pba, asize));
+                    }
+                }
+            }
+        }
+        Ok((stats, files))
+    }
+
     fn open_filesystem(inner: &Arc<Inner>, tree_id: TreeID)
         -> impl Future<Output=Result<Arc<ITree<FSKey, FSValue>>>> + Send
     {
@@ -225,6 +534,7 @@ impl Inner {
                 None => {
                     drop(rguard);
                     let tod = inner2.forest.get_tree(tree_id).await?;
+                    tod.check_version()?;
                     Inner::new_filesystem(&inner2, tree_id, tod).await
                 }
             }
@@ -236,6 +546,7 @@ impl Inner {
     fn fswrite<F, B, R>(
         inner: Arc<Self>,
         tree_id: TreeID,
+        class: SlopClass,
         ninsert: usize,
         nrange_delete: usize,
         nremove: usize,
@@ -245,20 +556,39 @@ impl Inner {
         where F: FnOnce(ReadWriteFilesystem) -> B,
               B: Future<Output=Result<R>>,
     {
-        inner.dirty.store(true, Ordering::Relaxed);
+        let readonly = inner.readonly;
+        inner.dirty.store(!readonly, Ordering::Relaxed);
         Inner::open_filesystem(&inner, tree_id)
         .and_then(move |itree| async move {
+            if readonly {
+                return Err(Error::EROFS);
+            }
+            // Wait out any freeze before borrowing credit, so a frozen file
+            // system doesn't tie up the writeback cache.
+            let _gate = inner.freeze_gate(tree_id).read().await;
+            inner.check_space(class)?;
             let cr = itree.credit_requirements();
-            let credit = inner.idml.borrow_credit(
-                ninsert * cr.insert +
+            let size = ninsert * cr.insert +
                 nrange_delete * cr.range_delete +
                 nremove * cr.remove +
-                blob_bytes
-            ).await;
+                blob_bytes;
+            // Take from the file system's own quota first, so a file system
+            // that's at its limit doesn't tie up any of the pool's credit
+            // while it waits.
+            let quota = inner.dirty_quota(tree_id);
+            let qcredit = match &quota {
+                Some(q) => Some(q.borrow(size).await),
+                None => None
+            };
+            let credit = inner.idml.borrow_credit(size).await;
             let idml2 = inner.idml.clone();
             let txg = inner.idml.txg().await;
             let ds = ReadWriteFilesystem::new(idml2, itree, *txg, credit);
             let r = f(ds).await;
+            if let (Some(q), Some(qcredit)) = (quota, qcredit) {
+                // Must happen before the txg can sync
+                q.owe(qcredit);
+            }
             drop(txg);
             r
         })
@@ -280,6 +610,16 @@ impl Inner {
     }
 }
 
+/// A file that suffered an unrecoverable read error
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd,
+         Serialize)]
+pub struct ErrorRecord {
+    /// The file system containing the damaged file
+    pub tree_id: TreeID,
+    /// The damaged file's inode number
+    pub ino: u64,
+}
+
 /// A directory entry in the Forest.
 ///
 /// Each dirent corresponds to one file system.
@@ -300,10 +640,24 @@ pub struct Stat {
     pub size: LbaT,
 }
 
+/// A pool's transaction group numbers.  Useful for correlating snapshots,
+/// labels, and logs.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct TxgStatus {
+    /// The transaction group currently accepting modifications
+    pub current: TxgT,
+    /// The most recent transaction group to be fully synced to disk, if any
+    /// have been since the pool was created.
+    pub synced: Option<TxgT>,
+    /// Transaction group of the pool's checkpoint, if it has one
+    pub checkpoint: Option<TxgT>,
+}
+
 pub struct Database {
     cleaner: Cleaner,
     inner: Arc<Inner>,
-    syncer: Syncer
+    /// Background transaction syncer.  Read-only databases don't have one.
+    syncer: Option<Syncer>
 }
 
 // Some of these methods have no unit tests.  Their test coverage is provided
@@ -334,8 +688,8 @@ impl Database {
         //   - For each entry in the RIDT, check that an entry exists in the
         //     AllocT.
         // * RIDT's refcounts are correct.
-        //   - Walk through the FSTrees building a new RIDT, and compare it to
-        //     the real RIDT.  Use some kind of external database.
+        //   - Done separately by `check_refcounts`, because it must hold every
+        //     referenced RID in memory.
         // * Spacemaps match actual usage
         //   - For each zone, calculate the actual usage by comparing entries
         //     from the Alloct and by a TXG-limited scan through the DTrees.
@@ -347,6 +701,117 @@ impl Database {
         idml_fut.and_then(|passed| forest_fut.map_ok(move |r| passed & r))
     }
 
+    /// Check that every directory's link count is two plus its number of
+    /// subdirectories.  Prints any discrepancies to stderr.
+    ///
+    /// If `repair` is set, then also correct any wrong link counts.  The
+    /// corrections will be persisted by the next transaction sync.
+    ///
+    /// # Returns
+    ///
+    /// `true` if every link count was already correct, `false` otherwise.
+    pub async fn check_dir_nlinks(&self, repair: bool) -> Result<bool> {
+        let tree_ids = self.inner.forest.trees()
+            .map_ok(|(tree_id, _tod)| tree_id)
+            .try_collect::<Vec<_>>()
+            .await?;
+        let mut passed = true;
+        for tree_id in tree_ids.into_iter() {
+            let bad = self.bad_dir_nlinks(tree_id).await?;
+            for (key, mut inode, nlink) in bad.into_iter() {
+                eprintln!(
+                    "Directory {} in tree {} has link count {}; should be {}",
+                    key.object(), tree_id.0, inode.nlink, nlink);
+                passed = false;
+                if repair {
+                    inode.nlink = nlink;
+                    let value = FSValue::inode(inode);
+                    Inner::fswrite(self.inner.clone(), tree_id,
+                        SlopClass::Reclaim, 1, 0, 0, 0,
+                        move |dataset| dataset.insert(key, value)
+                    ).await?;
+                }
+            }
+        }
+        Ok(passed)
+    }
+
+    /// Check that every indirect record's reference count is correct.
+    ///
+    /// Recomputes the reference counts by walking the Forest and every file
+    /// system tree, counting each reference to a tree node or blob, and
+    /// compares them to the RIDT.  Prints any discrepancies to stderr.
+    ///
+    /// # Returns
+    ///
+    /// `true` if every reference count was correct, `false` otherwise.
+    pub async fn check_refcounts(&self) -> Result<bool> {
+        let mut refs = BTreeMap::<RID, u64>::new();
+        let forest_addrs = self.inner.forest.addresses()
+            .collect::<Vec<_>>()
+            .await;
+        for rid in forest_addrs.into_iter() {
+            *refs.entry(rid).or_default() += 1;
+        }
+        let tree_ids = self.inner.forest.trees()
+            .map_ok(|(tree_id, _tod)| tree_id)
+            .try_collect::<Vec<_>>()
+            .await?;
+        for tree_id in tree_ids.into_iter() {
+            let tree = Inner::open_filesystem(&self.inner, tree_id).await?;
+            let addrs = tree.addresses(..).collect::<Vec<_>>().await;
+            for rid in addrs.into_iter() {
+                *refs.entry(rid).or_default() += 1;
+            }
+            tree.range::<RangeFull, FSKey>(..)
+            .try_for_each(|(_key, value)| {
+                for rid in value.blob_rids().into_iter() {
+                    *refs.entry(rid).or_default() += 1;
+                }
+                future::ok(())
+            }).await?;
+        }
+        self.inner.idml.check_refcounts(refs).await
+    }
+
+    /// Find all directories in a file system whose link counts are wrong.
+    ///
+    /// # Returns
+    ///
+    /// The key and inode of each such directory, and its correct link count.
+    async fn bad_dir_nlinks(&self, tree_id: TreeID)
+        -> Result<Vec<(FSKey, Inode, u64)>>
+    {
+        let is_subdir = |de: &fs_tree::Dirent| {
+            de.dtype == libc::DT_DIR && de.name != "." && de.name != ".."
+        };
+        let mut subdirs = HashMap::<u64, u64>::new();
+        let mut dirs = Vec::new();
+        let mut entries = self.fsreads(tree_id, |dataset| {
+            dataset.range::<RangeFull, FSKey>(..)
+        }).boxed();
+        while let Some((key, value)) = entries.try_next().await? {
+            match value {
+                FSValue::DirEntry(de) => if is_subdir(&de) {
+                    *subdirs.entry(key.object()).or_default() += 1;
+                },
+                FSValue::DirEntries(des) => {
+                    let n = des.iter().filter(|de| is_subdir(de)).count();
+                    *subdirs.entry(key.object()).or_default() += n as u64;
+                },
+                FSValue::Inode(inode) => if inode.file_type == FileType::Dir {
+                    dirs.push((key, *inode));
+                },
+                _ => ()
+            }
+        }
+        Ok(dirs.into_iter()
+            .filter_map(|(key, inode)| {
+                let nlink = 2 + subdirs.get(&key.object()).unwrap_or(&0);
+                (inode.nlink != nlink).then_some((key, inode, nlink))
+            }).collect())
+    }
+
     fn check_forest(&self) -> impl Future<Output=Result<bool>> {
         let inner2 = self.inner.clone();
         self.inner.forest.trees()
@@ -358,14 +823,65 @@ impl Database {
         })
     }
 
+    /// Take a checkpoint of the pool.
+    ///
+    /// Until the checkpoint is discarded, the pool may be rewound to its
+    /// current state at import time.  In the meantime, freed space will not
+    /// be reclaimed.
+    pub async fn checkpoint(&self) -> Result<()> {
+        // Outline:
+        // 1) Sync a transaction as usual, writing both labels
+        // 2) Preserve the second label and a complete copy of the spacemap
+        // 3) Rewrite the first label, so it will record the checkpoint
+        if self.inner.readonly {
+            return Err(Error::EROFS);
+        }
+        let inner2 = self.inner.clone();
+        self.inner.idml.advance_transaction(move |txg| async move {
+            if inner2.idml.checkpoint_txg().is_some() {
+                return Err(Error::EEXIST);
+            }
+            inner2.dirty.store(false, Ordering::Relaxed);
+            Database::sync_txg(inner2.clone(), txg).await?;
+            inner2.idml.checkpoint(txg).await?;
+            inner2.idml.clone().flush(Some(0), txg).await?;
+            inner2.idml.sync_all(txg).await?;
+            let label = inner2.label();
+            inner2.write_label(&label, 0, txg).await?;
+            inner2.idml.sync_all(txg).await
+        }).await
+    }
+
+    /// Transaction group of the pool's checkpoint, if it has one
+    pub fn checkpoint_txg(&self) -> Option<TxgT> {
+        self.inner.idml.checkpoint_txg()
+    }
+
     /// Clean zones immediately.  Does not wait for the result to be polled!
     ///
     /// The returned `Receiver` will deliver notification when cleaning is
     /// complete.  However, there is no requirement to poll it.  The client may
     /// drop it, and cleaning will continue in the background.
-    pub fn clean(&self) -> oneshot::Receiver<()> {
+    pub fn clean(&self, policy: CleanPolicy, progress: Progress)
+        -> Result<oneshot::Receiver<()>>
+    {
+        if self.inner.readonly {
+            return Err(Error::EROFS);
+        }
         self.inner.dirty.store(true, Ordering::Relaxed);
-        self.cleaner.clean()
+        Ok(self.cleaner.clean(policy, progress))
+    }
+
+    /// Report what `clean` would do with the given `policy`, without doing it.
+    pub fn clean_plan(&self, policy: CleanPolicy) -> CleanStats {
+        self.cleaner.plan(policy)
+    }
+
+    /// Merge under-filled nodes in a file system's Tree.
+    ///
+    /// Returns the number of nodes that were coalesced into their siblings.
+    pub async fn compact_fs(&self, tree: TreeID) -> Result<usize> {
+        Inner::compact_fs(self.inner.clone(), tree).await
     }
 
     /// Construct a new `Database` from its `IDML`.
@@ -374,7 +890,50 @@ impl Database {
     pub fn create(idml: Arc<IDML>) -> Self
     {
         let forest = Forest::create(idml.clone());
-        Database::new(idml, forest)
+        Database::new(idml, forest, false)
+    }
+
+    /// Rewrite the records of every fragmented file contiguously.
+    ///
+    /// Yields to foreground I/O, so it may take a long time on a busy pool.
+    /// The amount of data rewritten will be reported to `progress`.
+    pub fn defrag(&self, progress: Progress)
+        -> impl Future<Output=Result<()>> + Send
+    {
+        let inner = self.inner.clone();
+        async move {
+            if inner.readonly {
+                return Err(Error::EROFS);
+            }
+            Inner::defrag(inner, progress).await
+        }
+    }
+
+    /// Measure the pool's fragmentation, and report what `defrag` would do.
+    pub async fn defrag_plan(&self) -> Result<DefragStats> {
+        Inner::fragmented_files(&self.inner).await
+            .map(|(stats, _files)| stats)
+    }
+
+    /// Discard the pool's checkpoint, allowing freed space to be reclaimed.
+    pub async fn discard_checkpoint(&self) -> Result<()> {
+        if self.inner.readonly {
+            return Err(Error::EROFS);
+        }
+        let inner2 = self.inner.clone();
+        self.inner.idml.advance_transaction(move |txg| async move {
+            if inner2.idml.checkpoint_txg().is_none() {
+                return Err(Error::ENOENT);
+            }
+            inner2.idml.discard_checkpoint();
+            inner2.dirty.store(false, Ordering::Relaxed);
+            Database::sync_txg(inner2.clone(), txg).await?;
+            // Only now that neither label records the checkpoint is it safe
+            // to erase the zones it was using.
+            inner2.idml.release_checkpoint().await?;
+            inner2.dirty.store(true, Ordering::Relaxed);
+            Ok(())
+        }).await
     }
 
     /// Drop all data from the cache, for testing or benchmarking purposes
@@ -400,6 +959,17 @@ impl Database {
         }
     }
 
+    /// Dump the given Tree one Node at a time, without loading it all into
+    /// memory.  Only Nodes that may contain keys within `range` are dumped.
+    pub async fn dump_fs_nodes(&self, f: &mut dyn io::Write, tree: TreeID,
+                               format: DumpFormat,
+                               range: (Bound<FSKey>, Bound<FSKey>))
+        -> Result<()>
+    {
+        let fs = Inner::open_filesystem(&self.inner, tree).await?;
+        fs.dump_nodes(f, format, range).await
+    }
+
     pub async fn dump_alloct(&self, f: &mut dyn io::Write) -> Result<()>
     {
         self.inner.idml.dump_alloct(f).await
@@ -410,6 +980,45 @@ impl Database {
         self.inner.idml.dump_ridt(f).await
     }
 
+    /// Status of every leaf device, including its lifetime I/O error counts
+    pub fn leaf_status(&self) -> Vec<LeafStatus> {
+        self.inner.idml.leaf_status()
+    }
+
+    /// Predict the effect of a vdev configuration change, without making it.
+    /// See [`capacity::plan`].
+    pub fn capacity_plan(&self, change: &Change) -> Result<Plan> {
+        capacity::plan(&self.inner.idml.shape(), change)
+    }
+
+    /// Bring a removed or faulted leaf device back online, and resilver
+    /// whatever writes it missed.
+    pub async fn online(&self, leaf: VdevLeaf) -> Result<()> {
+        self.inner.idml.online(leaf).await
+    }
+
+    /// Grow the pool with a freshly created cluster.
+    ///
+    /// The transaction is synced afterwards, so the labels will record the
+    /// new cluster right away.
+    pub async fn add_cluster(&self, cluster: Cluster) -> Result<()> {
+        if self.is_readonly() {
+            return Err(Error::EROFS);
+        }
+        self.inner.idml.add_cluster(cluster)?;
+        self.sync_transaction().await
+    }
+
+    /// Every file that has suffered an unrecoverable read error, in order.
+    pub fn errors(&self) -> Vec<ErrorRecord> {
+        self.inner.errors.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// On-disk format features enabled on the pool
+    pub fn features(&self) -> Features {
+        self.inner.idml.features()
+    }
+
     /// Flush the database's dirty data to disk.
     ///
     /// Does not sync a transaction.  Does not rewrite the labels.
@@ -426,8 +1035,8 @@ impl Database {
             let txg = *txg_guard;
             let guard = inner2.fs_trees.read().await;
             stream::iter(guard.iter().map(Ok))
-                .try_fold((), move |_acc, (_tree_id, itree)|
-                          itree.clone().flush(txg)
+                .try_fold((), |_acc, (tree_id, itree)|
+                          inner2.flush_fs(*tree_id, itree.clone(), txg)
                 ).await?;
             idml2.clone().flush(None, txg).await
         }.boxed()
@@ -464,6 +1073,9 @@ impl Database {
         -> Result<TreeID>
         where S: Into<String> + 'static
     {
+        if self.inner.readonly {
+            return Err(Error::EROFS);
+        }
         self.inner.dirty.store(true, Ordering::Relaxed);
         let idml2 = self.inner.idml.clone();
         let idml3 = self.inner.idml.clone();
@@ -484,7 +1096,10 @@ impl Database {
             *txg_guard).await?;
 
         // Create the filesystem's root directory
-        Inner::fswrite(inner3, tree_id, 3, 0, 0, 0, move |dataset|
+        // The tree has already been flushed, so creating its root directory
+        // had better not fail.
+        Inner::fswrite(inner3, tree_id, SlopClass::Reclaim, 3, 0, 0, 0,
+            move |dataset|
         {
             let ino = 1;    // FUSE requires root dir to have inode 1
             let inode_key = FSKey::new(ino, ObjKey::Inode);
@@ -493,7 +1108,9 @@ impl Database {
             let inode = Inode {
                 size: 0,
                 bytes: 0,
-                nlink: 1,   // for "."
+                // One for ".", and one for "..", which refers back to the
+                // root itself.
+                nlink: 2,
                 flags: 0,
                 atime: now,
                 mtime: now,
@@ -502,7 +1119,8 @@ impl Database {
                 uid: 0,
                 gid: 0,
                 file_type: FileType::Dir,
-                perm: 0o755
+                perm: 0o755,
+                change: 0
             };
             let inode_value = FSValue::inode(inode);
 
@@ -563,6 +1181,29 @@ impl Database {
         Inner::destroy_fs(self.inner.clone(), parent, tree_id, name).await
     }
 
+    /// Block all modifications to file system `tree_id`, wait for those in
+    /// progress to finish, and sync it to disk.
+    ///
+    /// It stays frozen until [`Database::thaw_fs`].  Reads are unaffected, but
+    /// anything that would modify the file system, including updating atimes,
+    /// will wait until then.  That gives external tools a consistent view of
+    /// the file system on disk, for example to snapshot the underlying
+    /// devices.
+    pub async fn freeze_fs(&self, tree_id: TreeID) -> Result<()> {
+        if self.inner.readonly {
+            return Err(Error::EROFS);
+        }
+        // Fail if the file system doesn't exist
+        Inner::open_filesystem(&self.inner, tree_id).await?;
+        if self.is_frozen(tree_id) {
+            return Err(Error::EALREADY);
+        }
+        let guard = self.inner.freeze_gate(tree_id).write().await;
+        self.sync_transaction().await?;
+        self.inner.frozen.lock().unwrap().insert(tree_id, guard);
+        Ok(())
+    }
+
     fn fsread_real<F, B, R>(&self, tree_id: TreeID, f: F)
         -> impl Future<Output=Result<R>> + Send
         where F: FnOnce(ReadOnlyFilesystem) -> B + Send + 'static,
@@ -622,14 +1263,57 @@ impl Database {
             })
     }
 
-    fn new(idml: Arc<IDML>, forest: Forest) -> Self
+    /// Log an unrecoverable read error, and remember the damaged file.
+    ///
+    /// The record is persisted with the next transaction sync, and reported by
+    /// [`Database::errors`].
+    pub fn record_error(&self, tree_id: TreeID, ino: u64, error: Error) {
+        tracing::error!(pool = self.pool_name(), ?tree_id, ino, ?error,
+            "Unrecoverable read error");
+        let mut guard = self.inner.errors.lock().unwrap();
+        if guard.len() < ERROR_LOG_MAX &&
+            guard.insert(ErrorRecord{tree_id, ino})
+        {
+            self.inner.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Has file system `tree_id` been frozen by [`Database::freeze_fs`]?
+    pub fn is_frozen(&self, tree_id: TreeID) -> bool {
+        self.inner.frozen.lock().unwrap().contains_key(&tree_id)
+    }
+
+    /// Is this `Database` read-only?
+    pub fn is_readonly(&self) -> bool {
+        self.inner.readonly
+    }
+
+    fn new(idml: Arc<IDML>, forest: Forest, readonly: bool) -> Self
     {
         let cleaner = Cleaner::new(idml.clone(), None);
-        let inner = Arc::new(Inner::new(idml, forest));
-        let syncer = Syncer::new(inner.clone());
+        let inner = Arc::new(Inner::new(idml, forest, readonly));
+        let syncer = if readonly {
+            None
+        } else {
+            Some(Syncer::new(inner.clone()))
+        };
         Database{cleaner, inner, syncer}
     }
 
+    /// Restore the error log from the label we just opened.
+    fn with_errors(self, errors: Vec<ErrorRecord>) -> Self {
+        *self.inner.errors.lock().unwrap() = errors.into_iter().collect();
+        self
+    }
+
+    /// Record the transaction group of the label we just opened as the most
+    /// recently synced one.
+    fn with_synced_label(self) -> Self {
+        let txg = self.inner.idml.txg().now_or_never().map(|g| *g);
+        *self.inner.synced.lock().unwrap() = txg;
+        self
+    }
+
     /// Open an existing `Database`
     ///
     /// # Parameters
@@ -637,11 +1321,39 @@ impl Database {
     /// * `idml`:           An already-opened `IDML`
     /// * `label_reader`:   A `LabelReader` that has already consumed all labels
     ///                     prior to this layer.
-    pub fn open(idml: Arc<IDML>, mut label_reader: LabelReader) -> Self
+    ///
+    /// Fails with `EOPNOTSUPP` if the Forest was written in a newer format.
+    pub fn open(idml: Arc<IDML>, mut label_reader: LabelReader)
+        -> Result<Self>
+    {
+        let l: Label = label_reader.deserialize().unwrap();
+        l.forest.check_version()?;
+        let forest = Forest::open(idml.clone(), l.forest);
+        Ok(Database::new(idml, forest, false)
+            .with_errors(l.errors)
+            .with_synced_label())
+    }
+
+    /// Open an existing `Database` for read-only access
+    ///
+    /// No transactions will ever be synced, and any attempt to modify the
+    /// `Database` will fail with `EROFS`.  The lower layers must also have
+    /// been opened read-only.
+    ///
+    /// # Parameters
+    ///
+    /// * `idml`:           An already-opened `IDML`
+    /// * `label_reader`:   A `LabelReader` that has already consumed all labels
+    ///                     prior to this layer.
+    pub fn open_readonly(idml: Arc<IDML>, mut label_reader: LabelReader)
+        -> Result<Self>
     {
         let l: Label = label_reader.deserialize().unwrap();
+        l.forest.check_version()?;
         let forest = Forest::open(idml.clone(), l.forest);
-        Database::new(idml, forest)
+        Ok(Database::new(idml, forest, true)
+            .with_errors(l.errors)
+            .with_synced_label())
     }
 
     pub fn pool_name(&self) -> &str {
@@ -660,10 +1372,24 @@ impl Database {
         }
     }
 
+    /// Verify the checksum of every indirect record, keeping up to `inflight`
+    /// reads in flight at once.  Prints any corrupt records to stderr.
+    ///
+    /// # Returns
+    ///
+    /// `true` on success, `false` on failure
+    pub async fn scrub(&self, inflight: usize) -> Result<bool> {
+        self.inner.idml.scrub(inflight).await
+    }
+
     /// Shutdown all background tasks and close the Database
     pub async fn shutdown(self) {
-        future::join(self.syncer.shutdown(),
-                     self.cleaner.shutdown())
+        let syncer_fut = async move {
+            if let Some(syncer) = self.syncer {
+                syncer.shutdown().await;
+            }
+        };
+        future::join(syncer_fut, self.cleaner.shutdown())
         .await;
     }
 
@@ -675,13 +1401,107 @@ impl Database {
         }
     }
 
+    /// Limit how much dirty data the file system `tree_id` may hold in the
+    /// writeback cache.  A `limit` of 0 removes the limit.
+    pub fn set_dirty_limit(&self, tree_id: TreeID, limit: u64) {
+        let limit = usize::try_from(limit).unwrap_or(usize::MAX)
+            .min(self.inner.idml.writeback_size());
+        let mut guard = self.inner.dirty_quotas.lock().unwrap();
+        let old = if limit == 0 {
+            guard.remove(&tree_id)
+        } else {
+            guard.insert(tree_id, Arc::new(DirtyQuota::new(limit)))
+        };
+        if let Some(old) = old {
+            old.retire();
+        }
+    }
+
+    /// The most recent transaction group to be fully synced to disk, if any.
+    ///
+    /// Unlike [`Database::txgs`], this never waits for a sync in progress.
+    pub fn synced_txg(&self) -> Option<TxgT> {
+        *self.inner.synced.lock().unwrap()
+    }
+
+    /// Allow modifications to a file system frozen by [`Database::freeze_fs`].
+    pub fn thaw_fs(&self, tree_id: TreeID) -> Result<()> {
+        self.inner.frozen.lock().unwrap()
+            .remove(&tree_id)
+            .map(drop)
+            .ok_or(Error::EINVAL)
+    }
+
+    /// Report the pool's current, last synced, and checkpoint transaction
+    /// groups.
+    pub async fn txgs(&self) -> TxgStatus {
+        let current = *self.inner.idml.txg().await;
+        let synced = *self.inner.synced.lock().unwrap();
+        let checkpoint = self.inner.idml.checkpoint_txg();
+        TxgStatus{current, synced, checkpoint}
+    }
+
+    /// Zone open/close statistics, summed across the whole pool
+    pub fn zone_stats(&self) -> ZoneStats {
+        self.inner.idml.zone_stats()
+    }
+
+    /// Change pool properties, and record them in the label.
+    pub async fn set_pool_props(&self, props: Vec<PoolProperty>) -> Result<()>
+    {
+        if self.inner.readonly {
+            return Err(Error::EROFS);
+        }
+        for prop in props.into_iter() {
+            self.inner.idml.set_pool_property(prop);
+        }
+        self.inner.dirty.store(true, Ordering::Relaxed);
+        self.sync_transaction().await
+    }
+
+    /// Enable on-disk format features, and record them in the label.
+    ///
+    /// If `features` is empty, enable every feature that this version
+    /// supports.  Older software may be unable to import the pool afterwards.
+    ///
+    /// # Returns
+    ///
+    /// The features that weren't already enabled
+    pub async fn upgrade(&self, features: &[Feature]) -> Result<Vec<Feature>>
+    {
+        if self.inner.readonly {
+            return Err(Error::EROFS);
+        }
+        let features = if features.is_empty() {
+            &Feature::ALL[..]
+        } else {
+            features
+        };
+        let enabled = features.iter()
+            .copied()
+            .filter(|f| self.inner.idml.enable_feature(*f))
+            .collect::<Vec<_>>();
+        if !enabled.is_empty() {
+            self.inner.dirty.store(true, Ordering::Relaxed);
+            self.sync_transaction().await?;
+        }
+        Ok(enabled)
+    }
+
     /// Finish the current transaction group and start a new one.
     pub fn sync_transaction(&self)
         -> impl Future<Output=Result<()>> + Send
     {
-        future::try_join(self.syncer.kick(),
-                         Database::sync_transaction_priv(&self.inner))
-            .map_ok(drop)
+        match &self.syncer {
+            Some(syncer) => {
+                future::try_join(syncer.kick(),
+                                 Database::sync_transaction_priv(&self.inner))
+                    .map_ok(drop)
+                    .boxed()
+            }
+            // Nothing can be dirty in a read-only Database
+            None => future::ok(()).boxed()
+        }
     }
 
     fn sync_transaction_priv(inner: &Arc<Inner>)
@@ -699,41 +1519,49 @@ impl Database {
             return future::ok(()).boxed();
         }
         let inner2 = inner.clone();
-        let fut = inner.idml.advance_transaction(move |txg| async move {
-            let guard = inner2.fs_trees.read().await;
-            guard.iter()
-                .map(move |(_, itree)| {
-                    itree.clone().flush(txg)
-                }).collect::<FuturesUnordered<_>>()
-                .try_collect::<Vec<_>>().await?;
-            // TODO: only write out the dirty trees
-            let forest_futs = guard.iter()
-                .map(|(tree_id, itree)| {
-                    inner2.forest
-                        .update_tree(*tree_id, itree.serialize().unwrap(), txg)
-                }).collect::<FuturesUnordered<_>>();
-            drop(guard);
-            forest_futs.try_collect::<Vec<_>>().await?;
-            inner2.forest.flush(txg).await?;
-            inner2.idml.clone().flush(Some(0), txg).await?;
-            inner2.idml.sync_all(txg).await?;
-            let forest = inner2.forest.serialize();
-            let label = Label {forest};
-            inner2.write_label(&label, 0, txg).await?;
-            inner2.idml.clone().flush(Some(1), txg).await?;
-            // The only time we need to read the second label is if we lose
-            // power while writing the first.  The fact that we reached this
-            // point means that that won't happen, at least not until the
-            // _next_ transaction sync.  So we don't need an additional
-            // sync_all between inner2.idml.clone().flush(1, ...) and
-            // inner2.idml.sync_all(...).
-            inner2.idml.sync_all(txg).await?;
-            inner2.write_label(&label, 1, txg).await?;
-            inner2.idml.sync_all(txg).await
+        let fut = inner.idml.advance_transaction(move |txg| {
+            Database::sync_txg(inner2, txg)
         });
         fut.boxed()
     }
 
+    /// Flush all trees and write both labels for transaction group `txg`.
+    ///
+    /// Must be called with the txg lock held.
+    async fn sync_txg(inner: Arc<Inner>, txg: TxgT) -> Result<()> {
+        let guard = inner.fs_trees.read().await;
+        guard.iter()
+            .map(|(tree_id, itree)| {
+                inner.flush_fs(*tree_id, itree.clone(), txg)
+            }).collect::<FuturesUnordered<_>>()
+            .try_collect::<Vec<_>>().await?;
+        // TODO: only write out the dirty trees
+        let forest_futs = guard.iter()
+            .map(|(tree_id, itree)| {
+                inner.forest
+                    .update_tree(*tree_id, itree.serialize().unwrap(), txg)
+            }).collect::<FuturesUnordered<_>>();
+        drop(guard);
+        forest_futs.try_collect::<Vec<_>>().await?;
+        inner.forest.flush(txg).await?;
+        inner.idml.clone().flush(Some(0), txg).await?;
+        inner.idml.sync_all(txg).await?;
+        let label = inner.label();
+        inner.write_label(&label, 0, txg).await?;
+        inner.idml.clone().flush(Some(1), txg).await?;
+        // The only time we need to read the second label is if we lose
+        // power while writing the first.  The fact that we reached this
+        // point means that that won't happen, at least not until the
+        // _next_ transaction sync.  So we don't need an additional
+        // sync_all between inner.idml.clone().flush(1, ...) and
+        // inner.idml.sync_all(...).
+        inner.idml.sync_all(txg).await?;
+        inner.write_label(&label, 1, txg).await?;
+        inner.idml.sync_all(txg).await?;
+        *inner.synced.lock().unwrap() = Some(txg);
+        Ok(())
+    }
+
     /// Perform a read-write operation on a Filesystem
     ///
     /// All operations conducted by the supplied closure will be completed
@@ -766,8 +1594,29 @@ impl Database {
               B: Future<Output=Result<R>> + Send,
               R: 'static
     {
-        Inner::fswrite(self.inner.clone(), tree_id, ninsert, nrange_delete,
-            nremove, blob_bytes, f)
+        Inner::fswrite(self.inner.clone(), tree_id, SlopClass::User, ninsert,
+            nrange_delete, nremove, blob_bytes, f)
+    }
+
+    /// Like [`Database::fswrite`], but for operations that free space or are
+    /// otherwise essential.  They may succeed even when the pool is too full
+    /// for `fswrite`.  See [`slop_space`](super::slop_space).
+    #[cfg(not(test))]
+    pub fn fswrite_reclaim<F, B, R>(
+        &self,
+        tree_id: TreeID,
+        ninsert: usize,
+        nrange_delete: usize,
+        nremove: usize,
+        blob_bytes: usize,
+        f: F
+    ) -> impl Future<Output=Result<R>> + Send
+        where F: FnOnce(ReadWriteFilesystem) -> B + Send + 'static,
+              B: Future<Output=Result<R>> + Send,
+              R: 'static
+    {
+        Inner::fswrite(self.inner.clone(), tree_id, SlopClass::Reclaim,
+            ninsert, nrange_delete, nremove, blob_bytes, f)
     }
 
     /// Helper for MockDatabase::fswrite.
@@ -786,6 +1635,13 @@ impl Database {
     }
     // LCOV_EXCL_STOP
 
+    /// Read the roots of the Forest and of the IDML's trees, to verify that
+    /// the labels used to open the `Database` reference readable metadata.
+    pub async fn verify_roots(&self) -> Result<()> {
+        self.inner.idml.verify_roots().await?;
+        self.inner.forest.verify_root().await
+    }
+
     /// Get the maximum size of the writeback cache
     pub fn writeback_size(&self) -> usize {
         self.inner.idml.writeback_size()
@@ -819,6 +1675,22 @@ impl MockDatabase {
     {
         f(self.fswrite_inner(tree_id))
     }
+
+    pub fn fswrite_reclaim<F, B, R>(
+        &self,
+        tree_id: TreeID,
+        _ninsert: usize,
+        _nrange_delete: usize,
+        _nremove: usize,
+        _blob_bytes: usize,
+        f: F
+    ) -> impl Future<Output=Result<R>> + Send
+        where F: FnOnce(ReadWriteFilesystem) -> B + Send + 'static,
+              B: Future<Output=Result<R>> + Send,
+              R: 'static
+    {
+        f(self.fswrite_inner(tree_id))
+    }
 }
 
 #[cfg(test)]
@@ -837,7 +1709,7 @@ mod database {
     // pet kcov
     #[test]
     fn debug() {
-        let label = Label{forest: TreeOnDisk::default()};
+        let label = Label{forest: TreeOnDisk::default(), errors: Vec::new()};
         format!("{label:?}");
     }
 
@@ -880,7 +1752,7 @@ mod database {
             .once()
             .return_once(|_: RangeFull| rq);
 
-        let db = Database::new(Arc::new(idml), forest.into());
+        let db = Database::new(Arc::new(idml), forest.into(), false);
         let r = db.check().await.unwrap();
         assert!(r);
     }
@@ -924,7 +1796,7 @@ mod database {
             .once()
             .return_once(|_: RangeFull| rq);
 
-        let db = Database::new(Arc::new(idml), forest.into());
+        let db = Database::new(Arc::new(idml), forest.into(), false);
         let r = db.check().await.unwrap();
         assert!(!r);
     }
@@ -968,7 +1840,7 @@ mod database {
             .once()
             .return_once(|_: RangeFull| rq);
 
-        let db = Database::new(Arc::new(idml), forest.into());
+        let db = Database::new(Arc::new(idml), forest.into(), false);
         let r = db.check().await.unwrap();
         assert!(!r);
     }
@@ -988,7 +1860,7 @@ mod database {
 
         let forest = Tree::default();
 
-        let db = Database::new(Arc::new(idml), forest.into());
+        let db = Database::new(Arc::new(idml), forest.into(), false);
         Database::flush(&db.inner).await.unwrap();
     }
 
@@ -998,7 +1870,7 @@ mod database {
         let idml = IDML::default();
         let forest = Tree::default();
 
-        let db = Database::new(Arc::new(idml), forest.into());
+        let db = Database::new(Arc::new(idml), forest.into(), false);
         db.inner.dirty.store(false, Ordering::Relaxed);
         Database::flush(&db.inner).await.unwrap();
     }
@@ -1008,7 +1880,7 @@ mod database {
         let idml = IDML::default();
         let forest = Tree::default();
 
-        let db = Database::new(Arc::new(idml), forest.into());
+        let db = Database::new(Arc::new(idml), forest.into(), false);
         db.shutdown().await
     }
 
@@ -1069,7 +1941,7 @@ mod database {
             .with(eq(TxgT::from(0)))
             .returning(|_| Box::pin(future::ok::<(), Error>(())));
 
-        let db = Database::new(Arc::new(idml), forest.into());
+        let db = Database::new(Arc::new(idml), forest.into(), false);
         db.sync_transaction().await.unwrap();
         // Syncing a 2nd time should be a no-op, since the database
         // isn't dirty.
@@ -1082,10 +1954,156 @@ mod database {
         let idml = IDML::default();
         let forest = Tree::default();
 
-        let db = Database::new(Arc::new(idml), forest.into());
+        let db = Database::new(Arc::new(idml), forest.into(), false);
         db.inner.dirty.store(false, Ordering::Relaxed);
         db.sync_transaction().await.unwrap();
     }
+
+    /// A read-only Database should refuse to create file systems, without
+    /// touching the IDML.
+    #[tokio::test]
+    async fn create_fs_readonly() {
+        let idml = IDML::default();
+        let forest = Tree::default();
+
+        let db = Database::new(Arc::new(idml), forest.into(), true);
+        assert_eq!(db.create_fs(None, "").await, Err(Error::EROFS));
+    }
+
+    /// Syncing a read-only Database should be a no-op, and it should be able
+    /// to shut down without a Syncer.
+    #[tokio::test]
+    async fn sync_transaction_readonly() {
+        let idml = IDML::default();
+        let forest = Tree::default();
+
+        let db = Database::new(Arc::new(idml), forest.into(), true);
+        assert!(db.is_readonly());
+        db.sync_transaction().await.unwrap();
+        db.shutdown().await
+    }
+
+    /// A pool may only have one checkpoint at a time
+    #[tokio::test]
+    async fn checkpoint_eexist() {
+        let mut idml = IDML::default();
+        let forest = Tree::default();
+        idml.expect_advance_transaction_inner()
+            .once()
+            .returning(|| TxgT::from(5));
+        idml.expect_checkpoint_txg()
+            .return_const(Some(TxgT::from(2)));
+
+        let db = Database::new(Arc::new(idml), forest.into(), false);
+        assert_eq!(db.checkpoint().await, Err(Error::EEXIST));
+    }
+
+    #[tokio::test]
+    async fn discard_checkpoint_enoent() {
+        let mut idml = IDML::default();
+        let forest = Tree::default();
+        idml.expect_advance_transaction_inner()
+            .once()
+            .returning(|| TxgT::from(5));
+        idml.expect_checkpoint_txg()
+            .return_const(None);
+
+        let db = Database::new(Arc::new(idml), forest.into(), false);
+        assert_eq!(db.discard_checkpoint().await, Err(Error::ENOENT));
+    }
+
+    /// Upgrading a pool that already has every feature shouldn't sync
+    #[tokio::test]
+    async fn upgrade_already_enabled() {
+        let mut idml = IDML::default();
+        let forest = Tree::default();
+        idml.expect_enable_feature()
+            .times(Feature::ALL.len())
+            .return_const(false);
+        idml.expect_advance_transaction_inner()
+            .never();
+
+        let db = Database::new(Arc::new(idml), forest.into(), false);
+        assert_eq!(db.upgrade(&[]).await, Ok(vec![]));
+    }
+
+    #[tokio::test]
+    async fn upgrade_readonly() {
+        let idml = IDML::default();
+        let forest = Tree::default();
+        let db = Database::new(Arc::new(idml), forest.into(), true);
+        assert_eq!(db.upgrade(&[Feature::LargeRecords]).await,
+                   Err(Error::EROFS));
+    }
+
+    #[tokio::test]
+    async fn set_pool_props_readonly() {
+        let mut idml = IDML::default();
+        let forest = Tree::default();
+        idml.expect_set_pool_property()
+            .never();
+        let db = Database::new(Arc::new(idml), forest.into(), true);
+        let props = vec![PoolProperty::Autotrim(true)];
+        assert_eq!(db.set_pool_props(props).await, Err(Error::EROFS));
+    }
+
+    #[tokio::test]
+    async fn clean_readonly() {
+        let idml = IDML::default();
+        let forest = Tree::default();
+        let db = Database::new(Arc::new(idml), forest.into(), true);
+        let r = db.clean(CleanPolicy::Mixed, Progress::default());
+        assert_eq!(r.err(), Some(Error::EROFS));
+    }
+
+    #[tokio::test]
+    async fn defrag_readonly() {
+        let idml = IDML::default();
+        let forest = Tree::default();
+        let db = Database::new(Arc::new(idml), forest.into(), true);
+        assert_eq!(db.defrag(Progress::default()).await, Err(Error::EROFS));
+    }
+}
+
+mod dirty_quota {
+    use super::super::*;
+
+    /// Borrowers must wait until owed credit gets repaid
+    #[test]
+    fn owe_and_settle() {
+        let q = DirtyQuota::new(10);
+        let credit = q.borrow(8).now_or_never().unwrap();
+        q.owe(credit);
+        assert!(q.borrow(8).now_or_never().is_none());
+        let owed = q.take_owed();
+        q.wb.repay(owed);
+        let credit = q.borrow(8).now_or_never().unwrap();
+        q.wb.repay(credit);
+    }
+
+    /// An operation larger than the quota may proceed once it has the quota
+    /// to itself.
+    #[test]
+    fn oversized() {
+        let q = DirtyQuota::new(10);
+        let credit = q.borrow(100).now_or_never().unwrap();
+        assert_eq!(credit, 10);
+        q.wb.repay(credit);
+    }
+
+    /// Once retired, a quota repays credit immediately instead of holding it
+    #[test]
+    fn retire() {
+        let q = DirtyQuota::new(10);
+        let credit0 = q.borrow(8).now_or_never().unwrap();
+        q.owe(credit0);
+        q.retire();
+        let credit1 = q.borrow(8).now_or_never().unwrap();
+        q.owe(credit1);
+        assert!(q.take_owed().is_null());
+        let credit2 = q.borrow(8).now_or_never().unwrap();
+        q.wb.repay(credit2);
+    }
 }
 
 mod syncer_msg {
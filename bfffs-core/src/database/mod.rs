@@ -10,6 +10,7 @@ use crate::{
     idml::IDML,
     tree::{Key, MinValue, RangeQuery, TreeOnDisk, Value},
     types::*,
+    util::BYTES_PER_LBA,
     writeback::Credit
 };
 use futures::{
@@ -39,9 +40,31 @@ mod database;
 #[double]
 pub use self::database::Database;
 pub use self::database::Dirent;
+pub use self::database::ErrorRecord;
+pub(crate) use self::database::Label;
 
 pub use self::database::ReadOnlyFilesystem;
 pub use self::database::ReadWriteFilesystem;
+pub use self::database::TxgStatus;
+
+/// Fraction of the pool's capacity reserved as "slop" space, as a power of two.
+const SLOP_SHIFT: u32 = 5;
+
+/// Upper limit on the slop space, in LBAs.  Even a large pool never needs more
+/// than this much to recover from being full.
+const SLOP_MAX: LbaT = (128 << 30) / BYTES_PER_LBA as LbaT;
+
+/// How much space, in LBAs, is reserved in a pool of `size` LBAs.
+///
+/// Operations that consume space will fail with `ENOSPC` before they eat into
+/// the slop.  That leaves room for operations that free space, like unlink,
+/// and for the metadata needed to sync transactions and clean zones, so a full
+/// pool can never wedge itself.  Operations that free space may use most of
+/// the slop, but not all of it.  Tree flushes and the cleaner may use all of
+/// it.
+pub fn slop_space(size: LbaT) -> LbaT {
+    (size >> SLOP_SHIFT).min(SLOP_MAX)
+}
 
 /// Unique identifier for a tree, like a ZFS guid
 #[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, PartialOrd, Ord,
@@ -160,6 +183,11 @@ impl Forest {
         Self(Arc::new(ITree::create(idml, true, 4.0, 2.0)))
     }
 
+    /// Return the address of every node in the Forest
+    pub fn addresses(&self) -> impl Stream<Item=RID> {
+        self.0.addresses(..)
+    }
+
     /// Dump a a YAMLized representation of the Forest
     pub async fn dump(&self, f: &mut dyn io::Write)
         -> Result<()>
@@ -395,6 +423,11 @@ impl Forest {
             None => Ok(None)
         }
     }
+
+    /// Read the Forest's root node, to verify that it is accessible.
+    pub async fn verify_root(&self) -> Result<()> {
+        self.0.verify_root().await
+    }
 }
 
 #[cfg(test)]
@@ -407,6 +440,26 @@ impl From<ITree<ForestKey, ForestValue>> for Forest {
 // LCOV_EXCL_START
 #[cfg(test)]
 mod t {
+mod slop_space {
+    use super::super::*;
+
+    #[test]
+    fn large() {
+        let size = (1 << 50) / BYTES_PER_LBA as LbaT;   // 1 PB
+        assert_eq!(slop_space(size), SLOP_MAX);
+    }
+
+    #[test]
+    fn small() {
+        assert_eq!(slop_space(262_144), 8192);
+    }
+
+    #[test]
+    fn tiny() {
+        assert_eq!(slop_space(16), 0);
+    }
+}
+
 mod forest_key {
     use pretty_assertions::assert_eq;
     use super::super::*;
@@ -8,7 +8,7 @@ use crate::{
     writeback::Credit
 };
 use divbuf::{DivBuf, DivBufShared};
-use futures::{Future, FutureExt};
+use futures::{Future, FutureExt, TryFutureExt, future};
 use std::{
     borrow::Borrow,
     fmt::Debug,
@@ -25,6 +25,16 @@ struct Dataset<K: Key, V: Value>  {
 }
 
 impl<K: Key, V: Value> Dataset<K, V> {
+    fn dup_blob(&self, rid: RID, txg: TxgT)
+        -> impl Future<Output=Result<()>> + Send + '_
+    {
+        self.idml.dup(rid, txg)
+    }
+
+    fn evict_blob(&self, rid: RID) {
+        self.idml.evict(&rid)
+    }
+
     fn get(&self, k: K) -> impl Future<Output=Result<Option<V>>>
     {
         self.tree.get(k)
@@ -37,6 +47,40 @@ impl<K: Key, V: Value> Dataset<K, V> {
         self.idml.get::<DivBufShared, DivBuf>(&rid)
     }
 
+    /// Read directly from the IDML, falling back to a ditto copy
+    fn get_blob_ditto(&self, rid: RID, ditto: RID)
+        -> Pin<Box<dyn Future<Output=Result<Box<DivBuf>>> + Send>>
+    {
+        let idml = self.idml.clone();
+        self.get_blob(rid)
+        .or_else(move |e| {
+            if e == Error::EINTEGRITY {
+                tracing::warn!(?rid, ?ditto, "Reading ditto copy");
+                idml.get_uncached::<DivBufShared, DivBuf>(ditto)
+            } else {
+                future::err(e).boxed()
+            }
+        }).boxed()
+    }
+
+    fn get_blob_range(&self, rid: RID, offset: usize, len: usize)
+        -> Pin<Box<dyn Future<Output=Result<DivBuf>> + Send>>
+    {
+        self.idml.get_range(rid, offset, len)
+    }
+
+    fn get_blob_uncached(&self, rid: RID)
+        -> Pin<Box<dyn Future<Output=Result<Box<DivBuf>>> + Send>>
+    {
+        self.idml.get_uncached::<DivBufShared, DivBuf>(rid)
+    }
+
+    fn get_multi(&self, keys: Vec<K>)
+        -> impl Future<Output=Result<Vec<Option<V>>>>
+    {
+        self.tree.get_multi(keys)
+    }
+
     fn insert(&self, txg: TxgT, k: K, v: V, credit: Credit)
         -> impl Future<Output=Result<Option<V>>>
     {
@@ -120,6 +164,10 @@ impl<K: Key, V: Value> ReadOnlyDataset<K, V> {
 }
 
 impl<K: Key, V: Value> ReadDataset<K, V> for ReadOnlyDataset<K, V> {
+    fn evict_blob(&self, rid: RID) {
+        self.dataset.evict_blob(rid)
+    }
+
     fn get(&self, k: K)
         -> Pin<Box<dyn Future<Output=Result<Option<V>>> + Send>>
     {
@@ -133,6 +181,30 @@ impl<K: Key, V: Value> ReadDataset<K, V> for ReadOnlyDataset<K, V> {
         self.dataset.get_blob(rid)
     }
 
+    fn get_blob_ditto(&self, rid: RID, ditto: RID)
+        -> Pin<Box<dyn Future<Output=Result<Box<DivBuf>>> + Send>>
+    {
+        self.dataset.get_blob_ditto(rid, ditto)
+    }
+
+    fn get_blob_range(&self, rid: RID, offset: usize, len: usize)
+        -> Pin<Box<dyn Future<Output=Result<DivBuf>> + Send>>
+    {
+        self.dataset.get_blob_range(rid, offset, len)
+    }
+
+    fn get_blob_uncached(&self, rid: RID)
+        -> Pin<Box<dyn Future<Output=Result<Box<DivBuf>>> + Send>>
+    {
+        self.dataset.get_blob_uncached(rid)
+    }
+
+    fn get_multi(&self, keys: Vec<K>)
+        -> Pin<Box<dyn Future<Output=Result<Vec<Option<V>>>> + Send>>
+    {
+        self.dataset.get_multi(keys).boxed()
+    }
+
     fn range<R, T>(&self, range: R) -> RangeQuery<K, T, V>
         where K: Borrow<T>,
               R: RangeBounds<T> + 'static,
@@ -151,6 +223,14 @@ pub struct ReadWriteDataset<K: Key, V: Value>  {
 }
 
 impl<K: Key, V: Value> ReadWriteDataset<K, V> {
+    /// Add a reference to a blob, so it can be shared with another object or
+    /// dataset.
+    pub fn dup_blob(&self, rid: RID)
+        -> impl Future<Output=Result<()>> + Send + '_
+    {
+        self.dataset.dup_blob(rid, self.txg)
+    }
+
     pub fn insert(&self, k: K, v: V)
         -> impl Future<Output=Result<Option<V>>> + Send
     {
@@ -190,9 +270,18 @@ impl<K: Key, V: Value> ReadWriteDataset<K, V> {
         let credit = self.credit.atomic_split(self.cr.remove);
         self.dataset.remove(k, self.txg, credit)
     }
+
+    /// The transaction group that this handle's modifications will belong to
+    pub fn txg(&self) -> TxgT {
+        self.txg
+    }
 }
 
 impl<K: Key, V: Value> ReadDataset<K, V> for ReadWriteDataset<K, V> {
+    fn evict_blob(&self, rid: RID) {
+        self.dataset.evict_blob(rid)
+    }
+
     fn get(&self, k: K)
         -> Pin<Box<dyn Future<Output=Result<Option<V>>> + Send>>
     {
@@ -206,6 +295,30 @@ impl<K: Key, V: Value> ReadDataset<K, V> for ReadWriteDataset<K, V> {
         self.dataset.get_blob(rid)
     }
 
+    fn get_blob_ditto(&self, rid: RID, ditto: RID)
+        -> Pin<Box<dyn Future<Output=Result<Box<DivBuf>>> + Send>>
+    {
+        self.dataset.get_blob_ditto(rid, ditto)
+    }
+
+    fn get_blob_range(&self, rid: RID, offset: usize, len: usize)
+        -> Pin<Box<dyn Future<Output=Result<DivBuf>> + Send>>
+    {
+        self.dataset.get_blob_range(rid, offset, len)
+    }
+
+    fn get_blob_uncached(&self, rid: RID)
+        -> Pin<Box<dyn Future<Output=Result<Box<DivBuf>>> + Send>>
+    {
+        self.dataset.get_blob_uncached(rid)
+    }
+
+    fn get_multi(&self, keys: Vec<K>)
+        -> Pin<Box<dyn Future<Output=Result<Vec<Option<V>>>> + Send>>
+    {
+        self.dataset.get_multi(keys).boxed()
+    }
+
     fn range<R, T>(&self, range: R) -> RangeQuery<K, T, V>
         where K: Borrow<T>,
               R: RangeBounds<T> + 'static,
@@ -29,10 +29,19 @@ mock! {
         pub fn used(&self) -> LbaT;
     }
     impl<K: Key, V: Value> ReadDataset<K, V> for ReadOnlyDataset<K, V> {
+        fn evict_blob(&self, rid: RID);
         fn get(&self, k: K)
             -> Pin<Box<dyn Future<Output=Result<Option<V>>> + Send>>;
         fn get_blob(&self, rid: RID)
             -> Pin<Box<dyn Future<Output=Result<Box<DivBuf>>> + Send>>;
+        fn get_blob_ditto(&self, rid: RID, ditto: RID)
+            -> Pin<Box<dyn Future<Output=Result<Box<DivBuf>>> + Send>>;
+        fn get_blob_range(&self, rid: RID, offset: usize, len: usize)
+            -> Pin<Box<dyn Future<Output=Result<DivBuf>> + Send>>;
+        fn get_blob_uncached(&self, rid: RID)
+            -> Pin<Box<dyn Future<Output=Result<Box<DivBuf>>> + Send>>;
+        fn get_multi(&self, keys: Vec<K>)
+            -> Pin<Box<dyn Future<Output=Result<Vec<Option<V>>>> + Send>>;
         fn range<R, T>(&self, range: R) -> RangeQuery<K, T, V>
             where K: Borrow<T>,
                   R: RangeBounds<T> + 'static,
@@ -47,6 +56,8 @@ mock! {
     {
         pub fn borrow_credit(&self, _size: usize)
             -> impl Future<Output=Credit> + Send;
+        pub fn dup_blob(&self, rid: RID)
+            -> Pin<Box<dyn Future<Output=Result<()>> + Send>>;
         pub fn insert(&self, k: K, v: V)
             -> Pin<Box<dyn Future<Output=Result<Option<V>>> + Send>>;
         pub fn last_key(&self)
@@ -66,12 +77,22 @@ mock! {
             >>;
         pub fn repay_credit(&self, credit: Credit);
         pub fn size(&self) -> LbaT;
+        pub fn txg(&self) -> TxgT;
     }
     impl<K: Key, V: Value> ReadDataset<K, V> for ReadWriteDataset<K, V> {
+        fn evict_blob(&self, rid: RID);
         fn get(&self, k: K)
             -> Pin<Box<dyn Future<Output=Result<Option<V>>> + Send>>;
         fn get_blob(&self, rid: RID)
             -> Pin<Box<dyn Future<Output=Result<Box<DivBuf>>> + Send>>;
+        fn get_blob_ditto(&self, rid: RID, ditto: RID)
+            -> Pin<Box<dyn Future<Output=Result<Box<DivBuf>>> + Send>>;
+        fn get_blob_range(&self, rid: RID, offset: usize, len: usize)
+            -> Pin<Box<dyn Future<Output=Result<DivBuf>> + Send>>;
+        fn get_blob_uncached(&self, rid: RID)
+            -> Pin<Box<dyn Future<Output=Result<Box<DivBuf>>> + Send>>;
+        fn get_multi(&self, keys: Vec<K>)
+            -> Pin<Box<dyn Future<Output=Result<Vec<Option<V>>>> + Send>>;
         fn range<R, T>(&self, range: R) -> RangeQuery<K, T, V>
             where K: Borrow<T>,
                   R: RangeBounds<T> + 'static,
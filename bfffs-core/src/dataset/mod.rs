@@ -38,6 +38,9 @@ pub type ITree<K, V> = Tree<RID, IDML, K, V>;
 
 /// A Dataset that can be read from
 pub trait ReadDataset<K: Key, V: Value> {
+    /// Drop a blob from the cache, if present.
+    fn evict_blob(&self, rid: RID);
+
     fn get(&self, k: K)
         -> Pin<Box<dyn Future<Output=Result<Option<V>>> + Send>>;
 
@@ -45,6 +48,26 @@ pub trait ReadDataset<K: Key, V: Value> {
     fn get_blob(&self, rid: RID)
         -> Pin<Box<dyn Future<Output=Result<Box<DivBuf>>> + Send>>;
 
+    /// Like `get_blob`, but if the blob fails its checksum, then read `ditto`
+    /// instead.  `ditto` must be a second copy of the same blob.
+    fn get_blob_ditto(&self, rid: RID, ditto: RID)
+        -> Pin<Box<dyn Future<Output=Result<Box<DivBuf>>> + Send>>;
+
+    /// Like `get_blob`, but only get the `len` bytes at `offset`, and don't
+    /// add the blob to the cache.
+    fn get_blob_range(&self, rid: RID, offset: usize, len: usize)
+        -> Pin<Box<dyn Future<Output=Result<DivBuf>> + Send>>;
+
+    /// Like `get_blob`, but don't add the blob to the cache
+    fn get_blob_uncached(&self, rid: RID)
+        -> Pin<Box<dyn Future<Output=Result<Box<DivBuf>>> + Send>>;
+
+    /// Like `get`, but for many keys at once.  Cheaper than many separate
+    /// `get`s, because the tree is only descended once.  Results are in the
+    /// same order as `keys`.
+    fn get_multi(&self, keys: Vec<K>)
+        -> Pin<Box<dyn Future<Output=Result<Vec<Option<V>>>> + Send>>;
+
     fn range<R, T>(&self, range: R) -> RangeQuery<K, T, V>
         where K: Borrow<T>,
               R: RangeBounds<T> + 'static,
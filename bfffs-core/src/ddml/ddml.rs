@@ -1,21 +1,26 @@
 // vim: tw=80
 use crate::{
     cache::{self, Cache, Cacheable, CacheRef, Key},
+    capacity::ClusterShape,
     dml::*,
+    feature::{Feature, Features},
     label::*,
-    pool::ClosedZone,
+    pool::{ClosedZone, Temperature, ZoneStats},
+    pool_property::PoolProperty,
     types::*,
     util::*,
     vdev::*,
+    vdev_block::VdevLeaf,
     writeback::Credit
 };
-use divbuf::DivBufShared;
+use divbuf::{DivBuf, DivBufShared};
 use futures::{Future, FutureExt, TryFutureExt, future};
 //use futures::{Future, FutureExt, TryFutureExt, channel::oneshot, future};
 use metrohash::MetroHash64;
 #[cfg(test)] use mockall::mock;
 use std::{
     borrow,
+    cmp,
     //collections::BTreeMap,
     hash::Hasher,
     iter,
@@ -23,10 +28,18 @@ use std::{
     pin::Pin,
     sync::{Arc, Mutex}
 };
-use super::DRP;
+use super::{
+    CHUNK_SIZE,
+    CHUNKED_MIN,
+    DRP,
+    chunk_table_len,
+    class_temperature
+};
 use tracing::instrument;
 use tracing_futures::Instrument;
 
+#[cfg(not(test))] use crate::cluster::Cluster;
+#[cfg(test)] use crate::cluster::MockCluster as Cluster;
 #[cfg(not(test))] use crate::pool::Pool;
 #[cfg(test)] use crate::pool::MockPool as Pool;
 
@@ -47,18 +60,87 @@ pub struct DDML {
 // instead by integration tests.
 #[cfg_attr(test, allow(unused))]
 impl DDML {
+    /// Grow the pool with a new cluster.  See [`Pool::add_cluster`].
+    pub fn add_cluster(&self, cluster: Cluster) -> Result<()> {
+        self.pool.add_cluster(cluster)
+    }
+
     /// Assert that the given zone was clean as of the given transaction
     #[cfg(debug_assertions)]
     pub fn assert_clean_zone(&self, cluster: ClusterT, zone: ZoneT, txg: TxgT) {
         self.pool.assert_clean_zone(cluster, zone, txg)
     }
 
+    /// Take a checkpoint of the pool.  See [`Pool::checkpoint`].
+    pub fn checkpoint(&self, txg: TxgT) -> BoxVdevFut {
+        self.pool.checkpoint(txg)
+    }
+
+    /// Transaction group of the pool's checkpoint, if it has one
+    pub fn checkpoint_txg(&self) -> Option<TxgT> {
+        self.pool.checkpoint_txg()
+    }
+
+    /// Build the table of chunk checksums for an uncompressed record
+    fn chunk_table(data: &[u8]) -> Vec<u8> {
+        let mut table = Vec::with_capacity(chunk_table_len(data.len()));
+        for chunk in data.chunks(CHUNK_SIZE) {
+            let mut hasher = MetroHash64::new();
+            checksum_iovec(&chunk, &mut hasher);
+            table.extend_from_slice(&hasher.finish().to_le_bytes());
+        }
+        table
+    }
+
+    /// Write a record's raw contents, as read with
+    /// [`DRP::as_uncompressed`], to a new location.
+    ///
+    /// Unlike [`put_direct`](Self::put_direct), the contents are written
+    /// verbatim, so any compression or chunk checksums are preserved.
+    pub fn copy_direct<T>(&self, cacheref: &T, temp: Temperature, txg: TxgT)
+        -> impl Future<Output=Result<DRP>> + Send
+        where T: borrow::Borrow<dyn CacheRef>
+    {
+        self.put_common(cacheref, Compression::None, false, temp, txg)
+    }
+
     /// Free a record's storage, ignoring the Cache
     pub fn delete_direct(&self, drp: &DRP, _txg: TxgT) -> BoxVdevFut
     {
         Box::pin(self.pool.free(drp.pba, drp.asize()))
     }
 
+    /// Forget the pool's checkpoint.  See [`Pool::discard_checkpoint`].
+    pub fn discard_checkpoint(&self) {
+        self.pool.discard_checkpoint()
+    }
+
+    /// Enable an on-disk format feature.  See [`Pool::enable_feature`].
+    pub fn enable_feature(&self, feature: Feature) -> bool {
+        self.pool.enable_feature(feature)
+    }
+
+    /// Status of every leaf device.  See [`Pool::leaf_status`].
+    pub fn leaf_status(&self) -> Vec<LeafStatus> {
+        self.pool.leaf_status()
+    }
+
+    /// Bring a removed or faulted leaf device back online.  See
+    /// [`Pool::online`].
+    pub async fn online(&self, leaf: VdevLeaf) -> Result<()> {
+        self.pool.online(leaf).await
+    }
+
+    /// Describe the pool's layout and usage.  See [`Pool::shape`].
+    pub fn shape(&self) -> Vec<ClusterShape> {
+        self.pool.shape()
+    }
+
+    /// On-disk format features enabled on the pool
+    pub fn features(&self) -> Features {
+        self.pool.features()
+    }
+
     pub fn flush(&self, idx: u32) -> BoxVdevFut {
         Box::pin(self.pool.flush(idx))
     }
@@ -74,11 +156,79 @@ impl DDML {
     pub fn get_direct<T: Cacheable>(&self, drp: &DRP)
         -> Pin<Box<dyn Future<Output=Result<Box<T>>> + Send>>
     {
-        self.read(*drp).map_ok(move |dbs| {
-            Box::new(T::deserialize(dbs))
+        self.read(*drp).and_then(move |dbs| {
+            future::ready(T::deserialize(dbs).map(Box::new))
         }).boxed()
     }
 
+    /// Read the `len` bytes at `offset` within a record's contents, directly
+    /// from disk, bypassing cache.
+    ///
+    /// If the record has chunk checksums, then only the chunks that overlap
+    /// the requested range will be read and verified.  Otherwise, the whole
+    /// record will be.
+    #[instrument(skip(self, drp))]
+    pub fn get_range(&self, drp: &DRP, offset: usize, len: usize)
+        -> Pin<Box<dyn Future<Output=Result<DivBuf>> + Send>>
+    {
+        let end = offset + len;
+        let drp = *drp;
+        if !drp.has_chunk_checksums() {
+            return self.read(drp).map_ok(move |dbs| {
+                dbs.try_const().unwrap().slice(offset, end)
+            }).boxed();
+        }
+        let lsize = drp.lsize as usize;
+        let first = offset / CHUNK_SIZE;
+        let last = div_roundup(end, CHUNK_SIZE);
+        let data_lbas = (first * CHUNK_SIZE / BYTES_PER_LBA,
+            div_roundup(cmp::min(last * CHUNK_SIZE, lsize), BYTES_PER_LBA));
+        let table_lbas = (lsize / BYTES_PER_LBA, drp.asize() as usize);
+        // Read the chunks and the table in one operation if they're adjacent
+        let extents = if data_lbas.1 >= table_lbas.0 {
+            vec![(data_lbas.0, table_lbas.1)]
+        } else {
+            vec![data_lbas, table_lbas]
+        };
+        let futs = extents.into_iter().map(|(lba0, lba1)| {
+            let dbs = DivBufShared::uninitialized(
+                (lba1 - lba0) * BYTES_PER_LBA);
+            let pba = PBA::new(drp.pba.cluster, drp.pba.lba + lba0 as LbaT);
+            self.pool.read(dbs.try_mut().unwrap(), pba)
+                .map_ok(move |_| (lba0 * BYTES_PER_LBA, dbs))
+        }).collect::<Vec<_>>();
+        future::try_join_all(futs)
+        .and_then(move |bufs| {
+            // Get the record's bytes from `start` to `end`.  They must have
+            // been read by a single operation.
+            let bytes = |start: usize, end: usize| {
+                let (base, dbs) = bufs.iter()
+                    .rev()
+                    .find(|(base, _)| *base <= start)
+                    .unwrap();
+                dbs.try_const().unwrap().slice(start - base, end - base)
+            };
+            let table = bytes(lsize, lsize + chunk_table_len(lsize));
+            for (i, cksum) in table.chunks(mem::size_of::<u64>())
+                .enumerate()
+                .take(last)
+                .skip(first)
+            {
+                let chunk = bytes(i * CHUNK_SIZE,
+                                  cmp::min((i + 1) * CHUNK_SIZE, lsize));
+                let mut hasher = MetroHash64::new();
+                checksum_iovec(&chunk, &mut hasher);
+                if hasher.finish().to_le_bytes() != cksum {
+                    tracing::warn!(?drp, vdev = drp.pba.cluster, chunk = i,
+                        "Checksum mismatch");
+                    return future::err(Error::EINTEGRITY);
+                }
+            }
+            future::ok(bytes(offset, end))
+        }).in_current_span()
+        .boxed()
+    }
+
     //fn get_direct_selfless<T: Cacheable>(pool: Arc<Pool>, drp: &DRP)
         //-> Pin<Box<dyn Future<Output=Result<Box<T>>> + Send>>
     //{
@@ -136,16 +286,24 @@ impl DDML {
                 let mut hasher = MetroHash64::new();
                 checksum_iovec(&db, &mut hasher);
                 let checksum = hasher.finish();
+                drop(db);
                 if checksum == drp.checksum {
                     // Decompress
-                    let db = dbs.try_const().unwrap();
                     if drp.is_compressed() {
+                        let db = dbs.try_const().unwrap();
                         future::ok(Compression::decompress(&db))
                     } else {
+                        if drp.has_chunk_checksums() {
+                            // Strip the chunk checksum table
+                            dbs.try_mut().unwrap()
+                                .try_truncate(drp.lsize as usize)
+                                .unwrap();
+                        }
                         future::ok(dbs)
                     }
                 } else {
-                    tracing::warn!("Checksum mismatch");
+                    tracing::warn!(?drp, vdev = drp.pba.cluster,
+                        "Checksum mismatch");
                     future::err(Error::EINTEGRITY)
                 }
             })
@@ -205,6 +363,17 @@ impl DDML {
 
     /// Read a record and return ownership of it, bypassing Cache
     #[instrument(skip(self, drp))]
+    /// If `pba` lies within an open zone, return the temperature of the data
+    /// being written to that zone.
+    pub fn open_zone_temperature(&self, pba: PBA) -> Option<Temperature> {
+        self.pool.open_zone_temperature(pba)
+    }
+
+    /// Total number of read and write operations ever issued to the pool
+    pub fn ops(&self) -> u64 {
+        self.pool.ops()
+    }
+
     pub fn pop_direct<T: Cacheable>(&self, drp: &DRP)
         -> impl Future<Output=Result<Box<T>>> + Send
     {
@@ -212,10 +381,15 @@ impl DDML {
         let pba = drp.pba;
         let pool2 = self.pool.clone();
         self.read(*drp)
-            .and_then(move |dbs|
-                pool2.free(pba, lbas)
-                .map_ok(move |_| Box::new(T::deserialize(dbs)))
-            )
+            .and_then(move |dbs| {
+                // Don't free the record if we can't interpret it
+                match T::deserialize(dbs) {
+                    Ok(t) => pool2.free(pba, lbas)
+                        .map_ok(move |_| Box::new(t))
+                        .left_future(),
+                    Err(e) => future::err(e).right_future()
+                }
+            })
     }
 
     pub fn pool_name(&self) -> &str {
@@ -223,17 +397,20 @@ impl DDML {
     }
 
     /// Does most of the work of DDML::put
+    /// Write a record.  If `chunked`, then large records that can't be
+    /// compressed will get chunk checksums, if the pool supports them.
     fn put_common<T>(&self, cacheref: &T, compression: Compression,
-                     txg: TxgT)
+                     chunked: bool, temp: Temperature, txg: TxgT)
         -> impl Future<Output=Result<DRP>> + Send
         where T: borrow::Borrow<dyn CacheRef>
     {
         // Outline:
         // 1) Serialize
         // 2) Compress
-        // 3) Checksum
-        // 4) Write
-        // 5) Cache
+        // 3) Chunk checksum
+        // 4) Checksum
+        // 5) Write
+        // 6) Cache
 
         // Serialize
         let serialized = cacheref.borrow().serialize();
@@ -242,8 +419,19 @@ impl DDML {
         let lsize = serialized.len();
 
         // Compress
-        let (compressed_db, compression) = compression.compress(serialized);
+        let (mut compressed_db, compression) =
+            compression.compress(serialized);
         let compressed = compression.is_compressed();
+
+        // Chunk checksum
+        if chunked && !compressed && lsize >= CHUNKED_MIN &&
+            self.pool.features().contains(Feature::ChunkChecksums)
+        {
+            let mut v = Vec::with_capacity(lsize + chunk_table_len(lsize));
+            v.extend_from_slice(&compressed_db[..]);
+            v.extend(Self::chunk_table(&compressed_db[..]));
+            compressed_db = DivBufShared::from(v).try_const().unwrap();
+        }
         let csize = compressed_db.len() as u32;
 
         // Checksum
@@ -252,19 +440,32 @@ impl DDML {
         let checksum = hasher.finish();
 
         // Write
-        self.pool.write(compressed_db, txg)
+        self.pool.write(compressed_db, temp, txg)
         .map_ok(move |pba| {
             DRP { pba, compressed, lsize: lsize as u32, csize, checksum }
         })
     }
 
     /// Write a buffer bypassing cache.  Return the same buffer
+    ///
+    /// The record will be placed among others of the same `temp`erature.
     pub fn put_direct<T>(&self, cacheref: &T, compression: Compression,
-                         txg: TxgT)
+                         temp: Temperature, txg: TxgT)
         -> impl Future<Output=Result<DRP>> + Send
         where T: borrow::Borrow<dyn CacheRef>
     {
-        self.put_common(cacheref, compression, txg)
+        self.put_common(cacheref, compression, true, temp, txg)
+    }
+
+    /// Erase zones kept only for a discarded checkpoint.  See
+    /// [`Pool::release_checkpoint`].
+    pub fn release_checkpoint(&self) -> BoxVdevFut {
+        self.pool.release_checkpoint()
+    }
+
+    /// Change a pool property.  See [`Pool::set_property`].
+    pub fn set_pool_property(&self, prop: PoolProperty) {
+        self.pool.set_property(prop)
     }
 
     /// Return approximately the usable storage space in LBAs.
@@ -277,11 +478,49 @@ impl DDML {
         self.pool.used()
     }
 
+    /// How many blocks have been written since the pool was opened?
+    pub fn written(&self) -> LbaT {
+        self.pool.written()
+    }
+
+    /// Read a record directly from disk and verify its checksum, without
+    /// decompressing or caching it.
+    ///
+    /// The checksum is computed on Tokio's blocking thread pool, so that many
+    /// verifications may proceed in parallel without stalling the reactor.
+    #[instrument(skip(self))]
+    pub fn verify(&self, drp: DRP) -> impl Future<Output=Result<()>> + Send {
+        let len = drp.asize() as usize * BYTES_PER_LBA;
+        let dbs = DivBufShared::uninitialized(len);
+        self.pool.read(dbs.try_mut().unwrap(), drp.pba)
+        .and_then(move |_| {
+            tokio::task::spawn_blocking(move || {
+                let mut dbm = dbs.try_mut().unwrap();
+                dbm.try_truncate(drp.csize as usize).unwrap();
+                let db = dbm.freeze();
+                let mut hasher = MetroHash64::new();
+                checksum_iovec(&db, &mut hasher);
+                if hasher.finish() == drp.checksum {
+                    Ok(())
+                } else {
+                    tracing::warn!(?drp, vdev = drp.pba.cluster,
+                        "Checksum mismatch");
+                    Err(Error::EINTEGRITY)
+                }
+            }).map(|r| r.expect("checksum task panicked"))
+        })
+    }
+
     pub fn write_label(&self, labeller: LabelWriter)
         -> impl Future<Output=Result<()>> + Send
     {
         self.pool.write_label(labeller)
     }
+
+    /// Zone open/close statistics.  See [`Pool::zone_stats`].
+    pub fn zone_stats(&self) -> ZoneStats {
+        self.pool.zone_stats()
+    }
 }
 
 impl DML for DDML {
@@ -334,7 +573,8 @@ impl DML for DDML {
     {
         let cache2 = self.cache.clone();
         let db = cacheable.make_ref();
-        let fut = self.put_common(&db, compression, txg)
+        let temp = class_temperature(cacheable.class());
+        let fut = self.put_common(&db, compression, false, temp, txg)
             .map_ok(move |drp|{
                 let pba = drp.pba();
                 cache2.lock().unwrap()
@@ -344,6 +584,21 @@ impl DML for DDML {
         Box::pin(fut)
     }
 
+    #[instrument(skip(self))]
+    fn put_ditto<T: Cacheable>(&self, cacheable: T, compression: Compression,
+                               txg: TxgT)
+        -> Pin<Box<dyn Future<Output=Result<(DRP, DRP)>> + Send>>
+    {
+        // Open zones never mix temperatures, so writing the copy as cold data
+        // keeps it apart from the original.
+        let db = cacheable.make_ref();
+        let dfut = self.put_common(&db, compression, false, Temperature::Cold,
+                                   txg);
+        let fut = future::try_join(self.put(cacheable, compression, txg), dfut)
+            .in_current_span();
+        Box::pin(fut)
+    }
+
     fn repay(&self, credit: Credit) {
         // Writes to the DDML should never attempt to borrow credit.  That could
         // lead to deadlocks.
@@ -362,26 +617,50 @@ impl DML for DDML {
 #[cfg(test)]
 mock! {
     pub DDML {
+        pub fn add_cluster(&self, cluster: Cluster) -> Result<()>;
         pub fn assert_clean_zone(&self, cluster: ClusterT, zone: ZoneT, txg: TxgT);
+        pub fn checkpoint(&self, txg: TxgT) -> BoxVdevFut;
+        pub fn checkpoint_txg(&self) -> Option<TxgT>;
+        pub fn copy_direct<T: 'static>(&self, cacheref: &T, temp: Temperature,
+                                       txg: TxgT)
+            -> Pin<Box<dyn Future<Output=Result<DRP>> + Send>>
+            where T: borrow::Borrow<dyn CacheRef>;
         pub fn delete_direct(&self, drp: &DRP, txg: TxgT) -> BoxVdevFut;
+        pub fn discard_checkpoint(&self);
+        pub fn enable_feature(&self, feature: Feature) -> bool;
+        pub fn features(&self) -> Features;
         pub fn flush(&self, idx: u32) -> BoxVdevFut;
+        pub fn leaf_status(&self) -> Vec<LeafStatus>;
         pub fn new(pool: Pool, cache: Arc<Mutex<Cache>>) -> Self;
+        pub fn online(&self, leaf: VdevLeaf)
+            -> Pin<Box<dyn Future<Output=Result<()>> + Send>>;
         pub fn get_direct<T: Cacheable>(&self, drp: &DRP)
             -> Pin<Box<dyn Future<Output=Result<Box<T>>> + Send>>;
+        pub fn get_range(&self, drp: &DRP, offset: usize, len: usize)
+            -> Pin<Box<dyn Future<Output=Result<DivBuf>> + Send>>;
         pub fn list_closed_zones(&self)
             -> Box<dyn Iterator<Item=ClosedZone> + Send>;
         pub fn open(pool: Pool, cache: Arc<Mutex<Cache>>) -> Self;
+        pub fn open_zone_temperature(&self, pba: PBA) -> Option<Temperature>;
+        pub fn ops(&self) -> u64;
         pub fn pool_name(&self) -> &str;
         pub fn pop_direct<T: Cacheable>(&self, drp: &DRP)
             -> Pin<Box<dyn Future<Output=Result<Box<T>>> + Send>>;
         pub fn put_direct<T: 'static>(&self, cacheref: &T, compression: Compression,
-                         txg: TxgT)
+                         temp: Temperature, txg: TxgT)
             -> Pin<Box<dyn Future<Output=Result<DRP>> + Send>>
             where T: borrow::Borrow<dyn CacheRef>;
+        pub fn release_checkpoint(&self) -> BoxVdevFut;
+        pub fn set_pool_property(&self, prop: PoolProperty);
+        pub fn shape(&self) -> Vec<ClusterShape>;
         pub fn size(&self) -> LbaT;
         pub fn used(&self) -> LbaT;
+        pub fn verify(&self, drp: DRP)
+            -> Pin<Box<dyn Future<Output=Result<()>> + Send>>;
         pub fn write_label(&self, labeller: LabelWriter)
             -> Pin<Box<dyn Future<Output=Result<()>> + Send>>;
+        pub fn written(&self) -> LbaT;
+        pub fn zone_stats(&self) -> ZoneStats;
     }
     impl DML for DDML {
         type Addr = DRP;
@@ -396,6 +675,9 @@ mock! {
         fn put<T: Cacheable>(&self, cacheable: T, compression: Compression,
                                  txg: TxgT)
             -> Pin<Box<dyn Future<Output=Result<DRP>> + Send>>;
+        fn put_ditto<T: Cacheable>(&self, cacheable: T,
+                                   compression: Compression, txg: TxgT)
+            -> Pin<Box<dyn Future<Output=Result<(DRP, DRP)>> + Send>>;
         fn repay(&self, credit: Credit);
         fn sync_all(&self, txg: TxgT)
             -> Pin<Box<dyn Future<Output=Result<()>> + Send>>;
@@ -455,6 +737,38 @@ mod ddml {
     use rand::{RngCore, SeedableRng};
     use rand_xorshift::XorShiftRng;
 
+    /// Construct a 3-chunk record with chunk checksums, as it would be laid
+    /// out on disk.
+    fn chunked_record() -> (Vec<u8>, DRP) {
+        let lsize = 3 * CHUNK_SIZE;
+        let mut v = (0..lsize).map(|i| (i / 4096) as u8).collect::<Vec<_>>();
+        v.extend(DDML::chunk_table(&v[..]));
+        let csize = v.len();
+        let mut hasher = MetroHash64::new();
+        checksum_iovec(&v, &mut hasher);
+        let drp = DRP{pba: PBA::new(0, 1000), compressed: false,
+                      lsize: lsize as u32, csize: csize as u32,
+                      checksum: hasher.finish()};
+        v.resize(drp.asize() as usize * BYTES_PER_LBA, 0);
+        (v, drp)
+    }
+
+    /// Expect a read of `lbas` LBAs, starting `ofs` LBAs into `record`
+    fn expect_read(pool: &mut Pool, record: &[u8], drp: &DRP, ofs: usize,
+                   lbas: usize)
+    {
+        let buf = record[ofs * BYTES_PER_LBA..(ofs + lbas) * BYTES_PER_LBA]
+            .to_vec();
+        let pba = PBA::new(drp.pba.cluster, drp.pba.lba + ofs as LbaT);
+        pool.expect_read()
+            .once()
+            .withf(move |dbm, p| dbm.len() == lbas * BYTES_PER_LBA && *p == pba)
+            .return_once(move |mut dbm, _| {
+                dbm.copy_from_slice(&buf[..]);
+                Box::pin(future::ok::<(), Error>(()))
+            });
+    }
+
     #[test]
     fn delete_hot() {
         let mut seq = Sequence::new();
@@ -477,6 +791,92 @@ mod ddml {
             .unwrap();
     }
 
+    /// A full read of a record with chunk checksums shouldn't return them
+    #[test]
+    fn get_direct_chunked() {
+        let (record, drp) = chunked_record();
+        let cache = Cache::with_capacity(1_048_576);
+        let mut pool = Pool::default();
+        expect_read(&mut pool, &record, &drp, 0, drp.asize() as usize);
+
+        let ddml = DDML::new(pool, Arc::new(Mutex::new(cache)));
+        let dbs = ddml.get_direct::<DivBufShared>(&drp)
+            .now_or_never().unwrap()
+            .unwrap();
+        assert_eq!(&dbs.try_const().unwrap()[..], &record[..3 * CHUNK_SIZE]);
+    }
+
+    mod get_range {
+        use super::*;
+
+        /// Only the chunk containing the range, and the checksum table, should
+        /// be read.
+        #[test]
+        fn chunked() {
+            let (record, drp) = chunked_record();
+            let cache = Cache::with_capacity(1_048_576);
+            let mut pool = Pool::default();
+            expect_read(&mut pool, &record, &drp, 8, 8);
+            expect_read(&mut pool, &record, &drp, 24, 1);
+
+            let ddml = DDML::new(pool, Arc::new(Mutex::new(cache)));
+            let db = ddml.get_range(&drp, 40_000, 100)
+                .now_or_never().unwrap()
+                .unwrap();
+            assert_eq!(&db[..], &record[40_000..40_100]);
+        }
+
+        /// If the last chunk is needed, it and the table can be read at once
+        #[test]
+        fn chunked_last() {
+            let (record, drp) = chunked_record();
+            let cache = Cache::with_capacity(1_048_576);
+            let mut pool = Pool::default();
+            expect_read(&mut pool, &record, &drp, 8, 17);
+
+            let ddml = DDML::new(pool, Arc::new(Mutex::new(cache)));
+            let db = ddml.get_range(&drp, 40_000, 40_000)
+                .now_or_never().unwrap()
+                .unwrap();
+            assert_eq!(&db[..], &record[40_000..80_000]);
+        }
+
+        #[test]
+        fn ecksum() {
+            let (mut record, drp) = chunked_record();
+            record[32_768] ^= 1;
+            let cache = Cache::with_capacity(1_048_576);
+            let mut pool = Pool::default();
+            expect_read(&mut pool, &record, &drp, 8, 8);
+            expect_read(&mut pool, &record, &drp, 24, 1);
+
+            let ddml = DDML::new(pool, Arc::new(Mutex::new(cache)));
+            let r = ddml.get_range(&drp, 40_000, 100)
+                .now_or_never().unwrap();
+            assert_eq!(r, Err(Error::EINTEGRITY));
+        }
+
+        /// Without chunk checksums, the whole record must be read
+        #[test]
+        fn unchunked() {
+            let v = (0..8192).map(|i| (i / 64) as u8).collect::<Vec<_>>();
+            let mut hasher = MetroHash64::new();
+            checksum_iovec(&v, &mut hasher);
+            let drp = DRP{pba: PBA::new(0, 1000), compressed: false,
+                          lsize: 8192, csize: 8192,
+                          checksum: hasher.finish()};
+            let cache = Cache::with_capacity(1_048_576);
+            let mut pool = Pool::default();
+            expect_read(&mut pool, &v, &drp, 0, 2);
+
+            let ddml = DDML::new(pool, Arc::new(Mutex::new(cache)));
+            let db = ddml.get_range(&drp, 5000, 100)
+                .now_or_never().unwrap()
+                .unwrap();
+            assert_eq!(&db[..], &v[5000..5100]);
+        }
+    }
+
     #[test]
     fn evict() {
         let pba = PBA::default();
@@ -620,14 +1020,16 @@ mod ddml {
 
         // The first cluster has two closed zones
         let clz0 = ClosedZone{pba: PBA::new(0, 10), freed_blocks: 5, zid: 0,
-            total_blocks: 10, txgs: TxgT::from(0)..TxgT::from(1)};
+            total_blocks: 10, txgs: TxgT::from(0)..TxgT::from(1),
+            temp: Temperature::Hot};
         let clz0_1 = clz0.clone();
         pool.expect_find_closed_zone()
             .with(eq(0), eq(0))
             .return_once(move |_, _| (Some(clz0_1), Some((0, 11))));
 
         let clz1 = ClosedZone{pba: PBA::new(0, 30), freed_blocks: 6, zid: 1,
-            total_blocks: 10, txgs: TxgT::from(2)..TxgT::from(3)};
+            total_blocks: 10, txgs: TxgT::from(2)..TxgT::from(3),
+            temp: Temperature::Hot};
         let clz1_1 = clz1.clone();
         pool.expect_find_closed_zone()
             .with(eq(0), eq(11))
@@ -644,7 +1046,8 @@ mod ddml {
 
         // The third cluster has one closed zone
         let clz2 = ClosedZone{pba: PBA::new(2, 10), freed_blocks: 5, zid: 2,
-            total_blocks: 10, txgs: TxgT::from(0)..TxgT::from(1)};
+            total_blocks: 10, txgs: TxgT::from(0)..TxgT::from(1),
+            temp: Temperature::Hot};
         let clz2_1 = clz2.clone();
         pool.expect_find_closed_zone()
             .with(eq(2), eq(0))
@@ -770,8 +1173,10 @@ mod ddml {
         let key = Key::PBA(pba);
         let mut pool = Pool::default();
         pool.expect_write()
-            .with(always(), eq(TxgT::from(42)))
-            .return_once(move |_, _| Box::pin(future::ok::<PBA, Error>(pba)));
+            .with(always(), eq(Temperature::Hot), eq(TxgT::from(42)))
+            .return_once(move |_, _, _|
+                Box::pin(future::ok::<PBA, Error>(pba))
+            );
 
         let amcache = Arc::new(Mutex::new(cache));
         let ddml = DDML::new(pool, amcache.clone());
@@ -794,8 +1199,10 @@ mod ddml {
         let key = Key::PBA(pba);
         let mut pool = Pool::default();
         pool.expect_write()
-            .with(always(), eq(TxgT::from(42)))
-            .return_once(move |_, _| Box::pin(future::ok::<PBA, Error>(pba)));
+            .with(always(), eq(Temperature::Hot), eq(TxgT::from(42)))
+            .return_once(move |_, _, _|
+                Box::pin(future::ok::<PBA, Error>(pba))
+            );
 
         let amcache = Arc::new(Mutex::new(cache));
         let ddml = DDML::new(pool, amcache.clone());
@@ -819,8 +1226,10 @@ mod ddml {
         let key = Key::PBA(pba);
         let mut pool = Pool::default();
         pool.expect_write()
-            .with(always(), eq(TxgT::from(42)))
-            .return_once(move |_, _| Box::pin(future::ok::<PBA, Error>(pba)));
+            .with(always(), eq(Temperature::Hot), eq(TxgT::from(42)))
+            .return_once(move |_, _, _|
+                Box::pin(future::ok::<PBA, Error>(pba))
+            );
 
         let amcache = Arc::new(Mutex::new(cache));
         let ddml = DDML::new(pool, amcache.clone());
@@ -845,8 +1254,10 @@ mod ddml {
         let key = Key::PBA(pba);
         let mut pool = Pool::default();
         pool.expect_write()
-            .with(always(), eq(TxgT::from(42)))
-            .return_once(move |_, _| Box::pin(future::ok::<PBA, Error>(pba)));
+            .with(always(), eq(Temperature::Hot), eq(TxgT::from(42)))
+            .return_once(move |_, _, _|
+                Box::pin(future::ok::<PBA, Error>(pba))
+            );
 
         let amcache = Arc::new(Mutex::new(cache));
         let ddml = DDML::new(pool, amcache.clone());
@@ -867,13 +1278,16 @@ mod ddml {
         let mut pool = Pool::default();
         let txg = TxgT::from(42);
         pool.expect_write()
-            .with(always(), eq(txg))
-            .return_once(move |_, _| Box::pin(future::ok::<PBA, Error>(pba)));
+            .with(always(), eq(Temperature::Cold), eq(txg))
+            .return_once(move |_, _, _|
+                Box::pin(future::ok::<PBA, Error>(pba))
+            );
 
         let ddml = DDML::new(pool, Arc::new(Mutex::new(cache)));
         let dbs = DivBufShared::from(vec![42u8; 4096]);
         let db = Box::new(dbs.try_const().unwrap()) as Box<dyn CacheRef>;
-        let drp = ddml.put_direct(&db, Compression::None, txg)
+        let drp = ddml.put_direct(&db, Compression::None, Temperature::Cold,
+                                  txg)
             .now_or_never().unwrap()
             .unwrap();
         assert_eq!(drp.pba, pba);
@@ -881,6 +1295,56 @@ mod ddml {
         assert_eq!(drp.lsize, 4096);
     }
 
+    /// Large uncompressed records should get chunk checksums
+    #[test]
+    fn put_direct_chunked() {
+        let cache = Cache::with_capacity(1_048_576);
+        let mut pool = Pool::default();
+        let txg = TxgT::from(42);
+        pool.expect_features()
+            .return_const(Features::all());
+        pool.expect_write()
+            .withf(|buf, _, _| buf.len() == 2 * CHUNK_SIZE + 16)
+            .return_once(move |_, _, _|
+                Box::pin(future::ok::<PBA, Error>(PBA::default()))
+            );
+
+        let ddml = DDML::new(pool, Arc::new(Mutex::new(cache)));
+        let dbs = DivBufShared::from(vec![42u8; 2 * CHUNK_SIZE]);
+        let db = Box::new(dbs.try_const().unwrap()) as Box<dyn CacheRef>;
+        let drp = ddml.put_direct(&db, Compression::None, Temperature::Cold,
+                                  txg)
+            .now_or_never().unwrap()
+            .unwrap();
+        assert!(drp.has_chunk_checksums());
+        assert_eq!(drp.lsize as usize, 2 * CHUNK_SIZE);
+    }
+
+    /// Pools without the feature shouldn't get chunk checksums
+    #[test]
+    fn put_direct_chunked_disabled() {
+        let cache = Cache::with_capacity(1_048_576);
+        let mut pool = Pool::default();
+        let txg = TxgT::from(42);
+        pool.expect_features()
+            .return_const(Features::default());
+        pool.expect_write()
+            .withf(|buf, _, _| buf.len() == 2 * CHUNK_SIZE)
+            .return_once(move |_, _, _|
+                Box::pin(future::ok::<PBA, Error>(PBA::default()))
+            );
+
+        let ddml = DDML::new(pool, Arc::new(Mutex::new(cache)));
+        let dbs = DivBufShared::from(vec![42u8; 2 * CHUNK_SIZE]);
+        let db = Box::new(dbs.try_const().unwrap()) as Box<dyn CacheRef>;
+        let drp = ddml.put_direct(&db, Compression::None, Temperature::Cold,
+                                  txg)
+            .now_or_never().unwrap()
+            .unwrap();
+        assert!(!drp.has_chunk_checksums());
+        assert_eq!(drp.csize as usize, 2 * CHUNK_SIZE);
+    }
+
     #[test]
     fn sync_all() {
         let cache = Cache::with_capacity(1_048_576);
@@ -893,6 +1357,57 @@ mod ddml {
                 .now_or_never().unwrap()
                 .is_ok());
     }
+
+    mod verify {
+        use super::*;
+        use pretty_assertions::assert_eq;
+
+        #[tokio::test]
+        async fn ecksum() {
+            let pba = PBA::default();
+            let drp = DRP{pba, compressed: false, lsize: 4096,
+                          csize: 1, checksum: 0xdead_beef_dead_beef};
+            let cache = Cache::with_capacity(1_048_576);
+            let mut pool = Pool::default();
+            pool.expect_read()
+                .withf(|dbm, pba| dbm.len() == 4096 && *pba == PBA::default())
+                .return_once(|mut dbm, _pba| {
+                    for x in dbm.iter_mut() {
+                        *x = 0;
+                    }
+                    Box::pin(future::ok::<(), Error>(()))
+                });
+
+            let ddml = DDML::new(pool, Arc::new(Mutex::new(cache)));
+            let err = ddml.verify(drp).await.unwrap_err();
+            assert_eq!(err, Error::EINTEGRITY);
+        }
+
+        /// verify should bypass the cache and leave it unchanged
+        #[tokio::test]
+        async fn ok() {
+            let pba = PBA::default();
+            let key = Key::PBA(pba);
+            let drp = DRP{pba, compressed: false, lsize: 4096,
+                          csize: 1, checksum: 0xe7f_1596_6a3d_61f8};
+            let cache = Cache::with_capacity(1_048_576);
+            let mut pool = Pool::default();
+            pool.expect_read()
+                .withf(|dbm, pba| dbm.len() == 4096 && *pba == PBA::default())
+                .once()
+                .return_once(|mut dbm, _pba| {
+                    for x in dbm.iter_mut() {
+                        *x = 0;
+                    }
+                    Box::pin(future::ok::<(), Error>(()))
+                });
+
+            let amcache = Arc::new(Mutex::new(cache));
+            let ddml = DDML::new(pool, amcache.clone());
+            ddml.verify(drp).await.unwrap();
+            assert!(amcache.lock().unwrap().get::<DivBuf>(&key).is_none());
+        }
+    }
 }
 }
 // LCOV_EXCL_STOP
@@ -6,6 +6,7 @@
 /// duplicated, either through snapshots, clones, or deduplication.
 
 use crate::{
+    cache::EntryClass,
     types::*,
     util::*,
     dml::Compression
@@ -13,15 +14,41 @@ use crate::{
 #[cfg(test)] use rand::{self, Rng};
 
 pub type ClosedZone = crate::pool::ClosedZone;
+pub use crate::pool::Temperature;
 
 use mockall_double::*;
 use serde_derive::{Deserialize, Serialize};
+use std::mem;
 
 mod ddml;
 
 #[double]
 pub use self::ddml::DDML;
 
+/// Size of the chunks covered by sub-record checksums, in bytes.
+const CHUNK_SIZE: usize = 32_768;
+
+/// Uncompressed records at least this large get sub-record checksums, if the
+/// pool has the `chunk_checksums` feature.
+const CHUNKED_MIN: usize = 2 * CHUNK_SIZE;
+
+/// Length of the chunk checksum table for a record of `lsize` bytes
+fn chunk_table_len(lsize: usize) -> usize {
+    div_roundup(lsize, CHUNK_SIZE) * mem::size_of::<u64>()
+}
+
+/// Temperature of zones that newly written records of `class` should go to.
+///
+/// Metadata is segregated from data, because tree nodes are rewritten far more
+/// often.  Otherwise, the cleaner would have to copy long-lived data just to
+/// reclaim the space freed by dead nodes sharing its zones.
+pub fn class_temperature(class: EntryClass) -> Temperature {
+    match class {
+        EntryClass::Data => Temperature::Hot,
+        EntryClass::Metadata => Temperature::Metadata
+    }
+}
+
 /// Direct Record Pointer.  A persistable pointer to a record on disk.
 ///
 /// A Record is a local unit of data on disk.  It may be larger or smaller than
@@ -70,6 +97,17 @@ impl DRP {
         self
     }
 
+    /// Does this record carry sub-record checksums?
+    ///
+    /// If so, a table of each chunk's checksum immediately follows the data
+    /// on disk.  The table is covered by the record's own checksum, and is
+    /// included in `csize` but not in `lsize`.
+    pub fn has_chunk_checksums(&self) -> bool {
+        let lsize = self.lsize as usize;
+        !self.compressed && lsize >= CHUNKED_MIN &&
+            self.csize as usize == lsize + chunk_table_len(lsize)
+    }
+
     /// Was this record written in compressed form?
     pub fn is_compressed(&self) -> bool {
         self.compressed
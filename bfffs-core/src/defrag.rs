@@ -0,0 +1,172 @@
+// vim: tw=80
+//! Defragmentation
+//!
+//! Files written slowly, or rewritten piecemeal, end up with their records
+//! scattered across many zones, which makes sequential reads seek.  The
+//! defragmenter finds such files and rewrites their records, in offset order,
+//! into cold zones.  Nothing else writes to cold zones in the foreground, so
+//! each file's records end up adjacent.
+
+use crate::{types::*, util::BYTES_PER_LBA};
+use serde_derive::{Deserialize, Serialize};
+
+/// Files with at least this fraction of discontiguous records will be
+/// rewritten.
+const THRESHOLD: f32 = 0.25;
+
+/// A snapshot of the pool's fragmentation, and the work needed to fix it
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct DefragStats {
+    /// Number of files that have at least one blob record
+    pub files: u64,
+    /// Number of those files scattered enough to be rewritten
+    pub fragmented: u64,
+    /// Total blob records in all files
+    pub records: u64,
+    /// Total runs of contiguous records in all files.  A perfectly
+    /// defragmented pool has one per file.
+    pub fragments: u64,
+    /// Bytes of data to be rewritten
+    pub moved: u64,
+}
+
+impl DefragStats {
+    /// Account for one file, whose records are located at `extents`, in
+    /// offset order.
+    ///
+    /// Returns whether the file should be rewritten.
+    pub(crate) fn add(&mut self, extents: &[(PBA, LbaT)]) -> bool {
+        if extents.is_empty() {
+            return false;
+        }
+        let fragments = fragments(extents);
+        self.files += 1;
+        self.records += extents.len() as u64;
+        self.fragments += fragments;
+        let breaks = (fragments - 1) as f32;
+        let pairs = (extents.len() - 1) as f32;
+        let fragmented = breaks > 0.0 && breaks / pairs >= THRESHOLD;
+        if fragmented {
+            self.fragmented += 1;
+            self.moved += extents.iter()
+                .map(|(_, asize)| asize * BYTES_PER_LBA as u64)
+                .sum::<u64>();
+        }
+        fragmented
+    }
+
+    /// The fraction of consecutive record pairs, within all files, that
+    /// aren't adjacent on disk.  0 for a perfectly defragmented pool.
+    pub fn fragmentation(&self) -> f64 {
+        let pairs = self.records - self.files;
+        if pairs == 0 {
+            0.0
+        } else {
+            (self.fragments - self.files) as f64 / pairs as f64
+        }
+    }
+}
+
+/// Count the runs of physically contiguous records in `extents`, each of
+/// which is a record's address and allocated size.
+fn fragments(extents: &[(PBA, LbaT)]) -> u64 {
+    let breaks = extents.windows(2)
+        .filter(|w| {
+            let (pba, asize) = w[0];
+            w[1].0 != PBA::new(pba.cluster, pba.lba + asize)
+        }).count() as u64;
+    breaks + u64::from(!extents.is_empty())
+}
+
+// LCOV_EXCL_START
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    #[test]
+    fn contiguous() {
+        let extents = [
+            (PBA::new(0, 100), 8),
+            (PBA::new(0, 108), 8),
+            (PBA::new(0, 116), 2),
+        ];
+        let mut stats = DefragStats::default();
+        assert!(!stats.add(&extents));
+        assert_eq!(stats, DefragStats {
+            files: 1,
+            fragmented: 0,
+            records: 3,
+            fragments: 1,
+            moved: 0
+        });
+        assert_eq!(stats.fragmentation(), 0.0);
+    }
+
+    #[test]
+    fn empty() {
+        let mut stats = DefragStats::default();
+        assert!(!stats.add(&[]));
+        assert_eq!(stats, DefragStats::default());
+        assert_eq!(stats.fragmentation(), 0.0);
+    }
+
+    /// Records in different clusters are never adjacent, even at the same LBA
+    #[test]
+    fn different_clusters() {
+        let extents = [(PBA::new(0, 100), 8), (PBA::new(1, 108), 8)];
+        let mut stats = DefragStats::default();
+        assert!(stats.add(&extents));
+        assert_eq!(stats.fragments, 2);
+        assert_eq!(stats.moved, 16 * BYTES_PER_LBA as u64);
+    }
+
+    /// Records out of order on disk are not contiguous
+    #[test]
+    fn reversed() {
+        let extents = [(PBA::new(0, 108), 8), (PBA::new(0, 100), 8)];
+        let mut stats = DefragStats::default();
+        assert!(stats.add(&extents));
+        assert_eq!(stats.fragmentation(), 1.0);
+    }
+
+    /// A file with only a few discontinuities isn't worth rewriting, but
+    /// still counts toward the pool's fragmentation.
+    #[test]
+    fn slightly_fragmented() {
+        let extents = [
+            (PBA::new(0, 100), 1),
+            (PBA::new(0, 101), 1),
+            (PBA::new(0, 102), 1),
+            (PBA::new(0, 103), 1),
+            (PBA::new(0, 104), 1),
+            (PBA::new(0, 200), 1),
+        ];
+        let mut stats = DefragStats::default();
+        assert!(!stats.add(&extents));
+        assert_eq!(stats.fragments, 2);
+        assert_eq!(stats.moved, 0);
+        assert_eq!(stats.fragmentation(), 0.2);
+    }
+
+    #[test]
+    fn two_files() {
+        let contiguous = [(PBA::new(0, 100), 1), (PBA::new(0, 101), 1)];
+        let scattered = [
+            (PBA::new(0, 300), 1),
+            (PBA::new(0, 200), 1),
+            (PBA::new(0, 201), 1),
+        ];
+        let mut stats = DefragStats::default();
+        assert!(!stats.add(&contiguous));
+        assert!(stats.add(&scattered));
+        assert_eq!(stats, DefragStats {
+            files: 2,
+            fragmented: 1,
+            records: 5,
+            fragments: 3,
+            moved: 3 * BYTES_PER_LBA as u64
+        });
+        assert_eq!(stats.fragmentation(), 1.0 / 3.0);
+    }
+}
+// LCOV_EXCL_STOP
@@ -0,0 +1,62 @@
+// vim: tw=80
+//! Deterministic mode, for reproducible bug reports
+//!
+//! Several of BFFFS's decisions normally depend on timing: which mirror child
+//! services a read, which cluster receives a write, and when a transaction
+//! group ends.  When a seed is set, those decisions depend only on the seed
+//! and on the sequence of operations, so a failure found by a fuzz or torture
+//! test can be replayed by running again with the same seed.  The seed also
+//! breaks ties that would otherwise be resolved arbitrarily, like the order in
+//! which the cleaner visits equally dirty zones.  Different seeds thus explore
+//! different orders.
+//!
+//! Some things need no help.  RIDs are allocated sequentially, and the cache
+//! is strictly LRU, so both are already deterministic.
+//!
+//! The seed is process-wide.  It must be set before any pool is opened, and
+//! can't be unset.
+
+use metrohash::MetroHash64;
+use std::{
+    hash::Hasher,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SEED: AtomicU64 = AtomicU64::new(0);
+
+/// Hash `key` with `seed`, yielding a value suitable for choosing among
+/// equally good alternatives.
+pub(crate) fn choose(seed: u64, key: u64) -> u64 {
+    let mut hasher = MetroHash64::with_seed(seed);
+    hasher.write_u64(key);
+    hasher.finish()
+}
+
+/// Return the seed, if deterministic mode is enabled
+pub fn seed() -> Option<u64> {
+    if ENABLED.load(Ordering::Acquire) {
+        Some(SEED.load(Ordering::Relaxed))
+    } else {
+        None
+    }
+}
+
+/// Enable deterministic mode, using `seed` for every decision that would
+/// otherwise be arbitrary.
+pub fn set_seed(seed: u64) {
+    SEED.store(seed, Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Release);
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    #[test]
+    fn choose_depends_on_seed() {
+        assert_eq!(choose(1, 42), choose(1, 42));
+        assert_ne!(choose(1, 42), choose(2, 42));
+        assert_ne!(choose(1, 42), choose(1, 43));
+    }
+}
@@ -2,19 +2,25 @@
 
 use crate::{
     Error, Result, Uuid, vdev::Vdev, cache, database, ddml, idml, label, mirror,
-    pool, raid
+    memory::MemoryBudget, multihost::{self, Heartbeater}, pool, raid,
+    util::BYTES_PER_LBA, vdev_file
 };
 use futures::{
     Future,
     StreamExt,
     TryFutureExt,
     TryStreamExt,
+    future,
     stream::{self, FuturesOrdered, FuturesUnordered},
 };
 use mockall_double::double;
+use serde_derive::{Deserialize, Serialize};
 use std::{
     borrow::ToOwned,
     collections::BTreeMap,
+    fs,
+    io,
+    os::unix::fs::FileExt,
     path::{Path, PathBuf},
     sync::{Arc, Mutex}
 };
@@ -25,6 +31,9 @@ use std::{
 #[double] use crate::vdev_block::VdevBlock;
 #[double] use crate::vdev_file::VdevFile;
 
+/// A leaf's UUID, plus every label stored on it
+type Labels = (Uuid, mirror::Label, raid::Label, pool::Label);
+
 /// Holds cached labels detected during tasting.
 // NB: these labels may be out-of-date because we don't open devices exclusively
 // until import time.
@@ -36,19 +45,320 @@ struct Inner {
     pools: BTreeMap<Uuid, pool::Label>,
 }
 
+impl Inner {
+    /// List the paths of every device in a pool, if all of them have been
+    /// tasted.
+    fn devices(&self, uuid: Uuid) -> Option<Vec<PathBuf>> {
+        let pool = self.pools.get(&uuid)?;
+        let mut devices = Vec::new();
+        for raid_uuid in pool.children.iter() {
+            let raid = self.raids.get(raid_uuid)?;
+            for mirror_uuid in raid.iter_children() {
+                let mirror = self.mirrors.get(mirror_uuid)?;
+                for leaf_uuid in mirror.children.iter() {
+                    devices.push(self.leaves.get(leaf_uuid)?.clone());
+                }
+            }
+        }
+        Some(devices)
+    }
+
+    fn insert(&mut self, path: PathBuf, labels: Labels) {
+        let (leaf_uuid, ml, rl, pl) = labels;
+        self.leaves.insert(leaf_uuid, path);
+        self.mirrors.insert(ml.uuid, ml);
+        self.raids.insert(rl.uuid(), rl);
+        self.pools.insert(pl.uuid, pl);
+    }
+
+    /// List whichever of a pool's vdevs haven't been tasted.
+    ///
+    /// Usually those are leaves.  But if no leaf of a mirror or RAID was
+    /// tasted, then its children are unknown, so its own UUID is listed
+    /// instead.
+    fn missing(&self, uuid: Uuid) -> Vec<Uuid> {
+        let mut missing = Vec::new();
+        let pool = match self.pools.get(&uuid) {
+            Some(pool) => pool,
+            None => return missing
+        };
+        for raid_uuid in pool.children.iter() {
+            let raid = match self.raids.get(raid_uuid) {
+                Some(raid) => raid,
+                None => {
+                    missing.push(*raid_uuid);
+                    continue;
+                }
+            };
+            for mirror_uuid in raid.iter_children() {
+                match self.mirrors.get(mirror_uuid) {
+                    Some(mirror) => {
+                        missing.extend(mirror.children.iter()
+                            .filter(|u| !self.leaves.contains_key(u))
+                            .cloned());
+                    },
+                    None => missing.push(*mirror_uuid)
+                }
+            }
+        }
+        missing
+    }
+
+    fn merge(&mut self, other: Inner) {
+        self.leaves.extend(other.leaves);
+        self.mirrors.extend(other.mirrors);
+        self.raids.extend(other.raids);
+        self.pools.extend(other.pools);
+    }
+}
+
+/// A pool's entry in the device cache file
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct CachedPool {
+    name: String,
+    devices: Vec<PathBuf>,
+}
+
+/// A pool imported by this `DevManager`, with all of its devices present
+struct Imported {
+    name: String,
+    /// Value of the pool's `cachefile` property
+    cachefile: String,
+    devices: Vec<PathBuf>,
+}
+
+/// Contents of the device cache file.
+///
+/// Like ZFS's `zpool.cache`, it records the devices that make up each pool, so
+/// a pool can be imported without tasting every device in the system.  It's
+/// only a hint; the labels on the devices are always authoritative.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct CacheFile {
+    pools: BTreeMap<Uuid, CachedPool>,
+}
+
+impl CacheFile {
+    fn load(path: &Path) -> io::Result<Self> {
+        let f = fs::File::open(path)?;
+        serde_yaml::from_reader(f)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Atomically replace the cache file
+    fn save(&self, path: &Path) -> io::Result<()> {
+        let mut tmppath = path.as_os_str().to_owned();
+        tmppath.push(".tmp");
+        let f = fs::File::create(&tmppath)?;
+        serde_yaml::to_writer(&f, self)
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        f.sync_all()?;
+        fs::rename(&tmppath, path)
+    }
+}
+
 #[derive(Default)]
 pub struct DevManager {
+    background_rate: Option<u64>,
     cache_size: Option<usize>,
+    cachefile: Option<PathBuf>,
+    degraded: bool,
+    force: bool,
+    /// Heartbeats of every pool imported read-write
+    heartbeaters: Mutex<Vec<Heartbeater>>,
+    /// Every pool imported with all of its devices present
+    imported: Mutex<BTreeMap<Uuid, Imported>>,
     inner: Mutex<Inner>,
+    memory_budget: Option<MemoryBudget>,
+    metadata_reserve: Option<f32>,
+    open_zone_budget: Option<u32>,
+    readonly: bool,
+    rewind: bool,
     writeback_size: Option<usize>
 }
 
 impl DevManager {
+    /// Set the maximum throughput of background work like cleaning and
+    /// scrubbing, in bytes per second, while the pool is busy with foreground
+    /// I/O.  While the pool is idle, background work isn't limited.  0 means
+    /// background work will pause whenever the pool is busy.
+    pub fn background_rate(&mut self, background_rate: u64) {
+        self.background_rate = Some(background_rate);
+    }
+
     /// Set the maximum size in bytes of the Cache
     pub fn cache_size(&mut self, cache_size: usize) {
         self.cache_size = Some(cache_size);
     }
 
+    /// Use a device cache file at `path`.
+    ///
+    /// Every successful import will record the pool's devices there, and
+    /// [`taste_cached`](DevManager::taste_cached) will read them back.
+    pub fn cachefile<P: AsRef<Path>>(&mut self, path: P) {
+        self.cachefile = Some(path.as_ref().to_owned());
+    }
+
+    /// Format some unused devices as a new `Cluster`, to be added to the
+    /// named pool.
+    ///
+    /// * `pool`:               Name of an already imported pool
+    /// * `disks_per_stripe`:   Number of data plus parity chunks in each
+    ///                         RAID stripe.  1 for a single mirror.
+    /// * `redundancy`:         Degree of RAID redundancy.  0 for a single
+    ///                         mirror.
+    /// * `mirrors`:            The devices of each of the `Cluster`'s mirrors.
+    ///                         A single disk is a mirror with one child.
+    ///
+    /// Once the `Cluster` has been added to the pool, its devices should be
+    /// passed to [`record_cluster`](DevManager::record_cluster).
+    pub async fn create_cluster(
+        &self,
+        pool: &str,
+        disks_per_stripe: i16,
+        redundancy: i16,
+        mirrors: &[Vec<PathBuf>]
+    ) -> Result<Cluster>
+    {
+        if self.readonly {
+            return Err(Error::EROFS);
+        }
+        let uuid = self.imported.lock().unwrap().iter()
+            .find(|(_uuid, imported)| imported.name == pool)
+            .map(|(uuid, _imported)| *uuid)
+            .ok_or(Error::ENOENT)?;
+        if mirrors.iter().any(Vec::is_empty) {
+            return Err(Error::EINVAL);
+        }
+        raid::check_layout(mirrors.len(), disks_per_stripe, redundancy)?;
+        for dev in mirrors.iter().flatten() {
+            if self.imported.lock().unwrap().values()
+                .any(|imported| imported.devices.contains(dev))
+            {
+                return Err(Error::EBUSY);
+            }
+            // Maybe it's a different name for one of the pool's devices
+            if let Ok((_, _, _, pl)) = DevManager::read_labels(dev).await {
+                if pl.uuid == uuid {
+                    return Err(Error::EBUSY);
+                }
+            }
+        }
+        let mirrors = mirrors.iter()
+            .map(|devs| Mirror::create(devs, None).map_err(Error::from))
+            .collect::<Result<Vec<_>>>()?;
+        // RAID requires identically sized children
+        if mirrors.iter().any(|m| m.size() != mirrors[0].size()) {
+            return Err(Error::EINVAL);
+        }
+        let raid = raid::create(None, disks_per_stripe, redundancy, mirrors);
+        let mut cluster = Cluster::create(raid);
+        if let Some(budget) = self.open_zone_budget {
+            cluster.set_open_zone_budget(budget);
+        }
+        Ok(cluster)
+    }
+
+    /// Allow importing pools that are missing some of their devices.
+    ///
+    /// As long as every mirror has at least one child present, the pool will
+    /// be imported from whichever children are.  Such a pool is always
+    /// imported read-only, because writing to it would drop the missing
+    /// children from their mirrors' labels.  Intended for offline debugging
+    /// tools.
+    pub fn degraded(&mut self, degraded: bool) {
+        self.degraded = degraded;
+    }
+
+    /// Print every layer of both of a device's labels, in YAML.
+    ///
+    /// Nothing is imported, and the device is opened read-only, so this works
+    /// even for pools that refuse to import.  Each label is printed as a
+    /// separate document.  If a label can't be read, or one of its layers
+    /// can't be decoded, the error is printed in its place.  For debugging
+    /// purposes only.
+    pub fn dump_labels<P: AsRef<Path>>(p: P, f: &mut dyn io::Write)
+        -> Result<()>
+    {
+        /// Decode one layer, recording either it or the error in `map`.
+        fn layer<T>(
+            map: &mut serde_yaml::Mapping,
+            reader: &mut label::LabelReader,
+            name: &str
+        ) -> bool
+            where T: label::LabelLayer + serde::Serialize
+        {
+            match reader.deserialize::<T>() {
+                Ok(t) => {
+                    map.insert(name.into(), serde_yaml::to_value(&t).unwrap());
+                    true
+                },
+                Err(e) => {
+                    map.insert("error".into(), format!("{name}: {e}").into());
+                    false
+                }
+            }
+        }
+
+        let file = fs::File::open(p)?;
+        for idx in 0..label::LABEL_COUNT as u32 {
+            let mut map = serde_yaml::Mapping::new();
+            map.insert("label".into(), idx.into());
+            let mut buf = vec![0; label::LABEL_SIZE];
+            let offset = label::LabelReader::lba(idx) * BYTES_PER_LBA as u64;
+            file.read_exact_at(&mut buf, offset)?;
+            match label::LabelReader::new(buf) {
+                Ok(mut reader) => {
+                    let _ = layer::<vdev_file::Label>(&mut map, &mut reader,
+                                                      "leaf") &&
+                        layer::<mirror::Label>(&mut map, &mut reader,
+                                               "mirror") &&
+                        layer::<raid::Label>(&mut map, &mut reader, "raid") &&
+                        layer::<pool::Label>(&mut map, &mut reader, "pool") &&
+                        layer::<idml::Label>(&mut map, &mut reader, "idml") &&
+                        layer::<database::Label>(&mut map, &mut reader,
+                                                 "database");
+                },
+                Err(e) => {
+                    map.insert("error".into(), format!("{e:?}").into());
+                }
+            }
+            serde_yaml::to_writer(&mut *f, &map).unwrap();
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+
+    /// Import pools even if another host seems to have imported them.
+    ///
+    /// If the other host really has, then the pool will be corrupted.  See
+    /// [`multihost`].
+    pub fn force(&mut self, force: bool) {
+        self.force = force;
+    }
+
+    /// Share `budget` among the cache, the writeback cache, and anything else
+    /// that uses it.
+    ///
+    /// Unless [`cache_size`](DevManager::cache_size) or
+    /// [`writeback_size`](DevManager::writeback_size) are also set, each may
+    /// grow to the whole budget when the others don't need it.
+    pub fn memory_budget(&mut self, budget: MemoryBudget) {
+        self.memory_budget = Some(budget);
+    }
+
+    /// Set the fraction of the Cache, from 0.0 to 1.0, that is reserved for
+    /// metadata.  Data will never evict metadata within the reservation.
+    pub fn metadata_reserve(&mut self, fraction: f32) {
+        self.metadata_reserve = Some(fraction);
+    }
+
+    /// Set the maximum number of zones that each `Cluster` may keep open at
+    /// once.  It will be further limited by whatever the devices themselves
+    /// support.
+    pub fn open_zone_budget(&mut self, budget: u32) {
+        self.open_zone_budget = Some(budget);
+    }
+
     /// Import a pool by its pool name
     pub async fn import_by_name<S>(&self, name: S)
         -> Result<database::Database>
@@ -78,34 +388,156 @@ impl DevManager {
     /// Import a pool that is already known to exist
     async fn import(&self, uuid: Uuid) -> Result<database::Database>
     {
-        let (_pool, raids, mut mirrors, mut leaves) = self.open_labels(uuid)?;
-        let combined_clusters = raids.into_iter()
-        .map(move |raid| {
-            let mirror_labels = mirrors.remove(&raid.uuid()).unwrap();
-            mirror_labels.iter()
-                .map(|mirror_label| {
-                    let leaf_paths = leaves.remove(&mirror_label.uuid).unwrap();
-                    DevManager::open_mirror(mirror_label.uuid, leaf_paths)
+        let devices = self.inner.lock().unwrap().devices(uuid);
+        // A degraded pool must not be written, or the missing devices will be
+        // forgotten.
+        let readonly = self.readonly || (self.degraded && devices.is_none());
+        let rewind = self.rewind;
+        if rewind && readonly {
+            // Rewinding must erase everything written after the checkpoint
+            return Err(Error::EINVAL);
+        }
+        if let Some(label) = self.inner.lock().unwrap().pools.get(&uuid) {
+            // Refuse pools with on-disk features that we don't understand
+            label.features.check_import(readonly)?;
+        }
+        // Check before consuming the labels, so a refused import can be
+        // retried.
+        let heartbeater = match &devices {
+            Some(devices) if !readonly => {
+                multihost::check(devices, self.force).await?;
+                Some(Heartbeater::start(devices)?)
+            },
+            _ => None
+        };
+        let (pool, raids, mut mirrors, mut leaves) = self.open_labels(uuid)?;
+        if devices.is_none() {
+            tracing::warn!(pool = %pool.name,
+                "Some devices are unavailable.  Importing degraded and \
+                read-only.");
+        }
+        if rewind && pool.checkpoint.is_none() {
+            return Err(Error::ENOENT);
+        }
+        let topology = raids.into_iter()
+            .map(|raid| {
+                let children = mirrors.remove(&raid.uuid()).unwrap()
+                    .into_iter()
+                    .map(|ml| {
+                        let leaf_paths = leaves.remove(&ml.uuid).unwrap();
+                        (ml.uuid, leaf_paths)
+                    }).collect::<Vec<_>>();
+                (raid.uuid(), children)
+            }).collect::<Vec<_>>();
+        let r = match self.open_pool(uuid, topology.clone(), readonly, rewind)
+            .await
+        {
+            Err(e @ (Error::EINTEGRITY | Error::EIO))
+                if !rewind && pool.checkpoint.is_none() =>
+            {
+                // The newest label references metadata that can't be read,
+                // probably because we lost power while syncing.  The second
+                // label still records the previous transaction group, so roll
+                // back to it.  But if the pool has a checkpoint, then the
+                // second label records that instead, and rolling back to it
+                // automatically could lose far more than one transaction.
+                tracing::warn!(pool = %pool.name, error = ?e,
+                    "The newest label references unreadable metadata.  \
+                    Rolling back to the previous transaction group.");
+                self.open_pool(uuid, topology, readonly, true).await
+                    .map_err(|_| e)
+            },
+            r => r
+        };
+        if let (Ok(_), Some(devices)) = (&r, devices) {
+            let cachefile = pool.properties.cachefile;
+            self.update_cachefile(uuid, pool.name.clone(), &cachefile,
+                                  devices.clone());
+            self.imported.lock().unwrap().insert(uuid,
+                Imported{name: pool.name, cachefile, devices});
+        }
+        if let (Ok(_), Some(hb)) = (&r, heartbeater) {
+            self.heartbeaters.lock().unwrap().push(hb);
+        }
+        r
+    }
+
+    /// Open all of a pool's vdevs and construct its `Database`.
+    ///
+    /// If `rewind` is set, use the second label and spacemap rather than the
+    /// first.  Either way, the roots of the pool's trees will be read before
+    /// returning, so a label that references unreadable metadata will result
+    /// in an error rather than a panic later.
+    async fn open_pool(
+        &self,
+        uuid: Uuid,
+        topology: Vec<(Uuid, Vec<(Uuid, Vec<PathBuf>)>)>,
+        readonly: bool,
+        rewind: bool
+    ) -> Result<database::Database>
+    {
+        let mut combined_clusters = topology.into_iter()
+        .map(move |(raid_uuid, children)| {
+            children.into_iter()
+                .map(|(mirror_uuid, leaf_paths)| {
+                    DevManager::open_mirror(mirror_uuid, leaf_paths, rewind)
                 }).collect::<FuturesUnordered<_>>()
             .try_collect::<Vec<_>>()
-            .and_then(move |mirrors| DevManager::open_cluster(mirrors, raid.uuid()))
+            .and_then(move |mirrors| {
+                DevManager::open_cluster(mirrors, raid_uuid, readonly, rewind)
+            })
         }).collect::<FuturesOrdered<_>>()
         .try_collect::<Vec<_>>().await?;
+        if let Some(budget) = self.open_zone_budget {
+            for (cluster, _reader) in combined_clusters.iter_mut() {
+                cluster.set_open_zone_budget(budget);
+            }
+        }
         let (pool, label_reader) = Pool::open(Some(uuid), combined_clusters);
-        let cs = self.cache_size.unwrap_or(1_073_741_824);
-        let wbs = self.writeback_size.unwrap_or(268_435_456);
-        let cache = cache::Cache::with_capacity(cs);
+        // With a memory budget, the cache and writeback cache may each use all
+        // of it, unless limited further.
+        let budget_total = self.memory_budget.as_ref().map(MemoryBudget::total);
+        let cs = self.cache_size.or(budget_total).unwrap_or(1_073_741_824);
+        let wbs = self.writeback_size.or(budget_total).unwrap_or(268_435_456);
+        let mut cache = cache::Cache::with_capacity(cs);
+        if let Some(fraction) = self.metadata_reserve {
+            cache.set_metadata_reserve(fraction);
+        }
+        if let Some(budget) = &self.memory_budget {
+            cache.set_memory_budget(budget.clone());
+        }
         let arc_cache = Arc::new(Mutex::new(cache));
         let ddml = Arc::new(ddml::DDML::open(pool, arc_cache.clone()));
-        let (idml, label_reader) = idml::IDML::open(ddml, arc_cache,
-            wbs, label_reader);
-        Ok(database::Database::open(Arc::new(idml), label_reader))
+        let (mut idml, label_reader) = idml::IDML::open(ddml, arc_cache,
+            wbs, label_reader)?;
+        if let Some(budget) = &self.memory_budget {
+            idml.set_memory_budget(budget.clone());
+        }
+        if let Some(rate) = self.background_rate {
+            idml.set_background_rate(rate);
+        }
+        let db = if readonly {
+            database::Database::open_readonly(Arc::new(idml), label_reader)?
+        } else {
+            database::Database::open(Arc::new(idml), label_reader)?
+        };
+        if let Err(e) = db.verify_roots().await {
+            db.shutdown().await;
+            return Err(e);
+        }
+        if rewind && !readonly {
+            // Immediately overwrite the labels that still refer to the
+            // abandoned state.
+            db.sync_transaction().await?;
+        }
+        Ok(db)
     }
 
     /// Import all of the clusters from a Pool.  For debugging purposes only.
     #[doc(hidden)]
     pub async fn import_clusters(&self, uuid: Uuid) -> Result<Vec<Cluster>>
     {
+        let readonly = self.readonly;
         let (_pool, raids, mut mirrors, mut leaves) = self.open_labels(uuid)?;
         raids.into_iter()
         .map(move |raid| {
@@ -113,15 +545,28 @@ impl DevManager {
             mirror_labels.iter()
                 .map(|mirror_label| {
                     let leaf_paths = leaves.remove(&mirror_label.uuid).unwrap();
-                    DevManager::open_mirror(mirror_label.uuid, leaf_paths)
+                    DevManager::open_mirror(mirror_label.uuid, leaf_paths,
+                                            false)
                 }).collect::<FuturesUnordered<_>>()
             .try_collect::<Vec<_>>()
-            .and_then(move |mirrors| DevManager::open_cluster(mirrors, raid.uuid()))
+            .and_then(move |mirrors| {
+                DevManager::open_cluster(mirrors, raid.uuid(), readonly, false)
+            })
         }).collect::<FuturesOrdered<_>>()
         .map_ok(|(cluster, _reader)| cluster)
         .try_collect::<Vec<_>>().await
     }
 
+    /// List whichever of a pool's devices haven't been tasted, by UUID.
+    ///
+    /// If all of the leaves of a mirror or RAID are missing, then its own
+    /// UUID is listed instead.  The pool can be imported only if this is
+    /// empty, or if [`degraded`](DevManager::degraded) is set and every
+    /// mirror still has some leaf.
+    pub fn missing(&self, uuid: Uuid) -> Vec<Uuid> {
+        self.inner.lock().unwrap().missing(uuid)
+    }
+
     /// List every pool that hasn't been imported, but can be
     pub fn importable_pools(&self) -> Vec<(String, Uuid)> {
         let inner = self.inner.lock().unwrap();
@@ -133,18 +578,29 @@ impl DevManager {
 
     fn open_cluster(
         mirrors: Vec<(Mirror, label::LabelReader)>,
-        uuid: Uuid
+        uuid: Uuid,
+        readonly: bool,
+        rewind: bool
     ) -> impl Future<Output=Result<(Cluster, label::LabelReader)>>
     {
         let (vdev_raid_api, reader) = raid::open(Some(uuid), mirrors);
-        Cluster::open(vdev_raid_api)
-            .map_ok(move |cluster| (cluster, reader))
+        async move {
+            if readonly && rewind {
+                Cluster::open_readonly_rewind(vdev_raid_api).await
+            } else if readonly {
+                Cluster::open_readonly(vdev_raid_api).await
+            } else if rewind {
+                Cluster::open_rewind(vdev_raid_api).await
+            } else {
+                Cluster::open(vdev_raid_api).await
+            }
+        }.map_ok(move |cluster| (cluster, reader))
     }
 
-    fn open_mirror(uuid: Uuid, leaf_paths: Vec<PathBuf>)
+    fn open_mirror(uuid: Uuid, leaf_paths: Vec<PathBuf>, rewind: bool)
         -> impl Future<Output=Result<(Mirror, label::LabelReader)>>
     {
-        DevManager::open_vdev_blocks(leaf_paths)
+        DevManager::open_vdev_blocks(leaf_paths, rewind)
         .map_ok(move |vdev_blocks| {
             Mirror::open(Some(uuid), vdev_blocks)
         })
@@ -158,60 +614,207 @@ impl DevManager {
             BTreeMap<Uuid, Vec<PathBuf>>
         )>
     {
+        let degraded = self.degraded;
         let mut inner = self.inner.lock().unwrap();
-        inner.pools.remove(&uuid)
-        .map(|pool| {
-            let raids = pool.children.iter()
-                .map(|child_uuid| inner.raids.remove(child_uuid).unwrap())
-                .collect::<Vec<_>>();
-            let mirrors = raids.iter().map(|raid| {
-                let mirrors = raid.iter_children().map(|uuid| {
-                    inner.mirrors.remove(uuid).unwrap()
-                }).collect::<Vec<_>>();
-                (raid.uuid(), mirrors)
-            }).collect::<BTreeMap<_, _>>();
-            let mut leaves = BTreeMap::new();
-            for vmirrors in mirrors.values() {
-                for mirror in vmirrors {
-                    let vleaves = mirror.children.iter().map(|uuid| {
-                        inner.leaves.remove(uuid).unwrap()
-                    }).collect::<Vec<_>>();
-                    leaves.insert(mirror.uuid, vleaves);
+        let pool = inner.pools.remove(&uuid).ok_or(Error::ENOENT)?;
+        let raids = pool.children.iter()
+            .map(|child_uuid| {
+                inner.raids.remove(child_uuid).ok_or(Error::ENXIO)
+            }).collect::<Result<Vec<_>>>()?;
+        let mut mirrors = BTreeMap::new();
+        for raid in raids.iter() {
+            let vmirrors = raid.iter_children().map(|uuid| {
+                inner.mirrors.remove(uuid).ok_or(Error::ENXIO)
+            }).collect::<Result<Vec<_>>>()?;
+            mirrors.insert(raid.uuid(), vmirrors);
+        }
+        let mut leaves = BTreeMap::new();
+        for vmirrors in mirrors.values() {
+            for mirror in vmirrors {
+                let mut vleaves = Vec::new();
+                for uuid in mirror.children.iter() {
+                    match inner.leaves.remove(uuid) {
+                        Some(path) => vleaves.push(path),
+                        None if degraded => (),
+                        None => return Err(Error::ENXIO)
+                    }
+                }
+                if vleaves.is_empty() {
+                    return Err(Error::ENXIO);
                 }
+                leaves.insert(mirror.uuid, vleaves);
             }
-            // Drop the self.inner mutex
-            (pool, raids, mirrors, leaves)
-        }).ok_or(Error::ENOENT)
+        }
+        // Drop the self.inner mutex
+        Ok((pool, raids, mirrors, leaves))
     }
 
-    fn open_vdev_blocks(leaf_paths: Vec<PathBuf>)
+    fn open_vdev_blocks(leaf_paths: Vec<PathBuf>, rewind: bool)
         -> impl Future<Output=Result<Vec<(VdevBlock, label::LabelReader)>>>
     {
         stream::iter(leaf_paths.into_iter())
         .map(Ok)
-        .and_then(VdevFile::open)
+        .and_then(move |path| async move {
+            if rewind {
+                VdevFile::open_checkpoint(path).await
+            } else {
+                VdevFile::open(path).await
+            }
+        })
         .map_ok(|(leaf, reader)| {
             (VdevBlock::new(leaf), reader)
         }).try_collect()
     }
 
+    /// Import pools read-only.
+    ///
+    /// A read-only pool will never be written to: open zones won't be
+    /// reopened, transactions won't be synced, and every attempt to modify
+    /// the pool will fail with `EROFS`.  Useful for recovering data from
+    /// failing disks, or for reading pools that have read-only compatible
+    /// features which this version doesn't understand.
+    pub fn readonly(&mut self, readonly: bool) {
+        self.readonly = readonly;
+    }
+
+    /// Rewind pools to their checkpoints when importing them.
+    ///
+    /// Everything written since the checkpoint was taken will be lost, and the
+    /// checkpoint will be consumed.  Importing a pool that has no checkpoint
+    /// will fail with `ENOENT`.
+    pub fn rewind_to_checkpoint(&mut self, rewind: bool) {
+        self.rewind = rewind;
+    }
+
+    /// Record the devices of a `Cluster` that was just added to the named
+    /// pool.
+    ///
+    /// They'll be included in the pool's heartbeat, and in the cache file.
+    pub fn record_cluster(&self, pool: &str, devices: Vec<PathBuf>)
+        -> Result<()>
+    {
+        let mut imported = self.imported.lock().unwrap();
+        let (uuid, entry) = imported.iter_mut()
+            .find(|(_uuid, imported)| imported.name == pool)
+            .ok_or(Error::ENOENT)?;
+        let hb = Heartbeater::start(&devices)?;
+        self.heartbeaters.lock().unwrap().push(hb);
+        entry.devices.extend(devices);
+        self.update_cachefile(*uuid, entry.name.clone(), &entry.cachefile,
+                              entry.devices.clone());
+        Ok(())
+    }
+
     /// Taste the device identified by `p` for an BFFFS label.
     ///
     /// If present, retain the device in the `DevManager` for use as a spare or
     /// for building Pools.
-    // TODO: add a method for tasting disks in parallel.
     pub async fn taste<P: AsRef<Path>>(&self, p: P) -> Result<()> {
         let pathbuf = p.as_ref().to_owned();
+        let labels = DevManager::read_labels(p).await?;
+        self.inner.lock().unwrap().insert(pathbuf, labels);
+        Ok(())
+    }
+
+    /// Taste many devices concurrently.
+    ///
+    /// Unlike [`taste`](DevManager::taste), one unreadable device won't stop
+    /// the rest from being tasted.  Instead, the path of every device that
+    /// couldn't be is returned along with its error.
+    pub async fn taste_all<P: AsRef<Path>>(&self, paths: &[P])
+        -> Vec<(PathBuf, Error)>
+    {
+        paths.iter()
+        .map(|p| async move {
+            self.taste(p).await
+            .err()
+            .map(|e| (p.as_ref().to_owned(), e))
+        }).collect::<FuturesOrdered<_>>()
+        .filter_map(future::ready)
+        .collect::<Vec<_>>()
+        .await
+    }
+
+    async fn read_labels<P: AsRef<Path>>(p: P) -> Result<Labels> {
         let (vdev_file, mut reader) = VdevFile::open(p).await?;
-        let mut inner = self.inner.lock().unwrap();
-        inner.leaves.insert(vdev_file.uuid(), pathbuf);
         let ml: mirror::Label = reader.deserialize().unwrap();
-        inner.mirrors.insert(ml.uuid, ml);
         let rl: raid::Label = reader.deserialize().unwrap();
-        inner.raids.insert(rl.uuid(), rl);
         let pl: pool::Label = reader.deserialize().unwrap();
-        inner.pools.insert(pl.uuid, pl);
-        Ok(())
+        Ok((vdev_file.uuid(), ml, rl, pl))
+    }
+
+    /// Taste only the devices that the cache file lists for the named pool.
+    ///
+    /// Returns `true` if they comprise the complete pool, which may then be
+    /// imported.  Otherwise the cache file is missing or stale, and the caller
+    /// should fall back to tasting every candidate device.
+    pub async fn taste_cached<S: AsRef<str>>(&self, name: S) -> bool {
+        let path = match &self.cachefile {
+            Some(path) => path,
+            None => return false
+        };
+        let cf = match CacheFile::load(path) {
+            Ok(cf) => cf,
+            Err(e) => {
+                if e.kind() != io::ErrorKind::NotFound {
+                    tracing::warn!(path = %path.display(), error = %e,
+                        "Cannot read the device cache file");
+                }
+                return false;
+            }
+        };
+        let entry = cf.pools.into_iter()
+            .find(|(_uuid, cp)| cp.name == name.as_ref());
+        let (uuid, cp) = match entry {
+            Some(entry) => entry,
+            None => return false
+        };
+        // Taste into a scratch space, so a stale cache won't leave behind
+        // labels from some other pool.
+        let mut scratch = Inner::default();
+        for dev in cp.devices.iter() {
+            match DevManager::read_labels(dev).await {
+                Ok(labels) => scratch.insert(dev.clone(), labels),
+                Err(_) => return false
+            }
+        }
+        match scratch.pools.get(&uuid) {
+            Some(label) if label.name == name.as_ref() => (),
+            _ => return false
+        }
+        if scratch.devices(uuid).is_none() {
+            return false;
+        }
+        self.inner.lock().unwrap().merge(scratch);
+        true
+    }
+
+    /// Record a freshly imported pool's devices in the cache file, if any
+    ///
+    /// `cachefile` is the value of the pool's `cachefile` property, which may
+    /// override our own.
+    fn update_cachefile(
+        &self,
+        uuid: Uuid,
+        name: String,
+        cachefile: &str,
+        devices: Vec<PathBuf>
+    ) {
+        let path = match (cachefile, &self.cachefile) {
+            ("none", _) => return,
+            ("", Some(path)) => path.as_path(),
+            ("", None) => return,
+            (path, _) => Path::new(path)
+        };
+        // A corrupt or missing cache file is no great loss; just start over.
+        let mut cf = CacheFile::load(path).unwrap_or_default();
+        // Pool names must be unique within the cache
+        cf.pools.retain(|u, cp| *u == uuid || cp.name != name);
+        cf.pools.insert(uuid, CachedPool{name, devices});
+        if let Err(e) = cf.save(path) {
+            tracing::warn!(path = %path.display(), error = %e,
+                "Cannot update the device cache file");
+        }
     }
 
     /// Set the maximum amount of dirty cached data, in bytes.
@@ -115,6 +115,17 @@ pub trait DML: Send + Sync {
                              txg: TxgT)
         -> Pin<Box<dyn Future<Output=Result<<Self as DML>::Addr>> + Send>>;
 
+    /// Like [`put`](DML::put), but also write a second, independent copy of
+    /// the record to disk, though not to the cache.  Return the addresses of
+    /// the original and the copy.
+    ///
+    /// The two copies will never share a zone, even after being moved by the
+    /// cleaner.
+    fn put_ditto<T: Cacheable>(&self, cacheable: T, compression: Compression,
+                               txg: TxgT)
+        -> Pin<Box<dyn Future<Output=Result<(<Self as DML>::Addr,
+                                             <Self as DML>::Addr)>> + Send>>;
+
     /// Repay [`WriteBack`] [`Credit`]
     fn repay(&self, credit: Credit);
 
@@ -0,0 +1,231 @@
+//vim: tw=80
+//! On-disk format feature flags
+//!
+//! Each pool's label lists the optional on-disk features that are enabled for
+//! it.  A feature is never enabled silently; only pool creation and `bfffs
+//! pool upgrade` may do that.  Once enabled, a feature can't be disabled.
+use std::{
+    fmt,
+    str::FromStr
+};
+use serde_derive::*;
+
+use crate::types::*;
+
+/// Whether software that doesn't understand a feature may import the pool.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FeatureKind {
+    /// Unaware software may import the pool, but only read-only.
+    ReadOnlyCompat,
+    /// Unaware software may not import the pool at all.
+    Incompat
+}
+
+/// An optional on-disk format feature
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd,
+         Serialize)]
+pub enum Feature {
+    /// File systems may use records larger than 1MB.
+    ///
+    /// Older software can read such records, but may not be able to cache
+    /// and rewrite them.
+    LargeRecords,
+
+    /// Large uncompressed records carry a checksum for each 32 KiB chunk, so
+    /// part of one can be read and verified without reading the rest.
+    ///
+    /// Older software would return the checksums as though they were part of
+    /// the data, so it may not import the pool at all.
+    ChunkChecksums,
+
+    /// File systems may store a second copy of each data record, as set by
+    /// the `copies` property.
+    ///
+    /// Older software wouldn't understand the resulting extents.
+    DittoData,
+}
+
+impl Feature {
+    /// Every feature understood by this version of BFFFS
+    pub const ALL: [Feature; 3] =
+        [Feature::LargeRecords, Feature::ChunkChecksums, Feature::DittoData];
+
+    /// Bit position within the mask for this feature's kind
+    fn bit(self) -> u64 {
+        1 << match self {
+            Feature::LargeRecords => 0,
+            Feature::ChunkChecksums => 0,
+            Feature::DittoData => 1,
+        }
+    }
+
+    pub fn kind(self) -> FeatureKind {
+        match self {
+            Feature::LargeRecords => FeatureKind::ReadOnlyCompat,
+            Feature::ChunkChecksums => FeatureKind::Incompat,
+            Feature::DittoData => FeatureKind::Incompat,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Feature::LargeRecords => "large_records",
+            Feature::ChunkChecksums => "chunk_checksums",
+            Feature::DittoData => "ditto_data",
+        }
+    }
+}
+
+impl fmt::Display for Feature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.name().fmt(f)
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ParseFeatureError(String);
+
+impl fmt::Display for ParseFeatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown feature {}", self.0)
+    }
+}
+impl std::error::Error for ParseFeatureError {}
+
+impl FromStr for Feature {
+    type Err = ParseFeatureError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, ParseFeatureError> {
+        Feature::ALL.iter()
+            .find(|f| f.name() == s)
+            .copied()
+            .ok_or_else(|| ParseFeatureError(s.to_owned()))
+    }
+}
+
+/// The set of features enabled on a pool, as stored in its label.
+///
+/// Stored as bitmasks rather than as a list of `Feature`s, so that a label
+/// written by newer software can still be read, and its unknown features
+/// detected.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Features {
+    /// Features that may be ignored by read-only importers
+    ro_compat: u64,
+    /// Features that must be understood by any importer
+    incompat: u64
+}
+
+impl Features {
+    /// Every feature understood by this version of BFFFS.  New pools get
+    /// these.
+    pub fn all() -> Self {
+        let mut features = Features::default();
+        for f in Feature::ALL.iter() {
+            features.insert(*f);
+        }
+        features
+    }
+
+    /// Can this version of BFFFS import a pool with these features?
+    pub fn check_import(&self, readonly: bool) -> Result<()> {
+        let known = Features::all();
+        if self.incompat & !known.incompat != 0 {
+            Err(Error::EOPNOTSUPP)
+        } else if !readonly && self.ro_compat & !known.ro_compat != 0 {
+            Err(Error::EROFS)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn contains(&self, feature: Feature) -> bool {
+        match feature.kind() {
+            FeatureKind::ReadOnlyCompat => self.ro_compat & feature.bit() != 0,
+            FeatureKind::Incompat => self.incompat & feature.bit() != 0,
+        }
+    }
+
+    /// Enable a feature.  Returns `true` if it wasn't already enabled.
+    pub fn insert(&mut self, feature: Feature) -> bool {
+        let r = !self.contains(feature);
+        match feature.kind() {
+            FeatureKind::ReadOnlyCompat => self.ro_compat |= feature.bit(),
+            FeatureKind::Incompat => self.incompat |= feature.bit(),
+        }
+        r
+    }
+
+    /// Iterate through all enabled features that this version understands
+    pub fn iter(&self) -> impl Iterator<Item=Feature> + '_ {
+        Feature::ALL.iter()
+            .copied()
+            .filter(move |f| self.contains(*f))
+    }
+}
+
+// LCOV_EXCL_START
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    #[test]
+    fn all() {
+        let features = Features::all();
+        for f in Feature::ALL.iter() {
+            assert!(features.contains(*f));
+        }
+        assert_eq!(features.iter().count(), Feature::ALL.len());
+    }
+
+    #[test]
+    fn check_import_known() {
+        let features = Features::all();
+        assert_eq!(Ok(()), features.check_import(false));
+        assert_eq!(Ok(()), features.check_import(true));
+    }
+
+    #[test]
+    fn check_import_unknown_incompat() {
+        let features = Features{ro_compat: 0, incompat: 1 << 63};
+        assert_eq!(Err(Error::EOPNOTSUPP), features.check_import(false));
+        assert_eq!(Err(Error::EOPNOTSUPP), features.check_import(true));
+    }
+
+    #[test]
+    fn check_import_unknown_ro_compat() {
+        let features = Features{ro_compat: 1 << 63, incompat: 0};
+        assert_eq!(Err(Error::EROFS), features.check_import(false));
+        assert_eq!(Ok(()), features.check_import(true));
+    }
+
+    #[test]
+    fn from_str() {
+        assert_eq!(Ok(Feature::LargeRecords),
+            Feature::from_str("large_records"));
+        assert!(Feature::from_str("frobnicate").is_err());
+        for f in Feature::ALL.iter() {
+            assert_eq!(Ok(*f), Feature::from_str(&f.to_string()));
+        }
+    }
+
+    #[test]
+    fn insert() {
+        let mut features = Features::default();
+        assert!(!features.contains(Feature::LargeRecords));
+        assert!(features.insert(Feature::LargeRecords));
+        assert!(features.contains(Feature::LargeRecords));
+        assert!(!features.insert(Feature::LargeRecords));
+    }
+
+    /// Features of different kinds may share a bit position
+    #[test]
+    fn insert_different_kinds() {
+        let mut features = Features::default();
+        assert!(features.insert(Feature::ChunkChecksums));
+        assert!(!features.contains(Feature::LargeRecords));
+        assert_eq!(Err(Error::EOPNOTSUPP),
+            Features{ro_compat: 0, incompat: 1 << 2}.check_import(true));
+    }
+}
+// LCOV_EXCL_STOP
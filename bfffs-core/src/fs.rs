@@ -3,10 +3,18 @@
 
 use bitfield::*;
 use crate::{
-    database::{Database, ReadOnlyFilesystem, ReadWriteFilesystem, TreeID},
+    database::{
+        Database,
+        ReadOnlyFilesystem,
+        ReadWriteFilesystem,
+        TreeID,
+        slop_space
+    },
     dataset::{RangeQuery, ReadDataset},
+    feature::Feature,
     fs_tree::*,
     property::*,
+    tree::Value,
     types::*,
     util::*
 };
@@ -22,18 +30,25 @@ use futures::{
     task::{Context, Poll}
 };
 use libc::dev_t;
+use serde_derive::{Deserialize, Serialize};
 use std::{
+    array,
     cmp,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     ffi::{OsStr, OsString},
-    fmt::Debug,
+    fmt::{self, Debug},
     io,
     mem,
     os::unix::ffi::OsStrExt,
+    path::PathBuf,
     pin::Pin,
+    str::FromStr,
     sync::{
         atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering},
         Arc,
-    }
+        Mutex
+    },
+    time::{Duration, Instant}
 };
 
 #[cfg(test)] mod tests;
@@ -42,6 +57,73 @@ pub type ExtAttr = crate::fs_tree::ExtAttr;
 pub type ExtAttrNamespace = crate::fs_tree::ExtAttrNamespace;
 pub type Timespec = crate::fs_tree::Timespec;
 
+/// Maximum length of a single file name, in bytes.
+pub const NAME_MAX: usize = 255;
+
+/// Maximum length of a symbolic link's target, in bytes.
+///
+/// The `Fs` layer only ever sees one path component at a time, so this is the
+/// only place where a whole path is stored.
+pub const PATH_MAX: usize = 1024;
+
+/// Device number of a whiteout.
+///
+/// Overlay file systems mark a file as deleted from the lower layer by
+/// creating a character device with device number 0 in the upper layer.
+pub const WHITEOUT_RDEV: dev_t = 0;
+
+/// Name of the `System` namespace extended attribute that marks a directory as
+/// opaque.
+///
+/// An opaque directory in an overlay's upper layer hides the contents of the
+/// same directory in the lower layer.  Its value is "y".
+pub const OPAQUE_XATTR: &str = "overlay.opaque";
+
+/// Name of the `System` namespace extended attribute that overrides the
+/// record size of new files in a directory.
+///
+/// Its value is the record size in bytes, as a decimal string like "16384".
+/// New subdirectories inherit it.  Existing files are unaffected.
+pub const RECORDSIZE_XATTR: &str = "bfffs.recordsize";
+
+/// Name of the read-only `System` namespace extended attribute that reports a
+/// file's change counter.
+///
+/// Its value is the counter as a decimal string.  It isn't stored on disk, and
+/// doesn't appear in `listextattr`.
+pub const CHANGE_XATTR: &str = "bfffs.change";
+
+/// Largest record size, log base 2, that may be used without the
+/// `large_records` feature.
+const MAX_SMALL_RECORDSIZE: u8 = 20;
+
+/// Most whole records that a single transaction will deallocate when
+/// truncating or deleting a file.
+///
+/// Bigger files are freed in several batches, so that no one operation holds
+/// up a transaction or needs an outsized writeback credit.
+const DEALLOC_BATCH: u64 = 4096;
+
+/// How many records to prefetch after each read of a file that's being
+/// accessed sequentially.
+const READAHEAD_RECORDS: u64 = 8;
+
+/// How many files' unsynced data transactions to remember before pruning the
+/// ones that have since been synced.
+const DIRTY_DATA_PRUNE: usize = 4096;
+
+/// Parse the value of a [`RECORDSIZE_XATTR`], returning the record size's log
+/// base 2.
+fn parse_recordsize_xattr(buf: &[u8]) -> Option<u8> {
+    let rs = std::str::from_utf8(buf).ok()?.parse::<u64>().ok()?;
+    let exp = rs.trailing_zeros();
+    if rs.is_power_of_two() && (12..=24).contains(&exp) {
+        Some(exp as u8)
+    } else {
+        None
+    }
+}
+
 /// Operations used for data that is stored in in-BTree hash tables
 mod htable {
     use crate::{
@@ -298,7 +380,8 @@ impl Uio {
             } else {
                 (reclen1 + (rec - 1) * rs)..(reclen1 + rec * rs)
             };
-            // Data copy
+            // Data copy.  This is the only one, as long as the record is
+            // either full or is new and begins at a record boundary.
             let v = Vec::from(&self.data[range]);
             f(v)
         }).collect::<Vec<T>>()
@@ -323,16 +406,368 @@ impl<'a> From<&'a [u8]> for Uio {
     }
 }
 
+/// Access pattern hints for a file, like those of `posix_fadvise(2)`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub enum Advice {
+    /// No special treatment
+    #[default]
+    Normal,
+    /// The file will be read sequentially, so read ahead aggressively.
+    Sequential,
+    /// The file will be accessed randomly.
+    Random,
+    /// The file will be accessed in the near future.
+    WillNeed,
+    /// The file won't be accessed in the near future, so drop its data from
+    /// the cache.
+    DontNeed,
+    /// The file's data will only be accessed once, so don't cache it.
+    NoReuse,
+}
+
+impl Advice {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Advice::Normal => "normal",
+            Advice::Sequential => "sequential",
+            Advice::Random => "random",
+            Advice::WillNeed => "willneed",
+            Advice::DontNeed => "dontneed",
+            Advice::NoReuse => "noreuse",
+        }
+    }
+}
+
+impl FromStr for Advice {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "normal" => Ok(Advice::Normal),
+            "sequential" => Ok(Advice::Sequential),
+            "random" => Ok(Advice::Random),
+            "willneed" => Ok(Advice::WillNeed),
+            "dontneed" => Ok(Advice::DontNeed),
+            "noreuse" => Ok(Advice::NoReuse),
+            _ => Err(Error::EINVAL)
+        }
+    }
+}
+
+/// Counts of how often each access pattern hint has been applied
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct FadviseStats {
+    /// Number of times that `Advice::Sequential` was given
+    pub sequential: u64,
+    /// Number of times that `Advice::DontNeed` was given
+    pub dontneed: u64,
+    /// Number of times that `Advice::NoReuse` was given
+    pub noreuse: u64,
+    /// Number of records prefetched on behalf of `Advice::Sequential`
+    pub readahead: u64,
+    /// Number of records dropped from the cache by `Advice::DontNeed`
+    pub evicted: u64,
+    /// Number of reads that bypassed the cache due to `Advice::NoReuse`
+    pub uncached: u64,
+}
+
+impl fmt::Display for FadviseStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "sequential: {}", self.sequential)?;
+        writeln!(f, "dontneed: {}", self.dontneed)?;
+        writeln!(f, "noreuse: {}", self.noreuse)?;
+        writeln!(f, "readahead: {}", self.readahead)?;
+        writeln!(f, "evicted: {}", self.evicted)?;
+        writeln!(f, "uncached: {}", self.uncached)
+    }
+}
+
+/// Live counters backing [`FadviseStats`]
+#[derive(Debug, Default)]
+struct FadviseCounters {
+    sequential: AtomicU64,
+    dontneed: AtomicU64,
+    noreuse: AtomicU64,
+    readahead: AtomicU64,
+    evicted: AtomicU64,
+    uncached: AtomicU64,
+}
+
+impl FadviseCounters {
+    fn stats(&self) -> FadviseStats {
+        FadviseStats {
+            sequential: self.sequential.load(Ordering::Relaxed),
+            dontneed: self.dontneed.load(Ordering::Relaxed),
+            noreuse: self.noreuse.load(Ordering::Relaxed),
+            readahead: self.readahead.load(Ordering::Relaxed),
+            evicted: self.evicted.load(Ordering::Relaxed),
+            uncached: self.uncached.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Number of buckets in each of [`IoStats`]'s latency histograms
+pub const LATENCY_BUCKETS: usize = 24;
+
+/// Operation counters for a single file system, as reported by
+/// [`Fs::io_stats`].
+///
+/// Only operations that succeed are counted.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct IoStats {
+    /// Number of `read` operations
+    pub reads: u64,
+    /// Number of `write` operations
+    pub writes: u64,
+    /// Bytes returned by `read`
+    pub bytes_read: u64,
+    /// Bytes accepted by `write`
+    pub bytes_written: u64,
+    /// Histogram of `read` latencies.  Bucket `i` counts operations that took
+    /// less than 2^i microseconds, but no less than 2^(i-1).  The last bucket
+    /// also counts everything slower.
+    pub read_latency: [u64; LATENCY_BUCKETS],
+    /// Histogram of `write` latencies, like `read_latency`.
+    pub write_latency: [u64; LATENCY_BUCKETS],
+    /// How long the counters have been accumulating, since the file system
+    /// was mounted or the counters were last reset.
+    pub age: Duration,
+}
+
+/// Live counters backing [`IoStats`]
+#[derive(Debug)]
+struct IoCounters {
+    reads: AtomicU64,
+    writes: AtomicU64,
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    read_latency: [AtomicU64; LATENCY_BUCKETS],
+    write_latency: [AtomicU64; LATENCY_BUCKETS],
+    /// When the counters started accumulating
+    since: Mutex<Instant>,
+}
+
+impl IoCounters {
+    /// Which latency bucket does an operation belong in?
+    fn bucket(latency: Duration) -> usize {
+        let usecs = latency.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = (u64::BITS - usecs.leading_zeros()) as usize;
+        bucket.min(LATENCY_BUCKETS - 1)
+    }
+
+    fn record_read(&self, nbytes: usize, latency: Duration) {
+        self.reads.fetch_add(1, Ordering::Relaxed);
+        self.bytes_read.fetch_add(nbytes as u64, Ordering::Relaxed);
+        self.read_latency[Self::bucket(latency)]
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_write(&self, nbytes: u32, latency: Duration) {
+        self.writes.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written.fetch_add(u64::from(nbytes), Ordering::Relaxed);
+        self.write_latency[Self::bucket(latency)]
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Zero every counter.
+    ///
+    /// Operations that complete concurrently may or may not be counted.
+    fn reset(&self) {
+        let mut since = self.since.lock().unwrap();
+        self.reads.store(0, Ordering::Relaxed);
+        self.writes.store(0, Ordering::Relaxed);
+        self.bytes_read.store(0, Ordering::Relaxed);
+        self.bytes_written.store(0, Ordering::Relaxed);
+        for c in self.read_latency.iter().chain(self.write_latency.iter()) {
+            c.store(0, Ordering::Relaxed);
+        }
+        *since = Instant::now();
+    }
+
+    fn stats(&self) -> IoStats {
+        let load = |c: &AtomicU64| c.load(Ordering::Relaxed);
+        IoStats {
+            reads: load(&self.reads),
+            writes: load(&self.writes),
+            bytes_read: load(&self.bytes_read),
+            bytes_written: load(&self.bytes_written),
+            read_latency: array::from_fn(|i| load(&self.read_latency[i])),
+            write_latency: array::from_fn(|i| load(&self.write_latency[i])),
+            age: self.since.lock().unwrap().elapsed(),
+        }
+    }
+}
+
+impl Default for IoCounters {
+    fn default() -> Self {
+        IoCounters {
+            reads: Default::default(),
+            writes: Default::default(),
+            bytes_read: Default::default(),
+            bytes_written: Default::default(),
+            read_latency: Default::default(),
+            write_latency: Default::default(),
+            since: Mutex::new(Instant::now()),
+        }
+    }
+}
+
+/// Recently read symlink targets, by inode number.
+///
+/// A symlink's target can never change, and inode numbers are never reused
+/// while the file system is mounted, so entries never need to be invalidated.
+/// The oldest are simply evicted once the cache is full.
+#[derive(Debug, Default)]
+struct LinkCache {
+    targets: HashMap<u64, OsString>,
+    /// Inode numbers in the order they were inserted
+    order: VecDeque<u64>,
+}
+
+impl LinkCache {
+    /// Maximum number of symlinks to cache.  Enough for a large ports tree or
+    /// node_modules directory, yet at most a few MB even if every target is
+    /// PATH_MAX long.
+    const CAPACITY: usize = 4096;
+
+    fn get(&self, ino: u64) -> Option<OsString> {
+        self.targets.get(&ino).cloned()
+    }
+
+    fn insert(&mut self, ino: u64, target: OsString) {
+        if self.targets.contains_key(&ino) {
+            return;
+        }
+        if self.order.len() >= LinkCache::CAPACITY {
+            let oldest = self.order.pop_front().unwrap();
+            self.targets.remove(&oldest);
+        }
+        self.targets.insert(ino, target);
+        self.order.push_back(ino);
+    }
+}
+
+/// A run of adjacent small writes to one file, buffered so they can be written
+/// to the tree all at once.  See [`Property::Coalesce`].
+#[derive(Debug)]
+struct PendingWrite {
+    /// File offset of the start of `buf`
+    offset: u64,
+    /// The most recently synced transaction group when the first write was
+    /// buffered.  Writes aren't coalesced across transaction groups.
+    synced: Option<TxgT>,
+    buf: Vec<u8>,
+}
+
+impl PendingWrite {
+    /// File offset just past the end of the buffered data
+    fn end(&self) -> u64 {
+        self.offset + self.buf.len() as u64
+    }
+}
+
+/// A handle opened by a client with [`Fs::open`]
+#[derive(Clone, Copy, Debug)]
+struct Handle {
+    ino: u64,
+    pid: u32,
+    flags: u32,
+    opened: Instant,
+}
+
+/// A client's open file handle, as reported by [`Fs::open_files`]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct OpenFile {
+    /// Handle number, unique within this mount of the file system
+    pub fh: u64,
+    pub ino: u64,
+    /// The process that opened the file, or 0 if unknown
+    pub pid: u32,
+    /// Flags passed to `open(2)`
+    pub flags: u32,
+    /// How long ago the file was opened
+    pub age: Duration,
+}
+
+/// Per-inode locks.
+///
+/// Most operations modify an inode by reading it, changing it, and writing it
+/// back.  Two such operations on the same inode must not interleave, or one's
+/// changes would be lost.  Each one holds the locks of every inode that it
+/// modifies, so operations on unrelated files can still proceed in parallel.
+#[derive(Clone, Default)]
+struct InodeLocks(
+    /// Map of each locked inode to its lock and the number of tasks that hold
+    /// or await it.  Entries are removed when no task needs them anymore.
+    Arc<Mutex<HashMap<u64, (futures_locks::Mutex<()>, usize)>>>
+);
+
+impl InodeLocks {
+    /// Acquire the lock for `ino`.  It will be released when the returned
+    /// guard is dropped.
+    fn lock(&self, ino: u64) -> impl Future<Output=InodeGuard> + Send {
+        let mut map = self.0.lock().unwrap();
+        let entry = map.entry(ino)
+            .or_insert_with(|| (futures_locks::Mutex::new(()), 0));
+        entry.1 += 1;
+        let ticket = InodeTicket{locks: self.0.clone(), ino};
+        entry.0.lock()
+            .map(move |guard| InodeGuard{_guard: guard, _ticket: ticket})
+    }
+
+    /// Acquire the locks for all of `inos`.
+    ///
+    /// Locks are always taken in ascending order of inode number, so that two
+    /// tasks locking overlapping sets can't deadlock.  Duplicates are ignored.
+    async fn lock_many(&self, inos: &[u64]) -> Vec<InodeGuard> {
+        let mut inos = inos.to_vec();
+        inos.sort_unstable();
+        inos.dedup();
+        let mut guards = Vec::with_capacity(inos.len());
+        for ino in inos {
+            guards.push(self.lock(ino).await);
+        }
+        guards
+    }
+}
+
+/// Keeps an `InodeLocks` entry alive.  Dropping it, even before the lock is
+/// acquired, releases the entry.
+struct InodeTicket {
+    locks: Arc<Mutex<HashMap<u64, (futures_locks::Mutex<()>, usize)>>>,
+    ino: u64
+}
+
+impl Drop for InodeTicket {
+    fn drop(&mut self) {
+        let mut map = self.locks.lock().unwrap();
+        let entry = map.get_mut(&self.ino).unwrap();
+        entry.1 -= 1;
+        if entry.1 == 0 {
+            map.remove(&self.ino);
+        }
+    }
+}
+
+/// Exclusive access to a single inode.
+struct InodeGuard {
+    // NB: field order matters.  The lock must be released before the ticket.
+    _guard: futures_locks::MutexGuard<()>,
+    _ticket: InodeTicket
+}
+
 /// Information about an in-use file
 ///
 /// Basically, this is the stuff that would go in a vnode's v_data field
-#[derive(Debug)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct FileDataMut {
     ino: u64,
     pub lookup_count: u64,
     /// This file's parent's inode number.  Only valid for directories that
     /// aren't the root directory.
-    parent: Option<u64>
+    parent: Option<u64>,
+    /// Access pattern hint most recently given for this file
+    advice: Advice
 }
 
 impl FileDataMut {
@@ -346,7 +781,8 @@ impl FileDataMut {
                 "Fs::unlink depends upon this assumption");
         FileData {
             ino: self.ino,
-            parent: self.parent
+            parent: self.parent,
+            advice: self.advice
         }
     }
 
@@ -357,7 +793,7 @@ impl FileDataMut {
 
     /// Create a new `FileDataMut`
     fn new(parent: Option<u64>, ino: u64) -> Self {
-        Self{ino, lookup_count: 1, parent}
+        Self{ino, lookup_count: 1, parent, advice: Advice::Normal}
     }
 
     /// Create a new `FileDataMut` for use in tests outside of this module
@@ -376,18 +812,29 @@ impl FileDataMut {
             // Probably a non-directory, which doesn't store its parent.
         }
     }
+
+    /// Set the access pattern hint for subsequent operations on this file.
+    pub fn set_advice(&mut self, advice: Advice) {
+        self.advice = advice;
+    }
 }
 
 /// An immutable handle to a [`FileDataMut`].
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
 pub struct FileData{
     ino: u64,
     /// This file's parent's inode number.  Only valid for directories that
     /// aren't the root directory.
-    parent: Option<u64>
+    parent: Option<u64>,
+    /// Access pattern hint most recently given for this file
+    advice: Advice
 }
 
 impl FileData {
+    pub fn advice(&self) -> Advice {
+        self.advice
+    }
+
     pub fn ino(&self) -> u64
     {
         self.ino
@@ -413,12 +860,48 @@ pub struct Fs {
     /// Update files' atimes when reading?
     atime: AtomicBool,
     /// Record size for new files, in bytes, log base 2.
-    record_size: AtomicU8
+    record_size: AtomicU8,
+    /// The `sync` property, stored as a `SyncPolicy as u8`
+    sync: AtomicU8,
+    /// Reject file names that aren't valid UTF-8?
+    utf8only: AtomicBool,
+    /// Sync directory modifications before returning?
+    dirsync: AtomicBool,
+    /// How many copies of each data record to write
+    copies: AtomicU8,
+    /// Is the underlying pool imported read-only?
+    readonly: bool,
+    /// How often have access pattern hints been applied?
+    fadvise: Arc<FadviseCounters>,
+    /// Counts of reads and writes, for [`Fs::io_stats`]
+    io: IoCounters,
+    /// Targets of recently used symlinks, so `readlink` can usually skip the
+    /// tree lookup.
+    links: Mutex<LinkCache>,
+    /// The newest transaction group that modified each file's data, for files
+    /// that may have unsynced data.  Lets `fdatasync` skip the sync when the
+    /// data is already on disk.
+    dirty_data: Mutex<HashMap<u64, TxgT>>,
+    /// The `coalesce` property: the longest run of adjacent small writes, in
+    /// bytes, that may be buffered before being written to the tree.
+    coalesce: AtomicU64,
+    /// Buffered writes for each file that haven't been written to the tree
+    /// yet.  Errors writing them to the tree, like `ENOSPC`, are reported by
+    /// whichever operation flushes them.
+    pending: Mutex<HashMap<u64, PendingWrite>>,
+    /// Handles that clients currently have open, by handle number.
+    handles: Mutex<BTreeMap<u64, Handle>>,
+    /// The next handle number to allocate.  Handle numbers are never reused,
+    /// so a client can't accidentally use a handle that was force-closed.
+    next_fh: AtomicU64,
+    /// Serializes operations that modify the same inode.
+    inode_locks: InodeLocks,
 }
 
 bitfield! {
     /// File mode, including permissions and file type
-    #[derive(Clone, Copy, Eq, PartialEq, PartialOrd, Ord)]
+    #[derive(Clone, Copy, Deserialize, Eq, PartialEq, PartialOrd, Ord,
+             Serialize)]
     pub struct Mode(u16);
     impl Debug;
     pub perm, _: 11, 0;
@@ -432,7 +915,7 @@ impl Mode {
 }
 
 /// File attributes, as returned by `getattr`
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct GetAttr {
     pub ino:        u64,
     /// File size in bytes
@@ -461,10 +944,20 @@ pub struct GetAttr {
     pub blksize:    u32,
     /// File flags
     pub flags:      u64,
+    /// Change counter.  Incremented whenever the file's data or metadata
+    /// change.
+    pub change:     u64,
+}
+
+impl GetAttr {
+    /// Is this file an overlay whiteout?  See [`WHITEOUT_RDEV`].
+    pub fn is_whiteout(&self) -> bool {
+        self.mode.file_type() == libc::S_IFCHR && self.rdev == WHITEOUT_RDEV
+    }
 }
 
 /// File attributes, as set by `setattr`
-#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
 pub struct SetAttr {
     /// File size in bytes
     pub size:       Option<u64>,
@@ -502,6 +995,8 @@ struct CreateArgs<'a>
     uid: u32,
     gid: u32,
     nlink: u64,
+    /// Extended attributes to create along with the file
+    extattrs: Vec<ExtAttr>,
     cb: CreateCallback,
     /// Credit needed by [`cb`], in multiples of the dataset's insert,
     /// range_delete, remove CreditRequirements.
@@ -523,6 +1018,19 @@ impl<'a> CreateArgs<'a> {
         self
     }
 
+    /// Create an inline extended attribute along with the file
+    pub fn extattr(mut self, ns: ExtAttrNamespace, name: &OsStr, data: &[u8])
+        -> Self
+    {
+        let buf = Arc::new(DivBufShared::from(data));
+        self.extattrs.push(ExtAttr::Inline(InlineExtAttr {
+            namespace: ns,
+            name: name.to_owned(),
+            extent: InlineExtent::new(buf)
+        }));
+        self
+    }
+
     // Enable once chflags(2) support comes in
     //pub fn flags(mut self, flags: u64) -> Self {
         //self.flags = flags;
@@ -542,6 +1050,7 @@ impl<'a> CreateArgs<'a> {
             uid,
             gid,
             nlink: 1,
+            extattrs: Vec::new(),
             cb,
             cb_credit: (0, 0, 0),
         }
@@ -554,7 +1063,7 @@ impl<'a> CreateArgs<'a> {
 }
 
 /// For use with [`Fs::lseek`]
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub enum SeekWhence {
     /// Find the next hole
     Hole = libc::SEEK_HOLE as isize,
@@ -569,8 +1078,10 @@ impl Fs {
             -> std::result::Result<(), i32>
     {
         let ino = fd.ino;
+        self.flush_pending(ino).await?;
+        let _guard = self.inode_locks.lock(ino).await;
         let inode_key = FSKey::new(ino, ObjKey::Inode);
-        self.db.fswrite(self.tree, 3, 1, 2, 0,
+        let txg = self.db.fswrite_reclaim(self.tree, 3, 1, 2, 0,
         move |dataset| async move {
             let ds = Arc::new(dataset);
             let mut inode_value = ds.get(inode_key).await?.unwrap();
@@ -585,14 +1096,18 @@ impl Fs {
                 let now = Timespec::now();
                 inode.bytes = inode.bytes.saturating_sub(freed);
                 inode.mtime = now;
-                inode.ctime = now;
-                ds.insert(inode_key, inode_value).await
-                .map(drop)
+                inode.changed(now);
+                ds.insert(inode_key, inode_value).await?;
+                Ok(Some(ds.txg()))
             } else {
-                Ok(())
+                Ok(None)
             }
         }).map_err(Error::into)
-        .await
+        .await?;
+        if let Some(txg) = txg {
+            self.mark_data_dirty(ino, txg);
+        }
+        Ok(())
     }
 
     /// Delete an extended attribute
@@ -600,10 +1115,15 @@ impl Fs {
                          name: &OsStr)
         -> std::result::Result<(), i32>
     {
+        if ns == ExtAttrNamespace::System && name == CHANGE_XATTR {
+            return Err(libc::EPERM);
+        }
         let objkey = ObjKey::extattr(ns, name);
         let name = name.to_owned();
         let key = FSKey::new(fd.ino, objkey);
-        self.db.fswrite(self.tree, 1, 0, 1, 0, move |dataset| {
+        // Removing from a hash bucket is a read-modify-write
+        let _guard = self.inode_locks.lock(fd.ino).await;
+        self.db.fswrite_reclaim(self.tree, 1, 0, 1, 0, move |dataset| {
             let ads = Arc::new(dataset);
             htable::remove::<_, ExtAttr>(ads, key, ns, name)
             .map_ok(drop)
@@ -611,9 +1131,28 @@ impl Fs {
         .await
     }
 
-    fn do_create<'a>(&'a self, args: CreateArgs<'a>) ->
-        impl Future<Output = std::result::Result<FileDataMut, i32>>
+    /// Check that `name` is acceptable as a new directory entry.
+    fn check_name(&self, name: &OsStr) -> std::result::Result<(), i32> {
+        if name.len() > NAME_MAX {
+            Err(libc::ENAMETOOLONG)
+        } else if self.utf8only.load(Ordering::Relaxed) &&
+            std::str::from_utf8(name.as_bytes()).is_err()
+        {
+            Err(libc::EILSEQ)
+        } else {
+            Ok(())
+        }
+    }
+
+    async fn do_create(&self, args: CreateArgs<'_>)
+        -> std::result::Result<FileDataMut, i32>
     {
+        self.check_name(&args.name)?;
+        if let FileType::Link(ref target) = args.file_type {
+            if target.len() > PATH_MAX {
+                return Err(libc::ENAMETOOLONG);
+            }
+        }
         let ino = self.next_object();
         let inode_key = FSKey::new(ino, ObjKey::Inode);
         let parent_dirent_objkey = ObjKey::dir_entry(&args.name);
@@ -623,9 +1162,11 @@ impl Fs {
             dtype: args.file_type.dtype(),
             name:   args.name
         };
-        let bb = parent_dirent.allocated_space();
+        let bb = parent_dirent.allocated_space() +
+            args.extattrs.iter().map(ExtAttr::allocated_space).sum::<usize>();
         let parent_dirent_key = FSKey::new(args.parent.ino,
                                            parent_dirent_objkey);
+        let extattrs = args.extattrs;
 
         let cb = args.cb;
         let cb_credit = args.cb_credit;
@@ -651,26 +1192,80 @@ impl Fs {
             uid: args.uid,
             gid: args.gid,
             perm: args.perm,
-            file_type: args.file_type
+            file_type: args.file_type,
+            change: 0
         };
         let inode_value = FSValue::inode(inode);
 
-        let ninsert = 5 + cb_credit.0;
-        self.db.fswrite(self.tree, ninsert, cb_credit.1, cb_credit.2, bb,
-        move |dataset| async move {
+        let ninsert = 5 + cb_credit.0 + 2 * extattrs.len();
+        // The callback updates the parent's inode.  The new inode is
+        // invisible to other operations until the transaction completes.
+        let guard = self.inode_locks.lock(parent_ino).await;
+        let r = self.db.fswrite(self.tree, ninsert, cb_credit.1, cb_credit.2,
+                                bb, move |dataset| async move {
             let ds = Arc::new(dataset);
             let extra_fut = cb(&ds, parent_ino, ino);
+            let xattr_fut = extattrs.into_iter()
+                .map(|extattr| {
+                    let name = extattr.name().to_owned();
+                    let objkey = ObjKey::extattr(extattr.namespace(), &name);
+                    let key = FSKey::new(ino, objkey);
+                    htable::insert(ds.clone(), key, extattr, name)
+                }).collect::<FuturesUnordered<_>>()
+                .try_collect::<Vec<_>>();
             let inode_fut = ds.insert(inode_key, inode_value);
-            let dirent_fut = htable::insert(ds, parent_dirent_key,
+            let dirent_fut = htable::insert(ds.clone(), parent_dirent_key,
                                             parent_dirent, name2);
-            let (inode_r, dirent_r, _) = future::try_join3(inode_fut,
-                dirent_fut, extra_fut).await?;
+            let (inode_r, dirent_r, _, _) = future::try_join4(inode_fut,
+                dirent_fut, extra_fut, xattr_fut).await?;
             assert!(dirent_r.is_none(),
             "Create of an existing file.  The VFS should prevent this");
             assert!(inode_r.is_none(),
             "Inode double-create detected, ino={ino}");
             Ok(FileDataMut::new(fd_parent, ino))
-        }).map_err(Error::into)
+        }).await?;
+        drop(guard);
+        self.dirsync().await;
+        Ok(r)
+    }
+
+    /// Deallocate most of a regular file's whole records at or beyond offset
+    /// `floor`, working backwards from the end of the file in batches of
+    /// [`DEALLOC_BATCH`] records, each in its own transaction.
+    ///
+    /// The Inode's size is unchanged, but its byte count is reduced.  Up to one
+    /// more batch's worth of records may remain beyond `floor`, for the caller
+    /// to remove atomically with whatever else it does.
+    async fn dealloc_tail(&self, ino: u64, floor: u64) -> Result<()> {
+        let mut cursor = None;
+        loop {
+            cursor = self.db.fswrite_reclaim(self.tree, 1, 1, 0, 0,
+            move |dataset| async move {
+                let ds = Arc::new(dataset);
+                let inode_key = FSKey::new(ino, ObjKey::Inode);
+                let mut inode_value = ds.get(inode_key).await?.unwrap();
+                let inode = inode_value.as_mut_inode().unwrap();
+                let rs = match inode.record_size() {
+                    Some(rs) => rs as u64,
+                    // Only regular files have extents
+                    None => return Ok(None)
+                };
+                let span = DEALLOC_BATCH * rs;
+                let end = cursor.unwrap_or(div_roundup(inode.size, rs) * rs);
+                if end <= div_roundup(floor, rs) * rs + span {
+                    return Ok(None);
+                }
+                let start = end - span;
+                let freed = Fs::do_deallocate(ds.clone(), ino, start,
+                    Some(span), rs).await?;
+                inode.bytes = inode.bytes.saturating_sub(freed);
+                ds.insert(inode_key, inode_value).await?;
+                Ok(Some(start))
+            }).await?;
+            if cursor.is_none() {
+                return Ok(());
+            }
+        }
     }
 
     // Actually delete an inode, which must already be unlinked
@@ -696,11 +1291,18 @@ impl Fs {
     }
 
     /// Asynchronously read from a file.
+    ///
+    /// With `Advice::NoReuse`, records won't be cached.  With
+    /// `Advice::Random`, records that are only partly covered by the read won't
+    /// be cached either, and will be only partly read from disk if possible.
     fn do_read<DS>(dataset: DS, ino: u64, fsize: u64, rs: u64, offset: u64,
-                   size: usize)
+                   size: usize, advice: Advice)
         -> impl Future<Output=Result<SGList>>
         where DS: ReadDataset<FSKey, FSValue>
     {
+        let noreuse = advice == Advice::NoReuse;
+        let partial = matches!(advice, Advice::Random | Advice::NoReuse);
+
         // Populate a hole region in an sglist.
         let fill_hole = |sglist: &mut SGList, p: &mut u64, l: usize| {
             let l = cmp::min(l, ZERO_REGION_LEN);
@@ -725,8 +1327,28 @@ impl Fs {
                     future::ok((ofs, buf)).boxed()
                 },
                 Extent::Blob(be) => {
-                    dataset.get_blob(be.rid)
-                    .map_ok(move |bbuf| (ofs, *bbuf))
+                    if let Some(ditto) = v.ditto() {
+                        // Always read the whole record, so the ditto copy
+                        // can be used if the original is damaged.
+                        return dataset.get_blob_ditto(be.rid, ditto)
+                            .map_ok(move |bbuf| (ofs, *bbuf))
+                            .boxed();
+                    }
+                    // The part of the record that's needed
+                    let s = offset.saturating_sub(ofs) as usize;
+                    let e = cmp::min(u64::from(be.lsize),
+                                     offset + size64 - ofs) as usize;
+                    if partial && s < e && (s > 0 || e < be.lsize as usize) {
+                        return dataset.get_blob_range(be.rid, s, e - s)
+                            .map_ok(move |db| (ofs + s as u64, db))
+                            .boxed();
+                    }
+                    let bfut = if noreuse {
+                        dataset.get_blob_uncached(be.rid)
+                    } else {
+                        dataset.get_blob(be.rid)
+                    };
+                    bfut.map_ok(move |bbuf| (ofs, *bbuf))
                     .boxed()
                 }
             }
@@ -790,7 +1412,7 @@ impl Fs {
                     let inode = value.as_mut_inode().unwrap();
                     let now = Timespec::now();
                     inode.mtime = now;
-                    inode.ctime = now;
+                    inode.changed(now);
                     inode.nlink -= 1;
                 }
                 dataset.insert(parent_ino_key, value)
@@ -820,7 +1442,9 @@ impl Fs {
         let new_size = attr.size.unwrap_or(iv.size);
         iv.size = new_size;
         iv.atime = attr.atime.unwrap_or(iv.atime);
-        iv.ctime = attr.ctime.unwrap_or(iv.ctime);
+        if let Some(ctime) = attr.ctime {
+            iv.changed(ctime);
+        }
         iv.mtime = attr.mtime.unwrap_or_else(|| {
             if attr.size.is_some() {
                 // Always update mtime when truncating
@@ -884,25 +1508,25 @@ impl Fs {
                     future::ok(0)
                 }.boxed(),
                 Some(FSValue::InlineExtent(ile)) => async move {
-                    let mut b = ile.buf.try_mut().unwrap();
-                    let r = if recofs > b.len() as u64 {
-                        // Nothing to do.  The truncation happens in a part of
-                        // the record that is already sparse.
-                        0
-                    } else if len >= b.len() - recofs as usize {
-                        // truncate the record, making it sparse
-                        let old_len = b.len();
-                        b.try_truncate(recofs as usize).unwrap();
-                        old_len as u64 - recofs
-                    } else {
-                        // zero the deallocated portion of the record
-                        for i in 0..len {
-                            b[recofs as usize + i] = 0;
+                    let old_len = ile.stat_space();
+                    {
+                        let mut b = ile.buf.try_mut().unwrap();
+                        if recofs > b.len() as u64 {
+                            // Nothing to do.  The truncation happens in a part
+                            // of the record that is already sparse.
+                        } else if len >= b.len() - recofs as usize {
+                            // truncate the record, making it sparse
+                            b.try_truncate(recofs as usize).unwrap();
+                        } else {
+                            // zero the deallocated portion of the record
+                            for i in 0..len {
+                                b[recofs as usize + i] = 0;
+                            }
                         }
-                        0
-                    };
-                    let extent = InlineExtent::new(ile.buf);
-                    let v = FSValue::InlineExtent(extent);
+                    }
+                    // Any ditto copy shrinks along with the record
+                    let r = (old_len - ile.stat_space()) as u64;
+                    let v = FSValue::InlineExtent(ile);
                     dataset.insert(k, v).await?;
                     Ok(r)
                 }.boxed(),
@@ -917,14 +1541,8 @@ impl Fs {
         // Deallocate all whole records in the range
         let full_fut = dataset2.range(full_range)
        .try_fold(0u64, |mut s, (_k, v)| {
-            match v.as_extent().unwrap() {
-                Extent::Blob(be) => {
-                    s += u64::from(be.lsize);
-                },
-                Extent::Inline(ile) => {
-                    s += ile.len() as u64;
-                }
-            }
+            // Count ditto copies, too
+            s += v.stat_space() as u64;
             future::ok(s)
         }).and_then(move |old_len| {
             dataset3.range_delete(full_range2)
@@ -956,13 +1574,13 @@ impl Fs {
                             for i in 0..len {
                                 b[i as usize] = 0;
                             }
-                            let extent = InlineExtent::new(ile.buf);
-                            let v = FSValue::InlineExtent(extent);
+                            drop(b);
+                            let v = FSValue::InlineExtent(ile);
                             dataset4.insert(k, v).await?;
                             Ok(0)
                         } else {
                             // Nothing to do!  Eliminating the whole extent
-                            Ok(b.len() as u64)
+                            Ok(ile.stat_space() as u64)
                         }
                     }.boxed(),
                     // Some(FSValue::BlobExtent(be)) should never happen,
@@ -1000,7 +1618,7 @@ impl Fs {
             // 2a) Decrement the link count and touch the ctime
             iv.nlink = iv.nlink.saturating_sub(1);
             let nlink = iv.nlink;
-            iv.ctime = Timespec::now();
+            iv.changed(Timespec::now());
             // 2b) Update Inode, if we aren't immediately deleting it
             if nlink > 0 || active {
                 let fut = if nlink == 0 {
@@ -1045,60 +1663,172 @@ impl Fs {
     {
         let db3 = database.clone();
         let db4 = database.clone();
-        let (last_key, (atimep, _), (recsizep, _), _) =
+        let readonly = database.is_readonly();
+        let (last_key, (atimep, _), (recsizep, _),
+             ((syncp, _), (utf8p, _), (dirtyp, _), (coalescep, _),
+              (dirsyncp, _)), ((copiesp, _), dying)) =
         db4.fsread(tree_id, move |dataset| {
             let last_key_fut = dataset.last_key();
             let atime_fut = Fs::get_prop_unmounted(tree_id, db3.clone(),
                                                    PropertyName::Atime);
             let recsize_fut = Fs::get_prop_unmounted(tree_id, db3.clone(),
                                                      PropertyName::RecordSize);
-            let di_fut = db3.fswrite(tree_id, 0, 1, 0, 0,
-            move |dataset| async move {
-                // Delete all dying inodes.  If there are any, it means that
-                // the previous mount was uncleanly dismounted.
-                // TODO: don't do this when mounting read-only.
-                let ds = Arc::new(dataset);
-                let ds2 = ds.clone();
-                let had_dying_inodes = ds.range(FSKey::dying_inode_range())
-                .try_fold(false, move |_acc, (_k, v)| {
-                    let ds3 = ds.clone();
-                    async move {
-                        let ino = v.as_dying_inode().unwrap().ino();
-                        Fs::do_delete_inode(ds3, ino).await?;
-                        Ok(true)
-                    }
-                }).await?;
-                // Finally, range delete all of the dying inodes, if any
-                if had_dying_inodes {
-                    ds2.range_delete(FSKey::dying_inode_range())
-                        .await?;
-                }
-                Ok(())
-            }).boxed();
-            future::try_join4(last_key_fut, atime_fut, recsize_fut, di_fut)
+            let sync_fut = Fs::get_prop_unmounted(tree_id, db3.clone(),
+                                                  PropertyName::Sync);
+            let utf8_fut = Fs::get_prop_unmounted(tree_id, db3.clone(),
+                                                  PropertyName::Utf8Only);
+            let dirty_fut = Fs::get_prop_unmounted(tree_id, db3.clone(),
+                                                   PropertyName::DirtyLimit);
+            let coalesce_fut = Fs::get_prop_unmounted(tree_id, db3.clone(),
+                                                      PropertyName::Coalesce);
+            let dirsync_fut = Fs::get_prop_unmounted(tree_id, db3.clone(),
+                                                     PropertyName::DirSync);
+            let copies_fut = Fs::get_prop_unmounted(tree_id, db3.clone(),
+                                                    PropertyName::Copies);
+            let di_fut = if readonly {
+                // Any dying inodes will have to wait for a read-write mount.
+                future::ok(Vec::new()).boxed()
+            } else {
+                dataset.range(FSKey::dying_inode_range())
+                .map_ok(|(_k, v)| v.as_dying_inode().unwrap().ino())
+                .try_collect::<Vec<_>>()
+                .boxed()
+            };
+            future::try_join5(last_key_fut, atime_fut, recsize_fut,
+                              future::try_join5(sync_fut, utf8_fut, dirty_fut,
+                                                coalesce_fut, dirsync_fut),
+                              future::try_join(copies_fut, di_fut))
         }).map_err(Error::unhandled)
         .await.unwrap();
         let next_object = AtomicU64::new(last_key.unwrap().object() + 1);
-        let atime = AtomicBool::from(atimep.as_bool());
+        // Never update atimes on a read-only pool
+        let atime = AtomicBool::from(atimep.as_bool() && !readonly);
         let record_size = AtomicU8::from(recsizep.as_u8());
-
-        Fs {
+        let sync = AtomicU8::from(syncp.as_sync_policy() as u8);
+        let utf8only = AtomicBool::from(utf8p.as_bool());
+        let dirsync = AtomicBool::from(dirsyncp.as_bool());
+        let copies = AtomicU8::from(copiesp.as_u8());
+        database.set_dirty_limit(tree_id, dirtyp.as_u64());
+        let coalesce = AtomicU64::new(coalescep.as_u64());
+
+        let fs = Fs {
             db: database,
             next_object,
             tree: tree_id,
             atime,
             record_size,
+            sync,
+            utf8only,
+            dirsync,
+            copies,
+            readonly,
+            fadvise: Default::default(),
+            io: Default::default(),
+            links: Default::default(),
+            dirty_data: Default::default(),
+            coalesce,
+            pending: Default::default(),
+            handles: Default::default(),
+            next_fh: AtomicU64::new(1),
+            inode_locks: Default::default(),
+        };
+        // Delete all dying inodes.  If there are any, it means that the
+        // previous mount was uncleanly dismounted.  Free each one in batches,
+        // like Fs::inactive does, so even a huge one can be freed on a full
+        // pool.
+        for ino in dying {
+            fs.dealloc_tail(ino, 0).await
+                .expect("Failed to free a dying inode");
+            fs.db.fswrite_reclaim(tree_id, 0, 1, 1, 0, move |dataset| {
+                Fs::do_inactive(Arc::new(dataset), ino)
+            }).await
+            .expect("Failed to free a dying inode");
+        }
+        fs
+    }
+
+    /// Record that `ino`'s data was modified in transaction group `txg`.
+    fn mark_data_dirty(&self, ino: u64, txg: TxgT) {
+        let mut guard = self.dirty_data.lock().unwrap();
+        if guard.len() >= DIRTY_DATA_PRUNE {
+            let synced = self.db.synced_txg();
+            guard.retain(|_, t| Some(*t) > synced);
+        }
+        let t = guard.entry(ino).or_insert(txg);
+        *t = cmp::max(*t, txg);
+    }
+
+    /// Try to buffer a small write so it can be written to the tree along with
+    /// adjacent ones.  Returns `false` if it must be written directly instead.
+    fn coalesce_write(&self, ino: u64, offset: u64, uio: &Uio) -> bool {
+        let window = self.coalesce.load(Ordering::Relaxed);
+        let len = uio.len() as u64;
+        if self.readonly || len == 0 || len >= window ||
+            self.sync_policy() == SyncPolicy::Always
+        {
+            return false;
+        }
+        let synced = self.db.synced_txg();
+        let mut guard = self.pending.lock().unwrap();
+        match guard.get_mut(&ino) {
+            None => {
+                let buf = Vec::from(uio.data);
+                guard.insert(ino, PendingWrite{offset, synced, buf});
+                true
+            },
+            Some(pw) if pw.synced == synced && pw.end() == offset &&
+                pw.buf.len() as u64 + len <= window =>
+            {
+                pw.buf.extend_from_slice(uio.data);
+                true
+            },
+            Some(_) => false
         }
     }
 
+    /// Write any buffered writes for file `ino` to the tree.
+    async fn flush_pending(&self, ino: u64) -> Result<()> {
+        let pending = self.pending.lock().unwrap().remove(&ino);
+        if let Some(pw) = pending {
+            self.do_write(ino, pw.offset, Uio::from(&pw.buf[..])).await?;
+        }
+        Ok(())
+    }
+
+    /// Write every file's buffered writes to the tree.
+    async fn flush_all_pending(&self) -> Result<()> {
+        let pending = mem::take(&mut *self.pending.lock().unwrap());
+        pending.into_iter()
+            .map(|(ino, pw)| async move {
+                self.do_write(ino, pw.offset, Uio::from(&pw.buf[..])).await
+            }).collect::<FuturesUnordered<_>>()
+            .try_for_each(|_| future::ok(()))
+            .await
+    }
+
     fn next_object(&self) -> u64 {
         self.next_object.fetch_add(1, Ordering::Relaxed)
     }
 
+    /// Check that a handle returned by [`Fs::open`] is still open.
+    ///
+    /// Handle 0 is used by clients that don't open files, and is always valid.
+    /// A handle closed by [`Fs::force_close`] is invalid.
+    pub fn check_handle(&self, fh: u64) -> std::result::Result<(), i32> {
+        if fh == 0 || self.handles.lock().unwrap().contains_key(&fh) {
+            Ok(())
+        } else {
+            Err(libc::EBADF)
+        }
+    }
+
     pub async fn create(&self, parent: &FileData, name: &OsStr, perm: u16, uid: u32,
                   gid: u32) -> std::result::Result<FileDataMut, i32>
     {
-        let recsize = self.record_size.load(Ordering::Relaxed);
+        let recsize = match self.dir_recordsize(parent).await? {
+            Some(exp) => exp,
+            None => self.record_size.load(Ordering::Relaxed)
+        };
         let create_args = CreateArgs::new(parent, name, perm, uid, gid,
                                           FileType::Reg(recsize));
         self.do_create(create_args).await
@@ -1126,13 +1856,91 @@ impl Fs {
         .await
     }
 
+    /// Apply an access pattern hint to a file.
+    ///
+    /// The caller should already have stored `advice` in the file's
+    /// `FileDataMut`, so that subsequent reads will honor it.  This method
+    /// takes any immediate action that the advice requires.
+    pub async fn fadvise(&self, fd: &FileData, advice: Advice)
+        -> std::result::Result<(), i32>
+    {
+        match advice {
+            Advice::Sequential => {
+                self.fadvise.sequential.fetch_add(1, Ordering::Relaxed);
+            },
+            Advice::NoReuse => {
+                self.fadvise.noreuse.fetch_add(1, Ordering::Relaxed);
+            },
+            Advice::DontNeed => {
+                self.fadvise.dontneed.fetch_add(1, Ordering::Relaxed);
+                let ino = fd.ino;
+                let n = self.db.fsread(self.tree, move |ds| {
+                    ds.range(FSKey::extent_range(ino, ..))
+                    .try_fold(0, move |n, (_k, v)| {
+                        if let Extent::Blob(be) = v.as_extent().unwrap() {
+                            ds.evict_blob(be.rid);
+                            future::ok(n + 1)
+                        } else {
+                            future::ok(n)
+                        }
+                    })
+                }).map_err(Error::into)
+                .await?;
+                self.fadvise.evicted.fetch_add(n, Ordering::Relaxed);
+            },
+            Advice::Normal | Advice::Random | Advice::WillNeed => ()
+        }
+        Ok(())
+    }
+
+    /// Report how often access pattern hints have been applied.
+    pub fn fadvise_stats(&self) -> FadviseStats {
+        self.fadvise.stats()
+    }
+
+    /// Report the file system's read and write counters.
+    ///
+    /// If `reset`, then also zero them, so the next call will report only
+    /// what happened in between.
+    pub fn io_stats(&self, reset: bool) -> IoStats {
+        let stats = self.io.stats();
+        if reset {
+            self.io.reset();
+        }
+        stats
+    }
+
     /// Tell the file system that the given file is no longer needed by the
     /// client.  Its resources may be freed.
     // Fs::inactive consumes fd because the client should not longer need it.
     pub async fn inactive(&self, fd: FileDataMut) {
+        if self.readonly {
+            // Nothing can have been unlinked, so there's nothing to reclaim
+            return;
+        }
         let ino = fd.ino();
+        let pending = self.pending.lock().unwrap().remove(&ino);
+
+        // If the inode is dying, free most of its records in separate
+        // transactions first.
+        let dikey = FSKey::new(0, ObjKey::dying_inode(ino));
+        let dying = self.db.fsread(self.tree, move |dataset| async move {
+            dataset.get(dikey).await.map(|r| r.is_some())
+        }).await
+        .expect("Fs::inactive should never fail");
+        if let (false, Some(pw)) = (dying, pending) {
+            // There's nobody left to report an error to.
+            let uio = Uio::from(&pw.buf[..]);
+            if let Err(e) = self.do_write(ino, pw.offset, uio).await {
+                tracing::error!(ino, "Lost buffered writes: {e:?}");
+            }
+        }
+        if dying {
+            self.dealloc_tail(ino, 0).await
+                .expect("Fs::inactive should never fail");
+        }
 
-        self.db.fswrite(self.tree, 0, 1, 1, 0, move |dataset| {
+        self.db.fswrite_reclaim(self.tree, 0, 1, 1, 0, move |dataset| {
             Fs::do_inactive(Arc::new(dataset), ino)
             .map(|r| r.map(drop))
         }).await
@@ -1141,13 +1949,59 @@ impl Fs {
 
     /// Sync a file's data and metadata to disk so it can be recovered after a
     /// crash.
-    pub async fn fsync(&self, _fd: &FileData) -> std::result::Result<(), i32> {
+    pub async fn fsync(&self, fd: &FileData) -> std::result::Result<(), i32> {
+        self.flush_pending(fd.ino).await?;
+        if self.sync_policy() == SyncPolicy::Disabled {
+            return Ok(());
+        }
         // Until we come up with a better mechanism, we must sync the entire
         // file system.
-        self.sync().await;
+        self.sync_and_prune().await;
         Ok(())
     }
 
+    /// Sync a file's data to disk, along with just enough metadata to read it
+    /// back, like `fdatasync(2)`.
+    ///
+    /// Unlike [`Fs::fsync`], this does nothing if the file's data hasn't
+    /// changed since the last transaction group was synced, even if its other
+    /// metadata, like its timestamps or permissions, has.
+    pub async fn fdatasync(&self, fd: &FileData)
+        -> std::result::Result<(), i32>
+    {
+        self.flush_pending(fd.ino).await?;
+        if self.sync_policy() == SyncPolicy::Disabled {
+            return Ok(());
+        }
+        let txg = self.dirty_data.lock().unwrap().get(&fd.ino).cloned();
+        match txg {
+            Some(txg) if self.db.synced_txg() < Some(txg) => {
+                self.sync_and_prune().await;
+            },
+            _ => ()
+        }
+        Ok(())
+    }
+
+    /// Forcibly close a client's file handle, such as one leaked by a hung
+    /// process that prevents the file system from being unmounted.
+    ///
+    /// The file's buffered writes are flushed.  Any further operations on the
+    /// handle will fail with `EBADF`, but the client's own resources, like
+    /// the kernel's vnode, are not released until the client closes it.
+    pub async fn force_close(&self, fh: u64) -> Result<()> {
+        let handle = self.handles.lock().unwrap().remove(&fh)
+            .ok_or(Error::EBADF)?;
+        self.flush_pending(handle.ino).await
+    }
+
+    /// Write out any buffered writes, then freeze the file system.  See
+    /// [`Database::freeze_fs`].
+    pub async fn freeze(&self) -> Result<()> {
+        self.flush_all_pending().await?;
+        self.db.freeze_fs(self.tree).await
+    }
+
     /// Get the current value of the property `propname`
     pub fn get_prop(&self, propname: PropertyName)
         -> impl Future<Output = Result<(Property, PropertySource)>> + Send
@@ -1176,7 +2030,11 @@ impl Fs {
                     prop = p;
                     break;
                 }
-                let oparent = db.lookup_parent(tree_id).await?;
+                let oparent = if propname.inherited() {
+                    db.lookup_parent(tree_id).await?
+                } else {
+                    None
+                };
                 if let Some(parent) = oparent {
                     source_levels = Some(source_levels.unwrap() + 1);
                     tree_id = parent;
@@ -1193,6 +2051,22 @@ impl Fs {
         }
     }
 
+    /// Get the current value of one of the mount history properties.  Unlike
+    /// configurable properties, they're never inherited.
+    async fn get_prop_history(
+        tree_id: TreeID,
+        db: Arc<Database>,
+        propname: PropertyName)
+        -> Result<(Property, PropertySource)>
+    {
+        let key = FSKey::new(PROPERTY_OBJECT, ObjKey::Property(propname));
+        let prop = db.fsread(tree_id, move |dataset| dataset.get(key))
+            .await?
+            .and_then(FSValue::into_property)
+            .unwrap_or_else(|| Property::default_value(propname));
+        Ok((prop, PropertySource::None))
+    }
+
     /// Get the current value of a property on a file system that is not
     /// currently mounted
     pub(crate) fn get_prop_unmounted(
@@ -1201,11 +2075,70 @@ impl Fs {
         propname: PropertyName)
         -> impl Future<Output = Result<(Property, PropertySource)>> + Send + 'static
     {
+        if propname.mount_history() {
+            return Fs::get_prop_history(tree_id, db, propname).boxed();
+        }
         // TODO: handle properties that have been overridden temporarily
         Fs::get_prop_configurable(tree_id, db, propname).boxed()
     }
 
+    /// Get every user property that applies to a file system, whether set
+    /// locally or inherited, sorted by name.
+    pub(crate) async fn get_user_props_unmounted(
+        mut tree_id: TreeID,
+        db: Arc<Database>)
+        -> Result<Vec<(UserProperty, PropertySource)>>
+    {
+        let mut props = BTreeMap::new();
+        let mut level = 0;
+        loop {
+            let local = db.fsread(tree_id, Fs::read_user_props).await?;
+            for prop in local.into_iter() {
+                props.entry(prop.name.clone())
+                    .or_insert((prop, PropertySource::Set(level)));
+            }
+            match db.lookup_parent(tree_id).await? {
+                Some(parent) => {
+                    tree_id = parent;
+                    level += 1;
+                }
+                None => break
+            }
+        }
+        Ok(props.into_values().collect())
+    }
+
+    /// Read the user properties set locally on a single dataset
+    async fn read_user_props(dataset: ReadOnlyFilesystem)
+        -> Result<Vec<UserProperty>>
+    {
+        let xattrs = dataset.range(FSKey::extattr_range(PROPERTY_OBJECT))
+        .try_fold(Vec::new(), |mut xattrs, (k, v)| {
+            match v {
+                FSValue::ExtAttr(xattr) => xattrs.push(xattr),
+                FSValue::ExtAttrs(v) => xattrs.extend(v),
+                _ => panic!("Unexpected value {v:?} for key {k:?}")
+            }
+            future::ok::<_, Error>(xattrs)
+        }).await?;
+        let mut props = Vec::with_capacity(xattrs.len());
+        for xattr in xattrs.into_iter() {
+            if xattr.namespace() != ExtAttrNamespace::User {
+                continue;
+            }
+            let name = xattr.name().to_string_lossy().into_owned();
+            let buf = match xattr {
+                ExtAttr::Inline(iea) => iea.extent.buf.try_const().unwrap(),
+                ExtAttr::Blob(bea) => *dataset.get_blob(bea.extent.rid).await?
+            };
+            let value = String::from_utf8_lossy(&buf[..]).into_owned();
+            props.push(UserProperty{name, value});
+        }
+        Ok(props)
+    }
+
     pub async fn getattr(&self, fd: &FileData) -> std::result::Result<GetAttr, i32> {
+        self.flush_pending(fd.ino).await?;
         self.getattr_priv(fd.ino).map_err(Error::into).await
     }
 
@@ -1249,6 +2182,7 @@ impl Fs {
                         rdev,
                         blksize,
                         flags: inode.flags,
+                        change: inode.change,
                     };
                     Ok(attr)
                 },
@@ -1266,6 +2200,11 @@ impl Fs {
     pub async fn getextattr(&self, fd: &FileData, ns: ExtAttrNamespace, name: &OsStr)
         -> std::result::Result<DivBuf, i32>
     {
+        if ns == ExtAttrNamespace::System && name == CHANGE_XATTR {
+            let change = self.getattr(fd).await?.change;
+            let dbs = DivBufShared::from(change.to_string().into_bytes());
+            return Ok(dbs.try_const().unwrap());
+        }
         let owned_name = name.to_owned();
         let objkey = ObjKey::extattr(ns, name);
         let key = FSKey::new(fd.ino, objkey);
@@ -1297,6 +2236,10 @@ impl Fs {
                          name: &OsStr)
         -> std::result::Result<u32, i32>
     {
+        if ns == ExtAttrNamespace::System && name == CHANGE_XATTR {
+            let change = self.getattr(fd).await?.change;
+            return Ok(change.to_string().len() as u32);
+        }
         let owned_name = name.to_owned();
         let objkey = ObjKey::extattr(ns, name);
         let key = FSKey::new(fd.ino, objkey);
@@ -1357,6 +2300,7 @@ impl Fs {
     /// instead.
     #[cfg(debug_assertions)]
     pub async fn igetattr(&self, ino: u64) -> std::result::Result<GetAttr, i32> {
+        self.flush_pending(ino).await?;
         self.getattr_priv(ino).map_err(Error::into).await
     }
 
@@ -1401,6 +2345,31 @@ impl Fs {
         .await
     }
 
+    /// Get a directory's record size override, log base 2.  See
+    /// [`RECORDSIZE_XATTR`].
+    pub async fn dir_recordsize(&self, fd: &FileData)
+        -> std::result::Result<Option<u8>, i32>
+    {
+        let name = OsStr::new(RECORDSIZE_XATTR);
+        match self.getextattr(fd, ExtAttrNamespace::System, name).await {
+            Ok(buf) => Ok(parse_recordsize_xattr(&buf[..])),
+            Err(libc::ENOATTR) => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Is this directory opaque?  See [`OPAQUE_XATTR`].
+    pub async fn is_opaque(&self, fd: &FileData)
+        -> std::result::Result<bool, i32>
+    {
+        let name = OsStr::new(OPAQUE_XATTR);
+        match self.getextattr(fd, ExtAttrNamespace::System, name).await {
+            Ok(buf) => Ok(&buf[..] == b"y"),
+            Err(libc::ENOATTR) => Ok(false),
+            Err(e) => Err(e)
+        }
+    }
+
     /// Create a hardlink from `fd` to `parent/name`.
     pub async fn link(&self, parent: &FileData, fd: &FileData, name: &OsStr)
         -> std::result::Result<(), i32>
@@ -1409,9 +2378,14 @@ impl Fs {
         // * Increase the target's link count
         // * Add the new directory entry
         // * Update the parent's mtime and ctime
+        self.check_name(name)?;
         let ino = fd.ino;
         let parent_ino = parent.ino;
         let name = name.to_owned();
+        // The client holds a reference to the target, so it can't be deleted
+        // out from under us.  But another operation may be modifying its
+        // inode concurrently, so lock it along with the parent.
+        let guards = self.inode_locks.lock_many(&[parent_ino, ino]).await;
         self.db.fswrite(self.tree, 2, 0, 0, 0, move |dataset| async move {
             let ds = Arc::new(dataset);
             let inode_key = FSKey::new(ino, ObjKey::Inode);
@@ -1419,11 +2393,6 @@ impl Fs {
             let mut iv = r.unwrap().as_mut_inode().unwrap().clone();
             iv.nlink += 1;
             let dtype = iv.file_type.dtype();
-            // FUSE is single-threaded, so we don't have to worry that
-            // the target gets deleted before we increase its link
-            // count.  The real VFS will provide a held vnode rather
-            // than an inode.  So in neither case is there a race here.
-            // XXX TODO: fuse3 is _not_ single-threaded
             let ifut = ds.insert(inode_key, FSValue::inode(iv));
 
             let dirent_objkey = ObjKey::dir_entry(&name);
@@ -1449,8 +2418,10 @@ impl Fs {
 
             future::try_join4(ifut, dfut, parent_fut, ctime_fut).await?;
             Ok(())
-        }).map_err(Error::into)
-        .await
+        }).await?;
+        drop(guards);
+        self.dirsync().await;
+        Ok(())
     }
 
     /// Lookup a file by its file name.
@@ -1462,6 +2433,9 @@ impl Fs {
     pub async fn lookup(&self, grandparent: Option<&FileData>, parent: &FileData,
         name: &OsStr) -> std::result::Result<FileDataMut, i32>
     {
+        if name.len() > NAME_MAX {
+            return Err(libc::ENAMETOOLONG);
+        }
         let dot = name == OsStr::from_bytes(b".");
         let dotdot = name == OsStr::from_bytes(b"..");
         let parent_ino = if dot {
@@ -1579,6 +2553,7 @@ impl Fs {
         whence: SeekWhence) -> std::result::Result<u64, i32>
     {
         let ino = fd.ino;
+        self.flush_pending(ino).await?;
         let inode_key = FSKey::new(ino, ObjKey::Inode);
         self.db.fsread(self.tree, move |ds| async move {
             let r = ds.get(inode_key).await
@@ -1677,7 +2652,7 @@ impl Fs {
                         inode.nlink += 1;
                         let now = Timespec::now();
                         inode.mtime = now;
-                        inode.ctime = now;
+                        inode.changed(now);
                     }
                     dataset2.insert(parent_inode_key, value)
                 });
@@ -1691,10 +2666,16 @@ impl Fs {
             fut.boxed()
         }
 
-        let create_args = CreateArgs::new(parent, name, perm, uid, gid,
-                                          FileType::Dir)
+        let mut create_args = CreateArgs::new(parent, name, perm, uid, gid,
+                                              FileType::Dir)
         .nlink(nlink)
         .callback(f, 3, 0, 0);
+        // New subdirectories inherit their parent's record size override
+        if let Some(exp) = self.dir_recordsize(parent).await? {
+            let value = (1u64 << exp).to_string();
+            create_args = create_args.extattr(ExtAttrNamespace::System,
+                OsStr::new(RECORDSIZE_XATTR), value.as_bytes());
+        }
 
         self.do_create(create_args).await
     }
@@ -1717,6 +2698,13 @@ impl Fs {
         self.do_create(create_args).await
     }
 
+    /// Create an overlay whiteout.  See [`WHITEOUT_RDEV`].
+    pub async fn mkwhiteout(&self, parent: &FileData, name: &OsStr, uid: u32,
+                            gid: u32) -> std::result::Result<FileDataMut, i32>
+    {
+        self.mkchar(parent, name, 0, uid, gid, WHITEOUT_RDEV).await
+    }
+
     pub async fn mkfifo(&self, parent: &FileData, name: &OsStr, perm: u16, uid: u32,
                   gid: u32) -> std::result::Result<FileDataMut, i32>
     {
@@ -1733,13 +2721,142 @@ impl Fs {
         self.do_create(create_args).await
     }
 
+    /// Move a file from this file system to another file system in the same
+    /// pool.
+    ///
+    /// Blob extents and blob extended attributes are shared with the
+    /// destination by reference count, so their data is neither read nor
+    /// rewritten.  Inline extents are small, so they are simply copied.  The
+    /// moved file gets a new inode number, returned on success.
+    ///
+    /// # Arguments
+    ///
+    /// - `parent`:     Source file's parent directory
+    /// - `fd`:         Source file, if it's active, as in [`Fs::unlink`]
+    /// - `name`:       Source file's name within `parent`
+    /// - `dst`:        Destination file system
+    /// - `dst_parent`: Destination parent directory
+    /// - `dst_name`:   Name for the file within `dst_parent`.  It must not
+    ///                 already exist.
+    ///
+    /// Directories cannot be moved; they return `EXDEV`, just like a rename
+    /// across pools would.  The move is not atomic.  If interrupted by a
+    /// crash, the file might be found in both file systems, but never in
+    /// neither.  The caller must ensure that the source file isn't modified
+    /// while it's being moved.
+    pub async fn move_to(&self, parent: &FileData, fd: Option<&FileData>,
+                         name: &OsStr, dst: &Fs, dst_parent: &FileData,
+                         dst_name: &OsStr)
+        -> std::result::Result<u64, i32>
+    {
+        // Outline:
+        // 1) Lookup the source and read all of its records
+        // 2) Add a reference to each of its blobs, and insert its records into
+        //    the destination under a new inode number
+        // 3) Add the destination directory entry
+        // 4) Unlink the source
+        if !Arc::ptr_eq(&self.db, &dst.db) {
+            return Err(libc::EXDEV);
+        }
+        if self.tree == dst.tree {
+            // That's what rename is for
+            return Err(libc::EINVAL);
+        }
+        dst.check_name(dst_name)?;
+        if let Some(fd) = fd {
+            self.flush_pending(fd.ino).await?;
+        }
+
+        // 1) Lookup the source and read all of its records
+        let key = FSKey::new(parent.ino, ObjKey::dir_entry(name));
+        let owned_name = name.to_owned();
+        let src_dirent = self.db.fsread(self.tree, move |ds| {
+            let rfs = htable::ReadFilesystem::ReadOnly(&ds);
+            htable::get::<Dirent>(&rfs, key, 0, owned_name)
+        }).map_err(Error::into)
+        .await?;
+        if src_dirent.dtype == libc::DT_DIR {
+            return Err(libc::EXDEV);
+        }
+        let src_ino = src_dirent.ino;
+        let records = self.db.fsread(self.tree, move |ds| {
+            ds.range(FSKey::obj_range(src_ino))
+            .try_collect::<Vec<_>>()
+        }).map_err(Error::into)
+        .await?;
+
+        // 2) Rewrite the records for the destination
+        let ino = dst.next_object();
+        let now = Timespec::now();
+        let mut rids = Vec::new();
+        let records = records.into_iter()
+            .map(|(k, mut v)| {
+                if let Some(inode) = v.as_mut_inode() {
+                    // Only the moved link comes along
+                    inode.nlink = 1;
+                    inode.changed(now);
+                }
+                rids.extend(v.blob_rids());
+                (k.with_object(ino), v)
+            }).collect::<Vec<_>>();
+        let dirent = Dirent {
+            ino,
+            dtype: src_dirent.dtype,
+            name: dst_name.to_owned()
+        };
+        let bb = dirent.allocated_space() + records.iter()
+            .map(|(_, v)| v.allocated_space())
+            .sum::<usize>();
+        let dst_parent_ino = dst_parent.ino;
+        let dst_name = dst_name.to_owned();
+        let dirent_key = FSKey::new(dst_parent_ino,
+                                    ObjKey::dir_entry(&dst_name));
+        let ninsert = records.len() + 3;
+        dst.db.fswrite(dst.tree, ninsert, 0, 0, bb, move |dataset| async move {
+            let ds = Arc::new(dataset);
+            let r = htable::get::<Dirent>(
+                &htable::ReadFilesystem::ReadWrite(&ds), dirent_key, 0,
+                dst_name.clone()).await;
+            match r {
+                Ok(_) => return Err(Error::EEXIST),
+                Err(Error::ENOENT) => (),
+                Err(e) => return Err(e)
+            }
+            for rid in rids {
+                ds.dup_blob(rid).await?;
+            }
+            let ifut = records.into_iter()
+                .map(|(k, v)| ds.insert(k, v))
+                .collect::<FuturesUnordered<_>>()
+                .try_collect::<Vec<_>>();
+
+            // 3) Add the destination directory entry
+            let dirent_fut = htable::insert(ds.clone(), dirent_key, dirent,
+                                            dst_name);
+            let parent_attr = SetAttr {
+                ctime: Some(now),
+                mtime: Some(now),
+                .. Default::default()
+            };
+            let parent_fut = Fs::do_setattr(ds.clone(), dst_parent_ino,
+                parent_attr);
+            future::try_join3(ifut, dirent_fut, parent_fut).await?;
+            Ok(())
+        }).map_err(Error::into)
+        .await?;
+
+        // 4) Unlink the source
+        self.unlink(parent, fd, name).await?;
+        Ok(ino)
+    }
+
     /// Check that a directory is safe to delete
     fn ok_to_rmdir(ds: &ReadWriteFilesystem, ino: u64, parent: u64,
                    name: OsString)
         -> impl Future<Output=Result<()>> + Send
     {
         ds.range(FSKey::obj_range(ino))
-        .try_fold(false, |found_inode, (_, v)| {
+        .try_fold(false, move |found_inode, (_, v)| {
             match v {
                 FSValue::DirEntry(dirent) => {
                     if dirent.name != OsStr::new(".") &&
@@ -1766,9 +2883,16 @@ impl Fs {
                     // sorted lower than Inodes'
                     assert_eq!(inode.file_type, FileType::Dir,
                                "rmdir of a non-directory");
-                    assert_eq!(inode.nlink, 2,
-                        "Hard links to directories are forbidden.  nlink={}",
-                        inode.nlink);
+                    // An empty directory should have exactly two links: its
+                    // parent's entry and its own ".".  Hard links to
+                    // directories are forbidden, so anything else means that
+                    // the count has drifted.  That's no reason to refuse the
+                    // rmdir.
+                    if inode.nlink != 2 {
+                        tracing::warn!(ino, nlink = inode.nlink,
+                            "Empty directory has the wrong link count.  \
+                            Run \"bfffs check --repair\".");
+                    }
                     future::ok(true)
                 },
                 FSValue::ExtAttr(_) | FSValue::ExtAttrs(_) => {
@@ -1778,7 +2902,9 @@ impl Fs {
                 FSValue::DyingInode(_) => {
                     panic!("Directories should not have dying inodes")
                 },
-                FSValue::InlineExtent(_) | FSValue::BlobExtent(_) => {
+                FSValue::InlineExtent(_) |
+                FSValue::BlobExtent(_) |
+                FSValue::DittoExtent(_) => {
                     panic!("Directories should not have extents")
                 },
                 FSValue::Property(_) => {
@@ -1794,16 +2920,107 @@ impl Fs {
         })
     }
 
+    /// Record that a client has opened a file, and return a new handle
+    /// number for it.  It remains open until [`Fs::release`].
+    ///
+    /// # Arguments
+    ///
+    /// - `fd`:     The file that was opened
+    /// - `pid`:    The process that opened it, or 0 if unknown
+    /// - `flags`:  Flags passed to `open(2)`
+    pub fn open(&self, fd: &FileData, pid: u32, flags: u32) -> u64 {
+        let fh = self.next_fh.fetch_add(1, Ordering::Relaxed);
+        let handle = Handle {ino: fd.ino, pid, flags, opened: Instant::now()};
+        self.handles.lock().unwrap().insert(fh, handle);
+        fh
+    }
+
+    /// List every file handle that clients currently have open.
+    pub fn open_files(&self) -> Vec<OpenFile> {
+        let now = Instant::now();
+        self.handles.lock().unwrap()
+            .iter()
+            .map(|(fh, h)| OpenFile {
+                fh: *fh,
+                ino: h.ino,
+                pid: h.pid,
+                flags: h.flags,
+                age: now.duration_since(h.opened)
+            }).collect()
+    }
+
+    /// Find a path to each of the files in `inos`, for error reporting.
+    ///
+    /// Non-directories don't record their parents, so this must scan the
+    /// entire file system.  If a file has several hard links, an arbitrary one
+    /// is returned.  Returns `None` for any file that isn't linked anywhere.
+    pub async fn paths_of(&self, inos: &[u64])
+        -> std::result::Result<Vec<Option<PathBuf>>, i32>
+    {
+        // Map each directory, and each target, to its parent and name
+        let targets = inos.iter().cloned().collect::<HashSet<_>>();
+        let parents = self.db.fsread(self.tree, move |ds| {
+            ds.range(..)
+            .try_fold(HashMap::new(), move |mut parents, (k, v)| {
+                let dirents = match v {
+                    FSValue::DirEntry(dirent) => vec![dirent],
+                    FSValue::DirEntries(dirents) => dirents,
+                    _ => Vec::new()
+                };
+                for dirent in dirents {
+                    if dirent.name == "." || dirent.name == ".." {
+                        continue;
+                    }
+                    if dirent.dtype == libc::DT_DIR ||
+                        targets.contains(&dirent.ino)
+                    {
+                        parents.insert(dirent.ino, (k.object(), dirent.name));
+                    }
+                }
+                future::ok(parents)
+            })
+        }).map_err(Error::into)
+        .await?;
+
+        let root = self.root().ino();
+        Ok(inos.iter().map(|ino| {
+            let mut components = Vec::new();
+            let mut cur = *ino;
+            while cur != root {
+                match parents.get(&cur) {
+                    // The length check guards against cycles in a damaged tree
+                    Some((parent, name)) if components.len() < parents.len() =>
+                    {
+                        components.push(name.as_os_str());
+                        cur = *parent;
+                    },
+                    _ => return None
+                }
+            }
+            Some(components.into_iter().rev().collect())
+        }).collect())
+    }
+
     pub async fn read(&self, fd: &FileData, offset: u64, size: usize)
         -> std::result::Result<SGList, i32>
     {
+        let start = Instant::now();
         let ino = fd.ino;
+        self.flush_pending(ino).await?;
         let inode_key = FSKey::new(ino, ObjKey::Inode);
+        let advice = fd.advice;
+        let noreuse = advice == Advice::NoReuse;
+        if noreuse {
+            self.fadvise.uncached.fetch_add(1, Ordering::Relaxed);
+        }
         // We only need a writeable FS reference if we're going to update atime.
         // If not, then only get a read reference.  Read references are better
         // because they can be held during txg syncs.
-        if self.atime.load(Ordering::Relaxed) {
-            self.db.fswrite(self.tree, 1, 0, 0, 0, move |ds| async move {
+        let (sglist, fsize, rs) = if self.atime.load(Ordering::Relaxed) {
+            let _guard = self.inode_locks.lock(ino).await;
+            // Don't let a full pool prevent reads
+            self.db.fswrite_reclaim(self.tree, 1, 0, 0, 0,
+            move |ds| async move {
                 let r = ds.get(inode_key).await?;
                 let mut value = r.expect("Inode not found");
                 let inode = value.as_mut_inode()
@@ -1813,11 +3030,11 @@ impl Fs {
                 let fsize = inode.size;
                 let rs = inode.record_size().unwrap() as u64;
                 let afut = ds.insert(inode_key, value);
-                let dfut = Fs::do_read(ds, ino, fsize, rs, offset, size);
+                let dfut = Fs::do_read(ds, ino, fsize, rs, offset, size,
+                                       advice);
                 let (sglist, _) = future::try_join(dfut, afut).await?;
-                Ok(sglist)
-            }).map_err(Error::into)
-            .await
+                Ok((sglist, fsize, rs))
+            }).await
         } else {
             self.db.fsread(self.tree, move |ds| {
                 ds.get(inode_key)
@@ -1827,11 +3044,54 @@ impl Fs {
                         .expect("Wrong Value type");
                     let fsize = inode.size;
                     let rs = inode.record_size().unwrap() as u64;
-                    Fs::do_read(ds, ino, fsize, rs, offset, size)
+                    Fs::do_read(ds, ino, fsize, rs, offset, size, advice)
+                    .map_ok(move |sglist| (sglist, fsize, rs))
                 })
-            }).map_err(Error::into)
-            .await
+            }).await
+        }.map_err(|e| -> i32 {
+            if matches!(e, Error::EIO | Error::EINTEGRITY) {
+                self.db.record_error(self.tree, ino, e);
+            }
+            e.into()
+        })?;
+        if fd.advice == Advice::Sequential {
+            self.readahead(ino, offset + size as u64, fsize, rs);
         }
+        let nbytes = sglist.iter().map(DivBuf::len).sum::<usize>();
+        self.io.record_read(nbytes, start.elapsed());
+        Ok(sglist)
+    }
+
+    /// Prefetch the records following a sequential read into the cache.
+    ///
+    /// The prefetch runs in the background, and any errors are ignored.  A
+    /// subsequent read will report them, if they're persistent.
+    fn readahead(&self, ino: u64, end: u64, fsize: u64, rs: u64) {
+        let start = div_roundup(end, rs) * rs;
+        let stop = cmp::min(start + READAHEAD_RECORDS * rs, fsize);
+        if start >= stop {
+            return;
+        }
+        let db = self.db.clone();
+        let tree = self.tree;
+        let counters = self.fadvise.clone();
+        tokio::spawn(async move {
+            let r = db.fsread(tree, move |ds| async move {
+                ds.range(FSKey::extent_range(ino, start..stop))
+                .try_fold(0, |n, (_k, v)| {
+                    let bfut = match (v.as_extent().unwrap(), v.ditto()) {
+                        (Extent::Inline(_), _) => return future::ok(n).boxed(),
+                        (Extent::Blob(be), Some(ditto)) =>
+                            ds.get_blob_ditto(be.rid, ditto),
+                        (Extent::Blob(be), None) => ds.get_blob(be.rid)
+                    };
+                    bfut.map_ok(move |_| n + 1).boxed()
+                }).await
+            }).await;
+            if let Ok(n) = r {
+                counters.readahead.fetch_add(n, Ordering::Relaxed);
+            }
+        });
     }
 
     // TODO: change Ok type to just libc::dirent after switching to a FreeBSD
@@ -1979,7 +3239,10 @@ impl Fs {
 
     pub async fn readlink(&self, fd: &FileData) -> std::result::Result<OsString, i32> {
         let ino = fd.ino;
-        self.db.fsread(self.tree, move |dataset| {
+        if let Some(target) = self.links.lock().unwrap().get(ino) {
+            return Ok(target);
+        }
+        let target = self.db.fsread(self.tree, move |dataset| {
             let key = FSKey::new(ino, ObjKey::Inode);
             dataset.get(key)
             .map(move |r| {
@@ -1997,7 +3260,17 @@ impl Fs {
                 }
             })
         }).map_err(Error::into)
-        .await
+        .await?;
+        self.links.lock().unwrap().insert(ino, target.clone());
+        Ok(target)
+    }
+
+    /// Close a handle returned by [`Fs::open`].
+    ///
+    /// It's not an error if the handle was already closed by
+    /// [`Fs::force_close`].
+    pub fn release(&self, fh: u64) {
+        self.handles.lock().unwrap().remove(&fh);
     }
 
     /// Rename a file.  Return the inode number of the renamed file.
@@ -2011,7 +3284,9 @@ impl Fs {
     /// - `name`:       Name of the directory entry to move.
     /// - `newparent`:  `FileData` of the new parent directory
     /// - `newino`:     `FileData` of the target file (if it exists).  Must be
-    ///                 provided if the target already exists!
+    ///                 provided if the target already exists!  Like an
+    ///                 unlinked file, an overwritten target remains
+    ///                 accessible until the client calls [`inactive`] on it.
     /// - `newname`:    New name for the file
     ///
     /// # Returns
@@ -2051,8 +3326,12 @@ impl Fs {
         if name == OsStr::from_bytes(b".") || name == OsStr::from_bytes(b"..") {
             return Err(libc::EINVAL);
         }
+        self.check_name(newname)?;
 
-        self.db.fswrite(self.tree, 8, 1, 1, 0, move |dataset| {
+        let mut inos = vec![parent_ino, newparent_ino, ino];
+        inos.extend(dst_ino);
+        let guards = self.inode_locks.lock_many(&inos).await;
+        let r = self.db.fswrite(self.tree, 8, 1, 1, 0, move |dataset| {
             let ds = Arc::new(dataset);
             let ds4 = ds.clone();
             let ds5 = ds.clone();
@@ -2114,7 +3393,7 @@ impl Fs {
                         let inode = value.as_mut_inode().unwrap();
                         let now = Timespec::now();
                         inode.mtime = now;
-                        inode.ctime = now;
+                        inode.changed(now);
                         if isdir && (!samedir || old_dst_ino.is_some()) {
                             inode.nlink -= 1;
                         }
@@ -2135,7 +3414,7 @@ impl Fs {
                             let inode = value.as_mut_inode().unwrap();
                             let now = Timespec::now();
                             inode.mtime = now;
-                            inode.ctime = now;
+                            inode.changed(now);
                             if isdir && old_dst_ino.is_none() {
                                 inode.nlink += 1;
                             }
@@ -2168,7 +3447,9 @@ impl Fs {
                                                );
                         fut.boxed()
                     } else {
-                        let fut = Fs::do_unlink(ds.clone(), false, v)
+                        // The client must've looked up the destination, so
+                        // it may still be open.  Let Fs::inactive reclaim it.
+                        let fut = Fs::do_unlink(ds.clone(), true, v)
                         .map_ok(drop);
                         fut.boxed()
                     }
@@ -2179,8 +3460,10 @@ impl Fs {
                     np_nlink_fut)
                 .map_ok(move |_| ino)
             })
-        }).map_err(Error::into)
-        .await
+        }).await?;
+        drop(guards);
+        self.dirsync().await;
+        Ok(r)
     }
 
     /// Remove a directory entry for a directory
@@ -2201,7 +3484,11 @@ impl Fs {
         let owned_name2 = owned_name.clone();
         let owned_name3 = owned_name.clone();
         let objkey = ObjKey::dir_entry(&owned_name);
-        self.db.fswrite(self.tree, 2, 1, 1, 0, move |dataset| async move {
+        // The kernel already prevents entries from being created in the
+        // victim, so only the parent needs locking.
+        let guard = self.inode_locks.lock(parent_ino).await;
+        self.db.fswrite_reclaim(self.tree, 2, 1, 1, 0,
+        move |dataset| async move {
             let ds = Arc::new(dataset);
             let ds2 = ds.clone();
             // 1) Lookup the directory
@@ -2223,41 +3510,64 @@ impl Fs {
 
             future::try_join(dirent_fut, dfut).await?;
             Ok(())
-        }).map_err(Error::into)
-        .await
+        }).await?;
+        drop(guard);
+        self.dirsync().await;
+        Ok(())
     }
 
     /// Lookup the root directory
     pub fn root(&self) -> FileDataMut {
-        FileDataMut{ ino: 1 , lookup_count: 1, parent: None}
+        FileDataMut::new(None, 1)
     }
 
     pub async fn setattr(&self, fd: &FileData, mut attr: SetAttr) -> std::result::Result<(), i32> {
         let ino = fd.ino;
+        self.flush_pending(ino).await?;
+        // Lock only after flushing, because flush_pending takes the lock too.
+        let _guard = self.inode_locks.lock(ino).await;
         let mut ninsert = 1;
         let mut nrange_delete = 0;
         let mut nremove = 0;
-        if attr.size.is_some() {
-            // We're truncating
+        if let Some(size) = attr.size {
+            // We're truncating.  If the file is big, free most of it in
+            // separate transactions first.
+            self.dealloc_tail(ino, size).await.map_err(Error::into)?;
             ninsert += 1;
             nrange_delete += 1;
             nremove += 1;
         }
-        self.db.fswrite(self.tree, ninsert, nrange_delete, nremove, 0,
+        let truncating = attr.size.is_some();
+        // setattr never allocates much space, and truncating frees it
+        let txg = self.db.fswrite_reclaim(self.tree, ninsert, nrange_delete,
+                                          nremove, 0,
         move |dataset| {
             let ds = Arc::new(dataset);
+            let txg = ds.txg();
             if attr.ctime.is_none() {
                 attr.ctime = Some(Timespec::now());
             }
             Fs::do_setattr(ds, ino, attr)
-            .map_ok(drop)
+            .map_ok(move |_| txg)
         }).map_err(Error::into)
-        .await
+        .await?;
+        if truncating {
+            self.mark_data_dirty(ino, txg);
+        }
+        Ok(())
     }
 
     pub async fn setextattr(&self, fd: &FileData, ns: ExtAttrNamespace,
                       name: &OsStr, data: &[u8]) -> std::result::Result<(), i32>
     {
+        if ns == ExtAttrNamespace::System && name == CHANGE_XATTR {
+            return Err(libc::EPERM);
+        }
+        if ns == ExtAttrNamespace::System && name == RECORDSIZE_XATTR {
+            let exp = parse_recordsize_xattr(data).ok_or(libc::EINVAL)?;
+            let prop = Property::RecordSize(exp);
+            Fs::check_prop_features(&self.db, &prop).map_err(i32::from)?;
+        }
         let ino = fd.ino;
         let objkey = ObjKey::extattr(ns, name);
         let key = FSKey::new(ino, objkey);
@@ -2271,6 +3581,8 @@ impl Fs {
             extent
         });
         let bb = extattr.allocated_space();
+        // Inserting into a hash bucket is a read-modify-write
+        let _guard = self.inode_locks.lock(ino).await;
         self.db.fswrite(self.tree, 2, 0, 0, bb, move |dataset| {
             let ads = Arc::new(dataset);
             htable::insert(ads, key, extattr, owned_name)
@@ -2280,17 +3592,110 @@ impl Fs {
         .await
     }
 
+    /// Update the in-memory copy of a property, without writing it to disk.
+    ///
+    /// Used whenever the property's effective value changes, whether it was
+    /// set on this file system or inherited from an ancestor.
+    pub(crate) fn apply_prop(&self, prop: &Property) {
+        match prop {
+            Property::Atime(atime) =>
+                self.atime.store(*atime, Ordering::Relaxed),
+            Property::RecordSize(exp) =>
+                self.record_size.store(*exp, Ordering::Relaxed),
+            Property::Sync(sp) =>
+                self.sync.store(*sp as u8, Ordering::Relaxed),
+            Property::Utf8Only(b) =>
+                self.utf8only.store(*b, Ordering::Relaxed),
+            Property::DirSync(b) =>
+                self.dirsync.store(*b, Ordering::Relaxed),
+            Property::Copies(copies) =>
+                self.copies.store(*copies, Ordering::Relaxed),
+            Property::DirtyLimit(limit) =>
+                self.db.set_dirty_limit(self.tree, *limit),
+            Property::Coalesce(window) =>
+                self.coalesce.store(*window, Ordering::Relaxed),
+            // Everything else is either enforced by the mount options, or not
+            // cached by the Fs at all.
+            _ => ()
+        }
+    }
+
+    /// Set or clear a directory's record size override.  `exp` is log base 2.
+    /// See [`RECORDSIZE_XATTR`].
+    pub async fn set_dir_recordsize(&self, fd: &FileData, exp: Option<u8>)
+        -> std::result::Result<(), i32>
+    {
+        let attr = self.getattr(fd).await?;
+        if attr.mode.file_type() != libc::S_IFDIR {
+            return Err(libc::ENOTDIR);
+        }
+        let name = OsStr::new(RECORDSIZE_XATTR);
+        if let Some(exp) = exp {
+            let value = (1u64 << exp).to_string();
+            self.setextattr(fd, ExtAttrNamespace::System, name,
+                            value.as_bytes()).await
+        } else {
+            match self.deleteextattr(fd, ExtAttrNamespace::System, name).await
+            {
+                Err(libc::ENOATTR) => Ok(()),
+                r => r
+            }
+        }
+    }
+
+    /// Mark a directory as opaque, or not.  See [`OPAQUE_XATTR`].
+    pub async fn set_opaque(&self, fd: &FileData, opaque: bool)
+        -> std::result::Result<(), i32>
+    {
+        let attr = self.getattr(fd).await?;
+        if attr.mode.file_type() != libc::S_IFDIR {
+            return Err(libc::ENOTDIR);
+        }
+        let name = OsStr::new(OPAQUE_XATTR);
+        if opaque {
+            self.setextattr(fd, ExtAttrNamespace::System, name, b"y").await
+        } else {
+            match self.deleteextattr(fd, ExtAttrNamespace::System, name).await
+            {
+                Err(libc::ENOATTR) => Ok(()),
+                r => r
+            }
+        }
+    }
+
     /// Change filesystem properties
     // Should be private to the crate.  Is only public so it can be used by the
     // functional tests.
     #[doc(hidden)]
     pub async fn set_prop(&self, prop: Property) -> Result<()> {
+        if self.readonly {
+            return Err(Error::EROFS);
+        }
+        Fs::check_prop_features(&self.db, &prop)?;
         match prop {
-            Property::Atime(atime) =>
-                self.atime.store(atime, Ordering::Relaxed),
-            Property::RecordSize(exp) =>
-                self.record_size.store(exp, Ordering::Relaxed),
-            Property::Name(_) => panic!("Immutable property"),
+            Property::Atime(_) |
+            Property::RecordSize(_) |
+            Property::Sync(_) |
+            Property::Devices(_) |
+            Property::Exec(_) |
+            Property::Setuid(_) |
+            Property::Utf8Only(_) |
+            Property::DirSync(_) |
+            Property::Copies(_) |
+            Property::CanMount(_) |
+            Property::Share9p(_) |
+            Property::ShareIscsi(_) |
+            Property::DirtyLimit(_) => self.apply_prop(&prop),
+            Property::Coalesce(_) => {
+                self.apply_prop(&prop);
+                // Don't leave anything buffered under the old window
+                self.flush_all_pending().await?;
+            }
+            Property::Mounted(_) |
+            Property::Name(_) |
+            Property::LastMounted(_) |
+            Property::MountCount(_) |
+            Property::CleanUnmount(_) => panic!("Immutable property"),
             _ => todo!(),
         }
 
@@ -2298,6 +3703,28 @@ impl Fs {
         Fs::set_prop_unmounted(self.tree, &self.db, prop).await
     }
 
+    /// Fail with `EOPNOTSUPP` if `prop` requires an on-disk feature that
+    /// isn't enabled.
+    fn check_prop_features(db: &Database, prop: &Property) -> Result<()> {
+        match prop {
+            Property::RecordSize(exp) if *exp > MAX_SMALL_RECORDSIZE => {
+                if db.features().contains(Feature::LargeRecords) {
+                    Ok(())
+                } else {
+                    Err(Error::EOPNOTSUPP)
+                }
+            }
+            Property::Copies(copies) if *copies > 1 => {
+                if db.features().contains(Feature::DittoData) {
+                    Ok(())
+                } else {
+                    Err(Error::EOPNOTSUPP)
+                }
+            }
+            _ => Ok(())
+        }
+    }
+
     /// Set a property on a file system that is not currently mounted
     pub(crate) async fn set_prop_unmounted(
         tree_id: TreeID,
@@ -2305,6 +3732,7 @@ impl Fs {
         prop: Property)
         -> Result<()>
     {
+        Fs::check_prop_features(db, &prop)?;
         match prop.name() {
             PropertyName::Mountpoint =>
                 panic!("Property {:?} may not be set directly on a file system",
@@ -2319,19 +3747,114 @@ impl Fs {
         let objkey = ObjKey::Property(prop.name());
         let key = FSKey::new(PROPERTY_OBJECT, objkey);
         let value = FSValue::Property(prop);
-        db.fswrite(tree_id, 1, 0, 0, 0, move |dataset|
+        db.fswrite_reclaim(tree_id, 1, 0, 0, 0, move |dataset|
             dataset.insert(key, value)
         ).map_ok(drop)
         .await
     }
 
+    /// Record in a file system's mount history that it's being mounted now.
+    ///
+    /// Returns whether the previous mount, if any, was cleanly unmounted.
+    pub(crate) async fn record_mount(tree_id: TreeID, db: Arc<Database>)
+        -> Result<bool>
+    {
+        let (count, _) = Fs::get_prop_history(tree_id, db.clone(),
+            PropertyName::MountCount).await?;
+        let (clean, _) = Fs::get_prop_history(tree_id, db.clone(),
+            PropertyName::CleanUnmount).await?;
+        let now = Timespec::now().sec.max(0) as u64;
+        let props = vec![
+            Property::LastMounted(now),
+            Property::MountCount(count.as_u64() + 1),
+            Property::CleanUnmount(false)
+        ];
+        Fs::set_props_history(tree_id, &db, props).await?;
+        Ok(clean.as_bool())
+    }
+
+    /// Record in a file system's mount history that it's been cleanly
+    /// unmounted.
+    pub(crate) async fn record_unmount(tree_id: TreeID, db: &Database)
+        -> Result<()>
+    {
+        let props = vec![Property::CleanUnmount(true)];
+        Fs::set_props_history(tree_id, db, props).await
+    }
+
+    /// Write some mount history properties in a single transaction.
+    async fn set_props_history(
+        tree_id: TreeID,
+        db: &Database,
+        props: Vec<Property>)
+        -> Result<()>
+    {
+        let ninsert = props.len();
+        db.fswrite_reclaim(tree_id, ninsert, 0, 0, 0,
+        move |dataset| async move {
+            let ds = Arc::new(dataset);
+            props.into_iter()
+                .map(|prop| {
+                    let objkey = ObjKey::Property(prop.name());
+                    let key = FSKey::new(PROPERTY_OBJECT, objkey);
+                    ds.insert(key, FSValue::Property(prop))
+                }).collect::<FuturesUnordered<_>>()
+                .try_collect::<Vec<_>>()
+                .await?;
+            Ok(())
+        }).await
+    }
+
+    /// Set a user property on a file system, or clear it if its value is
+    /// empty.
+    pub(crate) async fn set_user_prop(
+        tree_id: TreeID,
+        db: &Database,
+        prop: UserProperty)
+        -> Result<()>
+    {
+        if !prop.is_valid() {
+            return Err(Error::EINVAL);
+        }
+        let ns = ExtAttrNamespace::User;
+        let name = OsString::from(prop.name);
+        let key = FSKey::new(PROPERTY_OBJECT, ObjKey::extattr(ns, &name));
+        if prop.value.is_empty() {
+            db.fswrite_reclaim(tree_id, 1, 0, 1, 0, move |dataset| {
+                let ads = Arc::new(dataset);
+                htable::remove::<_, ExtAttr>(ads, key, ns, name)
+                .map(|r| match r {
+                    // Clearing an unset property is not an error
+                    Ok(_) | Err(Error::ENOATTR) => Ok(()),
+                    Err(e) => Err(e)
+                })
+            }).await
+        } else {
+            let buf = Arc::new(DivBufShared::from(prop.value.into_bytes()));
+            let extattr = ExtAttr::Inline(InlineExtAttr {
+                namespace: ns,
+                name: name.clone(),
+                extent: InlineExtent::new(buf)
+            });
+            let bb = extattr.allocated_space();
+            db.fswrite(tree_id, 2, 0, 0, bb, move |dataset| {
+                let ads = Arc::new(dataset);
+                htable::insert(ads, key, extattr, name)
+                    .map_ok(drop)
+                    .boxed()
+            }).await
+        }
+    }
+
     pub async fn statvfs(&self) -> std::result::Result<libc::statvfs, i32> {
         let rs = 1 << self.record_size.load(Ordering::Relaxed);
         self.db.fsread(self.tree, move |dataset| {
             let blocks = dataset.size();
             let used = dataset.used();
+            // Writes fail with ENOSPC before they can use the slop space.
+            let avail = blocks.saturating_sub(used + slop_space(blocks));
             let r = libc::statvfs {
-                f_bavail: blocks - used,
+                f_bavail: avail,
                 f_bfree: blocks - used,
                 f_blocks: blocks,
                 f_favail: u64::max_value(),
@@ -2341,7 +3864,7 @@ impl Fs {
                 f_flag: 0,
                 f_frsize: 4096,
                 f_fsid: 0,
-                f_namemax: 255,
+                f_namemax: NAME_MAX as _,
             };
             future::ok(r)
         }).map_err(Error::into)
@@ -2356,15 +3879,43 @@ impl Fs {
         let file_type = FileType::Link(link.to_os_string());
         let create_args = CreateArgs::new(parent, name, perm, uid, gid,
                                           file_type);
-        self.do_create(create_args).await
+        let fd = self.do_create(create_args).await?;
+        // Programs that create symlinks often read them right back
+        self.links.lock().unwrap().insert(fd.ino, link.to_os_string());
+        Ok(fd)
     }
 
     pub async fn sync(&self) {
+        if let Err(e) = self.flush_all_pending().await {
+            tracing::error!("Lost buffered writes: {e:?}");
+        }
         self.db.sync_transaction()
         .await
         .expect("Fs::sync failed");
     }
 
+    /// Sync the file system, then forget about any data that's now on disk.
+    async fn sync_and_prune(&self) {
+        self.sync().await;
+        let synced = self.db.synced_txg();
+        self.dirty_data.lock().unwrap().retain(|_, t| Some(*t) > synced);
+    }
+
+    /// Sync a directory modification to disk, if the `dirsync` property says
+    /// to.  The caller must not hold any inode locks, because syncing flushes
+    /// buffered writes.
+    async fn dirsync(&self) {
+        if self.dirsync.load(Ordering::Relaxed) &&
+            self.sync_policy() != SyncPolicy::Disabled
+        {
+            self.sync_and_prune().await;
+        }
+    }
+
+    fn sync_policy(&self) -> SyncPolicy {
+        SyncPolicy::from_u8(self.sync.load(Ordering::Relaxed)).unwrap()
+    }
+
     /// Remove a directory entry for a non-directory
     ///
     /// - `parent_fd`:  `FileData` of the parent directory, as returned by
@@ -2384,7 +3935,10 @@ impl Fs {
         let parent_ino = parent_fd.ino;
         let owned_name = name.to_os_string();
         let dekey = ObjKey::dir_entry(&owned_name);
-        self.db.fswrite(self.tree, 3, 0, 1, 0, move |ds| async move {
+        let mut inos = vec![parent_ino];
+        inos.extend(ino);
+        let guards = self.inode_locks.lock_many(&inos).await;
+        self.db.fswrite_reclaim(self.tree, 3, 0, 1, 0, move |ds| async move {
             let dataset = Arc::new(ds);
             // 1) Lookup and remove the directory entry
             let key = FSKey::new(parent_ino, dekey);
@@ -2406,14 +3960,44 @@ impl Fs {
             let ts_fut = Fs::do_setattr(dataset, parent_ino, attr);
             future::try_join(unlink_fut, ts_fut).await?;
             Ok(())
-        }).map_err(Error::into)
-        .await
+        }).await?;
+        drop(guards);
+        self.dirsync().await;
+        Ok(())
     }
 
     pub async fn write<IU>(&self, fd: &FileData, offset: u64, data: IU, _flags: u32)
         -> std::result::Result<u32, i32>
         where IU: Into<Uio>
     {
+        let start = Instant::now();
+        let r = self.write_uio(fd.ino, offset, data.into()).await?;
+        self.io.record_write(r, start.elapsed());
+        Ok(r)
+    }
+
+    /// Body of [`Fs::write`]
+    async fn write_uio(&self, ino: u64, offset: u64, uio: Uio)
+        -> std::result::Result<u32, i32>
+    {
+        if self.coalesce_write(ino, offset, &uio) {
+            return Ok(uio.len() as u32);
+        }
+        // Whatever is already buffered must reach the tree first, or it could
+        // later clobber this write.  Then try again with an empty buffer.
+        self.flush_pending(ino).await?;
+        if self.coalesce_write(ino, offset, &uio) {
+            return Ok(uio.len() as u32);
+        }
+        let r = self.do_write(ino, offset, uio).await?;
+        if self.sync_policy() == SyncPolicy::Always {
+            self.sync_and_prune().await;
+        }
+        Ok(r)
+    }
+
+    /// Write `uio` to the tree at `offset`, without any buffering.
+    async fn do_write(&self, ino: u64, offset: u64, uio: Uio) -> Result<u32> {
         // Outline:
         // 1) Split the I/O into discrete records
         // 2) For each record
@@ -2424,17 +4008,17 @@ impl Fs {
         //         end if the Inode indicates that the file size requires it.
         //         Then write it as an InlineExtent
         //  3) Set file length
-        let ino = fd.ino;
-        let uio = data.into();
-
+        // Hold the lock from reading the inode until writing it back, or a
+        // concurrent write could lose our size and byte count updates.
+        let _guard = self.inode_locks.lock(ino).await;
         let inode_key = FSKey::new(ino, ObjKey::Inode);
         let mut value = self.db.fsread(self.tree, move |dataset| {
             let inode_key = FSKey::new(ino, ObjKey::Inode);
             dataset.get(inode_key)
-        }).map_err::<i32, _>(Error::into)
-        .await?.unwrap();
+        }).await?.unwrap();
 
         let rs = value.as_inode().unwrap().record_size().unwrap();
+        let copies = self.copies.load(Ordering::Relaxed);
         let offset0 = (offset % rs as u64) as usize;
         // Get WriteBack credit sufficient for nrecs full dirty records.  At
         // this point, we don't know if any of the records we're writing to are
@@ -2442,7 +4026,7 @@ impl Fs {
         let nrecs = uio.nrecs(offset0, rs);
         let bb = FSValue::extent_space(rs, nrecs);
 
-        self.db.fswrite(self.tree, 1 + nrecs, 0, nrecs, bb,
+        let r = self.db.fswrite(self.tree, 1 + nrecs, 0, nrecs, bb,
         move |ds| async move {
             let dataset = Arc::new(ds);
             let inode = value.as_inode().unwrap();
@@ -2460,7 +4044,8 @@ impl Fs {
                 .enumerate()
                 .map(|(i, dbs)| {
                     let ds3 = dataset.clone();
-                    Fs::write_record(ino, rs as u64, offset, i, dbs, ds3)
+                    Fs::write_record(ino, rs as u64, offset, i, dbs, copies,
+                                     ds3)
                 }).collect::<FuturesUnordered<_>>();
             let delta_len: i64 = data_futs.try_collect::<Vec<_>>().await?
                 .into_iter()
@@ -2477,12 +4062,13 @@ impl Fs {
                     as u64;
                 let now = Timespec::now();
                 inode.mtime = now;
-                inode.ctime = now;
+                inode.changed(now);
             }
             dataset.insert(inode_key, value).await?;
-            Ok(datalen as u32)
-        }).map_err(Error::into)
-        .await
+            Ok((datalen as u32, dataset.txg()))
+        }).await?;
+        self.mark_data_dirty(ino, r.1);
+        Ok(r.0)
     }
 
     /// Subroutine of write.  Returns the amount by which the file's on-disk
@@ -2490,6 +4076,7 @@ impl Fs {
     #[inline]
     async fn write_record(ino: u64, rs: u64, offset: u64, i: usize,
                     data: Arc<DivBufShared>,
+                    copies: u8,
                     dataset: Arc<ReadWriteFilesystem>)
         -> Result<i64>
     {
@@ -2506,6 +4093,15 @@ impl Fs {
             // We must read-modify-write
             let r = dataset.remove(k).await?;
             let (dbs, old_len) = match r {
+                None if offset_into_rec == 0 => {
+                    // Either a hole, or beyond EOF, and there's nothing to pad
+                    // at the beginning.  Use the new data as-is rather than
+                    // copying it into a fresh buffer.
+                    let extent = InlineExtent::new(data).with_copies(copies);
+                    let new_len = extent.stat_space();
+                    let new_v = FSValue::InlineExtent(extent);
+                    return dataset.insert(k, new_v).await.map(|_| new_len);
+                },
                 None => {
                     // Either a hole, or beyond EOF
                     let hsize = writelen + offset_into_rec;
@@ -2519,7 +4115,7 @@ impl Fs {
                     (r, 0)
                 },
                 Some(FSValue::InlineExtent(ile)) => {
-                    let old_len = ile.len() as i64;
+                    let old_len = ile.stat_space();
                     (ile.buf, old_len)
                 },
                 // Some(FSValue::BlobExtent(be)) should never happen, because
@@ -2537,14 +4133,15 @@ impl Fs {
 
             // Overwrite with new data
             base[r].copy_from_slice(&overlay[..]);
-            let extent = InlineExtent::new(dbs);
-            let new_len = extent.len() as i64;
+            let extent = InlineExtent::new(dbs).with_copies(copies);
+            let new_len = extent.stat_space();
             let new_v = FSValue::InlineExtent(extent);
             dataset.insert(k, new_v).await
             .map(|_| new_len - old_len)
         } else {
-            let new_len = data.len() as i64;
-            let v = FSValue::InlineExtent(InlineExtent::new(data));
+            let extent = InlineExtent::new(data).with_copies(copies);
+            let new_len = extent.stat_space();
+            let v = FSValue::InlineExtent(extent);
             dataset.insert(k, v).await
             .map(|ov| new_len - ov.map_or(0, |fsv| fsv.stat_space()))
         }
@@ -29,11 +29,13 @@ async fn setup() -> Database {
             mock_range_query(Vec::new())
         });
     let mut db = Database::default();
+    db.expect_is_readonly()
+        .return_const(false);
     db.expect_create_fs()
         .once()
         .returning(|_, _: &'static str| Ok(TreeID(0)));
     db.expect_fsread_inner()
-        .times(3)
+        .times(8)
         .returning(move |_| {
             let mut rods = ReadOnlyFilesystem::default();
             rods.expect_get()
@@ -44,6 +46,30 @@ async fn setup() -> Database {
                 .with(eq(FSKey::new(PROPERTY_OBJECT,
                                     ObjKey::Property(PropertyName::RecordSize))))
                 .returning(|_| future::ok(None).boxed());
+            rods.expect_get()
+                .with(eq(FSKey::new(PROPERTY_OBJECT,
+                                    ObjKey::Property(PropertyName::Sync))))
+                .returning(|_| future::ok(None).boxed());
+            rods.expect_get()
+                .with(eq(FSKey::new(PROPERTY_OBJECT,
+                                    ObjKey::Property(PropertyName::Utf8Only))))
+                .returning(|_| future::ok(None).boxed());
+            rods.expect_get()
+                .with(eq(FSKey::new(PROPERTY_OBJECT,
+                                    ObjKey::Property(PropertyName::DirtyLimit))))
+                .returning(|_| future::ok(None).boxed());
+            rods.expect_get()
+                .with(eq(FSKey::new(PROPERTY_OBJECT,
+                                    ObjKey::Property(PropertyName::Coalesce))))
+                .returning(|_| future::ok(None).boxed());
+            rods.expect_get()
+                .with(eq(FSKey::new(PROPERTY_OBJECT,
+                                    ObjKey::Property(PropertyName::DirSync))))
+                .returning(|_| future::ok(None).boxed());
+            rods.expect_get()
+                .with(eq(FSKey::new(PROPERTY_OBJECT,
+                                    ObjKey::Property(PropertyName::Copies))))
+                .returning(|_| future::ok(None).boxed());
             rods.expect_last_key()
                 .returning(|| {
                     let root_inode_key = FSKey::new(1, ObjKey::Inode);
@@ -54,6 +80,9 @@ async fn setup() -> Database {
     db.expect_fswrite_inner()
         .once()
         .return_once(move |_| rwds);
+    db.expect_set_dirty_limit()
+        .with(eq(TreeID(0)), eq(0))
+        .return_const(());
     db.expect_lookup_parent()
         .with(eq(TreeID(0)))
         .returning(|_| future::ok(None).boxed());
@@ -64,6 +93,23 @@ async fn setup() -> Database {
     db
 }
 
+/// Expect `Fs::create` to look for its parent directory's record size
+/// override, and find none
+fn expect_no_dir_recordsize(db: &mut Database, parent: u64) {
+    db.expect_fsread_inner()
+        .once()
+        .returning(move |_| {
+            let mut rods = ReadOnlyFilesystem::default();
+            let objkey = ObjKey::extattr(ExtAttrNamespace::System,
+                                         OsStr::new(RECORDSIZE_XATTR));
+            rods.expect_get()
+                .once()
+                .with(eq(FSKey::new(parent, objkey)))
+                .returning(|_| future::ok(None).boxed());
+            rods
+        });
+}
+
 /// Helper that creates a mock RangeQuery from the vec of items that it should
 /// return
 fn mock_range_query<K, T, V>(items: Vec<(K, V)>) -> RangeQuery<K, T, V>
@@ -89,6 +135,7 @@ fn mock_range_query<K, T, V>(items: Vec<(K, V)>) -> RangeQuery<K, T, V>
 #[tokio::test]
 async fn create() {
     let mut db = setup().await;
+    expect_no_dir_recordsize(&mut db, 1);
     let mut ds = read_write_filesystem();
     let root_ino = 1;
     let ino = 2;
@@ -112,6 +159,7 @@ async fn create() {
                 gid: 0,
                 file_type: FileType::Dir,
                 perm: 0o755,
+                change: 0,
             };
             future::ok(Some(FSValue::inode(inode))).boxed()
         });
@@ -164,6 +212,7 @@ async fn create() {
 #[tokio::test]
 async fn create_hash_collision() {
     let mut db = setup().await;
+    expect_no_dir_recordsize(&mut db, 1);
     let mut ds = read_write_filesystem();
     let root_ino = 1;
     let ino = 2;
@@ -192,6 +241,7 @@ async fn create_hash_collision() {
                 gid: 0,
                 file_type: FileType::Dir,
                 perm: 0o755,
+                change: 0,
             };
             future::ok(Some(FSValue::inode(inode))).boxed()
         });
@@ -288,9 +338,10 @@ fn debug_getattr() {
         rdev: 0,
         blksize: 131072,
         flags: 0,
+        change: 0,
     };
     let s = format!("{attr:?}");
-    assert_eq!("GetAttr { ino: 1, size: 4096, bytes: 4096, atime: Timespec { sec: 1, nsec: 2 }, mtime: Timespec { sec: 3, nsec: 4 }, ctime: Timespec { sec: 5, nsec: 6 }, birthtime: Timespec { sec: 7, nsec: 8 }, mode: Mode { .0: 33188, perm: 420 }, nlink: 1, uid: 1000, gid: 1000, rdev: 0, blksize: 131072, flags: 0 }", s);
+    assert_eq!("GetAttr { ino: 1, size: 4096, bytes: 4096, atime: Timespec { sec: 1, nsec: 2 }, mtime: Timespec { sec: 3, nsec: 4 }, ctime: Timespec { sec: 5, nsec: 6 }, birthtime: Timespec { sec: 7, nsec: 8 }, mode: Mode { .0: 33188, perm: 420 }, nlink: 1, uid: 1000, gid: 1000, rdev: 0, blksize: 131072, flags: 0, change: 0 }", s);
 }
 
 // Pet kcov
@@ -311,6 +362,7 @@ fn eq_getattr() {
         rdev: 0,
         blksize: 65536,
         flags: 0,
+        change: 0,
     };
     let attr2 = attr;
     assert_eq!(attr2, attr);
@@ -452,6 +504,93 @@ async fn deleteextattr_3way_collision_enoattr() {
     assert_eq!(Err(libc::ENOATTR), r);
 }
 
+/// `Advice::DontNeed` should evict all of a file's blob extents from cache
+#[tokio::test]
+async fn fadvise_dontneed() {
+    let ino = 42;
+    let rid = RID(0xdead_beef);
+
+    let mut db = setup().await;
+    db.expect_fsread_inner()
+        .once()
+        .returning(move |_| {
+            let mut rods = ReadOnlyFilesystem::default();
+            rods.expect_range()
+                .once()
+                .with(eq(FSKey::extent_range(ino, ..)))
+                .returning(move |_| {
+                    let k0 = FSKey::new(ino, ObjKey::Extent(0));
+                    let be = BlobExtent{lsize: 4096, rid};
+                    let k1 = FSKey::new(ino, ObjKey::Extent(4096));
+                    let dbs = Arc::new(DivBufShared::from(vec![0u8; 1]));
+                    let ie = InlineExtent::new(dbs);
+                    mock_range_query(vec![
+                        (k0, FSValue::BlobExtent(be)),
+                        (k1, FSValue::InlineExtent(ie))
+                    ])
+                });
+            rods.expect_evict_blob()
+                .once()
+                .with(eq(rid))
+                .return_const(());
+            rods
+        });
+    let fs = Fs::new(Arc::new(db), TreeID(0)).await;
+
+    let mut fd = FileDataMut::new(Some(1), ino);
+    fd.set_advice(Advice::DontNeed);
+    fs.fadvise(&fd.handle(), Advice::DontNeed).await.unwrap();
+    let stats = fs.fadvise_stats();
+    assert_eq!(stats.dontneed, 1);
+    assert_eq!(stats.evicted, 1);
+}
+
+/// fdatasync of a file whose data hasn't changed shouldn't sync anything
+#[tokio::test]
+async fn fdatasync_clean() {
+    let ino = 42;
+
+    let mut db = setup().await;
+    db.expect_sync_transaction().never();
+    db.expect_synced_txg()
+        .return_const(Some(TxgT(4)));
+    let fs = Fs::new(Arc::new(db), TreeID(0)).await;
+    // Written in a transaction group that has already synced
+    fs.mark_data_dirty(ino, TxgT(3));
+
+    let fd = FileDataMut::new(Some(1), ino);
+    assert!(fs.fdatasync(&fd.handle()).await.is_ok());
+    let fd2 = FileDataMut::new(Some(1), ino + 1);
+    assert!(fs.fdatasync(&fd2.handle()).await.is_ok());
+}
+
+/// fdatasync of a file with unsynced data should sync, but only once
+#[tokio::test]
+async fn fdatasync_dirty() {
+    let ino = 42;
+
+    let mut db = setup().await;
+    let mut seq = Sequence::new();
+    db.expect_synced_txg()
+        .once()
+        .in_sequence(&mut seq)
+        .return_const(Some(TxgT(4)));
+    db.expect_sync_transaction()
+        .once()
+        .in_sequence(&mut seq)
+        .returning(|| future::ok(()).boxed());
+    db.expect_synced_txg()
+        .once()
+        .in_sequence(&mut seq)
+        .return_const(Some(TxgT(5)));
+    let fs = Fs::new(Arc::new(db), TreeID(0)).await;
+    fs.mark_data_dirty(ino, TxgT(5));
+
+    let fd = FileDataMut::new(Some(1), ino);
+    assert!(fs.fdatasync(&fd.handle()).await.is_ok());
+    assert!(fs.fdatasync(&fd.handle()).await.is_ok());
+}
+
 #[tokio::test]
 async fn fsync() {
     let ino = 42;
@@ -460,12 +599,107 @@ async fn fsync() {
     db.expect_sync_transaction()
         .once()
         .returning(|| future::ok(()).boxed());
+    db.expect_synced_txg()
+        .return_const(Some(TxgT(1)));
     let fs = Fs::new(Arc::new(db), TreeID(0)).await;
 
     let fd = FileDataMut::new(Some(1), ino);
     assert!(fs.fsync(&fd.handle()).await.is_ok());
 }
 
+/// Once the oldest entries have been evicted, the newest should remain
+#[test]
+fn io_counters_bucket() {
+    assert_eq!(IoCounters::bucket(Duration::from_nanos(500)), 0);
+    assert_eq!(IoCounters::bucket(Duration::from_micros(1)), 1);
+    assert_eq!(IoCounters::bucket(Duration::from_micros(3)), 2);
+    assert_eq!(IoCounters::bucket(Duration::from_micros(4)), 3);
+    assert_eq!(IoCounters::bucket(Duration::from_secs(1)), 20);
+    assert_eq!(IoCounters::bucket(Duration::from_secs(3600)),
+               LATENCY_BUCKETS - 1);
+}
+
+#[test]
+fn link_cache_evict() {
+    let mut cache = LinkCache::default();
+    for ino in 0..(LinkCache::CAPACITY as u64 + 10) {
+        cache.insert(ino, OsString::from(format!("target{ino}")));
+    }
+    assert_eq!(cache.targets.len(), LinkCache::CAPACITY);
+    assert_eq!(cache.get(9), None);
+    assert_eq!(cache.get(10), Some(OsString::from("target10")));
+}
+
+/// An unrecoverable error reading a file should be recorded in the pool's
+/// error log
+#[tokio::test]
+async fn read_eio() {
+    let ino = 42;
+    let mut db = setup().await;
+    let mut ds = read_write_filesystem();
+    ds.expect_get()
+        .once()
+        .with(eq(FSKey::new(ino, ObjKey::Inode)))
+        .returning(|_| future::err(Error::EIO).boxed());
+    db.expect_fswrite_inner()
+        .once()
+        .return_once(move |_| ds);
+    db.expect_record_error()
+        .once()
+        .with(eq(TreeID(0)), eq(ino), eq(Error::EIO))
+        .return_const(());
+    let fs = Fs::new(Arc::new(db), TreeID(0)).await;
+
+    let fd = FileDataMut::new_for_tests(None, ino);
+    let r = fs.read(&fd.handle(), 0, 4096).await;
+    assert_eq!(Some(libc::EIO), r.err());
+}
+
+/// A second readlink of the same symlink should be served from the cache,
+/// without reading the inode again.
+#[tokio::test]
+async fn readlink_cached() {
+    let ino = 42;
+    let target = OsString::from("../lib/libfoo.so.1");
+    let target2 = target.clone();
+    let old_ts = Timespec::new(0, 0);
+
+    let mut db = setup().await;
+    db.expect_fsread_inner()
+        .once()
+        .returning(move |_| {
+            let target3 = target2.clone();
+            let mut rods = ReadOnlyFilesystem::default();
+            rods.expect_get()
+                .once()
+                .with(eq(FSKey::new(ino, ObjKey::Inode)))
+                .returning(move |_| {
+                    let inode = Inode {
+                        size: 0,
+                        bytes: 0,
+                        nlink: 1,
+                        flags: 0,
+                        atime: old_ts,
+                        mtime: old_ts,
+                        ctime: old_ts,
+                        birthtime: old_ts,
+                        uid: 0,
+                        gid: 0,
+                        file_type: FileType::Link(target3.clone()),
+                        perm: 0o777,
+                        change: 0,
+                    };
+                    future::ok(Some(FSValue::inode(inode))).boxed()
+                });
+            rods
+        });
+    let fs = Fs::new(Arc::new(db), TreeID(0)).await;
+
+    let fd = FileDataMut::new(None, ino);
+    assert_eq!(fs.readlink(&fd.handle()).await, Ok(target.clone()));
+    assert_eq!(fs.readlink(&fd.handle()).await, Ok(target));
+}
+
 /// Reading the source returns EIO.  Don't delete the dest
 #[tokio::test]
 async fn rename_eio() {
@@ -660,6 +894,32 @@ async fn set_prop() {
     fs.set_prop(Property::Atime(false)).await.unwrap();
 }
 
+/// Ditto copies may not be used until the feature has been enabled
+#[tokio::test]
+async fn set_prop_copies_disabled() {
+    let mut db = setup().await;
+    db.expect_features()
+        .return_const(crate::feature::Features::default());
+    db.expect_fswrite_inner()
+        .never();
+    let fs = Fs::new(Arc::new(db), TreeID(0)).await;
+    let r = fs.set_prop(Property::Copies(2)).await;
+    assert_eq!(Err(Error::EOPNOTSUPP), r);
+}
+
+/// Large records may not be used until the feature has been enabled
+#[tokio::test]
+async fn set_prop_large_records_disabled() {
+    let mut db = setup().await;
+    db.expect_features()
+        .return_const(crate::feature::Features::default());
+    db.expect_fswrite_inner()
+        .never();
+    let fs = Fs::new(Arc::new(db), TreeID(0)).await;
+    let r = fs.set_prop(Property::RecordSize(24)).await;
+    assert_eq!(Err(Error::EOPNOTSUPP), r);
+}
+
 #[tokio::test]
 async fn sync() {
     let mut db = setup().await;
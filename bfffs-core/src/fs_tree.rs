@@ -245,6 +245,11 @@ impl FSKey {
         FSKey::compose(object, objtype, offset)
     }
 
+    /// Return the equivalent key, but for a different object.
+    pub fn with_object(&self, object: u64) -> Self {
+        FSKey::compose(object, self.objtype(), self.offset())
+    }
+
     /// Create a range of `FSKey` that will include every item related to the
     /// given object.
     pub fn obj_range(ino: u64) -> Range<Self> {
@@ -738,10 +743,21 @@ pub struct Inode {
     // TODO: serialize as octal when dumping to YAML
     pub perm:       u16,
     /// File type.  Regular, directory, etc
-    pub file_type:   FileType
+    pub file_type:   FileType,
+    /// Change counter, like Linux's `i_version`.  Advanced whenever the file's
+    /// data or metadata changes, so NFS servers and backup tools can cheaply
+    /// tell whether the file has changed.
+    pub change:     u64,
 }
 
 impl Inode {
+    /// Record a change to the file's data or metadata: update its ctime and
+    /// advance its change counter.
+    pub fn changed(&mut self, now: Timespec) {
+        self.ctime = now;
+        self.change += 1;
+    }
+
     /// This file's record size in bytes.
     pub fn record_size(&self) -> Option<usize> {
         if let FileType::Reg(exp) = self.file_type {
@@ -752,6 +768,46 @@ impl Inode {
     }
 }
 
+/// On-disk layout of an `Inode` from before the change counter.
+///
+/// Pools written by older versions still contain these.  They're converted to
+/// `Inode`s, with a change counter of 0, as they're read.
+#[derive(Deserialize)]
+struct LegacyInode {
+    size:       u64,
+    bytes:      u64,
+    nlink:      u64,
+    flags:      u64,
+    atime:      Timespec,
+    mtime:      Timespec,
+    ctime:      Timespec,
+    birthtime:  Timespec,
+    uid:        u32,
+    gid:        u32,
+    perm:       u16,
+    file_type:  FileType
+}
+
+impl From<LegacyInode> for Inode {
+    fn from(li: LegacyInode) -> Self {
+        Inode {
+            size: li.size,
+            bytes: li.bytes,
+            nlink: li.nlink,
+            flags: li.flags,
+            atime: li.atime,
+            mtime: li.mtime,
+            ctime: li.ctime,
+            birthtime: li.birthtime,
+            uid: li.uid,
+            gid: li.gid,
+            perm: li.perm,
+            file_type: li.file_type,
+            change: 0
+        }
+    }
+}
+
 /// This module ought to be unreachable, but must exist to satisfy rustc
 mod dbs_serializer {
     use divbuf::DivBufShared;
@@ -778,7 +834,11 @@ mod dbs_serializer {
 pub struct InlineExtent {
     #[serde(with = "dbs_serializer")]
     // The Arc is necessary to make it Clone.
-    pub buf: Arc<DivBufShared>
+    pub buf: Arc<DivBufShared>,
+    /// How many copies to store when flushed to a blob.  Only meaningful in
+    /// memory; extents small enough to stay in the tree are never copied.
+    #[serde(skip)]
+    copies: u8
 }
 
 #[allow(clippy::len_without_is_empty)]  // It isn't needed
@@ -805,6 +865,25 @@ impl InlineExtent {
         InlineExtentFlush{k, inner: cfut, lsize: lsize as u32}
     }
 
+    /// Like `flush`, but also write a ditto copy of the blob.
+    fn flush_ditto<D, K>(self, k: K, dml: &D, txg: TxgT)
+        -> impl Future<Output=Result<(K, FSValue)>> + Send
+        where D: DML + 'static, D::Addr: 'static, K: Key
+    {
+        let lsize = self.len();
+        assert!(lsize > BLOB_THRESHOLD);
+        let dbs = Arc::try_unwrap(self.buf).unwrap();
+        dml.put_ditto(dbs, Compression::None, txg)
+        .map_ok(move |(rid, ditto)| {
+            let extent = BlobExtent {
+                lsize: lsize as u32,
+                rid: checked_transmute(rid)
+            };
+            let de = DittoExtent{extent, ditto: checked_transmute(ditto)};
+            (k, FSValue::DittoExtent(de))
+        })
+    }
+
     pub fn len(&self) -> usize {
         self.buf.len()
     }
@@ -814,14 +893,30 @@ impl InlineExtent {
     }
 
     pub fn new(buf: Arc<DivBufShared>) -> Self {
-        InlineExtent{buf}
+        InlineExtent{buf, copies: 1}
+    }
+
+    /// How much space this extent will contribute to `stat.st_blocks`, in
+    /// bytes, once it's flushed.
+    pub fn stat_space(&self) -> i64 {
+        if self.copies > 1 && self.needs_flush() {
+            self.len() as i64 * i64::from(self.copies)
+        } else {
+            self.len() as i64
+        }
+    }
+
+    /// Store `copies` copies of this extent, if it gets flushed to a blob.
+    pub fn with_copies(mut self, copies: u8) -> Self {
+        self.copies = copies;
+        self
     }
 }
 
 // Useful for the fuse unit tests
 impl Default for InlineExtent {
     fn default() -> Self {
-        InlineExtent { buf: Arc::new(DivBufShared::with_capacity(0)) }
+        InlineExtent::new(Arc::new(DivBufShared::with_capacity(0)))
     }
 }
 
@@ -865,6 +960,19 @@ pub struct BlobExtent {
     pub rid: RID,
 }
 
+/// A `BlobExtent` that was written with `copies=2`.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct DittoExtent {
+    pub extent: BlobExtent,
+    /// A second copy of the same data, in a different zone
+    pub ditto: RID,
+}
+
+impl DittoExtent {
+    /// How many copies of the data a `DittoExtent` stores
+    pub const COPIES: u8 = 2;
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Extent<'a> {
     Inline(&'a InlineExtent),
@@ -883,7 +991,8 @@ impl<'a> Extent<'a> {
     }
 }
 
-#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(from = "FSValueOnDisk")]
 pub enum FSValue {
     DirEntry(Dirent),
     Inode(Box<Inode>),
@@ -902,6 +1011,8 @@ pub enum FSValue {
     /// system.  Only valid for object 0.
     DyingInode(DyingInode),
     // TODO: hash bucket of DyingInode
+    /// A blob extent with a ditto copy
+    DittoExtent(DittoExtent),
     /// Only used temporarily in memory.  Never written to disk.
     /// Must come last!
     #[doc(hidden)]
@@ -909,6 +1020,82 @@ pub enum FSValue {
     Invalid,
 }
 
+/// On-disk layout of an `FSValue`.
+///
+/// Identical to `FSValue`, except that it can also hold legacy Inodes.  Never
+/// reorder the variants; always add new ones just before `Invalid`.
+#[derive(Deserialize)]
+#[serde(rename = "FSValue")]
+enum FSValueOnDisk {
+    DirEntry(Dirent),
+    LegacyInode(Box<LegacyInode>),
+    InlineExtent(InlineExtent),
+    BlobExtent(BlobExtent),
+    ExtAttr(ExtAttr),
+    ExtAttrs(Vec<ExtAttr>),
+    DirEntries(Vec<Dirent>),
+    Property(Property),
+    DyingInode(DyingInode),
+    Inode(Box<Inode>),
+    DittoExtent(DittoExtent),
+    Invalid,
+}
+
+impl From<FSValueOnDisk> for FSValue {
+    fn from(v: FSValueOnDisk) -> Self {
+        match v {
+            FSValueOnDisk::DirEntry(x) => FSValue::DirEntry(x),
+            FSValueOnDisk::LegacyInode(x) => FSValue::inode(Inode::from(*x)),
+            FSValueOnDisk::InlineExtent(x) => FSValue::InlineExtent(x),
+            FSValueOnDisk::BlobExtent(x) => FSValue::BlobExtent(x),
+            FSValueOnDisk::ExtAttr(x) => FSValue::ExtAttr(x),
+            FSValueOnDisk::ExtAttrs(x) => FSValue::ExtAttrs(x),
+            FSValueOnDisk::DirEntries(x) => FSValue::DirEntries(x),
+            FSValueOnDisk::Property(x) => FSValue::Property(x),
+            FSValueOnDisk::DyingInode(x) => FSValue::DyingInode(x),
+            FSValueOnDisk::Inode(x) => FSValue::Inode(x),
+            FSValueOnDisk::DittoExtent(x) => FSValue::DittoExtent(x),
+            FSValueOnDisk::Invalid => FSValue::Invalid,
+        }
+    }
+}
+
+impl Serialize for FSValue {
+    fn serialize<S>(&self, serializer: S)
+        -> std::result::Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        // Variant indices must match FSValueOnDisk
+        const NAME: &str = "FSValue";
+        match self {
+            FSValue::DirEntry(x) =>
+                serializer.serialize_newtype_variant(NAME, 0, "DirEntry", x),
+            FSValue::InlineExtent(x) =>
+                serializer.serialize_newtype_variant(NAME, 2, "InlineExtent",
+                                                     x),
+            FSValue::BlobExtent(x) =>
+                serializer.serialize_newtype_variant(NAME, 3, "BlobExtent", x),
+            FSValue::ExtAttr(x) =>
+                serializer.serialize_newtype_variant(NAME, 4, "ExtAttr", x),
+            FSValue::ExtAttrs(x) =>
+                serializer.serialize_newtype_variant(NAME, 5, "ExtAttrs", x),
+            FSValue::DirEntries(x) =>
+                serializer.serialize_newtype_variant(NAME, 6, "DirEntries", x),
+            FSValue::Property(x) =>
+                serializer.serialize_newtype_variant(NAME, 7, "Property", x),
+            FSValue::DyingInode(x) =>
+                serializer.serialize_newtype_variant(NAME, 8, "DyingInode", x),
+            FSValue::Inode(x) =>
+                serializer.serialize_newtype_variant(NAME, 9, "Inode", x),
+            FSValue::DittoExtent(x) =>
+                serializer.serialize_newtype_variant(NAME, 10, "DittoExtent",
+                                                     x),
+            FSValue::Invalid =>
+                serializer.serialize_unit_variant(NAME, 11, "Invalid"),
+        }
+    }
+}
+
 impl FSValue {
     pub fn as_direntries(&self) -> Option<&Vec<Dirent>> {
         if let FSValue::DirEntries(direntries) = self {
@@ -958,6 +1145,8 @@ impl FSValue {
             Some(Extent::Inline(extent))
         } else if let FSValue::BlobExtent(extent) = self {
             Some(Extent::Blob(extent))
+        } else if let FSValue::DittoExtent(de) = self {
+            Some(Extent::Blob(&de.extent))
         } else {
             None
         }
@@ -979,6 +1168,22 @@ impl FSValue {
         }
     }
 
+    /// The RIDs of every blob that this value references, in no particular
+    /// order.
+    pub fn blob_rids(&self) -> Vec<RID> {
+        match self {
+            FSValue::BlobExtent(be) => vec![be.rid],
+            FSValue::DittoExtent(de) => vec![de.extent.rid, de.ditto],
+            FSValue::ExtAttr(ExtAttr::Blob(xattr)) => vec![xattr.extent.rid],
+            FSValue::ExtAttrs(v) => v.iter()
+                .filter_map(|xattr| match xattr {
+                    ExtAttr::Blob(be) => Some(be.extent.rid),
+                    ExtAttr::Inline(_) => None
+                }).collect(),
+            _ => Vec::new()
+        }
+    }
+
     //pub fn as_property(&mut self) -> Option<&mut Property> {
         //if let FSValue::Property(ref mut prop) = self {
             //Some(prop)
@@ -997,6 +1202,9 @@ impl FSValue {
         where D: DML + 'static, D::Addr: 'static, K: Key
     {
         match self {
+            FSValue::InlineExtent(ie) if ie.copies > 1 => {
+                FSValueFlush::De(ie.flush_ditto(k, dml, txg).boxed())
+            },
             FSValue::InlineExtent(ie) => {
                 FSValueFlush::Ie(ie.flush(k, dml, txg))
             },
@@ -1030,6 +1238,15 @@ impl FSValue {
         }
     }
 
+    /// The RID of this extent's ditto copy, if it has one
+    pub fn ditto(&self) -> Option<RID> {
+        if let FSValue::DittoExtent(de) = self {
+            Some(de.ditto)
+        } else {
+            None
+        }
+    }
+
     /// Construct a new FSValue::Inode object
     pub fn inode(inode: Inode) -> Self {
         Self::Inode(Box::new(inode))
@@ -1047,8 +1264,10 @@ impl FSValue {
     /// bytes?
     pub fn stat_space(&self) -> i64 {
         match self {
-            FSValue::InlineExtent(ie) => ie.len() as i64,
+            FSValue::InlineExtent(ie) => ie.stat_space(),
             FSValue::BlobExtent(be) => be.lsize.into(),
+            FSValue::DittoExtent(de) =>
+                i64::from(DittoExtent::COPIES) * i64::from(de.extent.lsize),
             _ => 0
         }
     }
@@ -1092,6 +1311,13 @@ impl Value for FSValue {
             FSValue::BlobExtent(be) => {
                 dml.delete(&checked_transmute(be.rid), txg).boxed()
             },
+            FSValue::DittoExtent(de) => {
+                future::try_join(
+                    dml.delete(&checked_transmute(de.extent.rid), txg),
+                    dml.delete(&checked_transmute(de.ditto), txg)
+                ).map_ok(drop)
+                .boxed()
+            },
             FSValue::ExtAttr(ExtAttr::Blob(xattr)) => {
                 dml.delete(&checked_transmute(xattr.extent.rid), txg).boxed()
             },
@@ -1120,6 +1346,18 @@ impl Value for FSValue {
                     FSValue::InlineExtent(InlineExtent::new(Arc::new(*dbs)))
                 ).boxed()
             }
+            FSValue::DittoExtent(de) => {
+                // The ditto copy is identical, so there's no need to read it
+                let pfut = dml.pop::<DivBufShared, DivBuf>(
+                    &checked_transmute(de.extent.rid), txg);
+                let dfut = dml.delete(&checked_transmute(de.ditto), txg);
+                future::try_join(pfut, dfut)
+                .map_ok(move |(dbs, _)| {
+                    let ie = InlineExtent::new(Arc::new(*dbs))
+                        .with_copies(DittoExtent::COPIES);
+                    FSValue::InlineExtent(ie)
+                }).boxed()
+            }
             FSValue::ExtAttr(ea) => {
                 ea.dpop(dml, txg).map_ok(FSValue::ExtAttr).boxed()
             },
@@ -1175,6 +1413,7 @@ impl Value for FSValue {
 #[pin_project(project = FSValueFlushProj)]
 pub enum FSValueFlush<K: Key> {
     Ie(#[pin] InlineExtentFlush<K>),
+    De(#[pin] Pin<Box<dyn Future<Output=Result<(K, FSValue)>> + Send + 'static>>),
     // The ExtAttr and ExtAttrs types could be unboxed for better performance,
     // but they aren't nearly as common as InlineExtents.
     Ea(#[pin] Pin<Box<dyn Future<Output=Result<(K, FSValue)>> + Send + 'static>>),
@@ -1187,6 +1426,7 @@ impl<K: Key> Future for FSValueFlush<K> {
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
         match self.as_mut().project() {
             FSValueFlushProj::Ie(fut) => fut.poll(cx),
+            FSValueFlushProj::De(fut) => fut.poll(cx),
             FSValueFlushProj::Ea(fut) => fut.poll(cx),
             FSValueFlushProj::Eav(fut) => fut.poll(cx),
         }
@@ -1224,6 +1464,12 @@ fn fskey_typical_size() {
     assert_eq!(v.len(), FSKey::TYPICAL_SIZE);
 }
 
+#[test]
+fn fskey_with_object() {
+    let fsk = FSKey::new(5, ObjKey::Extent(0x1234));
+    assert_eq!(FSKey::new(7, ObjKey::Extent(0x1234)), fsk.with_object(7));
+}
+
 #[test]
 fn fsvalue_typical_size() {
     let fsv = FSValue::BlobExtent(BlobExtent{
@@ -1234,6 +1480,45 @@ fn fsvalue_typical_size() {
     assert_eq!(FSValue::TYPICAL_SIZE, v.len());
 }
 
+fn inode() -> Inode {
+    Inode {
+        size: 4096,
+        bytes: 4096,
+        nlink: 1,
+        flags: 0,
+        atime: Timespec::new(1, 2),
+        mtime: Timespec::new(3, 4),
+        ctime: Timespec::new(5, 6),
+        birthtime: Timespec::new(7, 8),
+        uid: 1000,
+        gid: 1000,
+        perm: 0o644,
+        file_type: FileType::Reg(17),
+        change: 42
+    }
+}
+
+#[test]
+fn fsvalue_inode_roundtrip() {
+    let fsv = FSValue::inode(inode());
+    let v: Vec<u8> = bincode::serialize(&fsv).unwrap();
+    let fsv2: FSValue = bincode::deserialize(&v).unwrap();
+    assert_eq!(fsv, fsv2);
+}
+
+/// Inodes written before the change counter existed should still be readable
+#[test]
+fn fsvalue_legacy_inode() {
+    let i = inode();
+    // The legacy Inode's variant index was 1
+    let legacy = (1u32, i.size, i.bytes, i.nlink, i.flags, i.atime, i.mtime,
+        i.ctime, i.birthtime, i.uid, i.gid, i.perm, i.file_type.clone());
+    let v: Vec<u8> = bincode::serialize(&legacy).unwrap();
+    let fsv: FSValue = bincode::deserialize(&v).unwrap();
+    let expected = Inode { change: 0, ..i };
+    assert_eq!(fsv.as_inode(), Some(&expected));
+}
+
 /// Print the size of every FSValue variant.  Not really a test, but useful for
 /// debugging.
 #[test]
@@ -1247,6 +1532,7 @@ fn fsvalue_variant_size() {
     println!("DirEntries:   {} bytes", mem::size_of::<Vec<Dirent>>());
     println!("Property:     {} bytes", mem::size_of::<Property>());
     println!("DyingInode:   {} bytes", mem::size_of::<DyingInode>());
+    println!("DittoExtent:  {} bytes", mem::size_of::<DittoExtent>());
 }
 
 /// Long InlineExtAttrs should be converted to BlobExtAttrs during flush
@@ -1316,6 +1602,44 @@ fn fsvalue_flush_inline_extent_long() {
     }
 }
 
+/// With copies=2, long InlineExtents should become DittoExtents
+#[test]
+fn fsvalue_flush_inline_extent_ditto() {
+    let rid = RID(999);
+    let ditto = RID(1000);
+    let mut idml = IDML::default();
+    idml.expect_put_ditto()
+        .once()
+        .withf(|cacheable: &DivBufShared, _compression, _txg| {
+            cacheable.len() == BYTES_PER_LBA
+        }).returning(move |_, _, _| future::ok((rid, ditto)).boxed());
+    let txg = TxgT(0);
+
+    let data = Arc::new(DivBufShared::from(vec![42u8; BYTES_PER_LBA]));
+    let ile = InlineExtent::new(data).with_copies(2);
+    let unflushed = FSValue::InlineExtent(ile);
+    assert_eq!(unflushed.stat_space(), 2 * BYTES_PER_LBA as i64);
+    let flushed = unflushed.flush(42u32, &idml, txg)
+        .now_or_never().unwrap()
+        .unwrap();
+
+    let extent = BlobExtent{lsize: BYTES_PER_LBA as u32, rid};
+    let expected = FSValue::DittoExtent(DittoExtent{extent, ditto});
+    assert_eq!(flushed.1, expected);
+    assert_eq!(flushed.1.ditto(), Some(ditto));
+    assert_eq!(flushed.1.blob_rids(), vec![rid, ditto]);
+    assert_eq!(flushed.1.stat_space(), 2 * BYTES_PER_LBA as i64);
+}
+
+#[test]
+fn fsvalue_ditto_extent_roundtrip() {
+    let extent = BlobExtent{lsize: 0xdead_beef, rid: RID(42)};
+    let fsv = FSValue::DittoExtent(DittoExtent{extent, ditto: RID(43)});
+    let v: Vec<u8> = bincode::serialize(&fsv).unwrap();
+    let fsv2: FSValue = bincode::deserialize(&v).unwrap();
+    assert_eq!(fsv, fsv2);
+}
+
 /// Short InlineExtents should be left in the B+Tree, not turned into blobs
 #[test]
 fn fsvalue_flush_inline_extent_short() {
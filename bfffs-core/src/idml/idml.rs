@@ -4,36 +4,142 @@ use crate::{
     dml::*,
     ddml::*,
     cache::{self, Cache, Cacheable, CacheRef, Key},
+    capacity::ClusterShape,
+    feature::{Feature, Features},
     label::*,
+    load_monitor::LoadMonitor,
+    memory::MemoryBudget,
+    pool::ZoneStats,
+    pool_property::PoolProperty,
     tree::TreeOnDisk,
     types::*,
+    util::BYTES_PER_LBA,
+    vdev::LeafStatus,
+    vdev_block::VdevLeaf,
     writeback::{Credit, WriteBack}
 };
-use divbuf::DivBufShared;
+use divbuf::{DivBuf, DivBufShared};
 use futures::{
-    Future, FutureExt, Stream, StreamExt, TryFutureExt, TryStreamExt, future
+    Future,
+    FutureExt,
+    Stream,
+    StreamExt,
+    TryFutureExt,
+    TryStreamExt,
+    future,
+    stream
 };
 use futures_locks::{RwLock, RwLockReadFut};
 #[cfg(test)] use mockall::mock;
 use serde_derive::{Deserialize, Serialize};
 use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
     io,
     pin::Pin,
     sync::{
         atomic::{AtomicU64, Ordering},
         Arc, Mutex
     },
+    time::Instant
 };
+use tokio::time::sleep;
 use tracing::instrument;
 use tracing_futures::Instrument;
 use super::{DTree, RidtEntry};
 
+#[cfg(not(test))] use crate::cluster::Cluster;
+#[cfg(test)] use crate::cluster::MockCluster as Cluster;
+
+/// A ditto copy's RID is the same as the original record's, but with this bit
+/// set, so either can be found from the other.  Ordinary RIDs are allocated
+/// sequentially, and will never get this high.
+const DITTO_BIT: u64 = 1 << 63;
+
+/// Return the RID of `rid`'s ditto copy, or of the original record if `rid` is
+/// itself a ditto copy.  The partner might not exist.
+fn ditto_partner(rid: RID) -> RID {
+    RID(rid.0 ^ DITTO_BIT)
+}
+
+/// Per-RID locks.
+///
+/// The cleaner relocates records while other tasks may be reading or freeing
+/// them.  Any operation that looks up a record's RIDT entry and then acts on
+/// its DRP must hold that RID's lock, so that readers never fetch from a DRP
+/// that has been freed, and a record can't be freed while the cleaner is in
+/// the midst of moving it.
+#[derive(Clone, Default)]
+struct RidLocks(
+    /// Map of each locked RID to its lock and the number of tasks that hold or
+    /// await it.  Entries are removed when no task needs them anymore.
+    Arc<Mutex<HashMap<RID, (futures_locks::Mutex<()>, usize)>>>
+);
+
+impl RidLocks {
+    /// Acquire the lock for `rid`.  It will be released when the returned
+    /// guard is dropped.
+    fn lock(&self, rid: RID) -> impl Future<Output=RidGuard> + Send {
+        let mut map = self.0.lock().unwrap();
+        let entry = map.entry(rid)
+            .or_insert_with(|| (futures_locks::Mutex::new(()), 0));
+        entry.1 += 1;
+        let ticket = RidTicket{locks: self.0.clone(), rid};
+        entry.0.lock()
+            .map(move |guard| RidGuard{_guard: guard, _ticket: ticket})
+    }
+}
+
+/// Keeps a `RidLocks` entry alive.  Dropping it, even before the lock is
+/// acquired, releases the entry.
+struct RidTicket {
+    locks: Arc<Mutex<HashMap<RID, (futures_locks::Mutex<()>, usize)>>>,
+    rid: RID
+}
+
+impl Drop for RidTicket {
+    fn drop(&mut self) {
+        let mut map = self.locks.lock().unwrap();
+        let entry = map.get_mut(&self.rid).unwrap();
+        entry.1 -= 1;
+        if entry.1 == 0 {
+            map.remove(&self.rid);
+        }
+    }
+}
+
+/// Exclusive access to a single RID.
+struct RidGuard {
+    // NB: field order matters.  The lock must be released before the ticket.
+    _guard: futures_locks::MutexGuard<()>,
+    _ticket: RidTicket
+}
+
+/// Reorder `records` so that consecutive records lie on different clusters,
+/// as far as possible, by taking one record from each cluster in turn.
+fn round_robin(records: Vec<(RID, DRP)>) -> Vec<(RID, DRP)> {
+    let mut out = Vec::with_capacity(records.len());
+    let mut queues = BTreeMap::<ClusterT, VecDeque<(RID, DRP)>>::new();
+    for (rid, drp) in records.into_iter() {
+        queues.entry(drp.pba().cluster).or_default().push_back((rid, drp));
+    }
+    while !queues.is_empty() {
+        queues.retain(|_cluster, q| {
+            out.push(q.pop_front().unwrap());
+            !q.is_empty()
+        });
+    }
+    out
+}
+
 /// Indirect Data Management Layer for a single `Pool`
 pub struct IDML {
     cache: Arc<Mutex<Cache>>,
 
     ddml: Arc<DDML>,
 
+    /// Decides how fast background work like scrubbing and cleaning may go
+    load: Arc<LoadMonitor>,
+
     /// Holds the next RID to allocate.  They are never reused.
     next_rid: AtomicU64,
 
@@ -55,6 +161,10 @@ pub struct IDML {
     // can be 'static
     ridt: Arc<DTree<RID, RidtEntry>>,
 
+    /// Serializes the cleaner's record moves with other accesses to the same
+    /// records.
+    rid_locks: RidLocks,
+
     /// The IDML is the owner of the WriteBack tracker
     writeback: WriteBack
 }
@@ -63,6 +173,13 @@ pub struct IDML {
 // instead by integration tests.
 #[cfg_attr(test, allow(unused))]
 impl<'a> IDML {
+    /// Grow the pool with a new cluster.  See [`Pool::add_cluster`].
+    ///
+    /// [`Pool::add_cluster`]: crate::pool::Pool::add_cluster
+    pub fn add_cluster(&self, cluster: Cluster) -> Result<()> {
+        self.ddml.add_cluster(cluster)
+    }
+
     pub fn borrow_credit(&self, size: usize)
         -> Pin<Box<dyn Future<Output=Credit> + Send>>
     {
@@ -168,6 +285,22 @@ impl<'a> IDML {
         })
     }
 
+    /// Take a checkpoint of the pool.
+    ///
+    /// Must be called with the txg lock held, after both labels have been
+    /// written for `txg`.  Afterwards, the first label must be rewritten so
+    /// it will record the checkpoint.
+    pub fn checkpoint(&self, txg: TxgT)
+        -> impl Future<Output=Result<()>> + Send
+    {
+        self.ddml.checkpoint(txg)
+    }
+
+    /// Transaction group of the pool's checkpoint, if it has one
+    pub fn checkpoint_txg(&self) -> Option<TxgT> {
+        self.ddml.checkpoint_txg()
+    }
+
     /// Foreground Tree consistency check.
     ///
     /// Checks that all DTrees are consistent and satisfy their invariants.
@@ -183,9 +316,39 @@ impl<'a> IDML {
         .map_ok(|(x, y, z)| x && y && z)
     }
 
+    /// Compare every RIDT entry's reference count to `refs`.
+    ///
+    /// `refs` must hold the number of references to each indirect record that
+    /// were actually found by walking every tree that may hold them.  Prints
+    /// any discrepancies to stderr.
+    ///
+    /// # Returns
+    ///
+    /// `true` if every reference count was correct, `false` otherwise.
+    pub async fn check_refcounts(&self, mut refs: BTreeMap<RID, u64>)
+        -> Result<bool>
+    {
+        let passed = self.ridt.range(..)
+        .try_fold(true, |passed, (rid, entry)| {
+            let found = refs.remove(&rid).unwrap_or(0);
+            if found != entry.refcount {
+                eprintln!(concat!("Indirect block {} has reference count {} ",
+                    "but {} references"), rid, entry.refcount, found);
+            }
+            future::ok(passed && found == entry.refcount)
+        }).await?;
+        for (rid, found) in refs.iter() {
+            eprintln!("Indirect block {} has {} references but no RIDT entry",
+                rid, found);
+        }
+        Ok(passed && refs.is_empty())
+    }
+
     /// Clean `zone` by moving all of its records to other zones.
+    ///
+    /// The records will be moved into zones of the given `temp`erature.
     #[tracing::instrument(skip(self))]
-    pub fn clean_zone(&self, zone: ClosedZone, txg: TxgT)
+    pub fn clean_zone(&self, zone: ClosedZone, temp: Temperature, txg: TxgT)
         -> impl Future<Output=Result<()>> + Send
     {
         // Outline:
@@ -201,6 +364,8 @@ impl<'a> IDML {
         let ridt2 = self.ridt.clone();
         let ridt3 = self.ridt.clone();
         let ddml2 = self.ddml.clone();
+        let load2 = self.load.clone();
+        let rid_locks2 = self.rid_locks.clone();
         #[cfg(debug_assertions)]
         let ddml3 = self.ddml.clone();
         #[cfg(debug_assertions)]
@@ -211,13 +376,17 @@ impl<'a> IDML {
         // 1, so as not to interfere too much with foreground tasks
         self.list_indirect_records(&zone)
         .try_for_each(move |record| {
+            // Each move is one read and one write
+            load2.record_background(2);
             IDML::move_record(&cache2, ridt2.clone(), alloct2.clone(), &ddml2,
-                record, txg)
-            .map_ok(move |drp| {
+                &rid_locks2, record, temp, txg)
+            .map_ok(move |odrp| {
                 // We shouldn't have moved the record into the same zone
-                debug_assert!(drp.pba().cluster != pba.cluster ||
-                              drp.pba().lba < pba.lba ||
-                              drp.pba().lba >= pba.lba + total_blocks);
+                if let Some(drp) = odrp {
+                    debug_assert!(drp.pba().cluster != pba.cluster ||
+                                  drp.pba().lba < pba.lba ||
+                                  drp.pba().lba >= pba.lba + total_blocks);
+                }
             })
         }).and_then(move |_| {
             let txgs2 = zone.txgs.clone();
@@ -249,7 +418,16 @@ impl<'a> IDML {
         let transaction = RwLock::new(TxgT::from(0));
         // TODO: apply configurable writeback size
         let writeback = WriteBack::limitless();
-        IDML{cache, ddml, next_rid, transaction, alloct, ridt, writeback}
+        let rid_locks = RidLocks::default();
+        let load = Arc::new(LoadMonitor::default());
+        IDML{cache, ddml, load, next_rid, transaction, alloct, ridt, rid_locks,
+             writeback}
+    }
+
+    /// Forget the pool's checkpoint.  Zones that it protects won't be erased
+    /// until [`release_checkpoint`](#method.release_checkpoint) is called.
+    pub fn discard_checkpoint(&self) {
+        self.ddml.discard_checkpoint()
     }
 
     /// Drop all data from the cache, for testing or benchmarking purposes
@@ -257,6 +435,18 @@ impl<'a> IDML {
         self.cache.lock().unwrap().drop_cache()
     }
 
+    /// Add a reference to an existing record, so it may be shared by another
+    /// owner.  Each reference must be separately [`delete`](DML::delete)d.
+    pub async fn dup(&self, rid: RID, txg: TxgT) -> Result<()> {
+        let _rid_guard = self.rid_locks.lock(rid).await;
+        let mut entry = self.ridt.get(rid).await?
+            .ok_or(Error::ENOENT)?;
+        entry.inc_refcount(rid);
+        self.ridt.clone().insert(rid, entry, txg, Credit::null())
+            .await
+            .map(|old| assert!(old.is_some()))
+    }
+
     pub async fn dump_alloct(&self, f: &mut dyn io::Write) -> Result<()>
     {
         self.alloct.dump(f).await
@@ -267,6 +457,35 @@ impl<'a> IDML {
         self.ridt.dump(f).await
     }
 
+    /// Enable an on-disk format feature.  It will be recorded in the next
+    /// label written.  Returns `true` if it wasn't already enabled.
+    pub fn enable_feature(&self, feature: Feature) -> bool {
+        self.ddml.enable_feature(feature)
+    }
+
+    /// Status of every leaf device, including its lifetime I/O error counts
+    pub fn leaf_status(&self) -> Vec<LeafStatus> {
+        self.ddml.leaf_status()
+    }
+
+    /// Bring a removed or faulted leaf device back online.  See
+    /// [`Pool::online`].
+    ///
+    /// [`Pool::online`]: crate::pool::Pool::online
+    pub async fn online(&self, leaf: VdevLeaf) -> Result<()> {
+        self.ddml.online(leaf).await
+    }
+
+    /// Describe the pool's layout and usage, for capacity planning
+    pub fn shape(&self) -> Vec<ClusterShape> {
+        self.ddml.shape()
+    }
+
+    /// On-disk format features enabled on the pool
+    pub fn features(&self) -> Features {
+        self.ddml.features()
+    }
+
     /// Flush the IDML's data to disk
     ///
     /// `idx`, if provided, is the index of the label to sync to disk.  If not
@@ -286,6 +505,61 @@ impl<'a> IDML {
         }
     }
 
+    /// Read the `len` bytes at `offset` within a record, bypassing the cache.
+    ///
+    /// If the record is already cached, they'll be copied from there.
+    /// Otherwise, as little of the record as possible will be read from disk.
+    /// See [`DDML::get_range`].
+    #[instrument(skip(self))]
+    pub fn get_range(&self, rid: RID, offset: usize, len: usize)
+        -> Pin<Box<dyn Future<Output=Result<DivBuf>> + Send>>
+    {
+        let cached = self.cache.lock().unwrap().get_ref(&Key::Rid(rid));
+        if let Some(cacheref) = cached {
+            let db = cacheref.downcast::<DivBuf>().unwrap();
+            return future::ok(db.slice(offset, offset + len)).boxed();
+        }
+        let ddml2 = self.ddml.clone();
+        let ridt2 = self.ridt.clone();
+        // Hold the RID's lock until the read completes, so the cleaner can't
+        // free the record's old location first.
+        let lock_fut = self.rid_locks.lock(rid);
+        async move {
+            let _rid_guard = lock_fut.await;
+            let entry = ridt2.get(rid).await?
+                .ok_or(Error::ENOENT)?;
+            ddml2.get_range(&entry.drp, offset, len).await
+        }.in_current_span()
+        .boxed()
+    }
+
+    /// Like [`DML::get`], but don't admit the record to the cache.
+    ///
+    /// If the record is already cached, the cached copy will be returned
+    /// without changing its position in the replacement order.
+    #[instrument(skip(self))]
+    pub fn get_uncached<T: Cacheable, R: CacheRef>(&self, rid: RID)
+        -> Pin<Box<dyn Future<Output=Result<Box<R>>> + Send>>
+    {
+        let cached = self.cache.lock().unwrap().get_ref(&Key::Rid(rid));
+        if let Some(cacheref) = cached {
+            return future::ok(cacheref.downcast::<R>().unwrap()).boxed();
+        }
+        let ddml2 = self.ddml.clone();
+        let ridt2 = self.ridt.clone();
+        // Hold the RID's lock until the read completes, so the cleaner can't
+        // free the record's old location first.
+        let lock_fut = self.rid_locks.lock(rid);
+        async move {
+            let _rid_guard = lock_fut.await;
+            let entry = ridt2.get(rid).await?
+                .ok_or(Error::ENOENT)?;
+            let cacheable = ddml2.get_direct::<T>(&entry.drp).await?;
+            Ok(cacheable.make_ref().downcast::<R>().unwrap())
+        }.in_current_span()
+        .boxed()
+    }
+
     #[tracing::instrument(skip(self))]
     pub fn list_closed_zones(&self)
         -> impl Iterator<Item=ClosedZone> + Send
@@ -307,6 +581,14 @@ impl<'a> IDML {
             .map_ok(|(_pba, rid)| rid)
     }
 
+    /// Find where an indirect record is stored.
+    ///
+    /// Returns its address and allocated size, or `None` if it doesn't exist.
+    pub async fn locate(&self, rid: RID) -> Result<Option<(PBA, LbaT)>> {
+        self.ridt.get(rid).await
+            .map(|o| o.map(|entry| (entry.drp.pba(), entry.drp.asize())))
+    }
+
     /// Open an existing `IDML`
     ///
     /// # Parameters
@@ -316,14 +598,19 @@ impl<'a> IDML {
     /// * `writeback_size`: Maximum amount of cached dirty data in bytes.
     /// * `label_reader`:   A `LabelReader` that has already consumed all labels
     ///                     prior to this layer.
+    ///
+    /// Fails with `EOPNOTSUPP` if either of its trees was written in a newer
+    /// format.
     pub fn open(
         ddml: Arc<DDML>,
         cache: Arc<Mutex<Cache>>,
         writeback_size: usize,
         mut label_reader: LabelReader,
-    ) -> (Self, LabelReader)
+    ) -> Result<(Self, LabelReader)>
     {
         let l: Label = label_reader.deserialize().unwrap();
+        l.alloct.check_version()?;
+        l.ridt.check_version()?;
         let alloct = Arc::new(DTree::open(ddml.clone(), true, l.alloct));
         let ridt = Arc::new(DTree::open(ddml.clone(), true, l.ridt));
         let transaction = RwLock::new(l.txg);
@@ -332,20 +619,27 @@ impl<'a> IDML {
         let idml = IDML{
             cache,
             ddml,
+            load: Arc::new(LoadMonitor::default()),
             next_rid,
             transaction,
             alloct,
             ridt,
+            rid_locks: RidLocks::default(),
             writeback
         };
-        (idml, label_reader)
+        Ok((idml, label_reader))
     }
 
     /// Rewrite the given direct Record and update its metadata.
+    ///
+    /// Returns the record's new address, or `None` if the record was freed
+    /// before it could be moved.
+    #[allow(clippy::too_many_arguments)]
     fn move_record(cache: &Arc<Mutex<Cache>>, ridt: Arc<DTree<RID, RidtEntry>>,
                    alloct: Arc<DTree<PBA, RID>>,
-                   ddml: &Arc<DDML>, rid: RID, txg: TxgT)
-        -> impl Future<Output=Result<DRP>> + Send
+                   ddml: &Arc<DDML>, rid_locks: &RidLocks, rid: RID,
+                   temp: Temperature, txg: TxgT)
+        -> impl Future<Output=Result<Option<DRP>>> + Send
     {
         // Even if the cache contains the target record, we must also do an RIDT
         // lookup because we're going to rewrite the RIDT
@@ -353,11 +647,41 @@ impl<'a> IDML {
         let ddml2 = ddml.clone();
         let ddml3 = ddml.clone();
         let ridt2 = ridt.clone();
-        ridt.get(rid)
-            .and_then(move |v| {
-                let mut entry = v.expect(
-                    "Inconsistency in alloct.  Entry not found in RIDT");
+        let rid_locks2 = rid_locks.clone();
+        // If the record has a ditto copy, lock that too, lowest RID first, so
+        // that they can't both be moved at once.
+        let partner = ditto_partner(rid);
+        let (first, second) = if rid < partner {
+            (rid, partner)
+        } else {
+            (partner, rid)
+        };
+        rid_locks.lock(first)
+        .then(move |guard1| {
+            rid_locks2.lock(second).map(move |guard2| (guard1, guard2))
+        }).then(move |rid_guards| {
+            future::try_join(ridt.get(rid), ridt.get(partner))
+            .and_then(move |(v, pv)| {
+                let mut entry = match v {
+                    Some(entry) => entry,
+                    // The record was freed after the cleaner listed it, but
+                    // before we could lock it.  Nothing to move.
+                    None => return future::ok(None).boxed()
+                };
                 let compressed = entry.drp.is_compressed();
+                // Never move a record into the same zone as its ditto copy.
+                // Open zones never mix temperatures, so if the copy lies in an
+                // open zone of the requested temperature, use another.
+                let partner_temp = pv.and_then(|pentry| {
+                    ddml2.open_zone_temperature(pentry.drp.pba())
+                });
+                let temp = if partner_temp != Some(temp) {
+                    temp
+                } else if temp == Temperature::Cold {
+                    Temperature::Hot
+                } else {
+                    Temperature::Cold
+                };
 
                 let cache_miss = || {
                     // Cache miss: get the old record, write the new one, then
@@ -369,13 +693,14 @@ impl<'a> IDML {
                     // works perfectly fine with put_direct.
                     //
                     // Read the record as though it were uncompressed, to avoid
-                    // the CPU cost of decompression/compression.
+                    // the CPU cost of decompression/compression.  That also
+                    // preserves its chunk checksums, if any.
                     let drp_uc = entry.drp.as_uncompressed();
                     let ddml4 = ddml2.clone();
                     let fut = ddml2.get_direct::<DivBufShared>(&drp_uc)
                     .and_then(move |dbs| {
                         let db = dbs.try_const().unwrap();
-                        ddml4.put_direct(&db, Compression::None, txg)
+                        ddml4.copy_direct(&db, temp, txg)
                         .and_then(move |drp| {
                             ddml4.delete_direct(&entry.drp, txg)
                             .map_ok(move |_| drp.into_compressed(&entry.drp))
@@ -395,7 +720,8 @@ impl<'a> IDML {
                         // NB: if BFFFS ever implements deferred zone erase,
                         // then we can write and delete in parallel.
                         let db = t.serialize();
-                        let fut = ddml2.put_direct(&db, Compression::None, txg)
+                        let fut = ddml2.put_direct(&db, Compression::None,
+                                                   temp, txg)
                         .and_then(move |drp| {
                             ddml3.delete_direct(&entry.drp, txg)
                             .map_ok(move |_| drp)
@@ -414,20 +740,157 @@ impl<'a> IDML {
                     let alloct_fut = alloct.insert(drp.pba(), rid, txg,
                                                    Credit::null());
                     future::try_join(ridt_fut, alloct_fut)
-                    .map_ok(move |_| drp)
-                })
+                    .map_ok(move |_| {
+                        drop(rid_guards);
+                        Some(drp)
+                    })
+                }).boxed()
             })
+        })
     }
 
     pub fn pool_name(&self) -> &str {
         self.ddml.pool_name()
     }
 
+    /// Verify the checksum of every indirect record.  Prints any corrupt or
+    /// unreadable records to stderr.
+    ///
+    /// Up to `inflight` records will be read at once.  Reads are dispatched to
+    /// the clusters in round-robin order, so scrub throughput scales with the
+    /// number of devices rather than being limited by a single one.
+    ///
+    /// # Returns
+    ///
+    /// `true` if every record verified, `false` otherwise.
+    #[tracing::instrument(skip(self))]
+    pub async fn scrub(&self, inflight: usize) -> Result<bool> {
+        let inflight = inflight.max(1);
+        // Sort the RIDT's entries by cluster in windows a few times larger
+        // than the number of records in flight.
+        let window = inflight * 4;
+        let ddml = self.ddml.clone();
+        let load = self.load.clone();
+        let ridt = self.ridt.clone();
+        let rid_locks = self.rid_locks.clone();
+        self.ridt.range(..)
+            .map_ok(|(rid, entry)| (rid, entry.drp))
+            .chunks(window)
+            .map(|v| v.into_iter().collect::<Result<Vec<_>>>())
+            .map_ok(|v| stream::iter(round_robin(v).into_iter().map(Ok)))
+            .try_flatten()
+            .map_ok(move |(rid, drp)| {
+                let bytes = drp.asize() * BYTES_PER_LBA as u64;
+                let ddml2 = ddml.clone();
+                let ridt2 = ridt.clone();
+                let rid_locks2 = rid_locks.clone();
+                IDML::throttle(ddml.clone(), load.clone(), bytes, 1)
+                .then(move |_| {
+                    IDML::scrub_record(ddml2, ridt2, rid_locks2, rid)
+                })
+            }).try_buffer_unordered(inflight)
+            .try_fold(true, |passed, ok| future::ok(passed & ok))
+            .await
+    }
+
+    /// Erase zones kept only for a discarded checkpoint.  Call this only after
+    /// the labels no longer record the checkpoint.
+    pub fn release_checkpoint(&self)
+        -> impl Future<Output=Result<()>> + Send
+    {
+        self.ddml.release_checkpoint()
+    }
+
+    /// Move a single record into a zone of the given `temp`erature.
+    ///
+    /// Successively relocated records will be adjacent, as long as nothing
+    /// else writes to zones of that temperature in between.  Returns `false`
+    /// if the record no longer exists.
+    pub fn relocate(&self, rid: RID, temp: Temperature, txg: TxgT)
+        -> impl Future<Output=Result<bool>> + Send
+    {
+        // Each move is one read and one write
+        self.load.record_background(2);
+        IDML::move_record(&self.cache, self.ridt.clone(), self.alloct.clone(),
+            &self.ddml, &self.rid_locks, rid, temp, txg)
+        .map_ok(|odrp| odrp.is_some())
+    }
+
+    /// Verify a single indirect record's checksum.  Returns `true` if it
+    /// verified or no longer exists.
+    async fn scrub_record(ddml: Arc<DDML>, ridt: Arc<DTree<RID, RidtEntry>>,
+                          rid_locks: RidLocks, rid: RID) -> Result<bool>
+    {
+        // The cleaner may have moved or freed the record since we listed it.
+        // Lock it and look it up again.
+        let _guard = rid_locks.lock(rid).await;
+        let drp = match ridt.get(rid).await? {
+            Some(entry) => entry.drp,
+            None => return Ok(true)
+        };
+        match ddml.verify(drp).await {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                eprintln!("Indirect block {} at {:?}: {:?}", rid, drp.pba(), e);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Change the maximum throughput of background work, in bytes per
+    /// second, while the pool is busy with foreground I/O.  0 pauses
+    /// background work whenever the pool is busy.
+    pub fn set_background_rate(&self, background_rate: u64) {
+        self.load.set_background_rate(background_rate)
+    }
+
+    /// Change a pool property.  It takes effect immediately, and will be
+    /// recorded in the next label written.
+    /// Account for dirty data in a budget shared with other subsystems
+    pub fn set_memory_budget(&mut self, budget: MemoryBudget) {
+        self.writeback.set_memory_budget(budget)
+    }
+
+    pub fn set_pool_property(&self, prop: PoolProperty) {
+        self.ddml.set_pool_property(prop)
+    }
+
     /// Return approximately the usable storage space in LBAs.
     pub fn size(&self) -> LbaT {
         self.ddml.size()
     }
 
+    /// Wait until the pool is idle enough to do `bytes` worth of background
+    /// work that will issue `ios` I/O operations.
+    async fn throttle(ddml: Arc<DDML>, load: Arc<LoadMonitor>, bytes: u64,
+                      ios: u64)
+    {
+        loop {
+            match load.reserve(ddml.ops(), bytes, Instant::now()) {
+                Some(delay) => {
+                    if !delay.is_zero() {
+                        sleep(delay).await;
+                    }
+                    break;
+                }
+                None => sleep(load.retry_interval()).await
+            }
+        }
+        load.record_background(ios);
+    }
+
+    /// Wait until the pool is idle enough for a background task to process
+    /// `bytes` worth of data.
+    ///
+    /// Background work may proceed immediately while the pool is idle, but
+    /// is limited to the background rate while it's busy.
+    pub fn throttle_background(&self, bytes: u64)
+        -> impl Future<Output=()> + Send
+    {
+        // The I/O itself will be recorded by the IDML method that does it.
+        IDML::throttle(self.ddml.clone(), self.load.clone(), bytes, 0)
+    }
+
     /// Get a reference to the current transaction group.
     ///
     /// The reference will prevent the current transaction group from syncing,
@@ -441,6 +904,14 @@ impl<'a> IDML {
         self.ddml.used()
     }
 
+    /// Read the roots of the Allocation and RID tables, to verify that the
+    /// label references readable metadata.
+    pub async fn verify_roots(&self) -> Result<()> {
+        future::try_join(self.alloct.verify_root(), self.ridt.verify_root())
+            .await
+            .map(drop)
+    }
+
     /// Finish the current transaction group and start a new one.
     #[tracing::instrument(skip(self, f))]
     pub fn advance_transaction<B, F>(&self, f: F)
@@ -482,6 +953,45 @@ impl<'a> IDML {
     pub fn writeback_size(&self) -> usize {
         self.writeback.capacity()
     }
+
+    /// How many blocks have been written since the pool was opened?
+    pub fn written(&self) -> LbaT {
+        self.ddml.written()
+    }
+
+    /// Zone open/close statistics, summed across the whole pool
+    pub fn zone_stats(&self) -> ZoneStats {
+        self.ddml.zone_stats()
+    }
+}
+
+impl IDML {
+    /// Write a record to the DDML under `rid`, which must not already be in
+    /// use, without caching it.
+    fn put_indirect<T>(&self, cacheable: &T, compression: Compression,
+                       temp: Temperature, rid: RID, txg: TxgT)
+        -> impl Future<Output=Result<RID>> + Send
+        where T: Cacheable
+    {
+        let alloct2 = self.alloct.clone();
+        let ridt2 = self.ridt.clone();
+
+        self.ddml.put_direct(&cacheable.make_ref(), compression, temp, txg)
+        .and_then(move|drp| {
+            let alloct_fut = alloct2.insert(drp.pba(), rid, txg,
+                                            Credit::null());
+            let rid_entry = RidtEntry::new(drp);
+            let ridt_fut = ridt2.insert(rid, rid_entry, txg, Credit::null());
+            future::try_join(ridt_fut, alloct_fut)
+            .map_ok(move |(old_rid_entry, old_alloc_entry)| {
+                assert!(old_rid_entry.is_none(), "RID was not unique");
+                assert!(old_alloc_entry.is_none(), concat!(
+                    "Double allocate without free.  ",
+                    "DDML allocator leak detected!"));
+                rid
+            })
+        })
+    }
 }
 
 impl DML for IDML {
@@ -496,14 +1006,16 @@ impl DML for IDML {
         let alloct2 = self.alloct.clone();
         let ridt2 = self.ridt.clone();
         let rid = *ridp;
-        let fut = self.ridt.get(rid)
+        let ridt3 = self.ridt.clone();
+        let fut = self.rid_locks.lock(rid)
+        .then(move |rid_guard| {
+            ridt3.get(rid)
             .and_then(move |oentry| {
                 let mut entry = match oentry {
                     Some(e) => e,
                     None => panic!("Double delete detected for {rid:?}.")
                 };
-                entry.refcount -= 1;
-                if entry.refcount == 0 {
+                if entry.dec_refcount(rid) == 0 {
                     cache2.lock().unwrap().remove(&Key::Rid(rid));
                     let ddml_fut = ddml2.delete_direct(&entry.drp, txg);
                     let alloct_fut = alloct2.remove(entry.drp.pba(), txg,
@@ -520,7 +1032,11 @@ impl DML for IDML {
                         .map_ok(|old| assert!(old.is_some()));
                     ridt_fut.boxed()
                 }
-            });
+            }).map(move |r| {
+                drop(rid_guard);
+                r
+            })
+        });
         Box::pin(fut)
     }
 
@@ -537,14 +1053,26 @@ impl DML for IDML {
         cache::get_or_insert!(T, R, &self.cache, Key::Rid(rid),
             {
                 let ddml2 = self.ddml.clone();
-                self.ridt.get(rid)
+                let ridt2 = self.ridt.clone();
+                // Hold the RID's lock until the read completes, so the
+                // cleaner can't free the record's old location first.
+                self.rid_locks.lock(rid)
+                .then(move |rid_guard| {
+                    ridt2.get(rid)
                     .map(|r| match r {
                         Ok(None) => Err(Error::ENOENT),
                         Ok(Some(entry)) => Ok(entry),
                         Err(e) => Err(e)
                     }).and_then(move |entry| {
-                        ddml2.get_direct::<T>(&entry.drp)
-                    }).in_current_span()
+                        let drp = entry.drp;
+                        ddml2.get_direct::<T>(&drp)
+                        .inspect_err(move |error| tracing::warn!(?rid, ?drp,
+                            ?error, "Failed to read record"))
+                    }).map(move |r| {
+                        drop(rid_guard);
+                        r
+                    })
+                }).in_current_span()
             }
         )
     }
@@ -558,12 +1086,12 @@ impl DML for IDML {
         let ddml2 = self.ddml.clone();
         let alloct2 = self.alloct.clone();
         let ridt2 = self.ridt.clone();
-        let efut = self.ridt.get(rid);
+        let lock_fut = self.rid_locks.lock(rid);
         async move {
-            let mut entry = efut.await?
+            let _rid_guard = lock_fut.await;
+            let mut entry = ridt2.get(rid).await?
                 .ok_or(Error::ENOENT)?;
-            entry.refcount -= 1;
-            if entry.refcount == 0 {
+            if entry.dec_refcount(rid) == 0 {
                 let cacheval = cache2.lock().unwrap()
                     .remove(&Key::Rid(rid));
                 let bfut = if let Some(cacheable) = cacheval {
@@ -607,30 +1135,41 @@ impl DML for IDML {
         // TODO: spawn a separate task, for better parallelism.
         // Outline:
         // 1) Write to the DDML
-        // 2) Cache
-        // 3) Add entry to the RIDT
-        // 4) Add reverse entry to the AllocT
+        // 2) Add entry to the RIDT
+        // 3) Add reverse entry to the AllocT
+        // 4) Cache
         let cache2 = self.cache.clone();
-        let alloct2 = self.alloct.clone();
-        let ridt2 = self.ridt.clone();
         let rid = RID(self.next_rid.fetch_add(1, Ordering::Relaxed));
+        let temp = class_temperature(cacheable.class());
+        let fut = self.put_indirect(&cacheable, compression, temp, rid, txg)
+        .map_ok(move |rid| {
+            cache2.lock().unwrap()
+                .insert(Key::Rid(rid), Box::new(cacheable));
+            rid
+        });
+        Box::pin(fut)
+    }
 
-        let fut = self.ddml.put_direct(&cacheable.make_ref(), compression, txg)
-        .and_then(move|drp| {
-            let alloct_fut = alloct2.insert(drp.pba(), rid, txg,
-                                            Credit::null());
-            let rid_entry = RidtEntry::new(drp);
-            let ridt_fut = ridt2.insert(rid, rid_entry, txg, Credit::null());
-            future::try_join(ridt_fut, alloct_fut)
-            .map_ok(move |(old_rid_entry, old_alloc_entry)| {
-                assert!(old_rid_entry.is_none(), "RID was not unique");
-                assert!(old_alloc_entry.is_none(), concat!(
-                    "Double allocate without free.  ",
-                    "DDML allocator leak detected!"));
-                cache2.lock().unwrap()
-                    .insert(Key::Rid(rid), Box::new(cacheable));
-                rid
-            })
+    #[instrument(skip(self))]
+    fn put_ditto<T>(&self, cacheable: T, compression: Compression, txg: TxgT)
+        -> Pin<Box<dyn Future<Output=Result<(RID, RID)>> + Send>>
+        where T: Cacheable
+    {
+        // Open zones never mix temperatures, so writing the copy as cold data
+        // keeps it apart from the original.  And move_record keeps them apart
+        // after that.
+        let cache2 = self.cache.clone();
+        let rid = RID(self.next_rid.fetch_add(1, Ordering::Relaxed));
+        let ditto = ditto_partner(rid);
+        let temp = class_temperature(cacheable.class());
+        let pfut = self.put_indirect(&cacheable, compression, temp, rid, txg);
+        let dfut = self.put_indirect(&cacheable, compression, Temperature::Cold,
+                                     ditto, txg);
+        let fut = future::try_join(pfut, dfut)
+        .map_ok(move |_| {
+            cache2.lock().unwrap()
+                .insert(Key::Rid(rid), Box::new(cacheable));
+            (rid, ditto)
         });
         Box::pin(fut)
     }
@@ -647,7 +1186,7 @@ impl DML for IDML {
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-struct Label {
+pub(crate) struct Label {
     alloct:             TreeOnDisk<DRP>,
     next_rid:           u64,
     ridt:               TreeOnDisk<DRP>,
@@ -655,35 +1194,74 @@ struct Label {
     txg:                TxgT,
 }
 
+impl LabelLayer for Label {
+    type V0 = Self;
+}
+
 // LCOV_EXCL_START
 #[cfg(test)]
 mock!{
     pub IDML {
+        pub fn add_cluster(&self, cluster: Cluster) -> Result<()>;
         pub fn cache_size(&self) -> usize;
         pub fn borrow_credit(&self, size: usize)
             -> Pin<Box<dyn Future<Output=Credit> + Send>>;
+        pub fn checkpoint(&self, txg: TxgT)
+            -> Pin<Box<dyn Future<Output=Result<()>> + Send>>;
+        pub fn checkpoint_txg(&self) -> Option<TxgT>;
         pub fn check(&self) -> Pin<Box<dyn Future<Output=Result<bool>>>>;
-        pub fn clean_zone(&self, zone: ClosedZone, txg: TxgT)
+        pub fn check_refcounts(&self, refs: BTreeMap<RID, u64>)
+            -> Pin<Box<dyn Future<Output=Result<bool>> + Send>>;
+        pub fn clean_zone(&self, zone: ClosedZone, temp: Temperature,
+                          txg: TxgT)
             -> Pin<Box<dyn Future<Output=Result<()>> + Send>>;
         pub fn create(ddml: Arc<DDML>, cache: Arc<Mutex<Cache>>) -> Self;
+        pub fn discard_checkpoint(&self);
         pub fn drop_cache(&self);
+        pub fn dup(&self, rid: RID, txg: TxgT)
+            -> Pin<Box<dyn Future<Output=Result<()>> + Send>>;
         pub fn dump_alloct(&self, f: &mut dyn io::Write)
             -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
         pub fn dump_ridt(&self, f: &mut dyn io::Write)
             -> Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+        pub fn enable_feature(&self, feature: Feature) -> bool;
+        pub fn features(&self) -> Features;
         pub fn flush(&self, idx: Option<u32>, txg: TxgT)
             -> Pin<Box<dyn Future<Output=Result<()>> + Send>>;
+        pub fn get_range(&self, rid: RID, offset: usize, len: usize)
+            -> Pin<Box<dyn Future<Output=Result<DivBuf>> + Send>>;
+        pub fn get_uncached<T: Cacheable, R: CacheRef>(&self, rid: RID)
+            -> Pin<Box<dyn Future<Output=Result<Box<R>>> + Send>>;
+        pub fn leaf_status(&self) -> Vec<LeafStatus>;
         pub fn list_closed_zones(&self)
             -> impl Iterator<Item=ClosedZone> + Send;
+        pub fn locate(&self, rid: RID)
+            -> Pin<Box<dyn Future<Output=Result<Option<(PBA, LbaT)>>> + Send>>;
+        pub fn online(&self, leaf: VdevLeaf)
+            -> Pin<Box<dyn Future<Output=Result<()>> + Send>>;
         pub fn open(ddml: Arc<DDML>, cache: Arc<Mutex<Cache>>, wbs: usize,
                      mut label_reader: LabelReader) -> (Self, LabelReader);
         pub fn pool_name(&self) -> &str;
+        pub fn release_checkpoint(&self)
+            -> Pin<Box<dyn Future<Output=Result<()>> + Send>>;
+        pub fn relocate(&self, rid: RID, temp: Temperature, txg: TxgT)
+            -> Pin<Box<dyn Future<Output=Result<bool>> + Send>>;
+        pub fn scrub(&self, inflight: usize)
+            -> Pin<Box<dyn Future<Output=Result<bool>> + Send>>;
+        pub fn set_background_rate(&self, background_rate: u64);
+        pub fn set_memory_budget(&mut self, budget: MemoryBudget);
+        pub fn set_pool_property(&self, prop: PoolProperty);
+        pub fn shape(&self) -> Vec<ClusterShape>;
         pub fn size(&self) -> LbaT;
+        pub fn throttle_background(&self, bytes: u64)
+            -> Pin<Box<dyn Future<Output=()> + Send>>;
         // Return a static reference instead of a RwLockReadFut because it makes
         // the expectations easier to write
         pub fn txg(&self)
             -> Pin<Box<dyn Future<Output=&'static TxgT> + Send>>;
         pub fn used(&self) -> LbaT;
+        pub fn verify_roots(&self)
+            -> Pin<Box<dyn Future<Output=Result<()>> + Send>>;
         // advance_transaction is difficult to mock with Mockall, because f's
         // output is typically a chained future that is difficult to name.
         // Instead, we'll use special logic in advance_transaction and only mock
@@ -692,6 +1270,8 @@ mock!{
         pub fn write_label(&self, mut labeller: LabelWriter, txg: TxgT)
             -> Pin<Box<dyn Future<Output=Result<()>> + Send>>;
         pub fn writeback_size(&self) -> usize;
+        pub fn written(&self) -> LbaT;
+        pub fn zone_stats(&self) -> ZoneStats;
     }
     impl DML for IDML {
         type Addr = RID;
@@ -705,6 +1285,9 @@ mock!{
         fn put<T: Cacheable>(&self, cacheable: T, compression: Compression,
                                  txg: TxgT)
             -> Pin<Box<dyn Future<Output=Result<RID>> + Send>>;
+        fn put_ditto<T: Cacheable>(&self, cacheable: T,
+                                   compression: Compression, txg: TxgT)
+            -> Pin<Box<dyn Future<Output=Result<(RID, RID)>> + Send>>;
         fn repay(&self, credit: Credit);
         fn sync_all(&self, txg: TxgT)
             -> Pin<Box<dyn Future<Output=Result<()>> + Send>>;
@@ -770,6 +1353,26 @@ mod t {
         format!("{label:?}");
     }
 
+    /// Overflowing the refcount should saturate, and the saturated count
+    /// should stick.
+    #[test]
+    fn ridtentry_refcount_overflow() {
+        let rid = RID(42);
+        let mut entry = RidtEntry{drp: DRP::default(), refcount: u64::MAX};
+        entry.inc_refcount(rid);
+        assert_eq!(entry.refcount, u64::MAX);
+        assert_eq!(entry.dec_refcount(rid), u64::MAX);
+    }
+
+    /// Underflowing the refcount should report no remaining references
+    #[test]
+    fn ridtentry_refcount_underflow() {
+        let rid = RID(42);
+        let mut entry = RidtEntry{drp: DRP::default(), refcount: 1};
+        assert_eq!(entry.dec_refcount(rid), 0);
+        assert_eq!(entry.dec_refcount(rid), 0);
+    }
+
     #[test]
     fn ridtentry_typical_size() {
         let typical = RidtEntry::new(DRP::default());
@@ -777,6 +1380,56 @@ mod t {
                    bincode::serialized_size(&typical).unwrap() as usize);
     }
 
+    mod check_refcounts {
+        use super::*;
+
+        fn setup(rid: RID, refcount: u64) -> IDML {
+            let drp = DRP::random(Compression::None, 4096);
+            let cache = Cache::with_capacity(1_048_576);
+            let arc_ddml = Arc::new(mock_ddml());
+            let idml = IDML::create(arc_ddml, Arc::new(Mutex::new(cache)));
+            inject_record(&idml, rid, &drp, refcount);
+            idml
+        }
+
+        #[tokio::test]
+        async fn ok() {
+            let rid = RID(42);
+            let idml = setup(rid, 2);
+            let refs = BTreeMap::from([(rid, 2)]);
+
+            assert!(idml.check_refcounts(refs).await.unwrap());
+        }
+
+        /// A record is referenced, but not by anything in the RIDT
+        #[tokio::test]
+        async fn dangling() {
+            let rid = RID(42);
+            let idml = setup(rid, 2);
+            let refs = BTreeMap::from([(rid, 2), (RID(43), 1)]);
+
+            assert!(!idml.check_refcounts(refs).await.unwrap());
+        }
+
+        #[tokio::test]
+        async fn mismatch() {
+            let rid = RID(42);
+            let idml = setup(rid, 2);
+            let refs = BTreeMap::from([(rid, 1)]);
+
+            assert!(!idml.check_refcounts(refs).await.unwrap());
+        }
+
+        /// A record exists in the RIDT, but nothing references it
+        #[tokio::test]
+        async fn orphan() {
+            let rid = RID(42);
+            let idml = setup(rid, 1);
+
+            assert!(!idml.check_refcounts(BTreeMap::new()).await.unwrap());
+        }
+    }
+
     mod check_ridt {
         use super::*;
 
@@ -947,6 +1600,53 @@ mod t {
         }
     }
 
+    mod dup {
+        use super::*;
+        use pretty_assertions::assert_eq;
+
+        #[test]
+        fn enoent() {
+            let rid = RID(42);
+            let cache = Cache::with_capacity(1_048_576);
+            let ddml = mock_ddml();
+            let arc_ddml = Arc::new(ddml);
+            let idml = IDML::create(arc_ddml, Arc::new(Mutex::new(cache)));
+
+            let r = idml.dup(rid, TxgT::from(42))
+                .now_or_never().unwrap();
+            assert_eq!(Err(Error::ENOENT), r);
+        }
+
+        /// After dup, the record should survive one delete
+        #[test]
+        fn ok() {
+            let rid = RID(42);
+            let drp = DRP::random(Compression::None, 4096);
+            let cache = Cache::with_capacity(1_048_576);
+            let ddml = mock_ddml();
+            let arc_ddml = Arc::new(ddml);
+            let idml = IDML::create(arc_ddml, Arc::new(Mutex::new(cache)));
+            inject_record(&idml, rid, &drp, 1);
+
+            idml.dup(rid, TxgT::from(42))
+                .now_or_never().unwrap().unwrap();
+            let entry = idml.ridt.get(rid)
+                .now_or_never().unwrap()
+                .unwrap()
+                .unwrap();
+            assert_eq!(entry.drp, drp);
+            assert_eq!(entry.refcount, 2);
+
+            idml.delete(&rid, TxgT::from(42))
+                .now_or_never().unwrap().unwrap();
+            let entry = idml.ridt.get(rid)
+                .now_or_never().unwrap()
+                .unwrap()
+                .unwrap();
+            assert_eq!(entry.refcount, 1);
+        }
+    }
+
     #[test]
     fn evict() {
         let rid = RID(42);
@@ -1037,11 +1737,61 @@ mod t {
         }
     }
 
+    mod get_uncached {
+        use super::*;
+
+        #[test]
+        fn hot() {
+            let rid = RID(42);
+            let key = Key::Rid(rid);
+            let mut cache = Cache::with_capacity(1_048_576);
+            let dbs = DivBufShared::from(vec![0u8; 4096]);
+            cache.insert(key, Box::new(dbs));
+            let ddml = mock_ddml();
+            let arc_ddml = Arc::new(ddml);
+            let amcache = Arc::new(Mutex::new(cache));
+            let idml = IDML::create(arc_ddml, amcache.clone());
+
+            idml.get_uncached::<DivBufShared, DivBuf>(rid)
+                .now_or_never().unwrap()
+                .unwrap();
+            assert!(amcache.lock().unwrap().get::<DivBuf>(&key).is_some());
+        }
+
+        /// A cache miss should read from disk without inserting into cache
+        #[test]
+        fn cold() {
+            let rid = RID(42);
+            let key = Key::Rid(rid);
+            let drp = DRP::random(Compression::None, 4096);
+            let cache = Cache::with_capacity(1_048_576);
+            let mut ddml = mock_ddml();
+            ddml.expect_get_direct::<DivBufShared>()
+                .once()
+                .with(eq(drp))
+                .returning(move |_| {
+                    let dbs = Box::new(DivBufShared::from(vec![0u8; 4096]));
+                    Box::pin(future::ok::<Box<DivBufShared>, Error>(dbs))
+                });
+            let arc_ddml = Arc::new(ddml);
+            let amcache = Arc::new(Mutex::new(cache));
+            let idml = IDML::create(arc_ddml, amcache.clone());
+            inject_record(&idml, rid, &drp, 1);
+
+            let r = idml.get_uncached::<DivBufShared, DivBuf>(rid)
+                .now_or_never().unwrap()
+                .unwrap();
+            assert_eq!(&r[..], &[0u8; 4096][..]);
+            assert!(amcache.lock().unwrap().get::<DivBuf>(&key).is_none());
+        }
+    }
+
     #[test]
     fn list_indirect_records() {
         let txgs = TxgT::from(0)..TxgT::from(2);
         let cz = ClosedZone{pba: PBA::new(0, 100), total_blocks: 100, zid: 0,
-                            freed_blocks: 50, txgs};
+                            freed_blocks: 50, txgs,
+                            temp: Temperature::Hot};
         let cache = Cache::with_capacity(1_048_576);
         let ddml = mock_ddml();
         let arc_ddml = Arc::new(ddml);
@@ -1102,13 +1852,11 @@ mod t {
                     let r = DivBufShared::from(&dbs.try_const().unwrap()[..]);
                     Box::pin(future::ok::<Box<DivBufShared>, Error>(Box::new(r)))
                 });
-            ddml.expect_put_direct::<DivBuf>()
+            ddml.expect_copy_direct::<DivBuf>()
                 .once()
                 .in_sequence(&mut seq)
-                .with(always(), eq(Compression::None), always())
-                .returning(move |_, _, _|
-                    Box::pin(future::ok(drp1))
-                );
+                .with(always(), eq(Temperature::Cold), always())
+                .returning(move |_, _, _| Box::pin(future::ok(drp1)));
             ddml.expect_delete_direct()
                 .once()
                 .in_sequence(&mut seq)
@@ -1121,8 +1869,9 @@ mod t {
             let idml = IDML::create(arc_ddml, amcache.clone());
             inject_record(&idml, rid, &drp0, 1);
 
-            IDML::move_record(&idml.cache, idml.ridt.clone(), idml.alloct.clone(),
-                &idml.ddml, rid, TxgT::from(0))
+            IDML::move_record(&idml.cache, idml.ridt.clone(),
+                idml.alloct.clone(), &idml.ddml, &idml.rid_locks, rid,
+                Temperature::Cold, TxgT::from(0))
             .now_or_never().unwrap().unwrap();
 
             // Now verify the RIDT and alloct entries
@@ -1161,10 +1910,10 @@ mod t {
                     let r = DivBufShared::from(&dbs.try_const().unwrap()[..]);
                     Box::pin(future::ok(Box::new(r)))
                 });
-            ddml.expect_put_direct::<DivBuf>()
+            ddml.expect_copy_direct::<DivBuf>()
                 .once()
                 .in_sequence(&mut seq)
-                .with(always(), eq(Compression::None), always())
+                .with(always(), eq(Temperature::Cold), always())
                 .returning(move |_, _, _| Box::pin(future::ok(drp1)));
             ddml.expect_delete_direct()
                 .once()
@@ -1175,8 +1924,9 @@ mod t {
             let idml = IDML::create(arc_ddml, Arc::new(Mutex::new(cache)));
             inject_record(&idml, rid, &drp0, 1);
 
-            IDML::move_record(&idml.cache, idml.ridt.clone(), idml.alloct.clone(),
-                &idml.ddml, rid, TxgT::from(0))
+            IDML::move_record(&idml.cache, idml.ridt.clone(),
+                idml.alloct.clone(), &idml.ddml, &idml.rid_locks, rid,
+                Temperature::Cold, TxgT::from(0))
                 .now_or_never().unwrap().unwrap();
 
             // Now verify the RIDT and alloct entries
@@ -1192,6 +1942,62 @@ mod t {
             assert_eq!(alloc_rec.unwrap(), rid);
         }
 
+        /// A record should never be moved into the same zone as its ditto
+        /// copy.
+        #[test]
+        fn ditto() {
+            let v = vec![42u8; 4096];
+            let dbs = DivBufShared::from(v);
+            let rid = RID(1);
+            let ditto = ditto_partner(rid);
+            let drp0 = DRP::random(Compression::None, 4096);
+            let drp1 = DRP::random(Compression::None, 4096);
+            let drpd = DRP::random(Compression::None, 4096);
+            let mut seq = Sequence::new();
+            let cache = Cache::with_capacity(1_048_576);
+            let mut ddml = mock_ddml();
+            ddml.expect_open_zone_temperature()
+                .once()
+                .with(eq(drpd.pba()))
+                .return_const(Some(Temperature::Cold));
+            ddml.expect_get_direct()
+                .once()
+                .in_sequence(&mut seq)
+                .returning(move |_| {
+                    let r = DivBufShared::from(&dbs.try_const().unwrap()[..]);
+                    Box::pin(future::ok::<Box<DivBufShared>, Error>(Box::new(r)))
+                });
+            // The ditto copy's zone is still open for cold data, so the
+            // record must go elsewhere.
+            ddml.expect_copy_direct::<DivBuf>()
+                .once()
+                .in_sequence(&mut seq)
+                .with(always(), eq(Temperature::Hot), always())
+                .returning(move |_, _, _| Box::pin(future::ok(drp1)));
+            ddml.expect_delete_direct()
+                .once()
+                .in_sequence(&mut seq)
+                .with(eq(drp0), always())
+                .returning(move |_, _| {
+                    Box::pin(future::ok::<(), Error>(()))
+                });
+            let arc_ddml = Arc::new(ddml);
+            let idml = IDML::create(arc_ddml, Arc::new(Mutex::new(cache)));
+            inject_record(&idml, rid, &drp0, 1);
+            inject_record(&idml, ditto, &drpd, 1);
+
+            IDML::move_record(&idml.cache, idml.ridt.clone(),
+                idml.alloct.clone(), &idml.ddml, &idml.rid_locks, rid,
+                Temperature::Cold, TxgT::from(0))
+            .now_or_never().unwrap().unwrap();
+
+            let entry = idml.ridt.get(rid)
+                .now_or_never().unwrap()
+                .unwrap()
+                .unwrap();
+            assert_eq!(entry.drp, drp1);
+        }
+
         /// When moving records, check the cache first.
         #[test]
         fn hot() {
@@ -1208,7 +2014,7 @@ mod t {
             ddml.expect_put_direct::<DivBuf>()
                 .once()
                 .in_sequence(&mut seq)
-                .returning(move |_, _, _|
+                .returning(move |_, _, _, _|
                            Box::pin(future::ok(drp1))
                 );
             ddml.expect_delete_direct()
@@ -1220,8 +2026,9 @@ mod t {
             let idml = IDML::create(arc_ddml, Arc::new(Mutex::new(cache)));
             inject_record(&idml, rid, &drp0, 1);
 
-            IDML::move_record(&idml.cache, idml.ridt.clone(), idml.alloct.clone(),
-                &idml.ddml, rid, TxgT::from(0))
+            IDML::move_record(&idml.cache, idml.ridt.clone(),
+                idml.alloct.clone(), &idml.ddml, &idml.rid_locks, rid,
+                Temperature::Hot, TxgT::from(0))
                 .now_or_never().unwrap().unwrap();
 
             // Now verify the RIDT and alloct entries
@@ -1377,7 +2184,8 @@ mod t {
         let key = Key::Rid(rid);
         ddml.expect_put_direct::<Box<dyn CacheRef>>()
             .once()
-            .returning(move |_, _, _|
+            .with(always(), always(), eq(Temperature::Hot), always())
+            .returning(move |_, _, _, _|
                        Box::pin(future::ok(drp))
             );
         let arc_ddml = Arc::new(ddml);
@@ -1404,6 +2212,112 @@ mod t {
         assert!(amcache.lock().unwrap().get::<DivBuf>(&key).is_some());
     }
 
+    /// The ditto copy should be written as cold data, under its partner RID
+    #[test]
+    fn put_ditto() {
+        let cache = Cache::with_capacity(1_048_576);
+        let mut ddml = mock_ddml();
+        let drp = DRP::new(PBA::new(0, 0), Compression::None, 4096, 4096,
+                           0xdead_beef);
+        let drpd = DRP::new(PBA::new(0, 1000), Compression::None, 4096, 4096,
+                            0xdead_beef);
+        ddml.expect_put_direct::<Box<dyn CacheRef>>()
+            .once()
+            .with(always(), always(), eq(Temperature::Hot), always())
+            .return_once(move |_, _, _, _| Box::pin(future::ok(drp)));
+        ddml.expect_put_direct::<Box<dyn CacheRef>>()
+            .once()
+            .with(always(), always(), eq(Temperature::Cold), always())
+            .return_once(move |_, _, _, _| Box::pin(future::ok(drpd)));
+        let idml = IDML::create(Arc::new(ddml), Arc::new(Mutex::new(cache)));
+
+        let dbs = DivBufShared::from(vec![42u8; 4096]);
+        let (rid, ditto) = idml.put_ditto(dbs, Compression::None,
+                                          TxgT::from(0))
+            .now_or_never().unwrap().unwrap();
+        assert_eq!(ditto, ditto_partner(rid));
+        assert_eq!(rid, ditto_partner(ditto));
+
+        let entry = idml.ridt.get(ditto)
+            .now_or_never().unwrap()
+            .unwrap().unwrap();
+        assert_eq!(entry.drp, drpd);
+        // Only the original should be cached
+        let cache = idml.cache.lock().unwrap();
+        assert!(cache.get::<DivBuf>(&Key::Rid(rid)).is_some());
+        assert!(cache.get::<DivBuf>(&Key::Rid(ditto)).is_none());
+    }
+
+    #[test]
+    fn round_robin() {
+        let rec = |rid, cluster, lba| {
+            let pba = PBA::new(cluster, lba);
+            (RID(rid), DRP::new(pba, Compression::None, 4096, 4096, 0))
+        };
+        let records = vec![rec(0, 0, 10), rec(1, 0, 11), rec(2, 0, 12),
+                           rec(3, 1, 10), rec(4, 2, 10), rec(5, 2, 11)];
+        let rids = super::round_robin(records).into_iter()
+            .map(|(rid, _drp)| rid.0)
+            .collect::<Vec<_>>();
+        assert_eq!(rids, vec![0, 3, 4, 1, 5, 2]);
+    }
+
+    mod scrub {
+        use super::*;
+
+        /// Corrupt records should be reported, and shouldn't stop the scrub.
+        #[test]
+        fn eintegrity() {
+            let cache = Cache::with_capacity(1_048_576);
+            let mut ddml = mock_ddml();
+            let drp0 = DRP::new(PBA::new(0, 0), Compression::None, 4096, 4096,
+                                0);
+            let drp1 = DRP::new(PBA::new(1, 0), Compression::None, 4096, 4096,
+                                0);
+            ddml.expect_verify()
+                .once()
+                .with(eq(drp0))
+                .returning(|_| Box::pin(future::err(Error::EINTEGRITY)));
+            ddml.expect_verify()
+                .once()
+                .with(eq(drp1))
+                .returning(|_| Box::pin(future::ok(())));
+            ddml.expect_ops().return_const(0u64);
+            let idml = IDML::create(Arc::new(ddml),
+                                    Arc::new(Mutex::new(cache)));
+            inject_record(&idml, RID(0), &drp0, 1);
+            inject_record(&idml, RID(1), &drp1, 1);
+
+            let r = idml.scrub(4).now_or_never().unwrap().unwrap();
+            assert!(!r);
+        }
+
+        #[test]
+        fn ok() {
+            let cache = Cache::with_capacity(1_048_576);
+            let mut ddml = mock_ddml();
+            let drps = (0..10u64).map(|i| {
+                let pba = PBA::new((i % 3) as ClusterT, i);
+                DRP::new(pba, Compression::None, 4096, 4096, i)
+            }).collect::<Vec<_>>();
+            for drp in drps.iter() {
+                ddml.expect_verify()
+                    .once()
+                    .with(eq(*drp))
+                    .returning(|_| Box::pin(future::ok(())));
+            }
+            ddml.expect_ops().return_const(0u64);
+            let idml = IDML::create(Arc::new(ddml),
+                                    Arc::new(Mutex::new(cache)));
+            for (i, drp) in drps.iter().enumerate() {
+                inject_record(&idml, RID(i as u64), drp, 1);
+            }
+
+            let r = idml.scrub(2).now_or_never().unwrap().unwrap();
+            assert!(r);
+        }
+    }
+
     #[test]
     fn sync_all() {
         let rid = RID(42);
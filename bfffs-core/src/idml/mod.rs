@@ -21,8 +21,10 @@ mod idml;
 
 #[double]
 pub use self::idml::IDML;
+pub(crate) use self::idml::Label;
 
 pub type ClosedZone = crate::ddml::ClosedZone;
+pub use crate::ddml::Temperature;
 
 pub type DTree<K, V> = Tree<DRP, DDML, K, V>;
 
@@ -38,6 +40,35 @@ impl RidtEntry {
     pub fn new(drp: DRP) -> Self {
         RidtEntry{drp, refcount: 1}
     }
+
+    /// Add a reference to the record.
+    ///
+    /// No record can really have 2^64 references, so an overflow means that
+    /// the entry is corrupt.  Rather than wrap around, and eventually free a
+    /// record that's still in use, saturate and log an error.  A saturated
+    /// count is never decremented, so the record will merely be leaked.
+    fn inc_refcount(&mut self, rid: RID) {
+        match self.refcount.checked_add(1) {
+            Some(refcount) => self.refcount = refcount,
+            None => tracing::error!(%rid,
+                "Reference count overflow.  The RIDT is corrupt.")
+        }
+    }
+
+    /// Drop a reference to the record, and return the number remaining.
+    ///
+    /// Entries are removed as soon as their counts reach zero, so one that's
+    /// already zero is corrupt.  Rather than wrap around, log an error and
+    /// report that no references remain.
+    fn dec_refcount(&mut self, rid: RID) -> u64 {
+        match self.refcount {
+            0 => tracing::error!(%rid,
+                "Reference count underflow.  The RIDT is corrupt."),
+            u64::MAX => (),
+            _ => self.refcount -= 1
+        }
+        self.refcount
+    }
 }
 
 impl TypicalSize for RidtEntry {
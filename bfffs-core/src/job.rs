@@ -0,0 +1,249 @@
+// vim: tw=80
+//! Progress tracking for long-running operations
+//!
+//! Operations like cleaning may run for hours in the background.  Each one
+//! registers a job with the `Controller`'s `Jobs`, and reports how many bytes
+//! it has processed through a `Progress` handle.  Clients may poll the jobs'
+//! status at any time.
+
+use crate::{Error, Result};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{
+        Arc,
+        Mutex,
+        atomic::{AtomicU64, Ordering}
+    }
+};
+
+pub type JobID = u64;
+
+/// The kind of operation that a job performs
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum JobKind {
+    /// Clean freed space from a pool
+    Clean,
+    /// Rewrite a pool's fragmented files contiguously
+    Defrag,
+}
+
+impl fmt::Display for JobKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobKind::Clean => "clean".fmt(f),
+            JobKind::Defrag => "defrag".fmt(f)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum JobState {
+    Running,
+    Done,
+    /// The job failed.  `ECANCELED` means that it was abandoned before it
+    /// could finish, for example because it was redundant with another job.
+    Failed(Error)
+}
+
+impl fmt::Display for JobState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobState::Running => "running".fmt(f),
+            JobState::Done => "done".fmt(f),
+            JobState::Failed(e) => write!(f, "failed: {e:?}")
+        }
+    }
+}
+
+/// A snapshot of a job's progress
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct JobStatus {
+    pub id: JobID,
+    pub kind: JobKind,
+    /// Name of the pool or dataset that the job operates on
+    pub target: String,
+    /// Bytes processed so far
+    pub processed: u64,
+    /// Total bytes that the job expects to process.  0 if not yet known.
+    pub total: u64,
+    pub state: JobState,
+}
+
+struct Job {
+    kind: JobKind,
+    target: String,
+    processed: AtomicU64,
+    total: AtomicU64,
+    state: Mutex<JobState>,
+}
+
+impl Job {
+    fn status(&self, id: JobID) -> JobStatus {
+        JobStatus {
+            id,
+            kind: self.kind,
+            target: self.target.clone(),
+            processed: self.processed.load(Ordering::Relaxed),
+            total: self.total.load(Ordering::Relaxed),
+            state: *self.state.lock().unwrap()
+        }
+    }
+}
+
+/// Marks its job as cancelled if dropped before the job finishes.
+struct Tracker(Arc<Job>);
+
+impl Drop for Tracker {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap();
+        if *state == JobState::Running {
+            *state = JobState::Failed(Error::ECANCELED);
+        }
+    }
+}
+
+/// Handle through which a long-running operation reports its progress.
+///
+/// The default `Progress` belongs to no job, and reports nothing.
+#[derive(Clone, Default)]
+pub struct Progress(Option<Arc<Tracker>>);
+
+impl Progress {
+    /// Record that `bytes` more bytes have been processed
+    pub fn add(&self, bytes: u64) {
+        if let Some(tracker) = &self.0 {
+            tracker.0.processed.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    /// Record the job's final result
+    pub fn finish(&self, r: Result<()>) {
+        if let Some(tracker) = &self.0 {
+            *tracker.0.state.lock().unwrap() = match r {
+                Ok(()) => JobState::Done,
+                Err(e) => JobState::Failed(e)
+            };
+        }
+    }
+
+    /// Set the total number of bytes that the job expects to process
+    pub fn set_total(&self, bytes: u64) {
+        if let Some(tracker) = &self.0 {
+            tracker.0.total.store(bytes, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Registry of all running and recently finished jobs
+#[derive(Default)]
+pub struct Jobs {
+    next_id: AtomicU64,
+    jobs: Mutex<BTreeMap<JobID, Arc<Job>>>,
+}
+
+impl Jobs {
+    /// Finished jobs are forgotten once there are more than this many
+    const MAX_FINISHED: usize = 16;
+
+    /// Status of every job that is running or recently finished
+    pub fn list(&self) -> Vec<JobStatus> {
+        self.jobs.lock().unwrap().iter()
+            .map(|(id, job)| job.status(*id))
+            .collect()
+    }
+
+    /// Register a new job.
+    ///
+    /// The job will be considered running until its `Progress` is finished,
+    /// or dropped.
+    pub fn start(&self, kind: JobKind, target: String) -> (JobID, Progress) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let job = Arc::new(Job {
+            kind,
+            target,
+            processed: AtomicU64::new(0),
+            total: AtomicU64::new(0),
+            state: Mutex::new(JobState::Running)
+        });
+        let mut jobs = self.jobs.lock().unwrap();
+        let finished = jobs.iter()
+            .filter(|(_, job)| *job.state.lock().unwrap() != JobState::Running)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        // IDs are allocated in order, so the oldest finished jobs come first
+        let excess = finished.len().saturating_sub(Jobs::MAX_FINISHED - 1);
+        for id in finished.into_iter().take(excess) {
+            jobs.remove(&id);
+        }
+        jobs.insert(id, job.clone());
+        (id, Progress(Some(Arc::new(Tracker(job)))))
+    }
+
+    /// Status of a single job
+    pub fn status(&self, id: JobID) -> Result<JobStatus> {
+        self.jobs.lock().unwrap().get(&id)
+            .map(|job| job.status(id))
+            .ok_or(Error::ENOENT)
+    }
+}
+
+// LCOV_EXCL_START
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    #[test]
+    fn cancel_on_drop() {
+        let jobs = Jobs::default();
+        let (id, progress) = jobs.start(JobKind::Clean, "pool".to_owned());
+        let progress2 = progress.clone();
+        drop(progress);
+        assert_eq!(jobs.status(id).unwrap().state, JobState::Running);
+        drop(progress2);
+        assert_eq!(jobs.status(id).unwrap().state,
+                   JobState::Failed(Error::ECANCELED));
+    }
+
+    #[test]
+    fn finish() {
+        let jobs = Jobs::default();
+        let (id, progress) = jobs.start(JobKind::Clean, "pool".to_owned());
+        progress.set_total(1000);
+        progress.add(400);
+        progress.add(600);
+        progress.finish(Ok(()));
+        drop(progress);
+        assert_eq!(jobs.status(id).unwrap(), JobStatus {
+            id,
+            kind: JobKind::Clean,
+            target: "pool".to_owned(),
+            processed: 1000,
+            total: 1000,
+            state: JobState::Done
+        });
+    }
+
+    #[test]
+    fn status_enoent() {
+        let jobs = Jobs::default();
+        assert_eq!(jobs.status(0), Err(Error::ENOENT));
+    }
+
+    /// Old finished jobs should be forgotten, but running ones never
+    #[test]
+    fn prune() {
+        let jobs = Jobs::default();
+        let (running, _progress) = jobs.start(JobKind::Clean, "p".to_owned());
+        for _ in 0..2 * Jobs::MAX_FINISHED {
+            let (_, progress) = jobs.start(JobKind::Clean, "p".to_owned());
+            progress.finish(Ok(()));
+        }
+        let list = jobs.list();
+        assert_eq!(list.len(), Jobs::MAX_FINISHED + 1);
+        assert_eq!(list[0].id, running);
+        assert_eq!(list[1].id, Jobs::MAX_FINISHED as JobID + 1);
+    }
+}
+// LCOV_EXCL_STOP
@@ -13,34 +13,51 @@ use std::{hash::{Hash, Hasher}, io::{self, Seek, SeekFrom}};
 /*
  * On-disk Label Format:
  *
- * Magic:       16 bytes
- * Checksum:    8 bytes     MetroHash64.  Covers all of Length and Contents.
+ * Magic:       12 bytes
+ * Version:     4 bytes     Label format version, big-endian.  Labels written
+ *                          before it existed have 0 here.
+ * Checksum:    8 bytes     MetroHash64.  Covers all of Version, Length, and
+ *                          Contents, except in version 0 labels, whose
+ *                          checksum predates the Version field.
  * Length:      8 bytes     Length of Contents in bytes
  * VdevFile:    variable    bincode-encoded VdevFile::Label
  * VdevRaid:    variable    bincode-encoded VdevRaid::Label
  * Pool:        variable    bincode-encoded Pool::Label
  * IDML:        variable    bincode-encoded IDML::Label
  * Database:    variable    bincode-encoded Database::Label
- * Pad:         variable    0-padding fills the remainder, up to 4 LBAs
+ * Pad:         variable    0-padding fills the remainder of the last LBA
  *
  * On-disk Reserved Region Format:
  *
- * Label 0      4 LBAs
+ * Label 0      3 LBAs
+ * Heartbeat    1 LBA       See the multihost module
  * Label 1      4 LBAs
  * Spacemap0    variable    bincode-encoded spacemap.  Size is determined at
  *                          format-time.
  * Spacemap1    variable
  */
-/// The file magic is "BFFFS Vdev\0\0\0\0\0\0"
-const MAGIC: &[u8; MAGIC_LEN] = b"BFFFS Vdev\0\0\0\0\0\0";
-const MAGIC_LEN: usize = 16;
+/// The file magic is "BFFFS Vdev\0\0"
+const MAGIC: &[u8; MAGIC_LEN] = b"BFFFS Vdev\0\0";
+const MAGIC_LEN: usize = 12;
+const VERSION_LEN: usize = 4;
+/// Current label format version.
+///
+/// * 0: The original format.
+/// * 1: Adds the leaf's error counts, the mirror's dirty regions and
+///      write-mostly children, the pool's checkpoint, features, and
+///      properties, and the database's error log.
+pub const LABEL_VERSION: u32 = 1;
 const CHECKSUM_LEN: usize = 8;
 const LENGTH_LEN: usize = 8;
+const HEADER_LEN: usize = MAGIC_LEN + VERSION_LEN + CHECKSUM_LEN + LENGTH_LEN;
 pub const LABEL_COUNT: LbaT = 2;
 // Actual label size is about 17 bytes for each RAID member plus 17 bytes for
 // each Cluster, plus a couple hundred bytes more.
 pub const LABEL_LBAS: LbaT = 4;
 pub const LABEL_SIZE: usize = LABEL_LBAS as usize * BYTES_PER_LBA;
+/// The last LBA of the first label's region holds the multihost heartbeat,
+/// instead of any part of the label.  The second label's region has none.
+pub const HEARTBEAT_LBA: LbaT = LABEL_LBAS - 1;
 /// Space allocated for storing the spacemap.  This the number of zones whose
 /// information can be recorded in one LBA of storage.
 pub const SPACEMAP_ZONES_PER_LBA: usize = 255;
@@ -50,35 +67,62 @@ pub fn spacemap_space(nzones: u64) -> LbaT {
     div_roundup(nzones, SPACEMAP_ZONES_PER_LBA as u64)
 }
 
+/// One layer's struct within the label.
+///
+/// bincode isn't self-describing, so a layer whose struct has changed since
+/// the original label format must still be able to decode the old one.
+pub trait LabelLayer: DeserializeOwned {
+    /// This layer's struct as it was encoded in version 0 labels.
+    type V0: DeserializeOwned + Into<Self>;
+}
+
 /// Used to read successive structs out of the label
 pub struct LabelReader {
-    cursor: io::Cursor<Vec<u8>>
+    cursor: io::Cursor<Vec<u8>>,
+    version: u32,
 }
 
 impl LabelReader {
-    /// Attempt to read a `T` out of the label
-    pub fn deserialize<T>(&mut self) -> bincode::Result<T>
-        where T: DeserializeOwned
-    {
-        bincode::deserialize_from(&mut self.cursor)
+    /// Attempt to read a `T` out of the label, in whichever format the label
+    /// was written with.
+    pub fn deserialize<T: LabelLayer>(&mut self) -> bincode::Result<T> {
+        if self.version == 0 {
+            bincode::deserialize_from::<_, T::V0>(&mut self.cursor)
+                .map(Into::into)
+        } else {
+            bincode::deserialize_from(&mut self.cursor)
+        }
     }
 
     /// Construct a `LabelReader` using the raw buffer read from disk
+    ///
+    /// Returns `EOPNOTSUPP` if the label was written in a newer format than
+    /// this version of BFFFS understands.
     pub fn new(buffer: Vec<u8>) -> Result<Self> {
-        if buffer.len() < MAGIC_LEN + CHECKSUM_LEN + LENGTH_LEN {
+        if buffer.len() < HEADER_LEN {
             return Err(Error::EINVAL);
         }
         if MAGIC[..] != buffer[0..MAGIC_LEN] {
             return Err(Error::EINVAL);
         }
+        let version_start = MAGIC_LEN;
+        let checksum_start = version_start + VERSION_LEN;
+        let version = BigEndian::read_u32(
+            &buffer[version_start..checksum_start]);
+        if version > LABEL_VERSION {
+            return Err(Error::EOPNOTSUPP);
+        }
 
         let checksum = BigEndian::read_u64(
-            &buffer[MAGIC_LEN..MAGIC_LEN + CHECKSUM_LEN]);
-        let length_start = MAGIC_LEN + CHECKSUM_LEN;
+            &buffer[checksum_start..checksum_start + CHECKSUM_LEN]);
+        let length_start = checksum_start + CHECKSUM_LEN;
         let contents_start = length_start + LENGTH_LEN;
         let contents_len = BigEndian::read_u64(
             &buffer[length_start .. contents_start]);
         let mut hasher = MetroHash64::new();
+        if version > 0 {
+            version.to_be().hash(&mut hasher);
+        }
         {
             let contents = &buffer[contents_start ..
                                contents_start + contents_len as usize];
@@ -93,7 +137,7 @@ impl LabelReader {
         // Seek past header
         cursor.seek(SeekFrom::Start(contents_start as u64))
             .expect("IoVec too short");
-        Ok(LabelReader { cursor })
+        Ok(LabelReader { cursor, version })
     }
 
     /// Get the offset of the `label`th label.
@@ -111,6 +155,11 @@ pub struct LabelWriter {
 }
 
 impl LabelWriter {
+    /// Return the index of the label that this `LabelWriter` will write
+    pub fn idx(&self) -> u32 {
+        self.label
+    }
+
     /// Return the LBA at which to write this label
     pub fn lba(&self) -> LbaT {
         LbaT::from(self.label) * LABEL_LBAS
@@ -136,24 +185,133 @@ impl LabelWriter {
 
     /// Consume the `LabelWriter` and return an `SGList` suitable for writing to
     /// the first sector of a disk.
-    pub fn into_sglist(self) -> SGList {
+    ///
+    /// Fails with `ENOSPC` if the label is too big for its reserved space.
+    pub fn into_sglist(self) -> Result<SGList> {
         let mut sglist: SGList = Vec::with_capacity(self.buffers.len() + 2);
-        let header_len = MAGIC_LEN + CHECKSUM_LEN + LENGTH_LEN;
-        let header_dbs = DivBufShared::with_capacity(header_len);
+        let header_dbs = DivBufShared::with_capacity(HEADER_LEN);
         let mut header = header_dbs.try_mut().unwrap();
         header.extend(&MAGIC[..]);
         let contents = self.buffers.into_iter().rev().collect::<Vec<_>>();
         let contents_len: usize = contents.iter().map(DivBuf::len).sum();
+        let max_len = HEARTBEAT_LBA as usize * BYTES_PER_LBA;
+        if HEADER_LEN + contents_len > max_len {
+            tracing::error!(len = HEADER_LEN + contents_len, max_len,
+                "Label would overwrite the heartbeat");
+            return Err(Error::ENOSPC);
+        }
         let mut hasher = MetroHash64::new();
+        LABEL_VERSION.to_be().hash(&mut hasher);
         (contents_len as u64).to_be().hash(&mut hasher);
         checksum_sglist(&contents, &mut hasher);
-        header.try_resize(MAGIC_LEN + CHECKSUM_LEN, 0).unwrap();
-        BigEndian::write_u64(&mut header[MAGIC_LEN..], hasher.finish());
-        header.try_resize(MAGIC_LEN + CHECKSUM_LEN + LENGTH_LEN, 0).unwrap();
-        let length_start = MAGIC_LEN + CHECKSUM_LEN;
+        let checksum_start = MAGIC_LEN + VERSION_LEN;
+        header.try_resize(checksum_start, 0).unwrap();
+        BigEndian::write_u32(&mut header[MAGIC_LEN..], LABEL_VERSION);
+        header.try_resize(checksum_start + CHECKSUM_LEN, 0).unwrap();
+        BigEndian::write_u64(&mut header[checksum_start..], hasher.finish());
+        header.try_resize(HEADER_LEN, 0).unwrap();
+        let length_start = checksum_start + CHECKSUM_LEN;
         BigEndian::write_u64(&mut header[length_start..], contents_len as u64);
         sglist.push(header.freeze());
         sglist.extend(contents);
-        sglist
+        Ok(sglist)
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use pretty_assertions::assert_eq;
+    use serde_derive::Serialize;
+    use crate::feature::Features;
+    use super::*;
+
+    /// Concatenate a `LabelWriter`'s output into a buffer like one read from
+    /// disk.
+    fn flatten(lw: LabelWriter) -> Vec<u8> {
+        let mut buf = lw.into_sglist().unwrap().iter()
+            .fold(Vec::new(), |mut v, db| {v.extend(&db[..]); v});
+        buf.resize(LABEL_SIZE, 0);
+        buf
+    }
+
+    /// Pool label as written by version 0
+    #[derive(Serialize)]
+    struct PoolLabelV0 {
+        name: String,
+        uuid: Uuid,
+        children: Vec<Uuid>,
+    }
+
+    /// A label too big for its space must not clobber the heartbeat
+    #[test]
+    fn enospc() {
+        let mut lw = LabelWriter::new(0);
+        lw.serialize(&vec![0u8; LABEL_SIZE]).unwrap();
+        assert_eq!(lw.into_sglist().err(), Some(Error::ENOSPC));
+    }
+
+    #[test]
+    fn future_version() {
+        let mut buf = flatten(LabelWriter::new(0));
+        BigEndian::write_u32(&mut buf[MAGIC_LEN..], LABEL_VERSION + 1);
+        assert_eq!(LabelReader::new(buf).err(), Some(Error::EOPNOTSUPP));
+    }
+
+    /// Labels written before the version field existed should still be
+    /// readable, with the new fields defaulted.
+    #[test]
+    fn version0() {
+        let old = PoolLabelV0 {
+            name: "testpool".to_owned(),
+            uuid: Uuid::new_v4(),
+            children: vec![Uuid::new_v4()]
+        };
+        let contents = bincode::serialize(&old).unwrap();
+        let mut hasher = MetroHash64::new();
+        (contents.len() as u64).to_be().hash(&mut hasher);
+        hasher.write(&contents);
+        let mut buf = b"BFFFS Vdev\0\0\0\0\0\0".to_vec();
+        buf.extend(hasher.finish().to_be_bytes());
+        buf.extend((contents.len() as u64).to_be_bytes());
+        buf.extend(contents);
+        buf.resize(LABEL_SIZE, 0);
+
+        let mut reader = LabelReader::new(buf).unwrap();
+        let label: crate::pool::Label = reader.deserialize().unwrap();
+        assert_eq!(label.name, old.name);
+        assert_eq!(label.uuid, old.uuid);
+        assert_eq!(label.children, old.children);
+        assert_eq!(label.checkpoint, None);
+        assert_eq!(label.features, Features::default());
+    }
+
+    #[test]
+    fn version1() {
+        let label = crate::pool::Label {
+            name: "testpool".to_owned(),
+            uuid: Uuid::new_v4(),
+            children: vec![Uuid::new_v4()],
+            checkpoint: Some(TxgT::from(42)),
+            features: Default::default(),
+            properties: Default::default()
+        };
+        let mut lw = LabelWriter::new(0);
+        lw.serialize(&label).unwrap();
+        let buf = flatten(lw);
+        assert_eq!(BigEndian::read_u32(&buf[MAGIC_LEN..]), LABEL_VERSION);
+
+        let mut reader = LabelReader::new(buf).unwrap();
+        let label2: crate::pool::Label = reader.deserialize().unwrap();
+        assert_eq!(label2.uuid, label.uuid);
+        assert_eq!(label2.checkpoint, label.checkpoint);
+    }
+
+    /// Altering the version must invalidate the checksum, lest a corrupt
+    /// version make us decode the label in the wrong format.
+    #[test]
+    fn version_checksummed() {
+        let mut buf = flatten(LabelWriter::new(0));
+        BigEndian::write_u32(&mut buf[MAGIC_LEN..], 0);
+        assert_eq!(LabelReader::new(buf).err(), Some(Error::EINTEGRITY));
     }
 }
@@ -27,20 +27,31 @@
 extern crate test;
 
 pub mod cache;
+pub mod capacity;
 pub mod cleaner;
 pub mod cluster;
 pub mod controller;
 pub mod database;
 pub mod dataset;
 pub mod ddml;
+pub mod defrag;
+pub mod determinism;
 pub mod device_manager;
 pub mod dml;
+pub mod feature;
 pub mod fs;
 pub mod fs_tree;
 pub mod idml;
+pub mod job;
 pub mod label;
+pub mod load_monitor;
+#[cfg(any(test, feature = "testing"))]
+pub mod mem_dml;
+pub mod memory;
 pub mod mirror;
+pub mod multihost;
 pub mod pool;
+pub mod pool_property;
 pub mod property;
 pub mod raid;
 pub mod rpc;
@@ -50,6 +61,7 @@ pub mod util;
 pub mod vdev;
 pub mod vdev_block;
 pub mod vdev_file;
+pub mod vfs;
 pub mod writeback;
 
 pub use crate::types::*;
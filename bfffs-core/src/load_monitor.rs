@@ -0,0 +1,199 @@
+// vim: tw=80
+//! Foreground load detection, so background work can yield to it
+//!
+//! Background tasks like the cleaner and scrub ask the `LoadMonitor` before
+//! doing each unit of work.  While the pool is idle, they may proceed at full
+//! speed.  While it's busy with foreground I/O, they're limited to
+//! `background_rate` bytes per second.
+
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant}
+};
+
+struct Inner {
+    /// Maximum background throughput in bytes per second while the pool is
+    /// busy.  0 means background work pauses completely.
+    background_rate: u64,
+
+    /// Background I/O operations issued during the current interval
+    background_ops: u64,
+
+    /// Was the pool busy during the last complete sampling interval?
+    busy: bool,
+
+    /// Earliest time at which the next background operation may start, while
+    /// the pool is busy.
+    next: Instant,
+
+    /// The pool's total I/O operation count at the start of the current
+    /// sampling interval
+    sample_ops: u64,
+
+    /// Start of the current sampling interval
+    sample_start: Instant,
+}
+
+pub struct LoadMonitor {
+    inner: Mutex<Inner>
+}
+
+impl LoadMonitor {
+    /// Default value of `background_rate`: 16 MiB/s
+    pub const DEFAULT_BACKGROUND_RATE: u64 = 16 << 20;
+
+    /// Foreground IOPS at or above which the pool is considered busy
+    const BUSY_IOPS: f64 = 10.0;
+
+    /// How often to recompute whether the pool is busy
+    const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+    pub fn new(background_rate: u64) -> Self {
+        let now = Instant::now();
+        let inner = Inner {
+            background_rate,
+            background_ops: 0,
+            busy: false,
+            next: now,
+            sample_ops: 0,
+            sample_start: now,
+        };
+        LoadMonitor{inner: Mutex::new(inner)}
+    }
+
+    /// Record that a background task issued `ios` I/O operations, so they
+    /// won't be mistaken for foreground load.
+    pub fn record_background(&self, ios: u64) {
+        self.inner.lock().unwrap().background_ops += ios;
+    }
+
+    /// Reserve permission for a background task to transfer `bytes`.
+    ///
+    /// # Parameters
+    ///
+    /// * `ops`:    Total number of I/O operations ever issued to the pool, as
+    ///             reported by the vdev stats.
+    /// * `bytes`:  Amount of data that the background task wants to transfer.
+    /// * `now`:    The current time.
+    ///
+    /// # Returns
+    ///
+    /// * `Some(d)`:    Permission granted, but the task must first wait for
+    ///                 `d`, which may be zero.
+    /// * `None`:       Background work is paused.  Try again later.
+    pub fn reserve(&self, ops: u64, bytes: u64, now: Instant)
+        -> Option<Duration>
+    {
+        let mut inner = self.inner.lock().unwrap();
+        let elapsed = now.saturating_duration_since(inner.sample_start);
+        if elapsed >= LoadMonitor::SAMPLE_INTERVAL {
+            let total = ops.saturating_sub(inner.sample_ops);
+            let foreground = total.saturating_sub(inner.background_ops);
+            let iops = foreground as f64 / elapsed.as_secs_f64();
+            inner.busy = iops >= LoadMonitor::BUSY_IOPS;
+            inner.background_ops = 0;
+            inner.sample_ops = ops;
+            inner.sample_start = now;
+        }
+        if !inner.busy {
+            inner.next = now;
+            Some(Duration::ZERO)
+        } else if inner.background_rate == 0 {
+            None
+        } else {
+            let start = inner.next.max(now);
+            let cost = bytes as f64 / inner.background_rate as f64;
+            inner.next = start + Duration::from_secs_f64(cost);
+            Some(start - now)
+        }
+    }
+
+    /// How long to wait before asking again, after `reserve` returns `None`
+    pub fn retry_interval(&self) -> Duration {
+        LoadMonitor::SAMPLE_INTERVAL
+    }
+
+    /// Change the maximum background throughput while the pool is busy.
+    pub fn set_background_rate(&self, background_rate: u64) {
+        self.inner.lock().unwrap().background_rate = background_rate;
+    }
+}
+
+impl Default for LoadMonitor {
+    fn default() -> Self {
+        LoadMonitor::new(LoadMonitor::DEFAULT_BACKGROUND_RATE)
+    }
+}
+
+// LCOV_EXCL_START
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    const SEC: Duration = Duration::from_secs(1);
+
+    /// Make the pool look busy for the next interval
+    fn busy(lm: &LoadMonitor, t0: Instant) -> Instant {
+        let t1 = t0 + SEC;
+        lm.reserve(1000, 0, t1);
+        t1
+    }
+
+    /// Background work shouldn't be throttled until the first sample completes
+    #[test]
+    fn initially_idle() {
+        let lm = LoadMonitor::new(1000);
+        let t0 = Instant::now();
+        assert_eq!(lm.reserve(0, 1 << 30, t0), Some(Duration::ZERO));
+        assert_eq!(lm.reserve(0, 1 << 30, t0), Some(Duration::ZERO));
+    }
+
+    /// Background work shouldn't count as foreground load
+    #[test]
+    fn background_isnt_load() {
+        let lm = LoadMonitor::new(1000);
+        let t0 = Instant::now();
+        lm.record_background(1000);
+        assert_eq!(lm.reserve(1000, 1 << 30, t0 + SEC), Some(Duration::ZERO));
+        assert_eq!(lm.reserve(1000, 1 << 30, t0 + SEC), Some(Duration::ZERO));
+    }
+
+    /// While busy, background work should be limited to background_rate
+    #[test]
+    fn busy_throttles() {
+        let lm = LoadMonitor::new(1000);
+        let t1 = busy(&lm, Instant::now());
+        assert_eq!(lm.reserve(1000, 500, t1), Some(Duration::ZERO));
+        assert_eq!(lm.reserve(1000, 500, t1), Some(SEC / 2));
+        assert_eq!(lm.reserve(1000, 500, t1), Some(SEC));
+    }
+
+    /// A background_rate of 0 pauses background work while busy
+    #[test]
+    fn busy_paused() {
+        let lm = LoadMonitor::new(0);
+        let t1 = busy(&lm, Instant::now());
+        assert_eq!(lm.reserve(1000, 1, t1), None);
+    }
+
+    /// Once foreground load stops, the throttle should open again
+    #[test]
+    fn busy_then_idle() {
+        let lm = LoadMonitor::new(1000);
+        let t1 = busy(&lm, Instant::now());
+        assert_eq!(lm.reserve(1000, 1000, t1), Some(Duration::ZERO));
+        assert_eq!(lm.reserve(1000, 1000, t1), Some(SEC));
+        let t2 = t1 + SEC;
+        assert_eq!(lm.reserve(1000, 1000, t2), Some(Duration::ZERO));
+        assert_eq!(lm.reserve(1000, 1000, t2), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn set_background_rate() {
+        let lm = LoadMonitor::new(1000);
+        let t1 = busy(&lm, Instant::now());
+        lm.set_background_rate(0);
+        assert_eq!(lm.reserve(1000, 1, t1), None);
+    }
+}
+// LCOV_EXCL_STOP
@@ -0,0 +1,197 @@
+// vim: tw=80
+//! A simple in-memory DML, for testing
+//!
+//! Unlike a `MockDML`, `MemDml` actually stores the records that are put into
+//! it, so tests can exercise long sequences of operations without having to
+//! predict every DML call in advance.
+
+use crate::{
+    dml::*,
+    types::*,
+    writeback::Credit
+};
+use futures::{Future, FutureExt, future};
+use std::{
+    collections::HashMap,
+    mem,
+    pin::Pin,
+    sync::{
+        Mutex,
+        atomic::{AtomicU32, Ordering}
+    }
+};
+
+/// A DML that stores records in a `HashMap`, without serializing them.
+///
+/// Addresses are assigned sequentially and never reused.  Every operation
+/// completes immediately.
+#[derive(Debug, Default)]
+pub struct MemDml {
+    next_addr: AtomicU32,
+    records: Mutex<HashMap<u32, Box<dyn Cacheable>>>
+}
+
+impl MemDml {
+    /// Is the given address currently allocated?
+    pub fn contains(&self, addr: &u32) -> bool {
+        self.records.lock().unwrap().contains_key(addr)
+    }
+
+    /// Are there no records stored?
+    pub fn is_empty(&self) -> bool {
+        self.records.lock().unwrap().is_empty()
+    }
+
+    /// How many records are currently stored?
+    pub fn len(&self) -> usize {
+        self.records.lock().unwrap().len()
+    }
+}
+
+impl DML for MemDml {
+    type Addr = u32;
+
+    fn delete(&self, addr: &u32, _txg: TxgT)
+        -> Pin<Box<dyn Future<Output=Result<()>> + Send>>
+    {
+        let r = self.records.lock().unwrap()
+            .remove(addr)
+            .map(drop)
+            .ok_or(Error::ENOENT);
+        future::ready(r).boxed()
+    }
+
+    fn evict(&self, _addr: &u32) {
+        // Records live only in RAM, so there's nowhere to evict them to.
+    }
+
+    fn get<T: Cacheable, R: CacheRef>(&self, addr: &u32)
+        -> Pin<Box<dyn Future<Output=Result<Box<R>>> + Send>>
+    {
+        let r = self.records.lock().unwrap()
+            .get(addr)
+            .map(|cacheable| cacheable.make_ref().downcast::<R>().unwrap())
+            .ok_or(Error::ENOENT);
+        future::ready(r).boxed()
+    }
+
+    fn pop<T: Cacheable, R: CacheRef>(&self, addr: &u32, _txg: TxgT)
+        -> Pin<Box<dyn Future<Output=Result<Box<T>>> + Send>>
+    {
+        let r = self.records.lock().unwrap()
+            .remove(addr)
+            .map(|cacheable| cacheable.downcast::<T>().unwrap())
+            .ok_or(Error::ENOENT);
+        future::ready(r).boxed()
+    }
+
+    fn put<T: Cacheable>(&self, cacheable: T, _compression: Compression,
+                         _txg: TxgT)
+        -> Pin<Box<dyn Future<Output=Result<u32>> + Send>>
+    {
+        let addr = self.next_addr.fetch_add(1, Ordering::Relaxed);
+        self.records.lock().unwrap().insert(addr, Box::new(cacheable));
+        future::ok(addr).boxed()
+    }
+
+    fn put_ditto<T: Cacheable>(&self, cacheable: T,
+                               _compression: Compression, _txg: TxgT)
+        -> Pin<Box<dyn Future<Output=Result<(u32, u32)>> + Send>>
+    {
+        let addr = self.next_addr.fetch_add(2, Ordering::Relaxed);
+        let mut records = self.records.lock().unwrap();
+        records.insert(addr + 1, cacheable.make_ref().into_owned());
+        records.insert(addr, Box::new(cacheable));
+        future::ok((addr, addr + 1)).boxed()
+    }
+
+    fn repay(&self, credit: Credit) {
+        // There's no WriteBack to repay.
+        mem::forget(credit);
+    }
+
+    fn sync_all(&self, _txg: TxgT)
+        -> Pin<Box<dyn Future<Output=Result<()>> + Send>>
+    {
+        future::ok(()).boxed()
+    }
+}
+
+// LCOV_EXCL_START
+#[cfg(test)]
+mod t {
+    use divbuf::{DivBuf, DivBufShared};
+    use pretty_assertions::assert_eq;
+    use super::*;
+
+    #[test]
+    fn delete() {
+        let dml = MemDml::default();
+        let dbs = DivBufShared::from(vec![1u8, 2, 3]);
+        let txg = TxgT::from(0);
+        let addr = dml.put(dbs, Compression::None, txg)
+            .now_or_never().unwrap()
+            .unwrap();
+        dml.delete(&addr, txg).now_or_never().unwrap().unwrap();
+        assert!(dml.is_empty());
+        let r = dml.get::<DivBufShared, DivBuf>(&addr).now_or_never().unwrap();
+        assert_eq!(r.err(), Some(Error::ENOENT));
+    }
+
+    #[test]
+    fn delete_enoent() {
+        let dml = MemDml::default();
+        let r = dml.delete(&0, TxgT::from(0)).now_or_never().unwrap();
+        assert_eq!(r, Err(Error::ENOENT));
+    }
+
+    #[test]
+    fn get() {
+        let dml = MemDml::default();
+        let dbs = DivBufShared::from(vec![1u8, 2, 3]);
+        let addr = dml.put(dbs, Compression::None, TxgT::from(0))
+            .now_or_never().unwrap()
+            .unwrap();
+        let db = dml.get::<DivBufShared, DivBuf>(&addr)
+            .now_or_never().unwrap()
+            .unwrap();
+        assert_eq!(&db[..], &[1, 2, 3]);
+        // get shouldn't remove the record
+        drop(db);
+        assert!(dml.contains(&addr));
+    }
+
+    #[test]
+    fn pop() {
+        let dml = MemDml::default();
+        let txg = TxgT::from(0);
+        let dbs = DivBufShared::from(vec![1u8, 2, 3]);
+        let addr = dml.put(dbs, Compression::None, txg)
+            .now_or_never().unwrap()
+            .unwrap();
+        let dbs = dml.pop::<DivBufShared, DivBuf>(&addr, txg)
+            .now_or_never().unwrap()
+            .unwrap();
+        assert_eq!(&dbs.try_const().unwrap()[..], &[1, 2, 3]);
+        assert!(!dml.contains(&addr));
+    }
+
+    /// Addresses should never be reused
+    #[test]
+    fn put() {
+        let dml = MemDml::default();
+        let txg = TxgT::from(0);
+        let dbs0 = DivBufShared::from(vec![0u8]);
+        let dbs1 = DivBufShared::from(vec![1u8]);
+        let addr0 = dml.put(dbs0, Compression::None, txg)
+            .now_or_never().unwrap()
+            .unwrap();
+        dml.delete(&addr0, txg).now_or_never().unwrap().unwrap();
+        let addr1 = dml.put(dbs1, Compression::None, txg)
+            .now_or_never().unwrap()
+            .unwrap();
+        assert_ne!(addr0, addr1);
+        assert_eq!(dml.len(), 1);
+    }
+}
+// LCOV_EXCL_STOP
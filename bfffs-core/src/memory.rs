@@ -0,0 +1,285 @@
+// vim: tw=80
+//! Central accounting for bfffsd's large allocations
+//!
+//! Without a budget, the cache, the writeback cache, the RPC server, and every
+//! FUSE mount each enforce their own limit, so bfffsd's worst-case memory use
+//! is the sum of all of them.  A `MemoryBudget` instead tracks all of them
+//! against a single total.
+//!
+//! Each [`Consumer`] is guaranteed a fixed share of the total.  While the total
+//! isn't exhausted, any consumer may borrow memory beyond its share.  Once it
+//! is, every consumer over its share gets pushed back, in proportion to how far
+//! over it is.  Elastic consumers like the cache respond by shrinking; the
+//! others by waiting for their own allocations to be freed.
+
+use std::{
+    mem,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering::Relaxed}
+    }
+};
+use tokio::sync::Notify;
+
+/// Subsystems whose memory is tracked by a [`MemoryBudget`]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Consumer {
+    /// Clean cached blocks
+    Cache = 0,
+    /// Dirty data that hasn't yet been written to disk
+    Writeback = 1,
+    /// Read data buffered by FUSE mounts while their replies are sent
+    Fuse = 2,
+    /// Buffers for in-flight requests and replies on bfffsd's RPC socket
+    Rpc = 3,
+}
+
+impl Consumer {
+    const ALL: [Consumer; 4] = [
+        Consumer::Cache,
+        Consumer::Writeback,
+        Consumer::Fuse,
+        Consumer::Rpc
+    ];
+
+    /// This consumer's guaranteed share of the total, in thousandths.
+    fn weight(self) -> u128 {
+        match self {
+            Consumer::Cache => 500,
+            Consumer::Writeback => 300,
+            Consumer::Fuse => 150,
+            Consumer::Rpc => 50,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Inner {
+    total: usize,
+    used: [AtomicUsize; 4],
+    /// Notified whenever any memory is released
+    released: Notify,
+}
+
+/// A limit on bfffsd's total memory consumption, shared by all subsystems.
+///
+/// Cloning a `MemoryBudget` yields another handle to the same budget.
+#[derive(Clone, Debug)]
+pub struct MemoryBudget {
+    inner: Arc<Inner>
+}
+
+impl MemoryBudget {
+    /// Does `consumer` have room for `bytes` more right now?
+    ///
+    /// A consumer holding nothing may always allocate, so that a single
+    /// allocation larger than its allowance can't wait forever.
+    pub fn admits(&self, consumer: Consumer, bytes: usize) -> bool {
+        let used = self.used(consumer);
+        used == 0 || used + bytes <= self.allowance(consumer)
+    }
+
+    /// How much memory may `consumer` hold right now?
+    ///
+    /// That's its share of the total, plus whatever nobody else is using.  If
+    /// the total is already exceeded, then each consumer over its share must
+    /// give up part of the overage in proportion to its excess.
+    pub fn allowance(&self, consumer: Consumer) -> usize {
+        let used = self.used(consumer);
+        let share = self.share(consumer);
+        let used_total = self.used_total();
+        if used_total <= self.inner.total {
+            return share.max(used + (self.inner.total - used_total));
+        }
+        let excess = used.saturating_sub(share);
+        if excess == 0 {
+            return share;
+        }
+        let overage = used_total - self.inner.total;
+        let all_excess = Consumer::ALL.iter()
+            .map(|c| self.used(*c).saturating_sub(self.share(*c)))
+            .sum::<usize>();
+        // Since the shares sum to the total, overage <= all_excess
+        let cut = (overage as u128 * excess as u128 / all_excess as u128)
+            as usize;
+        used - cut.min(excess)
+    }
+
+    /// Record that `consumer` has allocated `bytes`, whether or not it had room
+    /// for them.
+    ///
+    /// Consumers that can't wait, like the cache, use this and then check
+    /// their [`allowance`](MemoryBudget::allowance).
+    pub fn charge(&self, consumer: Consumer, bytes: usize) {
+        self.inner.used[consumer as usize].fetch_add(bytes, Relaxed);
+    }
+
+    /// Create a budget of `total` bytes
+    pub fn new(total: usize) -> Self {
+        let inner = Inner {
+            total,
+            used: Default::default(),
+            released: Notify::new()
+        };
+        MemoryBudget{inner: Arc::new(inner)}
+    }
+
+    /// Record that `consumer` has freed `bytes`.
+    pub fn release(&self, consumer: Consumer, bytes: usize) {
+        if bytes == 0 {
+            return;
+        }
+        let old = self.inner.used[consumer as usize].fetch_sub(bytes, Relaxed);
+        debug_assert!(old >= bytes, "{consumer:?} released more than it had");
+        self.inner.released.notify_waiters();
+    }
+
+    /// Wait until `consumer` has room for `bytes`, and charge them until the
+    /// returned `Reservation` is dropped.
+    pub async fn reserve(&self, consumer: Consumer, bytes: usize)
+        -> Reservation
+    {
+        loop {
+            // Register for notification before checking, so a release that
+            // happens in between can't be missed.
+            let notified = self.inner.released.notified();
+            // NB: checking and charging aren't atomic, so concurrent
+            // reservations may slightly exceed the allowance.  That's ok.  The
+            // budget is a target, not a hard limit.
+            if self.admits(consumer, bytes) {
+                self.charge(consumer, bytes);
+                return Reservation {
+                    budget: self.clone(),
+                    consumer,
+                    bytes
+                };
+            }
+            notified.await;
+        }
+    }
+
+    /// The part of the total guaranteed to `consumer`, in bytes.
+    pub fn share(&self, consumer: Consumer) -> usize {
+        (self.inner.total as u128 * consumer.weight() / 1000) as usize
+    }
+
+    /// The configured total, in bytes.
+    pub fn total(&self) -> usize {
+        self.inner.total
+    }
+
+    /// How much memory is `consumer` using right now?
+    pub fn used(&self, consumer: Consumer) -> usize {
+        self.inner.used[consumer as usize].load(Relaxed)
+    }
+
+    /// How much memory are all consumers using right now?
+    pub fn used_total(&self) -> usize {
+        Consumer::ALL.iter().map(|c| self.used(*c)).sum()
+    }
+}
+
+/// Memory charged to a [`MemoryBudget`].  It will be released on drop.
+#[derive(Debug)]
+pub struct Reservation {
+    budget: MemoryBudget,
+    consumer: Consumer,
+    bytes: usize
+}
+
+impl Reservation {
+    /// Keep the memory charged even after dropping the `Reservation`.  The
+    /// caller becomes responsible for eventually calling
+    /// [`MemoryBudget::release`].
+    pub fn forget(mut self) {
+        self.bytes = 0;
+    }
+
+    /// The size of the reservation, in bytes
+    pub fn len(&self) -> usize {
+        self.bytes
+    }
+
+    /// Is the reservation empty?
+    pub fn is_empty(&self) -> bool {
+        self.bytes == 0
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let bytes = mem::take(&mut self.bytes);
+        self.budget.release(self.consumer, bytes);
+    }
+}
+
+// LCOV_EXCL_START
+#[cfg(test)]
+mod t {
+    use super::*;
+    use futures::FutureExt;
+    use futures_test::task::noop_context;
+
+    /// Memory that nobody else is using may be borrowed
+    #[test]
+    fn borrow_idle() {
+        let budget = MemoryBudget::new(1000);
+        assert_eq!(budget.share(Consumer::Writeback), 300);
+        budget.charge(Consumer::Writeback, 800);
+        assert_eq!(budget.allowance(Consumer::Writeback), 1000);
+        assert!(budget.admits(Consumer::Writeback, 200));
+        assert!(!budget.admits(Consumer::Writeback, 201));
+        // Cache may still use its share, even though writeback borrowed it
+        assert_eq!(budget.allowance(Consumer::Cache), 500);
+        budget.release(Consumer::Writeback, 800);
+        assert_eq!(budget.used_total(), 0);
+    }
+
+    /// A single allocation larger than the allowance is allowed, if the
+    /// consumer has nothing else.
+    #[test]
+    fn oversized() {
+        let budget = MemoryBudget::new(1000);
+        budget.charge(Consumer::Cache, 1000);
+        assert!(budget.admits(Consumer::Rpc, 5000));
+        let r = budget.reserve(Consumer::Rpc, 5000).now_or_never().unwrap();
+        assert_eq!(r.len(), 5000);
+        assert!(!budget.admits(Consumer::Rpc, 1));
+        drop(r);
+        assert_eq!(budget.used(Consumer::Rpc), 0);
+    }
+
+    /// When the total is exceeded, consumers over their share must give back
+    /// the overage in proportion to their excess.
+    #[test]
+    fn proportional() {
+        let budget = MemoryBudget::new(1000);
+        // Cache is 300 over its share, writeback 100 over, rpc and fuse under
+        budget.charge(Consumer::Cache, 800);
+        budget.charge(Consumer::Writeback, 400);
+        budget.charge(Consumer::Rpc, 40);
+        // Total overage is 240.  Cache must give up 3/4 of it.
+        assert_eq!(budget.allowance(Consumer::Cache), 620);
+        assert_eq!(budget.allowance(Consumer::Writeback), 340);
+        // Consumers under their share may still grow to it.
+        assert_eq!(budget.allowance(Consumer::Fuse), 150);
+        assert_eq!(budget.allowance(Consumer::Rpc), 50);
+    }
+
+    /// A reservation waits until the consumer's allowance can accommodate it
+    #[test]
+    fn reserve_waits() {
+        let mut ctx = noop_context();
+        let budget = MemoryBudget::new(1000);
+        budget.charge(Consumer::Cache, 900);
+        let r0 = budget.reserve(Consumer::Fuse, 100).now_or_never().unwrap();
+        let mut fut = budget.reserve(Consumer::Fuse, 100).boxed();
+        assert!(fut.poll_unpin(&mut ctx).is_pending());
+        drop(r0);
+        let r1 = fut.now_or_never().unwrap();
+        assert_eq!(budget.used(Consumer::Fuse), 100);
+        r1.forget();
+        assert_eq!(budget.used(Consumer::Fuse), 100);
+    }
+}
+// LCOV_EXCL_STOP
@@ -6,23 +6,37 @@
 //! as temporary mirrors, used for spares and replacements.
 
 use std::{
+    collections::{BTreeMap, BTreeSet},
     io,
     num::NonZeroU64,
     path::Path,
-    sync::atomic::{AtomicU32, Ordering}
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+        Mutex
+    }
 };
 
+use divbuf::DivBufShared;
 use futures::{
+    Future,
+    FutureExt,
+    StreamExt,
     TryFutureExt,
     TryStreamExt,
+    future,
     stream::FuturesUnordered
 };
 use serde_derive::{Deserialize, Serialize};
 
 use crate::{
+    capacity::MirrorShape,
+    determinism,
     label::*,
     types::*,
+    util::*,
     vdev::*,
+    vdev_block::VdevLeaf,
 };
 #[cfg(test)] use mockall::mock;
 #[cfg(test)]
@@ -31,11 +45,129 @@ use crate::vdev_block::MockVdevBlock as VdevBlock;
 use crate::vdev_block::VdevBlock;
 
 
+/// Maximum number of regions in each child's dirty-region log.
+///
+/// The logs are stored in the label, which has limited space.  Beyond this,
+/// nearby regions will be merged, at the cost of resilvering some clean data.
+const DRL_MAX_REGIONS: usize = 64;
+
+/// Maximum size of each read and write issued while resilvering, in LBAs
+const RESILVER_CHUNK: LbaT = 256;
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Label {
     /// Vdev UUID, fixed at format time
     pub uuid:           Uuid,
-    pub children:       Vec<Uuid>
+    pub children:       Vec<Uuid>,
+    /// Dirty regions of each degraded child, indexed by the child's UUID
+    pub dirty:          BTreeMap<Uuid, DirtyRegions>,
+    /// UUIDs of the write-mostly children
+    pub write_mostly:   BTreeSet<Uuid>
+}
+
+impl LabelLayer for Label {
+    type V0 = LabelV0;
+}
+
+/// `Label` as it was in version 0 labels, before dirty regions and
+/// write-mostly children were recorded
+#[derive(Deserialize)]
+pub struct LabelV0 {
+    uuid:               Uuid,
+    children:           Vec<Uuid>
+}
+
+impl From<LabelV0> for Label {
+    fn from(v0: LabelV0) -> Self {
+        Label {
+            uuid: v0.uuid,
+            children: v0.children,
+            dirty: BTreeMap::new(),
+            write_mostly: BTreeSet::new()
+        }
+    }
+}
+
+/// A dirty-region log.
+///
+/// Records the LBA ranges of a child which are stale, because writes to them
+/// failed while the write to some other child succeeded.  A child with any
+/// dirty regions is degraded.  It won't be read from in those regions, and
+/// they'll be repaired by [`Mirror::resilver`].
+///
+/// Because BFFFS writes each zone sequentially, even a long outage will
+/// usually leave only one region per open zone.  So after the child returns,
+/// resilvering it is much faster than copying the whole device.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct DirtyRegions(BTreeMap<LbaT, LbaT>);
+
+impl DirtyRegions {
+    /// Return the first dirty region, as a half-open range of LBAs
+    fn first(&self) -> Option<(LbaT, LbaT)> {
+        self.0.iter().next().map(|(start, end)| (*start, *end))
+    }
+
+    /// Mark the LBAs from `start` up to but excluding `end` as dirty
+    fn insert(&mut self, mut start: LbaT, mut end: LbaT) {
+        // Absorb any region that overlaps or abuts this one
+        let neighbors = self.0.range(..=end)
+            .filter(|(_, e)| **e >= start)
+            .map(|(s, e)| (*s, *e))
+            .collect::<Vec<_>>();
+        for (s, e) in neighbors {
+            self.0.remove(&s);
+            start = start.min(s);
+            end = end.max(e);
+        }
+        self.0.insert(start, end);
+        while self.0.len() > DRL_MAX_REGIONS {
+            self.merge_closest();
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Does any dirty region overlap the LBAs from `start` to `end`,
+    /// exclusive?
+    fn overlaps(&self, start: LbaT, end: LbaT) -> bool {
+        self.0.range(..end).any(|(_, e)| *e > start)
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Merge the two regions with the smallest gap between them
+    fn merge_closest(&mut self) {
+        let (s0, s1, e1) = self.0.iter()
+            .zip(self.0.iter().skip(1))
+            .min_by_key(|((_, e0), (s1, _))| **s1 - **e0)
+            .map(|((s0, _), (s1, e1))| (*s0, *s1, *e1))
+            .unwrap();
+        self.0.remove(&s1);
+        self.0.insert(s0, e1);
+    }
+
+    /// Mark the LBAs from `start` to `end`, exclusive, as clean, either because
+    /// they've been repaired or because their contents are no longer needed.
+    fn remove(&mut self, start: LbaT, end: LbaT) {
+        let overlapping = self.0.range(..end)
+            .filter(|(_, e)| **e > start)
+            .map(|(s, e)| (*s, *e))
+            .collect::<Vec<_>>();
+        for (s, e) in overlapping {
+            self.0.remove(&s);
+            if s < start {
+                self.0.insert(s, start);
+            }
+            if e > end {
+                self.0.insert(end, e);
+            }
+        }
+    }
 }
 
 /// `Mirror`: Device mirroring, both permanent and temporary
@@ -55,13 +187,73 @@ pub struct Mirror {
     // NB it might be different for reads than for writes
     optimum_queue_depth: u32,
 
+    /// Dirty-region logs of each child, in the same order as `blockdevs`
+    dirty: Arc<Mutex<Vec<DirtyRegions>>>,
+
+    /// Children that have failed a write since they were last resilvered.
+    ///
+    /// They won't be read from or written to, so a missing disk won't delay
+    /// every write until it times out.  Instead, every write will be recorded
+    /// in their dirty-region logs.
+    faulted: Arc<[AtomicBool]>,
+
     /// Size of the vdev in bytes.  It's the minimum of the childrens' sizes.
     size: LbaT,
 
     uuid: Uuid,
+
+    /// Write-mostly children, in the same order as `blockdevs`.
+    ///
+    /// They're written like any other child, but only read from when no other
+    /// child can satisfy the read.  Useful for pairing a slow device, like an
+    /// HDD or a remote iSCSI LUN, with a fast one.
+    write_mostly: Vec<bool>,
 }
 
 impl Mirror {
+    /// Issue an operation to every child that hasn't been removed, and wait for
+    /// all of them.
+    ///
+    /// Removed children will be brought up to date when they come back online.
+    /// A child that disappears during the operation is tolerated too, as long
+    /// as some other child succeeds.
+    fn broadcast<F, R>(&self, f: F) -> BoxVdevFut
+        where F: FnMut(&VdevBlock) -> R,
+              R: Future<Output=Result<()>> + Send + Sync + 'static
+    {
+        let fut = self.blockdevs.iter()
+        .filter(|blockdev| !blockdev.is_removed())
+        .map(f)
+        .collect::<FuturesUnordered<_>>()
+        .collect::<Vec<_>>()
+        .map(|results| {
+            let mut any_ok = false;
+            for r in results {
+                match r {
+                    Ok(()) => any_ok = true,
+                    Err(Error::ENXIO | Error::ENODEV) => (),
+                    Err(e) => return Err(e)
+                }
+            }
+            if any_ok {
+                Ok(())
+            } else {
+                Err(Error::ENXIO)
+            }
+        });
+        Box::pin(fut)
+    }
+
+    /// Record that data read from this mirror failed checksum verification.
+    ///
+    /// The `Mirror` doesn't remember which child satisfied each read, so every
+    /// child gets the blame.
+    pub fn checksum_error(&self) {
+        for blockdev in self.blockdevs.iter() {
+            blockdev.checksum_error();
+        }
+    }
+
     /// Create a new Mirror from unused files or devices
     ///
     /// * `lbas_per_zone`:      If specified, this many LBAs will be assigned to
@@ -82,17 +274,33 @@ impl Mirror {
         Ok(Mirror::new(uuid, blockdevs.into_boxed_slice()))
     }
 
+    /// Status of each child, including its lifetime I/O error counts
+    pub fn leaf_status(&self) -> Vec<LeafStatus> {
+        self.blockdevs.iter()
+            .zip(self.write_mostly.iter())
+            .map(|(blockdev, write_mostly)| LeafStatus {
+                uuid: blockdev.uuid(),
+                errors: blockdev.error_counts(),
+                write_mostly: *write_mostly,
+                removed: blockdev.is_removed()
+            }).collect()
+    }
+
     /// Asynchronously erase a zone on a mirror
     ///
     /// # Parameters
     /// - `start`:  The first LBA within the target zone
     /// - `end`:    The last LBA within the target zone
     pub fn erase_zone(&self, start: LbaT, end: LbaT) -> BoxVdevFut {
-        let fut = self.blockdevs.iter().map(|blockdev| {
+        let dirty = self.dirty.clone();
+        let fut = self.broadcast(|blockdev| {
             blockdev.erase_zone(start, end)
-        }).collect::<FuturesUnordered<_>>()
-        .try_collect::<Vec<_>>()
-        .map_ok(drop);
+        }).map_ok(move |_| {
+            // Stale data in an erased zone needn't be repaired
+            for drl in dirty.lock().unwrap().iter_mut() {
+                drl.remove(start, end + 1);
+            }
+        });
         Box::pin(fut)
     }
 
@@ -102,12 +310,7 @@ impl Mirror {
     /// - `start`:  The first LBA within the target zone
     /// - `end`:    The last LBA within the target zone
     pub fn finish_zone(&self, start: LbaT, end: LbaT) -> BoxVdevFut {
-        let fut = self.blockdevs.iter().map(|blockdev| {
-            blockdev.finish_zone(start, end)
-        }).collect::<FuturesUnordered<_>>()
-        .try_collect::<Vec<_>>()
-        .map_ok(drop);
-        Box::pin(fut)
+        self.broadcast(|blockdev| blockdev.finish_zone(start, end))
     }
 
     fn new(uuid: Uuid, blockdevs: Box<[VdevBlock]>) -> Self
@@ -128,12 +331,24 @@ impl Mirror {
         .min()
         .unwrap();
 
+        let dirty = Arc::new(Mutex::new(
+            vec![DirtyRegions::default(); blockdevs.len()]
+        ));
+        let faulted = blockdevs.iter()
+            .map(|_| AtomicBool::new(false))
+            .collect::<Vec<_>>()
+            .into();
+        let write_mostly = vec![false; blockdevs.len()];
+
         Self {
             uuid,
             next_read_idx,
             optimum_queue_depth,
+            dirty,
+            faulted,
             size,
-            blockdevs
+            blockdevs,
+            write_mostly,
         }
     }
 
@@ -160,36 +375,102 @@ impl Mirror {
                 vdev_block
             }).collect::<Vec<VdevBlock>>()
             .into_boxed_slice();
-        let (label, reader) = label_pair.unwrap();
-        (Mirror::new(label.uuid, children), reader)
+        let (mut label, reader) = label_pair.unwrap();
+        let mut mirror = Mirror::new(label.uuid, children);
+        for (i, blockdev) in mirror.blockdevs.iter().enumerate() {
+            mirror.write_mostly[i] =
+                label.write_mostly.contains(&blockdev.uuid());
+        }
+        {
+            let mut dirty = mirror.dirty.lock().unwrap();
+            for (i, blockdev) in mirror.blockdevs.iter().enumerate() {
+                if let Some(drl) = label.dirty.remove(&blockdev.uuid()) {
+                    dirty[i] = drl;
+                }
+            }
+        }
+        (mirror, reader)
+    }
+
+    /// Bring a removed or faulted child back online, and resilver whatever
+    /// writes it missed.
+    ///
+    /// A removed child's device is replaced by `leaf`, after it has
+    /// reappeared.  A child that was faulted by a write error, but never
+    /// removed, keeps its existing device.
+    ///
+    /// Fails with `ENOENT` if `leaf` isn't one of this mirror's children, or
+    /// `EBUSY` if the child is already healthy.
+    pub async fn online(&self, leaf: VdevLeaf) -> Result<()> {
+        let uuid = leaf.uuid();
+        let i = self.blockdevs.iter()
+            .position(|blockdev| blockdev.uuid() == uuid)
+            .ok_or(Error::ENOENT)?;
+        if self.blockdevs[i].is_removed() {
+            self.blockdevs[i].reopen(leaf);
+            tracing::info!(mirror = %self.uuid, child = %uuid,
+                "Child is back online");
+        } else if self.faulted[i].load(Ordering::Relaxed) ||
+            !self.dirty.lock().unwrap()[i].is_empty()
+        {
+            tracing::info!(mirror = %self.uuid, child = %uuid,
+                "Child is no longer faulted");
+        } else {
+            return Err(Error::EBUSY);
+        }
+        self.resilver().await
     }
 
     pub fn open_zone(&self, start: LbaT) -> BoxVdevFut {
-        let fut = self.blockdevs.iter().map(|blockdev| {
-            blockdev.open_zone(start)
-        }).collect::<FuturesUnordered<_>>()
-        .try_collect::<Vec<_>>()
-        .map_ok(drop);
-        Box::pin(fut)
+        self.broadcast(|blockdev| blockdev.open_zone(start))
     }
 
     pub fn read_at(&self, buf: IoVecMut, lba: LbaT) -> BoxVdevFut
     {
-        let idx = self.read_idx();
+        let lbas = div_roundup(buf.len(), BYTES_PER_LBA) as LbaT;
+        let idx = self.read_idx_for(lba, lbas);
         let fut = self.blockdevs[idx].read_at(buf, lba)
         .map_ok(drop);
         Box::pin(fut)
     }
 
-    /// Return the index of the next child to read from
-    fn read_idx(&self) -> usize {
+    /// Return the index of the next child to read from, starting at `lba`
+    ///
+    /// In deterministic mode, the choice depends on the seed and the LBA
+    /// rather than on the order in which concurrent reads arrive.
+    fn read_idx(&self, lba: LbaT) -> usize {
+        if let Some(seed) = determinism::seed() {
+            return (determinism::choose(seed, lba) %
+                self.blockdevs.len() as u64) as usize;
+        }
         self.next_read_idx.fetch_add(1, Ordering::Relaxed) as usize %
             self.blockdevs.len()
     }
 
+    /// Return the index of the next child to read from that has valid data
+    /// for all `lbas` LBAs starting at `lba`.
+    ///
+    /// Write-mostly children are only chosen if no other child is usable.
+    fn read_idx_for(&self, lba: LbaT, lbas: LbaT) -> usize {
+        let n = self.blockdevs.len();
+        let idx = self.read_idx(lba);
+        let dirty = self.dirty.lock().unwrap();
+        let usable = |i: &usize| {
+            !self.faulted[*i].load(Ordering::Relaxed) &&
+                !self.blockdevs[*i].is_removed() &&
+                !dirty[*i].overlaps(lba, lba + lbas)
+        };
+        let mut candidates = (0..n).map(|i| (idx + i) % n);
+        candidates.clone()
+            .find(|i| !self.write_mostly[*i] && usable(i))
+            .or_else(|| candidates.find(usable))
+            .unwrap_or(idx)
+    }
+
     pub fn read_spacemap(&self, buf: IoVecMut, smidx: u32) -> BoxVdevFut
     {
-        let ridx = self.read_idx();
+        // The spacemap is never dirty, so any healthy child will do
+        let ridx = self.read_idx_for(0, 0);
         let fut = self.blockdevs[ridx].read_spacemap(buf, smidx)
         .map_ok(drop);
         Box::pin(fut)
@@ -198,19 +479,126 @@ impl Mirror {
     #[tracing::instrument(skip(self, bufs))]
     pub fn readv_at(&self, bufs: SGListMut, lba: LbaT) -> BoxVdevFut
     {
-        let idx = self.read_idx();
+        let len = bufs.iter().map(|b| b.len()).sum::<usize>();
+        let lbas = div_roundup(len, BYTES_PER_LBA) as LbaT;
+        let idx = self.read_idx_for(lba, lbas);
         let fut = self.blockdevs[idx].readv_at(bufs, lba)
         .map_ok(drop);
         Box::pin(fut)
     }
 
+    /// Repair every degraded child by copying its dirty regions from a
+    /// healthy child.
+    ///
+    /// Faulted children are brought back online first, so new writes will go
+    /// to them again.  Removed children are skipped until their devices come
+    /// back.  Fails with `EIO` if some dirty region isn't clean on any other
+    /// child.
+    pub async fn resilver(&self) -> Result<()> {
+        let n = self.blockdevs.len();
+        for i in 0..n {
+            if self.blockdevs[i].is_removed() {
+                continue;
+            }
+            self.faulted[i].store(false, Ordering::Relaxed);
+            loop {
+                let region = self.dirty.lock().unwrap()[i].first();
+                let (start, end) = match region {
+                    Some((start, end)) =>
+                        (start, end.min(start + RESILVER_CHUNK)),
+                    None => break
+                };
+                let src = {
+                    let dirty = self.dirty.lock().unwrap();
+                    (0..n).find(|j| {
+                        *j != i &&
+                            !self.faulted[*j].load(Ordering::Relaxed) &&
+                            !self.blockdevs[*j].is_removed() &&
+                            !dirty[*j].overlaps(start, end)
+                    })
+                }.ok_or(Error::EIO)?;
+                let len = (end - start) as usize * BYTES_PER_LBA;
+                let dbs = DivBufShared::from(vec![0u8; len]);
+                self.blockdevs[src].read_at(dbs.try_mut().unwrap(), start)
+                    .await?;
+                let wbuf = dbs.try_const().unwrap();
+                if let Err(e) = self.blockdevs[i].write_at(wbuf, start).await
+                {
+                    self.faulted[i].store(true, Ordering::Relaxed);
+                    return Err(e);
+                }
+                self.dirty.lock().unwrap()[i].remove(start, end);
+            }
+        }
+        Ok(())
+    }
+
+    /// Mark the `child`th child as write-mostly, or not.
+    ///
+    /// The setting will be persisted by the next [`write_label`].
+    ///
+    /// [`write_label`]: Mirror::write_label
+    pub fn set_write_mostly(&mut self, child: usize, write_mostly: bool) {
+        self.write_mostly[child] = write_mostly;
+    }
+
+    /// Describe this mirror's layout, for capacity planning
+    pub fn shape(&self) -> MirrorShape {
+        let children = self.blockdevs.iter()
+            .map(|bd| (bd.uuid(), bd.size()))
+            .collect();
+        MirrorShape{size: self.size, children}
+    }
+
     pub fn write_at(&self, buf: IoVec, lba: LbaT) -> BoxVdevFut
     {
-        let fut = self.blockdevs.iter().map(|blockdev| {
-            blockdev.write_at(buf.clone(), lba)
-        }).collect::<FuturesUnordered<_>>()
-        .try_collect::<Vec<_>>()
-        .map_ok(drop);
+        let lbas = div_roundup(buf.len(), BYTES_PER_LBA) as LbaT;
+        let futs = self.blockdevs.iter().enumerate().map(|(i, blockdev)| {
+            if self.faulted[i].load(Ordering::Relaxed) {
+                Box::pin(future::err::<(), Error>(Error::ENXIO)) as BoxVdevFut
+            } else {
+                Box::pin(blockdev.write_at(buf.clone(), lba)) as BoxVdevFut
+            }
+        }).collect::<Vec<_>>();
+        self.write_degradable(futs, lba, lbas)
+    }
+
+    /// Wait for a write to every child, tolerating failures as long as at
+    /// least one child succeeds.  Children that failed will be degraded and
+    /// faulted.
+    fn write_degradable(&self, futs: Vec<BoxVdevFut>, lba: LbaT, lbas: LbaT)
+        -> BoxVdevFut
+    {
+        let dirty = self.dirty.clone();
+        let faulted = self.faulted.clone();
+        let uuid = self.uuid;
+        let fut = futs.into_iter()
+        .enumerate()
+        .map(|(i, fut)| fut.map(move |r| (i, r)))
+        .collect::<FuturesUnordered<_>>()
+        .collect::<Vec<_>>()
+        .map(move |results| {
+            let successes = results.iter().filter(|(_, r)| r.is_ok()).count();
+            if successes == 0 {
+                // The caller will treat the space as unwritten, so there's
+                // nothing to repair.
+                let e = results.into_iter()
+                    .find_map(|(_, r)| r.err())
+                    .unwrap();
+                return Err(e);
+            }
+            let mut dirty = dirty.lock().unwrap();
+            for (i, r) in results.into_iter() {
+                if let Err(error) = r {
+                    if !faulted[i].swap(true, Ordering::Relaxed) {
+                        tracing::warn!(mirror = %uuid, child = i, lba, ?error,
+                            "Write failed.  Child is faulted.");
+                    }
+                    dirty[i].insert(lba, lba + lbas);
+                }
+            }
+            Ok(())
+        });
         Box::pin(fut)
     }
 
@@ -218,38 +606,62 @@ impl Mirror {
     {
         let children_uuids = self.blockdevs.iter().map(|bd| bd.uuid())
             .collect::<Vec<_>>();
+        let dirty = self.dirty.lock().unwrap()
+            .iter()
+            .zip(children_uuids.iter())
+            .filter(|(drl, _)| !drl.is_empty())
+            .map(|(drl, uuid)| (*uuid, drl.clone()))
+            .collect::<BTreeMap<_, _>>();
+        let write_mostly = children_uuids.iter()
+            .zip(self.write_mostly.iter())
+            .filter(|(_, wm)| **wm)
+            .map(|(uuid, _)| *uuid)
+            .collect::<BTreeSet<_>>();
         let label = Label {
             uuid: self.uuid,
-            children: children_uuids
+            children: children_uuids,
+            dirty,
+            write_mostly
         };
         labeller.serialize(&label).unwrap();
-        let fut = self.blockdevs.iter().map(|bd| {
-           bd.write_label(labeller.clone())
-        }).collect::<FuturesUnordered<_>>()
-        .try_collect::<Vec<_>>()
-        .map_ok(drop);
+        self.broadcast(|bd| bd.write_label(labeller.clone()))
+    }
+
+    /// Asynchronously read the write pointer for the zone beginning at
+    /// `start`.
+    ///
+    /// A child that missed some writes may lag behind the others, so the
+    /// furthest write pointer of any child is returned.  Returns `None` if no
+    /// child has native zones.
+    pub fn write_pointer(&self, start: LbaT) -> BoxWritePointerFut {
+        let fut = self.blockdevs.iter()
+        .filter(|blockdev| !blockdev.is_removed())
+        .map(|blockdev| blockdev.write_pointer(start))
+        .collect::<FuturesUnordered<_>>()
+        .try_fold(None, |acc, wp| future::ok(acc.max(wp)));
         Box::pin(fut)
     }
 
     pub fn write_spacemap(&self, sglist: SGList, idx: u32, block: LbaT)
         ->  BoxVdevFut
     {
-        let fut = self.blockdevs.iter().map(|blockdev| {
+        self.broadcast(|blockdev| {
             blockdev.write_spacemap(sglist.clone(), idx, block)
-        }).collect::<FuturesUnordered<_>>()
-        .try_collect::<Vec<_>>()
-        .map_ok(drop);
-        Box::pin(fut)
+        })
     }
 
     pub fn writev_at(&self, bufs: SGList, lba: LbaT) -> BoxVdevFut
     {
-        let fut = self.blockdevs.iter().map(|blockdev| {
-            blockdev.writev_at(bufs.clone(), lba)
-        }).collect::<FuturesUnordered<_>>()
-        .try_collect::<Vec<_>>()
-        .map_ok(drop);
-        Box::pin(fut)
+        let len = bufs.iter().map(|b| b.len()).sum::<usize>();
+        let lbas = div_roundup(len, BYTES_PER_LBA) as LbaT;
+        let futs = self.blockdevs.iter().enumerate().map(|(i, blockdev)| {
+            if self.faulted[i].load(Ordering::Relaxed) {
+                Box::pin(future::err::<(), Error>(Error::ENXIO)) as BoxVdevFut
+            } else {
+                Box::pin(blockdev.writev_at(bufs.clone(), lba)) as BoxVdevFut
+            }
+        }).collect::<Vec<_>>();
+        self.write_degradable(futs, lba, lbas)
     }
 }
 
@@ -258,6 +670,13 @@ impl Vdev for Mirror {
         self.blockdevs[0].lba2zone(lba)
     }
 
+    fn max_open_zones(&self) -> Option<u32> {
+        // Every child must be able to keep the zone open
+        self.blockdevs.iter()
+            .filter_map(VdevBlock::max_open_zones)
+            .min()
+    }
+
     fn optimum_queue_depth(&self) -> u32 {
         self.optimum_queue_depth
     }
@@ -267,13 +686,8 @@ impl Vdev for Mirror {
     }
 
     fn sync_all(&self) -> BoxVdevFut {
-        // TODO: handle errors on some devices
-        let fut = self.blockdevs.iter()
-        .map(VdevBlock::sync_all)
-        .collect::<FuturesUnordered<_>>()
-        .try_collect::<Vec<_>>()
-        .map_ok(drop);
-        Box::pin(fut)
+        // TODO: handle errors other than removal on some devices
+        self.broadcast(VdevBlock::sync_all)
     }
 
     fn uuid(&self) -> Uuid {
@@ -293,20 +707,25 @@ impl Vdev for Mirror {
 #[cfg(test)]
 mock! {
     pub Mirror {
+        pub fn checksum_error(&self);
         #[mockall::concretize]
         pub fn create<P>(paths: &[P], lbas_per_zone: Option<NonZeroU64>)
             -> io::Result<Self>
             where P: AsRef<Path>;
         pub fn erase_zone(&self, start: LbaT, end: LbaT) -> BoxVdevFut;
         pub fn finish_zone(&self, start: LbaT, end: LbaT) -> BoxVdevFut;
+        pub fn leaf_status(&self) -> Vec<LeafStatus>;
+        pub async fn online(&self, leaf: VdevLeaf) -> Result<()>;
         pub fn open(uuid: Option<Uuid>, combined: Vec<(VdevBlock, LabelReader)>)
             -> (Self, LabelReader);
         pub fn open_zone(&self, start: LbaT) -> BoxVdevFut;
         pub fn read_at(&self, buf: IoVecMut, lba: LbaT) -> BoxVdevFut;
         pub fn read_spacemap(&self, buf: IoVecMut, idx: u32) -> BoxVdevFut;
         pub fn readv_at(&self, bufs: SGListMut, lba: LbaT) -> BoxVdevFut;
+        pub fn shape(&self) -> MirrorShape;
         pub fn write_at(&self, buf: IoVec, lba: LbaT) -> BoxVdevFut;
         pub fn write_label(&self, labeller: LabelWriter) -> BoxVdevFut;
+        pub fn write_pointer(&self, start: LbaT) -> BoxWritePointerFut;
         pub fn write_spacemap(&self, sglist: SGList, idx: u32, block: LbaT)
             ->  BoxVdevFut;
         pub fn writev_at(&self, bufs: SGList, lba: LbaT) -> BoxVdevFut;
@@ -330,13 +749,13 @@ mod t {
     use mockall::predicate::*;
     use super::*;
 
-    fn mock_vdev_block() -> VdevBlock {
+    fn partial_mock_vdev_block(uuid: Uuid) -> VdevBlock {
         const ZL0: (LbaT, LbaT) = (3, 32);
         //const ZL1 = (32, 64);
 
         let mut bd = VdevBlock::default();
         bd.expect_uuid()
-            .return_const(Uuid::new_v4());
+            .return_const(uuid);
         bd.expect_optimum_queue_depth()
             .return_const(10u32);
         bd.expect_size()
@@ -347,6 +766,78 @@ mod t {
         bd
     }
 
+    fn mock_vdev_block() -> VdevBlock {
+        let mut bd = partial_mock_vdev_block(Uuid::new_v4());
+        bd.expect_is_removed()
+            .return_const(false);
+        bd
+    }
+
+    mod checksum_error {
+        use super::*;
+
+        /// The mirror can't tell which child returned bad data, so it should
+        /// blame them all.
+        #[test]
+        fn basic() {
+            let mock = || {
+                let mut bd = mock_vdev_block();
+                bd.expect_checksum_error()
+                    .once()
+                    .return_const(());
+                bd
+            };
+            let bd0 = mock();
+            let bd1 = mock();
+            let mirror = Mirror::new(Uuid::new_v4(), vec![bd0, bd1].into());
+            mirror.checksum_error();
+        }
+    }
+
+    mod dirty_regions {
+        use super::*;
+
+        #[test]
+        fn insert_coalesces() {
+            let mut drl = DirtyRegions::default();
+            drl.insert(10, 20);
+            drl.insert(30, 40);
+            // Abuts the first region
+            drl.insert(20, 25);
+            assert_eq!(drl.len(), 2);
+            assert_eq!(drl.first(), Some((10, 25)));
+            // Bridges both regions
+            drl.insert(24, 31);
+            assert_eq!(drl.len(), 1);
+            assert_eq!(drl.first(), Some((10, 40)));
+        }
+
+        /// Beyond the size limit, the closest regions should be merged
+        #[test]
+        fn insert_cap() {
+            let mut drl = DirtyRegions::default();
+            for i in 0..DRL_MAX_REGIONS as LbaT {
+                drl.insert(100 * i + 10, 100 * i + 20);
+            }
+            assert_eq!(drl.len(), DRL_MAX_REGIONS);
+            drl.insert(1, 2);
+            assert_eq!(drl.len(), DRL_MAX_REGIONS);
+            assert_eq!(drl.first(), Some((1, 20)));
+        }
+
+        #[test]
+        fn remove_splits() {
+            let mut drl = DirtyRegions::default();
+            drl.insert(10, 20);
+            drl.insert(30, 40);
+            drl.remove(15, 35);
+            assert_eq!(drl.len(), 2);
+            assert_eq!(drl.first(), Some((10, 15)));
+            assert!(!drl.overlaps(15, 35));
+            assert!(drl.overlaps(35, 36));
+        }
+    }
+
     mod erase_zone {
         use super::*;
 
@@ -365,6 +856,28 @@ mod t {
             let mirror = Mirror::new(Uuid::new_v4(), vec![bd0, bd1].into());
             mirror.erase_zone(3, 31).now_or_never().unwrap().unwrap();
         }
+
+        /// Erasing a zone should forget its dirty regions, but not those of
+        /// other zones.
+        #[test]
+        fn clears_dirty() {
+            let mock = || {
+                let mut bd = mock_vdev_block();
+                bd.expect_erase_zone()
+                    .once()
+                    .with(eq(3), eq(31))
+                    .return_once(|_, _| Box::pin(future::ok::<(), Error>(())));
+                bd
+            };
+            let bd0 = mock();
+            let bd1 = mock();
+            let mirror = Mirror::new(Uuid::new_v4(), vec![bd0, bd1].into());
+            mirror.dirty.lock().unwrap()[1].insert(3, 5);
+            mirror.dirty.lock().unwrap()[1].insert(32, 33);
+            mirror.erase_zone(3, 31).now_or_never().unwrap().unwrap();
+            let dirty = mirror.dirty.lock().unwrap();
+            assert_eq!(dirty[1].first(), Some((32, 33)));
+        }
     }
     mod finish_zone {
         use super::*;
@@ -391,6 +904,100 @@ mod t {
         }
     }
 
+    mod online {
+        use super::*;
+        use crate::vdev_file::MockVdevFile;
+
+        fn mock_leaf(uuid: Uuid) -> MockVdevFile {
+            let mut leaf = MockVdevFile::new();
+            leaf.expect_uuid()
+                .return_const(uuid);
+            leaf
+        }
+
+        /// Reattaching a removed child should resilver whatever it missed
+        #[test]
+        fn basic() {
+            let uuid1 = Uuid::new_v4();
+            let mut bd0 = mock_vdev_block();
+            bd0.expect_read_at()
+                .once()
+                .withf(|buf, lba| buf.len() == 8192 && *lba == 3)
+                .returning(|_, _| Box::pin(future::ok::<(), Error>(())));
+            let removed = Arc::new(AtomicBool::new(true));
+            let removed2 = removed.clone();
+            let mut bd1 = partial_mock_vdev_block(uuid1);
+            bd1.expect_is_removed()
+                .returning(move || removed.load(Ordering::Relaxed));
+            bd1.expect_reopen()
+                .once()
+                .returning(move |_| removed2.store(false, Ordering::Relaxed));
+            bd1.expect_write_at()
+                .once()
+                .withf(|buf, lba| buf.len() == 8192 && *lba == 3)
+                .return_once(|_, _| Box::pin(future::ok::<(), Error>(())));
+            let mirror = Mirror::new(Uuid::new_v4(), vec![bd0, bd1].into());
+            mirror.dirty.lock().unwrap()[1].insert(3, 5);
+            mirror.faulted[1].store(true, Ordering::Relaxed);
+            mirror.online(mock_leaf(uuid1)).now_or_never().unwrap().unwrap();
+            assert!(mirror.dirty.lock().unwrap()[1].is_empty());
+            assert!(!mirror.faulted[1].load(Ordering::Relaxed));
+        }
+
+        /// A child that was faulted by a write error, but never removed, should
+        /// be resilvered without reopening its device.
+        #[test]
+        fn faulted() {
+            let uuid1 = Uuid::new_v4();
+            let mut bd0 = mock_vdev_block();
+            bd0.expect_read_at()
+                .once()
+                .withf(|buf, lba| buf.len() == 8192 && *lba == 3)
+                .returning(|_, _| Box::pin(future::ok::<(), Error>(())));
+            let mut bd1 = partial_mock_vdev_block(uuid1);
+            bd1.expect_is_removed()
+                .return_const(false);
+            bd1.expect_reopen()
+                .never();
+            bd1.expect_write_at()
+                .once()
+                .withf(|buf, lba| buf.len() == 8192 && *lba == 3)
+                .return_once(|_, _| Box::pin(future::ok::<(), Error>(())));
+            let mirror = Mirror::new(Uuid::new_v4(), vec![bd0, bd1].into());
+            mirror.dirty.lock().unwrap()[1].insert(3, 5);
+            mirror.faulted[1].store(true, Ordering::Relaxed);
+            mirror.online(mock_leaf(uuid1)).now_or_never().unwrap().unwrap();
+            assert!(mirror.dirty.lock().unwrap()[1].is_empty());
+            assert!(!mirror.faulted[1].load(Ordering::Relaxed));
+        }
+
+        /// A healthy child can't be brought online
+        #[test]
+        fn ebusy() {
+            let uuid1 = Uuid::new_v4();
+            let bd0 = mock_vdev_block();
+            let mut bd1 = partial_mock_vdev_block(uuid1);
+            bd1.expect_is_removed()
+                .return_const(false);
+            bd1.expect_reopen()
+                .never();
+            let mirror = Mirror::new(Uuid::new_v4(), vec![bd0, bd1].into());
+            let r = mirror.online(mock_leaf(uuid1)).now_or_never().unwrap();
+            assert_eq!(r, Err(Error::EBUSY));
+        }
+
+        /// A device from somewhere else can't be brought online
+        #[test]
+        fn enoent() {
+            let bd0 = mock_vdev_block();
+            let bd1 = mock_vdev_block();
+            let mirror = Mirror::new(Uuid::new_v4(), vec![bd0, bd1].into());
+            let leaf = mock_leaf(Uuid::new_v4());
+            let r = mirror.online(leaf).now_or_never().unwrap();
+            assert_eq!(r, Err(Error::ENOENT));
+        }
+    }
+
     mod open_zone {
         use super::*;
 
@@ -475,6 +1082,218 @@ mod t {
                 mirror.read_at(buf, i).now_or_never().unwrap().unwrap();
             }
         }
+
+        /// Reads should avoid a child's dirty regions
+        #[test]
+        fn degraded() {
+            let dbs = DivBufShared::from(vec![0u8; 4096]);
+
+            let mut bd0 = mock_vdev_block();
+            bd0.expect_read_at()
+                .never();
+            let mut bd1 = mock_vdev_block();
+            bd1.expect_read_at()
+                .times(2)
+                .with(always(), eq(3))
+                .returning(|_, _| Box::pin(future::ok::<(), Error>(())));
+            let mirror = Mirror::new(Uuid::new_v4(), vec![bd0, bd1].into());
+            mirror.dirty.lock().unwrap()[0].insert(3, 4);
+            for _ in 0..2 {
+                let buf = dbs.try_mut().unwrap();
+                mirror.read_at(buf, 3).now_or_never().unwrap().unwrap();
+            }
+        }
+
+        /// Reads should avoid a faulted child, even outside of its dirty
+        /// regions
+        #[test]
+        fn faulted() {
+            let dbs = DivBufShared::from(vec![0u8; 4096]);
+
+            let mut bd0 = mock_vdev_block();
+            bd0.expect_read_at()
+                .times(2)
+                .with(always(), eq(3))
+                .returning(|_, _| Box::pin(future::ok::<(), Error>(())));
+            let mut bd1 = mock_vdev_block();
+            bd1.expect_read_at()
+                .never();
+            let mirror = Mirror::new(Uuid::new_v4(), vec![bd0, bd1].into());
+            mirror.faulted[1].store(true, Ordering::Relaxed);
+            for _ in 0..2 {
+                let buf = dbs.try_mut().unwrap();
+                mirror.read_at(buf, 3).now_or_never().unwrap().unwrap();
+            }
+        }
+
+        /// Reads should avoid a removed child
+        #[test]
+        fn removed() {
+            let dbs = DivBufShared::from(vec![0u8; 4096]);
+
+            let mut bd0 = partial_mock_vdev_block(Uuid::new_v4());
+            bd0.expect_is_removed()
+                .return_const(true);
+            bd0.expect_read_at()
+                .never();
+            let mut bd1 = mock_vdev_block();
+            bd1.expect_read_at()
+                .times(2)
+                .with(always(), eq(3))
+                .returning(|_, _| Box::pin(future::ok::<(), Error>(())));
+            let mirror = Mirror::new(Uuid::new_v4(), vec![bd0, bd1].into());
+            for _ in 0..2 {
+                let buf = dbs.try_mut().unwrap();
+                mirror.read_at(buf, 3).now_or_never().unwrap().unwrap();
+            }
+        }
+
+        /// Reads should avoid a write-mostly child
+        #[test]
+        fn write_mostly() {
+            let dbs = DivBufShared::from(vec![0u8; 4096]);
+
+            let mut bd0 = mock_vdev_block();
+            bd0.expect_read_at()
+                .never();
+            let mut bd1 = mock_vdev_block();
+            bd1.expect_read_at()
+                .times(2)
+                .with(always(), eq(3))
+                .returning(|_, _| Box::pin(future::ok::<(), Error>(())));
+            let mut mirror =
+                Mirror::new(Uuid::new_v4(), vec![bd0, bd1].into());
+            mirror.set_write_mostly(0, true);
+            for _ in 0..2 {
+                let buf = dbs.try_mut().unwrap();
+                mirror.read_at(buf, 3).now_or_never().unwrap().unwrap();
+            }
+        }
+
+        /// A write-mostly child should still be read if no other child can
+        /// satisfy the read.
+        #[test]
+        fn write_mostly_fallback() {
+            let dbs = DivBufShared::from(vec![0u8; 4096]);
+
+            let mut bd0 = mock_vdev_block();
+            bd0.expect_read_at()
+                .times(2)
+                .with(always(), eq(3))
+                .returning(|_, _| Box::pin(future::ok::<(), Error>(())));
+            let mut bd1 = mock_vdev_block();
+            bd1.expect_read_at()
+                .never();
+            let mut mirror =
+                Mirror::new(Uuid::new_v4(), vec![bd0, bd1].into());
+            mirror.set_write_mostly(0, true);
+            mirror.faulted[1].store(true, Ordering::Relaxed);
+            for _ in 0..2 {
+                let buf = dbs.try_mut().unwrap();
+                mirror.read_at(buf, 3).now_or_never().unwrap().unwrap();
+            }
+        }
+    }
+
+    mod resilver {
+        use super::*;
+
+        #[test]
+        fn basic() {
+            let mut bd0 = mock_vdev_block();
+            bd0.expect_read_at()
+                .once()
+                .withf(|buf, lba| buf.len() == 8192 && *lba == 3)
+                .returning(|mut buf, _| {
+                    buf.copy_from_slice(&[42u8; 8192][..]);
+                    Box::pin(future::ok::<(), Error>(()))
+                });
+            let mut bd1 = mock_vdev_block();
+            bd1.expect_write_at()
+                .once()
+                .withf(|buf, lba| buf[..] == [42u8; 8192][..] && *lba == 3)
+                .return_once(|_, _| Box::pin(future::ok::<(), Error>(())));
+            let mirror = Mirror::new(Uuid::new_v4(), vec![bd0, bd1].into());
+            mirror.dirty.lock().unwrap()[1].insert(3, 5);
+            mirror.faulted[1].store(true, Ordering::Relaxed);
+            mirror.resilver().now_or_never().unwrap().unwrap();
+            assert!(mirror.dirty.lock().unwrap()[1].is_empty());
+            assert!(!mirror.faulted[1].load(Ordering::Relaxed));
+        }
+
+        /// Large regions should be copied a piece at a time
+        #[test]
+        fn chunked() {
+            let mut bd0 = mock_vdev_block();
+            bd0.expect_read_at()
+                .once()
+                .withf(|buf, lba| buf.len() == 256 * 4096 && *lba == 3)
+                .returning(|_, _| Box::pin(future::ok::<(), Error>(())));
+            bd0.expect_read_at()
+                .once()
+                .withf(|buf, lba| buf.len() == 41 * 4096 && *lba == 259)
+                .returning(|_, _| Box::pin(future::ok::<(), Error>(())));
+            let mut bd1 = mock_vdev_block();
+            bd1.expect_write_at()
+                .times(2)
+                .returning(|_, _| Box::pin(future::ok::<(), Error>(())));
+            let mirror = Mirror::new(Uuid::new_v4(), vec![bd0, bd1].into());
+            mirror.dirty.lock().unwrap()[1].insert(3, 300);
+            mirror.resilver().now_or_never().unwrap().unwrap();
+            assert!(mirror.dirty.lock().unwrap()[1].is_empty());
+        }
+
+        /// If the child fails again, it should stay faulted and keep whatever
+        /// hasn't been repaired yet.
+        #[test]
+        fn write_error() {
+            let mut bd0 = mock_vdev_block();
+            bd0.expect_read_at()
+                .once()
+                .returning(|_, _| Box::pin(future::ok::<(), Error>(())));
+            let mut bd1 = mock_vdev_block();
+            bd1.expect_write_at()
+                .once()
+                .return_once(|_, _| {
+                    Box::pin(future::err::<(), Error>(Error::ENXIO))
+                });
+            let mirror = Mirror::new(Uuid::new_v4(), vec![bd0, bd1].into());
+            mirror.dirty.lock().unwrap()[1].insert(3, 5);
+            mirror.faulted[1].store(true, Ordering::Relaxed);
+            let r = mirror.resilver().now_or_never().unwrap();
+            assert_eq!(r, Err(Error::ENXIO));
+            assert!(mirror.faulted[1].load(Ordering::Relaxed));
+            assert_eq!(mirror.dirty.lock().unwrap()[1].first(), Some((3, 5)));
+        }
+
+        /// A removed child can't be repaired until it comes back
+        #[test]
+        fn removed() {
+            let bd0 = mock_vdev_block();
+            let mut bd1 = partial_mock_vdev_block(Uuid::new_v4());
+            bd1.expect_is_removed()
+                .return_const(true);
+            bd1.expect_write_at()
+                .never();
+            let mirror = Mirror::new(Uuid::new_v4(), vec![bd0, bd1].into());
+            mirror.dirty.lock().unwrap()[1].insert(3, 5);
+            mirror.faulted[1].store(true, Ordering::Relaxed);
+            mirror.resilver().now_or_never().unwrap().unwrap();
+            assert!(mirror.faulted[1].load(Ordering::Relaxed));
+            assert_eq!(mirror.dirty.lock().unwrap()[1].first(), Some((3, 5)));
+        }
+
+        /// If no child has clean data for a region, it can't be repaired
+        #[test]
+        fn no_source() {
+            let bd0 = mock_vdev_block();
+            let bd1 = mock_vdev_block();
+            let mirror = Mirror::new(Uuid::new_v4(), vec![bd0, bd1].into());
+            mirror.dirty.lock().unwrap()[0].insert(3, 5);
+            mirror.dirty.lock().unwrap()[1].insert(4, 6);
+            let r = mirror.resilver().now_or_never().unwrap();
+            assert_eq!(r, Err(Error::EIO));
+        }
     }
 
     mod read_spacemap {
@@ -577,6 +1396,66 @@ mod t {
             mirror.open_zone(0).now_or_never().unwrap().unwrap();
             mirror.write_at(buf, 3).now_or_never().unwrap().unwrap();
         }
+
+        fn mock(r: Result<()>) -> VdevBlock {
+            let mut bd = mock_vdev_block();
+            bd.expect_write_at()
+                .once()
+                .with(always(), eq(3))
+                .return_once(move |_, _| Box::pin(future::ready(r)));
+            bd
+        }
+
+        /// If every child fails, so does the write
+        #[test]
+        fn all_fail() {
+            let dbs = DivBufShared::from(vec![1u8; 4096]);
+            let buf = dbs.try_const().unwrap();
+            let bd0 = mock(Err(Error::EIO));
+            let bd1 = mock(Err(Error::EIO));
+            let mirror = Mirror::new(Uuid::new_v4(), vec![bd0, bd1].into());
+            let r = mirror.write_at(buf, 3).now_or_never().unwrap();
+            assert_eq!(r, Err(Error::EIO));
+            // There's nothing to repair
+            assert!(mirror.dirty.lock().unwrap().iter().all(|d| d.is_empty()));
+        }
+
+        /// If one child fails, the write should succeed but the child should
+        /// be degraded.
+        #[test]
+        fn degraded() {
+            let dbs = DivBufShared::from(vec![1u8; 8192]);
+            let buf = dbs.try_const().unwrap();
+            let bd0 = mock(Ok(()));
+            let bd1 = mock(Err(Error::EIO));
+            let mirror = Mirror::new(Uuid::new_v4(), vec![bd0, bd1].into());
+            mirror.write_at(buf, 3).now_or_never().unwrap().unwrap();
+            let dirty = mirror.dirty.lock().unwrap();
+            assert!(dirty[0].is_empty());
+            assert_eq!(dirty[1].first(), Some((3, 5)));
+        }
+
+        /// Once a child fails a write, later writes shouldn't be sent to it
+        /// at all.  They should extend its dirty region instead.
+        #[test]
+        fn faulted() {
+            let dbs = DivBufShared::from(vec![1u8; 8192]);
+            let mut bd0 = mock_vdev_block();
+            bd0.expect_write_at()
+                .times(2)
+                .returning(|_, _| Box::pin(future::ok::<(), Error>(())));
+            let bd1 = mock(Err(Error::EIO));
+            let mirror = Mirror::new(Uuid::new_v4(), vec![bd0, bd1].into());
+            let buf = dbs.try_const().unwrap();
+            mirror.write_at(buf, 3).now_or_never().unwrap().unwrap();
+            assert!(mirror.faulted[1].load(Ordering::Relaxed));
+            let buf = dbs.try_const().unwrap();
+            mirror.write_at(buf, 5).now_or_never().unwrap().unwrap();
+            let dirty = mirror.dirty.lock().unwrap();
+            assert_eq!(dirty[1].len(), 1);
+            assert_eq!(dirty[1].first(), Some((3, 7)));
+        }
+
     }
 
     mod writev_at {
@@ -632,6 +1511,89 @@ mod t {
             let labeller = LabelWriter::new(0);
             mirror.write_label(labeller).now_or_never().unwrap().unwrap();
         }
+
+        /// If every child has been removed, there's nowhere to write the label
+        #[test]
+        fn all_removed() {
+            let mock = || {
+                let mut bd = partial_mock_vdev_block(Uuid::new_v4());
+                bd.expect_is_removed()
+                    .return_const(true);
+                bd.expect_write_label()
+                    .never();
+                bd
+            };
+            let bd0 = mock();
+            let bd1 = mock();
+            let mirror = Mirror::new(Uuid::new_v4(), vec![bd0, bd1].into());
+            let labeller = LabelWriter::new(0);
+            let r = mirror.write_label(labeller).now_or_never().unwrap();
+            assert_eq!(r, Err(Error::ENXIO));
+        }
+
+        /// A child that disappears while writing the label shouldn't fail the
+        /// whole write.
+        #[test]
+        fn disappears() {
+            let mut bd0 = mock_vdev_block();
+            bd0.expect_write_label()
+                .once()
+                .return_once(|_| {
+                    Box::pin(future::err::<(), Error>(Error::ENXIO))
+                });
+            let mut bd1 = mock_vdev_block();
+            bd1.expect_write_label()
+                .once()
+                .return_once(|_| Box::pin(future::ok::<(), Error>(())));
+            let mirror = Mirror::new(Uuid::new_v4(), vec![bd0, bd1].into());
+            let labeller = LabelWriter::new(0);
+            mirror.write_label(labeller).now_or_never().unwrap().unwrap();
+        }
+
+        /// Removed children should be skipped
+        #[test]
+        fn removed() {
+            let mut bd0 = partial_mock_vdev_block(Uuid::new_v4());
+            bd0.expect_is_removed()
+                .return_const(true);
+            bd0.expect_write_label()
+                .never();
+            let mut bd1 = mock_vdev_block();
+            bd1.expect_write_label()
+                .once()
+                .return_once(|_| Box::pin(future::ok::<(), Error>(())));
+            let mirror = Mirror::new(Uuid::new_v4(), vec![bd0, bd1].into());
+            let labeller = LabelWriter::new(0);
+            mirror.write_label(labeller).now_or_never().unwrap().unwrap();
+        }
+
+        /// The write-mostly flag should be stored in the label
+        #[test]
+        fn write_mostly() {
+            let uuid1 = Uuid::new_v4();
+            let mut bd0 = mock_vdev_block();
+            bd0.expect_write_label()
+                .once()
+                .return_once(|_| Box::pin(future::ok::<(), Error>(())));
+            let mut bd1 = partial_mock_vdev_block(uuid1);
+            bd1.expect_is_removed()
+                .return_const(false);
+            bd1.expect_write_label()
+                .once()
+                .withf(move |labeller| {
+                    let buf = labeller.clone().into_sglist().unwrap().iter()
+                        .flat_map(|db| db.iter().cloned())
+                        .collect::<Vec<u8>>();
+                    let mut lr = LabelReader::new(buf).unwrap();
+                    let label: Label = lr.deserialize().unwrap();
+                    label.write_mostly == BTreeSet::from([uuid1])
+                }).return_once(|_| Box::pin(future::ok::<(), Error>(())));
+            let mut mirror =
+                Mirror::new(Uuid::new_v4(), vec![bd0, bd1].into());
+            mirror.set_write_mostly(1, true);
+            let labeller = LabelWriter::new(0);
+            mirror.write_label(labeller).now_or_never().unwrap().unwrap();
+        }
     }
 
     mod write_spacemap {
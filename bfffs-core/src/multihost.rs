@@ -0,0 +1,237 @@
+// vim: tw=80
+//! Multihost protection
+//!
+//! A pool on storage that several hosts can reach, like dual-ported SAS disks,
+//! would be quickly corrupted if two of them imported it at once.  To prevent
+//! that, the importing host periodically writes a heartbeat to each of the
+//! pool's leaf devices, for as long as the pool is imported.  Another host will
+//! refuse to import the pool until the heartbeat stops, unless forced.
+//!
+//! Read-only imports neither check nor write the heartbeat, because they can't
+//! corrupt anything.
+//!
+//! Hosts' clocks may disagree, so the timestamp is never compared to the
+//! reader's clock.  Instead, the reader watches the heartbeat for a while.  If
+//! it doesn't change, then its writer must be gone.
+//!
+//! Two hosts that check at the same time could both find the pool free.  So
+//! before importing, a host writes its own heartbeat and then reads it back
+//! after a little while.  If any other host wrote in the meantime, then at
+//! most one of them will find its own heartbeat still in place.  This relies
+//! on each host having a unique, nonzero hostid.
+
+use crate::{
+    Error,
+    Result,
+    label::HEARTBEAT_LBA,
+    util::BYTES_PER_LBA
+};
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fs,
+    io,
+    os::unix::fs::FileExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH}
+};
+
+/// The heartbeat's magic is "BFFFS Heartbeat\0"
+const MAGIC: &[u8; MAGIC_LEN] = b"BFFFS Heartbeat\0";
+const MAGIC_LEN: usize = 16;
+
+/// How often an importing host writes its heartbeat
+pub const INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to watch another host's heartbeat before deciding that it has
+/// stopped.
+pub const STALE: Duration = Duration::from_secs(10);
+
+extern "C" {
+    // From <unistd.h>
+    fn gethostid() -> libc::c_long;
+}
+
+/// This host's ID, as reported by gethostid(3)
+pub fn hostid() -> u64 {
+    // Safe because gethostid has no preconditions
+    unsafe { gethostid() as u64 }
+}
+
+/// Proof of life from the host that has imported a pool.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Heartbeat {
+    /// The writer's host ID
+    pub hostid: u64,
+    /// When the heartbeat was written, in milliseconds since the epoch,
+    /// according to the writer's clock.
+    pub timestamp: u64,
+}
+
+impl Heartbeat {
+    /// Construct a new heartbeat for this host, as of now.
+    pub fn new() -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        Heartbeat { hostid: hostid(), timestamp }
+    }
+
+    /// Read a device's heartbeat, if it has one.
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Option<Self>> {
+        let f = fs::File::open(path)?;
+        let mut buf = vec![0; BYTES_PER_LBA];
+        f.read_exact_at(&mut buf, HEARTBEAT_LBA * BYTES_PER_LBA as u64)?;
+        if buf[..MAGIC_LEN] != MAGIC[..] {
+            return Ok(None);
+        }
+        Ok(bincode::deserialize(&buf[MAGIC_LEN..]).ok())
+    }
+
+    /// Overwrite a device's heartbeat.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let f = fs::OpenOptions::new().write(true).open(path)?;
+        write_lba(&f, Some(self))?;
+        Ok(())
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Write `hb` to a device's heartbeat LBA, or zero it if `hb` is `None`
+fn write_lba(f: &fs::File, hb: Option<&Heartbeat>) -> io::Result<()> {
+    let mut buf = vec![0; BYTES_PER_LBA];
+    if let Some(hb) = hb {
+        buf[..MAGIC_LEN].copy_from_slice(&MAGIC[..]);
+        bincode::serialize_into(&mut buf[MAGIC_LEN..], hb).unwrap();
+    }
+    f.write_all_at(&buf, HEARTBEAT_LBA * BYTES_PER_LBA as u64)?;
+    f.sync_data()
+}
+
+fn read_all(devices: &[PathBuf]) -> Result<Vec<Option<Heartbeat>>> {
+    devices.iter().map(Heartbeat::read).collect()
+}
+
+/// Check that no other host is using the pool on `devices`, and claim it for
+/// this host.
+///
+/// If any device has a heartbeat written by another host, watch the
+/// heartbeats for [`STALE`].  If any of them change, then another host still
+/// has the pool imported, so fail with `EBUSY`.  Then write this host's
+/// heartbeat and read it back two [`INTERVAL`]s later.  If it was overwritten,
+/// then another host is importing the pool too, so fail with `EBUSY`.  The
+/// caller should start a [`Heartbeater`] as soon as this returns.
+///
+/// Fails with `EINVAL` if this host's hostid is zero, because that's what
+/// every host that hasn't configured one reports.  If `force` is set, skip
+/// the check altogether.
+pub async fn check(devices: &[PathBuf], force: bool) -> Result<()> {
+    if force {
+        return Ok(());
+    }
+    let hostid = hostid();
+    if hostid == 0 {
+        tracing::error!("This host has no hostid.  Refusing to import.");
+        return Err(Error::EINVAL);
+    }
+    let before = read_all(devices)?;
+    if before.iter().flatten().any(|hb| hb.hostid != hostid) {
+        tokio::time::sleep(STALE).await;
+        let after = read_all(devices)?;
+        let alive = before.iter().zip(after.iter())
+            .find_map(|(b, a)| match a {
+                Some(hb) if hb.hostid != hostid && b != a => Some(hb.hostid),
+                _ => None
+            });
+        if let Some(other) = alive {
+            tracing::error!(hostid = other,
+                "The pool is imported by another host.  Refusing to import.");
+            return Err(Error::EBUSY);
+        }
+    }
+
+    // Claim the pool.  Any host that's still heartbeating will overwrite our
+    // heartbeat within one interval.  Wait for two, in case it's running a
+    // little late.
+    let ours = Heartbeat::new();
+    for device in devices.iter() {
+        ours.write(device)?;
+    }
+    tokio::time::sleep(INTERVAL * 2).await;
+    let after = read_all(devices)?;
+    let other = after.iter()
+        .find(|hb| **hb != Some(ours))
+        .map(|hb| hb.map(|hb| hb.hostid));
+    match other {
+        Some(other) => {
+            tracing::error!(hostid = ?other,
+                "Another host is importing the pool.  Refusing to import.");
+            Err(Error::EBUSY)
+        },
+        None => Ok(())
+    }
+}
+
+/// Periodically writes this host's heartbeat to each of a pool's leaves.
+///
+/// It keeps writing for as long as it lives.  Dropping it clears the
+/// heartbeat, so that another host may import the pool without waiting.
+pub struct Heartbeater {
+    /// Open leaf devices.  `None` once the `Heartbeater` has stopped.
+    files: Arc<Mutex<Option<Vec<fs::File>>>>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl Heartbeater {
+    /// Start writing heartbeats to `devices`.
+    ///
+    /// Must be called from the tokio domain.
+    pub fn start(devices: &[PathBuf]) -> Result<Self> {
+        let files = devices.iter()
+            .map(|p| fs::OpenOptions::new().write(true).open(p))
+            .collect::<io::Result<Vec<_>>>()?;
+        let files = Arc::new(Mutex::new(Some(files)));
+        let files2 = files.clone();
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(INTERVAL);
+            loop {
+                interval.tick().await;
+                let files3 = files2.clone();
+                let r = tokio::task::spawn_blocking(move || {
+                    let hb = Heartbeat::new();
+                    match &*files3.lock().unwrap() {
+                        Some(files) => files.iter()
+                            .try_for_each(|f| write_lba(f, Some(&hb))),
+                        // Stopped while we were waiting
+                        None => Ok(())
+                    }
+                }).await.unwrap();
+                if let Err(e) = r {
+                    tracing::warn!(error = ?e, "Cannot write heartbeat");
+                }
+            }
+        });
+        Ok(Heartbeater { files, task })
+    }
+}
+
+impl Drop for Heartbeater {
+    fn drop(&mut self) {
+        self.task.abort();
+        // Taking the files ensures that no write still in progress can
+        // rewrite the heartbeat after we clear it.
+        if let Some(files) = self.files.lock().unwrap().take() {
+            for f in files.iter() {
+                if let Err(e) = write_lba(f, None) {
+                    tracing::warn!(error = ?e, "Cannot clear heartbeat");
+                }
+            }
+        }
+    }
+}
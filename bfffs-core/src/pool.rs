@@ -1,37 +1,47 @@
 // vim: tw=80
 
 use crate::{
+    determinism,
+    feature::{Feature, Features},
     label::*,
+    pool_property::{FailMode, PoolProperties, PoolProperty},
     types::*,
     util::*,
-    vdev::*
+    vdev::*,
+    vdev_block::VdevLeaf
 };
 use futures::{
     Future,
     FutureExt,
     TryFutureExt,
     TryStreamExt,
+    future,
     stream::FuturesUnordered,
-    task::{Context, Poll}
 };
-use pin_project::pin_project;
 #[cfg(test)] use mockall::automock;
 use serde_derive::{Deserialize, Serialize};
 use std::{
     ops::Range,
-    pin::Pin,
     sync::{
         atomic::{AtomicU32, AtomicU64, Ordering},
-        Arc
-    }
+        Arc,
+        Mutex,
+        RwLock
+    },
+    time::Duration
 };
 use std::collections::BTreeMap;
 
+pub use crate::cluster::{Temperature, ZoneStats};
+
 #[cfg(test)]
 use crate::cluster::MockCluster as Cluster;
 #[cfg(not(test))]
 use crate::cluster::Cluster;
 
+/// With `failmode=wait`, how long to wait before retrying a failed write
+const WAIT_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
 /// Public representation of a closed zone
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct ClosedZone {
@@ -47,6 +57,9 @@ pub struct ClosedZone {
     /// Range of transactions included in this zone
     pub txgs: Range<TxgT>,
 
+    /// Temperature of the data in this zone
+    pub temp: Temperature,
+
     /// Index of the closed zone
     pub zid: ZoneT
 }
@@ -61,9 +74,52 @@ pub struct Label {
 
     /// `UUID`s of all component `VdevRaid`s
     pub children:           Vec<Uuid>,
+
+    /// If the pool has a checkpoint, the transaction group at which it was
+    /// taken.  The checkpoint itself is preserved in the second label.
+    pub checkpoint:         Option<TxgT>,
+
+    /// Optional on-disk format features enabled on this pool
+    pub features:           Features,
+
+    /// Pool-wide properties
+    pub properties:         PoolProperties,
 }
 
-struct Stats {
+impl LabelLayer for Label {
+    type V0 = LabelV0;
+}
+
+/// `Label` as it was in version 0 labels, before checkpoints, features, and
+/// pool properties
+#[derive(Deserialize)]
+pub struct LabelV0 {
+    name:                   String,
+    uuid:                   Uuid,
+    children:               Vec<Uuid>,
+}
+
+impl From<LabelV0> for Label {
+    fn from(v0: LabelV0) -> Self {
+        Label {
+            name: v0.name,
+            uuid: v0.uuid,
+            children: v0.children,
+            checkpoint: None,
+            features: Features::default(),
+            properties: PoolProperties::default()
+        }
+    }
+}
+
+/// The `Pool`'s `Cluster`s, and statistics about each one.
+///
+/// Adding a `Cluster` replaces the entire `Layout`, while I/O already in
+/// progress keeps using the old one.  Clusters are only ever appended, so
+/// their indices remain valid.
+struct Layout {
+    clusters: Vec<Arc<Cluster>>,
+
     /// The queue depth of each `Cluster`, including both commands that have
     /// been sent to the disks, and commands that are pending in `VdevBlock`
     queue_depth: Vec<AtomicU32>,
@@ -73,18 +129,77 @@ struct Stats {
 
     /// The total size of each `Cluster`
     size: Vec<LbaT>,
-
-    /// The total amount of used space across all `Cluster`s, excluding space
-    /// that has already been freed but not erased.
-    used_space: AtomicU64
 }
 
-impl Stats {
+impl Layout {
+    fn new(clusters: Vec<Arc<Cluster>>) -> Self {
+        let size = clusters.iter()
+            .map(|cluster| cluster.size())
+            .collect::<Vec<_>>();
+        let optimum_queue_depth = clusters.iter()
+            .map(|cluster| f64::from(cluster.optimum_queue_depth()))
+            .collect::<Vec<_>>();
+        let queue_depth: Vec<_> = clusters.iter()
+            .map(|_| AtomicU32::new(0))
+            .collect();
+        Layout{clusters, queue_depth, optimum_queue_depth, size}
+    }
+
+    /// Choose the best Cluster for the next write
+    ///
+    /// This decision is subjective, but should strive to:
+    /// 1) Balance capacity utilization amongst all Clusters
+    /// 2) Balance IOPs amongst all Clusters
+    /// 3) Run quickly
+    fn choose_cluster(&self) -> ClusterT {
+        // This simple implementation weighs both capacity utilization and IOPs,
+        // though above 95% utilization it switches to weighing by capacity
+        // utilization only.  It's slow because it iterates through all clusters
+        // on every write.  A better implementation might perform the full
+        // calculation only occasionally, to update coefficients, and perform a
+        // quick calculation on each write.
+        //
+        // Queue depths depend on timing, so deterministic mode ignores them.
+        let deterministic = determinism::seed().is_some();
+        (0..self.clusters.len())
+        .map(|i| {
+            let alloc = self.clusters[i].allocated() as f64;
+            let space_util = alloc / (self.size[i] as f64);
+            let qdepth = if deterministic {
+                0.0
+            } else {
+                self.queue_depth[i].load(Ordering::Relaxed) as f64
+            };
+            let queue_fraction = qdepth / self.optimum_queue_depth[i];
+            let q_coeff = if 0.95 > space_util {0.95 - space_util} else {0.0};
+            let weight = q_coeff * queue_fraction + space_util;
+            (i, weight)
+        })
+        .min_by(|&(_, x), &(_, y)| x.partial_cmp(&y).unwrap())
+        .map(|(i, _)| i)
+        .unwrap() as ClusterT
+    }
+
     /// The approximate usable size of the Pool
     fn size(&self) -> LbaT {
         self.size.iter().sum()
     }
+}
 
+struct Stats {
+    /// Total number of read and write operations ever issued to the pool
+    ops: AtomicU64,
+
+    /// The total amount of used space across all `Cluster`s, excluding space
+    /// that has already been freed but not erased.
+    used_space: AtomicU64,
+
+    /// The total amount of space ever written since the `Pool` was opened,
+    /// including space that has since been freed.
+    written_space: AtomicU64
+}
+
+impl Stats {
     fn used(&self) -> LbaT {
         self.used_space.load(Ordering::Relaxed)
     }
@@ -92,63 +207,144 @@ impl Stats {
 
 /// An BFFFS storage pool
 pub struct Pool {
-    clusters: Vec<Cluster>,
+    /// Transaction group of the pool's checkpoint, if any
+    checkpoint: Mutex<Option<TxgT>>,
+
+    /// On-disk format features enabled on this pool
+    features: Mutex<Features>,
+
+    layout: RwLock<Arc<Layout>>,
 
     /// Human-readable pool name.  Must be unique on any one system.
     name: String,
 
+    properties: Mutex<PoolProperties>,
+
     stats: Arc<Stats>,
 
     uuid: Uuid,
 }
 
+impl Pool {
+    /// The current `Layout`
+    fn layout(&self) -> Arc<Layout> {
+        self.layout.read().unwrap().clone()
+    }
+}
+
 #[cfg_attr(test, automock)]
 impl Pool {
+    /// Grow the pool by adding a freshly created `Cluster`.
+    ///
+    /// New writes may go to it immediately, but it won't be recorded in the
+    /// label until the next one is written.  Fails with `EBUSY` if the pool
+    /// has a checkpoint, because rewinding to it would lose the new `Cluster`.
+    pub fn add_cluster(&self, cluster: Cluster) -> Result<()> {
+        if self.checkpoint_txg().is_some() {
+            return Err(Error::EBUSY);
+        }
+        let mut guard = self.layout.write().unwrap();
+        let mut clusters = guard.clusters.clone();
+        clusters.push(Arc::new(cluster));
+        *guard = Arc::new(Layout::new(clusters));
+        Ok(())
+    }
+
     /// Assert that the given zone was clean as of the given transaction
     #[cfg(debug_assertions)]
     pub fn assert_clean_zone(&self, cluster: ClusterT, zone: ZoneT, txg: TxgT) {
-        self.clusters[cluster as usize].assert_clean_zone(zone, txg)
+        self.layout().clusters[cluster as usize].assert_clean_zone(zone, txg)
     }
 
-    /// Choose the best Cluster for the next write
+    /// Take a checkpoint of the pool in its current state.
     ///
-    /// This decision is subjective, but should strive to:
-    /// 1) Balance capacity utilization amongst all Clusters
-    /// 2) Balance IOPs amongst all Clusters
-    /// 3) Run quickly
+    /// The second label and spacemap will be preserved, and no zones will be
+    /// erased, until the checkpoint is discarded.  The caller is responsible
+    /// for writing the second label _before_ calling this method, and the
+    /// first label afterwards.
+    pub fn checkpoint(&self, txg: TxgT) -> BoxVdevFut {
+        *self.checkpoint.lock().unwrap() = Some(txg);
+        let fut = self.layout().clusters.iter()
+        .map(|cl| cl.checkpoint(txg))
+        .collect::<FuturesUnordered<_>>()
+        .try_collect::<Vec<_>>()
+        .map_ok(drop);
+        Box::pin(fut)
+    }
+
+    /// Transaction group of the pool's checkpoint, if it has one
+    pub fn checkpoint_txg(&self) -> Option<TxgT> {
+        *self.checkpoint.lock().unwrap()
+    }
+
+    /// Choose the best Cluster for the next write
+    #[cfg(test)]
     fn choose_cluster(&self) -> ClusterT {
-        // This simple implementation weighs both capacity utilization and IOPs,
-        // though above 95% utilization it switches to weighing by capacity
-        // utilization only.  It's slow because it iterates through all clusters
-        // on every write.  A better implementation might perform the full
-        // calculation only occasionally, to update coefficients, and perform a
-        // quick calculation on each write.
-        (0..self.clusters.len())
-        .map(|i| {
-            let alloc = self.clusters[i].allocated() as f64;
-            let space_util = alloc / (self.stats.size[i] as f64);
-            let qdepth = self.stats.queue_depth[i]
-                .load(Ordering::Relaxed) as f64;
-            let queue_fraction = qdepth / self.stats.optimum_queue_depth[i];
-            let q_coeff = if 0.95 > space_util {0.95 - space_util} else {0.0};
-            let weight = q_coeff * queue_fraction + space_util;
-            (i, weight)
-        })
-        .min_by(|&(_, x), &(_, y)| x.partial_cmp(&y).unwrap())
-        .map(|(i, _)| i)
-        .unwrap() as ClusterT
+        self.layout().choose_cluster()
     }
 
     /// Create a new `Pool` from some freshly created `Cluster`s.
+    ///
+    /// Every feature supported by this version will be enabled.
     pub fn create(name: String, clusters: Vec<Cluster>) -> Self
     {
         Pool::new(name, Uuid::new_v4(), clusters)
     }
 
+    /// Forget the pool's checkpoint.
+    ///
+    /// Labels written from now on won't record it.  But the zones that it
+    /// refers to won't be erased until
+    /// [`release_checkpoint`](#method.release_checkpoint) is called, which
+    /// should happen only after the labels have been rewritten.
+    pub fn discard_checkpoint(&self) {
+        *self.checkpoint.lock().unwrap() = None;
+    }
+
+    /// Enable an on-disk format feature.
+    ///
+    /// It will be recorded in the next label written.  Returns `true` if the
+    /// feature wasn't already enabled.
+    pub fn enable_feature(&self, feature: Feature) -> bool {
+        self.features.lock().unwrap().insert(feature)
+    }
+
+    /// Status of every leaf device in the pool, including its lifetime I/O
+    /// error counts.
+    ///
+    /// The counts are persisted in each leaf's label, so they survive export
+    /// and import.
+    pub fn leaf_status(&self) -> Vec<LeafStatus> {
+        self.layout().clusters.iter()
+            .flat_map(|cluster| cluster.leaf_status())
+            .collect()
+    }
+
+    /// Bring a removed or faulted leaf device back online, and resilver
+    /// whatever writes it missed.
+    ///
+    /// Fails with `ENOENT` if `leaf` doesn't belong to this pool.
+    pub async fn online(&self, leaf: VdevLeaf) -> Result<()> {
+        let uuid = leaf.uuid();
+        let cluster = self.layout().clusters.iter()
+            .find(|c| c.leaf_status().iter().any(|ls| ls.uuid == uuid))
+            .cloned()
+            .ok_or(Error::ENOENT)?;
+        cluster.online(leaf).await
+    }
+
+    /// On-disk format features enabled on this pool
+    pub fn features(&self) -> Features {
+        *self.features.lock().unwrap()
+    }
+
     pub fn flush(&self, idx: u32)
         -> impl Future<Output=Result<()>> + Send + Sync
     {
-        self.clusters.iter()
+        // The second spacemap belongs to the checkpoint, if any
+        let preserve = idx == 1 && self.checkpoint_txg().is_some();
+        self.layout().clusters.iter()
+        .filter(|_| !preserve)
         .map(|cl| cl.flush(idx))
         .collect::<FuturesUnordered<_>>()
         .try_collect::<Vec<_>>()
@@ -166,7 +362,8 @@ impl Pool {
     pub fn free(&self, pba: PBA, length: LbaT) -> BoxVdevFut
     {
         self.stats.used_space.fetch_sub(length, Ordering::Relaxed);
-        Box::pin(self.clusters[pba.cluster as usize].free(pba.lba, length))
+        let layout = self.layout();
+        Box::pin(layout.clusters[pba.cluster as usize].free(pba.lba, length))
     }
 
     /// Construct a new `Pool` from some already constructed
@@ -174,26 +371,21 @@ impl Pool {
     #[allow(clippy::new_ret_no_self)]
     fn new(name: String, uuid: Uuid, clusters: Vec<Cluster>) -> Self
     {
-        let size = clusters.iter()
-            .map(Cluster::size)
-            .collect::<Vec<_>>();
-        let optimum_queue_depth = clusters.iter()
-            .map(|cluster| f64::from(cluster.optimum_queue_depth()))
-            .collect::<Vec<_>>();
-        let queue_depth: Vec<_> = clusters.iter()
-            .map(|_| AtomicU32::new(0))
-            .collect();
         let used_space = clusters.iter()
             .map(|cluster| cluster.used())
             .sum::<u64>()
             .into();
         let stats = Arc::new(Stats{
-            queue_depth,
-            optimum_queue_depth,
-            size,
+            ops: AtomicU64::new(0),
             used_space,
+            written_space: AtomicU64::new(0),
         });
-        Pool{clusters, name, stats, uuid}
+        let checkpoint = Mutex::new(None);
+        let clusters = clusters.into_iter().map(Arc::new).collect();
+        let layout = RwLock::new(Arc::new(Layout::new(clusters)));
+        let features = Mutex::new(Features::all());
+        let properties = Mutex::new(PoolProperties::default());
+        Pool{checkpoint, features, layout, name, properties, stats, uuid}
     }
 
     /// Find the next closed zone in the pool.
@@ -211,8 +403,9 @@ impl Pool {
     pub fn find_closed_zone(&self, clust: ClusterT, zid: ZoneT)
         -> (Option<ClosedZone>, Option<(ClusterT, ZoneT)>)
     {
-        let nclusters = self.clusters.len() as ClusterT;
-        let r = self.clusters[clust as usize].find_closed_zone(zid);
+        let layout = self.layout();
+        let nclusters = layout.clusters.len() as ClusterT;
+        let r = layout.clusters[clust as usize].find_closed_zone(zid);
         if let Some(cclz) = r {
             // convert cluster::ClosedZone to pool::ClosedZone
             let pclz = ClosedZone {
@@ -220,6 +413,7 @@ impl Pool {
                 pba: PBA::new(clust, cclz.start),
                 total_blocks: cclz.total_blocks,
                 txgs: cclz.txgs,
+                temp: cclz.temp,
                 zid: cclz.zid};
             (Some(pclz), Some((clust, cclz.zid + 1)))
         } else {
@@ -271,33 +465,88 @@ impl Pool {
         let children = label.children.iter().map(|uuid| {
             all_clusters.remove(uuid).unwrap()
         }).collect::<Vec<_>>();
-        (Pool::new(label.name, label.uuid, children), label_reader)
+        if label.checkpoint.is_some() {
+            for cluster in children.iter() {
+                cluster.set_checkpoint();
+            }
+        }
+        let pool = Pool::new(label.name, label.uuid, children);
+        *pool.checkpoint.lock().unwrap() = label.checkpoint;
+        *pool.features.lock().unwrap() = label.features;
+        *pool.properties.lock().unwrap() = label.properties;
+        (pool, label_reader)
+    }
+
+    /// Total number of read and write operations ever issued to the pool.
+    ///
+    /// Useful for estimating how busy the pool is.
+    pub fn ops(&self) -> u64 {
+        self.stats.ops.load(Ordering::Relaxed)
+    }
+
+    /// If `pba` lies within an open zone, return the temperature of the data
+    /// being written to that zone.
+    pub fn open_zone_temperature(&self, pba: PBA) -> Option<Temperature> {
+        self.layout().clusters.get(pba.cluster as usize)?
+            .open_zone_temperature(pba.lba)
+    }
+
+    /// The current values of the pool's properties
+    pub fn properties(&self) -> PoolProperties {
+        self.properties.lock().unwrap().clone()
     }
 
     /// Asynchronously read from the pool
     pub fn read(&self, buf: IoVecMut, pba: PBA) -> BoxVdevFut
     {
         let cidx = pba.cluster as usize;
-        self.stats.queue_depth[cidx].fetch_add(1, Ordering::Relaxed);
-        let stats2 = self.stats.clone();
-        let fut = self.clusters[pba.cluster as usize].read(buf, pba.lba)
+        let layout = self.layout();
+        self.stats.ops.fetch_add(1, Ordering::Relaxed);
+        layout.queue_depth[cidx].fetch_add(1, Ordering::Relaxed);
+        let fut = layout.clusters[cidx].read(buf, pba.lba)
             .map(move |r| {
-                stats2.queue_depth[cidx].fetch_sub(1, Ordering::Relaxed);
+                layout.queue_depth[cidx].fetch_sub(1, Ordering::Relaxed);
                 r
             });
         Box::pin(fut)
     }
 
+    /// Erase any zones that were only being kept for the sake of a discarded
+    /// checkpoint.
+    pub fn release_checkpoint(&self) -> BoxVdevFut {
+        let fut = self.layout().clusters.iter()
+        .map(|cluster| cluster.release_checkpoint())
+        .collect::<FuturesUnordered<_>>()
+        .try_collect::<Vec<_>>()
+        .map_ok(drop);
+        Box::pin(fut)
+    }
+
+    /// Change one of the pool's properties.
+    ///
+    /// It takes effect immediately, and will be recorded in the next label
+    /// written.
+    pub fn set_property(&self, prop: PoolProperty) {
+        self.properties.lock().unwrap().set(prop)
+    }
+
+    /// Describe every cluster's layout and usage, for capacity planning.
+    pub fn shape(&self) -> Vec<ClusterShape> {
+        self.layout().clusters.iter()
+            .map(|cluster| cluster.shape())
+            .collect()
+    }
+
     /// Return approximately the Pool's usable storage space in LBAs.
     pub fn size(&self) -> LbaT {
-        self.stats.size()
+        self.layout().size()
     }
 
     /// Sync the `Pool`, ensuring that all data written so far reaches stable
     /// storage.
     pub fn sync_all(&self) -> BoxVdevFut {
-        let fut = self.clusters.iter()
-        .map(Cluster::sync_all)
+        let fut = self.layout().clusters.iter()
+        .map(|cluster| cluster.sync_all())
         .collect::<FuturesUnordered<BoxVdevFut>>()
         .try_collect::<Vec<()>>()
         .map_ok(drop);
@@ -314,40 +563,102 @@ impl Pool {
         self.uuid
     }
 
+    /// How many blocks have been written since the `Pool` was opened,
+    /// including those that have since been freed?
+    pub fn written(&self) -> LbaT {
+        self.stats.written_space.load(Ordering::Relaxed)
+    }
+
+    /// Zone open/close statistics, summed across all `Cluster`s
+    pub fn zone_stats(&self) -> ZoneStats {
+        self.layout().clusters.iter()
+            .map(|cluster| cluster.zone_stats())
+            .fold(ZoneStats::default(), |acc, zs| ZoneStats {
+                opened: acc.opened + zs.opened,
+                closed: acc.closed + zs.closed,
+                evicted: acc.evicted + zs.evicted,
+            })
+    }
+
     /// Write a buffer to the pool
     ///
+    /// It will only share zones with other data of the same `temp`erature.
+    /// If the write fails on every copy, the `failmode` property determines
+    /// what happens next.
+    ///
     /// # Returns
     ///
     /// The `PBA` where the data was written
-    pub fn write(&self, buf: IoVec, txg: TxgT) ->
+    pub fn write(&self, buf: IoVec, temp: Temperature, txg: TxgT) ->
         impl Future<Output=Result<PBA>> + Send
     {
-        let cluster = self.choose_cluster();
-        let cidx = cluster as usize;
+        let layout = self.layout();
+        let failmode = self.properties.lock().unwrap().failmode;
+        let name = self.name.clone();
+        let stats = self.stats.clone();
         let space = div_roundup(buf.len(), BYTES_PER_LBA) as LbaT;
-        let stats2 = self.stats.clone();
-        match self.clusters[cidx].write(buf, txg) {
-            Ok((lba, wfut)) => {
-                self.stats.queue_depth[cidx].fetch_add(1, Ordering::Relaxed);
-                let pba = PBA::new(cluster, lba);
-                Write::Write(wfut, stats2, cidx, space, pba)
-            },
-            Err(e) => Write::EarlyErr(e)
+        async move {
+            loop {
+                let cluster = layout.choose_cluster();
+                let cidx = cluster as usize;
+                let (lba, wfut) =
+                    match layout.clusters[cidx].write(buf.clone(), temp, txg) {
+                        Ok(x) => x,
+                        Err(e) => return Err(e)
+                    };
+                stats.ops.fetch_add(1, Ordering::Relaxed);
+                layout.queue_depth[cidx].fetch_add(1, Ordering::Relaxed);
+                let r = wfut.await;
+                layout.queue_depth[cidx].fetch_sub(1, Ordering::Relaxed);
+                let e = match r {
+                    Ok(()) => {
+                        stats.used_space.fetch_add(space, Ordering::Relaxed);
+                        stats.written_space.fetch_add(space, Ordering::Relaxed);
+                        return Ok(PBA::new(cluster, lba));
+                    }
+                    Err(e) => e
+                };
+                match failmode {
+                    FailMode::Continue => return Err(e),
+                    FailMode::Panic => panic!(
+                        "Pool {name}: unrecoverable write error {e:?} with \
+                        failmode=panic"),
+                    FailMode::Wait => {
+                        tracing::error!(pool = %name, error = ?e,
+                            "Unrecoverable write error.  Retrying because \
+                            failmode=wait");
+                        // Don't leak the space allocated to the failed write
+                        let _ = layout.clusters[cidx].free(lba, space).await;
+                        tokio::time::sleep(WAIT_RETRY_INTERVAL).await;
+                    }
+                }
+            }
         }
     }
 
     /// Asynchronously write this `Pool`'s label to all component devices
+    ///
+    /// If the pool has a checkpoint, the second label will be left untouched.
     pub fn write_label(&self, mut labeller: LabelWriter) -> BoxVdevFut
     {
-        let cluster_uuids = self.clusters.iter().map(Cluster::uuid)
+        let checkpoint = self.checkpoint_txg();
+        if labeller.idx() == 1 && checkpoint.is_some() {
+            return Box::pin(future::ok(()));
+        }
+        let layout = self.layout();
+        let cluster_uuids = layout.clusters.iter()
+            .map(|cluster| cluster.uuid())
             .collect::<Vec<_>>();
         let label = Label {
             name: self.name.clone(),
             uuid: self.uuid,
             children: cluster_uuids,
+            checkpoint,
+            features: self.features(),
+            properties: self.properties(),
         };
         labeller.serialize(&label).unwrap();
-        let fut = self.clusters.iter()
+        let fut = layout.clusters.iter()
         .map(|cluster| cluster.write_label(labeller.clone()))
         .collect::<FuturesUnordered<_>>()
         .try_collect::<Vec<_>>()
@@ -356,31 +667,6 @@ impl Pool {
     }
 }
 
-/// Return value of `Pool::write`
-#[pin_project(project = WriteProj)]
-enum Write {
-    Write(#[pin] BoxVdevFut, Arc<Stats>, usize, LbaT, PBA),
-    EarlyErr(Error)
-}
-
-impl Future for Write {
-    type Output = Result<PBA>;
-
-    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        Poll::Ready(match self.as_mut().project() {
-            WriteProj::Write(wfut, stats, cidx, space, pba) => {
-                let r = futures::ready!(wfut.poll(cx));
-                stats.queue_depth[*cidx].fetch_sub(1, Ordering::Relaxed);
-                r.map(|_| {
-                    stats.used_space.fetch_add(*space, Ordering::Relaxed);
-                    *pba
-                })
-            },
-            WriteProj::EarlyErr(e) => Err(*e)
-        })
-    }
-}
-
 
 // LCOV_EXCL_START
 #[cfg(test)]
@@ -394,7 +680,10 @@ mod label {
     fn debug() {
         let label = Label{name: "Foo".to_owned(),
             uuid: Uuid::new_v4(),
-            children: vec![]
+            children: vec![],
+            checkpoint: None,
+            features: Features::all(),
+            properties: PoolProperties::default()
         };
         format!("{label:?}");
     }
@@ -405,7 +694,7 @@ mod pool {
     use crate::cluster;
     use divbuf::DivBufShared;
     use futures::future;
-    use mockall::predicate::*;
+    use mockall::{Sequence, predicate::*};
     use pretty_assertions::assert_eq;
 
     fn mock_cluster(allocated: u64, size: u64, used: u64) -> Cluster {
@@ -418,6 +707,59 @@ mod pool {
         c
     }
 
+    /// A newly added cluster should count toward the pool's size, and as the
+    /// emptiest, receive the next write.
+    #[test]
+    fn add_cluster() {
+        let clusters = vec![mock_cluster(900, 1000, 900)];
+        let pool = Pool::new("foo".to_string(), Uuid::new_v4(), clusters);
+        assert_eq!(pool.size(), 1000);
+
+        pool.add_cluster(mock_cluster(0, 2000, 0)).unwrap();
+        assert_eq!(pool.size(), 3000);
+        assert_eq!(pool.used(), 900);
+        assert_eq!(pool.choose_cluster(), 1);
+    }
+
+    /// Rewinding to a checkpoint would lose any cluster added since
+    #[test]
+    fn add_cluster_checkpoint() {
+        let mut cluster = mock_cluster(0, 1000, 0);
+        cluster.expect_checkpoint()
+            .return_once(|_| Box::pin(future::ok(())));
+        let pool = Pool::new("foo".to_string(), Uuid::new_v4(), vec![cluster]);
+        basic_runtime().block_on(pool.checkpoint(TxgT::from(42))).unwrap();
+
+        let r = pool.add_cluster(mock_cluster(0, 1000, 0));
+        assert_eq!(r, Err(Error::EBUSY));
+        assert_eq!(pool.size(), 1000);
+    }
+
+    /// While a checkpoint exists, the second label and spacemap must not be
+    /// overwritten.
+    #[test]
+    fn checkpoint() {
+        let mut cluster = mock_cluster(0, 32_768_000, 0);
+        cluster.expect_checkpoint()
+            .with(eq(TxgT::from(42)))
+            .once()
+            .return_once(|_| Box::pin(future::ok(())));
+        cluster.expect_flush()
+            .with(eq(0))
+            .once()
+            .return_once(|_| Box::pin(future::ok(())));
+
+        let rt = basic_runtime();
+        let clusters = vec![cluster];
+        let pool = Pool::new("foo".to_string(), Uuid::new_v4(), clusters);
+
+        rt.block_on(pool.checkpoint(TxgT::from(42))).unwrap();
+        assert_eq!(pool.checkpoint_txg(), Some(TxgT::from(42)));
+        rt.block_on(pool.flush(0)).unwrap();
+        rt.block_on(pool.flush(1)).unwrap();
+        rt.block_on(pool.write_label(LabelWriter::new(1))).unwrap();
+    }
+
     /// Two clusters, one full and one empty.  Choose the empty one
     #[test]
     fn choose_cluster_empty() {
@@ -442,13 +784,13 @@ mod pool {
     fn choose_cluster_queue_depth() {
         let clusters = vec![mock_cluster(0, 1000, 0), mock_cluster(0, 1000, 0)];
         let pool = Pool::new("foo".to_string(), Uuid::new_v4(), clusters);
-        pool.stats.queue_depth[0].store(0, Ordering::Relaxed);
-        pool.stats.queue_depth[1].store(10, Ordering::Relaxed);
+        pool.layout().queue_depth[0].store(0, Ordering::Relaxed);
+        pool.layout().queue_depth[1].store(10, Ordering::Relaxed);
         assert_eq!(pool.choose_cluster(), 0);
 
         // Try the reverse, too
-        pool.stats.queue_depth[0].store(10, Ordering::Relaxed);
-        pool.stats.queue_depth[1].store(0, Ordering::Relaxed);
+        pool.layout().queue_depth[0].store(10, Ordering::Relaxed);
+        pool.layout().queue_depth[1].store(0, Ordering::Relaxed);
         assert_eq!(pool.choose_cluster(), 1);
     }
 
@@ -461,8 +803,8 @@ mod pool {
             mock_cluster(50, 1000, 10)
         ];
         let pool =  Pool::new("foo".to_string(), Uuid::new_v4(), clusters);
-        pool.stats.queue_depth[0].store(0, Ordering::Relaxed);
-        pool.stats.queue_depth[1].store(10, Ordering::Relaxed);
+        pool.layout().queue_depth[0].store(0, Ordering::Relaxed);
+        pool.layout().queue_depth[1].store(10, Ordering::Relaxed);
         assert_eq!(pool.choose_cluster(), 1);
 
         // Try the reverse, too
@@ -471,11 +813,22 @@ mod pool {
             mock_cluster(960, 1000, 10)
         ];
         let pool =  Pool::new("foo".to_string(), Uuid::new_v4(), clusters);
-        pool.stats.queue_depth[0].store(10, Ordering::Relaxed);
-        pool.stats.queue_depth[1].store(0, Ordering::Relaxed);
+        pool.layout().queue_depth[0].store(10, Ordering::Relaxed);
+        pool.layout().queue_depth[1].store(0, Ordering::Relaxed);
         assert_eq!(pool.choose_cluster(), 0);
     }
 
+    #[test]
+    fn enable_feature() {
+        let clusters = vec![mock_cluster(0, 1000, 0)];
+        let pool = Pool::new("foo".to_string(), Uuid::new_v4(), clusters);
+        *pool.features.lock().unwrap() = Features::default();
+        assert!(!pool.features().contains(Feature::LargeRecords));
+        assert!(pool.enable_feature(Feature::LargeRecords));
+        assert!(pool.features().contains(Feature::LargeRecords));
+        assert!(!pool.enable_feature(Feature::LargeRecords));
+    }
+
     #[test]
     fn find_closed_zone() {
         let cluster = || {
@@ -488,7 +841,8 @@ mod pool {
                     start: 10,
                     freed_blocks: 5,
                     total_blocks: 10,
-                    txgs: TxgT::from(0)..TxgT::from(1)
+                    txgs: TxgT::from(0)..TxgT::from(1),
+                    temp: Temperature::Hot
                 }));
             c.expect_find_closed_zone()
                 .with(eq(2))
@@ -497,7 +851,8 @@ mod pool {
                     start: 30,
                     freed_blocks: 6,
                     total_blocks: 10,
-                    txgs: TxgT::from(2)..TxgT::from(3)
+                    txgs: TxgT::from(2)..TxgT::from(3),
+                    temp: Temperature::Metadata
                 }));
             c.expect_find_closed_zone()
                 .with(eq(4))
@@ -509,12 +864,14 @@ mod pool {
 
         let r0 = pool.find_closed_zone(0, 0);
         assert_eq!(r0.0, Some(ClosedZone{pba: PBA::new(0, 10), freed_blocks: 5,
-            total_blocks: 10, txgs: TxgT::from(0)..TxgT::from(1), zid: 1}));
+            total_blocks: 10, txgs: TxgT::from(0)..TxgT::from(1), zid: 1,
+            temp: Temperature::Hot}));
 
         let (clust, zid) = r0.1.unwrap();
         let r1 = pool.find_closed_zone(clust, zid);
         assert_eq!(r1.0, Some(ClosedZone{pba: PBA::new(0, 30), freed_blocks: 6,
-            total_blocks: 10, txgs: TxgT::from(2)..TxgT::from(3), zid: 3}));
+            total_blocks: 10, txgs: TxgT::from(2)..TxgT::from(3), zid: 3,
+            temp: Temperature::Metadata}));
 
         let (clust, zid) = r1.1.unwrap();
         let r2 = pool.find_closed_zone(clust, zid);
@@ -523,12 +880,14 @@ mod pool {
         let (clust, zid) = r2.1.unwrap();
         let r3 = pool.find_closed_zone(clust, zid);
         assert_eq!(r3.0, Some(ClosedZone{pba: PBA::new(1, 10), freed_blocks: 5,
-            total_blocks: 10, txgs: TxgT::from(0)..TxgT::from(1), zid: 1}));
+            total_blocks: 10, txgs: TxgT::from(0)..TxgT::from(1), zid: 1,
+            temp: Temperature::Hot}));
 
         let (clust, zid) = r3.1.unwrap();
         let r4 = pool.find_closed_zone(clust, zid);
         assert_eq!(r4.0, Some(ClosedZone{pba: PBA::new(1, 30), freed_blocks: 6,
-            total_blocks: 10, txgs: TxgT::from(2)..TxgT::from(3), zid: 3}));
+            total_blocks: 10, txgs: TxgT::from(2)..TxgT::from(3), zid: 3,
+            temp: Temperature::Metadata}));
 
         let (clust, zid) = r4.1.unwrap();
         let r5 = pool.find_closed_zone(clust, zid);
@@ -563,8 +922,8 @@ mod pool {
             mock_cluster(500, 1000, 400),
         ];
         let pool = Pool::new("foo".to_string(), Uuid::new_v4(), clusters);
-        assert_eq!(pool.stats.optimum_queue_depth[0], 10.0);
-        assert_eq!(pool.stats.optimum_queue_depth[1], 10.0);
+        assert_eq!(pool.layout().optimum_queue_depth[0], 10.0);
+        assert_eq!(pool.layout().optimum_queue_depth[1], 10.0);
         assert_eq!(pool.size(), 2000);
         assert_eq!(pool.used(), 800);
     }
@@ -633,19 +992,23 @@ mod pool {
     fn write() {
         let mut cluster = mock_cluster(0, 32_768_000, 0);
         cluster.expect_write()
-            .withf(|buf, txg| {
-                buf.len() == BYTES_PER_LBA && *txg == TxgT::from(42)
+            .withf(|buf, temp, txg| {
+                buf.len() == BYTES_PER_LBA &&
+                *temp == Temperature::Cold &&
+                *txg == TxgT::from(42)
             }).once()
-            .return_once(|_, _| Ok((0, Box::pin(future::ok(())))));
+            .return_once(|_, _, _| Ok((0, Box::pin(future::ok(())))));
 
         let rt = basic_runtime();
         let pool = Pool::new("foo".to_string(), Uuid::new_v4(), vec![cluster]);
 
         let dbs = DivBufShared::from(vec![0u8; 4096]);
         let db0 = dbs.try_const().unwrap();
-        let result = rt.block_on( pool.write(db0, TxgT::from(42)));
+        let result =
+            rt.block_on( pool.write(db0, Temperature::Cold, TxgT::from(42)));
         assert_eq!(result.unwrap(), PBA::new(0, 0));
         assert_eq!(pool.used(), 1);
+        assert_eq!(pool.written(), 1);
     }
 
     #[test]
@@ -654,14 +1017,15 @@ mod pool {
         let mut cluster = mock_cluster(0, 32_768_000, 0);
         cluster.expect_write()
             .once()
-            .return_once(move |_, _| Ok((0, Box::pin(future::err(e)))));
+            .return_once(move |_, _, _| Ok((0, Box::pin(future::err(e)))));
 
         let rt = basic_runtime();
         let pool = Pool::new("foo".to_string(), Uuid::new_v4(), vec![cluster]);
 
         let dbs = DivBufShared::from(vec![0u8; 4096]);
         let db0 = dbs.try_const().unwrap();
-        let result = rt.block_on( pool.write(db0, TxgT::from(42)));
+        let result =
+            rt.block_on( pool.write(db0, Temperature::Hot, TxgT::from(42)));
         assert_eq!(result.unwrap_err(), e);
         assert_eq!(pool.used(), 0);
     }
@@ -672,25 +1036,77 @@ mod pool {
         let mut cluster = mock_cluster(0, 32_768_000, 0);
         cluster.expect_write()
             .once()
-            .return_once(move |_, _| Err(e));
+            .return_once(move |_, _, _| Err(e));
 
         let rt = basic_runtime();
         let pool = Pool::new("foo".to_string(), Uuid::new_v4(), vec![cluster]);
 
         let dbs = DivBufShared::from(vec![0u8; 4096]);
         let db0 = dbs.try_const().unwrap();
-        let result = rt.block_on( pool.write(db0, TxgT::from(42)));
+        let result =
+            rt.block_on( pool.write(db0, Temperature::Hot, TxgT::from(42)));
         assert_eq!(result.unwrap_err(), e);
         assert_eq!(pool.used(), 0);
     }
 
+    #[test]
+    #[should_panic(expected = "failmode=panic")]
+    fn write_async_error_failmode_panic() {
+        let e = Error::EIO;
+        let mut cluster = mock_cluster(0, 32_768_000, 0);
+        cluster.expect_write()
+            .once()
+            .return_once(move |_, _, _| Ok((0, Box::pin(future::err(e)))));
+
+        let rt = basic_runtime();
+        let pool = Pool::new("foo".to_string(), Uuid::new_v4(), vec![cluster]);
+        pool.set_property(PoolProperty::Failmode(FailMode::Panic));
+
+        let dbs = DivBufShared::from(vec![0u8; 4096]);
+        let db0 = dbs.try_const().unwrap();
+        let _ = rt.block_on(pool.write(db0, Temperature::Hot, TxgT::from(42)));
+    }
+
+    /// With failmode=wait, a failed write should be freed and retried
+    #[test]
+    fn write_async_error_failmode_wait() {
+        let e = Error::EIO;
+        let mut seq = Sequence::new();
+        let mut cluster = mock_cluster(0, 32_768_000, 0);
+        cluster.expect_write()
+            .once()
+            .in_sequence(&mut seq)
+            .return_once(move |_, _, _| Ok((0, Box::pin(future::err(e)))));
+        cluster.expect_free()
+            .with(eq(0), eq(1))
+            .once()
+            .in_sequence(&mut seq)
+            .return_once(|_, _| Box::pin(future::ok(())));
+        cluster.expect_write()
+            .once()
+            .in_sequence(&mut seq)
+            .return_once(|_, _, _| Ok((1, Box::pin(future::ok(())))));
+
+        let rt = basic_runtime();
+        let pool = Pool::new("foo".to_string(), Uuid::new_v4(), vec![cluster]);
+        pool.set_property(PoolProperty::Failmode(FailMode::Wait));
+
+        let dbs = DivBufShared::from(vec![0u8; 4096]);
+        let db0 = dbs.try_const().unwrap();
+        let result =
+            rt.block_on( pool.write(db0, Temperature::Hot, TxgT::from(42)));
+        assert_eq!(result.unwrap(), PBA::new(0, 1));
+        assert_eq!(pool.used(), 1);
+        assert_eq!(pool.written(), 1);
+    }
+
     // Make sure allocated space accounting is symmetric
     #[test]
     fn write_and_free() {
         let mut cluster = mock_cluster(0, 32_768_000, 0);
         cluster.expect_write()
             .once()
-            .return_once(|_, _| Ok((0, Box::pin(future::ok(())))));
+            .return_once(|_, _, _| Ok((0, Box::pin(future::ok(())))));
         cluster.expect_free()
             .once()
             .return_once(|_, _| Box::pin(future::ok(())));
@@ -700,9 +1116,26 @@ mod pool {
 
         let dbs = DivBufShared::from(vec![0u8; 1024]);
         let db0 = dbs.try_const().unwrap();
-        let drp = rt.block_on( pool.write(db0, TxgT::from(42))).unwrap();
+        let drp =
+            rt.block_on( pool.write(db0, Temperature::Hot, TxgT::from(42)))
+            .unwrap();
         rt.block_on( pool.free(drp, 1)).unwrap();
         assert_eq!(pool.used(), 0);
+        assert_eq!(pool.written(), 1);
+    }
+
+    #[test]
+    fn zone_stats() {
+        let cluster = |opened, closed, evicted| {
+            let mut c = mock_cluster(0, 32_768_000, 0);
+            c.expect_zone_stats()
+                .return_const(ZoneStats{opened, closed, evicted});
+            c
+        };
+        let clusters = vec![cluster(5, 3, 1), cluster(2, 1, 0)];
+        let pool = Pool::new("foo".to_string(), Uuid::new_v4(), clusters);
+        assert_eq!(pool.zone_stats(),
+                   ZoneStats{opened: 7, closed: 4, evicted: 1});
     }
 }
 }
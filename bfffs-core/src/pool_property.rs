@@ -0,0 +1,269 @@
+//vim: tw=80
+//! Pool Properties
+//!
+//! Unlike dataset properties, pool properties apply to the whole pool.  They're
+//! stored in the pool's label rather than in the forest, so they're available
+//! before any dataset is opened.  They aren't inherited, and every one always
+//! has a value.
+use std::{
+    fmt,
+    str::FromStr
+};
+use serde_derive::*;
+
+/// Values for the `failmode` property.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub enum FailMode {
+    /// Keep retrying a failed write until it succeeds.  Writers, and
+    /// eventually transaction syncs, will block in the meantime.  Suitable
+    /// when the devices may come back on their own, as when a cable is
+    /// reseated.
+    Wait,
+    /// Return an error to the writer.  The error will usually propagate up to
+    /// the transaction sync, which will fail.  Reads can still be serviced.
+    #[default]
+    Continue,
+    /// Panic.  Suitable for redundant installations where another server
+    /// can take over.
+    Panic,
+}
+
+impl fmt::Display for FailMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FailMode::Wait => "wait".fmt(f),
+            FailMode::Continue => "continue".fmt(f),
+            FailMode::Panic => "panic".fmt(f),
+        }
+    }
+}
+
+/// Pool Properties.
+///
+/// Each of these controls the behavior of an entire pool.  See
+/// [`Property`](crate::property::Property) for dataset properties.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub enum PoolProperty {
+    /// Automatically replace a failed disk with a new one found in the same
+    /// physical location.
+    ///
+    /// Recorded for future use.  BFFFS cannot yet replace disks.
+    Autoreplace(bool),
+
+    /// Inform the disks of freed space as soon as it's freed, rather than
+    /// waiting for the zone to be erased.
+    ///
+    /// Recorded for future use.  BFFFS currently notifies the disks only when
+    /// it erases a whole zone.
+    Autotrim(bool),
+
+    /// Where to record the pool's devices when it's imported.
+    ///
+    /// The empty string means bfffsd's own cache file, "none" means nowhere,
+    /// and anything else must be an absolute path.  Takes effect at the next
+    /// import.
+    Cachefile(String),
+
+    /// How to respond when a write fails on every copy of the data.  See
+    /// [`FailMode`].
+    Failmode(FailMode),
+}
+
+impl PoolProperty {
+    pub fn name(&self) -> PoolPropertyName {
+        match self {
+            PoolProperty::Autoreplace(_) => PoolPropertyName::Autoreplace,
+            PoolProperty::Autotrim(_) => PoolPropertyName::Autotrim,
+            PoolProperty::Cachefile(_) => PoolPropertyName::Cachefile,
+            PoolProperty::Failmode(_) => PoolPropertyName::Failmode,
+        }
+    }
+}
+
+impl fmt::Display for PoolProperty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolProperty::Autoreplace(b) |
+            PoolProperty::Autotrim(b) => match b {
+                true => "on".fmt(f),
+                false => "off".fmt(f),
+            },
+            PoolProperty::Cachefile(s) if s.is_empty() => "-".fmt(f),
+            PoolProperty::Cachefile(s) => s.fmt(f),
+            PoolProperty::Failmode(fm) => fm.fmt(f),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ParsePoolPropertyError {
+    NoEquals,
+    Name(String),
+    Value(String)
+}
+
+impl fmt::Display for ParsePoolPropertyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoEquals => write!(f, "Must contain an '=' character"),
+            Self::Name(s) => write!(f, "{s} is not a valid pool property"),
+            Self::Value(s) =>
+                write!(f, "{s} is not a valid value for this property")
+        }
+    }
+}
+impl std::error::Error for ParsePoolPropertyError {}
+
+impl FromStr for PoolProperty {
+    type Err = ParsePoolPropertyError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, ParsePoolPropertyError> {
+        let mut words = s.splitn(2, '=');
+        let propname = PoolPropertyName::from_str(words.next().unwrap())?;
+        let propval = words.next().ok_or(ParsePoolPropertyError::NoEquals)?;
+        let parse_bool = |v: &str| match v {
+            "true" | "on" => Ok(true),
+            "false" | "off" => Ok(false),
+            _ => Err(ParsePoolPropertyError::Value(v.to_string()))
+        };
+        match propname {
+            PoolPropertyName::Autoreplace =>
+                parse_bool(propval).map(PoolProperty::Autoreplace),
+            PoolPropertyName::Autotrim =>
+                parse_bool(propval).map(PoolProperty::Autotrim),
+            PoolPropertyName::Cachefile => {
+                if propval.is_empty() || propval == "none" ||
+                    propval.starts_with('/')
+                {
+                    Ok(PoolProperty::Cachefile(propval.to_string()))
+                } else {
+                    Err(ParsePoolPropertyError::Value(propval.to_string()))
+                }
+            }
+            PoolPropertyName::Failmode => match propval {
+                "wait" => Ok(PoolProperty::Failmode(FailMode::Wait)),
+                "continue" => Ok(PoolProperty::Failmode(FailMode::Continue)),
+                "panic" => Ok(PoolProperty::Failmode(FailMode::Panic)),
+                _ => Err(ParsePoolPropertyError::Value(propval.to_string()))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, PartialOrd, Ord,
+         Serialize)]
+pub enum PoolPropertyName {
+    Autoreplace,
+    Autotrim,
+    Cachefile,
+    Failmode,
+}
+
+impl fmt::Display for PoolPropertyName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Autoreplace => "autoreplace".fmt(f),
+            Self::Autotrim => "autotrim".fmt(f),
+            Self::Cachefile => "cachefile".fmt(f),
+            Self::Failmode => "failmode".fmt(f),
+        }
+    }
+}
+
+impl FromStr for PoolPropertyName {
+    type Err = ParsePoolPropertyError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, ParsePoolPropertyError> {
+        match s {
+            "autoreplace" => Ok(PoolPropertyName::Autoreplace),
+            "autotrim" => Ok(PoolPropertyName::Autotrim),
+            "cachefile" => Ok(PoolPropertyName::Cachefile),
+            "failmode" => Ok(PoolPropertyName::Failmode),
+            _ => Err(ParsePoolPropertyError::Name(s.to_string()))
+        }
+    }
+}
+
+/// The values of all of a pool's properties, as stored in its label
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PoolProperties {
+    pub autoreplace: bool,
+    pub autotrim: bool,
+    pub cachefile: String,
+    pub failmode: FailMode,
+}
+
+impl PoolProperties {
+    pub fn get(&self, name: PoolPropertyName) -> PoolProperty {
+        match name {
+            PoolPropertyName::Autoreplace =>
+                PoolProperty::Autoreplace(self.autoreplace),
+            PoolPropertyName::Autotrim => PoolProperty::Autotrim(self.autotrim),
+            PoolPropertyName::Cachefile =>
+                PoolProperty::Cachefile(self.cachefile.clone()),
+            PoolPropertyName::Failmode => PoolProperty::Failmode(self.failmode),
+        }
+    }
+
+    pub fn set(&mut self, prop: PoolProperty) {
+        match prop {
+            PoolProperty::Autoreplace(b) => self.autoreplace = b,
+            PoolProperty::Autotrim(b) => self.autotrim = b,
+            PoolProperty::Cachefile(s) => self.cachefile = s,
+            PoolProperty::Failmode(fm) => self.failmode = fm,
+        }
+    }
+}
+
+// LCOV_EXCL_START
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    #[test]
+    fn from_str() {
+        assert_eq!(Ok(PoolProperty::Autotrim(true)),
+            PoolProperty::from_str("autotrim=on"));
+        assert_eq!(Ok(PoolProperty::Autoreplace(false)),
+            PoolProperty::from_str("autoreplace=false"));
+        assert_eq!(Ok(PoolProperty::Failmode(FailMode::Wait)),
+            PoolProperty::from_str("failmode=wait"));
+        assert_eq!(Ok(PoolProperty::Cachefile("none".to_string())),
+            PoolProperty::from_str("cachefile=none"));
+        assert_eq!(Ok(PoolProperty::Cachefile("".to_string())),
+            PoolProperty::from_str("cachefile="));
+        assert_eq!(Ok(PoolProperty::Cachefile("/tmp/x.cache".to_string())),
+            PoolProperty::from_str("cachefile=/tmp/x.cache"));
+    }
+
+    #[test]
+    fn from_str_bad_name() {
+        assert_eq!(Err(ParsePoolPropertyError::Name("atime".to_string())),
+            PoolProperty::from_str("atime=on"));
+    }
+
+    #[test]
+    fn from_str_bad_value() {
+        assert_eq!(Err(ParsePoolPropertyError::Value("maybe".to_string())),
+            PoolProperty::from_str("failmode=maybe"));
+        assert_eq!(Err(ParsePoolPropertyError::Value("rel".to_string())),
+            PoolProperty::from_str("cachefile=rel"));
+    }
+
+    #[test]
+    fn from_str_no_equals() {
+        assert_eq!(Err(ParsePoolPropertyError::NoEquals),
+            PoolProperty::from_str("autotrim"));
+    }
+
+    #[test]
+    fn get_and_set() {
+        let mut props = PoolProperties::default();
+        assert_eq!(props.get(PoolPropertyName::Failmode),
+            PoolProperty::Failmode(FailMode::Continue));
+        props.set(PoolProperty::Failmode(FailMode::Panic));
+        assert_eq!(props.failmode, FailMode::Panic);
+        assert_eq!(props.get(PoolPropertyName::Failmode).to_string(), "panic");
+    }
+}
+// LCOV_EXCL_STOP
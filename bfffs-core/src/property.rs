@@ -2,6 +2,7 @@
 //! Dataset Properties
 use std::{
     fmt,
+    net::SocketAddr,
     str::FromStr
 };
 use serde_derive::*;
@@ -15,8 +16,7 @@ pub const PROPERTY_OBJECT: u64 = 0;
 /// way.  They all have default values, and if unset they'll inherit from their
 /// parent dataset.  They're basically just like ZFS properties.
 ///
-/// This enum is not used for User Properties.  User Properties are stored as
-/// extended attributes on inode 0.
+/// This enum is not used for User Properties.  See [`UserProperty`] for those.
 #[derive(Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
 pub enum Property {
     /// Access time.
@@ -38,6 +38,11 @@ pub enum Property {
     /// "/", the pool name, and the file system name.
     Mountpoint(String),
 
+    /// Is the file system currently mounted by bfffsd?
+    ///
+    /// This is a read-only pseudoproperty.  Only bfffsd knows its value.
+    Mounted(bool),
+
     /// The dataset's name
     Name(String),
 
@@ -45,8 +50,173 @@ pub enum Property {
     ///
     /// Units are in bytes, log base 2.  So `RecordSize(16)` means 64KB records.
     /// BFFFS will usually divide files into blocks of this many bytes.  But the
-    /// record size is only advisory.  The default is 128KB.
+    /// record size is only advisory.  Valid values are powers of two from 4KB
+    /// through 16MB.  The default is 128KB.
     RecordSize(u8),
+
+    /// Synchronous write behavior.
+    ///
+    /// Usually set on a pool's root file system, so it applies pool-wide.
+    /// See [`SyncPolicy`] for the durability tradeoffs of each setting.
+    Sync(SyncPolicy),
+
+    /// Allow device nodes to be opened.
+    ///
+    /// When off, the file system is mounted with `nodev`.
+    Devices(bool),
+
+    /// Allow files to be executed.
+    ///
+    /// When off, the file system is mounted with `noexec`.
+    Exec(bool),
+
+    /// Honor the set-user-id and set-group-id bits.
+    ///
+    /// When off, the file system is mounted with `nosuid`.
+    Setuid(bool),
+
+    /// Reject file names that aren't valid UTF-8.
+    ///
+    /// When off, file names are treated as raw bytes, like most Unix file
+    /// systems.  When on, creating or renaming a file to a name that is not
+    /// valid UTF-8 will fail with `EILSEQ`.  Existing names are unaffected.
+    Utf8Only(bool),
+
+    /// Share the file system with virtual machines over 9P2000.L.
+    ///
+    /// Either "off", or the TCP address on which bfffsd should listen, like
+    /// "127.0.0.1:564".  Clients select a file system by passing its full name
+    /// as the `aname`, so several file systems may share one address.  There is
+    /// no authentication, so only listen on an address that is reachable by
+    /// trusted guests.
+    Share9p(String),
+
+    /// Size of a volume, in bytes.
+    ///
+    /// Volumes are datasets that hold a single, fixed-size block device instead
+    /// of a file system.  This is 0 for file systems.  It's set when the volume
+    /// is created, and can't be changed afterwards.
+    Volsize(u64),
+
+    /// Export volumes as iSCSI targets.
+    ///
+    /// Either "off", or the TCP address on which bfffsd should listen, like
+    /// "0.0.0.0:3260".  Every volume shared at an address is a separate target,
+    /// which initiators may find with a SendTargets discovery session.  There
+    /// is no CHAP authentication, so only listen on an address that is
+    /// reachable by trusted initiators.  Has no effect on file systems.
+    ShareIscsi(String),
+
+    /// Maximum amount of dirty data, in bytes, that the file system may hold
+    /// in the writeback cache.
+    ///
+    /// Writers will block once the file system reaches its limit, until the
+    /// next transaction sync or flush, even if the pool-wide writeback cache
+    /// has room to spare.  That prevents one busy file system from starving
+    /// the others.  0, the default, means no limit beyond the pool's own.
+    DirtyLimit(u64),
+
+    /// Maximum size, in bytes, of a run of adjacent small writes to one file
+    /// that may be buffered and written to the tree as one.
+    ///
+    /// Many small appends within one transaction group otherwise each update
+    /// the file's tree and inode separately.  Buffered data is written to the
+    /// tree when the window fills, at the next transaction group boundary
+    /// that a writer notices, or whenever something else looks at the file,
+    /// like `read`, `getattr`, or `fsync`.  0, the default, disables write
+    /// coalescing.
+    Coalesce(u64),
+
+    /// When the file system was last mounted, in seconds since the epoch.
+    ///
+    /// This is a read-only property, recorded by bfffsd whenever it mounts the
+    /// file system.  0 means that it has never been mounted.  Unlike most
+    /// properties, it isn't inherited.
+    LastMounted(u64),
+
+    /// How many times bfffsd has mounted the file system.
+    ///
+    /// This is a read-only property, and isn't inherited.
+    MountCount(u64),
+
+    /// Was the file system cleanly unmounted after it was last mounted?
+    ///
+    /// This is a read-only property, and isn't inherited.  It's always false
+    /// while the file system is mounted.  If it's false while unmounted, then
+    /// bfffsd must have exited without unmounting it, for example by crashing.
+    CleanUnmount(bool),
+
+    /// Make directory modifications synchronous.
+    ///
+    /// When on, operations that modify a directory, like `create`, `mkdir`,
+    /// `unlink`, and `rename`, sync the current transaction group before
+    /// returning, like a file system mounted with `dirsync`.  Mail servers and
+    /// other programs that rely on a new directory entry being durable as soon
+    /// as it's visible should set it.  Ordinary writes are unaffected, and
+    /// `sync=disabled` overrides it.
+    DirSync(bool),
+
+    /// How many copies of each data record to store.
+    ///
+    /// When 2, every newly written record of file data is also stored a
+    /// second time, in a different zone from the first.  If the first copy
+    /// fails its checksum, then reads will use the second.  That protects
+    /// against isolated bad sectors even on a pool without redundancy, but
+    /// not against the loss of a whole disk.  Each copy counts against the
+    /// file system's space usage.  Existing data is unaffected, and metadata
+    /// is not copied.  Valid values are 1 and 2.  The default is 1.  Requires
+    /// the `ditto_data` feature.
+    Copies(u8),
+
+    /// May the file system be mounted by `bfffs fs mount --all`?
+    ///
+    /// When off, the file system is skipped by bulk mounts, though it may
+    /// still be mounted explicitly by name.  That's useful for file systems
+    /// that exist only to pass properties on to their children.  Unlike most
+    /// properties, it isn't inherited.
+    CanMount(bool),
+}
+
+/// Values for the `sync` property.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd,
+         Serialize)]
+#[repr(u8)]
+pub enum SyncPolicy {
+    /// `fsync` returns immediately without syncing anything.  Data will
+    /// still reach the disk at the next periodic transaction sync, but an
+    /// application can never be sure that its data is durable.  Only suitable
+    /// for throwaway data.
+    Disabled = 0,
+    /// `fsync` syncs the current transaction group before returning.  Ordinary
+    /// writes are acknowledged immediately, and may be lost in a crash if they
+    /// haven't yet been synced.
+    Standard = 1,
+    /// Every write syncs the current transaction group before returning, as if
+    /// the file were opened with `O_SYNC`.  Nothing acknowledged can be lost,
+    /// but write performance will be very poor.
+    Always = 2,
+}
+
+impl SyncPolicy {
+    /// Inverse of `SyncPolicy as u8`
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(SyncPolicy::Disabled),
+            1 => Some(SyncPolicy::Standard),
+            2 => Some(SyncPolicy::Always),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for SyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncPolicy::Disabled => "disabled".fmt(f),
+            SyncPolicy::Standard => "standard".fmt(f),
+            SyncPolicy::Always => "always".fmt(f),
+        }
+    }
 }
 
 impl Property {
@@ -57,9 +227,26 @@ impl Property {
                 Property::BaseMountpoint("".to_string()),
             PropertyName::Mountpoint =>
                 unimplemented!("Does not have a static default value"),
+            PropertyName::Mounted => Property::Mounted(false),
             PropertyName::Name =>
                 unimplemented!("Does not have a static default value"),
             PropertyName::RecordSize => Property::RecordSize(17), // 128KB
+            PropertyName::Sync => Property::Sync(SyncPolicy::Standard),
+            PropertyName::Devices => Property::Devices(true),
+            PropertyName::Exec => Property::Exec(true),
+            PropertyName::Setuid => Property::Setuid(true),
+            PropertyName::Utf8Only => Property::Utf8Only(false),
+            PropertyName::Share9p => Property::Share9p("off".to_string()),
+            PropertyName::Volsize => Property::Volsize(0),
+            PropertyName::ShareIscsi => Property::ShareIscsi("off".to_string()),
+            PropertyName::DirtyLimit => Property::DirtyLimit(0),
+            PropertyName::Coalesce => Property::Coalesce(0),
+            PropertyName::LastMounted => Property::LastMounted(0),
+            PropertyName::MountCount => Property::MountCount(0),
+            PropertyName::CleanUnmount => Property::CleanUnmount(true),
+            PropertyName::DirSync => Property::DirSync(false),
+            PropertyName::Copies => Property::Copies(1),
+            PropertyName::CanMount => Property::CanMount(true),
         }
     }
 
@@ -81,14 +268,39 @@ impl Property {
             Property::Atime(_) => PropertyName::Atime,
             Property::BaseMountpoint(_) => PropertyName::BaseMountpoint,
             Property::Mountpoint(_) => PropertyName::Mountpoint,
+            Property::Mounted(_) => PropertyName::Mounted,
             Property::Name(_) => PropertyName::Name,
             Property::RecordSize(_) => PropertyName::RecordSize,
+            Property::Sync(_) => PropertyName::Sync,
+            Property::Devices(_) => PropertyName::Devices,
+            Property::Exec(_) => PropertyName::Exec,
+            Property::Setuid(_) => PropertyName::Setuid,
+            Property::Utf8Only(_) => PropertyName::Utf8Only,
+            Property::Share9p(_) => PropertyName::Share9p,
+            Property::Volsize(_) => PropertyName::Volsize,
+            Property::ShareIscsi(_) => PropertyName::ShareIscsi,
+            Property::DirtyLimit(_) => PropertyName::DirtyLimit,
+            Property::Coalesce(_) => PropertyName::Coalesce,
+            Property::LastMounted(_) => PropertyName::LastMounted,
+            Property::MountCount(_) => PropertyName::MountCount,
+            Property::CleanUnmount(_) => PropertyName::CleanUnmount,
+            Property::DirSync(_) => PropertyName::DirSync,
+            Property::Copies(_) => PropertyName::Copies,
+            Property::CanMount(_) => PropertyName::CanMount,
         }
     }
 
     pub fn as_bool(&self) -> bool {
         match self {
-            Property::Atime(atime) => *atime,
+            Property::Atime(b) => *b,
+            Property::CanMount(b) => *b,
+            Property::CleanUnmount(b) => *b,
+            Property::Devices(b) => *b,
+            Property::DirSync(b) => *b,
+            Property::Exec(b) => *b,
+            Property::Mounted(b) => *b,
+            Property::Setuid(b) => *b,
+            Property::Utf8Only(b) => *b,
             _ => panic!("{self:?} is not a boolean Property")
         }
     }
@@ -98,29 +310,70 @@ impl Property {
             Property::BaseMountpoint(mp) => mp,
             Property::Mountpoint(mp) => mp,
             Property::Name(s) => s,
+            Property::Share9p(s) => s,
+            Property::ShareIscsi(s) => s,
             _ => panic!("{self:?} is not a str Property")
         }
     }
 
+    pub fn as_sync_policy(&self) -> SyncPolicy {
+        match self {
+            Property::Sync(sp) => *sp,
+            _ => panic!("{self:?} is not a SyncPolicy Property")
+        }
+    }
+
     pub fn as_u8(&self) -> u8 {
         match self {
             Property::RecordSize(rs) => *rs,
+            Property::Copies(copies) => *copies,
             _ => panic!("{self:?} is not a u8 Property")
         }
     }
+
+    pub fn as_u64(&self) -> u64 {
+        match self {
+            Property::Volsize(size) => *size,
+            Property::DirtyLimit(limit) => *limit,
+            Property::Coalesce(window) => *window,
+            Property::LastMounted(secs) => *secs,
+            Property::MountCount(count) => *count,
+            _ => panic!("{self:?} is not a u64 Property")
+        }
+    }
 }
 
 impl fmt::Display for Property {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Property::Atime(b) => match b {
+            Property::Atime(b) |
+            Property::CanMount(b) |
+            Property::Devices(b) |
+            Property::DirSync(b) |
+            Property::Exec(b) |
+            Property::Setuid(b) |
+            Property::Utf8Only(b) => match b {
                 true => "on".fmt(f),
                 false => "off".fmt(f),
             },
             Property::BaseMountpoint(s) => s.fmt(f),
             Property::Mountpoint(s) => s.fmt(f),
+            Property::Mounted(b) |
+            Property::CleanUnmount(b) => match b {
+                true => "yes".fmt(f),
+                false => "no".fmt(f),
+            },
             Property::Name(s) => s.fmt(f),
             Property::RecordSize(i) => (1 << i).fmt(f),
+            Property::Sync(sp) => sp.fmt(f),
+            Property::Share9p(s) => s.fmt(f),
+            Property::Volsize(size) => size.fmt(f),
+            Property::ShareIscsi(s) => s.fmt(f),
+            Property::DirtyLimit(limit) => limit.fmt(f),
+            Property::Coalesce(window) => window.fmt(f),
+            Property::LastMounted(secs) => secs.fmt(f),
+            Property::MountCount(count) => count.fmt(f),
+            Property::Copies(copies) => copies.fmt(f),
         }
     }
 }
@@ -160,17 +413,27 @@ impl FromStr for Property {
         } else {
             words.next().ok_or(ParsePropertyError::NoEquals)?
         };
+        let parse_bool = |v: &str| match v {
+            "true" | "on" => Ok(true),
+            "false" | "off" => Ok(false),
+            _ => Err(ParsePropertyError::Value(v.to_string()))
+        };
+        // For the share properties: "off", or a socket address
+        let parse_addr = |v: &str| {
+            if v == "off" {
+                Ok(v.to_string())
+            } else if let Ok(sa) = v.parse::<SocketAddr>() {
+                Ok(sa.to_string())
+            } else {
+                Err(ParsePropertyError::Value(v.to_string()))
+            }
+        };
         match propname {
-            PropertyName::Atime => {
-                match propval {
-                    "true" | "on" => Ok(Property::Atime(true)),
-                    "false" | "off" => Ok(Property::Atime(false)),
-                    _ => Err(ParsePropertyError::Value(propval.to_string()))
-                }
-            },
+            PropertyName::Atime => parse_bool(propval).map(Property::Atime),
             PropertyName::BaseMountpoint => Err(ParsePropertyError::ReadOnly),
             PropertyName::Mountpoint =>
                 Ok(Property::Mountpoint(propval.to_string())),
+            PropertyName::Mounted => Err(ParsePropertyError::ReadOnly),
             PropertyName::Name => Err(ParsePropertyError::ReadOnly),
             PropertyName::RecordSize => {
                 if let Ok(rs) = propval.parse::<usize>() {
@@ -186,13 +449,54 @@ impl FromStr for Property {
                         131_072 => Ok(Property::RecordSize(17)),
                         262_144 => Ok(Property::RecordSize(18)),
                         524_288 => Ok(Property::RecordSize(19)),
-                        1_048_776 => Ok(Property::RecordSize(20)),
+                        1_048_576 => Ok(Property::RecordSize(20)),
+                        2_097_152 => Ok(Property::RecordSize(21)),
+                        4_194_304 => Ok(Property::RecordSize(22)),
+                        8_388_608 => Ok(Property::RecordSize(23)),
+                        16_777_216 => Ok(Property::RecordSize(24)),
                         _ => Err(ParsePropertyError::Value(propval.to_string()))
                     }
                 } else {
                     Err(ParsePropertyError::Value(propval.to_string()))
                 }
             }
+            PropertyName::Sync => {
+                match propval {
+                    "disabled" => Ok(Property::Sync(SyncPolicy::Disabled)),
+                    "standard" => Ok(Property::Sync(SyncPolicy::Standard)),
+                    "always" => Ok(Property::Sync(SyncPolicy::Always)),
+                    _ => Err(ParsePropertyError::Value(propval.to_string()))
+                }
+            }
+            PropertyName::Devices => parse_bool(propval).map(Property::Devices),
+            PropertyName::Exec => parse_bool(propval).map(Property::Exec),
+            PropertyName::Setuid => parse_bool(propval).map(Property::Setuid),
+            PropertyName::Utf8Only =>
+                parse_bool(propval).map(Property::Utf8Only),
+            PropertyName::Share9p =>
+                parse_addr(propval).map(Property::Share9p),
+            // Volumes can't be resized
+            PropertyName::Volsize => Err(ParsePropertyError::ReadOnly),
+            PropertyName::ShareIscsi =>
+                parse_addr(propval).map(Property::ShareIscsi),
+            PropertyName::DirtyLimit => propval.parse::<u64>()
+                .map(Property::DirtyLimit)
+                .map_err(|_| ParsePropertyError::Value(propval.to_string())),
+            PropertyName::Coalesce => propval.parse::<u64>()
+                .map(Property::Coalesce)
+                .map_err(|_| ParsePropertyError::Value(propval.to_string())),
+            // Mount history is recorded by bfffsd
+            PropertyName::LastMounted |
+            PropertyName::MountCount |
+            PropertyName::CleanUnmount => Err(ParsePropertyError::ReadOnly),
+            PropertyName::DirSync => parse_bool(propval).map(Property::DirSync),
+            PropertyName::Copies => match propval {
+                "1" => Ok(Property::Copies(1)),
+                "2" => Ok(Property::Copies(2)),
+                _ => Err(ParsePropertyError::Value(propval.to_string()))
+            },
+            PropertyName::CanMount =>
+                parse_bool(propval).map(Property::CanMount),
         }
     }
 }
@@ -203,14 +507,45 @@ pub enum PropertyName {
     Atime,
     BaseMountpoint,
     Mountpoint,
+    Mounted,
     Name,
     RecordSize,
+    Sync,
+    Devices,
+    Exec,
+    Setuid,
+    Utf8Only,
+    Share9p,
+    Volsize,
+    ShareIscsi,
+    DirtyLimit,
+    Coalesce,
+    LastMounted,
+    MountCount,
+    CleanUnmount,
+    DirSync,
+    Copies,
+    CanMount,
 }
 
 impl PropertyName {
     /// Does this property take boolean values?
     fn boolean(self) -> bool {
-        matches!(self, Self::Atime)
+        matches!(self, Self::Atime | Self::Devices | Self::Exec | Self::Setuid |
+                 Self::Utf8Only | Self::DirSync | Self::CanMount)
+    }
+
+    /// Do file systems inherit this property from their parents, when it
+    /// isn't set locally?
+    pub fn inherited(self) -> bool {
+        !self.mount_history() && self != Self::CanMount
+    }
+
+    /// Is this one of the mount history properties?  They're recorded
+    /// separately for every file system, and never inherited.
+    pub fn mount_history(self) -> bool {
+        matches!(self, Self::LastMounted | Self::MountCount |
+                 Self::CleanUnmount)
     }
 
     pub(crate) fn inheritable(self) -> Self {
@@ -228,8 +563,25 @@ impl fmt::Display for PropertyName {
             Self::Atime => "atime".fmt(f),
             Self::BaseMountpoint => "basemountpoint".fmt(f),
             Self::Mountpoint => "mountpoint".fmt(f),
+            Self::Mounted => "mounted".fmt(f),
             Self::Name => "name".fmt(f),
             Self::RecordSize => "recordsize".fmt(f),
+            Self::Sync => "sync".fmt(f),
+            Self::Devices => "devices".fmt(f),
+            Self::Exec => "exec".fmt(f),
+            Self::Setuid => "setuid".fmt(f),
+            Self::Utf8Only => "utf8only".fmt(f),
+            Self::Share9p => "share9p".fmt(f),
+            Self::Volsize => "volsize".fmt(f),
+            Self::ShareIscsi => "shareiscsi".fmt(f),
+            Self::DirtyLimit => "dirtylimit".fmt(f),
+            Self::Coalesce => "coalesce".fmt(f),
+            Self::LastMounted => "lastmounted".fmt(f),
+            Self::MountCount => "mountcount".fmt(f),
+            Self::CleanUnmount => "cleanunmount".fmt(f),
+            Self::DirSync => "dirsync".fmt(f),
+            Self::Copies => "copies".fmt(f),
+            Self::CanMount => "canmount".fmt(f),
         }
     }
 }
@@ -251,9 +603,26 @@ impl FromStr for PropertyName {
             "atime" => Ok(PropertyName::Atime),
             "basemountpoint" => Ok(PropertyName::BaseMountpoint),
             "mountpoint" => Ok(PropertyName::Mountpoint),
+            "mounted" => Ok(PropertyName::Mounted),
             "name" => Ok(PropertyName::Name),
             "recordsize" => Ok(PropertyName::RecordSize),
             "recsize" => Ok(PropertyName::RecordSize),
+            "sync" => Ok(PropertyName::Sync),
+            "devices" => Ok(PropertyName::Devices),
+            "exec" => Ok(PropertyName::Exec),
+            "setuid" => Ok(PropertyName::Setuid),
+            "utf8only" => Ok(PropertyName::Utf8Only),
+            "share9p" => Ok(PropertyName::Share9p),
+            "volsize" => Ok(PropertyName::Volsize),
+            "shareiscsi" => Ok(PropertyName::ShareIscsi),
+            "dirtylimit" => Ok(PropertyName::DirtyLimit),
+            "coalesce" => Ok(PropertyName::Coalesce),
+            "lastmounted" => Ok(PropertyName::LastMounted),
+            "mountcount" => Ok(PropertyName::MountCount),
+            "cleanunmount" => Ok(PropertyName::CleanUnmount),
+            "dirsync" => Ok(PropertyName::DirSync),
+            "copies" => Ok(PropertyName::Copies),
+            "canmount" => Ok(PropertyName::CanMount),
             _ => Err(ParsePropertyNameError{})
         }
     }
@@ -325,6 +694,74 @@ impl FromStr for PropertySource {
     }
 }
 
+/// A user-defined dataset property.
+///
+/// User properties have no effect on BFFFS itself.  They exist so that
+/// external tools can attach arbitrary metadata to datasets.  Like in ZFS,
+/// their names must contain a ':', which distinguishes them from native
+/// properties.  They are inherited just like native properties, but have no
+/// default values.  They are stored as extended attributes on inode 0.
+#[derive(Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct UserProperty {
+    pub name: String,
+    /// The property's value.  May be any Unicode string, but an empty value
+    /// means "not set locally".
+    pub value: String
+}
+
+impl UserProperty {
+    /// Maximum length of a user property's name, in bytes.
+    pub const NAME_MAX: usize = 255;
+
+    /// Maximum length of a user property's value, in bytes.
+    pub const VALUE_MAX: usize = 8191;
+
+    /// Is this a valid name for a user property?
+    ///
+    /// Names must contain a ':' and otherwise consist only of lowercase ASCII
+    /// letters, digits, and the characters "+-._".
+    pub fn is_valid_name(name: &str) -> bool {
+        name.len() <= UserProperty::NAME_MAX &&
+            name.contains(':') &&
+            !name.starts_with('-') &&
+            name.chars().all(|c| {
+                c.is_ascii_lowercase() || c.is_ascii_digit() ||
+                    ":+-._".contains(c)
+            })
+    }
+
+    /// Are this property's name and value both acceptable?
+    pub fn is_valid(&self) -> bool {
+        UserProperty::is_valid_name(&self.name) &&
+            self.value.len() <= UserProperty::VALUE_MAX
+    }
+}
+
+impl fmt::Display for UserProperty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.value.fmt(f)
+    }
+}
+
+impl FromStr for UserProperty {
+    type Err = ParsePropertyError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, ParsePropertyError> {
+        let (name, value) = s.split_once('=')
+            .ok_or(ParsePropertyError::NoEquals)?;
+        if !UserProperty::is_valid_name(name) {
+            Err(ParsePropertyError::Name(ParsePropertyNameError{}))
+        } else if value.len() > UserProperty::VALUE_MAX {
+            Err(ParsePropertyError::Value(value.to_string()))
+        } else {
+            Ok(UserProperty {
+                name: name.to_string(),
+                value: value.to_string()
+            })
+        }
+    }
+}
+
 
 // LCOV_EXCL_START
 #[cfg(test)]
@@ -383,7 +820,19 @@ fn property_from_str() {
     assert_eq!(Ok(Property::RecordSize(19)),
         Property::from_str("recordsize=524288"));
     assert_eq!(Ok(Property::RecordSize(20)),
-        Property::from_str("recordsize=1048776"));
+        Property::from_str("recordsize=1048576"));
+    assert_eq!(Ok(Property::RecordSize(21)),
+        Property::from_str("recordsize=2097152"));
+    assert_eq!(Ok(Property::RecordSize(22)),
+        Property::from_str("recordsize=4194304"));
+    assert_eq!(Ok(Property::RecordSize(23)),
+        Property::from_str("recordsize=8388608"));
+    assert_eq!(Ok(Property::RecordSize(24)),
+        Property::from_str("recordsize=16777216"));
+    assert!(matches!(
+        Property::from_str("recordsize=33554432"),
+        Err(ParsePropertyError::Value(_))
+    ));
     assert!(matches!(
         Property::from_str("recordsize=12"),
         Err(ParsePropertyError::Value(_))
@@ -394,6 +843,137 @@ fn property_from_str() {
     ));
     assert_eq!(Err(ParsePropertyError::NoEquals),
         Property::from_str("recordsize"));
+    assert_eq!(Ok(Property::Sync(SyncPolicy::Disabled)),
+        Property::from_str("sync=disabled"));
+    assert_eq!(Ok(Property::Sync(SyncPolicy::Standard)),
+        Property::from_str("sync=standard"));
+    assert_eq!(Ok(Property::Sync(SyncPolicy::Always)),
+        Property::from_str("sync=always"));
+    assert!(matches!(
+        Property::from_str("sync=on"),
+        Err(ParsePropertyError::Value(_))
+    ));
+    assert_eq!(Err(ParsePropertyError::NoEquals),
+        Property::from_str("sync"));
+    assert_eq!(Ok(Property::Devices(false)),
+        Property::from_str("devices=off"));
+    assert_eq!(Ok(Property::Devices(true)), Property::from_str("devices"));
+    assert_eq!(Ok(Property::Exec(false)), Property::from_str("exec=off"));
+    assert_eq!(Ok(Property::Exec(true)), Property::from_str("exec=on"));
+    assert!(matches!(
+        Property::from_str("exec=xyz"),
+        Err(ParsePropertyError::Value(_))
+    ));
+    assert_eq!(Ok(Property::Setuid(false)),
+        Property::from_str("setuid=false"));
+    assert_eq!(Ok(Property::Setuid(true)), Property::from_str("setuid"));
+    assert_eq!(Ok(Property::Utf8Only(true)), Property::from_str("utf8only"));
+    assert_eq!(Ok(Property::Utf8Only(false)),
+        Property::from_str("utf8only=off"));
+    assert_eq!(Ok(Property::DirSync(true)), Property::from_str("dirsync"));
+    assert_eq!(Ok(Property::DirSync(false)),
+        Property::from_str("dirsync=off"));
+    assert_eq!(Ok(Property::Copies(2)), Property::from_str("copies=2"));
+    assert_eq!(Ok(Property::CanMount(true)), Property::from_str("canmount"));
+    assert_eq!(Ok(Property::CanMount(false)),
+        Property::from_str("canmount=off"));
+    assert!(matches!(
+        Property::from_str("copies=3"),
+        Err(ParsePropertyError::Value(_))
+    ));
+    assert_eq!(Ok(Property::Share9p("off".to_string())),
+        Property::from_str("share9p=off"));
+    assert_eq!(Ok(Property::Share9p("127.0.0.1:564".to_string())),
+        Property::from_str("share9p=127.0.0.1:564"));
+    assert_eq!(Ok(Property::Share9p("[::1]:5640".to_string())),
+        Property::from_str("share9p=[::1]:5640"));
+    // A port is required
+    assert!(matches!(
+        Property::from_str("share9p=127.0.0.1"),
+        Err(ParsePropertyError::Value(_))
+    ));
+    assert_eq!(Err(ParsePropertyError::NoEquals),
+        Property::from_str("share9p"));
+    assert!(matches!(
+        Property::from_str("volsize=1048576"),
+        Err(ParsePropertyError::ReadOnly)
+    ));
+    assert_eq!(Ok(Property::ShareIscsi("off".to_string())),
+        Property::from_str("shareiscsi=off"));
+    assert_eq!(Ok(Property::ShareIscsi("0.0.0.0:3260".to_string())),
+        Property::from_str("shareiscsi=0.0.0.0:3260"));
+    assert!(matches!(
+        Property::from_str("shareiscsi=on"),
+        Err(ParsePropertyError::Value(_))
+    ));
+    assert_eq!(Err(ParsePropertyError::ReadOnly),
+        Property::from_str("mounted=yes"));
+    assert_eq!(Ok(Property::DirtyLimit(0)),
+        Property::from_str("dirtylimit=0"));
+    assert_eq!(Ok(Property::DirtyLimit(67_108_864)),
+        Property::from_str("dirtylimit=67108864"));
+    assert!(matches!(
+        Property::from_str("dirtylimit=-1"),
+        Err(ParsePropertyError::Value(_))
+    ));
+    assert_eq!(Err(ParsePropertyError::NoEquals),
+        Property::from_str("dirtylimit"));
+    assert_eq!(Ok(Property::Coalesce(65_536)),
+        Property::from_str("coalesce=65536"));
+    assert!(matches!(
+        Property::from_str("coalesce=lots"),
+        Err(ParsePropertyError::Value(_))
+    ));
+    assert_eq!(Err(ParsePropertyError::ReadOnly),
+        Property::from_str("lastmounted=0"));
+    assert_eq!(Err(ParsePropertyError::ReadOnly),
+        Property::from_str("mountcount=1"));
+    assert_eq!(Err(ParsePropertyError::ReadOnly),
+        Property::from_str("cleanunmount=yes"));
+}
+
+#[test]
+fn user_property_from_str() {
+    assert_eq!(Ok(UserProperty {
+            name: "user:backup-policy".to_string(),
+            value: "daily".to_string()
+        }),
+        UserProperty::from_str("user:backup-policy=daily"));
+    // Values may contain any Unicode, including '='
+    assert_eq!(Ok(UserProperty {
+            name: "com.example:note".to_string(),
+            value: "a=b ☃".to_string()
+        }),
+        UserProperty::from_str("com.example:note=a=b ☃"));
+    // An empty value is allowed; it clears the property
+    assert_eq!(Ok(UserProperty {
+            name: "user:foo".to_string(),
+            value: String::new()
+        }),
+        UserProperty::from_str("user:foo="));
+    assert_eq!(Err(ParsePropertyError::NoEquals),
+        UserProperty::from_str("user:foo"));
+    // Names must contain a ':'
+    assert!(matches!(
+        UserProperty::from_str("foo=bar"),
+        Err(ParsePropertyError::Name(_))
+    ));
+    // Names may not contain uppercase letters
+    assert!(matches!(
+        UserProperty::from_str("user:Foo=bar"),
+        Err(ParsePropertyError::Name(_))
+    ));
+    let longname = format!("user:{}=bar", "x".repeat(UserProperty::NAME_MAX));
+    assert!(matches!(
+        UserProperty::from_str(&longname),
+        Err(ParsePropertyError::Name(_))
+    ));
+    let longval = format!("user:foo={}",
+                          "x".repeat(UserProperty::VALUE_MAX + 1));
+    assert!(matches!(
+        UserProperty::from_str(&longval),
+        Err(ParsePropertyError::Value(_))
+    ));
 }
 
 }
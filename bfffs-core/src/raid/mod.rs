@@ -11,6 +11,8 @@ use crate::{
     types::*,
     vdev::*,
 };
+#[cfg(test)] use crate::capacity::ClusterShape;
+#[cfg(test)] use crate::vdev_block::VdevLeaf;
 #[cfg(test)] use mockall::*;
 use mockall_double::double;
 use serde_derive::{Deserialize, Serialize};
@@ -42,6 +44,10 @@ pub enum Label {
     Raid(self::vdev_raid::Label),
 }
 
+impl LabelLayer for Label {
+    type V0 = Self;
+}
+
 impl<'a> Label {
     pub fn iter_children(&'a self) -> Box<dyn Iterator<Item=&Uuid> + 'a> {
         match self {
@@ -58,6 +64,30 @@ impl<'a> Label {
     }
 }
 
+/// Check whether `create` would accept the given RAID layout.
+///
+/// `create` panics on an invalid layout, so anything that gets the layout from
+/// the user should call this first.  Returns `EINVAL` if the layout is
+/// invalid.
+pub fn check_layout(num_disks: usize, disks_per_stripe: i16, redundancy: i16)
+    -> Result<()>
+{
+    let valid = if num_disks == 1 {
+        disks_per_stripe == 1 && redundancy == 0
+    } else {
+        i16::try_from(num_disks).map(|n| {
+            prime_s::is_prime(n) && n <= 215 &&
+                disks_per_stripe > 1 && disks_per_stripe <= n &&
+                redundancy > 0 && redundancy < disks_per_stripe
+        }).unwrap_or(false)
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(Error::EINVAL)
+    }
+}
+
 /// Create a raid-like `Vdev` from its components.
 ///
 ///
@@ -135,16 +165,47 @@ mock!{
     }
     #[async_trait]
     impl VdevRaidApi for VdevRaid {
+        async fn check_write_pointers(&self, zone: ZoneT, allocated: LbaT)
+            -> Result<bool>;
         fn erase_zone(&self, zone: ZoneT) -> BoxVdevFut;
         fn finish_zone(&self, zone: ZoneT) -> BoxVdevFut;
         fn flush_zone(&self, zone: ZoneT) -> (LbaT, BoxVdevFut);
+        fn leaf_status(&self) -> Vec<LeafStatus>;
+        async fn online(&self, leaf: VdevLeaf) -> Result<()>;
         fn open_zone(&self, zone: ZoneT) -> BoxVdevFut;
         fn read_at(&self, buf: IoVecMut, lba: LbaT) -> BoxVdevFut;
         fn read_spacemap(&self, buf: IoVecMut, idx: u32) -> BoxVdevFut;
         fn reopen_zone(&self, zone: ZoneT, allocated: LbaT) -> BoxVdevFut;
+        fn shape(&self) -> ClusterShape;
         fn write_at(&self, buf: IoVec, zone: ZoneT, lba: LbaT) -> BoxVdevFut;
         fn write_label(&self, labeller: LabelWriter) -> BoxVdevFut;
         fn write_spacemap(&self, sglist: SGList, idx: u32, block: LbaT)
             -> BoxVdevFut;
     }
 }
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    #[test]
+    fn check_layout_ok() {
+        check_layout(1, 1, 0).unwrap();
+        check_layout(3, 3, 1).unwrap();
+        check_layout(7, 5, 2).unwrap();
+    }
+
+    #[test]
+    fn check_layout_invalid() {
+        // Single disks can't have redundancy
+        assert_eq!(check_layout(1, 1, 1), Err(Error::EINVAL));
+        // Disk count must be prime
+        assert_eq!(check_layout(4, 3, 1), Err(Error::EINVAL));
+        // Stripes can't be wider than the array
+        assert_eq!(check_layout(5, 6, 1), Err(Error::EINVAL));
+        // Some redundancy is required
+        assert_eq!(check_layout(5, 3, 0), Err(Error::EINVAL));
+        // But not too much
+        assert_eq!(check_layout(5, 3, 3), Err(Error::EINVAL));
+    }
+}
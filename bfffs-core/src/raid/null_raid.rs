@@ -4,9 +4,11 @@ use async_trait::async_trait;
 use crate::{
     BYTES_PER_LBA,
     ZERO_REGION,
+    capacity::ClusterShape,
     label::*,
     types::*,
     vdev::*,
+    vdev_block::VdevLeaf,
 };
 use futures::future;
 use mockall_double::double;
@@ -71,6 +73,10 @@ impl Vdev for NullRaid {
         self.mirror.lba2zone(lba)
     }
 
+    fn max_open_zones(&self) -> Option<u32> {
+        self.mirror.max_open_zones()
+    }
+
     fn optimum_queue_depth(&self) -> u32 {
         self.mirror.optimum_queue_depth()
     }
@@ -98,11 +104,23 @@ impl Vdev for NullRaid {
 
 #[async_trait]
 impl VdevRaidApi for NullRaid {
+    async fn check_write_pointers(&self, zone: ZoneT, allocated: LbaT)
+        -> Result<bool>
+    {
+        let start = self.mirror.zone_limits(zone).0;
+        let wp = self.mirror.write_pointer(start).await?;
+        Ok(wp.map_or(true, |wp| wp == start + allocated))
+    }
+
     fn erase_zone(&self, zone: ZoneT) -> BoxVdevFut {
         let limits = self.mirror.zone_limits(zone);
         Box::pin(self.mirror.erase_zone(limits.0, limits.1 - 1))
     }
 
+    fn leaf_status(&self) -> Vec<LeafStatus> {
+        self.mirror.leaf_status()
+    }
+
     fn finish_zone(&self, zone: ZoneT) -> BoxVdevFut {
         let limits = self.mirror.zone_limits(zone);
         Box::pin(self.mirror.finish_zone(limits.0, limits.1 - 1))
@@ -112,6 +130,10 @@ impl VdevRaidApi for NullRaid {
         (0, Box::pin(future::ok(())))
     }
 
+    async fn online(&self, leaf: VdevLeaf) -> Result<()> {
+        self.mirror.online(leaf).await
+    }
+
     fn open_zone(&self, zone: ZoneT) -> BoxVdevFut {
         let limits = self.mirror.zone_limits(zone);
         Box::pin(self.mirror.open_zone(limits.0))
@@ -131,6 +153,16 @@ impl VdevRaidApi for NullRaid {
         Box::pin(future::ok(()))
     }
 
+    fn shape(&self) -> ClusterShape {
+        ClusterShape {
+            disks_per_stripe: 1,
+            redundancy: 0,
+            mirrors: vec![self.mirror.shape()],
+            size: self.mirror.size(),
+            used: 0
+        }
+    }
+
     fn write_at(&self, buf: IoVec, _zone: ZoneT, lba: LbaT) -> BoxVdevFut
     {
         // Pad up to a whole number of LBAs.  Upper layers don't do this because
@@ -56,7 +56,7 @@ fn invmod<T>(a: T, n: T) -> T
 }
 
 /// A simple primality tester.  Optimized for size, not speed
-fn is_prime(n: i16) -> bool {
+pub(super) fn is_prime(n: i16) -> bool {
     if n <= 1 {
         return false;
     } else if n <= 3 {
@@ -2,10 +2,12 @@
 
 use async_trait::async_trait;
 use crate::{
+    capacity::ClusterShape,
     label::*,
     types::*,
     util::*,
     vdev::*,
+    vdev_block::VdevLeaf,
 };
 use divbuf::{DivBuf, DivBufShared};
 use futures::{
@@ -346,6 +348,47 @@ impl VdevRaid {
                       children.into_boxed_slice())
     }
 
+    /// Return where each child's write pointer should be for `zone`, after
+    /// `allocated` LBAs have been written to it.
+    ///
+    /// `allocated` must be a whole number of stripes, as it always is once
+    /// the zone's `StripeBuffer` has been flushed.
+    fn expected_write_pointers(&self, zone: ZoneT, allocated: LbaT)
+        -> Vec<LbaT>
+    {
+        let (start_lba, _) = self.zone_limits(zone);
+        // Leading wasted space was zero-filled when the zone was opened
+        let mut wps = (0..self.mirrors.len())
+            .map(|idx| self.first_usable_disk_lba(zone, idx))
+            .collect::<Vec<_>>();
+        let start = ChunkId::Data(start_lba / self.chunksize);
+        let end = ChunkId::Data((start_lba + allocated) / self.chunksize);
+        for (_, loc) in self.locator.iter(start, end) {
+            let wp = &mut wps[loc.disk as usize];
+            *wp = cmp::max(*wp, (loc.offset + 1) * self.chunksize);
+        }
+        wps
+    }
+
+    /// Find the first LBA of child `idx` that's within `zone`.  Anything
+    /// before it in the child's zone is wasted.
+    fn first_usable_disk_lba(&self, zone: ZoneT, idx: usize) -> LbaT {
+        let (start_lba, _) = self.zone_limits(zone);
+        let (first_disk_lba, _) = self.mirrors[0].zone_limits(zone);
+        let start_disk_chunk = div_roundup(first_disk_lba, self.chunksize);
+        let mut first_usable_disk_lba = 0;
+        for chunk in start_disk_chunk.. {
+            let loc = Chunkloc::new(idx as i16, chunk);
+            let chunk_id = self.locator.loc2id(loc);
+            let chunk_lba = chunk_id.address() * self.chunksize;
+            if chunk_lba >= start_lba {
+                first_usable_disk_lba = chunk * self.chunksize;
+                break;
+            }
+        }
+        first_usable_disk_lba
+    }
+
     /// Asynchronously open a zone on a RAID device
     ///
     /// # Parameters
@@ -363,22 +406,14 @@ impl VdevRaid {
         assert!(self.stripe_buffers.write().unwrap().insert(zone, sb).is_none());
 
         let (first_disk_lba, _) = self.mirrors[0].zone_limits(zone);
-        let start_disk_chunk = div_roundup(first_disk_lba, self.chunksize);
         let futs = FuturesUnordered::<BoxVdevFut>::new();
         for (idx, mirrordev) in self.mirrors.iter().enumerate() {
-            // Find the first LBA of this disk that's within our zone
-            let mut first_usable_disk_lba = 0;
-            for chunk in start_disk_chunk.. {
-                let loc = Chunkloc::new(idx as i16, chunk);
-                let chunk_id = self.locator.loc2id(loc);
-                let chunk_lba = chunk_id.address() * self.chunksize;
-                if chunk_lba >= start_lba {
-                    first_usable_disk_lba = chunk * self.chunksize;
-                    break;
-                }
-            }
+            let first_usable_disk_lba = self.first_usable_disk_lba(zone, idx);
             futs.push(Box::pin(mirrordev.open_zone(first_disk_lba)));
-            if first_usable_disk_lba > first_disk_lba {
+            // A reopened zone's leading space was already zero-filled, and
+            // rewriting it would violate the write pointer.
+            if already_allocated == 0 && first_usable_disk_lba > first_disk_lba
+            {
                 // Zero-fill leading wasted space so as not to cause a
                 // write pointer violation on SMR disks.
                 let zero_lbas = first_usable_disk_lba - first_disk_lba;
@@ -712,6 +747,14 @@ impl Vdev for VdevRaid {
         }
     }
 
+    // Every RAID zone spans one zone on each child, so the children's most
+    // restrictive limit applies.
+    fn max_open_zones(&self) -> Option<u32> {
+        self.mirrors.iter()
+            .filter_map(|m| m.max_open_zones())
+            .min()
+    }
+
     fn optimum_queue_depth(&self) -> u32 {
         self.optimum_queue_depth
     }
@@ -826,6 +869,19 @@ impl Vdev for VdevRaid {
 
 #[async_trait]
 impl VdevRaidApi for VdevRaid {
+    async fn check_write_pointers(&self, zone: ZoneT, allocated: LbaT)
+        -> Result<bool>
+    {
+        let expected = self.expected_write_pointers(zone, allocated);
+        let (first_disk_lba, _) = self.mirrors[0].zone_limits(zone);
+        let wps = future::try_join_all(
+            self.mirrors.iter().map(|m| m.write_pointer(first_disk_lba))
+        ).await?;
+        Ok(wps.into_iter()
+            .zip(expected)
+            .all(|(wp, expected)| wp.map_or(true, |wp| wp == expected)))
+    }
+
     fn erase_zone(&self, zone: ZoneT) -> BoxVdevFut {
         assert!(!self.stripe_buffers.read().unwrap().contains_key(&zone),
             "Tried to erase an open zone");
@@ -838,6 +894,12 @@ impl VdevRaidApi for VdevRaid {
         Box::pin(fut)
     }
 
+    fn leaf_status(&self) -> Vec<LeafStatus> {
+        self.mirrors.iter()
+            .flat_map(Mirror::leaf_status)
+            .collect()
+    }
+
     // Zero-fill the current StripeBuffer and write it out.  Then drop the
     // StripeBuffer.
     fn finish_zone(&self, zone: ZoneT) -> BoxVdevFut {
@@ -890,6 +952,14 @@ impl VdevRaidApi for VdevRaid {
         }
     }
 
+    async fn online(&self, leaf: VdevLeaf) -> Result<()> {
+        let uuid = leaf.uuid();
+        let mirror = self.mirrors.iter()
+            .find(|m| m.leaf_status().iter().any(|ls| ls.uuid == uuid))
+            .ok_or(Error::ENOENT)?;
+        mirror.online(leaf).await
+    }
+
     fn open_zone(&self, zone: ZoneT) -> BoxVdevFut {
         self.open_zone_priv(zone, 0)
     }
@@ -975,6 +1045,16 @@ impl VdevRaidApi for VdevRaid {
         self.open_zone_priv(zone, allocated)
     }
 
+    fn shape(&self) -> ClusterShape {
+        ClusterShape {
+            disks_per_stripe: self.codec.stripesize(),
+            redundancy: self.codec.protection(),
+            mirrors: self.mirrors.iter().map(Mirror::shape).collect(),
+            size: self.size(),
+            used: 0
+        }
+    }
+
     fn write_at(&self, buf: IoVec, zone: ZoneT, mut lba: LbaT) -> BoxVdevFut
     {
         let col_len = self.chunksize as usize * BYTES_PER_LBA;
@@ -1947,6 +2027,54 @@ fn open_zone_reopen() {
     vdev_raid.write_at(wbuf, 1, 4196).now_or_never().unwrap().unwrap();
 }
 
+// When reopening a zone, each disk's write pointer should be right after the
+// last stripe that was allocated.  Disks with simulated zones don't have write
+// pointers.
+#[rstest]
+#[case(Some(4196), true)]
+#[case(Some(4197), false)]
+#[case(Some(4195), false)]
+#[case(None, true)]
+fn check_write_pointers(#[case] wp: Option<LbaT>, #[case] expected: bool) {
+    let k = 2;
+    let f = 1;
+    const CHUNKSIZE: LbaT = 1;
+    let zl0 = (1, 4096);
+    let zl1 = (4096, 8192);
+
+    let mut mirrors = Vec::<Mirror>::new();
+    let bd = || {
+        let mut bd = Mirror::default();
+        bd.expect_size()
+            .return_const(262_144u64);
+        bd.expect_zone_limits()
+            .with(eq(0))
+            .return_const(zl0);
+        bd.expect_zone_limits()
+            .with(eq(1))
+            .return_const(zl1);
+        bd.expect_optimum_queue_depth()
+            .return_const(10u32);
+        bd.expect_write_pointer()
+            .once()
+            .with(eq(4096))
+            .return_once(move |_| Box::pin(future::ok(wp)));
+        bd
+    };
+    mirrors.push(bd());    //disk 0
+    mirrors.push(bd());    //disk 1
+
+    let vdev_raid = VdevRaid::new(CHUNKSIZE, k, f,
+                                  Uuid::new_v4(),
+                                  LayoutAlgorithm::PrimeS,
+                                  mirrors.into_boxed_slice());
+    let r = vdev_raid.check_write_pointers(1, 100)
+        .now_or_never()
+        .unwrap()
+        .unwrap();
+    assert_eq!(r, expected);
+}
+
 // Open a zone that has wasted leading space due to a chunksize misaligned with
 // the zone size.
 // Use highly unrealistic disks with 32 LBAs per zone
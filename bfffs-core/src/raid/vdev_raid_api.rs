@@ -1,21 +1,42 @@
 // vim: tw=80
 use async_trait::async_trait;
 use crate::{
+    capacity::ClusterShape,
     label::*,
     types::*,
-    vdev::*
+    vdev::*,
+    vdev_block::VdevLeaf
 };
 
 /// The public interface for all RAID Vdevs.  All Vdevs that slot beneath a
 /// cluster must implement this API.
 #[async_trait]
 pub trait VdevRaidApi : Vdev + Send + Sync + 'static {
+    /// Check that the devices' write pointers for an open zone agree with the
+    /// `Cluster`'s record of it.
+    ///
+    /// Returns `false` if any device with native zones has its write pointer
+    /// anywhere other than where it would be after writing `allocated` LBAs
+    /// to the zone, meaning that the zone can't safely be appended to.
+    /// Devices with simulated zones always agree.
+    ///
+    /// # Parameters
+    /// - `zone`:       The target zone ID
+    /// - `allocated`:  The amount of data that was previously allocated in
+    ///                 this zone.
+    async fn check_write_pointers(&self, zone: ZoneT, allocated: LbaT)
+        -> Result<bool>;
+
     /// Asynchronously erase a zone on a RAID device
     ///
     /// # Parameters
     /// - `zone`:    The target zone ID
     fn erase_zone(&self, zone: ZoneT) -> BoxVdevFut;
 
+    /// Return the status of every leaf device, including its lifetime I/O
+    /// error counts.
+    fn leaf_status(&self) -> Vec<LeafStatus>;
+
     /// Asynchronously finish a zone on a RAID device
     ///
     /// # Parameters
@@ -30,6 +51,12 @@ pub trait VdevRaidApi : Vdev + Send + Sync + 'static {
     /// complete when the zone's contents are fully written
     fn flush_zone(&self, zone: ZoneT) -> (LbaT, BoxVdevFut);
 
+    /// Bring a removed or faulted leaf device back online, and resilver
+    /// whatever writes it missed.
+    ///
+    /// Fails with `ENOENT` if `leaf` doesn't belong to this RAID device.
+    async fn online(&self, leaf: VdevLeaf) -> Result<()>;
+
     /// Asynchronously open a zone on a RAID device
     ///
     /// # Parameters
@@ -61,6 +88,12 @@ pub trait VdevRaidApi : Vdev + Send + Sync + 'static {
     ///                        in this zone.
     fn reopen_zone(&self, zone: ZoneT, allocated: LbaT) -> BoxVdevFut;
 
+    /// Describe the device's layout, for capacity planning.
+    ///
+    /// The returned shape's `used` field is always 0; only the cluster knows
+    /// how much space is allocated.
+    fn shape(&self) -> ClusterShape;
+
     /// Asynchronously write a contiguous portion of the vdev.
     ///
     /// Returns `()` on success, or an error on failure
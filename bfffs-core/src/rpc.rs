@@ -5,16 +5,94 @@
 // or without no_std.
 
 use crate::{
-    controller::TreeID,
+    capacity::Plan,
+    cleaner::CleanStats,
+    controller::{DataError, PoolStatus, TreeID},
+    database::TxgStatus,
+    defrag::DefragStats,
+    feature::Feature,
+    fs::{IoStats, OpenFile},
+    job::{JobID, JobStatus},
+    Error,
     Result
 };
+use metrohash::MetroHash64;
 use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::hash::Hasher;
+
+pub mod daemon {
+    use super::Request;
+
+    /// Check that bfffsd is alive and processing requests
+    pub fn ping() -> Request {
+        Request::DaemonPing
+    }
+}
+
+pub mod debug {
+    use super::Request;
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct CloseFile {
+        /// File system name, including the pool
+        pub name: String,
+        /// File handle number
+        pub fh: u64,
+    }
+
+    /// Forcibly close a client's file handle
+    pub fn close_file(name: String, fh: u64) -> Request {
+        Request::DebugCloseFile(CloseFile{name, fh})
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Compact {
+        /// File system name, including the pool
+        pub name: String,
+    }
+
+    pub fn compact(name: String) -> Request {
+        Request::DebugCompact(Compact{name})
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct OpenFiles {
+        /// File system name, including the pool
+        pub name: String,
+    }
+
+    /// List the file handles that clients have open on a file system
+    pub fn open_files(name: String) -> Request {
+        Request::DebugOpenFiles(OpenFiles{name})
+    }
+}
 
 pub mod fs {
-    use crate::property::{Property, PropertyName, PropertySource};
+    use crate::{
+        Result,
+        property::{
+            Property,
+            PropertyName,
+            PropertySource,
+            UserProperty
+        }
+    };
     use super::Request;
     use serde_derive::{Deserialize, Serialize};
 
+    /// A file was closed after being written through, as reported to a
+    /// [`watch`]er.
+    #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+    pub struct CloseEvent {
+        /// File system name, including the pool
+        pub dataset: String,
+        pub ino: u64,
+        /// Absolute path of the file, if bfffsd knows it
+        pub path: Option<String>,
+    }
+
     #[derive(Debug, Deserialize, Serialize)]
     pub struct Create {
         pub name: String,
@@ -28,23 +106,82 @@ pub mod fs {
     #[derive(Debug, Deserialize, Serialize)]
     pub struct Destroy {
         pub name: String,
+        /// Only report what would be destroyed
+        pub dry_run: bool,
+    }
+
+    pub fn destroy(name: String, dry_run: bool) -> Request {
+        Request::FsDestroy(Destroy{name, dry_run})
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Events {
+        /// As returned by [`watch`]
+        pub id: WatchId,
     }
 
-    pub fn destroy(name: String) -> Request {
-        Request::FsDestroy(Destroy{name})
+    /// Wait for the next batch of events for a [`watch`].  This also
+    /// acknowledges every event returned by the previous call, releasing any
+    /// closes that were waiting on a synchronous watch.
+    pub fn events(id: WatchId) -> Request {
+        Request::FsEvents(Events{id})
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Freeze {
+        /// File system name, including the pool
+        pub name: String,
+    }
+
+    /// Block modifications to a file system and sync it to disk, so that
+    /// external tools can take a consistent copy of the underlying devices.
+    /// It stays frozen until [`thaw`].
+    pub fn freeze(name: String) -> Request {
+        Request::FsFreeze(Freeze{name})
     }
 
     #[derive(Debug, Deserialize, Serialize)]
     pub struct DsInfo {
-        pub name:   String,
-        pub props:  Vec<(Property, PropertySource)>,
-        pub offset: u64
+        pub name:       String,
+        pub props:      Vec<(Property, PropertySource)>,
+        /// Every user property that applies to the dataset, if requested
+        pub user_props: Vec<(UserProperty, PropertySource)>,
+        pub offset:     u64
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Iostat {
+        /// File system name, including the pool
+        pub name: String,
+        /// Zero the counters after reading them
+        pub reset: bool,
+    }
+
+    /// Report a file system's read and write counters
+    pub fn iostat(name: String, reset: bool) -> Request {
+        Request::FsIostat(Iostat{name, reset})
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Jail {
+        /// Numeric ID of the jail
+        pub jid: i32,
+        /// File system name, including the pool
+        pub name: String,
+    }
+
+    /// Delegate management of a file system's descendants to processes
+    /// running within the given jail.  Like `zfs jail`.
+    pub fn jail(name: String, jid: i32) -> Request {
+        Request::FsJail(Jail{jid, name})
     }
 
     #[derive(Debug, Deserialize, Serialize)]
     pub struct List {
         pub name: String,
         pub props: Vec<PropertyName>,
+        /// Also return all user properties
+        pub user_props: bool,
         pub offset: Option<u64>
     }
 
@@ -54,10 +191,14 @@ pub mod fs {
     /// The named dataset itself will not be included.  If `offset` is provided,
     /// it can be used to resume a previous listing, as in `getdirentries`.
     ///
-    pub fn list(name: String, props: Vec<PropertyName>, offset: Option<u64>)
-        -> Request
+    pub fn list(
+        name: String,
+        props: Vec<PropertyName>,
+        user_props: bool,
+        offset: Option<u64>
+    ) -> Request
     {
-        Request::FsList(List{name, props, offset})
+        Request::FsList(List{name, props, user_props, offset})
     }
 
     #[derive(Debug, Deserialize, Serialize)]
@@ -66,27 +207,98 @@ pub mod fs {
         pub opts: String,
         /// File system name, including the pool
         pub name: String,
+        /// Mount here, rather than at the `mountpoint` property
+        pub mountpoint: Option<String>,
     }
 
-    pub fn mount(name: String) -> Request {
+    /// Mount a file system.  Unless `opts` includes "remount", it must not
+    /// already be mounted.
+    pub fn mount(name: String, mountpoint: Option<String>, opts: Vec<String>)
+        -> Request
+    {
         Request::FsMount(Mount {
-            opts: String::new(),    // TODO
-            name
+            opts: opts.join(","),
+            name,
+            mountpoint
         })
     }
 
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct MountAll {
+        /// Comma-separated mount options, applied to every file system
+        pub opts: String,
+        pub pool: String,
+    }
+
+    /// Mount every file system in the pool that has `canmount=on` and isn't
+    /// already mounted, parents before children.
+    pub fn mount_all(pool: String, opts: Vec<String>) -> Request {
+        Request::FsMountAll(MountAll {
+            opts: opts.join(","),
+            pool
+        })
+    }
+
+    /// The name of each file system that a bulk operation tried, in order,
+    /// and what happened to it.
+    pub type BulkResult = Vec<(String, Result<()>)>;
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Move {
+        /// Source file system, including the pool
+        pub src_ds: String,
+        /// Source path, relative to `src_ds`'s root
+        pub src_path: String,
+        /// Destination file system, including the pool
+        pub dst_ds: String,
+        /// Destination path, relative to `dst_ds`'s root
+        pub dst_path: String,
+    }
+
+    /// Move a file from one file system to another in the same pool.
+    pub fn mv(src_ds: String, src_path: String, dst_ds: String,
+              dst_path: String) -> Request
+    {
+        Request::FsMove(Move{src_ds, src_path, dst_ds, dst_path})
+    }
+
+    /// A file system that bfffsd has mounted
+    #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+    pub struct MountInfo {
+        /// File system name, including the pool
+        pub name: String,
+        pub mountpoint: String,
+        /// Mount options derived from the file system's properties
+        pub opts: Vec<String>,
+        /// User ID of the client that mounted it
+        pub uid: u32,
+    }
+
+    /// List every currently mounted file system
+    pub fn mounts() -> Request {
+        Request::FsMounts
+    }
+
     #[derive(Debug, Deserialize, Serialize)]
     pub struct Set {
         /// File system name, including the pool
         pub name: String,
         /// Dataset properties
-        pub props: Vec<Property>
+        pub props: Vec<Property>,
+        /// User properties.  An empty value clears the property.
+        pub user_props: Vec<UserProperty>
     }
 
-    pub fn set(name: String, props: Vec<Property>) -> Request {
+    pub fn set(
+        name: String,
+        props: Vec<Property>,
+        user_props: Vec<UserProperty>
+    ) -> Request
+    {
         Request::FsSet(Set {
             name,
-            props
+            props,
+            user_props
         })
     }
 
@@ -94,11 +306,50 @@ pub mod fs {
     pub struct Stat {
         pub name: String,
         pub props: Vec<PropertyName>,
+        /// Also return all user properties
+        pub user_props: bool,
     }
 
     /// Lookup the requested properties for a single dataset
-    pub fn stat(name: String, props: Vec<PropertyName>) -> Request {
-        Request::FsStat(Stat{name, props})
+    pub fn stat(name: String, props: Vec<PropertyName>, user_props: bool)
+        -> Request
+    {
+        Request::FsStat(Stat{name, props, user_props})
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Thaw {
+        /// File system name, including the pool
+        pub name: String,
+    }
+
+    /// Allow modifications to a file system that was frozen by [`freeze`].
+    pub fn thaw(name: String) -> Request {
+        Request::FsThaw(Thaw{name})
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Unjail {
+        /// Numeric ID of the jail
+        pub jid: i32,
+        /// File system name, including the pool
+        pub name: String,
+    }
+
+    /// Revoke a delegation made by [`jail`].
+    pub fn unjail(name: String, jid: i32) -> Request {
+        Request::FsUnjail(Unjail{jid, name})
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Unwatch {
+        /// As returned by [`watch`]
+        pub id: WatchId,
+    }
+
+    /// Cancel a [`watch`].  Any closes still waiting on it are released.
+    pub fn unwatch(id: WatchId) -> Request {
+        Request::FsUnwatch(Unwatch{id})
     }
 
     #[derive(Debug, Deserialize, Serialize)]
@@ -116,55 +367,483 @@ pub mod fs {
         })
     }
 
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct UnmountAll {
+        /// Forcibly unmount, even if in-use
+        pub force: bool,
+        pub pool: String,
+    }
+
+    /// Unmount every file system in the pool, children before parents.
+    pub fn unmount_all(pool: String, force: bool) -> Request {
+        Request::FsUnmountAll(UnmountAll {
+            force,
+            pool
+        })
+    }
+
+    /// Identifies a registration made by [`watch`]
+    pub type WatchId = u64;
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Watch {
+        /// File system name, including the pool.  Its descendants are watched
+        /// too.
+        pub name: String,
+        /// Make every close wait until the watcher acknowledges the event, or
+        /// until it times out.
+        pub sync: bool,
+    }
+
+    /// Register to be notified whenever a file is closed after being written
+    /// through, for example to scan or index it.  Events are retrieved with
+    /// [`events`].
+    pub fn watch(name: String, sync: bool) -> Request {
+        Request::FsWatch(Watch{name, sync})
+    }
+
+}
+
+pub mod job {
+    use crate::job::JobID;
+    use super::Request;
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Status {
+        pub id: JobID
+    }
+
+    pub fn list() -> Request {
+        Request::JobList
+    }
+
+    pub fn status(id: JobID) -> Request {
+        Request::JobStatus(Status {
+            id
+        })
+    }
 }
 
 pub mod pool {
+    use crate::{
+        capacity::Change,
+        cleaner::CleanPolicy,
+        feature::Feature,
+        pool_property::PoolProperty
+    };
     use super::Request;
     use serde_derive::{Deserialize, Serialize};
+    use std::path::PathBuf;
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Add {
+        pub pool: String,
+        /// Number of data plus parity chunks in each RAID stripe
+        pub disks_per_stripe: i16,
+        /// Degree of RAID redundancy
+        pub redundancy: i16,
+        /// Devices of each of the new cluster's mirrors
+        pub mirrors: Vec<Vec<PathBuf>>
+    }
+
+    /// Grow the pool by adding a new cluster, built from unused devices
+    pub fn add(
+        pool: String,
+        disks_per_stripe: i16,
+        redundancy: i16,
+        mirrors: Vec<Vec<PathBuf>>
+    ) -> Request {
+        Request::PoolAdd(Add {
+            pool,
+            disks_per_stripe,
+            redundancy,
+            mirrors
+        })
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Checkpoint {
+        pub pool: String,
+        /// Discard the existing checkpoint instead of taking a new one
+        pub discard: bool
+    }
+
+    pub fn checkpoint(pool: String, discard: bool) -> Request {
+        Request::PoolCheckpoint(Checkpoint {
+            pool,
+            discard
+        })
+    }
 
     #[derive(Debug, Deserialize, Serialize)]
     pub struct Clean {
-        pub pool: String
+        pub pool: String,
+        /// Only report what would be cleaned
+        pub dry_run: bool,
+        /// Where to put the records that get moved
+        pub policy: CleanPolicy
     }
 
-    pub fn clean(pool: String) -> Request {
+    pub fn clean(pool: String, dry_run: bool, policy: CleanPolicy) -> Request {
         Request::PoolClean(Clean {
+            pool,
+            dry_run,
+            policy
+        })
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Defrag {
+        pub pool: String,
+        /// Only measure fragmentation
+        pub dry_run: bool
+    }
+
+    pub fn defrag(pool: String, dry_run: bool) -> Request {
+        Request::PoolDefrag(Defrag {
+            pool,
+            dry_run
+        })
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Errors {
+        pub pool: String
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Export {
+        pub pool: String,
+        /// Forcibly unmount file systems, even if in-use
+        pub force: bool
+    }
+
+    /// Unmount every file system, children before parents, and then stop
+    /// bfffsd.  If any file system can't be unmounted, then bfffsd keeps
+    /// running.
+    pub fn export(pool: String, force: bool) -> Request {
+        Request::PoolExport(Export {
+            pool,
+            force
+        })
+    }
+
+    /// List every file in the pool with unrecoverable read errors
+    pub fn errors(pool: String) -> Request {
+        Request::PoolErrors(Errors {
+            pool
+        })
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Online {
+        pub pool: String,
+        /// Path to the reattached device
+        pub dev: PathBuf
+    }
+
+    /// Bring a removed or faulted device back online
+    pub fn online(pool: String, dev: PathBuf) -> Request {
+        Request::PoolOnline(Online {
+            pool,
+            dev
+        })
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Plan {
+        pub pool: String,
+        pub change: Change
+    }
+
+    /// Predict the effect of a vdev configuration change, without making it
+    pub fn plan(pool: String, change: Change) -> Request {
+        Request::PoolPlan(Plan {
+            pool,
+            change
+        })
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Set {
+        pub pool: String,
+        pub props: Vec<PoolProperty>
+    }
+
+    /// Change pool properties
+    pub fn set(pool: String, props: Vec<PoolProperty>) -> Request {
+        Request::PoolSet(Set {
+            pool,
+            props
+        })
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Status {
+        pub pool: String
+    }
+
+    pub fn status(pool: String) -> Request {
+        Request::PoolStatus(Status {
+            pool
+        })
+    }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Txgs {
+        pub pool: String
+    }
+
+    pub fn txgs(pool: String) -> Request {
+        Request::PoolTxgs(Txgs {
             pool
         })
     }
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Upgrade {
+        pub pool: String,
+        /// Features to enable.  If empty, enable all supported features.
+        pub features: Vec<Feature>
+    }
+
+    pub fn upgrade(pool: String, features: Vec<Feature>) -> Request {
+        Request::PoolUpgrade(Upgrade {
+            pool,
+            features
+        })
+    }
+}
+
+pub mod volume {
+    use crate::property::Property;
+    use super::Request;
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(Debug, Deserialize, Serialize)]
+    pub struct Create {
+        /// Volume name, including the pool
+        pub name: String,
+        /// Size in bytes
+        pub size: u64,
+        pub props: Vec<Property>,
+    }
+
+    pub fn create(name: String, size: u64, props: Vec<Property>) -> Request {
+        Request::VolumeCreate(Create{name, size, props})
+    }
+}
+
+/// Identifies a request on a single connection.
+///
+/// Every request packet is a bincode-encoded `(RequestId, Option<AuthHash>,
+/// Request)` tuple, and every response packet a `(RequestId, Response)` tuple,
+/// each followed by a checksum.  Since the server may complete requests out of
+/// order, the client uses the ID to match each response to its request.
+pub type RequestId = u64;
+
+/// Hash of an authentication token.
+///
+/// If bfffsd was started with a token file, then clients who can read that
+/// file may make privileged requests by sending its hash along with them.
+pub type AuthHash = [u8; 32];
+
+/// Size of the checksum that follows every encoded message
+const CHECKSUM_SIZE: usize = 8;
+
+/// Compute the SHA-256 hash of an authentication token
+pub fn auth_hash(token: &[u8]) -> AuthHash {
+    Sha256::digest(token).into()
+}
+
+fn checksum(body: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    let mut hasher = MetroHash64::new();
+    hasher.write(body);
+    hasher.finish().to_le_bytes()
+}
+
+/// Serialize a message and append its checksum
+pub fn encode<T: serde::Serialize>(msg: &T) -> Result<Vec<u8>> {
+    let mut buf = bincode::serialize(msg).map_err(|_| Error::EINVAL)?;
+    let cksum = checksum(&buf);
+    buf.extend_from_slice(&cksum);
+    Ok(buf)
+}
+
+/// Verify a message's checksum and deserialize it.
+///
+/// Fails with `EINTEGRITY` if the checksum doesn't match, or `EINVAL` if the
+/// message is malformed.
+pub fn decode<T: serde::de::DeserializeOwned>(packet: &[u8]) -> Result<T> {
+    if packet.len() < CHECKSUM_SIZE {
+        return Err(Error::EINVAL);
+    }
+    let (body, cksum) = packet.split_at(packet.len() - CHECKSUM_SIZE);
+    if checksum(body) != cksum {
+        return Err(Error::EINTEGRITY);
+    }
+    bincode::deserialize(body).map_err(|_| Error::EINVAL)
 }
 
 /// An RPC request from bfffs to bfffsd
 #[derive(Debug, Deserialize, Serialize)]
 pub enum Request {
+    /// Cancel the identified request, which must still be in progress on the
+    /// same connection.  It will complete with `ECANCELED`.
+    Cancel(RequestId),
+    /// A no-op, for monitoring daemon liveness
+    DaemonPing,
+    DebugCloseFile(debug::CloseFile),
+    /// Merge under-filled nodes in a file system's metadata tree
+    DebugCompact(debug::Compact),
     DebugDropCache,
+    DebugOpenFiles(debug::OpenFiles),
     FsCreate(fs::Create),
     FsDestroy(fs::Destroy),
+    /// Wait for close events from a watch
+    FsEvents(fs::Events),
+    /// Quiesce a file system until it's thawed
+    FsFreeze(fs::Freeze),
+    FsIostat(fs::Iostat),
+    /// Delegate a file system to a jail
+    FsJail(fs::Jail),
     FsList(fs::List),
     FsMount(fs::Mount),
+    /// Mount every file system with `canmount=on`
+    FsMountAll(fs::MountAll),
+    /// List all mounted file systems
+    FsMounts,
+    /// Move a file between file systems
+    FsMove(fs::Move),
     FsSet(fs::Set),
     FsStat(fs::Stat),
+    FsThaw(fs::Thaw),
+    FsUnjail(fs::Unjail),
     FsUnmount(fs::Unmount),
-    PoolClean(pool::Clean)
+    /// Unmount every mounted file system
+    FsUnmountAll(fs::UnmountAll),
+    FsUnwatch(fs::Unwatch),
+    /// Register for close events
+    FsWatch(fs::Watch),
+    /// List all running and recently finished jobs
+    JobList,
+    JobStatus(job::Status),
+    PoolAdd(pool::Add),
+    PoolCheckpoint(pool::Checkpoint),
+    PoolClean(pool::Clean),
+    PoolDefrag(pool::Defrag),
+    PoolErrors(pool::Errors),
+    /// Unmount everything and stop bfffsd
+    PoolExport(pool::Export),
+    PoolOnline(pool::Online),
+    PoolPlan(pool::Plan),
+    PoolSet(pool::Set),
+    PoolStatus(pool::Status),
+    PoolTxgs(pool::Txgs),
+    PoolUpgrade(pool::Upgrade),
+    VolumeCreate(volume::Create),
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub enum Response {
+    Cancel(Result<()>),
+    DaemonPing(Result<()>),
+    DebugCloseFile(Result<()>),
+    /// The number of nodes that were coalesced
+    DebugCompact(Result<usize>),
     DebugDropCache(Result<()>),
+    DebugOpenFiles(Result<Vec<OpenFile>>),
+    /// The server could not process the request at all, for example because
+    /// it was malformed.
+    Error(Error),
     FsCreate(Result<TreeID>),
-    FsDestroy(Result<()>),
+    FsDestroy(Result<Vec<String>>),
+    FsEvents(Result<Vec<fs::CloseEvent>>),
+    FsFreeze(Result<()>),
+    FsIostat(Result<IoStats>),
+    FsJail(Result<()>),
     FsList(Result<Vec<fs::DsInfo>>),
     FsMount(Result<()>),
+    FsMountAll(Result<fs::BulkResult>),
+    FsMounts(Result<Vec<fs::MountInfo>>),
+    FsMove(Result<()>),
     FsSet(Result<()>),
     FsStat(Result<fs::DsInfo>),
+    FsThaw(Result<()>),
+    FsUnjail(Result<()>),
     FsUnmount(Result<()>),
-    PoolClean(Result<()>),
+    FsUnmountAll(Result<fs::BulkResult>),
+    FsUnwatch(Result<()>),
+    FsWatch(Result<fs::WatchId>),
+    JobList(Result<Vec<JobStatus>>),
+    JobStatus(Result<JobStatus>),
+    PoolAdd(Result<()>),
+    PoolCheckpoint(Result<()>),
+    /// Statistics about the pool's cleanliness, and the ID of the cleaning
+    /// job, if one was started.
+    PoolClean(Result<(CleanStats, Option<JobID>)>),
+    /// The pool's fragmentation before defragmenting, and the ID of the
+    /// defragmentation job, if one was started.
+    PoolDefrag(Result<(DefragStats, Option<JobID>)>),
+    PoolErrors(Result<Vec<DataError>>),
+    /// The result of unmounting each file system.  The pool was only exported
+    /// if they all succeeded.
+    PoolExport(Result<fs::BulkResult>),
+    PoolOnline(Result<()>),
+    PoolPlan(Result<Plan>),
+    PoolSet(Result<()>),
+    PoolStatus(Result<PoolStatus>),
+    PoolTxgs(Result<TxgStatus>),
+    PoolUpgrade(Result<Vec<Feature>>),
+    VolumeCreate(Result<TreeID>),
 }
 
 impl Response {
+    pub fn into_cancel(self) -> Result<()> {
+        match self {
+            Response::Cancel(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_daemon_ping(self) -> Result<()> {
+        match self {
+            Response::DaemonPing(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_debug_close_file(self) -> Result<()> {
+        match self {
+            Response::DebugCloseFile(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_debug_compact(self) -> Result<usize> {
+        match self {
+            Response::DebugCompact(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
     pub fn into_debug_drop_cache(self) -> Result<()> {
         match self {
             Response::DebugDropCache(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_debug_open_files(self) -> Result<Vec<OpenFile>> {
+        match self {
+            Response::DebugOpenFiles(r) => r,
+            Response::Error(e) => Err(e),
             x => panic!("Unexpected response type {x:?}")
         }
     }
@@ -172,13 +851,47 @@ impl Response {
     pub fn into_fs_create(self) -> Result<TreeID> {
         match self {
             Response::FsCreate(r) => r,
+            Response::Error(e) => Err(e),
             x => panic!("Unexpected response type {x:?}")
         }
     }
 
-    pub fn into_fs_destroy(self) -> Result<()> {
+    pub fn into_fs_destroy(self) -> Result<Vec<String>> {
         match self {
             Response::FsDestroy(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_fs_events(self) -> Result<Vec<fs::CloseEvent>> {
+        match self {
+            Response::FsEvents(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_fs_freeze(self) -> Result<()> {
+        match self {
+            Response::FsFreeze(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_fs_iostat(self) -> Result<IoStats> {
+        match self {
+            Response::FsIostat(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_fs_jail(self) -> Result<()> {
+        match self {
+            Response::FsJail(r) => r,
+            Response::Error(e) => Err(e),
             x => panic!("Unexpected response type {x:?}")
         }
     }
@@ -186,6 +899,7 @@ impl Response {
     pub fn into_fs_list(self) -> Result<Vec<fs::DsInfo>> {
         match self {
             Response::FsList(r) => r,
+            Response::Error(e) => Err(e),
             x => panic!("Unexpected response type {x:?}")
         }
     }
@@ -193,6 +907,31 @@ impl Response {
     pub fn into_fs_mount(self) -> Result<()> {
         match self {
             Response::FsMount(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_fs_mount_all(self) -> Result<fs::BulkResult> {
+        match self {
+            Response::FsMountAll(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_fs_mounts(self) -> Result<Vec<fs::MountInfo>> {
+        match self {
+            Response::FsMounts(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_fs_move(self) -> Result<()> {
+        match self {
+            Response::FsMove(r) => r,
+            Response::Error(e) => Err(e),
             x => panic!("Unexpected response type {x:?}")
         }
     }
@@ -200,6 +939,7 @@ impl Response {
     pub fn into_fs_set(self) -> Result<()> {
         match self {
             Response::FsSet(r) => r,
+            Response::Error(e) => Err(e),
             x => panic!("Unexpected response type {x:?}")
         }
     }
@@ -207,13 +947,135 @@ impl Response {
     pub fn into_fs_stat(self) -> Result<fs::DsInfo> {
         match self {
             Response::FsStat(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_fs_thaw(self) -> Result<()> {
+        match self {
+            Response::FsThaw(r) => r,
+            Response::Error(e) => Err(e),
             x => panic!("Unexpected response type {x:?}")
         }
     }
 
-    pub fn into_pool_clean(self) -> Result<()> {
+    pub fn into_fs_unjail(self) -> Result<()> {
+        match self {
+            Response::FsUnjail(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_job_list(self) -> Result<Vec<JobStatus>> {
+        match self {
+            Response::JobList(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_job_status(self) -> Result<JobStatus> {
+        match self {
+            Response::JobStatus(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_pool_add(self) -> Result<()> {
+        match self {
+            Response::PoolAdd(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_pool_checkpoint(self) -> Result<()> {
+        match self {
+            Response::PoolCheckpoint(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_pool_clean(self) -> Result<(CleanStats, Option<JobID>)> {
         match self {
             Response::PoolClean(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_pool_defrag(self) -> Result<(DefragStats, Option<JobID>)> {
+        match self {
+            Response::PoolDefrag(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_pool_errors(self) -> Result<Vec<DataError>> {
+        match self {
+            Response::PoolErrors(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_pool_export(self) -> Result<fs::BulkResult> {
+        match self {
+            Response::PoolExport(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_pool_online(self) -> Result<()> {
+        match self {
+            Response::PoolOnline(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_pool_plan(self) -> Result<Plan> {
+        match self {
+            Response::PoolPlan(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_pool_set(self) -> Result<()> {
+        match self {
+            Response::PoolSet(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_pool_status(self) -> Result<PoolStatus> {
+        match self {
+            Response::PoolStatus(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_pool_txgs(self) -> Result<TxgStatus> {
+        match self {
+            Response::PoolTxgs(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_pool_upgrade(self) -> Result<Vec<Feature>> {
+        match self {
+            Response::PoolUpgrade(r) => r,
+            Response::Error(e) => Err(e),
             x => panic!("Unexpected response type {x:?}")
         }
     }
@@ -221,7 +1083,83 @@ impl Response {
     pub fn into_fs_unmount(self) -> Result<()> {
         match self {
             Response::FsUnmount(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_fs_unmount_all(self) -> Result<fs::BulkResult> {
+        match self {
+            Response::FsUnmountAll(r) => r,
+            Response::Error(e) => Err(e),
             x => panic!("Unexpected response type {x:?}")
         }
     }
+
+    pub fn into_fs_unwatch(self) -> Result<()> {
+        match self {
+            Response::FsUnwatch(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_fs_watch(self) -> Result<fs::WatchId> {
+        match self {
+            Response::FsWatch(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+
+    pub fn into_volume_create(self) -> Result<TreeID> {
+        match self {
+            Response::VolumeCreate(r) => r,
+            Response::Error(e) => Err(e),
+            x => panic!("Unexpected response type {x:?}")
+        }
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    /// auth_hash should be plain SHA-256, so tools other than bfffs can compute
+    /// it too.
+    #[test]
+    fn auth_hash_sha256() {
+        let expected = [
+            0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea,
+            0x41, 0x41, 0x40, 0xde, 0x5d, 0xae, 0x22, 0x23,
+            0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c,
+            0xb4, 0x10, 0xff, 0x61, 0xf2, 0x00, 0x15, 0xad
+        ];
+        assert_eq!(auth_hash(b"abc"), expected);
+    }
+
+    #[test]
+    fn decode_corrupt() {
+        let mut packet = encode(&(42u64, Request::DebugDropCache)).unwrap();
+        packet[0] ^= 1;
+        let r = decode::<(RequestId, Request)>(&packet);
+        assert_eq!(r.unwrap_err(), Error::EINTEGRITY);
+    }
+
+    #[test]
+    fn decode_short() {
+        let r = decode::<(RequestId, Request)>(&[0, 1, 2]);
+        assert_eq!(r.unwrap_err(), Error::EINVAL);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let auth = Some(auth_hash(b"sekrit"));
+        let packet = encode(&(42u64, auth, Request::DebugDropCache)).unwrap();
+        let (id, auth2, req) =
+            decode::<(RequestId, Option<AuthHash>, Request)>(&packet).unwrap();
+        assert_eq!(id, 42);
+        assert_eq!(auth2, auth);
+        assert!(matches!(req, Request::DebugDropCache));
+    }
 }
@@ -47,7 +47,22 @@ pub struct CreditRequirements {
     pub remove: usize
 }
 
-fn serialize_reserved<S>(reserved: &[u8; 7], s: S)
+/// Output format for `Tree::dump_nodes`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DumpFormat {
+    /// One JSON object per line
+    Json,
+    /// One YAML document per Node
+    Yaml
+}
+
+/// On-disk format version of `TreeOnDisk`, written into every new tree.
+///
+/// A future change to the layout of trees must increment it, so that older
+/// versions of BFFFS fail cleanly instead of misinterpreting the tree.
+pub(crate) const TREE_VERSION: u8 = 0;
+
+fn serialize_reserved<S>(reserved: &[u8; 6], s: S)
     -> std::result::Result<S::Ok, S::Error>
     where S: Serializer
 {
@@ -67,9 +82,12 @@ struct InnerOnDisk<A: Addr> {
     // 8 bits of tree height is sufficient for a tree that can contain more data
     // than will ever be created by mankind, even with fanout of 2.
     height: u8,
+    /// On-disk format version.  Trees written before it existed have 0 here,
+    /// because it used to be reserved.
+    version: u8,
     // Makes the rest of the structure line up nicely in a hexdump
     #[serde(serialize_with = "serialize_reserved")]
-    _reserved: [u8; 7],
+    _reserved: [u8; 6],
     limits: Limits,
     root: A,
     txgs: Range<TxgT>,
@@ -80,6 +98,7 @@ impl<A: Addr + Default> Default for InnerOnDisk<A> {
     fn default() -> Self {
         InnerOnDisk {
             height: Default::default(),
+            version: TREE_VERSION,
             _reserved: Default::default(),
             limits: Default::default(),
             root: Default::default(),
@@ -94,6 +113,17 @@ impl<A: Addr + Default> Default for InnerOnDisk<A> {
 #[cfg_attr(test, derive(Default))]
 pub struct TreeOnDisk<A: Addr>(InnerOnDisk<A>);
 
+impl<A: Addr> TreeOnDisk<A> {
+    /// Can this version of BFFFS open the tree?
+    pub fn check_version(&self) -> Result<()> {
+        if self.0.version > TREE_VERSION {
+            Err(Error::EOPNOTSUPP)
+        } else {
+            Ok(())
+        }
+    }
+}
+
 impl<A: Addr> TypicalSize for TreeOnDisk<A> {
     // Verified in tree::tests::io::serialize_forest
     const TYPICAL_SIZE: usize = 32 + A::TYPICAL_SIZE;
@@ -113,5 +143,13 @@ mod t {
         let tod = TreeOnDisk::<RID>::default();
         format!("{cr:?} {tod:?}");
     }
+
+    #[test]
+    fn check_version() {
+        let mut tod = TreeOnDisk::<RID>::default();
+        assert_eq!(Ok(()), tod.check_version());
+        tod.0.version = TREE_VERSION + 1;
+        assert_eq!(Err(Error::EOPNOTSUPP), tod.check_version());
+    }
 }
 // LCOV_EXCL_STOP
@@ -2,6 +2,7 @@
 
 //! Nodes for Trees (private module)
 use crate::{
+    cache::EntryClass,
     dml::{Cacheable, CacheRef, DML},
     types::*,
     util::*,
@@ -547,7 +548,7 @@ impl<K: Key, V: Value> LeafData<K, V> {
         self.credit.take()
     }
 
-    fn wb_space(&self) -> usize {
+    pub(super) fn wb_space(&self) -> usize {
         // Unlike cache_space, we need to be able to update the credit when
         // adding and removing individual items.  So we simplify the
         // calculation, trading accuracy for mutatability.
@@ -1077,6 +1078,14 @@ impl<A: Addr, K: Key, V: Value> IntData<A, K, V> {
     }
 }
 
+/// Highest `NodeData` format tag that this version of BFFFS understands.
+const NODE_FORMAT_MAX: u32 = 1;
+
+/// The contents of a `Node`.
+///
+/// On disk, the leading variant index doubles as the node's format tag.  Any
+/// future change to the layout of `LeafData` or `IntData` must add a new
+/// variant rather than modify an existing one, and bump `NODE_FORMAT_MAX`.
 #[derive(Debug, Deserialize, PartialEq, Serialize)]
 #[serde(bound(deserialize = "K: DeserializeOwned"))]
 pub enum NodeData<A: Addr, K: Key, V: Value> {
@@ -1085,6 +1094,19 @@ pub enum NodeData<A: Addr, K: Key, V: Value> {
 }
 
 impl<A: Addr, K: Key, V: Value> NodeData<A, K, V> {
+    /// Deserialize a `NodeData` from its on-disk representation.
+    ///
+    /// Nodes written in a newer format fail with `EOPNOTSUPP`.
+    fn from_bytes(buf: &[u8]) -> Result<Self> {
+        let tag = buf.get(0..4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+            .ok_or(Error::EINTEGRITY)?;
+        if tag > NODE_FORMAT_MAX {
+            return Err(Error::EOPNOTSUPP);
+        }
+        bincode::deserialize(buf).map_err(|_| Error::EINTEGRITY)
+    }
+
     pub fn as_int(&self) -> &IntData<A, K, V> {
         if let NodeData::Int(int) = self {
             int
@@ -1358,10 +1380,14 @@ impl<A: Addr, K: Key, V: Value> NodeData<A, K, V> {
 }
 
 impl<A: Addr, K: Key, V: Value> Cacheable for Arc<Node<A, K, V>> {
-    fn deserialize(dbs: DivBufShared) -> Self where Self: Sized {
+    fn class(&self) -> EntryClass {
+        EntryClass::Metadata
+    }
+
+    fn deserialize(dbs: DivBufShared) -> Result<Self> where Self: Sized {
         let db = dbs.try_const().unwrap();
-        let node_data: NodeData<A, K, V> = bincode::deserialize(&db[..]).unwrap();
-        Arc::new(Node(RwLock::new(node_data)))
+        let node_data = NodeData::<A, K, V>::from_bytes(&db[..])?;
+        Ok(Arc::new(Node(RwLock::new(node_data))))
     }
 
     fn eq(&self, o: &dyn Cacheable) -> bool {
@@ -1433,11 +1459,13 @@ impl<A: Addr, K: Key, V: Value> Cacheable for Arc<Node<A, K, V>> {
 }
 
 impl<A: Addr, K: Key, V: Value> CacheRef for Arc<Node<A, K, V>> {
-    fn deserialize(dbs: DivBufShared) -> Box<dyn Cacheable> where Self: Sized {
+    fn deserialize(dbs: DivBufShared) -> Result<Box<dyn Cacheable>>
+        where Self: Sized
+    {
         let db = dbs.try_const().unwrap();
-        let node_data: NodeData<A, K, V> = bincode::deserialize(&db[..]).unwrap();
+        let node_data = NodeData::<A, K, V>::from_bytes(&db[..])?;
         let node = Arc::new(Node(RwLock::new(node_data)));
-        Box::new(node)
+        Ok(Box::new(node))
     }
 
     fn serialize(&self) -> DivBuf {
@@ -1632,7 +1660,8 @@ fn deserialize_int() {
                         0xdead_beef);
     let drp1 = DRP::new(PBA::new(0, 256), Compression::Zstd(None),
                         16000, 8000, 0x1a7e_babe);
-    let node: Arc<Node<DRP, u32, u32>> = Cacheable::deserialize(serialized);
+    let node: Arc<Node<DRP, u32, u32>> =
+        Cacheable::deserialize(serialized).unwrap();
     let guard = node.0.try_read().unwrap();
     let int_data = guard.deref().as_int();
     assert_eq!(int_data.children.len(), 2);
@@ -1653,7 +1682,8 @@ fn deserialize_leaf() {
             1, 0, 0, 0, 200, 0, 0, 0,   // K=1, V=200
             99, 0, 0, 0, 80, 195, 0, 0  // K=99, V=50000
         ]);
-    let node: Arc<Node<DRP, u32, u32>> = Cacheable::deserialize(serialized);
+    let node: Arc<Node<DRP, u32, u32>> =
+        Cacheable::deserialize(serialized).unwrap();
     let guard = node.0.try_read().unwrap();
     let leaf_data = guard.deref().as_leaf();
     assert_eq!(leaf_data.items.len(), 3);
@@ -1662,6 +1692,40 @@ fn deserialize_leaf() {
     assert_eq!(leaf_data.items[&99], 50_000);
 }
 
+/// A node written by a future version of BFFFS should fail cleanly
+#[test]
+fn deserialize_newer_format() {
+    let serialized = DivBufShared::from(vec![
+        2u8, 0, 0, 0, // enum variant 2, which doesn't exist yet
+        0, 0, 0, 0, 0, 0, 0, 0,
+    ]);
+    let r: Result<Arc<Node<DRP, u32, u32>>> =
+        Cacheable::deserialize(serialized);
+    assert_eq!(Error::EOPNOTSUPP, r.unwrap_err());
+}
+
+/// The same goes for a `CacheRef`
+#[test]
+fn deserialize_newer_format_cacheref() {
+    let serialized = DivBufShared::from(vec![
+        2u8, 0, 0, 0, // enum variant 2, which doesn't exist yet
+        0, 0, 0, 0, 0, 0, 0, 0,
+    ]);
+    let r = <Arc<Node<DRP, u32, u32>> as CacheRef>::deserialize(serialized);
+    assert_eq!(Some(Error::EOPNOTSUPP), r.err());
+}
+
+#[test]
+fn deserialize_truncated() {
+    let serialized = DivBufShared::from(vec![
+        0u8, 0, 0, 0, // enum variant 0 for LeafNode
+        3, 0, 0, 0, 0, 0, 0, 0,     // 3 elements in the map, but none follow
+    ]);
+    let r: Result<Arc<Node<DRP, u32, u32>>> =
+        Cacheable::deserialize(serialized);
+    assert_eq!(Error::EINTEGRITY, r.unwrap_err());
+}
+
 #[test]
 fn intelem_typical_size() {
     let pba = PBA::new(0, 1);
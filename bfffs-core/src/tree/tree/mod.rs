@@ -44,12 +44,30 @@ use std::{
     rc::Rc,
     sync::Arc,
 };
-use super::{Addr, CreditRequirements, InnerOnDisk, IntData, IntElem, Key, Limits, Node, NodeData, NodeId, TreeOnDisk, TreePtr, TreeReadGuard, TreeWriteGuard, Value};
+use super::{Addr, CreditRequirements, DumpFormat, InnerOnDisk, IntData, IntElem, Key, Limits, Node, NodeData, NodeId, TreeOnDisk, TreePtr, TreeReadGuard, TreeWriteGuard, Value};
 use tracing::instrument;
 use tracing_futures::Instrument;
 
 #[cfg(test)] mod tests;
 
+/// Could an Int Node's child, whose keys begin at `key` and end before `next`,
+/// contain any keys within `range`?
+fn child_in_range<K>(range: &(Bound<K>, Bound<K>), key: &K, next: Option<&K>)
+    -> bool
+    where K: Ord
+{
+    let after_start = match (&range.0, next) {
+        (Bound::Included(s) | Bound::Excluded(s), Some(n)) => n > s,
+        _ => true
+    };
+    let before_end = match &range.1 {
+        Bound::Included(e) => key <= e,
+        Bound::Excluded(e) => key < e,
+        Bound::Unbounded => true
+    };
+    after_start && before_end
+}
+
 /// Are there any elements in common between the two Ranges?
 #[allow(clippy::if_same_then_else)]
 #[allow(clippy::needless_bool)]
@@ -369,6 +387,51 @@ struct TreeRoot<A: Addr, K: Key, V: Value> {
     elem: IntElem<A, K, V>,
 }
 
+/// A child pointer within a `DumpRecord`
+#[derive(Serialize)]
+struct DumpChild<A: Addr, K: Key> {
+    key: K,
+    txgs: Range<TxgT>,
+    /// On-disk address, or `None` if the child is dirty
+    addr: Option<A>,
+}
+
+/// The contents of a Node within a `DumpRecord`
+#[derive(Serialize)]
+enum DumpNode<A: Addr, K: Key, V: Value> {
+    Int(Vec<DumpChild<A, K>>),
+    // Items are a list of pairs rather than a map, because JSON only allows
+    // string keys.
+    Leaf(Vec<(K, V)>),
+}
+
+/// A single Node, as printed by `Tree::dump_nodes`
+#[derive(Serialize)]
+struct DumpRecord<A: Addr, K: Key, V: Value> {
+    /// On-disk address, or `None` if the Node is dirty
+    addr: Option<A>,
+    /// 0 for Leaf Nodes
+    height: u8,
+    node: DumpNode<A, K, V>,
+}
+
+impl<A: Addr, K: Key, V: Value> DumpRecord<A, K, V> {
+    fn write(&self, f: &mut dyn io::Write, format: DumpFormat) -> Result<()> {
+        match format {
+            DumpFormat::Json => {
+                serde_json::to_writer(&mut *f, self)
+                    .map_err(|_| Error::EIO)?;
+                io::Write::write_all(f, b"\n")?;
+            },
+            DumpFormat::Yaml => {
+                serde_yaml::to_writer(&mut *f, self)
+                    .map_err(|_| Error::EIO)?;
+            }
+        }
+        Ok(())
+    }
+}
+
 /// A version of `Inner` that can be represented as a String.
 #[derive(Debug, Deserialize, PartialEq, Serialize)]
 #[serde(bound(deserialize = "K: DeserializeOwned"))]
@@ -649,6 +712,184 @@ impl<A, D, K, V> Tree<A, D, K, V>
         }).boxed()
     }
 
+    /// Merge under-filled sibling nodes whose keys lie within `range`.
+    ///
+    /// `range_delete` only fixes nodes that actually underflow, so a large
+    /// deletion can leave long runs of nodes that are barely above the minimum
+    /// fanout, which makes later lookups and flushes more expensive.  This pass
+    /// walks the affected part of the Tree and merges adjacent siblings
+    /// wherever their combined contents fit in a single node.
+    ///
+    /// # Parameters
+    ///
+    /// - `credit`: Writeback credit.  Clean leaf nodes will only be merged as
+    ///             long as there is enough credit to dirty them.  Any excess
+    ///             will be repaid.
+    ///
+    /// # Returns
+    ///
+    /// The number of nodes that were coalesced into their siblings.
+    pub async fn compact<R, T>(
+        self: Arc<Self>,
+        range: R,
+        txg: TxgT,
+        credit: Credit
+    ) -> Result<usize>
+        where K: Borrow<T>,
+              R: Debug + Clone + RangeBounds<T> + Send + 'static,
+              T: Ord + Clone + 'static + Debug
+    {
+        if range.is_empty() {
+            self.dml.repay(credit);
+            return Ok(0);
+        }
+        let guard = self.write().await;
+        let height = guard.height;
+        let (mut tree_guard, root_guard, credit) =
+            Tree::xlock_root(&self.dml, guard, txg, credit).await?;
+        let (_, merged, mut credit) = Tree::compact_r(self.clone(), height - 1,
+            root_guard, range, txg, credit).await?;
+        // Merging may have left the root with a single child
+        loop {
+            let (tree_guard2, root_guard, credit2) =
+                Tree::xlock_and_merge_root(self.dml.clone(), tree_guard, txg,
+                                           credit).await?;
+            if root_guard.is_leaf() || root_guard.as_int().nchildren() > 1 {
+                self.dml.repay(credit2);
+                break Ok(merged);
+            }
+            tree_guard = tree_guard2;
+            credit = credit2;
+        }
+    }
+
+    /// Subroutine of `compact`.  Returns the indices of the first and last
+    /// children of `guard` that may contain keys within `range`.
+    fn compact_bounds<R, T>(guard: &TreeWriteGuard<A, K, V>, range: &R)
+        -> (usize, usize)
+        where K: Borrow<T>,
+              R: RangeBounds<T>,
+              T: Ord
+    {
+        let int = guard.as_int();
+        let first = match range.start_bound() {
+            Bound::Included(t) | Bound::Excluded(t) => int.position(t),
+            Bound::Unbounded => 0
+        };
+        let last = match range.end_bound() {
+            Bound::Included(t) | Bound::Excluded(t) => int.position(t),
+            Bound::Unbounded => int.nchildren() - 1
+        };
+        (first, last)
+    }
+
+    /// Subroutine of `compact`.  Compacts the subtree rooted at `guard`,
+    /// returning the guard, the number of nodes coalesced, and any leftover
+    /// credit.
+    ///
+    /// - `height`: The height of `guard`, where leaves are 0
+    fn compact_r<R, T>(
+        self: Arc<Self>,
+        height: u8,
+        guard: TreeWriteGuard<A, K, V>,
+        range: R,
+        txg: TxgT,
+        credit: Credit
+    ) -> Pin<Box<dyn Future<Output=Result<
+            (TreeWriteGuard<A, K, V>, usize, Credit)>> + Send>>
+        where K: Borrow<T>,
+              R: Debug + Clone + RangeBounds<T> + Send + 'static,
+              T: Ord + Clone + 'static + Debug
+    {
+        if height == 0 {
+            return future::ok((guard, 0, credit)).boxed();
+        }
+        async move {
+            let mut guard = guard;
+            let mut credit = credit;
+            let mut merged = 0;
+
+            // First, compact the children's own children.  That may leave
+            // some children underflowing, so fix them as we go.
+            if height > 1 {
+                let (mut i, mut last) = Tree::compact_bounds(&guard, &range);
+                while i <= last {
+                    let (mut g, child, c) = guard.xlock(&self.dml, i, txg,
+                                                        credit).await?;
+                    let (child, n, c) = Tree::compact_r(self.clone(),
+                        height - 1, child, range.clone(), txg, c).await?;
+                    g.as_int_mut().children[i].txgs.start =
+                        child.start_txg(txg);
+                    merged += n;
+                    if child.underflow(&self.limits) && g.len() > 1 {
+                        let (g, before, after, c) = Tree::fix_int(&self, g, i,
+                            child, txg, c).await?;
+                        merged += (before + after) as usize;
+                        if before > 0 {
+                            // Merged into the left sibling.  Our right sibling
+                            // has taken our place.
+                            last -= 1;
+                        } else if after > 0 {
+                            // Absorbed the right sibling, whose children
+                            // still need compacting.
+                            if i < last {
+                                last -= 1;
+                            }
+                        } else {
+                            // Stole some keys
+                            i += 1;
+                        }
+                        guard = g;
+                        credit = c;
+                    } else {
+                        i += 1;
+                        guard = g;
+                        credit = c;
+                    }
+                }
+            }
+
+            // Then, merge adjacent children wherever they'll fit.  Check their
+            // sizes with shared locks first, so as not to dirty clean nodes
+            // that won't be merged.
+            let (mut i, mut last) = Tree::compact_bounds(&guard, &range);
+            while i < last {
+                let (mergeable, need) = {
+                    let children = &guard.as_int().children;
+                    let left = children[i].rlock(&self.dml).await?;
+                    let right = children[i + 1].rlock(&self.dml).await?;
+                    // Clean leaves must be accredited when they're dirtied
+                    let pairs = [(&children[i], &left),
+                                 (&children[i + 1], &right)];
+                    let need = pairs.iter()
+                        .filter(|(elem, g)| {
+                            K::USES_CREDIT && elem.ptr.is_addr() && g.is_leaf()
+                        }).map(|(_, g)| g.as_leaf().wb_space())
+                        .sum::<usize>();
+                    (left.can_merge(&right, &self.limits), need)
+                };
+                if mergeable && credit >= need {
+                    let (g, mut left, c) = guard.xlock(&self.dml, i, txg,
+                                                       credit).await?;
+                    let (mut g, right, c) = g.xlock(&self.dml, i + 1, txg, c)
+                        .await?;
+                    left.merge(right);
+                    let children = &mut g.as_int_mut().children;
+                    children[i].txgs.start = left.start_txg(txg);
+                    drop(left);
+                    children.remove(i + 1);
+                    merged += 1;
+                    last -= 1;
+                    guard = g;
+                    credit = c;
+                } else {
+                    i += 1;
+                }
+            }
+            Ok((guard, merged, credit))
+        }.boxed()
+    }
+
     /// Create a new tree.  `sequentially_optimized` controls whether some
     /// internal operations will assume a mostly-sequential or mostly-random
     /// write pattern.  `leaf_xratio` and `int_xratio` are
@@ -825,6 +1066,71 @@ impl<A, D, K, V> Tree<A, D, K, V>
         Box::pin(fut) as Pin<Box<_>>
     }
 
+    /// Dump the Tree's Nodes one at a time, in depth-first order.
+    ///
+    /// Unlike [`dump`](Self::dump), this only keeps a single path from root to
+    /// leaf in memory at any one time, so it's suitable for huge Trees.  Each
+    /// Node is printed as a separate record, either a line of JSON or a YAML
+    /// document.  Only Nodes that may contain keys within `range` are printed,
+    /// and from Leaf Nodes only the items within `range`.
+    pub async fn dump_nodes(&self, f: &mut dyn io::Write, format: DumpFormat,
+                            range: (Bound<K>, Bound<K>)) -> Result<()>
+    {
+        let tree_guard = self.read().await;
+        let height = tree_guard.height;
+        let addr = tree_guard.elem.ptr.is_addr()
+            .then(|| *tree_guard.elem.ptr.as_addr());
+        let guard = tree_guard.elem.rlock(&self.dml).await?;
+        Tree::dump_nodes_r(&self.dml, f, format, &range, height - 1, addr,
+                           guard).await
+    }
+
+    fn dump_nodes_r<'a>(
+        dml: &'a Arc<D>,
+        f: &'a mut dyn io::Write,
+        format: DumpFormat,
+        range: &'a (Bound<K>, Bound<K>),
+        height: u8,
+        addr: Option<A>,
+        node: TreeReadGuard<A, K, V>
+    ) -> Pin<Box<dyn Future<Output=Result<()>> + 'a>>
+    {
+        async move {
+            let contents = match *node {
+                NodeData::Int(ref int) => {
+                    let children = int.children.iter()
+                        .map(|child| DumpChild {
+                            key: child.key,
+                            txgs: child.txgs.clone(),
+                            addr: child.ptr.is_addr()
+                                .then(|| *child.ptr.as_addr())
+                        }).collect::<Vec<_>>();
+                    DumpNode::Int(children)
+                },
+                NodeData::Leaf(ref leaf) => {
+                    let (items, _) = leaf.range(range.clone());
+                    DumpNode::Leaf(items.into())
+                }
+            };
+            DumpRecord{addr, height, node: contents}.write(f, format)?;
+            if let NodeData::Int(ref int) = *node {
+                let citer = int.children.iter().enumerate();
+                for (i, child) in citer {
+                    let next = int.children.get(i + 1).map(|c| &c.key);
+                    if !child_in_range(range, &child.key, next) {
+                        continue;
+                    }
+                    let caddr = child.ptr.is_addr()
+                        .then(|| *child.ptr.as_addr());
+                    let cguard = child.rlock(dml).await?;
+                    Tree::dump_nodes_r(dml, &mut *f, format, range, height - 1,
+                                       caddr, cguard).await?;
+                }
+            }
+            Ok(())
+        }.boxed_local()
+    }
+
     /// Fix an Int node in danger of being underfull, returning the parent guard
     /// back to the caller
     #[allow(clippy::collapsible_if, clippy::collapsible_else_if)]
@@ -1131,6 +1437,78 @@ impl<A, D, K, V> Tree<A, D, K, V>
         .boxed()
     }
 
+    /// Lookup the values of many keys at once.
+    ///
+    /// Equivalent to a [`get`](Tree::get) for each key, but every node is
+    /// locked and fetched only once, no matter how many of the keys lie
+    /// beneath it.  The results are returned in the same order as `keys`.
+    #[instrument(skip(self, keys))]
+    pub fn get_multi(&self, keys: Vec<K>)
+        -> impl Future<Output=Result<Vec<Option<V>>>>
+    {
+        let dml2 = self.dml.clone();
+        let mut sorted = keys.into_iter()
+            .enumerate()
+            .map(|(i, k)| (k, i))
+            .collect::<Vec<_>>();
+        sorted.sort_unstable();
+        self.read()
+            .then(move |tree_guard| {
+                tree_guard.elem.rlock(&dml2)
+                     .and_then(move |guard| {
+                         drop(tree_guard);
+                         Tree::get_multi_r(dml2, guard, sorted)
+                     })
+            }).map_ok(|mut found| {
+                found.sort_unstable_by_key(|(i, _)| *i);
+                found.into_iter().map(|(_, v)| v).collect()
+            }).in_current_span()
+    }
+
+    /// Lookup the values of several keys in a node, which must already be
+    /// locked.  `keys` must be sorted, and each is paired with its index in
+    /// the caller's original list.  Results are returned unsorted.
+    fn get_multi_r(dml: Arc<D>, node: TreeReadGuard<A, K, V>,
+                   keys: Vec<(K, usize)>)
+        -> Pin<Box<dyn Future<Output=Result<Vec<(usize, Option<V>)>>> + Send>>
+    {
+        // Partition the keys among the children that contain them
+        let mut groups: Vec<(usize, Vec<(K, usize)>)> = Vec::new();
+        let lock_futs = match *node {
+            NodeData::Leaf(ref leaf) => {
+                let r = keys.into_iter()
+                    .map(|(k, i)| (i, leaf.get(&k)))
+                    .collect::<Vec<_>>();
+                return future::ok(r).boxed();
+            },
+            NodeData::Int(ref int) => {
+                for (k, i) in keys.into_iter() {
+                    let idx = int.position(&k);
+                    match groups.last_mut() {
+                        Some((last, group)) if *last == idx =>
+                            group.push((k, i)),
+                        _ => groups.push((idx, vec![(k, i)]))
+                    }
+                }
+                groups.iter()
+                    .map(|(idx, _)| int.children[*idx].rlock(&dml))
+                    .collect::<Vec<_>>()
+            }
+        };
+        async move {
+            let children = future::try_join_all(lock_futs).await?;
+            drop(node);
+            children.into_iter()
+                .zip(groups)
+                .map(|(child, (_, group))| {
+                    Tree::get_multi_r(dml.clone(), child, group)
+                }).collect::<FuturesUnordered<_>>()
+                .try_concat()
+                .await
+        }.in_current_span()
+        .boxed()
+    }
+
     /// Private helper for `RangeQuery::poll_next`.  Returns a subset of the
     /// total results, consisting of all matching (K,V) pairs within a single
     /// Leaf Node, plus an optional Bound for the next iteration of the search.
@@ -2165,6 +2543,7 @@ impl<A, D, K, V> Tree<A, D, K, V>
         .map(|root_guard| {
             let iod = InnerOnDisk{
                 height: root_guard.height,
+                version: super::TREE_VERSION,
                 _reserved: Default::default(),
                 limits: self.limits,
                 root: *root_guard.elem.ptr.as_addr(),
@@ -2174,6 +2553,14 @@ impl<A, D, K, V> Tree<A, D, K, V>
         }).or(Err(Error::EDEADLK))
     }
 
+    /// Read the Tree's root node, to verify that it is accessible.
+    ///
+    /// Nothing else in the Tree is read.
+    pub async fn verify_root(&self) -> Result<()> {
+        let tree_guard = self.read().await;
+        tree_guard.elem.rlock(&self.dml).await.map(drop)
+    }
+
     /// Lock the Tree for writing
     fn write(&self) -> impl Future<Output=RwLockWriteGuard<TreeRoot<A, K, V>>>
     {
@@ -2477,6 +2864,12 @@ impl<D, K, V> Tree<ddml::DRP, D, K, V>
     fn rewrite_node(self: Arc<Self>, node: NodeId<K>, txg: TxgT)
         -> impl Future<Output=Result<()>> + Send
     {
+        // Compress the rewritten node just like a freshly flushed one
+        let compressor = if node.height == 0 {
+            self.leaf_compressor
+        } else {
+            self.int_compressor
+        };
         self.write()
         .then(move |mut guard| {
             let h = guard.height;
@@ -2492,7 +2885,7 @@ impl<D, K, V> Tree<ddml::DRP, D, K, V>
                                      Arc<Node<ddml::DRP, K, V>>>(
                                         guard.elem.ptr.as_addr(), txg)
                     .and_then(move |arc| {
-                        dml2.put(*arc, Compression::None, txg)
+                        dml2.put(*arc, compressor, txg)
                     }).map_ok(move |addr| {
                         let new = TreePtr::Addr(addr);
                         guard.elem.ptr = new;
@@ -2505,7 +2898,7 @@ impl<D, K, V> Tree<ddml::DRP, D, K, V>
                 let fut = Tree::xlock_root(&dml2, guard, txg, credit)
                      .and_then(move |(_root_guard, child_guard, _credit)| {
                          Tree::rewrite_node_r(dml2, child_guard, h - 1, node,
-                                              txg)
+                                              compressor, txg)
                      });
                 fut.boxed()
             }
@@ -2513,7 +2906,8 @@ impl<D, K, V> Tree<ddml::DRP, D, K, V>
     }
 
     fn rewrite_node_r(dml: Arc<D>, mut guard: TreeWriteGuard<ddml::DRP, K, V>,
-                      height: u8, node: NodeId<K>, txg: TxgT)
+                      height: u8, node: NodeId<K>, compressor: Compression,
+                      txg: TxgT)
         -> Pin<Box<dyn Future<Output=Result<()>> + Send>>
     {
         debug_assert!(height > 0);
@@ -2535,7 +2929,7 @@ impl<D, K, V> Tree<ddml::DRP, D, K, V>
                             assert!(node.key <= *guard.key());
                         }   // LCOV_EXCL_LINE   grcov false negative
                     }
-                    dml2.put(*arc, Compression::None, txg)
+                    dml2.put(*arc, compressor, txg)
                 }).map_ok(move |addr| {
                     let new = TreePtr::Addr(addr);
                     guard.as_int_mut().children[child_idx].ptr = new;
@@ -2561,7 +2955,8 @@ impl<D, K, V> Tree<ddml::DRP, D, K, V>
             guard.xlock(&dml, child_idx, txg, credit)
                 .and_then(move |(parent_guard, child_guard, _credit)| {
                     drop(parent_guard);
-                    Tree::rewrite_node_r(dml, child_guard, height - 1, node, txg)
+                    Tree::rewrite_node_r(dml, child_guard, height - 1, node,
+                                         compressor, txg)
                 }).boxed()
         }
     }
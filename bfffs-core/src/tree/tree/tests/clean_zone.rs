@@ -90,6 +90,8 @@ fn basic() {
         .times(3)
         .with(always(), always(), eq(TxgT::from(42)))
         .returning(move |_cacheable, compression, _txg| {
+            // Rewritten nodes should be compressed like any others
+            assert!(matches!(compression, Compression::LZ4(Some(_))));
             let lba = next_lba.fetch_add(1, Ordering::Relaxed);
             let drp = DRP::new(PBA{cluster: 1, lba}, compression, 0, 0, 0);
             Box::pin(future::ok(drp))
@@ -371,9 +373,11 @@ fn dirty_root() {
         .return_once(move |_, _| {
             Box::pin(future::ok(Box::new(inr_c)))
         });
+    let int_ts = IntElem::<DRP, u32, f32>::TYPICAL_SIZE as u8;
+    let int_compressor = Compression::LZ4(NonZeroU8::new(int_ts));
     mock.expect_put::<T>()
         .once()
-        .with(always(), always(), eq(TxgT::from(42)))
+        .with(always(), eq(int_compressor), eq(TxgT::from(42)))
         .returning(move |_cacheable, _compression, _txg| {
             let drp = DRP::random(Compression::None, 1024);
             Box::pin(future::ok(drp))
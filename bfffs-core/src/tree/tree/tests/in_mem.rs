@@ -1537,6 +1537,275 @@ root:
 "#);
 }
 
+// Compacting should merge under-filled leaves, fix the Int nodes that
+// underflow as a result, and finally merge down the root.
+#[test]
+fn compact() {
+    let mock = mock_dml();
+    let dml = Arc::new(mock);
+    let tree = Arc::new(Tree::<u32, MockDML, u32, f32>::from_str(dml, false, r#"
+---
+limits:
+  min_int_fanout: 2
+  max_int_fanout: 5
+  min_leaf_fanout: 2
+  max_leaf_fanout: 5
+  _max_size: 4194304
+root:
+  height: 3
+  elem:
+    key: 0
+    txgs:
+      start: 41
+      end: 42
+    ptr:
+      Mem:
+        Int:
+          children:
+            - key: 1
+              txgs:
+                start: 41
+                end: 42
+              ptr:
+                Mem:
+                  Int:
+                    children:
+                      - key: 1
+                        txgs:
+                          start: 41
+                          end: 42
+                        ptr:
+                          Mem:
+                            Leaf:
+                              credit: 32
+                              items:
+                                1: 1.0
+                                2: 2.0
+                      - key: 5
+                        txgs:
+                          start: 41
+                          end: 42
+                        ptr:
+                          Mem:
+                            Leaf:
+                              credit: 32
+                              items:
+                                5: 5.0
+                                6: 6.0
+                      - key: 10
+                        txgs:
+                          start: 41
+                          end: 42
+                        ptr:
+                          Mem:
+                            Leaf:
+                              credit: 32
+                              items:
+                                10: 10.0
+                                11: 11.0
+            - key: 15
+              txgs:
+                start: 41
+                end: 42
+              ptr:
+                Mem:
+                  Int:
+                    children:
+                      - key: 15
+                        txgs:
+                          start: 41
+                          end: 42
+                        ptr:
+                          Mem:
+                            Leaf:
+                              credit: 32
+                              items:
+                                15: 15.0
+                                16: 16.0
+                      - key: 20
+                        txgs:
+                          start: 41
+                          end: 42
+                        ptr:
+                          Mem:
+                            Leaf:
+                              credit: 32
+                              items:
+                                20: 20.0
+                                25: 25.0
+  "#));
+    let r = tree.clone()
+        .compact(.., TxgT::from(42), Credit::forge(80))
+        .now_or_never().unwrap();
+    // Two pairs of leaves, and then the two Int nodes
+    assert_eq!(r, Ok(3));
+    assert_eq!(format!("{}", &tree),
+r#"---
+limits:
+  min_int_fanout: 2
+  max_int_fanout: 5
+  min_leaf_fanout: 2
+  max_leaf_fanout: 5
+  _max_size: 4194304
+root:
+  height: 2
+  elem:
+    key: 0
+    txgs:
+      start: 41
+      end: 43
+    ptr:
+      Mem:
+        Int:
+          children:
+            - key: 1
+              txgs:
+                start: 42
+                end: 43
+              ptr:
+                Mem:
+                  Leaf:
+                    credit: 64
+                    items:
+                      1: 1.0
+                      2: 2.0
+                      5: 5.0
+                      6: 6.0
+            - key: 10
+              txgs:
+                start: 41
+                end: 42
+              ptr:
+                Mem:
+                  Leaf:
+                    credit: 32
+                    items:
+                      10: 10.0
+                      11: 11.0
+            - key: 15
+              txgs:
+                start: 42
+                end: 43
+              ptr:
+                Mem:
+                  Leaf:
+                    credit: 64
+                    items:
+                      15: 15.0
+                      16: 16.0
+                      20: 20.0
+                      25: 25.0
+"#);
+}
+
+// Nodes outside of the requested range should be left alone
+#[test]
+fn compact_range() {
+    let mock = mock_dml();
+    let dml = Arc::new(mock);
+    let tree = Arc::new(Tree::<u32, MockDML, u32, f32>::from_str(dml, false, r#"
+---
+limits:
+  min_int_fanout: 2
+  max_int_fanout: 5
+  min_leaf_fanout: 2
+  max_leaf_fanout: 5
+  _max_size: 4194304
+root:
+  height: 2
+  elem:
+    key: 0
+    txgs:
+      start: 41
+      end: 42
+    ptr:
+      Mem:
+        Int:
+          children:
+            - key: 1
+              txgs:
+                start: 41
+                end: 42
+              ptr:
+                Mem:
+                  Leaf:
+                    credit: 32
+                    items:
+                      1: 1.0
+                      2: 2.0
+            - key: 5
+              txgs:
+                start: 41
+                end: 42
+              ptr:
+                Mem:
+                  Leaf:
+                    credit: 32
+                    items:
+                      5: 5.0
+                      6: 6.0
+            - key: 10
+              txgs:
+                start: 41
+                end: 42
+              ptr:
+                Mem:
+                  Leaf:
+                    credit: 32
+                    items:
+                      10: 10.0
+                      11: 11.0
+  "#));
+    let r = tree.clone()
+        .compact(6..12, TxgT::from(42), Credit::forge(80))
+        .now_or_never().unwrap();
+    assert_eq!(r, Ok(1));
+    assert_eq!(format!("{}", &tree),
+r#"---
+limits:
+  min_int_fanout: 2
+  max_int_fanout: 5
+  min_leaf_fanout: 2
+  max_leaf_fanout: 5
+  _max_size: 4194304
+root:
+  height: 2
+  elem:
+    key: 0
+    txgs:
+      start: 41
+      end: 43
+    ptr:
+      Mem:
+        Int:
+          children:
+            - key: 1
+              txgs:
+                start: 41
+                end: 42
+              ptr:
+                Mem:
+                  Leaf:
+                    credit: 32
+                    items:
+                      1: 1.0
+                      2: 2.0
+            - key: 5
+              txgs:
+                start: 42
+                end: 43
+              ptr:
+                Mem:
+                  Leaf:
+                    credit: 64
+                    items:
+                      5: 5.0
+                      6: 6.0
+                      10: 10.0
+                      11: 11.0
+"#);
+}
+
 #[test]
 fn get() {
     let mock = mock_dml();
@@ -1600,6 +1869,66 @@ root:
     assert_eq!(r, Ok(Some(3.0)))
 }
 
+/// get_multi should return results in the caller's order, regardless of
+/// which leaves they come from
+#[test]
+fn get_multi() {
+    let dml = Arc::new(mock_dml());
+    let tree = Arc::new(Tree::<u32, MockDML, u32, f32>::from_str(dml, false, r#"
+---
+limits:
+  min_int_fanout: 2
+  max_int_fanout: 5
+  min_leaf_fanout: 2
+  max_leaf_fanout: 5
+  _max_size: 4194304
+root:
+  height: 2
+  elem:
+    key: 0
+    txgs:
+      start: 0
+      end: 42
+    ptr:
+      Mem:
+        Int:
+          children:
+            - key: 0
+              txgs:
+                start: 0
+                end: 42
+              ptr:
+                Mem:
+                  Leaf:
+                    credit: 32
+                    items:
+                      0: 0.0
+                      1: 1.0
+            - key: 3
+              txgs:
+                start: 0
+                end: 42
+              ptr:
+                Mem:
+                  Leaf:
+                    credit: 32
+                    items:
+                      3: 3.0
+                      4: 4.0
+  "#));
+    let r = tree.get_multi(vec![4, 2, 0, 4, 1]).now_or_never().unwrap();
+    assert_eq!(r, Ok(vec![Some(4.0), None, Some(0.0), Some(4.0), Some(1.0)]));
+}
+
+#[test]
+fn get_multi_empty() {
+    let dml = Arc::new(mock_dml());
+    let limits = Limits::new(2, 5, 2, 5);
+    let tree: Tree<u32, MockDML, u32, f32> = Tree::new(dml, limits, false, None);
+    let r = tree.get_multi(Vec::new()).now_or_never().unwrap();
+    assert_eq!(r, Ok(Vec::new()));
+}
+
 #[test]
 fn get_nonexistent() {
     let mock = mock_dml();
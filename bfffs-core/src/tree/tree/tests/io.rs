@@ -169,6 +169,93 @@ root:
     assert_eq!(vec![addrl], addrs);
 }
 
+/// Tree with two on-disk leaves, for the compact tests
+fn compact_tree(dml: Arc<MockDML>) -> Arc<Tree<u32, MockDML, u32, f32>> {
+    Arc::new(Tree::<u32, MockDML, u32, f32>::from_str(dml, false, r#"
+---
+limits:
+  min_int_fanout: 2
+  max_int_fanout: 5
+  min_leaf_fanout: 2
+  max_leaf_fanout: 5
+  _max_size: 4194304
+root:
+  height: 2
+  elem:
+    key: 0
+    txgs:
+      start: 41
+      end: 42
+    ptr:
+      Mem:
+        Int:
+          children:
+            - key: 0
+              txgs:
+                start: 41
+                end: 42
+              ptr:
+                Addr: 0
+            - key: 10
+              txgs:
+                start: 41
+                end: 42
+              ptr:
+                Addr: 1
+  "#))
+}
+
+/// Compacting shouldn't dirty on-disk leaves that can't be merged
+#[test]
+fn compact_clean() {
+    let mut mock = MockDML::new();
+    let mut ld0 = LeafData::default();
+    ld0.items.insert(0, 0.0);
+    ld0.items.insert(1, 1.0);
+    ld0.items.insert(2, 2.0);
+    expect_get(&mut mock, 0, Arc::new(Node::new(NodeData::Leaf(ld0))));
+    let mut ld1 = LeafData::default();
+    ld1.items.insert(10, 10.0);
+    ld1.items.insert(11, 11.0);
+    ld1.items.insert(12, 12.0);
+    expect_get(&mut mock, 1, Arc::new(Node::new(NodeData::Leaf(ld1))));
+    // All credit should be returned
+    mock.expect_repay()
+        .once()
+        .withf(|credit| *credit == 80)
+        .returning(mem::forget);
+
+    let tree = compact_tree(Arc::new(mock));
+    let r = tree.compact(.., TxgT::from(42), Credit::forge(80))
+        .now_or_never()
+        .unwrap();
+    assert_eq!(r, Ok(0));
+}
+
+/// Compacting shouldn't dirty on-disk leaves without enough credit to do so
+#[test]
+fn compact_insufficient_credit() {
+    let mut mock = MockDML::new();
+    let mut ld0 = LeafData::default();
+    ld0.items.insert(0, 0.0);
+    ld0.items.insert(1, 1.0);
+    expect_get(&mut mock, 0, Arc::new(Node::new(NodeData::Leaf(ld0))));
+    let mut ld1 = LeafData::default();
+    ld1.items.insert(10, 10.0);
+    ld1.items.insert(11, 11.0);
+    expect_get(&mut mock, 1, Arc::new(Node::new(NodeData::Leaf(ld1))));
+    mock.expect_repay()
+        .once()
+        .withf(|credit| *credit == 8)
+        .returning(mem::forget);
+
+    let tree = compact_tree(Arc::new(mock));
+    let r = tree.compact(.., TxgT::from(42), Credit::forge(8))
+        .now_or_never()
+        .unwrap();
+    assert_eq!(r, Ok(0));
+}
+
 #[test]
 fn dump() {
     let mut mock = mock_dml();
@@ -416,6 +503,102 @@ root:
     assert_eq!(expected, OsStr::from_bytes(&out[..]));
 }
 
+/// Build a two-level Tree for the `dump_nodes` tests.  Only the Nodes at
+/// addresses in `reads` are expected to be read.
+fn dump_nodes_tree(reads: &[u32]) -> Tree<u32, MockDML, u32, f32> {
+    let mut mock = mock_dml();
+    let children = vec![
+        IntElem::new(0u32, TxgT::from(8)..TxgT::from(9), TreePtr::Addr(0)),
+        IntElem::new(2u32, TxgT::from(8)..TxgT::from(9), TreePtr::Addr(1)),
+    ];
+    let intnode = Arc::new(Node::new(NodeData::Int(IntData::new(children))));
+
+    let mut ld0 = LeafData::default();
+    ld0.items.insert(0, 0.0);
+    ld0.items.insert(1, 1.0);
+    let leafnode0 = Arc::new(Node::new(NodeData::Leaf(ld0)));
+
+    let mut ld1 = LeafData::default();
+    ld1.items.insert(2, 2.0);
+    ld1.items.insert(3, 3.0);
+    let leafnode1 = Arc::new(Node::new(NodeData::Leaf(ld1)));
+
+    for (addr, node) in [(2, intnode), (0, leafnode0), (1, leafnode1)] {
+        if reads.contains(&addr) {
+            expect_get(&mut mock, addr, node);
+        }
+    }
+
+    let dml = Arc::new(mock);
+    Tree::from_str(dml, false, r#"
+---
+limits:
+  min_int_fanout: 2
+  max_int_fanout: 5
+  min_leaf_fanout: 2
+  max_leaf_fanout: 5
+  _max_size: 4194304
+root:
+  height: 2
+  elem:
+    key: 0
+    txgs:
+      start: 8
+      end: 9
+    ptr:
+      Addr: 2
+"#)
+}
+
+#[test]
+fn dump_nodes_json() {
+    let tree = dump_nodes_tree(&[0, 1, 2]);
+    let expected = concat!(
+r#"{"addr":2,"height":1,"node":{"Int":[{"key":0,"txgs":{"start":8,"end":9},"addr":0},{"key":2,"txgs":{"start":8,"end":9},"addr":1}]}}"#, "\n",
+r#"{"addr":0,"height":0,"node":{"Leaf":[[0,0.0],[1,1.0]]}}"#, "\n",
+r#"{"addr":1,"height":0,"node":{"Leaf":[[2,2.0],[3,3.0]]}}"#, "\n",
+    );
+    let mut out = Vec::new();
+    tree.dump_nodes(&mut out, DumpFormat::Json,
+                    (Bound::Unbounded, Bound::Unbounded))
+        .now_or_never().unwrap().unwrap();
+    assert_eq!(expected, OsStr::from_bytes(&out[..]));
+}
+
+/// Nodes outside of the requested range should not even be read
+#[test]
+fn dump_nodes_range() {
+    let tree = dump_nodes_tree(&[1, 2]);
+    let expected = r#"---
+addr: 2
+height: 1
+node:
+  Int:
+    - key: 0
+      txgs:
+        start: 8
+        end: 9
+      addr: 0
+    - key: 2
+      txgs:
+        start: 8
+        end: 9
+      addr: 1
+---
+addr: 1
+height: 0
+node:
+  Leaf:
+    - - 3
+      - 3.0
+"#;
+    let mut out = Vec::new();
+    tree.dump_nodes(&mut out, DumpFormat::Yaml,
+                    (Bound::Included(3), Bound::Unbounded))
+        .now_or_never().unwrap().unwrap();
+    assert_eq!(expected, OsStr::from_bytes(&out[..]));
+}
+
 /// Dump a Tree, but get an I/O error reading part of it.
 #[test]
 fn dump_error() {
@@ -962,6 +1145,7 @@ fn open() {
     let tod = TreeOnDisk(
         InnerOnDisk {
             height: 1,
+            version: 0,
             _reserved: Default::default(),
             limits,
             root: root_drp,
@@ -1611,6 +1795,66 @@ root:
     assert_eq!(Ok(Some(200)), r);
 }
 
+/// get_multi should read each node only once, no matter how many keys it
+/// contains, and shouldn't read nodes that contain none of them.
+#[test]
+fn get_multi() {
+    let mut mock = mock_dml();
+    let mut ld0 = LeafData::default();
+    ld0.items.insert(0, 0.0);
+    ld0.items.insert(1, 1.0);
+    ld0.items.insert(2, 2.0);
+    let mut ld1 = LeafData::default();
+    ld1.items.insert(10, 10.0);
+    ld1.items.insert(11, 11.0);
+    ld1.items.insert(12, 12.0);
+    expect_get(&mut mock, 0, Arc::new(Node::new(NodeData::Leaf(ld0))));
+    expect_get(&mut mock, 1, Arc::new(Node::new(NodeData::Leaf(ld1))));
+    let dml = Arc::new(mock);
+    let tree: Tree<u32, MockDML, u32, f32> = Tree::from_str(dml, false, r#"
+---
+limits:
+  min_int_fanout: 2
+  max_int_fanout: 5
+  min_leaf_fanout: 2
+  max_leaf_fanout: 5
+  _max_size: 4194304
+root:
+  height: 2
+  elem:
+    key: 0
+    txgs:
+      start: 0
+      end: 42
+    ptr:
+      Mem:
+        Int:
+          children:
+            - key: 0
+              txgs:
+                start: 0
+                end: 42
+              ptr:
+                Addr: 0
+            - key: 10
+              txgs:
+                start: 0
+                end: 42
+              ptr:
+                Addr: 1
+            - key: 20
+              txgs:
+                start: 0
+                end: 42
+              ptr:
+                Addr: 2
+  "#);
+
+    let r = tree.get_multi(vec![12, 0, 2, 10, 11]).now_or_never().unwrap();
+    assert_eq!(r, Ok(vec![Some(12.0), Some(0.0), Some(2.0), Some(10.0),
+                          Some(11.0)]));
+}
+
 #[test]
 fn remove() {
     let mut mock = MockDML::new();
@@ -2141,6 +2385,7 @@ fn serialize_inner() {
     let expected = TreeOnDisk(
         InnerOnDisk {
             height: 1,
+            version: 0,
             _reserved: Default::default(),
             limits: Limits::new(2, 5, 2, 5),
             root: root_drp,
@@ -1,6 +1,7 @@
 #[cfg(test)] mod clean_zone;
 #[cfg(test)] mod in_mem;
 #[cfg(test)] mod io;
+#[cfg(test)] mod model;
 #[cfg(test)] mod txg;
 
 use crate::dml::MockDML;
@@ -0,0 +1,110 @@
+// vim: tw=80
+//! Model-based property tests for Trees
+//!
+//! Random sequences of operations are applied both to a real `Tree` and to a
+//! `BTreeMap`, which serves as the model.  After every operation the two must
+//! hold identical contents, and the `Tree` must satisfy its structural
+//! invariants.
+// LCOV_EXCL_START
+
+use crate::mem_dml::MemDml;
+use proptest::prelude::*;
+use std::ops::RangeFull;
+use super::*;
+
+/// Keys are drawn from a small space, so that removals and range deletions
+/// will frequently hit existing entries.
+const KEYSPACE: u32 = 256;
+
+#[derive(Clone, Debug)]
+enum Op {
+    Insert(u32, u32),
+    Remove(u32),
+    RangeDelete(u32, u32),
+    Range(u32, u32),
+    Flush
+}
+
+fn op() -> impl Strategy<Value=Op> {
+    let key = || 0..KEYSPACE;
+    let range = move || (key(), key()).prop_map(|(a, b)| (a.min(b), a.max(b)));
+    prop_oneof![
+        8 => (key(), any::<u32>()).prop_map(|(k, v)| Op::Insert(k, v)),
+        3 => key().prop_map(Op::Remove),
+        1 => range().prop_map(|(a, b)| Op::RangeDelete(a, b)),
+        2 => range().prop_map(|(a, b)| Op::Range(a, b)),
+        1 => Just(Op::Flush),
+    ]
+}
+
+type TreeT = Tree<u32, MemDml, u32, u32>;
+
+async fn contents(tree: &Arc<TreeT>) -> Vec<(u32, u32)> {
+    tree.range::<RangeFull, u32>(..)
+        .try_collect::<Vec<_>>()
+        .await
+        .unwrap()
+}
+
+async fn run(seq: bool, ops: Vec<Op>) {
+    let dml = Arc::new(MemDml::default());
+    let limits = Limits::new(2, 5, 2, 5);
+    let tree = Arc::new(TreeT::new(dml, limits, seq, None));
+    let reqs = tree.credit_requirements();
+    let mut model = BTreeMap::new();
+    let mut txg = TxgT::from(1);
+
+    for op in ops.into_iter() {
+        match op {
+            Op::Insert(k, v) => {
+                let credit = Credit::forge(reqs.insert);
+                let r = tree.clone().insert(k, v, txg, credit).await.unwrap();
+                assert_eq!(r, model.insert(k, v), "Insert({k}, {v})");
+            }
+            Op::Remove(k) => {
+                let credit = Credit::forge(reqs.remove);
+                let r = tree.clone().remove(k, txg, credit).await.unwrap();
+                assert_eq!(r, model.remove(&k), "Remove({k})");
+            }
+            Op::RangeDelete(a, b) => {
+                let credit = Credit::forge(reqs.range_delete);
+                tree.clone().range_delete(a..b, txg, credit).await.unwrap();
+                model.retain(|k, _| !(a..b).contains(k));
+            }
+            Op::Range(a, b) => {
+                let r = tree.range(a..b)
+                    .try_collect::<Vec<_>>()
+                    .await
+                    .unwrap();
+                let expected = model.range(a..b)
+                    .map(|(k, v)| (*k, *v))
+                    .collect::<Vec<_>>();
+                assert_eq!(r, expected, "Range({a}, {b})");
+            }
+            Op::Flush => {
+                tree.clone().flush(txg).await.unwrap();
+                assert!(!tree.is_dirty());
+                txg += 1;
+            }
+        }
+        // Check fanout bounds, key ordering, and TXG ranges
+        assert!(tree.clone().check().await.unwrap());
+        let expected = model.iter()
+            .map(|(k, v)| (*k, *v))
+            .collect::<Vec<_>>();
+        assert_eq!(contents(&tree).await, expected);
+    }
+}
+
+proptest! {
+    #[test]
+    fn model(seq in any::<bool>(),
+             ops in prop::collection::vec(op(), 1..256))
+    {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        rt.block_on(run(seq, ops));
+    }
+}
+// LCOV_EXCL_STOP
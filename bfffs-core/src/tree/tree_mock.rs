@@ -8,13 +8,13 @@ use crate::{
     tree::*,
     writeback::Credit
 };
-use futures::Future;
+use futures::{Future, Stream};
 use mockall::mock;
 use std::{
     borrow::Borrow,
     fmt::Debug,
     io,
-    ops::{Range, RangeBounds},
+    ops::{Bound, Range, RangeBounds},
     pin::Pin,
     sync::{Arc, Mutex},
 };
@@ -28,18 +28,34 @@ mock! {
               K: Key,
               V: Value
     {
+        pub fn addresses<R, T>(&self, txgs: R)
+            -> Pin<Box<dyn Stream<Item=A> + Send>>
+            where TxgT: Borrow<T>,
+                  R: Clone + RangeBounds<T> + Send + Sync + 'static,
+                  T: Ord + Clone + Send + 'static;
         pub async fn check(self: Arc<Self>) -> Result<bool>;
         pub async fn clean_zone(self: Arc<Self>, pbas: Range<PBA>,
                                 txgs: Range<TxgT>, txg: TxgT)
             -> Result<()>;
+        pub async fn compact<R, T>(self: Arc<Self>, range: R, txg: TxgT,
+            credit: Credit)
+            -> Result<usize>
+            where K: Borrow<T>,
+                  R: RangeBounds<T> + 'static,
+                  T: Ord + Clone + Send + 'static;
         pub fn create(dml: Arc<D>, seq: bool, lzratio: f32, izratio: f32)
             -> MockTree<A, D, K, V>;
         pub fn credit_requirements(&self) -> CreditRequirements;
         pub async fn dump<'a>(&self, f: &'a mut dyn io::Write)
             -> Result<()>;
+        pub async fn dump_nodes<'a>(&self, f: &'a mut dyn io::Write,
+            format: DumpFormat, range: (Bound<K>, Bound<K>))
+            -> Result<()>;
         pub async fn flush(self: Arc<Self>, txg: TxgT) -> Result<()>;
         pub fn get(&self, k: K)
             -> Pin<Box<dyn Future<Output=Result<Option<V>>> + Send>>;
+        pub fn get_multi(&self, keys: Vec<K>)
+            -> Pin<Box<dyn Future<Output=Result<Vec<Option<V>>>> + Send>>;
         pub async fn insert(self: Arc<Self>, k: K, v: V, txg: TxgT,
                             credit: Credit)
             -> Result<Option<V>>;
@@ -61,6 +77,7 @@ mock! {
         pub async fn remove(self: Arc<Self>, k: K, txg: TxgT, credit: Credit)
             -> Result<Option<V>>;
         pub fn serialize(&self) -> Result<TreeOnDisk<A>>;
+        pub async fn verify_root(&self) -> Result<()>;
     }
 }
 // LCOV_EXCL_STOP
@@ -309,13 +309,11 @@ impl<'de> Deserialize<'de> for Uuid {
     }
 }
 
-// LCOV_EXCL_START      only used by debugging code
 impl Display for Uuid {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         self.0.fmt(f)
     }
 }
-// LCOV_EXCL_STOP
 
 impl Serialize for Uuid {
     fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
@@ -1,6 +1,7 @@
 // vim: tw=80
 
 use crate::types::*;
+use serde_derive::{Deserialize, Serialize};
 use std::pin::Pin;
 
 /// Future representing an operation on a vdev.
@@ -9,6 +10,39 @@ pub type VdevFut = dyn futures::Future<Output = Result<()>> + Send + Sync;
 /// Boxed `VdevFut`
 pub type BoxVdevFut = Pin<Box<dyn futures::Future<Output = Result<()>> + Send + Sync>>;
 
+/// Future that reads a zone's write pointer from a vdev.  It resolves to
+/// `None` if the vdev's zones are only simulated.
+pub type BoxWritePointerFut =
+    Pin<Box<dyn futures::Future<Output = Result<Option<LbaT>>> + Send>>;
+
+/// Cumulative I/O error counts for a single leaf device
+///
+/// These are stored in the leaf's label, so they cover the device's entire
+/// lifetime rather than just the time since the pool was last imported.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ErrorCounts {
+    /// Read operations that failed
+    pub read: u64,
+    /// Write operations that failed
+    pub write: u64,
+    /// Reads that returned data which failed checksum verification
+    pub checksum: u64,
+}
+
+/// Status of a single leaf device, as reported by `bfffs pool status`
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct LeafStatus {
+    pub uuid: Uuid,
+    /// Lifetime I/O error counts
+    pub errors: ErrorCounts,
+    /// Is this a write-mostly mirror child?  If so, it will only be read from
+    /// when no other child can satisfy the read.
+    pub write_mostly: bool,
+    /// Has the device disappeared?  If so, it won't be used again until it's
+    /// brought back online.
+    pub removed: bool,
+}
+
 /// Vdev: Virtual Device
 ///
 /// This is directly analogous to ZFS Vdevs.  A vdev is a virtual block device
@@ -27,6 +61,14 @@ pub trait Vdev {
     /// `None` indicates that the LBA is unused.
     fn lba2zone(&self, lba: LbaT) -> Option<ZoneT>;
 
+    /// Return the maximum number of zones that the underlying device(s) can
+    /// keep open simultaneously, if they impose such a limit.
+    ///
+    /// Devices with simulated zones have no limit.
+    fn max_open_zones(&self) -> Option<u32> {
+        None
+    }
+
     /// Returns the "best" number of operations to queue to this `Vdev`.  A
     /// smaller number may result in inefficient use of resources, or even
     /// starvation.  A larger number won't hurt, but won't accrue any economies
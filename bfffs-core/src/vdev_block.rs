@@ -151,7 +151,7 @@ struct BlockOp {
     /// Used by the `VdevLeaf` to complete this future
     // Consider replacing with std::sync::Waker, which is smaller than oneshot
     // Sender and Receiver.
-    pub senders: Vec<oneshot::Sender<()>>
+    pub senders: Vec<oneshot::Sender<Result<()>>>
 }
 
 impl Eq for BlockOp {
@@ -371,51 +371,52 @@ impl BlockOp {
     //}
 
     pub fn erase_zone(start: LbaT, end: LbaT,
-                      sender: oneshot::Sender<()>) -> BlockOp {
+                      sender: oneshot::Sender<Result<()>>) -> BlockOp {
         BlockOp { lba: end, cmd: Cmd::EraseZone(start), senders: vec![sender] }
     }
 
     pub fn finish_zone(start: LbaT, end: LbaT,
-                       sender: oneshot::Sender<()>) -> BlockOp {
+                       sender: oneshot::Sender<Result<()>>) -> BlockOp {
         BlockOp { lba: end, cmd: Cmd::FinishZone(start), senders: vec![sender] }
     }
 
-    pub fn open_zone(lba: LbaT, sender: oneshot::Sender<()>) -> BlockOp {
+    pub fn open_zone(lba: LbaT,
+                     sender: oneshot::Sender<Result<()>>) -> BlockOp {
         BlockOp { lba, cmd: Cmd::OpenZone, senders: vec![sender] }
     }
 
     pub fn read_at(buf: IoVecMut, lba: LbaT,
-                   sender: oneshot::Sender<()>) -> BlockOp {
+                   sender: oneshot::Sender<Result<()>>) -> BlockOp {
         BlockOp { lba, cmd: Cmd::ReadAt(buf), senders: vec![sender]}
     }
 
     pub fn read_spacemap(buf: IoVecMut, lba: LbaT, idx: u32,
-                         sender: oneshot::Sender<()>) -> BlockOp
+                         sender: oneshot::Sender<Result<()>>) -> BlockOp
     {
         BlockOp { lba, cmd: Cmd::ReadSpacemap(buf, idx), senders: vec![sender]}
     }
 
     pub fn readv_at(bufs: SGListMut, lba: LbaT,
-                    sender: oneshot::Sender<()>) -> BlockOp {
+                    sender: oneshot::Sender<Result<()>>) -> BlockOp {
         BlockOp { lba, cmd: Cmd::ReadvAt(bufs), senders: vec![sender]}
     }
 
-    pub fn sync_all(sender: oneshot::Sender<()>) -> BlockOp {
+    pub fn sync_all(sender: oneshot::Sender<Result<()>>) -> BlockOp {
         BlockOp { lba: 0, cmd: Cmd::SyncAll, senders: vec![sender]}
     }
 
     pub fn write_at(buf: IoVec, lba: LbaT,
-                    sender: oneshot::Sender<()>) -> BlockOp {
+                    sender: oneshot::Sender<Result<()>>) -> BlockOp {
         BlockOp { lba, cmd: Cmd::WriteAt(buf), senders: vec![sender]}
     }
 
     pub fn write_label(labeller: LabelWriter,
-                       sender: oneshot::Sender<()>) -> BlockOp {
+                       sender: oneshot::Sender<Result<()>>) -> BlockOp {
         BlockOp { lba: 0, cmd: Cmd::WriteLabel(labeller), senders: vec![sender]}
     }
 
     pub fn write_spacemap(sglist: SGList, lba: LbaT, idx: u32, block: LbaT,
-                          sender: oneshot::Sender<()>) -> BlockOp
+                          sender: oneshot::Sender<Result<()>>) -> BlockOp
     {
         BlockOp{
             lba,
@@ -425,7 +426,7 @@ impl BlockOp {
     }
 
     pub fn writev_at(bufs: SGList, lba: LbaT,
-                     sender: oneshot::Sender<()>) -> BlockOp {
+                     sender: oneshot::Sender<Result<()>>) -> BlockOp {
         BlockOp { lba, cmd: Cmd::WritevAt(bufs), senders: vec![sender]}
     }
 }
@@ -433,7 +434,7 @@ impl BlockOp {
 struct Inner {
     /// A VdevLeaf future that got delayed by an EAGAIN error.  We hold the
     /// future around instead of spawning it into the reactor.
-    delayed: Option<(Vec<oneshot::Sender<()>>, Pin<Box<VdevFut>>)>,
+    delayed: Option<(Vec<oneshot::Sender<Result<()>>>, Pin<Box<VdevFut>>)>,
 
     /// Max commands that will be simultaneously queued to the VdevLeaf
     optimum_queue_depth: u32,
@@ -451,6 +452,11 @@ struct Inner {
     /// storage
     syncing: bool,
 
+    /// If true, then the underlying device has disappeared and its file
+    /// descriptor has been released.  Every operation will fail until it's
+    /// reopened.
+    removed: bool,
+
     // Pending operations are stored in a pair of priority queues.  They _could_
     // be stored in a single queue, _if_ the priority queue's comparison
     // function were allowed to be stateful, as in C++'s STL.  However, Rust's
@@ -473,6 +479,27 @@ struct Inner {
 }
 
 impl Inner {
+    /// Deliver an operation's result to everybody waiting for it.
+    ///
+    /// If the error shows that the device has disappeared, then first mark it
+    /// as removed and release its file descriptor, so the upper layers will
+    /// know not to expect it back until it's reopened.
+    fn complete(&mut self, senders: Vec<oneshot::Sender<Result<()>>>,
+                r: Result<()>)
+    {
+        if let Err(error @ (Error::ENXIO | Error::ENODEV)) = r {
+            if !self.removed {
+                tracing::warn!(vdev = %self.leaf.uuid(), ?error,
+                    "Device removed");
+                self.removed = true;
+                self.leaf.close();
+            }
+        }
+        for sender in senders {
+            sender.send(r).unwrap();
+        }
+    }
+
     /// Issue as many scheduled operations as possible
     // Use the C-LOOK scheduling algorithm.  It guarantees that writes scheduled
     // in LBA order will also be issued in LBA order.
@@ -519,10 +546,10 @@ impl Inner {
     /// Returns a delayed operation if there were insufficient resources to
     /// immediately issue the future.
     fn issue_fut(&mut self,
-                 senders: Vec<oneshot::Sender<()>>,
+                 senders: Vec<oneshot::Sender<Result<()>>>,
                  mut fut: Pin<Box<VdevFut>>,
                  cx: &mut Context)
-        -> Option<(Vec<oneshot::Sender<()>>, Pin<Box<VdevFut>>)>
+        -> Option<(Vec<oneshot::Sender<Result<()>>>, Pin<Box<VdevFut>>)>
     {
 
         let inner = self.weakself.upgrade().expect(
@@ -537,25 +564,23 @@ impl Inner {
                 // Out of resources to issue this future.  Delay it.
                 return Some((senders, fut));
             },
-            Poll::Ready(Err(e)) => panic!("Unhandled error {e:?}"),
             Poll::Pending => {
                 let schfut = self.reschedule();
                 tokio::spawn( async move {
                     let r = fut.await;
-                    r.expect("Unhandled error");
-                    for sender in senders{
-                        sender.send(()).unwrap();
+                    {
+                        let mut guard = inner.write().unwrap();
+                        guard.complete(senders, r);
+                        guard.queue_depth -= 1;
                     }
-                    inner.write().unwrap().queue_depth -= 1;
                     schfut.await
                 });
             },
-            Poll::Ready(Ok(_)) => {
+            Poll::Ready(r) => {
                 // This normally doesn't happen, but it can happen on a
-                // heavily laden system or one with very fast storage.
-                for sender in senders {
-                    sender.send(()).unwrap();
-                }
+                // heavily laden system or one with very fast storage, or if
+                // the device is already gone.
+                self.complete(senders, r);
                 self.queue_depth -= 1;
             }
         }
@@ -564,7 +589,7 @@ impl Inner {
 
     /// Create a future from a BlockOp, but don't spawn it yet
     fn make_fut(&mut self, block_op: BlockOp)
-        -> (Vec<oneshot::Sender<()>>, Pin<Box<VdevFut>>) {
+        -> (Vec<oneshot::Sender<Result<()>>>, Pin<Box<VdevFut>>) {
 
         self.queue_depth += 1;
         let lba = block_op.lba;
@@ -685,7 +710,7 @@ pub struct VdevBlockFut {
     block_op: Option<BlockOp>,
     inner: Arc<RwLock<Inner>>,
     #[pin]
-    receiver: oneshot::Receiver<()>,
+    receiver: oneshot::Receiver<Result<()>>,
 }
 
 impl Future for VdevBlockFut {
@@ -697,7 +722,7 @@ impl Future for VdevBlockFut {
             self.inner.write().unwrap().sched_and_issue(block_op, cx);
         }
         self.project().receiver.poll(cx)
-            .map_err(|_| Error::EPIPE)
+            .map(|r| r.unwrap_or(Err(Error::EPIPE)))
     }
 }
 
@@ -733,6 +758,11 @@ impl VdevBlock {
         assert!(last_lba <= self.size)
     }
 
+    /// Record that data read from this device failed checksum verification.
+    pub fn checksum_error(&self) {
+        self.inner.read().unwrap().leaf.checksum_error()
+    }
+
     /// Create a new VdevBlock from an unused file or device
     ///
     /// * `path`:           A pathname to a file or device
@@ -756,7 +786,7 @@ impl VdevBlock {
     {
         // The zone must already be closed, but VdevBlock doesn't keep enough
         // information to assert that
-        let (sender, receiver) = oneshot::channel::<()>();
+        let (sender, receiver) = oneshot::channel::<Result<()>>();
         let block_op = BlockOp::erase_zone(start, end, sender);
 
         // Sanity check LBAs
@@ -773,6 +803,12 @@ impl VdevBlock {
         self.new_fut(block_op, receiver)
     }
 
+    /// Cumulative I/O error counts of the underlying device, over its entire
+    /// lifetime.
+    pub fn error_counts(&self) -> ErrorCounts {
+        self.inner.read().unwrap().leaf.error_counts()
+    }
+
     /// Asynchronously finish a zone on a block device
     ///
     /// # Parameters
@@ -780,7 +816,7 @@ impl VdevBlock {
     /// - `end`:    The last LBA within the target zone
     pub fn finish_zone(&self, start: LbaT, end: LbaT) -> VdevBlockFut
     {
-        let (sender, receiver) = oneshot::channel::<()>();
+        let (sender, receiver) = oneshot::channel::<Result<()>>();
         let block_op = BlockOp::finish_zone(start, end, sender);
 
         // Sanity check LBAs
@@ -798,8 +834,14 @@ impl VdevBlock {
         self.new_fut(block_op, receiver)
     }
 
+    /// Has the underlying device disappeared?  If so, every operation will
+    /// fail with `ENXIO` until it's [reopened](VdevBlock::reopen).
+    pub fn is_removed(&self) -> bool {
+        self.inner.read().unwrap().removed
+    }
+
     fn new_fut(&self, block_op: BlockOp,
-               receiver: oneshot::Receiver<()>) -> VdevBlockFut {
+               receiver: oneshot::Receiver<Result<()>>) -> VdevBlockFut {
         VdevBlockFut {
             block_op: Some(block_op),
             inner: self.inner.clone(),
@@ -813,7 +855,7 @@ impl VdevBlock {
     /// - `start`:    The first LBA within the target zone
     pub fn open_zone(&self, start: LbaT) -> VdevBlockFut
     {
-        let (sender, receiver) = oneshot::channel::<()>();
+        let (sender, receiver) = oneshot::channel::<Result<()>>();
         let block_op = BlockOp::open_zone(start, sender);
 
         // Sanity check LBA
@@ -842,6 +884,7 @@ impl VdevBlock {
             leaf,
             last_lba: 0,
             syncing: false,
+            removed: false,
             after_sync: VecDeque::new(),
             ahead: BinaryHeap::new(),
             behind: BinaryHeap::new(),
@@ -862,7 +905,7 @@ impl VdevBlock {
     pub fn read_at(&self, buf: IoVecMut, lba: LbaT) -> VdevBlockFut
     {
         self.check_iovec_bounds(lba, &buf);
-        let (sender, receiver) = oneshot::channel::<()>();
+        let (sender, receiver) = oneshot::channel::<Result<()>>();
         let block_op = BlockOp::read_at(buf, lba, sender);
         self.new_fut(block_op, receiver)
     }
@@ -872,7 +915,7 @@ impl VdevBlock {
     #[tracing::instrument(skip(self, buf))]
     pub fn read_spacemap(&self, buf: IoVecMut, idx: u32) -> VdevBlockFut
     {
-        let (sender, receiver) = oneshot::channel::<()>();
+        let (sender, receiver) = oneshot::channel::<Result<()>>();
         // lba is for sorting purposes only.  It should sort before any other
         // write operation, and different read_spacemap operations should sort
         // in the same order as their true LBA order.
@@ -893,33 +936,53 @@ impl VdevBlock {
     pub fn readv_at(&self, bufs: SGListMut, lba: LbaT) -> VdevBlockFut
     {
         self.check_sglist_bounds(lba, &bufs);
-        let (sender, receiver) = oneshot::channel::<()>();
+        let (sender, receiver) = oneshot::channel::<Result<()>>();
         let block_op = BlockOp::readv_at(bufs, lba, sender);
         self.new_fut(block_op, receiver)
     }
 
+    /// Replace the leaf of a removed device with a freshly opened one, after
+    /// the device has come back.
+    ///
+    /// # Panics
+    ///
+    /// If `leaf` is not the same device that was removed.
+    pub fn reopen(&self, leaf: VdevLeaf) {
+        let mut inner = self.inner.write().unwrap();
+        assert_eq!(inner.leaf.uuid(), leaf.uuid(),
+                   "Reopening the wrong device");
+        inner.leaf = leaf;
+        inner.removed = false;
+    }
+
     /// Asynchronously write a contiguous portion of the vdev.
     ///
     /// Returns nothing on success, and on error on failure
     pub fn write_at(&self, buf: IoVec, lba: LbaT) -> VdevBlockFut
     {
         self.check_iovec_bounds(lba, &buf);
-        let (sender, receiver) = oneshot::channel::<()>();
+        let (sender, receiver) = oneshot::channel::<Result<()>>();
         let block_op = BlockOp::write_at(buf, lba, sender);
         self.new_fut(block_op, receiver)
     }
 
     pub fn write_label(&self, labeller: LabelWriter) -> VdevBlockFut
     {
-        let (sender, receiver) = oneshot::channel::<()>();
+        let (sender, receiver) = oneshot::channel::<Result<()>>();
         let block_op = BlockOp::write_label(labeller, sender);
         self.new_fut(block_op, receiver)
     }
 
+    /// Asynchronously read the device's write pointer for the zone beginning
+    /// at `start`.  Returns `None` if the device's zones are simulated.
+    pub fn write_pointer(&self, start: LbaT) -> BoxWritePointerFut {
+        self.inner.read().unwrap().leaf.write_pointer(start)
+    }
+
     pub fn write_spacemap(&self, sglist: SGList, idx: u32, block: LbaT)
         ->  VdevBlockFut
     {
-        let (sender, receiver) = oneshot::channel::<()>();
+        let (sender, receiver) = oneshot::channel::<Result<()>>();
         let sglist = copy_and_pad_sglist(sglist);
         // lba is for sorting purposes only.  It should sort after write_label,
         // but before any other write operation, and different write_spacemap
@@ -940,7 +1003,7 @@ impl VdevBlock {
     pub fn writev_at(&self, bufs: SGList, lba: LbaT) -> VdevBlockFut
     {
         self.check_sglist_bounds(lba, &bufs);
-        let (sender, receiver) = oneshot::channel::<()>();
+        let (sender, receiver) = oneshot::channel::<Result<()>>();
         let sglist = copy_and_pad_sglist(bufs);
         let block_op = BlockOp::writev_at(sglist, lba, sender);
         self.new_fut(block_op, receiver)
@@ -952,6 +1015,10 @@ impl Vdev for VdevBlock {
         self.inner.read().unwrap().leaf.lba2zone(lba)
     }
 
+    fn max_open_zones(&self) -> Option<u32> {
+        self.inner.read().unwrap().leaf.max_open_zones()
+    }
+
     /// Returns the "best" number of operations to queue to this `VdevBlock`.  A
     /// smaller number may result in inefficient use of resources, or even
     /// starvation.  A larger number won't hurt, but won't accrue any economies
@@ -967,7 +1034,7 @@ impl Vdev for VdevBlock {
     /// Asynchronously sync the underlying device, ensuring that all data
     /// reaches stable storage
     fn sync_all(&self) -> BoxVdevFut {
-        let (sender, receiver) = oneshot::channel::<()>();
+        let (sender, receiver) = oneshot::channel::<Result<()>>();
         let block_op = BlockOp::sync_all(sender);
         Box::pin(self.new_fut(block_op, receiver))
     }
@@ -989,19 +1056,24 @@ impl Vdev for VdevBlock {
 #[cfg(test)]
 mock! {
     pub VdevBlock {
+        pub fn checksum_error(&self);
         #[mockall::concretize]
         pub fn create<P>(path: P, lbas_per_zone: Option<NonZeroU64>)
             -> io::Result<Self>
             where P: AsRef<Path>;
         pub fn erase_zone(&self, start: LbaT, end: LbaT) -> BoxVdevFut;
+        pub fn error_counts(&self) -> ErrorCounts;
         pub fn finish_zone(&self, start: LbaT, end: LbaT) -> BoxVdevFut;
+        pub fn is_removed(&self) -> bool;
         pub fn new(leaf: VdevLeaf) -> Self;
         pub fn open_zone(&self, start: LbaT) -> BoxVdevFut;
         pub fn read_at(&self, buf: IoVecMut, lba: LbaT) -> BoxVdevFut;
         pub fn read_spacemap(&self, buf: IoVecMut, idx: u32) -> BoxVdevFut;
         pub fn readv_at(&self, bufs: SGListMut, lba: LbaT) -> BoxVdevFut;
+        pub fn reopen(&self, leaf: VdevLeaf);
         pub fn write_at(&self, buf: IoVec, lba: LbaT) -> BoxVdevFut;
         pub fn write_label(&self, labeller: LabelWriter) -> BoxVdevFut;
+        pub fn write_pointer(&self, start: LbaT) -> BoxWritePointerFut;
         pub fn write_spacemap(&self, sglist: SGList, idx: u32, block: LbaT)
             ->  BoxVdevFut;
         pub fn writev_at(&self, bufs: SGList, lba: LbaT) -> BoxVdevFut;
@@ -1922,6 +1994,84 @@ mod t {
             vdev.readv_at(rbuf0, 2).await.unwrap();
         }
 
+        // Errors from the leaf should be returned to the caller
+        #[rstest]
+        #[tokio::test]
+        async fn read_at_eio(mut leaf: MockVdevFile) {
+            leaf.expect_read_at()
+                .with(always(), eq(2))
+                .returning(|_, _| {
+                    Box::pin(future::err::<(), Error>(Error::EIO))
+                });
+            leaf.expect_close().never();
+
+            let dbs0 = DivBufShared::from(vec![0u8; 4096]);
+            let rbuf0 = dbs0.try_mut().unwrap();
+            let vdev = VdevBlock::new(leaf);
+
+            let e = vdev.read_at(rbuf0, 2).await.unwrap_err();
+            assert_eq!(e, Error::EIO);
+            assert!(!vdev.is_removed());
+        }
+
+        // If the device disappears, the VdevBlock should release it
+        #[rstest]
+        #[tokio::test]
+        async fn removed(mut leaf: MockVdevFile) {
+            leaf.expect_write_at()
+                .with(always(), eq(2))
+                .returning(|_, _| {
+                    Box::pin(future::err::<(), Error>(Error::ENXIO))
+                });
+            leaf.expect_uuid()
+                .return_const(Uuid::default());
+            leaf.expect_close()
+                .once()
+                .return_const(());
+
+            let dbs = DivBufShared::from(vec![0u8; 4096]);
+            let wbuf = dbs.try_const().unwrap();
+            let vdev = VdevBlock::new(leaf);
+
+            let e = vdev.write_at(wbuf.clone(), 2).await.unwrap_err();
+            assert_eq!(e, Error::ENXIO);
+            assert!(vdev.is_removed());
+            // Subsequent errors shouldn't try to close it again
+            let e = vdev.write_at(wbuf, 2).await.unwrap_err();
+            assert_eq!(e, Error::ENXIO);
+        }
+
+        // Reopening a removed device should make it usable again
+        #[rstest]
+        #[tokio::test]
+        async fn reopen(mut leaf: MockVdevFile) {
+            let uuid = Uuid::new_v4();
+            leaf.expect_read_at()
+                .returning(|_, _| {
+                    Box::pin(future::err::<(), Error>(Error::ENODEV))
+                });
+            leaf.expect_uuid()
+                .return_const(uuid);
+            leaf.expect_close()
+                .once()
+                .return_const(());
+            let mut leaf2 = MockVdevFile::new();
+            leaf2.expect_uuid()
+                .return_const(uuid);
+            leaf2.expect_read_at()
+                .with(always(), eq(2))
+                .returning(|_, _| Box::pin(future::ok::<(), Error>(())));
+
+            let dbs = DivBufShared::from(vec![0u8; 4096]);
+            let vdev = VdevBlock::new(leaf);
+
+            vdev.read_at(dbs.try_mut().unwrap(), 2).await.unwrap_err();
+            assert!(vdev.is_removed());
+            vdev.reopen(leaf2);
+            assert!(!vdev.is_removed());
+            vdev.read_at(dbs.try_mut().unwrap(), 2).await.unwrap();
+        }
+
         /// Tests for Inner::sched
         mod sched {
             use super::*;
@@ -1950,7 +2100,7 @@ mod t {
                     inner.last_lba = 1000;
                     for lba in permutation {
                         let op = BlockOp::write_at(dummy_buffer.clone(), *lba,
-                            oneshot::channel::<()>().0);
+                            oneshot::channel().0);
                         inner.sched(op);
                     }
 
@@ -1964,10 +2114,10 @@ mod t {
                     // get issued in the right order
                     let just_before2 = BlockOp::write_at(dummy_buffer.clone(),
                         1000,
-                        oneshot::channel::<()>().0);
+                        oneshot::channel().0);
                     let well_before = BlockOp::write_at(dummy_buffer.clone(),
                         990,
-                        oneshot::channel::<()>().0);
+                        oneshot::channel().0);
                     inner.sched(just_before2);
                     inner.sched(well_before);
 
@@ -1994,21 +2144,21 @@ mod t {
                 // scheduler, then erase them.  This simulates garbage
                 // collection.
                 let ez0 = BlockOp::erase_zone(0, (1 << 16) - 1,
-                    oneshot::channel::<()>().0);
+                    oneshot::channel().0);
                 let ez_discriminant = mem::discriminant(&ez0.cmd);
                 inner.sched(ez0);
                 let r = BlockOp::read_at(dummy.split_to(4096), (1 << 16) - 1,
-                    oneshot::channel::<()>().0);
+                    oneshot::channel().0);
                 let read_at_discriminant = mem::discriminant(&r.cmd);
                 inner.sched(r);
                 inner.sched(BlockOp::erase_zone(1 << 16, (2 << 16) - 1,
-                    oneshot::channel::<()>().0));
+                    oneshot::channel().0));
                 inner.sched(BlockOp::read_at(dummy.split_to(4096),
-                    (2 << 16) - 1, oneshot::channel::<()>().0));
+                    (2 << 16) - 1, oneshot::channel().0));
                 inner.sched(BlockOp::erase_zone(2 << 16, (3 << 16) - 1,
-                    oneshot::channel::<()>().0));
+                    oneshot::channel().0));
                 inner.sched(BlockOp::read_at(dummy, (3 << 16) - 1,
-                    oneshot::channel::<()>().0));
+                    oneshot::channel().0));
 
                 let first = inner.pop_op().unwrap();
                 assert_eq!(first.lba, (2 << 16) - 1);
@@ -2043,21 +2193,21 @@ mod t {
                 // Write to zones that lie behind, around, and ahead of the
                 // scheduler, then finish them.
                 let fz0 = BlockOp::finish_zone(0, (1 << 16) - 1,
-                    oneshot::channel::<()>().0);
+                    oneshot::channel().0);
                 let fz_discriminant = mem::discriminant(&fz0.cmd);
                 inner.sched(fz0);
                 let r = BlockOp::write_at(dummy.clone(), (1 << 16) - 1,
-                    oneshot::channel::<()>().0);
+                    oneshot::channel().0);
                 let write_at_discriminant = mem::discriminant(&r.cmd);
                 inner.sched(r);
                 inner.sched(BlockOp::finish_zone(1 << 16, (2 << 16) - 1,
-                    oneshot::channel::<()>().0));
+                    oneshot::channel().0));
                 inner.sched(BlockOp::write_at(dummy.clone(), (2 << 16) - 1,
-                    oneshot::channel::<()>().0));
+                    oneshot::channel().0));
                 inner.sched(BlockOp::finish_zone(2 << 16, (3 << 16) - 1,
-                    oneshot::channel::<()>().0));
+                    oneshot::channel().0));
                 inner.sched(BlockOp::write_at(dummy, (3 << 16) - 1,
-                    oneshot::channel::<()>().0));
+                    oneshot::channel().0));
 
                 let first = inner.pop_op().unwrap();
                 assert_eq!(first.lba, (2 << 16) - 1);
@@ -2097,24 +2247,24 @@ mod t {
                 // these zones, because that would imply that it had just
                 // performed an operation on an empty zone.
                 let w = BlockOp::write_at(dummy.clone(), 1,
-                    oneshot::channel::<()>().0);
+                    oneshot::channel().0);
                 let write_at_discriminant = mem::discriminant(&w.cmd);
                 inner.sched(w);
                 inner.sched(BlockOp::write_at(dummy.clone(), (1 << 16) - 1,
-                            oneshot::channel::<()>().0));
+                            oneshot::channel().0));
                 inner.sched(BlockOp::write_at(dummy.clone(), 2,
-                            oneshot::channel::<()>().0));
-                let oz0 = BlockOp::open_zone(1, oneshot::channel::<()>().0);
+                            oneshot::channel().0));
+                let oz0 = BlockOp::open_zone(1, oneshot::channel().0);
                 let oz_discriminant = mem::discriminant(&oz0.cmd);
                 inner.sched(oz0);
                 inner.sched(BlockOp::open_zone(2 << 16,
-                                               oneshot::channel::<()>().0));
+                                               oneshot::channel().0));
                 inner.sched(BlockOp::write_at(dummy.clone(), (2 << 16) + 1,
-                            oneshot::channel::<()>().0));
+                            oneshot::channel().0));
                 inner.sched(BlockOp::write_at(dummy.clone(), 2 << 16,
-                            oneshot::channel::<()>().0));
+                            oneshot::channel().0));
                 inner.sched(BlockOp::write_at(dummy, (3 << 16) - 1,
-                            oneshot::channel::<()>().0));
+                            oneshot::channel().0));
 
                 let first = inner.pop_op().unwrap();
                 assert_eq!(first.lba, 2 << 16);
@@ -2150,24 +2300,24 @@ mod t {
                 // and after
                 inner.last_lba = 1000;
                 inner.sched(BlockOp::write_at(dummy_buffer.clone(), 1001,
-                    oneshot::channel::<()>().0));
+                    oneshot::channel().0));
                 inner.sched(BlockOp::write_at(dummy_buffer.clone(), 999,
-                    oneshot::channel::<()>().0));
+                    oneshot::channel().0));
                 // Now schedule a sync_all, too
-                inner.sched(BlockOp::sync_all(oneshot::channel::<()>().0));
+                inner.sched(BlockOp::sync_all(oneshot::channel().0));
                 // Now schedule some more data ops both before and after the
                 // scheudler
                 inner.sched(BlockOp::write_at(dummy_buffer.clone(), 1002,
-                    oneshot::channel::<()>().0));
+                    oneshot::channel().0));
                 inner.sched(BlockOp::write_at(dummy_buffer.clone(), 998,
-                    oneshot::channel::<()>().0));
+                    oneshot::channel().0));
                 // For good measure, schedule a second sync and some more data
                 // after that
-                inner.sched(BlockOp::sync_all(oneshot::channel::<()>().0));
+                inner.sched(BlockOp::sync_all(oneshot::channel().0));
                 inner.sched(BlockOp::write_at(dummy_buffer.clone(), 1003,
-                    oneshot::channel::<()>().0));
+                    oneshot::channel().0));
                 inner.sched(BlockOp::write_at(dummy_buffer, 997,
-                    oneshot::channel::<()>().0));
+                    oneshot::channel().0));
 
                 // All pre-sync operations should be issued, then the sync, then
                 // the post-sync operations
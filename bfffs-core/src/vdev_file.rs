@@ -31,7 +31,11 @@ use std::{
         io::{AsRawFd, RawFd}
     },
     path::Path,
-    pin::Pin
+    pin::Pin,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering}
+    }
 };
 use tokio_file::File;
 use tokio::task;
@@ -67,14 +71,123 @@ impl EraseMethod {
     }
 }
 
+/// How does this device implement zones?
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ZoneMode {
+    /// Ordinary file or conventional disk.  Zones are simulated.
+    Simulated,
+    /// Host-managed SMR or ZNS device.  Zone operations are real commands.
+    HostManaged {
+        /// Size of the device's logical sector in bytes.  Zone commands are
+        /// addressed in units of sectors rather than LBAs.
+        sectorsize: u32,
+        /// Maximum number of simultaneously open sequential zones, if the
+        /// device reports one.
+        max_open: Option<u32>
+    }
+}
+
+/// Native zone parameters, as reported by a zoned device.
+#[derive(Clone, Copy, Debug)]
+struct NativeZones {
+    lbas_per_zone: LbaT,
+    mode: ZoneMode
+}
+
 /// FFI definitions that don't belong in libc.  The ioctls can't go in libc
 /// because they use Nix's macros.  The structs probably shouldn't go in libc,
 /// because they're not really intended to be a stable interface.
 #[doc(hidden)]
 mod ffi {
-    use nix::{ ioctl_readwrite, ioctl_write_ptr, libc::{c_int, off_t} };
+    use nix::{
+        ioctl_read,
+        ioctl_readwrite,
+        ioctl_write_ptr,
+        libc::{c_int, c_uint, off_t}
+    };
     const DISK_IDENT_SIZE: usize = 256;
 
+    pub const DISK_ZONE_OPEN: u8 = 0x01;
+    pub const DISK_ZONE_FINISH: u8 = 0x03;
+    pub const DISK_ZONE_REPORT_ZONES: u8 = 0x04;
+    pub const DISK_ZONE_RWP: u8 = 0x05;
+    pub const DISK_ZONE_GET_PARAMS: u8 = 0x06;
+
+    pub const DISK_ZONE_MODE_HOST_MANAGED: u32 = 0x04;
+    pub const DISK_ZONE_MAX_SEQ_SET: u64 = 0x008;
+
+    pub const DISK_ZONE_TYPE_CONVENTIONAL: u8 = 0x01;
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    #[doc(hidden)]
+    pub struct disk_zone_disk_params {
+        pub zone_mode: u32,
+        pub flags: u64,
+        pub optimal_seq_zones: u32,
+        pub optimal_nonseq_zones: u32,
+        pub max_seq_zones: u32,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    #[doc(hidden)]
+    pub struct disk_zone_rwp_params {
+        pub id: u64,
+        pub flags: u8,
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    #[doc(hidden)]
+    pub struct disk_zone_report_entry {
+        pub zone_type: u8,
+        pub zone_condition: u8,
+        pub zone_flags: u8,
+        pub zone_length: u64,
+        pub zone_start_lba: u64,
+        pub write_pointer_lba: u64,
+        pub reserved: [u8; 32],
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    #[doc(hidden)]
+    pub struct disk_zone_rep_header {
+        pub same_type: u8,
+        pub flags: u8,
+        pub maximum_lba: u64,
+        pub reserved: [u8; 64],
+    }
+
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    #[doc(hidden)]
+    pub struct disk_zone_report {
+        pub starting_id: u64,
+        pub rep_options: u8,
+        pub entries_allocated: u32,
+        pub entries_filled: u32,
+        pub entries_available: u32,
+        pub header: disk_zone_rep_header,
+        pub entries: *mut disk_zone_report_entry,
+    }
+
+    #[repr(C)]
+    #[doc(hidden)]
+    pub union disk_zone_params {
+        pub disk_params: disk_zone_disk_params,
+        pub rwp: disk_zone_rwp_params,
+        pub report: disk_zone_report,
+    }
+
+    #[repr(C)]
+    #[doc(hidden)]
+    pub struct disk_zone_args {
+        pub zone_cmd: u8,
+        pub zone_params: disk_zone_params,
+    }
+
     #[repr(C)]
     #[doc(hidden)]
     pub union diocgattr_arg_value {
@@ -105,9 +218,27 @@ mod ffi {
         #[doc(hidden)]
         diocgdelete, b'd', 136, [off_t; 2]
     }
+
+    ioctl_read! {
+        #[doc(hidden)]
+        diocgsectorsize, b'd', 128, c_uint
+    }
+
+    ioctl_readwrite! {
+        /// Issue a zone command to a zoned (SMR or ZNS) device
+        #[doc(hidden)]
+        dioczonecmd, b'd', 143, disk_zone_args
+    }
 }
 
-use ffi::{diocgdelete, diocgattr, diocgattr_arg};
+use ffi::{
+    diocgdelete,
+    diocgattr,
+    diocgattr_arg,
+    diocgsectorsize,
+    dioczonecmd,
+    disk_zone_args
+};
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct Label {
@@ -118,7 +249,81 @@ pub struct Label {
     /// Number of LBAs that were present at format time
     lbas:           LbaT,
     /// LBAs in the first zone reserved for storing each spacemap.
-    spacemap_space:    LbaT
+    spacemap_space:    LbaT,
+    /// Cumulative error counts as of the time the label was written
+    errors:         ErrorCounts
+}
+
+impl LabelLayer for Label {
+    type V0 = LabelV0;
+}
+
+/// `Label` as it was in version 0 labels, before error counts were recorded
+#[derive(Deserialize)]
+pub struct LabelV0 {
+    uuid:           Uuid,
+    lbas_per_zone:  LbaT,
+    lbas:           LbaT,
+    spacemap_space: LbaT,
+}
+
+impl From<LabelV0> for Label {
+    fn from(v0: LabelV0) -> Self {
+        Label {
+            uuid: v0.uuid,
+            lbas_per_zone: v0.lbas_per_zone,
+            lbas: v0.lbas,
+            spacemap_space: v0.spacemap_space,
+            errors: ErrorCounts::default()
+        }
+    }
+}
+
+/// Live error counters for a `VdevFile`.
+///
+/// They start from whatever the label recorded, and are shared with every
+/// outstanding I/O future.
+#[derive(Debug, Default)]
+struct ErrorCounters {
+    read: AtomicU64,
+    write: AtomicU64,
+    checksum: AtomicU64
+}
+
+impl ErrorCounters {
+    fn load(&self) -> ErrorCounts {
+        ErrorCounts {
+            read: self.read.load(Ordering::Relaxed),
+            write: self.write.load(Ordering::Relaxed),
+            checksum: self.checksum.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Count a failed read.  EAGAIN doesn't count, because VdevBlock will
+    /// simply reissue the operation.
+    fn read_error(&self, e: Error) {
+        if e != Error::EAGAIN {
+            self.read.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Count a failed write.  EAGAIN doesn't count, because VdevBlock will
+    /// simply reissue the operation.
+    fn write_error(&self, e: Error) {
+        if e != Error::EAGAIN {
+            self.write.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl From<ErrorCounts> for ErrorCounters {
+    fn from(ec: ErrorCounts) -> Self {
+        ErrorCounters {
+            read: AtomicU64::new(ec.read),
+            write: AtomicU64::new(ec.write),
+            checksum: AtomicU64::new(ec.checksum),
+        }
+    }
 }
 
 /// `VdevFile`: File-backed implementation of `VdevBlock`
@@ -130,7 +335,8 @@ pub struct Label {
 ///
 #[derive(Debug)]
 pub struct VdevFile {
-    file:           File,
+    /// The open file, or `None` once it's been closed by [`VdevFile::close`].
+    file:           Option<File>,
     /// Number of reserved LBAS in first zone for each spacemap
     spacemap_space: LbaT,
     /// Number of LBAs per simulated zone
@@ -140,7 +346,11 @@ pub struct VdevFile {
     /// How does the underlying file deallocate data?
     // NB: this could be Arc<atomic_enum> to eliminate the need for &mut self in
     // fn erase_zone()
-    erase_method:   EraseMethod
+    erase_method:   EraseMethod,
+    /// Are zones native to the device, or simulated?
+    zone_mode:      ZoneMode,
+    /// Lifetime I/O error counts
+    errors:         Arc<ErrorCounters>
 }
 
 impl Vdev for VdevFile {
@@ -152,6 +362,13 @@ impl Vdev for VdevFile {
         }
     }
 
+    fn max_open_zones(&self) -> Option<u32> {
+        match self.zone_mode {
+            ZoneMode::Simulated => None,
+            ZoneMode::HostManaged{max_open, ..} => max_open
+        }
+    }
+
     fn optimum_queue_depth(&self) -> u32 {
         // The value `10` is just a total guess.
         10
@@ -162,7 +379,11 @@ impl Vdev for VdevFile {
     }
 
     fn sync_all(&self) -> BoxVdevFut {
-        let fut = self.file.sync_all().unwrap()
+        let file = match self.file() {
+            Ok(f) => f,
+            Err(e) => return Box::pin(future::err::<(), Error>(e))
+        };
+        let fut = file.sync_all().unwrap()
             .map_ok(drop)
             .map_err(Error::from);
         Box::pin(fut)
@@ -208,6 +429,17 @@ impl VdevFile {
         }
     }
 
+    /// Record that data read from this device failed checksum verification.
+    pub fn checksum_error(&self) {
+        self.errors.checksum.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Release the underlying file descriptor, for example because the device
+    /// has disappeared.  Every subsequent operation will fail with `ENXIO`.
+    pub fn close(&mut self) {
+        self.file = None;
+    }
+
     /// Create a new Vdev, backed by a file
     ///
     /// * `path`:           Pathname for the file.  It may be a device node.
@@ -224,9 +456,13 @@ impl VdevFile {
             .custom_flags(libc::O_DIRECT)
             .open(path)
             .map(File::new)?;
-        let lpz = match lbas_per_zone {
-            None => VdevFile::DEFAULT_LBAS_PER_ZONE,
-            Some(x) => x.get()
+        let native = VdevFile::native_zones(f.as_raw_fd())?;
+        let (lpz, zone_mode) = match (native, lbas_per_zone) {
+            // The device's own zone size always wins over the requested one
+            (Some(nz), _) => (nz.lbas_per_zone, nz.mode),
+            (None, None) =>
+                (VdevFile::DEFAULT_LBAS_PER_ZONE, ZoneMode::Simulated),
+            (None, Some(x)) => (x.get(), ZoneMode::Simulated)
         };
         let erase_method = EraseMethod::get(f.as_raw_fd()).unwrap();
         let size = f.len().unwrap() / BYTES_PER_LBA as u64;
@@ -234,15 +470,22 @@ impl VdevFile {
         let spacemap_space = spacemap_space(nzones);
         let uuid = Uuid::new_v4();
         Ok(VdevFile{
-            file: f,
+            file: Some(f),
             spacemap_space,
             lbas_per_zone: lpz,
             size,
             uuid,
-            erase_method
+            erase_method,
+            zone_mode,
+            errors: Arc::default()
         })
     }
 
+    /// Cumulative I/O error counts over the device's entire lifetime
+    pub fn error_counts(&self) -> ErrorCounts {
+        self.errors.load()
+    }
+
     /// Asynchronously erase the given zone.
     ///
     /// After this, the zone will be in the empty state.  The data may or may
@@ -254,7 +497,14 @@ impl VdevFile {
     // There isn't (yet) a way to asynchronously trim, so use a synchronous
     // method in a blocking_task
     pub fn erase_zone(&mut self, lba: LbaT) -> BoxVdevFut {
-        let fd = self.file.as_raw_fd();
+        if let ZoneMode::HostManaged{..} = self.zone_mode {
+            // Resetting the write pointer is the native way to erase a zone.
+            return self.zone_cmd(ffi::DISK_ZONE_RWP, lba);
+        }
+        let fd = match self.file() {
+            Ok(f) => f.as_raw_fd(),
+            Err(e) => return Box::pin(future::err::<(), Error>(e))
+        };
         let off = lba as off_t * (BYTES_PER_LBA as off_t);
         let len = self.lbas_per_zone as off_t * BYTES_PER_LBA as off_t;
         let em = self.erase_method;
@@ -316,9 +566,88 @@ impl VdevFile {
     /// # Parameters
     ///
     /// -`lba`: The first LBA of the zone to finish
-    pub fn finish_zone(&self, _lba: LbaT) -> BoxVdevFut {
-        // ordinary files don't have Zone operations
-        Box::pin(future::ok(()))
+    pub fn finish_zone(&self, lba: LbaT) -> BoxVdevFut {
+        match self.zone_mode {
+            // ordinary files don't have Zone operations
+            ZoneMode::Simulated => Box::pin(future::ok(())),
+            ZoneMode::HostManaged{..} =>
+                self.zone_cmd(ffi::DISK_ZONE_FINISH, lba)
+        }
+    }
+
+    /// The underlying file, or `ENXIO` if it has been closed
+    fn file(&self) -> Result<&File> {
+        self.file.as_ref().ok_or(Error::ENXIO)
+    }
+
+    /// Query the device for native zone support.
+    ///
+    /// Returns `None` for devices that should use simulated zones, including
+    /// ordinary files, conventional disks, and drive-managed or host-aware
+    /// SMR disks.  Host-aware disks do accept random writes, so simulating
+    /// zones on them is safe.
+    fn native_zones(fd: RawFd) -> io::Result<Option<NativeZones>> {
+        let mut args = MaybeUninit::<disk_zone_args>::zeroed();
+        let r = unsafe {
+            (*args.as_mut_ptr()).zone_cmd = ffi::DISK_ZONE_GET_PARAMS;
+            dioczonecmd(fd, args.as_mut_ptr())
+        };
+        let params = match r {
+            Ok(_) => unsafe { args.assume_init().zone_params.disk_params },
+            // Not a GEOM device, or not a zoned one.
+            Err(nix::Error::ENOTTY) | Err(nix::Error::EOPNOTSUPP) |
+                Err(nix::Error::EINVAL) | Err(nix::Error::ENODEV)
+                => return Ok(None),
+            Err(e) => return Err(e.into())
+        };
+        if params.zone_mode != ffi::DISK_ZONE_MODE_HOST_MANAGED {
+            return Ok(None);
+        }
+        let mut sectorsize: libc::c_uint = 0;
+        unsafe { diocgsectorsize(fd, &mut sectorsize) }?;
+
+        // Report the first two zones.  The labels and spacemaps live in the
+        // first zone, so it must accept random writes.  The second zone is
+        // the first one that BFFFS writes sequentially, and its length
+        // determines the zone size.
+        let mut entries = [ffi::disk_zone_report_entry {
+            zone_type: 0,
+            zone_condition: 0,
+            zone_flags: 0,
+            zone_length: 0,
+            zone_start_lba: 0,
+            write_pointer_lba: 0,
+            reserved: [0; 32]
+        }; 2];
+        let mut args = MaybeUninit::<disk_zone_args>::zeroed();
+        unsafe {
+            let p = args.as_mut_ptr();
+            (*p).zone_cmd = ffi::DISK_ZONE_REPORT_ZONES;
+            (*p).zone_params.report.starting_id = 0;
+            (*p).zone_params.report.entries_allocated = entries.len() as u32;
+            (*p).zone_params.report.entries = entries.as_mut_ptr();
+            dioczonecmd(fd, p)
+        }?;
+        let filled = unsafe {
+            args.assume_init().zone_params.report.entries_filled
+        };
+        if filled < 2 {
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        if entries[0].zone_type != ffi::DISK_ZONE_TYPE_CONVENTIONAL {
+            // There's nowhere to put the label
+            tracing::error!("First zone of a host-managed device must be conventional");
+            return Err(io::Error::from_raw_os_error(libc::EINVAL));
+        }
+        let lbas_per_zone = entries[1].zone_length * u64::from(sectorsize)
+            / BYTES_PER_LBA as u64;
+        let max_open = if params.flags & ffi::DISK_ZONE_MAX_SEQ_SET != 0 {
+            Some(params.max_seq_zones)
+        } else {
+            None
+        };
+        let mode = ZoneMode::HostManaged{sectorsize, max_open};
+        Ok(Some(NativeZones{lbas_per_zone, mode}))
     }
 
     /// Open an existing `VdevFile`
@@ -329,6 +658,22 @@ impl VdevFile {
     /// * `path`    Pathname for the file.  It may be a device node.
     pub async fn open<P: AsRef<Path>>(path: P)
         -> Result<(Self, LabelReader)>
+    {
+        VdevFile::open_priv(path, false).await
+    }
+
+    /// Open an existing `VdevFile` using the label preserved by a pool
+    /// checkpoint, rather than the most recent one.
+    ///
+    /// * `path`    Pathname for the file.  It may be a device node.
+    pub async fn open_checkpoint<P: AsRef<Path>>(path: P)
+        -> Result<(Self, LabelReader)>
+    {
+        VdevFile::open_priv(path, true).await
+    }
+
+    async fn open_priv<P: AsRef<Path>>(path: P, checkpoint: bool)
+        -> Result<(Self, LabelReader)>
     {
         let file = OpenOptions::new()
             .read(true)
@@ -339,12 +684,17 @@ impl VdevFile {
             .map_err(|e| Error::from_i32(e.raw_os_error().unwrap()).unwrap());
         match file {
             Ok(f) => {
-                let r = match VdevFile::read_label(f, 0).await {
-                    Err((_e, f)) => {
-                        // Try the second label
-                        VdevFile::read_label(f, 1).await
-                    },
-                    Ok(r) => Ok(r)
+                let r = if checkpoint {
+                    // A checkpoint is always preserved in the second label
+                    VdevFile::read_label(f, 1).await
+                } else {
+                    match VdevFile::read_label(f, 0).await {
+                        Err((_e, f)) => {
+                            // Try the second label
+                            VdevFile::read_label(f, 1).await
+                        },
+                        Ok(r) => Ok(r)
+                    }
                 };
                 match r {
                     Err((e, _f)) => Err(e),
@@ -354,13 +704,24 @@ impl VdevFile {
                         let label: Label = label_reader.deserialize().unwrap();
                         assert!(size >= label.lbas,
                                 "Vdev has shrunk since creation");
+                        let zone_mode = match VdevFile::native_zones(
+                            f.as_raw_fd())?
+                        {
+                            Some(nz) if nz.lbas_per_zone ==
+                                label.lbas_per_zone => nz.mode,
+                            // The device's zone size has changed
+                            Some(_) => return Err(Error::EINVAL),
+                            None => ZoneMode::Simulated
+                        };
                         let vdev = VdevFile {
-                            file: f,
+                            file: Some(f),
                             spacemap_space: label.spacemap_space,
                             lbas_per_zone: label.lbas_per_zone,
                             size: label.lbas,
                             uuid: label.uuid,
-                            erase_method
+                            erase_method,
+                            zone_mode,
+                            errors: Arc::new(label.errors.into())
                         };
                         Ok((vdev, label_reader))
                     }
@@ -377,9 +738,13 @@ impl VdevFile {
     /// # Parameters
     ///
     /// -`lba`: The first LBA of the zone to open
-    pub fn open_zone(&self, _lba: LbaT) -> BoxVdevFut {
-        // ordinary files don't have Zone operations
-        Box::pin(future::ok(()))
+    pub fn open_zone(&self, lba: LbaT) -> BoxVdevFut {
+        match self.zone_mode {
+            // ordinary files don't have Zone operations
+            ZoneMode::Simulated => Box::pin(future::ok(())),
+            ZoneMode::HostManaged{..} =>
+                self.zone_cmd(ffi::DISK_ZONE_OPEN, lba)
+        }
     }
 
     /// Asynchronously read a contiguous portion of the vdev.
@@ -397,8 +762,13 @@ impl VdevFile {
             // heap-allocated, moving it won't change the data's address.
             mem::transmute::<&mut[u8], &'static mut [u8]>(buf.as_mut())
         };
-        let fut = self.file.read_at(&mut *bufaddr, off).unwrap();
-        Box::pin(ReadAt { _buf: buf, fut })
+        let file = match self.file() {
+            Ok(f) => f,
+            Err(e) => return Box::pin(future::err::<(), Error>(e))
+        };
+        let fut = file.read_at(&mut *bufaddr, off).unwrap();
+        let errors = self.errors.clone();
+        Box::pin(ReadAt { _buf: buf, errors, fut })
     }
 
     /// Read just one of a vdev's labels
@@ -471,10 +841,15 @@ impl VdevFile {
                 &mut slices
             )
         };
-        let fut = self.file.readv_at(bufs, off).unwrap();
+        let file = match self.file() {
+            Ok(f) => f,
+            Err(e) => return Box::pin(future::err::<(), Error>(e))
+        };
+        let fut = file.readv_at(bufs, off).unwrap();
         Box::pin(ReadvAt {
             _sglist: sglist,
             _slices: slices,
+            errors: self.errors.clone(),
             fut
         })
     }
@@ -488,6 +863,33 @@ impl VdevFile {
         self.spacemap_space
     }
 
+    /// Issue a native zone command for the zone beginning at `lba`.
+    ///
+    /// Zone commands may take a long time (especially FINISH, which must fill
+    /// the zone), so run them in a blocking task.
+    fn zone_cmd(&self, cmd: u8, lba: LbaT) -> BoxVdevFut {
+        let sectorsize = match self.zone_mode {
+            ZoneMode::HostManaged{sectorsize, ..} => u64::from(sectorsize),
+            ZoneMode::Simulated => unreachable!()
+        };
+        let fd = match self.file() {
+            Ok(f) => f.as_raw_fd(),
+            Err(e) => return Box::pin(future::err::<(), Error>(e))
+        };
+        let id = lba * BYTES_PER_LBA as u64 / sectorsize;
+        let t = task::spawn_blocking(move || {
+            let mut args = MaybeUninit::<disk_zone_args>::zeroed();
+            unsafe {
+                let p = args.as_mut_ptr();
+                (*p).zone_cmd = cmd;
+                (*p).zone_params.rwp.id = id;
+                dioczonecmd(fd, p)
+            }.map(drop)
+        }).map(std::result::Result::unwrap)
+        .map_err(Error::from);
+        Box::pin(t)
+    }
+
     /// Asynchronously write a contiguous portion of the vdev.
     pub fn write_at(&self, buf: IoVec, lba: LbaT) -> BoxVdevFut
     {
@@ -509,9 +911,14 @@ impl VdevFile {
         let sbuf: &'static [u8] = unsafe {
             mem::transmute::<&[u8], &'static [u8]>( buf.as_ref())
         };
-        let fut = self.file.write_at(sbuf, off).unwrap();
+        let file = match self.file() {
+            Ok(f) => f,
+            Err(e) => return Box::pin(future::err::<(), Error>(e))
+        };
+        let fut = file.write_at(sbuf, off).unwrap();
+        let errors = self.errors.clone();
 
-        Box::pin(WriteAt { _buf: buf, fut })
+        Box::pin(WriteAt { _buf: buf, errors, fut })
     }
 
     /// Asynchronously write this Vdev's label.
@@ -524,15 +931,64 @@ impl VdevFile {
             uuid: self.uuid,
             spacemap_space: self.spacemap_space,
             lbas_per_zone: self.lbas_per_zone,
-            lbas: self.size
+            lbas: self.size,
+            errors: self.errors.load()
         };
         label_writer.serialize(&label).unwrap();
         let lba = label_writer.lba();
-        let sglist = label_writer.into_sglist();
-        let sglist = copy_and_pad_sglist(sglist);
+        let sglist = match label_writer.into_sglist() {
+            Ok(sglist) => copy_and_pad_sglist(sglist),
+            Err(e) => return Box::pin(future::err::<(), Error>(e))
+        };
         self.writev_at_unchecked(sglist, lba)
     }
 
+    /// Asynchronously read the device's write pointer for the zone beginning
+    /// at `lba`.
+    ///
+    /// Returns `None` if the device doesn't have native zones, in which case
+    /// the `Cluster`'s spacemap is the only record of the write pointer.
+    pub fn write_pointer(&self, lba: LbaT) -> BoxWritePointerFut {
+        let sectorsize = match self.zone_mode {
+            ZoneMode::Simulated => return Box::pin(future::ok(None)),
+            ZoneMode::HostManaged{sectorsize, ..} => u64::from(sectorsize)
+        };
+        let fd = match self.file() {
+            Ok(f) => f.as_raw_fd(),
+            Err(e) => return Box::pin(future::err(e))
+        };
+        let sectors_per_lba = BYTES_PER_LBA as u64 / sectorsize;
+        let t = task::spawn_blocking(move || {
+            let mut entry = ffi::disk_zone_report_entry {
+                zone_type: 0,
+                zone_condition: 0,
+                zone_flags: 0,
+                zone_length: 0,
+                zone_start_lba: 0,
+                write_pointer_lba: 0,
+                reserved: [0; 32]
+            };
+            let mut args = MaybeUninit::<disk_zone_args>::zeroed();
+            unsafe {
+                let p = args.as_mut_ptr();
+                (*p).zone_cmd = ffi::DISK_ZONE_REPORT_ZONES;
+                (*p).zone_params.report.starting_id = lba * sectors_per_lba;
+                (*p).zone_params.report.entries_allocated = 1;
+                (*p).zone_params.report.entries = &mut entry;
+                dioczonecmd(fd, p)
+            }?;
+            let filled = unsafe {
+                args.assume_init().zone_params.report.entries_filled
+            };
+            if filled < 1 {
+                return Err(nix::Error::EINVAL);
+            }
+            Ok(Some(entry.write_pointer_lba / sectors_per_lba))
+        }).map(std::result::Result::unwrap)
+        .map_err(Error::from);
+        Box::pin(t)
+    }
+
     /// Asynchronously write to the Vdev's spacemap area.
     ///
     /// # Parameters
@@ -566,9 +1022,12 @@ impl VdevFile {
         self.writev_at_unchecked(sglist, lba)
     }
 
-    fn writev_at_unchecked(&self, sglist: SGList, lba: LbaT)
-        -> Pin<Box<WritevAt>>
+    fn writev_at_unchecked(&self, sglist: SGList, lba: LbaT) -> BoxVdevFut
     {
+        let file = match self.file() {
+            Ok(f) => f,
+            Err(e) => return Box::pin(future::err::<(), Error>(e))
+        };
         let off = lba * (BYTES_PER_LBA as u64);
 
         let slices: Box<[IoSlice<'static>]> =
@@ -584,11 +1043,12 @@ impl VdevFile {
                     IoSlice::new(sb)
                 }).collect::<Vec<_>>()
                 .into_boxed_slice();
-        let fut = self.file.writev_at(&slices, off).unwrap();
+        let fut = file.writev_at(&slices, off).unwrap();
 
         Box::pin(WritevAt {
             _sglist: sglist,
             _slices: slices,
+            errors: self.errors.clone(),
             fut
         })
     }
@@ -598,6 +1058,7 @@ impl VdevFile {
 struct ReadAt {
     // Owns the buffer used by the Future
     _buf: IoVecMut,
+    errors: Arc<ErrorCounters>,
     #[pin]
     fut: tokio_file::ReadAt<'static>
 }
@@ -612,9 +1073,14 @@ impl Future for ReadAt {
     // FuturesExt::{map, map_err}'s implementations.  So we have to define a
     // custom poll method here, with map's and map_err's functionality inlined.
     fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        match self.project().fut.poll(cx) {
+        let this = self.project();
+        match this.fut.poll(cx) {
             Poll::Ready(Ok(_aio_result)) => Poll::Ready(Ok(())),
-            Poll::Ready(Err(e)) => Poll::Ready(Err(Error::from(e))),
+            Poll::Ready(Err(e)) => {
+                let e = Error::from(e);
+                this.errors.read_error(e);
+                Poll::Ready(Err(e))
+            },
             Poll::Pending => Poll::Pending
         }
     }
@@ -626,6 +1092,7 @@ struct WriteAt {
     fut: tokio_file::WriteAt<'static>,
     // Owns the buffer used by the Future
     _buf: IoVec,
+    errors: Arc<ErrorCounters>,
 }
 
 impl Future for WriteAt {
@@ -638,9 +1105,14 @@ impl Future for WriteAt {
     // FuturesExt::{map, map_err}'s implementations.  So we have to define a
     // custom poll method here, with map's and map_err's functionality inlined.
     fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        match self.project().fut.poll(cx) {
+        let this = self.project();
+        match this.fut.poll(cx) {
             Poll::Ready(Ok(_aio_result)) => Poll::Ready(Ok(())),
-            Poll::Ready(Err(e)) => Poll::Ready(Err(Error::from(e))),
+            Poll::Ready(Err(e)) => {
+                let e = Error::from(e);
+                this.errors.write_error(e);
+                Poll::Ready(Err(e))
+            },
             Poll::Pending => Poll::Pending
         }
     }
@@ -654,6 +1126,7 @@ struct ReadvAt {
     _slices: Box<[IoSliceMut<'static>]>,
     // Owns the buffers used by the Future
     _sglist: SGListMut,
+    errors: Arc<ErrorCounters>,
 }
 
 impl Future for ReadvAt {
@@ -666,9 +1139,14 @@ impl Future for ReadvAt {
     // FuturesExt::{map, map_err}'s implementations.  So we have to define a
     // custom poll method here, with map's and map_err's functionality inlined.
     fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        match self.project().fut.poll(cx) {
+        let this = self.project();
+        match this.fut.poll(cx) {
             Poll::Ready(Ok(_l)) => Poll::Ready(Ok(())),
-            Poll::Ready(Err(e)) => Poll::Ready(Err(Error::from(e))),
+            Poll::Ready(Err(e)) => {
+                let e = Error::from(e);
+                this.errors.read_error(e);
+                Poll::Ready(Err(e))
+            },
             Poll::Pending => Poll::Pending
         }
     }
@@ -682,6 +1160,7 @@ struct WritevAt{
     _slices: Box<[IoSlice<'static>]>,
     // Owns the buffers used by the Future
     _sglist: SGList,
+    errors: Arc<ErrorCounters>,
 }
 
 impl Future for WritevAt {
@@ -694,9 +1173,14 @@ impl Future for WritevAt {
     // FuturesExt::{map, map_err}'s implementations.  So we have to define a
     // custom poll method here, with map's and map_err's functionality inlined.
     fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
-        match self.project().fut.poll(cx) {
+        let this = self.project();
+        match this.fut.poll(cx) {
             Poll::Ready(Ok(_l)) => Poll::Ready(Ok(())),
-            Poll::Ready(Err(e)) => Poll::Ready(Err(Error::from(e))),
+            Poll::Ready(Err(e)) => {
+                let e = Error::from(e);
+                this.errors.write_error(e);
+                Poll::Ready(Err(e))
+            },
             Poll::Pending => Poll::Pending
         }
     }
@@ -706,22 +1190,29 @@ impl Future for WritevAt {
 #[cfg(test)]
 mock!{
     pub VdevFile {
+        pub fn checksum_error(&self);
+        pub fn close(&mut self);
         #[mockall::concretize]
         pub fn create<P>(path: P, lbas_per_zone: Option<NonZeroU64>)
             -> io::Result<Self>
             where P: AsRef<Path>;
         pub fn erase_zone(&mut self, lba: LbaT) -> BoxVdevFut;
-        pub fn finish_zone(&self, _lba: LbaT) -> BoxVdevFut;
+        pub fn error_counts(&self) -> ErrorCounts;
+        pub fn finish_zone(&self, lba: LbaT) -> BoxVdevFut;
         #[mockall::concretize]
         pub async fn open<P>(path: P) -> Result<(Self, LabelReader)>
             where P: AsRef<Path>;
-        pub fn open_zone(&self, _lba: LbaT) -> BoxVdevFut;
+        #[mockall::concretize]
+        pub async fn open_checkpoint<P>(path: P) -> Result<(Self, LabelReader)>
+            where P: AsRef<Path>;
+        pub fn open_zone(&self, lba: LbaT) -> BoxVdevFut;
         pub fn read_at(&self, buf: IoVecMut, lba: LbaT) -> BoxVdevFut;
         pub fn read_spacemap(&self, buf: IoVecMut, idx: u32) -> BoxVdevFut;
         pub fn readv_at(&self, bufs: SGListMut, lba: LbaT) -> BoxVdevFut;
         pub fn spacemap_space(&self) -> LbaT;
         pub fn write_at(&self, buf: IoVec, lba: LbaT) -> BoxVdevFut;
         pub fn write_label(&self, mut label_writer: LabelWriter) -> BoxVdevFut;
+        pub fn write_pointer(&self, lba: LbaT) -> BoxWritePointerFut;
         pub fn write_spacemap(&self, buf: SGList, idx: u32, block: LbaT)
             -> BoxVdevFut;
         pub fn writev_at(&self, buf: SGList, lba: LbaT) -> BoxVdevFut;
@@ -750,7 +1241,8 @@ mod label {
         let label = Label{ uuid: Uuid::new_v4(),
             lbas_per_zone: 0,
             lbas: 0,
-            spacemap_space: 0
+            spacemap_space: 0,
+            errors: ErrorCounts::default()
         };
         format!("{label:?}");
     }
@@ -0,0 +1,299 @@
+// vim: tw=80
+//! Generic file system operations, for use by frontends
+//!
+//! A frontend, such as the FUSE server, translates some external protocol
+//! into calls on a [`Vfs`].  [`Fs`] is the real implementation, but frontends
+//! may be written generically, so they can be tested against a mock instead.
+
+use async_trait::async_trait;
+use divbuf::DivBuf;
+use futures::{StreamExt, stream::BoxStream};
+use libc::dev_t;
+use std::ffi::{OsStr, OsString};
+
+use crate::{
+    SGList,
+    fs::{
+        Advice,
+        ExtAttr,
+        ExtAttrNamespace,
+        FadviseStats,
+        FileData,
+        FileDataMut,
+        Fs,
+        GetAttr,
+        SeekWhence,
+        SetAttr
+    }
+};
+
+/// Operations on a single mounted file system.
+///
+/// Errors are reported as errno values.  See [`Fs`] for the meaning of each
+/// method.
+#[async_trait]
+pub trait Vfs: Send + Sync + 'static {
+    fn check_handle(&self, fh: u64) -> Result<(), i32>;
+    async fn create(&self, parent: &FileData, name: &OsStr, perm: u16,
+        uid: u32, gid: u32) -> Result<FileDataMut, i32>;
+    async fn deallocate(&self, fd: &FileData, offset: u64, len: u64)
+        -> Result<(), i32>;
+    async fn deleteextattr(&self, fd: &FileData, ns: ExtAttrNamespace,
+        name: &OsStr) -> Result<(), i32>;
+    async fn fadvise(&self, fd: &FileData, advice: Advice) -> Result<(), i32>;
+    fn fadvise_stats(&self) -> FadviseStats;
+    async fn fdatasync(&self, fd: &FileData) -> Result<(), i32>;
+    async fn fsync(&self, fd: &FileData) -> Result<(), i32>;
+    async fn getattr(&self, fd: &FileData) -> Result<GetAttr, i32>;
+    async fn getextattr(&self, fd: &FileData, ns: ExtAttrNamespace,
+        name: &OsStr) -> Result<DivBuf, i32>;
+    async fn getextattrlen(&self, fd: &FileData, ns: ExtAttrNamespace,
+        name: &OsStr) -> Result<u32, i32>;
+    async fn ilookup(&self, ino: u64) -> Result<FileDataMut, i32>;
+    async fn inactive(&self, fd: FileDataMut);
+    async fn link(&self, parent: &FileData, fd: &FileData, name: &OsStr)
+        -> Result<(), i32>;
+    async fn listextattr<F>(&self, fd: &FileData, size: u32, f: F)
+        -> Result<Vec<u8>, i32>
+        where F: Fn(&mut Vec<u8>, &ExtAttr) + Send + 'static;
+    async fn listextattrlen<F>(&self, fd: &FileData, f: F) -> Result<u32, i32>
+        where F: Fn(&ExtAttr) -> u32 + Send + 'static;
+    async fn lookup<'a>(&self, grandparent: Option<&'a FileData>,
+        parent: &'a FileData, name: &OsStr) -> Result<FileDataMut, i32>;
+    async fn lseek(&self, fd: &FileData, offset: u64, whence: SeekWhence)
+        -> Result<u64, i32>;
+    async fn mkblock(&self, parent: &FileData, name: &OsStr, perm: u16,
+        uid: u32, gid: u32, rdev: dev_t) -> Result<FileDataMut, i32>;
+    async fn mkchar(&self, parent: &FileData, name: &OsStr, perm: u16,
+        uid: u32, gid: u32, rdev: dev_t) -> Result<FileDataMut, i32>;
+    async fn mkdir(&self, parent: &FileData, name: &OsStr, perm: u16,
+        uid: u32, gid: u32) -> Result<FileDataMut, i32>;
+    async fn mkfifo(&self, parent: &FileData, name: &OsStr, perm: u16,
+        uid: u32, gid: u32) -> Result<FileDataMut, i32>;
+    async fn mksock(&self, parent: &FileData, name: &OsStr, perm: u16,
+        uid: u32, gid: u32) -> Result<FileDataMut, i32>;
+    fn open(&self, fd: &FileData, pid: u32, flags: u32) -> u64;
+    async fn read(&self, fd: &FileData, offset: u64, size: usize)
+        -> Result<SGList, i32>;
+    fn readdir(&self, fd: &FileData, soffs: i64)
+        -> BoxStream<'static, Result<(libc::dirent, i64), i32>>;
+    async fn readlink(&self, fd: &FileData) -> Result<OsString, i32>;
+    fn release(&self, fh: u64);
+    async fn rename<'a>(&self, parent: &'a FileData, fd: &'a FileData,
+        name: &'a OsStr, newparent: &'a FileData, newino: Option<u64>,
+        newname: &'a OsStr) -> Result<u64, i32>;
+    async fn rmdir(&self, parent: &FileData, name: &OsStr) -> Result<(), i32>;
+    fn root(&self) -> FileDataMut;
+    async fn setattr(&self, fd: &FileData, attr: SetAttr) -> Result<(), i32>;
+    async fn setextattr(&self, fd: &FileData, ns: ExtAttrNamespace,
+        name: &OsStr, data: &[u8]) -> Result<(), i32>;
+    async fn statvfs(&self) -> Result<libc::statvfs, i32>;
+    async fn symlink(&self, parent: &FileData, name: &OsStr, perm: u16,
+        uid: u32, gid: u32, link: &OsStr) -> Result<FileDataMut, i32>;
+    async fn sync(&self);
+    async fn unlink<'a>(&self, parent: &'a FileData, fd: Option<&'a FileData>,
+        name: &'a OsStr) -> Result<(), i32>;
+    async fn write(&self, fd: &FileData, offset: u64, data: &[u8], flags: u32)
+        -> Result<u32, i32>;
+}
+
+#[async_trait]
+impl Vfs for Fs {
+    fn check_handle(&self, fh: u64) -> Result<(), i32> {
+        Fs::check_handle(self, fh)
+    }
+
+    async fn create(&self, parent: &FileData, name: &OsStr, perm: u16,
+        uid: u32, gid: u32) -> Result<FileDataMut, i32>
+    {
+        Fs::create(self, parent, name, perm, uid, gid).await
+    }
+
+    async fn deallocate(&self, fd: &FileData, offset: u64, len: u64)
+        -> Result<(), i32>
+    {
+        Fs::deallocate(self, fd, offset, len).await
+    }
+
+    async fn deleteextattr(&self, fd: &FileData, ns: ExtAttrNamespace,
+        name: &OsStr) -> Result<(), i32>
+    {
+        Fs::deleteextattr(self, fd, ns, name).await
+    }
+
+    async fn fadvise(&self, fd: &FileData, advice: Advice) -> Result<(), i32> {
+        Fs::fadvise(self, fd, advice).await
+    }
+
+    fn fadvise_stats(&self) -> FadviseStats {
+        Fs::fadvise_stats(self)
+    }
+
+    async fn fdatasync(&self, fd: &FileData) -> Result<(), i32> {
+        Fs::fdatasync(self, fd).await
+    }
+
+    async fn fsync(&self, fd: &FileData) -> Result<(), i32> {
+        Fs::fsync(self, fd).await
+    }
+
+    async fn getattr(&self, fd: &FileData) -> Result<GetAttr, i32> {
+        Fs::getattr(self, fd).await
+    }
+
+    async fn getextattr(&self, fd: &FileData, ns: ExtAttrNamespace,
+        name: &OsStr) -> Result<DivBuf, i32>
+    {
+        Fs::getextattr(self, fd, ns, name).await
+    }
+
+    async fn getextattrlen(&self, fd: &FileData, ns: ExtAttrNamespace,
+        name: &OsStr) -> Result<u32, i32>
+    {
+        Fs::getextattrlen(self, fd, ns, name).await
+    }
+
+    async fn ilookup(&self, ino: u64) -> Result<FileDataMut, i32> {
+        Fs::ilookup(self, ino).await
+    }
+
+    async fn inactive(&self, fd: FileDataMut) {
+        Fs::inactive(self, fd).await
+    }
+
+    async fn link(&self, parent: &FileData, fd: &FileData, name: &OsStr)
+        -> Result<(), i32>
+    {
+        Fs::link(self, parent, fd, name).await
+    }
+
+    async fn listextattr<F>(&self, fd: &FileData, size: u32, f: F)
+        -> Result<Vec<u8>, i32>
+        where F: Fn(&mut Vec<u8>, &ExtAttr) + Send + 'static
+    {
+        Fs::listextattr(self, fd, size, f).await
+    }
+
+    async fn listextattrlen<F>(&self, fd: &FileData, f: F) -> Result<u32, i32>
+        where F: Fn(&ExtAttr) -> u32 + Send + 'static
+    {
+        Fs::listextattrlen(self, fd, f).await
+    }
+
+    async fn lookup<'a>(&self, grandparent: Option<&'a FileData>,
+        parent: &'a FileData, name: &OsStr) -> Result<FileDataMut, i32>
+    {
+        Fs::lookup(self, grandparent, parent, name).await
+    }
+
+    async fn lseek(&self, fd: &FileData, offset: u64, whence: SeekWhence)
+        -> Result<u64, i32>
+    {
+        Fs::lseek(self, fd, offset, whence).await
+    }
+
+    async fn mkblock(&self, parent: &FileData, name: &OsStr, perm: u16,
+        uid: u32, gid: u32, rdev: dev_t) -> Result<FileDataMut, i32>
+    {
+        Fs::mkblock(self, parent, name, perm, uid, gid, rdev).await
+    }
+
+    async fn mkchar(&self, parent: &FileData, name: &OsStr, perm: u16,
+        uid: u32, gid: u32, rdev: dev_t) -> Result<FileDataMut, i32>
+    {
+        Fs::mkchar(self, parent, name, perm, uid, gid, rdev).await
+    }
+
+    async fn mkdir(&self, parent: &FileData, name: &OsStr, perm: u16,
+        uid: u32, gid: u32) -> Result<FileDataMut, i32>
+    {
+        Fs::mkdir(self, parent, name, perm, uid, gid).await
+    }
+
+    async fn mkfifo(&self, parent: &FileData, name: &OsStr, perm: u16,
+        uid: u32, gid: u32) -> Result<FileDataMut, i32>
+    {
+        Fs::mkfifo(self, parent, name, perm, uid, gid).await
+    }
+
+    async fn mksock(&self, parent: &FileData, name: &OsStr, perm: u16,
+        uid: u32, gid: u32) -> Result<FileDataMut, i32>
+    {
+        Fs::mksock(self, parent, name, perm, uid, gid).await
+    }
+
+    fn open(&self, fd: &FileData, pid: u32, flags: u32) -> u64 {
+        Fs::open(self, fd, pid, flags)
+    }
+
+    async fn read(&self, fd: &FileData, offset: u64, size: usize)
+        -> Result<SGList, i32>
+    {
+        Fs::read(self, fd, offset, size).await
+    }
+
+    fn readdir(&self, fd: &FileData, soffs: i64)
+        -> BoxStream<'static, Result<(libc::dirent, i64), i32>>
+    {
+        Fs::readdir(self, fd, soffs).boxed()
+    }
+
+    async fn readlink(&self, fd: &FileData) -> Result<OsString, i32> {
+        Fs::readlink(self, fd).await
+    }
+
+    fn release(&self, fh: u64) {
+        Fs::release(self, fh)
+    }
+
+    async fn rename<'a>(&self, parent: &'a FileData, fd: &'a FileData,
+        name: &'a OsStr, newparent: &'a FileData, newino: Option<u64>,
+        newname: &'a OsStr) -> Result<u64, i32>
+    {
+        Fs::rename(self, parent, fd, name, newparent, newino, newname).await
+    }
+
+    async fn rmdir(&self, parent: &FileData, name: &OsStr) -> Result<(), i32> {
+        Fs::rmdir(self, parent, name).await
+    }
+
+    fn root(&self) -> FileDataMut {
+        Fs::root(self)
+    }
+
+    async fn setattr(&self, fd: &FileData, attr: SetAttr) -> Result<(), i32> {
+        Fs::setattr(self, fd, attr).await
+    }
+
+    async fn setextattr(&self, fd: &FileData, ns: ExtAttrNamespace,
+        name: &OsStr, data: &[u8]) -> Result<(), i32>
+    {
+        Fs::setextattr(self, fd, ns, name, data).await
+    }
+
+    async fn statvfs(&self) -> Result<libc::statvfs, i32> {
+        Fs::statvfs(self).await
+    }
+
+    async fn symlink(&self, parent: &FileData, name: &OsStr, perm: u16,
+        uid: u32, gid: u32, link: &OsStr) -> Result<FileDataMut, i32>
+    {
+        Fs::symlink(self, parent, name, perm, uid, gid, link).await
+    }
+
+    async fn sync(&self) {
+        Fs::sync(self).await
+    }
+
+    async fn unlink<'a>(&self, parent: &'a FileData, fd: Option<&'a FileData>,
+        name: &'a OsStr) -> Result<(), i32>
+    {
+        Fs::unlink(self, parent, fd, name).await
+    }
+
+    async fn write(&self, fd: &FileData, offset: u64, data: &[u8], flags: u32)
+        -> Result<u32, i32>
+    {
+        Fs::write(self, fd, offset, data, flags).await
+    }
+}
@@ -6,6 +6,7 @@ use futures::{
     future
 };
 use serde_derive::Serialize;
+use crate::memory::{Consumer, MemoryBudget};
 #[cfg(test)]
 use serde_derive::Deserialize;
 use std::{
@@ -146,6 +147,9 @@ struct Sleeper {
 /// It doesn't actually own the cached data; it just tracks how much there is.
 #[derive(Debug)]
 pub struct WriteBack {
+    /// Shared with other subsystems.  If set, borrowers must also wait for
+    /// room in the budget.
+    budget: Option<MemoryBudget>,
     capacity: isize,
     // Use isize instead of usize because it might temporarily go negative due
     // to the Relaxed ordering in the atomic fetch_sub
@@ -184,6 +188,28 @@ impl WriteBack {
 
     pub fn borrow(&self, size: usize)
         -> Pin<Box<dyn Future<Output=Credit> + Send>>
+    {
+        let fut = self.borrow_supply(size);
+        match &self.budget {
+            Some(budget) => {
+                let budget = budget.clone();
+                async move {
+                    // Wait for the budget first, because a future holding
+                    // granted Credit mustn't be dropped.  The charge will be
+                    // released as the credit gets repaid.
+                    let reservation = budget.reserve(Consumer::Writeback, size)
+                        .await;
+                    let credit = fut.await;
+                    reservation.forget();
+                    credit
+                }.boxed()
+            }
+            None => fut
+        }
+    }
+
+    fn borrow_supply(&self, size: usize)
+        -> Pin<Box<dyn Future<Output=Credit> + Send>>
     {
         let wants = size << 1;
         debug_assert!(self.capacity >= wants.try_into().unwrap());
@@ -246,6 +272,9 @@ impl WriteBack {
         let iwants = *credit.0.get_mut() as isize;
         let old_supply = self.supply.fetch_add(iwants, Acquire);
         debug_assert!(self.capacity >= (old_supply & !0x1) + iwants);
+        if let Some(budget) = &self.budget {
+            budget.release(Consumer::Writeback, (iwants >> 1) as usize);
+        }
 
         if old_supply & 0x1 == 0x1 {
             // There must be somebody to wake up
@@ -255,9 +284,18 @@ impl WriteBack {
         mem::forget(credit);
     }
 
+    /// Account for dirty data in a budget shared with other subsystems.
+    ///
+    /// The WriteBack will still never exceed its capacity, but borrowers may
+    /// have to wait for less when the others need the memory.
+    pub fn set_memory_budget(&mut self, budget: MemoryBudget) {
+        self.budget = Some(budget);
+    }
+
     pub fn with_capacity(capacity: usize) -> Self {
         let icapacity: isize = capacity.try_into().unwrap();
         WriteBack {
+            budget: None,
             capacity: icapacity << 1,
             supply: AtomicIsize::new(icapacity << 1),
             sleepers: Default::default()
@@ -476,6 +514,27 @@ fn take() {
     writeback.repay(credit0);
 }
 
+/// With a memory budget, borrowers must also wait for room in the budget
+#[test]
+fn memory_budget() {
+    let mut ctx = noop_context();
+    let budget = MemoryBudget::new(100);
+    let mut writeback = WriteBack::with_capacity(100);
+    writeback.set_memory_budget(budget.clone());
+    budget.charge(Consumer::Cache, 60);
+
+    let credit0 = writeback.borrow(30).now_or_never().unwrap();
+    assert_eq!(budget.used(Consumer::Writeback), 30);
+    // The WriteBack has plenty of supply, but the budget only has 10 bytes left
+    let mut fut1 = writeback.borrow(20);
+    assert!(fut1.as_mut().poll(&mut ctx).is_pending());
+    writeback.repay(credit0);
+    let credit1 = fut1.now_or_never().unwrap();
+    assert_eq!(budget.used(Consumer::Writeback), 20);
+    writeback.repay(credit1);
+    assert_eq!(budget.used(Consumer::Writeback), 0);
+}
+
 /// As soon as one borrower must sleep, all borrowers must sleep, even those
 /// whose credit needs are small enough to be satisfied.  That prevents any
 /// borrower from sleeping indefinitely.
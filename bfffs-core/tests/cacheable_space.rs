@@ -89,6 +89,14 @@ impl DML for StubDML {
         unimplemented!()
     }
 
+    fn put_ditto<T: Cacheable>(&self, _cacheable: T,
+                               _compression: Compression, _txg: TxgT)
+        -> Pin<Box<dyn Future<Output=Result<(<Self as DML>::Addr,
+                                             <Self as DML>::Addr)>> + Send>>
+    {
+        unimplemented!()
+    }
+
     fn repay(&self, _credit: Credit)
     {
         unimplemented!()
@@ -399,7 +407,8 @@ fn fs_leaf_inode(wb: &WriteBack, n: usize) -> Box<dyn CacheableForgetable> {
             uid: 0,
             gid: 0,
             perm: 0o644,
-            file_type: FileType::Reg(17)
+            file_type: FileType::Reg(17),
+            change: 0
         };
         let v = FSValue::inode(inode);
         let credit = borrow_credit(wb, &v);
@@ -1,10 +1,12 @@
 // vim: tw=80
 use bfffs_core::{
     cache::*,
+    cleaner::CleanPolicy,
     database::*,
     ddml::*,
     fs::*,
     idml::*,
+    job::Progress,
 };
 use rstest::rstest;
 use std::{
@@ -55,7 +57,12 @@ async fn sleep_sync_sync(#[case] devsize: u64, #[case] zone_size: u64) {
 #[rstest]
 #[case(1 << 20, 32)]
 #[tokio::test]
-async fn clean_zone(#[case] devsize: u64, #[case] zone_size: u64) {
+async fn clean_zone(
+    #[case] devsize: u64,
+    #[case] zone_size: u64,
+    #[values(CleanPolicy::Mixed, CleanPolicy::Generational{cold_age: 0})]
+    policy: CleanPolicy)
+{
     let (db, fs) = harness(devsize, zone_size).await;
     let root = fs.root();
     let rooth = root.handle();
@@ -79,8 +86,15 @@ async fn clean_zone(#[case] devsize: u64, #[case] zone_size: u64) {
     fs.unlink(&rooth, Some(&big_fdh), &big_filename).await.unwrap();
     fs.sync().await;
 
-    db.clean().await.unwrap();
+    db.clean(policy, Progress::default()).unwrap().await.unwrap();
     fs.sync().await;
+
+    // The cleaner should've measured its own contribution to write
+    // amplification
+    let stats = db.clean_plan(policy);
+    assert!(stats.rewritten > 0);
+    assert!(stats.written > stats.rewritten);
+    assert!(stats.write_amplification() > 1.0);
 }
 
 #[ignore = "Test is slow" ]
@@ -105,7 +119,7 @@ async fn clean_zone_leak(#[case] devsize: u64, #[case] zone_size: u64) {
     println!("Before cleaning: {:?} free out of {:?}",
              statvfs.f_bfree, statvfs.f_blocks);
     assert!(db.check().await.unwrap());
-    db.clean().await.unwrap();
+    db.clean(CleanPolicy::Mixed, Progress::default()).unwrap().await.unwrap();
     statvfs = fs.statvfs().await.unwrap();
     println!("After cleaning: {:?} free out of {:?}",
              statvfs.f_bfree, statvfs.f_blocks);
@@ -141,7 +155,7 @@ async fn get_mut(#[case] devsize: u64, #[case] zone_size: u64) {
     let mut statvfs = fs.statvfs().await.unwrap();
     println!("Before cleaning: {:?} free out of {:?}",
              statvfs.f_bfree, statvfs.f_blocks);
-    db.clean().await.unwrap();
+    db.clean(CleanPolicy::Mixed, Progress::default()).unwrap().await.unwrap();
     statvfs = fs.statvfs().await.unwrap();
     println!("After cleaning: {:?} free out of {:?}",
              statvfs.f_bfree, statvfs.f_blocks);
@@ -16,23 +16,28 @@ mod persistence {
 
     // To regenerate this literal, dump the binary label using this command:
     // hexdump -e '8/1 "0x%02x, " " // "' -e '8/1 "%_p" "\n"' /tmp/label.bin
-    const GOLDEN_LABEL: [u8; 172] = [
+    const GOLDEN_LABEL: [u8; 212] = [
         // First the VdevFile label
         0x42, 0x46, 0x46, 0x46, 0x53, 0x20, 0x56, 0x64, // BFFFS Vd
-        0x65, 0x76, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ev......
-        0xca, 0xf4, 0x51, 0x4b, 0x59, 0x00, 0x76, 0x4a,
-        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x64,
+        0x65, 0x76, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, // ev......
+        0xf3, 0xe5, 0x67, 0xfe, 0xf3, 0xa2, 0x61, 0xfd,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xb4,
         0x30, 0x55, 0xe2, 0x7d, 0x68, 0xeb, 0x4c, 0x96,
         0xbd, 0x50, 0x88, 0xe4, 0x3f, 0x92, 0xe8, 0x48,
         0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
         0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00,
         0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
         // Then the mirror label
         0xb9, 0xd8, 0x56, 0x54, 0xbd, 0xe4, 0x40, 0xe5,
         0xa2, 0xfb, 0x7b, 0x8b, 0xb6, 0x17, 0xff, 0x55,
         0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
         0x57, 0x33, 0x3b, 0x93, 0xce, 0xea, 0x44, 0x42,
         0xa6, 0xd2, 0x47, 0x07, 0x26, 0x74, 0x76, 0xdb,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
         // Then the raid label
         0x00, 0x00, 0x00, 0x00, 0x86, 0x82, 0x03, 0x1d,
         0x3a, 0x06, 0x4b, 0x4a, 0xb0, 0xb5, 0x5e, 0x85,
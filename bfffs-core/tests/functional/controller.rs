@@ -1,16 +1,27 @@
 // vim: tw=80
 use bfffs_core::{
     Error,
+    TxgT,
     cache::*,
-    controller::Controller,
+    capacity::Change,
+    cleaner::CleanPolicy,
+    controller::{Controller, VOLUME_FILE},
     database::Database,
     ddml::*,
+    fs::Fs,
     idml::*,
-    property::{Property, PropertyName, PropertySource},
+    property::{
+        Property,
+        PropertyName,
+        PropertySource,
+        SyncPolicy,
+        UserProperty
+    },
 };
 use futures::TryStreamExt;
 use rstest::{fixture, rstest};
 use std::{
+    ffi::OsStr,
     fs,
     sync::{Arc, Mutex}
 };
@@ -106,6 +117,293 @@ mod create_fs {
             Error::ENOENT
         )
     }
+
+    /// Redundant separators should be ignored
+    #[rstest]
+    #[tokio::test]
+    async fn normalized_name(harness: Harness) {
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        harness.0.create_fs(&format!("{POOLNAME}//child/")).await.unwrap();
+        harness.0.new_fs(&format!("{POOLNAME}/child")).await.unwrap();
+    }
+
+    /// The pool name must be a whole component, not just a prefix
+    #[rstest]
+    #[tokio::test]
+    async fn pool_name_prefix(harness: Harness) {
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        assert_eq!(
+            harness.0.create_fs(&format!("{POOLNAME}child")).await
+                .unwrap_err(),
+            Error::ENOENT
+        )
+    }
+}
+
+mod create_volume {
+    use super::*;
+
+    /// A new volume's data file should be exactly as large as the volume
+    #[rstest]
+    #[tokio::test]
+    async fn ok(harness: Harness) {
+        let volname = format!("{POOLNAME}/vol");
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        harness.0.create_volume(&volname, 1 << 20, vec![]).await.unwrap();
+        assert_eq!(
+            (Property::Volsize(1 << 20), PropertySource::LOCAL),
+            harness.0.get_prop(volname.clone(), PropertyName::Volsize)
+                .await
+                .unwrap()
+        );
+        let fs = harness.0.new_fs(&volname).await.unwrap();
+        let root = fs.root();
+        let fd = fs.lookup(None, &root.handle(), OsStr::new(VOLUME_FILE))
+            .await
+            .unwrap();
+        let attr = fs.getattr(&fd.handle()).await.unwrap();
+        assert_eq!(attr.size, 1 << 20);
+        // Volumes use smaller records than file systems, by default
+        assert_eq!(attr.blksize, 16384);
+    }
+
+    /// Volume sizes must be a multiple of the block size
+    #[rstest]
+    #[tokio::test]
+    async fn einval(harness: Harness) {
+        let volname = format!("{POOLNAME}/vol");
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        assert_eq!(
+            harness.0.create_volume(&volname, 1000, vec![]).await.unwrap_err(),
+            Error::EINVAL
+        );
+    }
+
+    /// Volumes may not have children
+    #[rstest]
+    #[tokio::test]
+    async fn enotdir(harness: Harness) {
+        let volname = format!("{POOLNAME}/vol");
+        let childname = format!("{POOLNAME}/vol/child");
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        harness.0.create_volume(&volname, 1 << 20, vec![]).await.unwrap();
+        assert_eq!(
+            harness.0.create_fs(&childname).await.unwrap_err(),
+            Error::ENOTDIR
+        );
+    }
+
+    /// Properties should be set before the data file is created
+    #[rstest]
+    #[tokio::test]
+    async fn recordsize(harness: Harness) {
+        let volname = format!("{POOLNAME}/vol");
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        let props = vec![Property::RecordSize(12)];
+        harness.0.create_volume(&volname, 1 << 20, props).await.unwrap();
+        let fs = harness.0.new_fs(&volname).await.unwrap();
+        let root = fs.root();
+        let fd = fs.lookup(None, &root.handle(), OsStr::new(VOLUME_FILE))
+            .await
+            .unwrap();
+        let attr = fs.getattr(&fd.handle()).await.unwrap();
+        assert_eq!(attr.blksize, 4096);
+    }
+}
+
+mod capacity_plan {
+    use super::*;
+
+    /// Adding a disk adds its capacity, but doesn't move anything
+    #[rstest]
+    #[tokio::test]
+    async fn add_disk(harness: Harness) {
+        let change = Change::AddCluster {
+            disks_per_stripe: 1,
+            redundancy: 0,
+            mirrors: vec![vec![1 << 30]]
+        };
+        let plan = harness.0.capacity_plan(POOLNAME, &change).unwrap();
+        assert_eq!(plan.size_after, plan.size_before + (1 << 30));
+        assert_eq!(plan.redundancy_before, 0);
+        assert_eq!(plan.redundancy_after, 0);
+        assert_eq!(plan.moved, 0);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn enoent(harness: Harness) {
+        let leaf = harness.0.leaf_status(POOLNAME).unwrap()[0].uuid;
+        let change = Change::RemoveCluster(leaf);
+        assert_eq!(
+            harness.0.capacity_plan("NoExistPool", &change).unwrap_err(),
+            Error::ENOENT
+        );
+    }
+
+    /// A pool's only cluster can't be removed
+    #[rstest]
+    #[tokio::test]
+    async fn remove_last(harness: Harness) {
+        let leaf = harness.0.leaf_status(POOLNAME).unwrap()[0].uuid;
+        let change = Change::RemoveCluster(leaf);
+        assert_eq!(
+            harness.0.capacity_plan(POOLNAME, &change).unwrap_err(),
+            Error::EINVAL
+        );
+    }
+}
+
+mod clean_plan {
+    use super::*;
+
+    /// A checkpoint prevents cleaning, but not planning
+    #[rstest]
+    #[tokio::test]
+    async fn checkpoint(harness: Harness) {
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        harness.0.checkpoint(POOLNAME, false).await.unwrap();
+        let stats = harness.0.clean_plan(POOLNAME, CleanPolicy::Mixed).unwrap();
+        assert_eq!(stats.reclaimable, 0);
+        assert_eq!(stats.pinned, stats.freed);
+        assert_eq!(
+            harness.0.clean(POOLNAME, CleanPolicy::Mixed).err(),
+            Some(Error::EBUSY)
+        );
+    }
+
+    /// A freshly created pool has nothing to clean
+    #[rstest]
+    #[tokio::test]
+    async fn empty(harness: Harness) {
+        let stats = harness.0.clean_plan(POOLNAME, CleanPolicy::Mixed).unwrap();
+        assert_eq!(stats.zones, 0);
+        assert_eq!(stats.moved, 0);
+        assert_eq!(stats.freed, 0);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn enoent(harness: Harness) {
+        assert_eq!(
+            harness.0
+                .clean_plan("NoExistPool", CleanPolicy::Mixed)
+                .unwrap_err(),
+            Error::ENOENT
+        );
+    }
+}
+
+mod compact_fs {
+    use super::*;
+
+    /// A freshly created file system has nothing to compact
+    #[rstest]
+    #[tokio::test]
+    async fn empty(harness: Harness) {
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        assert_eq!(harness.0.compact_fs(POOLNAME).await.unwrap(), 0);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn enoent(harness: Harness) {
+        let fsname = format!("{POOLNAME}/child");
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        assert_eq!(
+            harness.0.compact_fs(&fsname).await.unwrap_err(),
+            Error::ENOENT
+        );
+    }
+}
+
+mod destroy_fs {
+    use super::*;
+
+    /// Destroying a file system must invalidate its cached name
+    #[rstest]
+    #[tokio::test]
+    async fn recreate(harness: Harness) {
+        let fsname = format!("{POOLNAME}/child");
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        harness.0.create_fs(&fsname).await.unwrap();
+        harness.0.get_prop(fsname.clone(), PropertyName::Atime).await.unwrap();
+        harness.0.destroy_fs(&fsname).await.unwrap();
+        assert_eq!(
+            harness.0.get_prop(fsname.clone(), PropertyName::Atime).await
+                .unwrap_err(),
+            Error::ENOENT
+        );
+
+        // And a new one with the same name must be found again
+        harness.0.create_fs(&fsname).await.unwrap();
+        harness.0.set_prop(&fsname, Property::Atime(false)).await.unwrap();
+        assert_eq!(
+            harness.0.get_prop(fsname.clone(), PropertyName::Atime).await
+                .unwrap(),
+            (Property::Atime(false), PropertySource::LOCAL)
+        );
+    }
+
+    /// Names should be normalized before they're cached
+    #[rstest]
+    #[tokio::test]
+    async fn unnormalized(harness: Harness) {
+        let fsname = format!("{POOLNAME}/child");
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        harness.0.create_fs(&fsname).await.unwrap();
+        harness.0.get_prop(format!("{fsname}/"), PropertyName::Atime).await
+            .unwrap();
+        harness.0.destroy_fs(&fsname).await.unwrap();
+        assert_eq!(
+            harness.0.get_prop(format!("{fsname}/"), PropertyName::Atime)
+                .await
+                .unwrap_err(),
+            Error::ENOENT
+        );
+    }
+}
+
+mod destroy_fs_plan {
+    use super::*;
+
+    /// A dry run should report the file system, but not destroy it
+    #[rstest]
+    #[tokio::test]
+    async fn child(harness: Harness) {
+        let fsname = format!("{POOLNAME}/child");
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        harness.0.create_fs(&fsname).await.unwrap();
+        assert_eq!(
+            harness.0.destroy_fs_plan(&fsname).await.unwrap(),
+            vec![fsname.clone()]
+        );
+        harness.0.new_fs(&fsname).await.unwrap();
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn enoent(harness: Harness) {
+        let fsname = format!("{POOLNAME}/child");
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        assert_eq!(
+            harness.0.destroy_fs_plan(&fsname).await.unwrap_err(),
+            Error::ENOENT
+        );
+    }
+
+    /// Can't destroy a file system that has children
+    #[rstest]
+    #[tokio::test]
+    async fn parent(harness: Harness) {
+        let fsname = format!("{POOLNAME}/child");
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        harness.0.create_fs(&fsname).await.unwrap();
+        assert_eq!(
+            harness.0.destroy_fs_plan(POOLNAME).await.unwrap_err(),
+            Error::EBUSY
+        );
+    }
 }
 
 mod get_prop {
@@ -118,11 +416,44 @@ mod get_prop {
             PropertyName::BaseMountpoint =>
                 Property::BaseMountpoint("/xxx".to_owned()),
             PropertyName::Mountpoint => Property::Mountpoint("/xxx".to_owned()),
+            PropertyName::Mounted => unimplemented!(),
             PropertyName::Name => unimplemented!(),
             PropertyName::RecordSize => Property::RecordSize(15),
+            PropertyName::Sync => Property::Sync(SyncPolicy::Always),
+            PropertyName::Devices => Property::Devices(false),
+            PropertyName::Exec => Property::Exec(false),
+            PropertyName::Setuid => Property::Setuid(false),
+            PropertyName::Utf8Only => Property::Utf8Only(true),
+            PropertyName::Share9p =>
+                Property::Share9p("127.0.0.1:564".to_owned()),
+            PropertyName::Volsize => unimplemented!(),
+            PropertyName::ShareIscsi =>
+                Property::ShareIscsi("127.0.0.1:3260".to_owned()),
+            PropertyName::DirtyLimit => Property::DirtyLimit(1 << 20),
+            PropertyName::Coalesce => Property::Coalesce(1 << 16),
+            PropertyName::LastMounted => unimplemented!(),
+            PropertyName::MountCount => unimplemented!(),
+            PropertyName::CleanUnmount => unimplemented!(),
+            PropertyName::DirSync => Property::DirSync(true),
+            PropertyName::Copies => Property::Copies(2),
+            PropertyName::CanMount => Property::CanMount(false),
         }
     }
 
+    /// canmount applies only to the file system it's set on
+    #[rstest]
+    #[tokio::test]
+    async fn canmount_not_inherited(harness: Harness) {
+        let dsname = format!("{POOLNAME}/child");
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        harness.0.create_fs(&dsname).await.unwrap();
+        harness.0.set_prop(POOLNAME, Property::CanMount(false)).await.unwrap();
+        assert_eq!(
+            (Property::CanMount(true), PropertySource::Default),
+            harness.0.get_prop(dsname, PropertyName::CanMount).await.unwrap()
+        );
+    }
+
     // Try to lookup a property for a dataset that does not exist
     #[rstest]
     #[tokio::test]
@@ -138,14 +469,28 @@ mod get_prop {
     #[rstest(propname,
         case(PropertyName::Atime),
         case(PropertyName::RecordSize),
-        case(PropertyName::Mountpoint)
+        case(PropertyName::Mountpoint),
+        case(PropertyName::Sync),
+        case(PropertyName::Devices),
+        case(PropertyName::Exec),
+        case(PropertyName::Setuid),
+        case(PropertyName::Utf8Only),
+        case(PropertyName::Share9p),
+        case(PropertyName::ShareIscsi),
+        case(PropertyName::DirtyLimit),
+        case(PropertyName::Coalesce),
+        case(PropertyName::DirSync),
+        case(PropertyName::Copies),
+        case(PropertyName::CanMount)
     )]
     fn all_props(#[case] propname: PropertyName) {}
 
     #[template]
     #[rstest(propname,
         case(PropertyName::Atime),
-        case(PropertyName::RecordSize)
+        case(PropertyName::RecordSize),
+        case(PropertyName::Sync),
+        case(PropertyName::Exec)
     )]
     fn inheritable_props(#[case] propname: PropertyName) {}
 
@@ -388,6 +733,21 @@ mod list_fs {
         assert_eq!(dsname2, datasets3[0].name);
     }
 
+    /// The children's names should be normalized even if the parent's isn't
+    #[rstest]
+    #[tokio::test]
+    async fn trailing_slash(harness: Harness) {
+        let dsname = format!("{POOLNAME}/child");
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        harness.0.create_fs(&dsname).await.unwrap();
+        let datasets = harness.0.list_fs(&format!("{POOLNAME}/"), None)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(1, datasets.len());
+        assert_eq!(dsname, datasets[0].name);
+    }
+
     #[rstest]
     #[tokio::test]
     async fn one_grandchild(harness: Harness) {
@@ -411,6 +771,207 @@ mod list_fs {
     }
 }
 
+mod move_file {
+    use super::*;
+
+    /// Create a root file system containing "d/f", and an empty child file
+    /// system.  Return the root file system and the contents of "f".
+    async fn populate(harness: &Harness) -> (Arc<Fs>, Vec<u8>) {
+        let childname = format!("{POOLNAME}/child");
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        harness.0.create_fs(&childname).await.unwrap();
+        let fs = harness.0.new_fs(POOLNAME).await.unwrap();
+        let root = fs.root();
+        let d = fs.mkdir(&root.handle(), OsStr::new("d"), 0o755, 0, 0)
+            .await
+            .unwrap();
+        let f = fs.create(&d.handle(), OsStr::new("f"), 0o644, 0, 0)
+            .await
+            .unwrap();
+        let buf = (0..65536u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        fs.write(&f.handle(), 0, &buf[..], 0).await.unwrap();
+        (fs, buf)
+    }
+
+    /// Read a whole file from the root of the named file system
+    async fn read_file(harness: &Harness, fsname: &str, name: &str)
+        -> Vec<u8>
+    {
+        let fs = harness.0.new_fs(fsname).await.unwrap();
+        let root = fs.root();
+        let fd = fs.lookup(None, &root.handle(), OsStr::new(name))
+            .await
+            .unwrap();
+        let attr = fs.getattr(&fd.handle()).await.unwrap();
+        assert_eq!(attr.nlink, 1);
+        let sglist = fs.read(&fd.handle(), 0, attr.size as usize)
+            .await
+            .unwrap();
+        sglist.iter().flat_map(|db| db.iter().cloned()).collect()
+    }
+
+    /// The source is a directory
+    #[rstest]
+    #[tokio::test]
+    async fn directory(harness: Harness) {
+        let childname = format!("{POOLNAME}/child");
+        let (fs, _) = populate(&harness).await;
+        drop(fs);
+        assert_eq!(
+            harness.0.move_file(POOLNAME, "d", &childname, "d").await,
+            Err(Error::EXDEV)
+        );
+    }
+
+    /// The destination already exists
+    #[rstest]
+    #[tokio::test]
+    async fn eexist(harness: Harness) {
+        let (fs, _) = populate(&harness).await;
+        drop(fs);
+        let childname = format!("{POOLNAME}/child");
+        harness.0.move_file(POOLNAME, "d/f", &childname, "g").await.unwrap();
+        let fs = harness.0.new_fs(POOLNAME).await.unwrap();
+        let root = fs.root();
+        let d = fs.lookup(None, &root.handle(), OsStr::new("d"))
+            .await
+            .unwrap();
+        fs.create(&d.handle(), OsStr::new("f"), 0o644, 0, 0).await.unwrap();
+        drop(fs);
+        assert_eq!(
+            harness.0.move_file(POOLNAME, "d/f", &childname, "g").await,
+            Err(Error::EEXIST)
+        );
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn enoent(harness: Harness) {
+        let childname = format!("{POOLNAME}/child");
+        let (fs, _) = populate(&harness).await;
+        drop(fs);
+        assert_eq!(
+            harness.0.move_file(POOLNAME, "d/nonexistent", &childname, "g")
+                .await,
+            Err(Error::ENOENT)
+        );
+        assert_eq!(
+            harness.0.move_file(POOLNAME, "d/f", &childname, "x/g").await,
+            Err(Error::ENOENT)
+        );
+    }
+
+    /// Within a single file system, rename should be used instead
+    #[rstest]
+    #[tokio::test]
+    async fn same_fs(harness: Harness) {
+        let (fs, _) = populate(&harness).await;
+        drop(fs);
+        assert_eq!(
+            harness.0.move_file(POOLNAME, "d/f", POOLNAME, "g").await,
+            Err(Error::EINVAL)
+        );
+    }
+
+    /// Move a file out of a file system that's currently open
+    #[rstest]
+    #[tokio::test]
+    async fn mounted(harness: Harness) {
+        let childname = format!("{POOLNAME}/child");
+        let (fs, buf) = populate(&harness).await;
+        harness.0.move_file(POOLNAME, "d/f", &childname, "g").await.unwrap();
+        let root = fs.root();
+        let d = fs.lookup(None, &root.handle(), OsStr::new("d"))
+            .await
+            .unwrap();
+        assert_eq!(
+            fs.lookup(Some(&root.handle()), &d.handle(), OsStr::new("f"))
+                .await
+                .unwrap_err(),
+            libc::ENOENT
+        );
+        assert_eq!(read_file(&harness, &childname, "g").await, buf);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn ok(harness: Harness) {
+        let childname = format!("{POOLNAME}/child");
+        let (fs, buf) = populate(&harness).await;
+        drop(fs);
+        harness.0.move_file(POOLNAME, "d/f", &childname, "g").await.unwrap();
+        assert_eq!(read_file(&harness, &childname, "g").await, buf);
+
+        // Move it back, to show that the data survives the source's deletion
+        harness.0.move_file(&childname, "g", POOLNAME, "h").await.unwrap();
+        assert_eq!(read_file(&harness, POOLNAME, "h").await, buf);
+    }
+}
+
+mod record_mount {
+    use super::*;
+
+    async fn history(harness: &Harness, name: &str) -> (u64, u64, bool) {
+        let mut r = Vec::new();
+        for propname in [PropertyName::LastMounted, PropertyName::MountCount,
+                         PropertyName::CleanUnmount]
+        {
+            let (prop, source) = harness.0.get_prop(name.to_owned(), propname)
+                .await
+                .unwrap();
+            assert_eq!(source, PropertySource::None);
+            r.push(prop);
+        }
+        (r[0].as_u64(), r[1].as_u64(), r[2].as_bool())
+    }
+
+    /// A file system that's never been mounted has an empty history
+    #[rstest]
+    #[tokio::test]
+    async fn never(harness: Harness) {
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        assert_eq!((0, 0, true), history(&harness, POOLNAME).await);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn clean(harness: Harness) {
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        assert!(harness.0.record_mount(POOLNAME).await.unwrap());
+        let (last, count, clean) = history(&harness, POOLNAME).await;
+        assert!(last > 0);
+        assert_eq!(count, 1);
+        assert!(!clean);
+
+        harness.0.record_unmount(POOLNAME).await.unwrap();
+        assert_eq!((last, 1, true), history(&harness, POOLNAME).await);
+        assert!(harness.0.record_mount(POOLNAME).await.unwrap());
+        assert_eq!(2, history(&harness, POOLNAME).await.1);
+    }
+
+    /// Mounting again without unmounting first should report that the
+    /// previous mount was unclean.
+    #[rstest]
+    #[tokio::test]
+    async fn unclean(harness: Harness) {
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        assert!(harness.0.record_mount(POOLNAME).await.unwrap());
+        assert!(!harness.0.record_mount(POOLNAME).await.unwrap());
+        assert_eq!(2, history(&harness, POOLNAME).await.1);
+    }
+
+    /// Mount history isn't inherited
+    #[rstest]
+    #[tokio::test]
+    async fn child(harness: Harness) {
+        let childname = format!("{POOLNAME}/child");
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        harness.0.create_fs(&childname).await.unwrap();
+        harness.0.record_mount(POOLNAME).await.unwrap();
+        assert_eq!((0, 0, true), history(&harness, &childname).await);
+    }
+}
+
 mod set_prop {
     use super::*;
 
@@ -424,6 +985,24 @@ mod set_prop {
         );
     }
 
+    /// Setting a property on a parent should immediately affect any mounted
+    /// children that inherit it.
+    #[rstest]
+    #[tokio::test]
+    async fn inherited_by_mounted_child(harness: Harness) {
+        let childname = format!("{POOLNAME}/child");
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        harness.0.create_fs(&childname).await.unwrap();
+        let fs = harness.0.new_fs(&childname).await.unwrap();
+        harness.0.set_prop(POOLNAME, Property::RecordSize(13)).await.unwrap();
+        let root = fs.root();
+        let fd = fs.create(&root.handle(), OsStr::new("x"), 0o644, 0, 0)
+            .await
+            .unwrap();
+        let attr = fs.getattr(&fd.handle()).await.unwrap();
+        assert_eq!(attr.blksize, 8192);
+    }
+
     #[rstest]
     #[tokio::test]
     async fn mounted(harness: Harness) {
@@ -449,6 +1028,30 @@ mod set_prop {
         harness.0.set_prop(POOLNAME, Property::Atime(false)).await.unwrap();
     }
 
+    /// Only bfffsd may record mount history
+    #[rstest]
+    #[tokio::test]
+    async fn mount_history(harness: Harness) {
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        assert_eq!(
+            Err(Error::EINVAL),
+            harness.0.set_prop(POOLNAME, Property::MountCount(0)).await
+        );
+    }
+
+    /// Volumes can't be resized
+    #[rstest]
+    #[tokio::test]
+    async fn volsize(harness: Harness) {
+        let volname = format!("{POOLNAME}/vol");
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        harness.0.create_volume(&volname, 1 << 20, vec![]).await.unwrap();
+        assert_eq!(
+            Err(Error::EINVAL),
+            harness.0.set_prop(&volname, Property::Volsize(1 << 21)).await
+        );
+    }
+
     mod mountpoint {
         use super::*;
 
@@ -462,3 +1065,180 @@ mod set_prop {
         }
     }
 }
+
+mod status {
+    use super::*;
+
+    #[rstest]
+    #[tokio::test]
+    async fn enoent(harness: Harness) {
+        assert_eq!(Err(Error::ENOENT), harness.0.status("Nonexistent"));
+    }
+
+    /// Syncing must write something, so it must open a zone
+    #[rstest]
+    #[tokio::test]
+    async fn synced(harness: Harness) {
+        harness.0.sync_transaction().await.unwrap();
+        let status = harness.0.status(POOLNAME).unwrap();
+        assert_eq!(status.leaves.len(), 1);
+        assert!(status.zones.opened > 0);
+        assert_eq!(status.zones.evicted, 0);
+    }
+}
+
+mod txgs {
+    use super::*;
+
+    #[rstest]
+    #[tokio::test]
+    async fn enoent(harness: Harness) {
+        assert_eq!(Err(Error::ENOENT), harness.0.txgs("Nonexistent").await);
+    }
+
+    /// A new pool hasn't synced anything yet
+    #[rstest]
+    #[tokio::test]
+    async fn fresh(harness: Harness) {
+        let txgs = harness.0.txgs(POOLNAME).await.unwrap();
+        assert_eq!(txgs.current, TxgT::from(0));
+        assert_eq!(txgs.synced, None);
+        assert_eq!(txgs.checkpoint, None);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn synced(harness: Harness) {
+        harness.0.sync_transaction().await.unwrap();
+        let txgs = harness.0.txgs(POOLNAME).await.unwrap();
+        assert_eq!(txgs.current, TxgT::from(1));
+        assert_eq!(txgs.synced, Some(TxgT::from(0)));
+        assert_eq!(txgs.checkpoint, None);
+    }
+}
+
+mod user_props {
+    use super::*;
+
+    fn uprop(name: &str, value: &str) -> UserProperty {
+        UserProperty {
+            name: name.to_owned(),
+            value: value.to_owned()
+        }
+    }
+
+    /// Clearing a property reverts it to the inherited value, if any
+    #[rstest]
+    #[tokio::test]
+    async fn clear(harness: Harness) {
+        let childname = format!("{POOLNAME}/child");
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        harness.0.create_fs(&childname).await.unwrap();
+        harness.0.set_user_prop(POOLNAME, uprop("user:foo", "parent"))
+            .await.unwrap();
+        harness.0.set_user_prop(&childname, uprop("user:foo", "child"))
+            .await.unwrap();
+        harness.0.set_user_prop(&childname, uprop("user:foo", ""))
+            .await.unwrap();
+        assert_eq!(
+            harness.0.get_user_props(&childname).await.unwrap(),
+            vec![(uprop("user:foo", "parent"), PropertySource::FROM_PARENT)]
+        );
+    }
+
+    /// Clearing a property that was never set is not an error
+    #[rstest]
+    #[tokio::test]
+    async fn clear_unset(harness: Harness) {
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        harness.0.set_user_prop(POOLNAME, uprop("user:foo", ""))
+            .await.unwrap();
+        assert!(harness.0.get_user_props(POOLNAME).await.unwrap().is_empty());
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn einval(harness: Harness) {
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        let r = harness.0.set_user_prop(POOLNAME, uprop("nocolon", "x")).await;
+        assert_eq!(Err(Error::EINVAL), r);
+        let value = "x".repeat(UserProperty::VALUE_MAX + 1);
+        let r = harness.0.set_user_prop(POOLNAME, uprop("user:foo", &value))
+            .await;
+        assert_eq!(Err(Error::EINVAL), r);
+    }
+
+    #[rstest]
+    #[tokio::test]
+    async fn enoent(harness: Harness) {
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        let childname = format!("{POOLNAME}/child");
+        let r = harness.0.set_user_prop(&childname, uprop("user:foo", "x"))
+            .await;
+        assert_eq!(Err(Error::ENOENT), r);
+        let r = harness.0.get_user_props(&childname).await;
+        assert_eq!(Err(Error::ENOENT), r);
+    }
+
+    /// Properties set on a parent are inherited, but a child's own setting
+    /// takes precedence.
+    #[rstest]
+    #[tokio::test]
+    async fn inherited(harness: Harness) {
+        let childname = format!("{POOLNAME}/child");
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        harness.0.create_fs(&childname).await.unwrap();
+        harness.0.set_user_prop(POOLNAME, uprop("user:bar", "parent"))
+            .await.unwrap();
+        harness.0.set_user_prop(POOLNAME, uprop("user:foo", "parent"))
+            .await.unwrap();
+        harness.0.set_user_prop(&childname, uprop("user:foo", "child"))
+            .await.unwrap();
+        assert_eq!(
+            harness.0.get_user_props(&childname).await.unwrap(),
+            vec![
+                (uprop("user:bar", "parent"), PropertySource::FROM_PARENT),
+                (uprop("user:foo", "child"), PropertySource::LOCAL),
+            ]
+        );
+    }
+
+    /// Values may be any Unicode, and large values are stored out of line.
+    #[rstest]
+    #[case("daily".to_owned())]
+    #[case("☃ snowman".to_owned())]
+    #[case("x".repeat(UserProperty::VALUE_MAX))]
+    #[tokio::test]
+    async fn local(harness: Harness, #[case] value: String) {
+        let value = &value[..];
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        harness.0.set_user_prop(POOLNAME, uprop("user:backup-policy", value))
+            .await.unwrap();
+        harness.0.sync_transaction().await.unwrap();
+        harness.0.drop_cache();
+        assert_eq!(
+            harness.0.get_user_props(POOLNAME).await.unwrap(),
+            vec![(uprop("user:backup-policy", value), PropertySource::LOCAL)]
+        );
+    }
+
+    /// User properties should be retrievable from a mounted file system,
+    /// and shouldn't show up as ordinary extended attributes.
+    #[rstest]
+    #[tokio::test]
+    async fn mounted(harness: Harness) {
+        harness.0.create_fs(POOLNAME).await.unwrap();
+        let fs = harness.0.new_fs(POOLNAME).await.unwrap();
+        harness.0.set_user_prop(POOLNAME, uprop("user:foo", "bar"))
+            .await.unwrap();
+        assert_eq!(
+            harness.0.get_user_props(POOLNAME).await.unwrap(),
+            vec![(uprop("user:foo", "bar"), PropertySource::LOCAL)]
+        );
+        let root = fs.root();
+        let len = fs.listextattrlen(&root.handle(), |x| x.name().len() as u32)
+            .await
+            .unwrap();
+        assert_eq!(len, 0);
+    }
+}
@@ -26,8 +26,8 @@ async fn open_db(path: &Path) -> Database {
     let cache = Cache::with_capacity(4_194_304);
     let arc_cache = Arc::new(Mutex::new(cache));
     let ddml = Arc::new(DDML::open(pool, arc_cache.clone()));
-    let (idml, reader) = IDML::open(ddml, arc_cache, 1<<30, reader);
-    Database::open(Arc::new(idml), reader)
+    let (idml, reader) = IDML::open(ddml, arc_cache, 1<<30, reader).unwrap();
+    Database::open(Arc::new(idml), reader).unwrap()
 }
 
 mod persistence {
@@ -100,7 +100,7 @@ mod persistence {
         let mut f = fs::File::open(&paths[0]).unwrap();
         let mut v = vec![0; 8192];
         // Skip leaf, raid, cluster, pool, and idml labels
-        f.seek(SeekFrom::Start(334)).unwrap();
+        f.seek(SeekFrom::Start(358)).unwrap();
         f.read_exact(&mut v).unwrap();
         // Uncomment this block to save the binary label for inspection
         /* {
@@ -184,6 +184,7 @@ root:
           parent: ~
           tod:
             height: 1
+            version: 0
             _reserved: 0
             limits:
               min_int_fanout: 91
@@ -350,6 +351,68 @@ root:
         }
     }
 
+    mod freeze_fs {
+        use std::time::Duration;
+        use super::*;
+
+        /// Freezing an already frozen file system is an error
+        #[tokio::test]
+        async fn ealready() {
+            let (db, _tempdir, tree_id, _paths) = harness().await;
+            db.freeze_fs(tree_id).await.unwrap();
+            assert_eq!(Err(Error::EALREADY), db.freeze_fs(tree_id).await);
+        }
+
+        /// A frozen file system can't be destroyed
+        #[tokio::test]
+        async fn destroy_ebusy() {
+            let (db, _tempdir, tree_id, _paths) = harness().await;
+            db.freeze_fs(tree_id).await.unwrap();
+            assert_eq!(
+                Err(Error::EBUSY),
+                db.destroy_fs(None, tree_id, "").await
+            );
+        }
+
+        #[tokio::test]
+        async fn enoent() {
+            let (db, _tempdir, _tree_id, _paths) = harness().await;
+            assert_eq!(Err(Error::ENOENT), db.freeze_fs(TreeID(42)).await);
+        }
+
+        /// Freezing syncs, and then blocks modifications until thawed
+        #[tokio::test]
+        async fn freeze_and_thaw() {
+            let (db, _tempdir, tree_id, _paths) = harness().await;
+            db.fswrite(tree_id, 0, 0, 0, 0, |_| future::ok(())).await.unwrap();
+            db.freeze_fs(tree_id).await.unwrap();
+            assert!(db.is_frozen(tree_id));
+            let txgs = db.txgs().await;
+            assert_eq!(Some(txgs.current - 1), txgs.synced);
+
+            // Reads still work
+            db.fsread(tree_id, |_| future::ok(())).await.unwrap();
+
+            let mut fut = Box::pin(
+                db.fswrite(tree_id, 0, 0, 0, 0, |_| future::ok(()))
+            );
+            let r = tokio::time::timeout(Duration::from_millis(100), &mut fut)
+                .await;
+            assert!(r.is_err(), "fswrite should block while frozen");
+
+            db.thaw_fs(tree_id).unwrap();
+            assert!(!db.is_frozen(tree_id));
+            fut.await.unwrap();
+        }
+
+        /// Thawing a file system that isn't frozen is an error
+        #[tokio::test]
+        async fn thaw_einval() {
+            let (db, _tempdir, tree_id, _paths) = harness().await;
+            assert_eq!(Err(Error::EINVAL), db.thaw_fs(tree_id));
+        }
+    }
+
     mod lookup_fs {
         use pretty_assertions::assert_eq;
         use super::*;
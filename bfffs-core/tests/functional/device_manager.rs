@@ -10,14 +10,23 @@ mod device_manager {
         device_manager::*,
         cache::*,
         ddml::*,
-        idml::*
+        idml::*,
+        multihost::{self, Heartbeat}
     };
     use pretty_assertions::assert_eq;
     use rstest::rstest;
     use rstest_reuse::{apply, template};
     use std::{
-        path::PathBuf,
-        sync::{Arc, Mutex}
+        fs::OpenOptions,
+        os::unix::fs::FileExt,
+        path::{Path, PathBuf},
+        sync::{
+            Arc,
+            Mutex,
+            atomic::{AtomicBool, Ordering}
+        },
+        thread,
+        time::Duration
     };
     use tempfile::TempDir;
     use tokio::runtime::Runtime;
@@ -56,6 +65,13 @@ mod device_manager {
         (rt, dev_manager, paths, tempdir)
     }
 
+    /// Overwrite the first spacemap of a single-disk pool with garbage
+    fn corrupt_spacemap<P: AsRef<Path>>(path: P) {
+        // The first spacemap immediately follows both labels
+        let f = OpenOptions::new().write(true).open(path).unwrap();
+        f.write_all_at(&[0xba; 4096], 8 * 4096).unwrap();
+    }
+
     #[template]
     #[rstest(h,
              case(harness(1, 1, 1, 0, None, None)), // Single-disk configuration
@@ -71,6 +87,78 @@ mod device_manager {
         assert!(h.1.importable_pools().is_empty());
     }
 
+    /// After one import, the cache file alone should be enough to find the
+    /// pool's devices.
+    #[apply(all_configs)]
+    fn cachefile(h: Harness) {
+        let (rt, mut dm, paths, tempdir) = h;
+        let cachefile = tempdir.path().join("bfffs.cache");
+        dm.cachefile(&cachefile);
+        rt.block_on(async move {
+            for path in paths.iter() {
+                dm.taste(path).await.unwrap();
+            }
+            let db = dm.import_by_name("functional_test_pool").await.unwrap();
+            db.shutdown().await;
+        });
+        assert!(cachefile.exists());
+
+        let mut dm = DevManager::default();
+        dm.cachefile(&cachefile);
+        rt.block_on(async move {
+            assert!(dm.taste_cached("functional_test_pool").await);
+            dm.import_by_name("functional_test_pool").await.unwrap();
+        });
+    }
+
+    /// Without a cache file, taste_cached should find nothing
+    #[rstest(h, case(harness(1, 1, 1, 0, None, None)))]
+    fn cachefile_missing(h: Harness) {
+        let (rt, mut dm, _paths, tempdir) = h;
+        dm.cachefile(tempdir.path().join("bfffs.cache"));
+        rt.block_on(async move {
+            assert!(!dm.taste_cached("functional_test_pool").await);
+            assert!(dm.importable_pools().is_empty());
+        });
+    }
+
+    /// If the cached devices have moved, taste_cached should report that the
+    /// cache is stale.  A full scan should then refresh it.
+    #[rstest(h, case(harness(2, 2, 1, 0, None, None)))]
+    fn cachefile_stale(h: Harness) {
+        let (rt, mut dm, paths, tempdir) = h;
+        let cachefile = tempdir.path().join("bfffs.cache");
+        dm.cachefile(&cachefile);
+        let paths2 = paths.clone();
+        rt.block_on(async move {
+            for path in paths2.iter() {
+                dm.taste(path).await.unwrap();
+            }
+            let db = dm.import_by_name("functional_test_pool").await.unwrap();
+            db.shutdown().await;
+        });
+        let moved = tempdir.path().join("moved");
+        std::fs::rename(&paths[1], &moved).unwrap();
+
+        let mut dm = DevManager::default();
+        dm.cachefile(&cachefile);
+        rt.block_on(async {
+            assert!(!dm.taste_cached("functional_test_pool").await);
+            // A stale cache must not leave behind a partial pool
+            assert!(dm.importable_pools().is_empty());
+            dm.taste(&paths[0]).await.unwrap();
+            dm.taste(&moved).await.unwrap();
+            let db = dm.import_by_name("functional_test_pool").await.unwrap();
+            db.shutdown().await;
+        });
+
+        let mut dm = DevManager::default();
+        dm.cachefile(&cachefile);
+        rt.block_on(async move {
+            assert!(dm.taste_cached("functional_test_pool").await);
+        });
+    }
+
     #[rstest(h, case(harness(1, 1, 1, 0, Some(100_000_000), None)))]
     fn cache_size(h: Harness) {
         let (rt, dm, paths, _tempdir) = h;
@@ -81,6 +169,127 @@ mod device_manager {
         assert_eq!(db.cache_size(), 100_000_000);
     }
 
+    /// With degraded set, a mirrored pool can be imported with one disk
+    /// missing, but only read-only.
+    #[rstest(h, case(harness(2, 2, 1, 0, None, None)))]
+    fn import_degraded(h: Harness) {
+        let (rt, mut dm, paths, _tempdir) = h;
+        dm.degraded(true);
+        rt.block_on(async move {
+            dm.taste(&paths[0]).await.unwrap();
+            let (_, uuid) = dm.importable_pools().pop().unwrap();
+            assert_eq!(dm.missing(uuid).len(), 1);
+            let db = dm.import_by_uuid(uuid).await.unwrap();
+            assert!(db.is_readonly());
+            db.shutdown().await;
+        });
+    }
+
+    /// Without degraded set, a pool missing a disk can't be imported
+    #[rstest(h, case(harness(2, 2, 1, 0, None, None)))]
+    fn import_degraded_enxio(h: Harness) {
+        let (rt, dm, paths, _tempdir) = h;
+        let e = rt.block_on(async move {
+            dm.taste(&paths[0]).await.unwrap();
+            dm.import_by_name("functional_test_pool").await
+        }).err().unwrap();
+        assert_eq!(e, Error::ENXIO);
+    }
+
+    /// A pool imported by another host that has since died can be imported
+    /// after waiting for its heartbeat to go stale.
+    #[rstest(h, case(harness(1, 1, 1, 0, None, None)))]
+    fn heartbeat_stale(h: Harness) {
+        let (rt, dm, paths, _tempdir) = h;
+        let hostid = multihost::hostid().wrapping_add(1);
+        let hb = Heartbeat{hostid, timestamp: 0};
+        hb.write(&paths[0]).unwrap();
+        rt.block_on(async move {
+            dm.taste(&paths[0]).await.unwrap();
+            dm.import_by_name("functional_test_pool").await.unwrap();
+        });
+    }
+
+    /// Refuse to import a pool that another host is still using, unless
+    /// forced.
+    #[rstest(h, case(harness(1, 1, 1, 0, None, None)))]
+    fn heartbeat_live(h: Harness) {
+        let (rt, mut dm, paths, _tempdir) = h;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop2 = stop.clone();
+        let path = paths[0].clone();
+        let other = thread::spawn(move || {
+            let hostid = multihost::hostid().wrapping_add(1);
+            let mut timestamp = 0;
+            while !stop2.load(Ordering::Relaxed) {
+                let hb = Heartbeat{hostid, timestamp};
+                hb.write(&path).unwrap();
+                timestamp += 1;
+                thread::sleep(Duration::from_millis(100));
+            }
+        });
+        let e = rt.block_on(async {
+            dm.taste(&paths[0]).await.unwrap();
+            dm.import_by_name("functional_test_pool").await
+        }).err().unwrap();
+        assert_eq!(e, Error::EBUSY);
+
+        dm.force(true);
+        rt.block_on(async {
+            dm.import_by_name("functional_test_pool").await.unwrap();
+        });
+        stop.store(true, Ordering::Relaxed);
+        other.join().unwrap();
+    }
+
+    /// Refuse to import a pool that another host started importing at the
+    /// same time.  That includes a host with the same hostid.
+    #[rstest]
+    #[case(0)]
+    #[case(1)]
+    fn heartbeat_race(#[case] offset: u64) {
+        let (rt, dm, paths, _tempdir) = harness(1, 1, 1, 0, None, None);
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop2 = stop.clone();
+        let path = paths[0].clone();
+        let other = thread::spawn(move || {
+            // Start after this host's first look at the heartbeats
+            thread::sleep(Duration::from_millis(500));
+            let hostid = multihost::hostid().wrapping_add(offset);
+            let mut timestamp = 0;
+            while !stop2.load(Ordering::Relaxed) {
+                let hb = Heartbeat{hostid, timestamp};
+                hb.write(&path).unwrap();
+                timestamp += 1;
+                thread::sleep(Duration::from_millis(100));
+            }
+        });
+        let e = rt.block_on(async {
+            dm.taste(&paths[0]).await.unwrap();
+            dm.import_by_name("functional_test_pool").await
+        }).err().unwrap();
+        assert_eq!(e, Error::EBUSY);
+        stop.store(true, Ordering::Relaxed);
+        other.join().unwrap();
+    }
+
+    /// While a pool is imported, its devices should have this host's
+    /// heartbeat.  Dropping the DevManager should clear it.
+    #[rstest(h, case(harness(1, 1, 1, 0, None, None)))]
+    fn heartbeat_written(h: Harness) {
+        let (rt, dm, paths, _tempdir) = h;
+        rt.block_on(async {
+            dm.taste(&paths[0]).await.unwrap();
+            let db = dm.import_by_name("functional_test_pool").await.unwrap();
+            tokio::time::sleep(multihost::INTERVAL * 2).await;
+            let hb = Heartbeat::read(&paths[0]).unwrap().unwrap();
+            assert_eq!(hb.hostid, multihost::hostid());
+            db.shutdown().await;
+            drop(dm);
+        });
+        assert_eq!(Heartbeat::read(&paths[0]).unwrap(), None);
+    }
+
     /// Import a single pool by its name.  Try both single-disk and raid pools
     #[apply(all_configs)]
     fn import_by_name(h: Harness) {
@@ -156,6 +365,69 @@ mod device_manager {
         assert_eq!(e, Error::ENOENT);
     }
 
+    /// Import a pool read-only.  It should refuse all modifications.
+    #[apply(all_configs)]
+    fn import_readonly(h: Harness) {
+        let (rt, mut dm, paths, _tempdir) = h;
+        dm.readonly(true);
+        rt.block_on(async move {
+            for path in paths.iter() {
+                dm.taste(path).await.unwrap();
+            }
+            let db = dm.import_by_name("functional_test_pool").await.unwrap();
+            assert!(db.is_readonly());
+            assert_eq!(db.create_fs(None, "").await, Err(Error::EROFS));
+            db.sync_transaction().await.unwrap();
+            db.shutdown().await;
+        });
+    }
+
+    /// If the newest label references unreadable metadata, import should
+    /// roll back to the previous label rather than fail.
+    #[rstest(h, case(harness(1, 1, 1, 0, None, None)))]
+    fn import_rollback(h: Harness) {
+        let (rt, dm, paths, _tempdir) = h;
+        corrupt_spacemap(&paths[0]);
+        rt.block_on(async move {
+            dm.taste(&paths[0]).await.unwrap();
+            let db = dm.import_by_name("functional_test_pool").await.unwrap();
+            db.create_fs(None, "").await.unwrap();
+            db.sync_transaction().await.unwrap();
+            db.shutdown().await;
+        });
+    }
+
+    /// Rolling back to the previous label should work for read-only imports,
+    /// too.
+    #[rstest(h, case(harness(1, 1, 1, 0, None, None)))]
+    fn import_rollback_readonly(h: Harness) {
+        let (rt, mut dm, paths, _tempdir) = h;
+        dm.readonly(true);
+        corrupt_spacemap(&paths[0]);
+        rt.block_on(async move {
+            dm.taste(&paths[0]).await.unwrap();
+            let db = dm.import_by_name("functional_test_pool").await.unwrap();
+            assert!(db.is_readonly());
+            db.shutdown().await;
+        });
+    }
+
+    /// taste_all should report unreadable devices without giving up on the
+    /// rest
+    #[apply(all_configs)]
+    fn taste_all(h: Harness) {
+        let (rt, dm, mut paths, tempdir) = h;
+        paths.push(tempdir.path().join("does_not_exist"));
+        rt.block_on(async move {
+            let failed = dm.taste_all(&paths).await;
+            assert_eq!(failed.len(), 1);
+            assert_eq!(&failed[0].0, paths.last().unwrap());
+            let (_, uuid) = dm.importable_pools().pop().unwrap();
+            assert!(dm.missing(uuid).is_empty());
+            dm.import_by_uuid(uuid).await.unwrap();
+        });
+    }
+
     #[rstest(h, case(harness(1, 1, 1, 0, None, Some(100_000_000))))]
     fn writeback_size(h: Harness) {
         let (rt, dm, paths, _tempdir) = h;
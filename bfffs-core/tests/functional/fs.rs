@@ -13,9 +13,10 @@ mod fs {
         ddml::*,
         fs::*,
         idml::*,
+        job::Progress,
         property::*
     };
-    use futures::TryStreamExt;
+    use futures::{TryStreamExt, future};
     use rand::{Rng, thread_rng};
     use rstest::rstest;
     use std::{
@@ -23,6 +24,7 @@ mod fs {
         ffi::{CString, CStr, OsString, OsStr},
         os::raw::c_char,
         os::unix::ffi::OsStrExt,
+        path::PathBuf,
         slice,
         sync::{Arc, Mutex}
     };
@@ -117,6 +119,61 @@ mod fs {
             .unwrap()
     }
 
+    /// Database::check_dir_nlinks should find and repair directories whose link
+    /// counts have drifted.
+    #[tokio::test]
+    async fn check_dir_nlinks() {
+        use bfffs_core::{
+            dataset::ReadDataset,
+            fs_tree::{FSKey, ObjKey}
+        };
+
+        let (fs, _cache, db) = harness4k().await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let dirname = OsString::from("x");
+        let fd = fs.mkdir(&rooth, &dirname, 0o755, 0, 0).await.unwrap();
+        assert!(db.check_dir_nlinks(false).await.unwrap());
+
+        // Corrupt the new directory's link count
+        let tree_id = db.lookup_fs("").await.unwrap().1.unwrap();
+        let key = FSKey::new(fd.ino(), ObjKey::Inode);
+        db.fswrite(tree_id, 1, 0, 0, 0, move |ds| async move {
+            let mut value = ds.get(key).await?.unwrap();
+            value.as_mut_inode().unwrap().nlink = 5;
+            ds.insert(key, value).await
+        }).await.unwrap();
+
+        // Without repair, check should only report the problem
+        assert!(!db.check_dir_nlinks(false).await.unwrap());
+        assert_eq!(fs.getattr(&fd.handle()).await.unwrap().nlink, 5);
+
+        assert!(!db.check_dir_nlinks(true).await.unwrap());
+        assert_eq!(fs.getattr(&fd.handle()).await.unwrap().nlink, 2);
+        assert!(db.check_dir_nlinks(false).await.unwrap());
+    }
+
+    /// Database::check_refcounts should agree with the RIDT about the
+    /// references to both tree nodes and blobs.
+    #[tokio::test]
+    async fn check_refcounts() {
+        let (fs, _cache, db) = harness4k().await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let fd = fs.create(&rooth, &OsString::from("x"), 0o644, 0, 0).await
+        .unwrap();
+        let mut buf = vec![0u8; 16384];
+        let mut rng = thread_rng();
+        for x in &mut buf {
+            *x = rng.gen();
+        }
+        let r = fs.write(&fd.handle(), 0, &buf[..], 0).await;
+        assert_eq!(Ok(16384), r);
+        fs.sync().await;        // Flush it to BlobExtents
+
+        assert!(db.check_refcounts().await.unwrap());
+    }
+
     #[tokio::test]
     async fn create() {
         let (fs, _cache, _db) = harness4k().await;
@@ -144,7 +201,7 @@ mod fs {
 
         // The parent dir's link count should not have increased
         let parent_attr = fs.getattr(&rooth).await.unwrap();
-        assert_eq!(parent_attr.nlink, 1);
+        assert_eq!(parent_attr.nlink, 2);
 
         // Check the new file's attributes
         let attr = fs.getattr(&fd1.handle()).await.unwrap();
@@ -178,6 +235,70 @@ mod fs {
         fs.create(&rooth, &filename, 0o644, 0, 0).await.unwrap();
     }
 
+    /// Names longer than NAME_MAX should be rejected, but NAME_MAX itself is ok
+    #[tokio::test]
+    async fn create_enametoolong() {
+        let (fs, _cache, _db) = harness4k().await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let longname = OsString::from("x".repeat(NAME_MAX + 1));
+        assert_eq!(fs.create(&rooth, &longname, 0o644, 0, 0).await.unwrap_err(),
+            libc::ENAMETOOLONG);
+        let maxname = OsString::from("x".repeat(NAME_MAX));
+        fs.create(&rooth, &maxname, 0o644, 0, 0).await.unwrap();
+    }
+
+    /// By default, file names are raw bytes and needn't be valid UTF-8
+    #[tokio::test]
+    async fn create_non_utf8() {
+        let (fs, _cache, _db) = harness4k().await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let filename = OsStr::from_bytes(b"\xff\xfe");
+        let fd = fs.create(&rooth, filename, 0o644, 0, 0).await.unwrap();
+        assert_eq!(fd.ino(),
+            fs.lookup(None, &rooth, filename).await.unwrap().ino());
+    }
+
+    /// With utf8only set, non-UTF-8 names should be rejected
+    #[tokio::test]
+    async fn create_utf8only() {
+        let (fs, _cache, _db) = harness(vec![Property::Utf8Only(true)]).await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let filename = OsStr::from_bytes(b"\xff\xfe");
+        assert_eq!(fs.create(&rooth, filename, 0o644, 0, 0).await.unwrap_err(),
+            libc::EILSEQ);
+        let filename = OsStr::new("\u{00e9}t\u{00e9}");
+        fs.create(&rooth, filename, 0o644, 0, 0).await.unwrap();
+    }
+
+    /// With dirsync set, create should sync before returning
+    #[tokio::test]
+    async fn create_dirsync() {
+        let (fs, _cache, db) = harness(vec![Property::DirSync(true)]).await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let before = db.synced_txg();
+        fs.create(&rooth, &OsString::from("x"), 0o644, 0, 0).await.unwrap();
+        assert!(db.synced_txg() > before);
+    }
+
+    /// sync=disabled overrides dirsync
+    #[tokio::test]
+    async fn create_dirsync_sync_disabled() {
+        let props = vec![
+            Property::DirSync(true),
+            Property::Sync(SyncPolicy::Disabled)
+        ];
+        let (fs, _cache, db) = harness(props).await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let before = db.synced_txg();
+        fs.create(&rooth, &OsString::from("x"), 0o644, 0, 0).await.unwrap();
+        assert_eq!(db.synced_txg(), before);
+    }
+
     /// Create should update the parent dir's timestamps
     #[tokio::test]
     async fn create_timestamps() {
@@ -623,6 +744,101 @@ mod fs {
         assert!(fs.deallocate(&h, rs as u64 / 2, rs as u64 * 2).await.is_ok());
     }
 
+    /// The change counter should advance on every data or metadata change
+    #[tokio::test]
+    async fn change() {
+        let (fs, _cache, _db) = harness4k().await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let fd = fs.create(&rooth, &OsString::from("x"), 0o644, 0, 0).await
+        .unwrap();
+        let fdh = fd.handle();
+        let change0 = fs.getattr(&fdh).await.unwrap().change;
+
+        let buf = vec![42u8; 4096];
+        fs.write(&fdh, 0, &buf[..], 0).await.unwrap();
+        let change1 = fs.getattr(&fdh).await.unwrap().change;
+        assert!(change1 > change0);
+
+        let attr = SetAttr {
+            perm: Some(0o600),
+            .. Default::default()
+        };
+        fs.setattr(&fdh, attr).await.unwrap();
+        let change2 = fs.getattr(&fdh).await.unwrap().change;
+        assert!(change2 > change1);
+
+        // Reading doesn't change anything
+        fs.read(&fdh, 0, 4096).await.unwrap();
+        assert_eq!(change2, fs.getattr(&fdh).await.unwrap().change);
+    }
+
+    /// The change counter may be read, but not written, as an extattr
+    #[tokio::test]
+    async fn change_xattr() {
+        let (fs, _cache, _db) = harness4k().await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let fd = fs.create(&rooth, &OsString::from("x"), 0o644, 0, 0).await
+        .unwrap();
+        let fdh = fd.handle();
+        let buf = vec![42u8; 4096];
+        fs.write(&fdh, 0, &buf[..], 0).await.unwrap();
+        let change = fs.getattr(&fdh).await.unwrap().change.to_string();
+
+        let ns = ExtAttrNamespace::System;
+        let name = OsStr::new(CHANGE_XATTR);
+        let v = fs.getextattr(&fdh, ns, name).await.unwrap();
+        assert_eq!(&v[..], change.as_bytes());
+        let len = fs.getextattrlen(&fdh, ns, name).await.unwrap();
+        assert_eq!(len as usize, change.len());
+        assert_eq!(Err(libc::EPERM),
+                   fs.setextattr(&fdh, ns, name, b"0").await);
+        assert_eq!(Err(libc::EPERM), fs.deleteextattr(&fdh, ns, name).await);
+    }
+
+    /// Defragmenting should rewrite files whose records are interleaved
+    #[tokio::test]
+    async fn defrag() {
+        let (fs, _cache, db) = harness4k().await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let fd0 = fs.create(&rooth, &OsString::from("x"), 0o644, 0, 0).await
+        .unwrap();
+        let fd1 = fs.create(&rooth, &OsString::from("y"), 0o644, 0, 0).await
+        .unwrap();
+        let mut buf = vec![0u8; 4096];
+        thread_rng().fill(&mut buf[..]);
+        // Syncing after each record interleaves the two files on disk
+        for i in 0..4 {
+            for fd in [&fd0, &fd1] {
+                let r = fs.write(&fd.handle(), i * 4096, &buf[..], 0).await;
+                assert_eq!(Ok(4096), r);
+            }
+            fs.sync().await;
+        }
+
+        let before = db.defrag_plan().await.unwrap();
+        assert_eq!(before.files, 2);
+        assert_eq!(before.fragmented, 2);
+        assert_eq!(before.records, 8);
+        assert_eq!(before.fragments, 8);
+        assert_eq!(before.fragmentation(), 1.0);
+
+        db.defrag(Progress::default()).await.unwrap();
+        let after = db.defrag_plan().await.unwrap();
+        assert_eq!(after.files, 2);
+        assert_eq!(after.fragmented, 0);
+        assert_eq!(after.fragments, 2);
+        assert_eq!(after.fragmentation(), 0.0);
+
+        // The data should be intact
+        for fd in [&fd0, &fd1] {
+            let sglist = fs.read(&fd.handle(), 12288, 4096).await.unwrap();
+            assert_eq!(&sglist[0][..], &buf[..]);
+        }
+    }
+
     #[tokio::test]
     async fn deleteextattr() {
         let (fs, _cache, _db) = harness4k().await;
@@ -857,7 +1073,7 @@ root:
         Inode:
           size: 0
           bytes: 0
-          nlink: 1
+          nlink: 2
           flags: 0
           atime: "1970-01-01T00:00:00Z"
           mtime: "1970-01-01T00:00:00Z"
@@ -867,10 +1083,167 @@ root:
           gid: 0
           perm: 493
           file_type: Dir
+          change: 0
 "#;
         pretty_assertions::assert_eq!(expected, fs_tree);
     }
 
+    /// Advice::DontNeed should drop the file's records from the cache
+    #[tokio::test]
+    async fn fadvise_dontneed() {
+        let (fs, cache, _db) = harness4k().await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let mut fd = fs.create(&rooth, &OsString::from("x"), 0o644, 0, 0)
+            .await
+            .unwrap();
+        let buf = vec![42u8; 8192];
+        let r = fs.write(&fd.handle(), 0, &buf[..], 0).await;
+        assert_eq!(Ok(8192), r);
+        // Sync the filesystem to flush the InlineExtents to BlobExtents
+        fs.sync().await;
+        let size_before = cache.lock().unwrap().size();
+
+        fd.set_advice(Advice::DontNeed);
+        fs.fadvise(&fd.handle(), Advice::DontNeed).await.unwrap();
+        let size_after = cache.lock().unwrap().size();
+        assert_eq!(size_before - size_after, 8192);
+        let stats = fs.fadvise_stats();
+        assert_eq!(stats.dontneed, 1);
+        assert_eq!(stats.evicted, 2);
+
+        // The data should still be readable
+        let sglist = fs.read(&fd.handle(), 0, 4096).await.unwrap();
+        assert_eq!(&sglist[0][..], &buf[0..4096]);
+    }
+
+    /// Reads with Advice::NoReuse should not add records to the cache
+    #[tokio::test]
+    async fn fadvise_noreuse() {
+        let (fs, cache, _db) = harness4k().await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let mut fd = fs.create(&rooth, &OsString::from("x"), 0o644, 0, 0)
+            .await
+            .unwrap();
+        let buf = vec![42u8; 4096];
+        let r = fs.write(&fd.handle(), 0, &buf[..], 0).await;
+        assert_eq!(Ok(4096), r);
+        fs.sync().await;
+        fs.fadvise(&fd.handle(), Advice::DontNeed).await.unwrap();
+        let size_before = cache.lock().unwrap().size();
+
+        fd.set_advice(Advice::NoReuse);
+        fs.fadvise(&fd.handle(), Advice::NoReuse).await.unwrap();
+        let sglist = fs.read(&fd.handle(), 0, 4096).await.unwrap();
+        assert_eq!(&sglist[0][..], &buf[..]);
+        assert_eq!(cache.lock().unwrap().size(), size_before);
+        let stats = fs.fadvise_stats();
+        assert_eq!(stats.noreuse, 1);
+        assert_eq!(stats.uncached, 1);
+    }
+
+    /// Reads with Advice::Random should only read part of large records, and
+    /// shouldn't cache them.
+    #[tokio::test]
+    async fn fadvise_random() {
+        let (fs, cache, _db) = harness(vec![Property::RecordSize(17)]).await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let mut fd = fs.create(&rooth, &OsString::from("x"), 0o644, 0, 0)
+            .await
+            .unwrap();
+        let buf = (0..131_072).map(|i| (i / 1000) as u8).collect::<Vec<_>>();
+        let r = fs.write(&fd.handle(), 0, &buf[..], 0).await;
+        assert_eq!(Ok(131_072), r);
+        fs.sync().await;
+        fs.fadvise(&fd.handle(), Advice::DontNeed).await.unwrap();
+        let size_before = cache.lock().unwrap().size();
+
+        fd.set_advice(Advice::Random);
+        fs.fadvise(&fd.handle(), Advice::Random).await.unwrap();
+        let sglist = fs.read(&fd.handle(), 40_000, 4096).await.unwrap();
+        assert_eq!(&sglist[0][..], &buf[40_000..44_096]);
+        assert_eq!(cache.lock().unwrap().size(), size_before);
+    }
+
+    /// A force-closed handle can no longer be used, but releasing it is still
+    /// harmless.
+    #[tokio::test]
+    async fn force_close() {
+        let (fs, _cache, _db) = harness4k().await;
+        let root = fs.root();
+        let fd = fs.create(&root.handle(), &OsString::from("x"), 0o644, 0, 0)
+            .await
+            .unwrap();
+        let fh = fs.open(&fd.handle(), 1234, libc::O_RDWR as u32);
+        assert_eq!(Ok(()), fs.check_handle(fh));
+
+        fs.force_close(fh).await.unwrap();
+        assert_eq!(Err(libc::EBADF), fs.check_handle(fh));
+        assert!(fs.open_files().is_empty());
+        fs.release(fh);
+    }
+
+    #[tokio::test]
+    async fn force_close_ebadf() {
+        let (fs, _cache, _db) = harness4k().await;
+        assert_eq!(Err(bfffs_core::Error::EBADF), fs.force_close(42).await);
+    }
+
+    /// Force-closing a handle should flush the file's buffered writes
+    #[tokio::test]
+    async fn force_close_flushes() {
+        let (fs, _cache, db) = harness(vec![Property::Coalesce(8192)]).await;
+        let name = OsString::from("x");
+        let root = fs.root();
+        let fd = fs.create(&root.handle(), &name, 0o644, 0, 0).await.unwrap();
+        let fdh = fd.handle();
+        let fh = fs.open(&fdh, 0, libc::O_WRONLY as u32);
+        assert_eq!(Ok(100), fs.write(&fdh, 0, &[42u8; 100][..], 0).await);
+
+        fs.force_close(fh).await.unwrap();
+        // Look at the tree through a separate Fs, which can't see the first
+        // one's buffers.
+        let tree_id = db.lookup_fs("").await.unwrap().1.unwrap();
+        let fs2 = Fs::new(db.clone(), tree_id).await;
+        let root2 = fs2.root();
+        let fd2 = fs2.lookup(None, &root2.handle(), &name).await.unwrap();
+        let attr = fs2.getattr(&fd2.handle()).await.unwrap();
+        assert_eq!(attr.size, 100);
+    }
+
+    /// With sync=standard, fsync and fdatasync should sync the file's dirty
+    /// data.  With sync=disabled, they should return without syncing anything.
+    #[rstest]
+    #[case(SyncPolicy::Standard, false)]
+    #[case(SyncPolicy::Standard, true)]
+    #[case(SyncPolicy::Disabled, false)]
+    #[case(SyncPolicy::Disabled, true)]
+    #[tokio::test]
+    async fn fsync(#[case] policy: SyncPolicy, #[case] datasync: bool) {
+        let (fs, _cache, db) = harness(vec![Property::Sync(policy)]).await;
+        let root = fs.root();
+        let fd = fs.create(&root.handle(), &OsString::from("x"), 0o644, 0, 0)
+            .await
+            .unwrap();
+        let fdh = fd.handle();
+        assert_eq!(Ok(100), fs.write(&fdh, 0, &[42u8; 100][..], 0).await);
+
+        let before = db.synced_txg();
+        let r = if datasync {
+            fs.fdatasync(&fdh).await
+        } else {
+            fs.fsync(&fdh).await
+        };
+        assert_eq!(Ok(()), r);
+        if policy == SyncPolicy::Disabled {
+            assert_eq!(db.synced_txg(), before);
+        } else {
+            assert!(db.synced_txg() > before);
+        }
+    }
+
     #[tokio::test]
     async fn get_prop_default() {
         let (fs, _cache, _db) = harness4k().await;
@@ -889,7 +1262,7 @@ root:
         let root = fs.root();
         let rooth = root.handle();
         let attr = fs.getattr(&rooth).await.unwrap();
-        assert_eq!(attr.nlink, 1);
+        assert_eq!(attr.nlink, 2);
         assert_eq!(attr.blksize, 4096);
         assert_eq!(attr.flags, 0);
         assert!(attr.atime.sec > 0);
@@ -1159,6 +1532,140 @@ root:
         assert_eq!(fd1.parent(), None);
     }
 
+    /// Reads and writes should be counted, until reset
+    #[tokio::test]
+    async fn io_stats() {
+        let (fs, _cache, _db) = harness4k().await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let fd = fs.create(&rooth, &OsString::from("x"), 0o644, 0, 0).await
+            .unwrap();
+        let buf = vec![42u8; 4096];
+        fs.write(&fd.handle(), 0, &buf[..], 0).await.unwrap();
+        fs.write(&fd.handle(), 4096, &buf[..1024], 0).await.unwrap();
+        fs.read(&fd.handle(), 0, 4096).await.unwrap();
+
+        let stats = fs.io_stats(true);
+        assert_eq!(stats.reads, 1);
+        assert_eq!(stats.writes, 2);
+        assert_eq!(stats.bytes_read, 4096);
+        assert_eq!(stats.bytes_written, 5120);
+        assert_eq!(stats.read_latency.iter().sum::<u64>(), 1);
+        assert_eq!(stats.write_latency.iter().sum::<u64>(), 2);
+
+        let stats = fs.io_stats(false);
+        assert_eq!(stats, IoStats{age: stats.age, ..Default::default()});
+    }
+
+    #[tokio::test]
+    async fn is_opaque() {
+        let (fs, _cache, _db) = harness4k().await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let fd = fs.mkdir(&rooth, &OsString::from("x"), 0o755, 0, 0).await
+        .unwrap();
+        let fdh = fd.handle();
+        assert!(!fs.is_opaque(&fdh).await.unwrap());
+
+        fs.set_opaque(&fdh, true).await.unwrap();
+        assert!(fs.is_opaque(&fdh).await.unwrap());
+        // The marker should be visible as an ordinary extended attribute, so
+        // overlay file systems can set and clear it themselves.
+        let name = OsStr::new(OPAQUE_XATTR);
+        let v = fs.getextattr(&fdh, ExtAttrNamespace::System, name).await
+        .unwrap();
+        assert_eq!(&v[..], b"y");
+
+        fs.set_opaque(&fdh, false).await.unwrap();
+        assert!(!fs.is_opaque(&fdh).await.unwrap());
+        // Clearing it again is not an error
+        fs.set_opaque(&fdh, false).await.unwrap();
+    }
+
+    /// A directory's record size override should apply to new files within
+    /// it, and be inherited by new subdirectories
+    #[tokio::test]
+    async fn dir_recordsize() {
+        let (fs, _cache, _db) = harness4k().await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let dir = fs.mkdir(&rooth, &OsString::from("x"), 0o755, 0, 0).await
+        .unwrap();
+        let dirh = dir.handle();
+        assert_eq!(fs.dir_recordsize(&dirh).await, Ok(None));
+        fs.set_dir_recordsize(&dirh, Some(14)).await.unwrap();
+        assert_eq!(fs.dir_recordsize(&dirh).await, Ok(Some(14)));
+
+        let fd = fs.create(&dirh, &OsString::from("f"), 0o644, 0, 0).await
+        .unwrap();
+        let attr = fs.getattr(&fd.handle()).await.unwrap();
+        assert_eq!(attr.blksize, 16384);
+
+        let subdir = fs.mkdir(&dirh, &OsString::from("y"), 0o755, 0, 0)
+        .await
+        .unwrap();
+        let subdirh = subdir.handle();
+        assert_eq!(fs.dir_recordsize(&subdirh).await, Ok(Some(14)));
+        let fd = fs.create(&subdirh, &OsString::from("f"), 0o644, 0, 0).await
+        .unwrap();
+        let attr = fs.getattr(&fd.handle()).await.unwrap();
+        assert_eq!(attr.blksize, 16384);
+
+        // Clearing the override shouldn't affect existing subdirectories
+        fs.set_dir_recordsize(&dirh, None).await.unwrap();
+        assert_eq!(fs.dir_recordsize(&dirh).await, Ok(None));
+        assert_eq!(fs.dir_recordsize(&subdirh).await, Ok(Some(14)));
+        let fd = fs.create(&dirh, &OsString::from("g"), 0o644, 0, 0).await
+        .unwrap();
+        let attr = fs.getattr(&fd.handle()).await.unwrap();
+        assert_eq!(attr.blksize, 4096);
+    }
+
+    /// The record size override can be set as an ordinary extended attribute,
+    /// but only to valid record sizes
+    #[rstest]
+    #[case(b"16384", Ok(()))]
+    #[case(b"16385", Err(libc::EINVAL))]
+    #[case(b"512", Err(libc::EINVAL))]
+    #[case(b"banana", Err(libc::EINVAL))]
+    #[case(b"33554432", Err(libc::EINVAL))]
+    #[tokio::test]
+    async fn dir_recordsize_setextattr(
+        #[case] value: &[u8],
+        #[case] expected: Result<(), i32>
+    ) {
+        let (fs, _cache, _db) = harness4k().await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let name = OsStr::new(RECORDSIZE_XATTR);
+        let r = fs.setextattr(&rooth, ExtAttrNamespace::System, name, value)
+        .await;
+        assert_eq!(r, expected);
+    }
+
+    #[tokio::test]
+    async fn set_dir_recordsize_enotdir() {
+        let (fs, _cache, _db) = harness4k().await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let fd = fs.create(&rooth, &OsString::from("x"), 0o644, 0, 0).await
+        .unwrap();
+        let r = fs.set_dir_recordsize(&fd.handle(), Some(14)).await;
+        assert_eq!(r, Err(libc::ENOTDIR));
+    }
+
+    /// Only directories can be opaque
+    #[tokio::test]
+    async fn set_opaque_enotdir() {
+        let (fs, _cache, _db) = harness4k().await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let fd = fs.create(&rooth, &OsString::from("x"), 0o644, 0, 0).await
+        .unwrap();
+        let r = fs.set_opaque(&fd.handle(), true).await;
+        assert_eq!(r, Err(libc::ENOTDIR));
+    }
+
     #[tokio::test]
     async fn link() {
         let (fs, _cache, _db) = harness4k().await;
@@ -1413,6 +1920,16 @@ root:
             libc::ENOENT);
     }
 
+    #[tokio::test]
+    async fn lookup_enametoolong() {
+        let (fs, _cache, _db) = harness4k().await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let filename = OsString::from("x".repeat(NAME_MAX + 1));
+        assert_eq!(fs.lookup(None, &rooth, &filename).await.unwrap_err(),
+            libc::ENAMETOOLONG);
+    }
+
     #[rstest]
     #[case(false)]
     #[case(true)]
@@ -1585,7 +2102,7 @@ root:
 
         // The parent dir's link count should've increased
         let parent_attr = fs.getattr(&rooth).await.unwrap();
-        assert_eq!(parent_attr.nlink, 2);
+        assert_eq!(parent_attr.nlink, 3);
 
         // Check the new directory's attributes
         let attr = fs.getattr(&fdh).await.unwrap();
@@ -1829,24 +2346,63 @@ root:
         assert_eq!(u64::from(dirent.d_fileno), fd.ino());
     }
 
-    /// mksock(2) should update the parent dir's timestamps
+    /// A whiteout should look like an ordinary character device with device
+    /// number 0.
     #[tokio::test]
-    async fn mksock_timestamps() {
+    async fn mkwhiteout() {
         let (fs, _cache, _db) = harness4k().await;
         let root = fs.root();
         let rooth = root.handle();
-        clear_timestamps(&fs, &rooth).await;
+        let fd = fs.mkwhiteout(&rooth, &OsString::from("x"), 0, 0).await
+        .unwrap();
+        let attr = fs.getattr(&fd.handle()).await.unwrap();
+        assert!(attr.is_whiteout());
+        assert_eq!(attr.mode.0, libc::S_IFCHR);
+        assert_eq!(attr.rdev, WHITEOUT_RDEV);
 
-        fs.mkfifo(&rooth, &OsString::from("x"), 0o644, 0, 0).await.unwrap();
-        assert_ts_changed(&fs, &rooth, false, true, true, false).await;
+        let entries = readdir_all(&fs, &rooth, 0).await;
+        let (dirent, _ofs) = entries
+        .into_iter()
+        .find(|(dirent, _ofs)| {
+            dirent.d_name[0] == 'x' as i8
+        }).expect("'x' directory entry not found");
+        assert_eq!(dirent.d_type, libc::DT_CHR);
     }
 
-    // If the file system was unmounted uncleanly and has open but deleted
-    // files, they should be deleted during mount
-    #[cfg(debug_assertions)]
+    /// Other device nodes are not whiteouts
     #[tokio::test]
-    async fn mount_with_open_but_deleted_files() {
-        let (fs, _cache, db) = harness4k().await;
+    async fn mkwhiteout_not() {
+        let (fs, _cache, _db) = harness4k().await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let fd = fs.mkchar(&rooth, &OsString::from("x"), 0o644, 0, 0, 42).await
+        .unwrap();
+        let attr = fs.getattr(&fd.handle()).await.unwrap();
+        assert!(!attr.is_whiteout());
+        let fd = fs.mkblock(&rooth, &OsString::from("y"), 0o644, 0, 0, 0).await
+        .unwrap();
+        let attr = fs.getattr(&fd.handle()).await.unwrap();
+        assert!(!attr.is_whiteout());
+    }
+
+    /// mksock(2) should update the parent dir's timestamps
+    #[tokio::test]
+    async fn mksock_timestamps() {
+        let (fs, _cache, _db) = harness4k().await;
+        let root = fs.root();
+        let rooth = root.handle();
+        clear_timestamps(&fs, &rooth).await;
+
+        fs.mkfifo(&rooth, &OsString::from("x"), 0o644, 0, 0).await.unwrap();
+        assert_ts_changed(&fs, &rooth, false, true, true, false).await;
+    }
+
+    // If the file system was unmounted uncleanly and has open but deleted
+    // files, they should be deleted during mount
+    #[cfg(debug_assertions)]
+    #[tokio::test]
+    async fn mount_with_open_but_deleted_files() {
+        let (fs, _cache, db) = harness4k().await;
         let root = fs.root();
         let rooth = root.handle();
 
@@ -1879,6 +2435,123 @@ root:
         assert_eq!(Err(libc::ENOENT), r);
     }
 
+    // Like mount_with_open_but_deleted_files, but the file is too big to free
+    // in a single transaction.
+    #[cfg(debug_assertions)]
+    #[tokio::test]
+    async fn mount_with_open_but_deleted_huge_file() {
+        const STRIDE: u64 = 1000 * 4096;
+        let (fs, _cache, db) = harness4k().await;
+        let root = fs.root();
+        let rooth = root.handle();
+
+        let filename = OsString::from("x");
+        let fd = fs.create(&rooth, &filename, 0o644, 0, 0).await.unwrap();
+        let fdh = fd.handle();
+        let ino = fd.ino();
+        let buf = vec![42u8; 4096];
+        for i in 0..20 {
+            fs.write(&fdh, i * STRIDE, &buf[..], 0).await.unwrap();
+        }
+        fs.unlink(&rooth, Some(&fdh), &filename).await.unwrap();
+        fs.sync().await;
+
+        // Unmount, without closing the file
+        drop(fs);
+
+        // Mount again.  The inode should be gone as soon as the mount is done
+        let tree_id = db.lookup_fs("").await.unwrap().1.unwrap();
+        let fs = Fs::new(db, tree_id).await;
+        assert_eq!(Err(libc::ENOENT), fs.igetattr(ino).await);
+    }
+
+    // Like mount_with_open_but_deleted_files, but the file was deleted by
+    // renaming another file over it.
+    #[cfg(debug_assertions)]
+    #[tokio::test]
+    async fn mount_with_open_but_renamed_over_files() {
+        let (fs, _cache, db) = harness4k().await;
+        let root = fs.root();
+        let rooth = root.handle();
+
+        let src = OsString::from("src");
+        let dst = OsString::from("dst");
+        let src_fd = fs.create(&rooth, &src, 0o644, 0, 0).await.unwrap();
+        let dst_fd = fs.create(&rooth, &dst, 0o644, 0, 0).await.unwrap();
+        let ino = dst_fd.ino();
+        fs.rename(&rooth, &src_fd.handle(), &src, &rooth, Some(ino), &dst)
+            .await
+            .unwrap();
+        fs.sync().await;
+
+        // Unmount, without closing the overwritten file
+        drop(fs);
+
+        let tree_id = db.lookup_fs("").await.unwrap().1.unwrap();
+        let fs = Fs::new(db, tree_id).await;
+
+        let mut r = Err(0);
+        for _ in 0..20 {
+            r = fs.igetattr(ino).await;
+            if r.is_err() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        assert_eq!(Err(libc::ENOENT), r);
+    }
+
+    #[tokio::test]
+    async fn open_files() {
+        let (fs, _cache, _db) = harness4k().await;
+        let root = fs.root();
+        let fd = fs.create(&root.handle(), &OsString::from("x"), 0o644, 0, 0)
+            .await
+            .unwrap();
+        let fh0 = fs.open(&fd.handle(), 1234, libc::O_RDONLY as u32);
+        let fh1 = fs.open(&fd.handle(), 0, libc::O_RDWR as u32);
+        assert_ne!(fh0, fh1);
+
+        let files = fs.open_files();
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].fh, fh0);
+        assert_eq!(files[0].ino, fd.ino());
+        assert_eq!(files[0].pid, 1234);
+        assert_eq!(files[0].flags, libc::O_RDONLY as u32);
+        assert_eq!(files[1].fh, fh1);
+        assert_eq!(files[1].pid, 0);
+
+        fs.release(fh0);
+        let files = fs.open_files();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].fh, fh1);
+        assert_eq!(Err(libc::EBADF), fs.check_handle(fh0));
+    }
+
+    // Find the paths of files and directories by their inode numbers
+    #[tokio::test]
+    async fn paths_of() {
+        let (fs, _cache, _db) = harness4k().await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let d = fs.mkdir(&rooth, OsStr::new("d"), 0o755, 0, 0).await.unwrap();
+        let f = fs.create(&d.handle(), OsStr::new("f"), 0o644, 0, 0)
+            .await
+            .unwrap();
+        let g = fs.create(&rooth, OsStr::new("g"), 0o644, 0, 0).await.unwrap();
+        fs.unlink(&rooth, Some(&g.handle()), OsStr::new("g")).await.unwrap();
+
+        let paths = fs.paths_of(&[f.ino(), d.ino(), g.ino(), root.ino()])
+            .await
+            .unwrap();
+        assert_eq!(paths, vec![
+            Some(PathBuf::from("d/f")),
+            Some(PathBuf::from("d")),
+            None,
+            Some(PathBuf::new())
+        ]);
+    }
+
     // Read a hole that's bigger than the zero region
     #[tokio::test]
     async fn read_big_hole() {
@@ -2442,6 +3115,7 @@ root:
         );
 
         fs.inactive(src_fd).await;
+        fs.inactive(dst_fd).await;
         assert_eq!(src_ino,
             fs.lookup(Some(&rooth), &dstdir_fdh, &dst).await.unwrap().ino()
         );
@@ -2639,6 +3313,22 @@ root:
         assert_eq!(dstdir_attr.nlink, 3);
     }
 
+    // Renaming to a non-UTF-8 name should fail when utf8only is set
+    #[tokio::test]
+    async fn rename_utf8only() {
+        let (fs, _cache, _db) = harness(vec![Property::Utf8Only(true)]).await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let src = OsStr::from_bytes(b"src");
+        let dst = OsStr::from_bytes(b"\xff");
+        let fd = fs.create(&rooth, src, 0o644, 0, 0).await.unwrap();
+
+        let r = fs.rename(&rooth, &fd.handle(), src, &rooth, None, dst).await;
+        assert_eq!(Err(libc::EILSEQ), r);
+        // The source should be unaffected
+        assert_eq!(fd.ino(), fs.lookup(None, &rooth, src).await.unwrap().ino());
+    }
+
     // Attempting to rename "." should return EINVAL
     #[tokio::test]
     async fn rename_dot() {
@@ -2736,6 +3426,7 @@ root:
         );
 
         fs.inactive(src_fd).await;
+        fs.inactive(dst_fd).await;
         let dst_fd1 = fs.lookup(Some(&rooth), &dstdir_fdh, &dst).await;
         assert_eq!(dst_fd1.unwrap().ino(), src_ino);
         let r = fs.lookup(Some(&rooth), &srcdir_fdh, &src).await;
@@ -2750,6 +3441,36 @@ root:
         }
     }
 
+    // Rename over a non-directory that is still open.  Like an unlinked file,
+    // the target should remain accessible until it's inactive.
+    #[tokio::test]
+    async fn rename_nondir_to_open_nondir() {
+        let (fs, _cache, _db) = harness4k().await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let src = OsString::from("src");
+        let dst = OsString::from("dst");
+        let src_fd = fs.create(&rooth, &src, 0o644, 0, 0).await.unwrap();
+        let dst_fd = fs.create(&rooth, &dst, 0o644, 0, 0).await.unwrap();
+        let dst_fdh = dst_fd.handle();
+        #[cfg(debug_assertions)] let dst_ino = dst_fd.ino();
+
+        fs.rename(&rooth, &src_fd.handle(), &src, &rooth, Some(dst_fd.ino()),
+            &dst).await
+        .unwrap();
+        fs.inactive(src_fd).await;
+        fs.sync().await;
+
+        let attr = fs.getattr(&dst_fdh).await.expect("Inode deleted too soon");
+        assert_eq!(0, attr.nlink);
+
+        fs.inactive(dst_fd).await;
+        #[cfg(debug_assertions)]
+        {
+            assert_eq!(fs.igetattr(dst_ino).await, Err(libc::ENOENT));
+        }
+    }
+
     // Rename a non-directory.  The target name does not exist
     #[tokio::test]
     async fn rename_nondir_to_nothing() {
@@ -2815,6 +3536,7 @@ root:
         );
 
         fs.inactive(src_fd).await;
+        fs.inactive(dst_fd).await;
         assert_eq!(src_ino,
             fs.lookup(Some(&rooth), &dstdir_fdh, &dst).await.unwrap().ino()
         );
@@ -2923,7 +3645,7 @@ root:
 
         // Make sure the parent dir's refcount dropped
         let inode = fs.getattr(&rooth).await.unwrap();
-        assert_eq!(inode.nlink, 1);
+        assert_eq!(inode.nlink, 2);
     }
 
     /// Remove a directory whose name has a hash collision
@@ -2972,6 +3694,23 @@ root:
         fs.rmdir(&rooth, &filename).await.unwrap();
     }
 
+    /// Whiteouts count as directory entries.  Overlay file systems must remove
+    /// them before removing the directory.
+    #[tokio::test]
+    async fn rmdir_enotempty_whiteout() {
+        let (fs, _cache, _db) = harness4k().await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let dirname = OsString::from("x");
+        let fd = fs.mkdir(&rooth, &dirname, 0o755, 0, 0).await
+        .unwrap();
+        let fdh = fd.handle();
+        fs.mkwhiteout(&fdh, &dirname, 0, 0).await
+        .unwrap();
+        assert_eq!(fs.rmdir(&rooth, &dirname).await.unwrap_err(),
+            libc::ENOTEMPTY);
+    }
+
     #[tokio::test]
     async fn rmdir_enotempty() {
         let (fs, _cache, _db) = harness4k().await;
@@ -3345,6 +4084,136 @@ root:
         fs.setattr(&fdh, attr).await.unwrap();
     }
 
+    /// Truncate a file too big to free in a single transaction.  Everything
+    /// past the truncation point should be gone, and accounted for.
+    #[rstest]
+    #[case(false)]
+    #[case(true)]
+    #[tokio::test]
+    async fn setattr_truncate_huge(#[case] blobs: bool) {
+        // Several deallocation batches' worth, but mostly sparse
+        const STRIDE: u64 = 1000 * 4096;
+        let (fs, _cache, _db) = harness4k().await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let fd = fs.create(&rooth, &OsString::from("x"), 0o644, 0, 0).await
+        .unwrap();
+        let fdh = fd.handle();
+        let buf = vec![42u8; 4096];
+        for i in 0..20 {
+            fs.write(&fdh, i * STRIDE, &buf[..], 0).await.unwrap();
+        }
+        if blobs {
+            fs.sync().await;        // Flush them to BlobExtents
+        }
+
+        let mut attr = SetAttr {
+            size: Some(STRIDE + 4096),
+            .. Default::default()
+        };
+        fs.setattr(&fdh, attr).await.unwrap();
+        let stat = fs.getattr(&fdh).await.unwrap();
+        assert_eq!(STRIDE + 4096, stat.size);
+        assert_eq!(8192, stat.bytes);
+
+        // Extend the file again.  None of the old records should reappear.
+        attr.size = Some(20 * STRIDE);
+        fs.setattr(&fdh, attr).await.unwrap();
+        let expected = [0u8; 4096];
+        for i in 2..20 {
+            let sglist = fs.read(&fdh, i * STRIDE, 4096).await.unwrap();
+            assert_eq!(&sglist[0][..], &expected[..]);
+        }
+        // But the records before the truncation point should be intact
+        let sglist = fs.read(&fdh, STRIDE, 4096).await.unwrap();
+        assert_eq!(&sglist[0][..], &buf[..]);
+    }
+
+    /// Reads of the part of a file that survives truncation should be
+    /// unaffected, even while a multi-transaction truncation is in progress.
+    #[tokio::test]
+    async fn setattr_truncate_huge_concurrent_read() {
+        const STRIDE: u64 = 1000 * 4096;
+        let (fs, _cache, _db) = harness4k().await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let fd = fs.create(&rooth, &OsString::from("x"), 0o644, 0, 0).await
+        .unwrap();
+        let fdh = fd.handle();
+        let buf = vec![42u8; 4096];
+        for i in 0..20 {
+            fs.write(&fdh, i * STRIDE, &buf[..], 0).await.unwrap();
+        }
+        fs.sync().await;
+
+        let attr = SetAttr {
+            size: Some(STRIDE + 4096),
+            .. Default::default()
+        };
+        let reads = async {
+            for _ in 0..10 {
+                for ofs in [0, STRIDE] {
+                    let sglist = fs.read(&fdh, ofs, 4096).await.unwrap();
+                    assert_eq!(&sglist[0][..], &buf[..]);
+                }
+            }
+        };
+        let (r, _) = futures::join!(fs.setattr(&fdh, attr), reads);
+        r.unwrap();
+        let stat = fs.getattr(&fdh).await.unwrap();
+        assert_eq!(8192, stat.bytes);
+    }
+
+    /// Truncating a file too big to free in a single transaction must work
+    /// even when the pool is full, because it frees space.
+    #[tokio::test]
+    async fn setattr_truncate_huge_enospc() {
+        const STRIDE: u64 = 1000 * 4096;
+        let (_tempdir, _, pool) = crate::PoolBuilder::new()
+            .fsize(1 << 26)     // 64 MB
+            .build();
+        let cache = Arc::new(Mutex::new(Cache::with_capacity(1_000_000)));
+        let ddml = Arc::new(DDML::new(pool, cache.clone()));
+        let idml = IDML::create(ddml, cache);
+        let db = Arc::new(Database::create(Arc::new(idml)));
+        let tree_id = db.create_fs(None, "").await.unwrap();
+        let fs = Fs::new(db.clone(), tree_id).await;
+        fs.set_prop(Property::RecordSize(12)).await.unwrap();
+        let root = fs.root();
+        let rooth = root.handle();
+        let fd = fs.create(&rooth, &OsString::from("x"), 0o644, 0, 0).await
+        .unwrap();
+        let fdh = fd.handle();
+
+        // Spread records across several deallocation batches' worth of the
+        // file, then fill the pool beyond them.
+        let buf = vec![42u8; 4096];
+        for i in 0..20 {
+            fs.write(&fdh, i * STRIDE, &buf[..], 0).await.unwrap();
+        }
+        let buf = vec![42u8; 1 << 18];
+        let mut offset = 20 * STRIDE;
+        let e = loop {
+            match fs.write(&fdh, offset, &buf[..], 0).await {
+                Ok(_) => offset += buf.len() as u64,
+                Err(e) => break e
+            }
+            fs.sync().await;
+            assert!(offset < 20 * STRIDE + (1 << 26), "Pool never filled up");
+        };
+        assert_eq!(e, libc::ENOSPC);
+
+        let attr = SetAttr {
+            size: Some(4096),
+            .. Default::default()
+        };
+        fs.setattr(&fdh, attr).await.unwrap();
+        let stat = fs.getattr(&fdh).await.unwrap();
+        assert_eq!(4096, stat.size);
+        assert_eq!(4096, stat.bytes);
+        fs.sync().await;
+    }
+
     /// Set an blob extended attribute
     #[tokio::test]
     async fn setextattr_blob() {
@@ -3574,6 +4443,18 @@ root:
         assert_eq!(attr.flags, 0);
     }
 
+    /// A symlink's target may be no longer than PATH_MAX
+    #[tokio::test]
+    async fn symlink_enametoolong() {
+        let (fs, _cache, _db) = harness4k().await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let dstname = OsString::from("x".repeat(PATH_MAX + 1));
+        let srcname = OsString::from("src");
+        let r = fs.symlink(&rooth, &srcname, 0o642, 0, 0, &dstname).await;
+        assert_eq!(r.unwrap_err(), libc::ENAMETOOLONG);
+    }
+
     /// symlink should update the parent dir's timestamps
     #[tokio::test]
     async fn symlink_timestamps() {
@@ -3620,6 +4501,21 @@ root:
         assert!(x_de.is_none(), "Directory entry was not removed");
     }
 
+    /// With dirsync set, unlink should sync before returning
+    #[tokio::test]
+    async fn unlink_dirsync() {
+        let (fs, _cache, db) = harness(vec![Property::DirSync(true)]).await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let filename = OsString::from("x");
+        let fd = fs.create(&rooth, &filename, 0o644, 0, 0).await.unwrap();
+        let fdh = fd.handle();
+        let before = db.synced_txg();
+        fs.unlink(&rooth, Some(&fdh), &filename).await.unwrap();
+        assert!(db.synced_txg() > before);
+        fs.inactive(fd).await;
+    }
+
     // Access an opened but deleted file
     #[tokio::test]
     async fn unlink_but_opened() {
@@ -3638,6 +4534,35 @@ root:
         fs.inactive(fd).await;
     }
 
+    // Delete an open file too big to free in a single transaction
+    #[tokio::test]
+    async fn unlink_but_opened_huge() {
+        const STRIDE: u64 = 1000 * 4096;
+        let (fs, _cache, _db) = harness4k().await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let filename = OsString::from("x");
+        let fd = fs.create(&rooth, &filename, 0o644, 0, 0).await.unwrap();
+        let fdh = fd.handle();
+        #[cfg(debug_assertions)] let ino = fd.ino();
+        let buf = vec![42u8; 4096];
+        for i in 0..20 {
+            fs.write(&fdh, i * STRIDE, &buf[..], 0).await.unwrap();
+        }
+        fs.unlink(&rooth, Some(&fdh), &filename).await.unwrap();
+        fs.sync().await;
+
+        let sglist = fs.read(&fdh, 19 * STRIDE, 4096).await
+            .expect("Inode deleted too soon");
+        assert_eq!(&sglist[0][..], &buf[..]);
+
+        fs.inactive(fd).await;
+        #[cfg(debug_assertions)]
+        {
+            assert_eq!(fs.igetattr(ino).await, Err(libc::ENOENT));
+        }
+    }
+
     // Access an open file that was deleted during a previous TXG
     #[tokio::test]
     async fn unlink_but_opened_across_txg() {
@@ -4049,6 +4974,300 @@ root:
         assert_eq!(&db[1024..2048], &buf1[..]);
     }
 
+    /// Concurrent writes to different records of the same file must not lose
+    /// each other's updates to the file's size.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn write_concurrent() {
+        const NRECS: u64 = 32;
+        let (fs, _cache, _db) = harness4k().await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let fd = fs.create(&rooth, &OsString::from("x"), 0o644, 0, 0).await
+        .unwrap();
+        let fdh = fd.handle();
+        let buf = vec![42u8; 4096];
+        let r = future::join_all((0..NRECS).map(|i| {
+            fs.write(&fdh, i * 4096, &buf[..], 0)
+        })).await;
+        assert!(r.into_iter().all(|r| r == Ok(4096)));
+
+        let attr = fs.getattr(&fdh).await.unwrap();
+        assert_eq!(attr.size, NRECS * 4096);
+        assert_eq!(attr.bytes, NRECS * 4096);
+    }
+
+    /// Once the pool is nearly full, writes should fail with ENOSPC, but there
+    /// must still be room to delete files.
+    #[tokio::test]
+    async fn write_enospc() {
+        let (_tempdir, _, pool) = crate::PoolBuilder::new()
+            .fsize(1 << 26)     // 64 MB
+            .build();
+        let cache = Arc::new(Mutex::new(Cache::with_capacity(1_000_000)));
+        let ddml = Arc::new(DDML::new(pool, cache.clone()));
+        let idml = IDML::create(ddml, cache);
+        let db = Arc::new(Database::create(Arc::new(idml)));
+        let tree_id = db.create_fs(None, "").await.unwrap();
+        let fs = Fs::new(db.clone(), tree_id).await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let name = OsString::from("x");
+        let fd = fs.create(&rooth, &name, 0o644, 0, 0).await.unwrap();
+        let fdh = fd.handle();
+        let buf = vec![42u8; 1 << 18];
+
+        // Sync after every write, so the pool's usage is up-to-date.
+        let mut offset = 0;
+        let e = loop {
+            match fs.write(&fdh, offset, &buf[..], 0).await {
+                Ok(_) => offset += buf.len() as u64,
+                Err(e) => break e
+            }
+            fs.sync().await;
+            assert!(offset < 1 << 26, "Pool never filled up");
+        };
+        assert_eq!(e, libc::ENOSPC);
+        assert_eq!(fs.statvfs().await.unwrap().f_bavail, 0);
+        let r = fs.mkdir(&rooth, OsStr::new("d"), 0o755, 0, 0).await;
+        assert_eq!(r.err(), Some(libc::ENOSPC));
+
+        fs.unlink(&rooth, Some(&fdh), &name).await.unwrap();
+        fs.inactive(fd).await;
+        fs.sync().await;
+    }
+
+    // Many small appends, spanning several records, with write coalescing
+    // enabled
+    #[tokio::test]
+    async fn write_coalesce() {
+        let (fs, _cache, _db) = harness(vec![Property::RecordSize(12),
+                                             Property::Coalesce(8192)]).await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let fd = fs.create(&rooth, &OsString::from("x"), 0o644, 0, 0).await
+        .unwrap();
+        let fdh = fd.handle();
+        let mut buf = vec![0u8; 10000];
+        thread_rng().fill(&mut buf[..]);
+        for (i, chunk) in buf.chunks(100).enumerate() {
+            let r = fs.write(&fdh, 100 * i as u64, chunk, 0).await;
+            assert_eq!(Ok(100), r);
+        }
+
+        let attr = fs.getattr(&fdh).await.unwrap();
+        assert_eq!(attr.size, 10000);
+        assert_eq!(attr.bytes, 10000);
+        let sglist = fs.read(&fdh, 0, 10000).await.unwrap();
+        let rbuf = sglist.iter().flat_map(|b| b[..].iter().cloned())
+            .collect::<Vec<_>>();
+        assert_eq!(&rbuf[..], &buf[..]);
+    }
+
+    // A write that isn't adjacent to the buffered ones must not be coalesced
+    // with them
+    #[tokio::test]
+    async fn write_coalesce_nonadjacent() {
+        let (fs, _cache, _db) = harness(vec![Property::RecordSize(12),
+                                             Property::Coalesce(8192)]).await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let fd = fs.create(&rooth, &OsString::from("x"), 0o644, 0, 0).await
+        .unwrap();
+        let fdh = fd.handle();
+        let buf0 = vec![1u8; 512];
+        let buf1 = vec![2u8; 512];
+        let buf2 = vec![3u8; 512];
+        assert_eq!(Ok(512), fs.write(&fdh, 0, &buf0[..], 0).await);
+        assert_eq!(Ok(512), fs.write(&fdh, 1024, &buf1[..], 0).await);
+        // Overwrite the beginning of the first write
+        assert_eq!(Ok(512), fs.write(&fdh, 256, &buf2[..], 0).await);
+
+        let attr = fs.getattr(&fdh).await.unwrap();
+        assert_eq!(attr.size, 1536);
+        let sglist = fs.read(&fdh, 0, 1536).await.unwrap();
+        let db = &sglist[0];
+        assert_eq!(&db[0..256], &buf0[0..256]);
+        assert_eq!(&db[256..768], &buf2[..]);
+        assert!(db[768..1024].iter().all(|&x| x == 0));
+        assert_eq!(&db[1024..1536], &buf1[..]);
+    }
+
+    // fsync and fdatasync must write out any buffered writes, while writes
+    // after them may be buffered again.  Check that with a second Fs on the
+    // same tree, which can only see what reached the tree.
+    #[rstest]
+    #[case(false)]
+    #[case(true)]
+    #[tokio::test]
+    async fn write_coalesce_fsync(#[case] datasync: bool) {
+        let (fs, _cache, db) = harness(vec![Property::RecordSize(12),
+                                            Property::Coalesce(8192)]).await;
+        let name = OsString::from("x");
+        let root = fs.root();
+        let rooth = root.handle();
+        let fd = fs.create(&rooth, &name, 0o644, 0, 0).await.unwrap();
+        let fdh = fd.handle();
+        let mut buf = vec![0u8; 6000];
+        thread_rng().fill(&mut buf[..]);
+        let tree_id = db.lookup_fs("").await.unwrap().1.unwrap();
+        let fs2 = Fs::new(db.clone(), tree_id).await;
+        let root2 = fs2.root();
+        let fd2 = fs2.lookup(None, &root2.handle(), &name).await.unwrap();
+        let fdh2 = fd2.handle();
+
+        for (i, chunk) in buf.chunks(1000).enumerate() {
+            let offset = 1000 * i as u64;
+            assert_eq!(Ok(1000), fs.write(&fdh, offset, chunk, 0).await);
+            if i % 3 == 2 {
+                let r = if datasync {
+                    fs.fdatasync(&fdh).await
+                } else {
+                    fs.fsync(&fdh).await
+                };
+                assert_eq!(Ok(()), r);
+                let attr = fs2.getattr(&fdh2).await.unwrap();
+                assert_eq!(attr.size, 1000 * (i as u64 + 1));
+            } else {
+                // At least the latest write is still buffered.  Earlier ones
+                // may have been flushed if a transaction group was synced in
+                // the meantime.
+                let attr = fs2.getattr(&fdh2).await.unwrap();
+                assert!(attr.size <= 1000 * i as u64);
+            }
+        }
+
+        let sglist = fs2.read(&fdh2, 0, 6000).await.unwrap();
+        let rbuf = sglist.iter().flat_map(|b| b[..].iter().cloned())
+            .collect::<Vec<_>>();
+        assert_eq!(&rbuf[..], &buf[..]);
+    }
+
+    /// With copies=2, every record should be stored twice, and count twice
+    /// against the file's space.
+    #[rstest]
+    #[case(false)]
+    #[case(true)]
+    #[tokio::test]
+    async fn write_copies(#[case] blobs: bool) {
+        let props = vec![Property::RecordSize(12), Property::Copies(2)];
+        let (fs, _cache, db) = harness(props).await;
+        let root = fs.root();
+        let rooth = root.handle();
+        let fd = fs.create(&rooth, &OsString::from("x"), 0o644, 0, 0).await
+        .unwrap();
+        let fdh = fd.handle();
+        let mut buf = vec![0u8; 8192];
+        let mut rng = thread_rng();
+        rng.fill(&mut buf[..]);
+        fs.sync().await;
+        let stat1 = db.stat();
+
+        let r = fs.write(&fdh, 0, &buf[..], 0).await;
+        assert_eq!(Ok(8192), r);
+        if blobs {
+            fs.sync().await;        // Flush it to DittoExtents
+            // Each record, and its copy, should take one LBA
+            let stat2 = db.stat();
+            assert_eq!(stat2.used - stat1.used, 4);
+        }
+        assert_eq!(16384, fs.getattr(&fdh).await.unwrap().bytes);
+
+        let sglist = fs.read(&fdh, 0, buf.len()).await.unwrap();
+        assert_eq!(&sglist[0][..], &buf[..4096]);
+        assert_eq!(&sglist[1][..], &buf[4096..]);
+
+        // Truncating a record should free both copies
+        let attr = SetAttr {
+            size: Some(4096),
+            .. Default::default()
+        };
+        fs.setattr(&fdh, attr).await.unwrap();
+        assert_eq!(8192, fs.getattr(&fdh).await.unwrap().bytes);
+    }
+
+    /// With copies=2, a record should still be readable if either copy is
+    /// damaged.
+    #[rstest]
+    #[case(0)]
+    #[case(1)]
+    #[tokio::test]
+    async fn read_copies_damaged(#[case] victim: usize) {
+        use std::os::unix::fs::FileExt;
+
+        let (_tempdir, paths, pool) = crate::PoolBuilder::new()
+            .fsize(1 << 26)  // 64 MB
+            .build();
+        let cache = Arc::new(Mutex::new(Cache::with_capacity(1_000_000)));
+        let ddml = Arc::new(DDML::new(pool, cache.clone()));
+        let idml = IDML::create(ddml, cache.clone());
+        let db = Arc::new(Database::create(Arc::new(idml)));
+        let tree_id = db.create_fs(None, "").await.unwrap();
+        let fs = Fs::new(db.clone(), tree_id).await;
+        fs.set_prop(Property::RecordSize(12)).await.unwrap();
+        fs.set_prop(Property::Copies(2)).await.unwrap();
+        let root = fs.root();
+        let rooth = root.handle();
+        let fd = fs.create(&rooth, &OsString::from("x"), 0o644, 0, 0).await
+        .unwrap();
+        let fdh = fd.handle();
+        let mut buf = vec![0u8; 4096];
+        thread_rng().fill(&mut buf[..]);
+        let r = fs.write(&fdh, 0, &buf[..], 0).await;
+        assert_eq!(Ok(4096), r);
+        fs.sync().await;
+        cache.lock().unwrap().drop_cache();
+
+        // Find both copies on disk, and scribble over one of them
+        let disk = std::fs::read(&paths[0]).unwrap();
+        let copies = disk.chunks(4096)
+            .enumerate()
+            .filter(|(_, block)| *block == &buf[..])
+            .map(|(i, _)| i as u64 * 4096)
+            .collect::<Vec<_>>();
+        assert_eq!(copies.len(), 2);
+        let f = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&paths[0])
+            .unwrap();
+        f.write_all_at(&[0xba; 4096], copies[victim]).unwrap();
+
+        let sglist = fs.read(&fdh, 0, buf.len()).await.unwrap();
+        assert_eq!(&sglist[0][..], &buf[..]);
+    }
+
+    /// Write and read back a file with the largest allowed record size.  The
+    /// cache must be big enough to hold a whole record.
+    #[tokio::test]
+    async fn write_large_records() {
+        let exp = 24u8;
+        let rs = 1usize << exp;
+        let (_, _, pool) = crate::PoolBuilder::new()
+            .build();
+        let cache = Arc::new(Mutex::new(Cache::with_capacity(4 * rs)));
+        let ddml = Arc::new(DDML::new(pool, cache.clone()));
+        let idml = IDML::create(ddml, cache);
+        let db = Arc::new(Database::create(Arc::new(idml)));
+        let tree_id = db.create_fs(None, "").await.unwrap();
+        let fs = Fs::new(db.clone(), tree_id).await;
+        fs.set_prop(Property::RecordSize(exp)).await.unwrap();
+        let root = fs.root();
+        let rooth = root.handle();
+        let fd = fs.create(&rooth, &OsString::from("x"), 0o644, 0, 0).await
+        .unwrap();
+        let fdh = fd.handle();
+        let mut buf = vec![0u8; rs * 3 / 2];
+        let mut rng = thread_rng();
+        rng.fill(&mut buf[..]);
+        let r = fs.write(&fdh, 0, &buf[..], 0).await;
+        assert_eq!(Ok(buf.len() as u32), r);
+        fs.sync().await;
+
+        let sglist = fs.read(&fdh, 0, buf.len()).await.unwrap();
+        assert_eq!(2, sglist.len());
+        assert_eq!(&sglist[0][..], &buf[..rs]);
+        assert_eq!(&sglist[1][..], &buf[rs..]);
+    }
+
     // Partially fill a hole that's at neither the beginning nor the end of the
     // file
     #[tokio::test]
@@ -4113,6 +5332,28 @@ root:
         assert_eq!(&db[2560..], &buf0[2560..]);
     }
 
+    /// With sync=always, every write should be synced before it returns, even
+    /// a small one that would otherwise be coalesced.
+    #[tokio::test]
+    async fn write_sync_always() {
+        let props = vec![
+            Property::Coalesce(8192),
+            Property::Sync(SyncPolicy::Always)
+        ];
+        let (fs, _cache, db) = harness(props).await;
+        let root = fs.root();
+        let fd = fs.create(&root.handle(), &OsString::from("x"), 0o644, 0, 0)
+            .await
+            .unwrap();
+        let fdh = fd.handle();
+        for i in 0..3u64 {
+            let before = db.synced_txg();
+            let r = fs.write(&fdh, 100 * i, &[42u8; 100][..], 0).await;
+            assert_eq!(Ok(100), r);
+            assert!(db.synced_txg() > before);
+        }
+    }
+
     // write updates a file's ctime and mtime
     #[tokio::test]
     async fn write_timestamps() {
@@ -4227,10 +5468,12 @@ mod torture {
     use bfffs_core::{
         *,
         cache::*,
+        cleaner::CleanPolicy,
         database::*,
         ddml::*,
         fs::*,
         idml::*,
+        job::Progress,
     };
     use futures::{future, StreamExt};
     use tracing::*;
@@ -4291,7 +5534,8 @@ mod torture {
             let db = self.db.as_ref().unwrap();
             let rt = self.rt.as_ref().unwrap();
             rt.block_on( async {
-                db.clean()
+                db.clean(CleanPolicy::Mixed, Progress::default())
+                .unwrap()
                 .await
             }).unwrap();
             self.check();
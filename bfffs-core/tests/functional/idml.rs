@@ -144,7 +144,7 @@ mod persistence {
         let cache = cache::Cache::with_capacity(4_194_304);
         let arc_cache = Arc::new(Mutex::new(cache));
         let ddml = Arc::new(ddml::DDML::open(pool, arc_cache.clone()));
-        idml::IDML::open(ddml, arc_cache, 1<<30, reader);
+        idml::IDML::open(ddml, arc_cache, 1<<30, reader).unwrap();
     }
 
     #[rstest]
@@ -163,7 +163,7 @@ mod persistence {
         let mut f = fs::File::open(&paths[0]).unwrap();
         let mut v = vec![0; 8192];
         // Skip leaf, mirror, raid, cluster, and pool labels
-        f.seek(SeekFrom::Start(204)).unwrap();
+        f.seek(SeekFrom::Start(228)).unwrap();
         f.read_exact(&mut v).unwrap();
         // Uncomment this block to save the binary label for inspection
         /* {
@@ -185,7 +185,8 @@ mod t {
     use bfffs_core::dml::*;
     use bfffs_core::ddml::*;
     use bfffs_core::idml::*;
-    use divbuf::DivBufShared;
+    use divbuf::{DivBuf, DivBufShared};
+    use futures::{StreamExt, stream::FuturesUnordered};
     use rstest::{fixture, rstest};
     use std::sync::{Arc, Mutex};
     use tempfile::TempDir;
@@ -227,8 +228,78 @@ mod t {
         {
             let txg = idml3.txg().await;
             let cz = idml3.list_closed_zones().next().unwrap();
-            idml3.clean_zone(cz, *txg).await.unwrap();
+            idml3.clean_zone(cz, Temperature::Hot, *txg).await.unwrap();
         }
         assert!(idml3.check().await.unwrap());
     }
+
+    // Reading, popping, and deleting records while the cleaner is moving them
+    // should neither read from a freed location nor leak the new one.
+    #[rstest]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn read_while_cleaning(objects: (IDML, TempDir)) {
+        let (idml, _tempdir) = objects;
+        let idml = Arc::new(idml);
+        let mut rids = Vec::new();
+        for i in 0..=LBA_PER_ZONE {
+            let txg = idml.txg().await;
+            let dbs = DivBufShared::from(vec![i as u8; 4096]);
+            rids.push(idml.put(dbs, Compression::None, *txg).await.unwrap());
+        }
+        // Force reads to go to disk
+        idml.drop_cache();
+
+        let txg = *idml.txg().await;
+        let cz = idml.list_closed_zones().next().unwrap();
+        let clean_fut =
+            tokio::spawn(idml.clean_zone(cz, Temperature::Cold, txg));
+        let ops = rids.iter()
+            .enumerate()
+            .map(|(i, &rid)| {
+                let idml2 = idml.clone();
+                tokio::spawn(async move {
+                    match i % 3 {
+                        0 => {
+                            let dbs = idml2.pop::<DivBufShared, DivBuf>(&rid,
+                                txg).await.unwrap();
+                            let db = dbs.try_const().unwrap();
+                            assert!(db.iter().all(|&b| b == i as u8));
+                        }
+                        1 => idml2.delete(&rid, txg).await.unwrap(),
+                        _ => {
+                            let db = idml2.get::<DivBufShared, DivBuf>(&rid)
+                                .await.unwrap();
+                            assert!(db.iter().all(|&b| b == i as u8));
+                        }
+                    }
+                })
+            }).collect::<FuturesUnordered<_>>()
+            .collect::<Vec<_>>();
+        let (r, results) = futures::join!(clean_fut, ops);
+        r.unwrap().unwrap();
+        for result in results {
+            result.unwrap();
+        }
+
+        // Every surviving record must still be readable from its new home
+        idml.drop_cache();
+        for (i, rid) in rids.iter().enumerate().filter(|(i, _)| i % 3 == 2) {
+            let db = idml.get::<DivBufShared, DivBuf>(rid).await.unwrap();
+            assert!(db.iter().all(|&b| b == i as u8));
+        }
+        assert!(idml.check().await.unwrap());
+    }
+
+    // Scrub should verify every record, with many reads in flight at once
+    #[rstest]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn scrub(objects: (IDML, TempDir)) {
+        let (idml, _tempdir) = objects;
+        for i in 0..64u8 {
+            let txg = idml.txg().await;
+            let dbs = DivBufShared::from(vec![i; 4096]);
+            idml.put(dbs, Compression::None, *txg).await.unwrap();
+        }
+        assert!(idml.scrub(8).await.unwrap());
+    }
 }
@@ -75,7 +75,7 @@ mod persistence {
         for path in harness.2 {
             let mut f = fs::File::open(path).unwrap();
             let mut v = vec![0; 8192];
-            f.seek(SeekFrom::Start(72)).unwrap();   // Skip the VdevLeaf label
+            f.seek(SeekFrom::Start(96)).unwrap();   // Skip the VdevLeaf label
             f.read_exact(&mut v).unwrap();
             // Uncomment this block to save the binary label for inspection
             /* {
@@ -100,7 +100,7 @@ mod persistence {
             let mut f = fs::File::open(path).unwrap();
             let mut v = vec![0; 8192];
             // Skip leaf, raid, and cluster labels
-            f.seek(SeekFrom::Start(148)).unwrap();
+            f.seek(SeekFrom::Start(172)).unwrap();
             f.read_exact(&mut v).unwrap();
             // Uncomment this block to save the binary label for inspection
             /* {
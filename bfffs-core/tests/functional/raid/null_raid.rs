@@ -68,7 +68,7 @@ mod persistence {
         }).unwrap();
         let mut f = fs::File::open(harness.2).unwrap();
         let mut v = vec![0; 8192];
-        f.seek(SeekFrom::Start(112)).unwrap();   // Skip the leaf, mirror labels
+        f.seek(SeekFrom::Start(136)).unwrap();   // Skip the leaf, mirror labels
         f.read_exact(&mut v).unwrap();
         // Uncomment this block to save the binary label for inspection
         /* {
@@ -716,7 +716,7 @@ mod persistence {
         for path in harness.2 {
             let mut f = fs::File::open(path).unwrap();
             let mut v = vec![0; 8192];
-            f.seek(SeekFrom::Start(112)).unwrap();   // Skip leaf, mirror labels
+            f.seek(SeekFrom::Start(136)).unwrap();   // Skip leaf, mirror labels
             f.read_exact(&mut v).unwrap();
             // Uncomment this block to save the binary label for inspection
             /* {
@@ -42,6 +42,21 @@ mod basic {
         format!("{:?}", harness.0);
     }
 
+    /// After closing, every operation should fail with ENXIO
+    #[rstest]
+    fn close(mut harness: Harness) {
+        let dbs = DivBufShared::from(vec![42u8; 4096]);
+        let wbuf = dbs.try_const().unwrap();
+        harness.0.close();
+        let rt = runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let e = harness.0.write_at(wbuf, 10).await.unwrap_err();
+            assert_eq!(e, Error::ENXIO);
+            let e = harness.0.sync_all().await.unwrap_err();
+            assert_eq!(e, Error::ENXIO);
+        });
+    }
+
     #[test]
     fn create_enoent() {
         let dir = t!(
@@ -377,14 +392,16 @@ mod persistence {
     use tempfile::{Builder, TempDir};
     use tokio::runtime;
 
-    const GOLDEN: [u8; 72] = [
-        // First 16 bytes are file magic
+    const GOLDEN: [u8; 96] = [
+        // First 12 bytes are file magic
         0x42, 0x46, 0x46, 0x46, 0x53, 0x20, 0x56, 0x64, // BFFFS Vd
-        0x65, 0x76, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // ev......
+        0x65, 0x76, 0x00, 0x00,                         // ev..
+        // Next 4 bytes are the label version, in BE
+        0x00, 0x00, 0x00, 0x01,
         // Next 8 bytes are a checksum
-        0x2e, 0x43, 0xc2, 0x5d, 0x1f, 0x55, 0x20, 0x3b,
+        0x9a, 0xee, 0x41, 0x02, 0xc9, 0xe7, 0xab, 0x6f,
         // Next 8 bytes are the contents length, in BE
-        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x28,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x40,
         // The rest is a serialized VdevFile::Label object.
         // First comes the VdevFile's UUID.
         0x3f, 0xa1, 0xf6, 0xb9, 0x54, 0xb1, 0x4a, 0x10,
@@ -393,7 +410,34 @@ mod persistence {
         0xbe, 0xba, 0x7e, 0x1a, 0xef, 0xbe, 0xad, 0xde,
         // Then the number of LBAs as a 64-bit number
         0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
-        // Finally the number of LBAs reserved for the spacemap
+        // Then the number of LBAs reserved for the spacemap
+        0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        // Finally the lifetime read, write, and checksum error counts
+        0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    /// The same label, as written before the label was versioned.  It has no
+    /// error counts, and its checksum doesn't cover the version.
+    const GOLDEN_V0: [u8; 72] = [
+        // First 12 bytes are file magic
+        0x42, 0x46, 0x46, 0x46, 0x53, 0x20, 0x56, 0x64, // BFFFS Vd
+        0x65, 0x76, 0x00, 0x00,                         // ev..
+        // Version 0
+        0x00, 0x00, 0x00, 0x00,
+        // Checksum
+        0x2e, 0x43, 0xc2, 0x5d, 0x1f, 0x55, 0x20, 0x3b,
+        // Contents length
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x28,
+        // UUID
+        0x3f, 0xa1, 0xf6, 0xb9, 0x54, 0xb1, 0x4a, 0x10,
+        0xbc, 0x6b, 0x5b, 0x2a, 0x15, 0xe8, 0xa0, 0x3d,
+        // LBAs per zone
+        0xbe, 0xba, 0x7e, 0x1a, 0xef, 0xbe, 0xad, 0xde,
+        // LBAs
+        0x00, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        // Spacemap LBAs
         0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
     ];
 
@@ -431,6 +475,8 @@ mod persistence {
         }).unwrap();
         assert_eq!(vdev.size(), 16_384);
         assert_eq!(vdev.uuid(), golden_uuid);
+        let expected = ErrorCounts{read: 1, write: 2, checksum: 3};
+        assert_eq!(vdev.error_counts(), expected);
         let _ = harness.1;
     }
 
@@ -478,6 +524,27 @@ mod persistence {
         let _ = harness.1;
     }
 
+    /// A label written by a newer version of BFFFS should be rejected
+    #[rstest]
+    fn open_future_version(harness: Harness) {
+        let mut label = GOLDEN;
+        label[12..16].copy_from_slice(&(LABEL_VERSION + 1).to_be_bytes());
+        {
+            let f = std::fs::OpenOptions::new()
+                .write(true)
+                .open(harness.0.clone()).unwrap();
+            let offset0 = 0;
+            f.write_all_at(&label, offset0).unwrap();
+            let offset1 = 4 * BYTES_PER_LBA as u64;
+            f.write_all_at(&label, offset1).unwrap();
+        }
+        let rt = runtime::Runtime::new().unwrap();
+        let e = rt.block_on(async { VdevFile::open(harness.0).await})
+            .err()
+            .expect("Opening the file should've failed");
+        assert_eq!(e, Error::EOPNOTSUPP);
+    }
+
     // Open a device without a valid label
     #[rstest]
     fn open_invalid(harness: Harness) {
@@ -509,6 +576,30 @@ mod persistence {
         let _ = harness.1;
     }
 
+    /// Open a label written before the label was versioned
+    #[rstest]
+    fn open_v0(harness: Harness) {
+        let golden_uuid = Uuid::parse_str(
+            "3fa1f6b9-54b1-4a10-bc6b-5b2a15e8a03d").unwrap();
+        {
+            let f = std::fs::OpenOptions::new()
+                .write(true)
+                .open(harness.0.clone()).unwrap();
+            let offset0 = 0;
+            f.write_all_at(&GOLDEN_V0, offset0).unwrap();
+            let offset1 = 4 * BYTES_PER_LBA as u64;
+            f.write_all_at(&GOLDEN_V0, offset1).unwrap();
+        }
+        let rt = runtime::Runtime::new().unwrap();
+        let (vdev, _label_reader) = rt.block_on(async {
+            VdevFile::open(harness.0).await
+        }).unwrap();
+        assert_eq!(vdev.size(), 16_384);
+        assert_eq!(vdev.uuid(), golden_uuid);
+        assert_eq!(vdev.error_counts(), ErrorCounts::default());
+        let _ = harness.1;
+    }
+
     // Write the label, and compare to a golden master
     #[rstest]
     fn write_label(harness: Harness) {
@@ -532,9 +623,37 @@ mod persistence {
             println!("UUID is {}", vdev.uuid());
         } */
         // Compare against the golden master, skipping the checksum and UUID
-        // fields
+        // fields.  A new vdev has no errors yet.
         assert_eq!(&v[0..16], &GOLDEN[0..16]);
         assert_eq!(&v[24..32], &GOLDEN[24..32]);
-        assert_eq!(&v[48..GOLDEN.len()], &GOLDEN[48..GOLDEN.len()]);
+        assert_eq!(&v[48..72], &GOLDEN[48..72]);
+        assert_eq!(&v[72..GOLDEN.len()], &[0u8; 24][..]);
+    }
+
+    /// Error counts should survive a round trip through the label
+    #[rstest]
+    fn write_label_errors(harness: Harness) {
+        {
+            let f = std::fs::OpenOptions::new()
+                .write(true)
+                .open(harness.0.clone()).unwrap();
+            f.write_all_at(&GOLDEN, 0).unwrap();
+        }
+        let rt = runtime::Runtime::new().unwrap();
+        let (vdev, _label_reader) = rt.block_on(async {
+            VdevFile::open(harness.0.clone()).await
+        }).unwrap();
+        vdev.checksum_error();
+        let label_writer = LabelWriter::new(0);
+        rt.block_on(async { vdev.write_label(label_writer).await })
+            .unwrap();
+        drop(vdev);
+
+        let (vdev, _label_reader) = rt.block_on(async {
+            VdevFile::open(harness.0).await
+        }).unwrap();
+        let expected = ErrorCounts{read: 1, write: 2, checksum: 4};
+        assert_eq!(vdev.error_counts(), expected);
+        let _ = harness.1;
     }
 }
@@ -1,14 +1,18 @@
 // vim: tw=80
-//! FUSE filesystem access
+//! FUSE frontend for BFFFS
+//!
+//! Translates FUSE requests into operations on a [`Vfs`], which is normally a
+//! `bfffs_core::fs::Fs`.
 // Some conversions are only necessary for one of FreeBSD 11 or 12.  After libc
 // makes the switchover, delete this line and clean them up.
 #![allow(clippy::useless_conversion)]
 #![allow(clippy::unnecessary_cast)]
 
 use std::{
-    collections::hash_map::HashMap,
+    collections::{hash_map::HashMap, HashSet},
     ffi::{OsStr, OsString},
     os::unix::ffi::OsStrExt,
+    path::PathBuf,
     pin::Pin,
     slice,
     sync::{Arc, Mutex},
@@ -16,17 +20,21 @@ use std::{
 };
 
 use async_trait::async_trait;
-use bfffs_core::fs::{
-    self,
-    ExtAttr,
-    ExtAttrNamespace,
-    FileData,
-    FileDataMut,
-    SeekWhence,
-    Timespec,
+use bfffs_core::{
+    fs::{
+        self,
+        Advice,
+        ExtAttr,
+        ExtAttrNamespace,
+        FileData,
+        FileDataMut,
+        SeekWhence,
+        Timespec,
+    },
+    memory::{Consumer, MemoryBudget},
+    vfs::Vfs,
 };
 use bytes::Bytes;
-use cfg_if::cfg_if;
 use fuse3::{
     raw::{
         reply::{
@@ -39,6 +47,7 @@ use fuse3::{
             ReplyDirectory,
             ReplyEntry,
             ReplyLSeek,
+            ReplyOpen,
             ReplyStatFs,
             ReplyWrite,
             ReplyXAttr,
@@ -50,17 +59,11 @@ use fuse3::{
     SetAttr,
     Timestamp,
 };
-use futures::{Stream, TryFutureExt, TryStreamExt};
-
-cfg_if! {
-    if #[cfg(test)] {
-        mod mock;
-        use self::mock::MockFs as Fs;
-    } else {
-        use bfffs_core::fs::Fs;
-    }
-}
+use futures::{future::BoxFuture, Stream, TryFutureExt, TryStreamExt};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
+#[cfg(any(test, feature = "testing"))]
+pub mod mock;
 #[cfg(test)]
 mod tests;
 
@@ -68,12 +71,145 @@ mod tests;
 pub const FUSE_FALLOC_FL_KEEP_SIZE: u32 = 0x1;
 pub const FUSE_FALLOC_FL_PUNCH_HOLE: u32 = 0x2;
 
+/// Pseudo extended attribute for access pattern hints.
+///
+/// FUSE has no equivalent of `posix_fadvise(2)`, so instead applications may
+/// set `system.bfffs.fadvise` to one of "normal", "sequential", "random",
+/// "willneed", "dontneed", or "noreuse".  Reading it returns the file's current
+/// hint.
+const FADVISE_XATTR: &[u8] = b"bfffs.fadvise";
+
+/// Read-only pseudo extended attribute reporting how often access pattern
+/// hints have been applied, file system-wide.
+const FADVISE_STATS_XATTR: &[u8] = b"bfffs.fadvise_stats";
+
+/// Called whenever a file handle that was written through gets released.
+///
+/// The arguments are the file's inode number and its path relative to the
+/// root of the file system, if known.  The release isn't acknowledged to the
+/// kernel until the returned future completes.
+pub type CloseHook =
+    Arc<dyn Fn(u64, Option<PathBuf>) -> BoxFuture<'static, ()> + Send + Sync>;
+
+/// FuseFs's private name cache.
+///
+/// Maps a parent inode and the final component of a path name to the child's
+/// inode.  It also keeps a reverse index, so that all of an inode's names can
+/// be evicted when the kernel forgets that inode.
+#[derive(Debug, Default)]
+struct NameCache {
+    names: HashMap<(u64, OsString), u64>,
+    links: HashMap<u64, HashSet<(u64, OsString)>>,
+}
+
+impl NameCache {
+    fn contains_key(&self, key: &(u64, OsString)) -> bool {
+        self.names.contains_key(key)
+    }
+
+    /// Remove every name that refers to `ino`
+    fn evict(&mut self, ino: u64) {
+        if let Some(keys) = self.links.remove(&ino) {
+            for key in keys {
+                self.names.remove(&key);
+            }
+        }
+    }
+
+    fn get(&self, key: &(u64, OsString)) -> Option<&u64> {
+        self.names.get(key)
+    }
+
+    /// Reconstruct a path for `ino`, relative to the root of the file system,
+    /// from the names that the kernel has looked up.  Returns `None` if any
+    /// component is no longer cached.  If the file has several hard links, an
+    /// arbitrary one is chosen.
+    fn path(&self, mut ino: u64) -> Option<PathBuf> {
+        let mut components = Vec::new();
+        while ino != 1 {
+            // "." and ".." entries may be cached on behalf of the NFS server,
+            // but they don't lead toward the root.
+            let (parent, name) = self
+                .links
+                .get(&ino)?
+                .iter()
+                .find(|(_, name)| name != "." && name != "..")?;
+            components.push(name);
+            ino = *parent;
+        }
+        Some(components.into_iter().rev().collect())
+    }
+
+    fn insert(&mut self, key: (u64, OsString), ino: u64) -> Option<u64> {
+        let old_ino = self.names.insert(key.clone(), ino);
+        if let Some(oi) = old_ino {
+            self.unlink(oi, &key);
+        }
+        self.links.entry(ino).or_default().insert(key);
+        old_ino
+    }
+
+    fn remove(&mut self, key: &(u64, OsString)) -> Option<u64> {
+        let old_ino = self.names.remove(key);
+        if let Some(oi) = old_ino {
+            self.unlink(oi, key);
+        }
+        old_ino
+    }
+
+    /// Remove `key` from `ino`'s reverse index
+    fn unlink(&mut self, ino: u64, key: &(u64, OsString)) {
+        if let Some(keys) = self.links.get_mut(&ino) {
+            keys.remove(key);
+            if keys.is_empty() {
+                self.links.remove(&ino);
+            }
+        }
+    }
+}
+
+/// A limit on the number of bytes of read data that may be in flight at once.
+///
+/// Data read from the file system stays in memory until its reply is sent.  So
+/// a client issuing many large concurrent reads could otherwise exhaust
+/// bfffsd's memory.  Instead, reads that would exceed the budget wait for
+/// earlier ones to finish.  A single read larger than the entire budget is
+/// allowed, but only when nothing else is in flight.
+///
+/// A budget may be shared by several [`FuseFs`]s, to limit bfffsd as a whole.
+#[derive(Clone, Debug)]
+pub struct ReadBudget {
+    sem:   Arc<Semaphore>,
+    limit: u32,
+}
+
+impl ReadBudget {
+    /// Create a budget of `limit` bytes
+    pub fn new(limit: usize) -> Self {
+        let limit = u32::try_from(limit).unwrap_or(u32::MAX).max(1);
+        ReadBudget {
+            sem: Arc::new(Semaphore::new(limit as usize)),
+            limit,
+        }
+    }
+
+    /// Wait until `bytes` bytes are available, and reserve them until the
+    /// returned permit is dropped.
+    async fn acquire(&self, bytes: u32) -> OwnedSemaphorePermit {
+        self.sem
+            .clone()
+            .acquire_many_owned(bytes.min(self.limit))
+            .await
+            .expect("ReadBudget's semaphore should never be closed")
+    }
+}
+
 /// FUSE's handle to an BFFFS filesystem.  One per mountpoint.
 ///
 /// This object lives in the synchronous domain, and spawns commands into the
 /// Tokio domain.
-pub struct FuseFs {
-    fs:    Arc<Fs>,
+pub struct FuseFs<V: Vfs> {
+    fs:             Arc<V>,
     /// Basically a vnode cache for FuseFS.  It must always be in sync with
     /// the real vnode cache in the kernel.  It is an error to drop an entry
     /// from here if its `lookup_count` is non-zero.
@@ -82,17 +218,29 @@ pub struct FuseFs {
     // * forget: the kernel will ensure that it isn't called concurrently
     //    with any others.
     // * lookup: only increments lookup_count
+    // * setxattr: only changes the advice, which only affects future reads
     // and the only thing that ever cares about lookup_count is unlink, which
     // only cares about zero vs nonzero.
     // TODO: consider using chashmap instead.
     // NB: lock discipline: lock files before names
-    files: Mutex<HashMap<u64, FileDataMut>>,
+    files:          Mutex<HashMap<u64, FileDataMut>>,
     /// A private namecache, indexed by the parent inode and the final
     /// component of the path name.
-    names: Mutex<HashMap<(u64, OsString), u64>>,
+    names:          Mutex<NameCache>,
+    /// Limits the read data buffered for this mount alone
+    session_budget: Option<ReadBudget>,
+    /// Limits the read data buffered for this and other mounts combined
+    global_budget:  Option<ReadBudget>,
+    /// Accounts for read data alongside bfffsd's other large allocations
+    memory_budget:  Option<MemoryBudget>,
+    /// Notified when a file handle that was written through is released
+    close_hook:     Option<CloseHook>,
+    /// File handles that have been written through since they were opened.
+    /// Only tracked if there is a `close_hook`.
+    written:        Mutex<HashSet<u64>>,
 }
 
-impl FuseFs {
+impl<V: Vfs> FuseFs<V> {
     // Allow the kernel to cache attributes and entries for an unlimited amount
     // of time, since all changes will come through the kernel.
     const TTL: Duration = Duration::from_secs(u64::MAX);
@@ -132,6 +280,37 @@ impl FuseFs {
         );
     }
 
+    /// Drop `nlookup` of the kernel's references to `ino`, or all of them if
+    /// `None`.  Once none remain, evict the inode from the caches and release
+    /// the `Fs` layer's state for it.
+    async fn do_forget(&self, ino: u64, nlookup: Option<u64>) {
+        if ino == 1 {
+            // Special case: since fusefs never does a lookup for the root
+            // inode, its FORGETs may be "unmatched"
+            return;
+        }
+        let ofd = {
+            let mut files_guard = self.files.lock().unwrap();
+            let fd = files_guard
+                .get_mut(&ino)
+                .expect("Forget before lookup or double-forget");
+            let nlookup = nlookup.unwrap_or(fd.lookup_count);
+            fd.lookup_count = fd
+                .lookup_count
+                .checked_sub(nlookup)
+                .expect("Forgot more lookups than were made");
+            if fd.lookup_count == 0 {
+                self.names.lock().unwrap().evict(ino);
+                files_guard.remove(&ino)
+            } else {
+                None
+            }
+        };
+        if let Some(fd) = ofd {
+            self.fs.inactive(fd).await;
+        }
+    }
+
     /// Private helper for getattr-like operations
     async fn do_getattr(&self, fd: &FileData) -> Result<FileAttr, i32> {
         match self.fs.getattr(fd).await {
@@ -177,10 +356,33 @@ impl FuseFs {
         }
     }
 
-    pub fn new(fs: Arc<Fs>) -> Self {
+    pub fn new(fs: Arc<V>) -> Self {
         FuseFs::from(fs)
     }
 
+    /// Call `hook` whenever a file handle that was written through gets
+    /// released.
+    pub fn close_hook(&mut self, hook: CloseHook) {
+        self.close_hook = Some(hook);
+    }
+
+    /// Share `budget` with other mounts, limiting how much read data they
+    /// may buffer all together.
+    pub fn global_read_budget(&mut self, budget: ReadBudget) {
+        self.global_budget = Some(budget);
+    }
+
+    /// Charge buffered read data to `budget`, which is shared with the rest of
+    /// bfffsd.  Reads will wait while other subsystems need the memory.
+    pub fn memory_budget(&mut self, budget: MemoryBudget) {
+        self.memory_budget = Some(budget);
+    }
+
+    /// Limit how many bytes of read data this mount may buffer at once.
+    pub fn session_read_limit(&mut self, limit: usize) {
+        self.session_budget = Some(ReadBudget::new(limit));
+    }
+
     /// Actually send a ReplyEntry
     fn reply_entry(&self, attr: FileAttr) -> ReplyEntry {
         ReplyEntry {
@@ -215,9 +417,51 @@ impl FuseFs {
         }
     }
 
+    /// Apply an access pattern hint, set via [`FADVISE_XATTR`].
+    async fn fadvise(&self, ino: u64, value: &[u8]) -> fuse3::Result<()> {
+        let advice = std::str::from_utf8(value)
+            .ok()
+            .and_then(|s| s.trim().parse::<Advice>().ok())
+            .ok_or_else(|| fuse3::Errno::from(libc::EINVAL))?;
+        let fd = {
+            let mut files = self.files.lock().unwrap();
+            let fdm = files
+                .get_mut(&ino)
+                .expect("setxattr before lookup or after forget");
+            fdm.set_advice(advice);
+            fdm.handle()
+        };
+        self.fs.fadvise(&fd, advice).await.map_err(fuse3::Errno::from)
+    }
+
+    /// Get the value of one of the pseudo extended attributes that bfffsd
+    /// synthesizes rather than storing on disk, if `name` refers to one.
+    fn pseudo_xattr(
+        &self,
+        fd: &FileData,
+        ns: ExtAttrNamespace,
+        name: &OsStr,
+    ) -> Option<Vec<u8>> {
+        if ns != ExtAttrNamespace::System {
+            return None;
+        }
+        match name.as_bytes() {
+            FADVISE_XATTR => Some(fd.advice().as_str().as_bytes().to_vec()),
+            FADVISE_STATS_XATTR => {
+                Some(self.fs.fadvise_stats().to_string().into_bytes())
+            }
+            _ => None,
+        }
+    }
+
     /// Split a packed xattr name of the form "namespace.name" into its
     /// components
-    fn split_xattr_name(packed_name: &OsStr) -> (ExtAttrNamespace, &OsStr) {
+    ///
+    /// Names in namespaces other than "user" and "system", like Linux's
+    /// "trusted" namespace, are not supported.
+    fn split_xattr_name(
+        packed_name: &OsStr,
+    ) -> fuse3::Result<(ExtAttrNamespace, &OsStr)> {
         // FUSE packs namespace into the name, separated by a "."
         let mut groups =
             packed_name.as_bytes().splitn(2, |&b| b == b'.').take(2);
@@ -227,16 +471,18 @@ impl FuseFs {
         } else if ns_str == "system" {
             ExtAttrNamespace::System
         } else {
-            panic!("Unknown namespace {ns_str:?}")
+            return Err(libc::EOPNOTSUPP.into());
         };
-        let name = OsStr::from_bytes(groups.next().unwrap());
-        (ns, name)
+        let name = groups
+            .next()
+            .map(OsStr::from_bytes)
+            .ok_or_else(|| fuse3::Errno::from(libc::EINVAL))?;
+        Ok((ns, name))
     }
 
     #[allow(clippy::if_same_then_else)]
     fn uncache_name(&self, parent_ino: u64, name: &OsStr) {
         let name_key = (parent_ino, name.to_owned());
-        // TODO: handle multiply linked files
         if let Some(_ino) = self.names.lock().unwrap().remove(&name_key) {
             /* FORGET will come separately */
         } else {
@@ -245,15 +491,14 @@ impl FuseFs {
     }
 }
 
-#[cfg(test)]
-impl Default for FuseFs {
+impl<V: Vfs + Default> Default for FuseFs<V> {
     fn default() -> Self {
-        FuseFs::new(Arc::new(Fs::default()))
+        FuseFs::new(Arc::new(V::default()))
     }
 }
 
 #[async_trait]
-impl Filesystem for FuseFs {
+impl<V: Vfs> Filesystem for FuseFs<V> {
     // TODO: implement readdirplus
     type DirEntryPlusStream =
         Pin<Box<dyn Stream<Item = fuse3::Result<DirectoryEntryPlus>> + Send>>;
@@ -264,6 +509,11 @@ impl Filesystem for FuseFs {
         Ok(())
     }
 
+    // There's deliberately no access method.  bfffs doesn't know the caller's
+    // supplementary groups, nor does it interpret ACLs, so it can't answer
+    // FUSE_ACCESS correctly.  Mount with default_permissions instead, so the
+    // kernel checks permissions itself.
+
     // FreeBSD's VOP_CREATE doesn't forward the open(2) flags, so the kernel
     // hardcodes them to O_CREAT | O_RDWR.  O_CREAT is implied by FUSE_CREATE,
     // and O_RDWR doesn't matter to the FS layer, so bfffs ignores those flags.
@@ -273,7 +523,7 @@ impl Filesystem for FuseFs {
         parent: u64,
         name: &OsStr,
         mode: u32,
-        _flags: u32,
+        flags: u32,
     ) -> fuse3::Result<ReplyCreated> {
         let parent_fd = self
             .files
@@ -291,11 +541,14 @@ impl Filesystem for FuseFs {
             .create(&parent_fd, name, perm, req.uid, req.gid)
             .await?;
         let r = self.do_getattr(&fd.handle()).await;
-        if r.is_ok() {
+        let fh = if r.is_ok() {
+            let fh = self.fs.open(&fd.handle(), req.pid, flags);
             self.cache_file(parent, name, fd);
+            fh
         } else {
             self.fs.inactive(fd).await;
-        }
+            0
+        };
         match r {
             Ok(file_attr) => {
                 // The generation number is only used for filesystems exported
@@ -306,7 +559,7 @@ impl Filesystem for FuseFs {
                     ttl: Self::TTL,
                     attr: file_attr,
                     generation,
-                    fh: 0,
+                    fh,
                     flags: 0,
                 })
             }
@@ -345,23 +598,16 @@ impl Filesystem for FuseFs {
     }
 
     async fn forget(&self, _req: Request, ino: u64, nlookup: u64) {
-        // XXX will FUSE_FORGET ever be sent with nlookup less than the actual
-        // lookup count?  Not as far as I know.
-        // TODO: figure out how to expire entries from the name cache, too
-        if ino == 1 {
-            // Special case: since fusefs never does a lookup for the root
-            // inode, its FORGETs may be "unmatched"
-            return;
+        self.do_forget(ino, Some(nlookup)).await
+    }
+
+    async fn batch_forget(&self, _req: Request, inodes: &[u64]) {
+        // fuse3 doesn't pass along the lookup counts.  But the kernel only
+        // sends FUSE_BATCH_FORGET when it evicts vnodes from its own cache, so
+        // it must be forgetting every lookup that it ever made.
+        for ino in inodes {
+            self.do_forget(*ino, None).await
         }
-        let mut fd = self
-            .files
-            .lock()
-            .unwrap()
-            .remove(&ino)
-            .expect("Forget before lookup or double-forget");
-        fd.lookup_count -= nlookup;
-        assert_eq!(fd.lookup_count, 0, "Partial forgets are not yet handled");
-        self.fs.inactive(fd).await;
     }
 
     async fn fsync(
@@ -369,7 +615,7 @@ impl Filesystem for FuseFs {
         _req: Request,
         ino: u64,
         _fh: u64,
-        _datasync: bool,
+        datasync: bool,
     ) -> fuse3::Result<()> {
         let fd = self
             .files
@@ -378,7 +624,14 @@ impl Filesystem for FuseFs {
             .get(&ino)
             .expect("fsync before lookup or after forget")
             .handle();
-        self.fs.fsync(&fd).await.map_err(fuse3::Errno::from)
+        // FUSE has no sync_file_range operation, so fdatasync is the finest
+        // grained barrier available to applications.
+        let r = if datasync {
+            self.fs.fdatasync(&fd).await
+        } else {
+            self.fs.fsync(&fd).await
+        };
+        r.map_err(fuse3::Errno::from)
     }
 
     async fn getattr(
@@ -420,7 +673,16 @@ impl Filesystem for FuseFs {
             .get(&ino)
             .expect("getxattr before lookup or after forget")
             .handle();
-        let (ns, name) = FuseFs::split_xattr_name(packed_name);
+        let (ns, name) = Self::split_xattr_name(packed_name)?;
+        if let Some(value) = self.pseudo_xattr(&fd, ns, name) {
+            return if size == 0 {
+                Ok(ReplyXAttr::Size(value.len() as u32))
+            } else if value.len() <= size as usize {
+                Ok(ReplyXAttr::Data(Bytes::from(value)))
+            } else {
+                Err(libc::ERANGE.into())
+            };
+        }
         if size == 0 {
             match self.fs.getextattrlen(&fd, ns, name).await {
                 Ok(len) => Ok(ReplyXAttr::Size(len)),
@@ -746,14 +1008,32 @@ impl Filesystem for FuseFs {
         self.handle_new_entry(r, parent, name).await
     }
 
+    async fn open(
+        &self,
+        req: Request,
+        ino: u64,
+        flags: u32,
+    ) -> fuse3::Result<ReplyOpen> {
+        let fd = self
+            .files
+            .lock()
+            .unwrap()
+            .get(&ino)
+            .expect("open before lookup or after forget")
+            .handle();
+        let fh = self.fs.open(&fd, req.pid, flags);
+        Ok(ReplyOpen { fh, flags: 0 })
+    }
+
     async fn read(
         &self,
         _req: Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: u64,
         size: u32,
     ) -> fuse3::Result<ReplyData> {
+        self.fs.check_handle(fh)?;
         let fd = self
             .files
             .lock()
@@ -761,6 +1041,23 @@ impl Filesystem for FuseFs {
             .get(&ino)
             .expect("read before lookup or after forget")
             .handle();
+        // Hold the permits until the reply has been built.  Always acquire
+        // the session's budget first, so a client waiting on its own budget
+        // can't hold the global one.
+        let _session_permit = match &self.session_budget {
+            Some(budget) => Some(budget.acquire(size).await),
+            None => None,
+        };
+        let _global_permit = match &self.global_budget {
+            Some(budget) => Some(budget.acquire(size).await),
+            None => None,
+        };
+        let _reservation = match &self.memory_budget {
+            Some(budget) => {
+                Some(budget.reserve(Consumer::Fuse, size as usize).await)
+            }
+            None => None,
+        };
         match self.fs.read(&fd, offset, size as usize).await {
             Ok(sglist) => {
                 // Vectored data requires an additional data copy, thanks to
@@ -839,6 +1136,25 @@ impl Filesystem for FuseFs {
         }
     }
 
+    async fn release(
+        &self,
+        _req: Request,
+        ino: u64,
+        fh: u64,
+        _flags: u32,
+        _lock_owner: u64,
+        _flush: bool,
+    ) -> fuse3::Result<()> {
+        self.fs.release(fh);
+        if let Some(hook) = &self.close_hook {
+            if self.written.lock().unwrap().remove(&fh) {
+                let path = self.names.lock().unwrap().path(ino);
+                hook(ino, path).await;
+            }
+        }
+        Ok(())
+    }
+
     async fn removexattr(
         &self,
         _req: Request,
@@ -852,7 +1168,7 @@ impl Filesystem for FuseFs {
             .get(&ino)
             .expect("removexattr before lookup or after forget")
             .handle();
-        let (ns, name) = FuseFs::split_xattr_name(packed_name);
+        let (ns, name) = Self::split_xattr_name(packed_name)?;
         self.fs
             .deleteextattr(&fd, ns, name)
             .map_err(fuse3::Errno::from)
@@ -1020,6 +1336,10 @@ impl Filesystem for FuseFs {
         _flags: u32,
         _position: u32,
     ) -> fuse3::Result<()> {
+        let (ns, name) = Self::split_xattr_name(packed_name)?;
+        if ns == ExtAttrNamespace::System && name.as_bytes() == FADVISE_XATTR {
+            return self.fadvise(ino, value).await;
+        }
         let fd = self
             .files
             .lock()
@@ -1027,7 +1347,6 @@ impl Filesystem for FuseFs {
             .get(&ino)
             .expect("setxattr before lookup or after forget")
             .handle();
-        let (ns, name) = FuseFs::split_xattr_name(packed_name);
         match self.fs.setextattr(&fd, ns, name, value).await {
             Ok(()) => Ok(()),
             Err(e) => Err(e.into()),
@@ -1130,11 +1449,12 @@ impl Filesystem for FuseFs {
         &self,
         _req: Request,
         ino: u64,
-        _fh: u64,
+        fh: u64,
         offset: u64,
         data: &[u8],
         flags: u32,
     ) -> fuse3::Result<ReplyWrite> {
+        self.fs.check_handle(fh)?;
         let fd = self
             .files
             .lock()
@@ -1143,23 +1463,33 @@ impl Filesystem for FuseFs {
             .expect("write before lookup or after forget")
             .handle();
         match self.fs.write(&fd, offset, data, flags).await {
-            Ok(lsize) => Ok(ReplyWrite { written: lsize }),
+            Ok(lsize) => {
+                if self.close_hook.is_some() {
+                    self.written.lock().unwrap().insert(fh);
+                }
+                Ok(ReplyWrite { written: lsize })
+            }
             Err(e) => Err(e.into()),
         }
     }
 }
 
-impl From<Arc<Fs>> for FuseFs {
-    fn from(fs: Arc<Fs>) -> Self {
+impl<V: Vfs> From<Arc<V>> for FuseFs<V> {
+    fn from(fs: Arc<V>) -> Self {
         let mut files = HashMap::default();
-        let names = HashMap::default();
+        let names = NameCache::default();
         // fusefs(5) looks up the root inode (see fuse_vfsop_root).  Prepopulate
         // it into the cache.
         files.insert(1, fs.root());
         FuseFs {
             fs,
-            files: Mutex::new(files),
-            names: Mutex::new(names),
+            files:          Mutex::new(files),
+            names:          Mutex::new(names),
+            session_budget: None,
+            global_budget:  None,
+            memory_budget:  None,
+            close_hook:     None,
+            written:        Mutex::new(HashSet::new()),
         }
     }
 }
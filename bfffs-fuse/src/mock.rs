@@ -0,0 +1,103 @@
+// vim: tw=80
+// LCOV_EXCL_START
+//! Mock objects for bfffs-fuse
+
+use std::ffi::{OsStr, OsString};
+
+use async_trait::async_trait;
+use bfffs_core::{
+    fs::{
+        Advice,
+        ExtAttr,
+        ExtAttrNamespace,
+        FadviseStats,
+        FileData,
+        FileDataMut,
+        GetAttr,
+        SeekWhence,
+        SetAttr,
+    },
+    vfs::Vfs,
+    SGList,
+};
+use divbuf::DivBuf;
+use futures::stream::BoxStream;
+use libc::dev_t;
+use mockall::mock;
+
+mock! {
+    pub Fs {}
+    #[async_trait]
+    impl Vfs for Fs {
+        fn check_handle(&self, fh: u64) -> Result<(), i32>;
+        async fn create(&self, parent: &FileData, name: &OsStr, perm: u16,
+            uid: u32, gid: u32) -> Result<FileDataMut, i32>;
+        async fn deallocate(&self, fd: &FileData, offset: u64, len: u64)
+            -> Result<(), i32>;
+        async fn deleteextattr(&self, fd: &FileData, ns: ExtAttrNamespace,
+            name: &OsStr) -> Result<(), i32>;
+        async fn fadvise(&self, fd: &FileData, advice: Advice)
+            -> Result<(), i32>;
+        fn fadvise_stats(&self) -> FadviseStats;
+        async fn fdatasync(&self, fd: &FileData) -> Result<(), i32>;
+        async fn fsync(&self, fd: &FileData) -> Result<(), i32>;
+        async fn getattr(&self, fd: &FileData) -> Result<GetAttr, i32>;
+        async fn getextattr(&self, fd: &FileData, ns: ExtAttrNamespace,
+            name: &OsStr)
+            -> Result<DivBuf, i32>;
+        async fn getextattrlen(&self, fd: &FileData, ns: ExtAttrNamespace,
+            name: &OsStr) -> Result<u32, i32>;
+        async fn ilookup(&self, ino: u64) -> Result<FileDataMut, i32>;
+        async fn inactive(&self, fd: FileDataMut);
+        async fn link(&self, parent: &FileData, fd: &FileData, name: &OsStr)
+            -> Result<(), i32>;
+        async fn listextattr<F>(&self, fd: &FileData, size: u32, f: F)
+            -> Result<Vec<u8>, i32>
+            where F: Fn(&mut Vec<u8>, &ExtAttr) + Send + 'static;
+        async fn listextattrlen<F>(&self, fd: &FileData, f: F)
+            -> Result<u32, i32>
+            where F: Fn(&ExtAttr) -> u32 + Send + 'static;
+        async fn lookup<'a>(&self, grandparent: Option<&'a FileData>,
+            parent: &'a FileData, name: &OsStr) -> Result<FileDataMut, i32>;
+        async fn lseek(&self, fd: &FileData, offset: u64,
+            whence: SeekWhence) -> Result<u64, i32>;
+        async fn mkblock(&self, parent: &FileData, name: &OsStr, perm: u16,
+            uid: u32, gid: u32, rdev: dev_t) -> Result<FileDataMut, i32>;
+        async fn mkchar(&self, parent: &FileData, name: &OsStr, perm: u16,
+            uid: u32, gid: u32, rdev: dev_t) -> Result<FileDataMut, i32>;
+        async fn mkdir(&self, parent: &FileData, name: &OsStr, perm: u16,
+            id: u32, gid: u32) -> Result<FileDataMut, i32>;
+        async fn mkfifo(&self, parent: &FileData, name: &OsStr, perm: u16,
+            uid: u32, gid: u32) -> Result<FileDataMut, i32>;
+        async fn mksock(&self, parent: &FileData, name: &OsStr, perm: u16,
+            uid: u32, gid: u32) -> Result<FileDataMut, i32>;
+        fn open(&self, fd: &FileData, pid: u32, flags: u32) -> u64;
+        async fn read(&self, fd: &FileData, offset: u64, size: usize)
+            -> Result<SGList, i32>;
+        fn readdir(&self, fd: &FileData, soffs: i64)
+            -> BoxStream<'static, Result<(libc::dirent, i64), i32>>;
+        async fn readlink(&self, fd: &FileData) -> Result<OsString, i32>;
+        fn release(&self, fh: u64);
+        async fn rename<'a>(&self, parent: &'a FileData, fd: &'a FileData,
+            name: &'a OsStr, newparent: &'a FileData, newino: Option<u64>,
+            newname: &'a OsStr)
+            -> Result<u64, i32>;
+        async fn rmdir(&self, parent: &FileData, name: &OsStr)
+            -> Result<(), i32>;
+        fn root(&self) -> FileDataMut;
+        async fn setattr(&self, fd: &FileData, attr: SetAttr)
+            -> Result<(), i32>;
+        async fn setextattr(&self, fd: &FileData, ns: ExtAttrNamespace,
+                      name: &OsStr, data: &[u8]) -> Result<(), i32>;
+        async fn statvfs(&self) -> Result<libc::statvfs, i32>;
+        async fn symlink(&self, parent: &FileData, name: &OsStr, perm: u16,
+            uid: u32, gid: u32, link: &OsStr) -> Result<FileDataMut, i32>;
+        async fn sync(&self);
+        async fn unlink<'a>(&self, parent: &'a FileData,
+            fd: Option<&'a FileData>, name: &'a OsStr)
+            -> Result<(), i32>;
+        async fn write(&self, fd: &FileData, offset: u64, data: &[u8],
+            flags: u32) -> Result<u32, i32>;
+    }
+}
+// LCOV_EXCL_STOP
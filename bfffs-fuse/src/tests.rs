@@ -1,11 +1,14 @@
 // vim: tw=80
 use std::mem;
 
-use bfffs_core::fs::{FileData, GetAttr, Mode};
+use bfffs_core::fs::{Advice, FadviseStats, FileData, GetAttr, Mode};
 use futures::FutureExt;
 use mockall::{predicate, Sequence};
 
 use super::*;
+use crate::mock::MockFs as Fs;
+
+type FuseFs = crate::FuseFs<Fs>;
 
 fn assert_cached(fusefs: &FuseFs, parent_ino: u64, name: &OsStr, ino: u64) {
     assert!(fusefs.files.lock().unwrap().contains_key(&ino));
@@ -35,6 +38,7 @@ where
         .expect_root()
         .returning(|| FileDataMut::new_for_tests(None, 1));
     f(&mut mock_fs);
+    mock_fs.expect_check_handle().returning(|_| Ok(()));
     FuseFs::from(Arc::new(mock_fs))
 }
 
@@ -147,10 +151,13 @@ mod create {
         let ino = 43;
         let uid = 12345u32;
         let gid = 54321u32;
+        let pid = 1234u32;
+        let fh = 5;
 
         let request = Request {
             uid,
             gid,
+            pid,
             ..Default::default()
         };
 
@@ -188,7 +195,17 @@ mod create {
                     rdev: 0,
                     blksize: 131072,
                     flags: 0,
+                    change: 0,
                 }));
+            mock_fs
+                .expect_open()
+                .times(1)
+                .with(
+                    predicate::function(move |fd: &FileData| fd.ino() == ino),
+                    predicate::eq(pid),
+                    predicate::eq(FLAGS),
+                )
+                .return_const(fh);
         });
 
         fusefs
@@ -208,6 +225,7 @@ mod create {
         assert_eq!(reply.attr.nlink, 1);
         assert_eq!(reply.attr.uid, uid);
         assert_eq!(reply.attr.gid, gid);
+        assert_eq!(reply.fh, fh);
         assert_cached(&fusefs, parent, name, ino);
     }
 }
@@ -250,6 +268,30 @@ mod removexattr {
         assert_eq!(reply, Err(libc::ENOATTR.into()));
     }
 
+    /// Linux's trusted namespace isn't supported
+    #[test]
+    fn eopnotsupp() {
+        let ino = 42;
+        let packed_name = OsStr::from_bytes(b"trusted.overlay.opaque");
+
+        let request = Request::default();
+
+        let fusefs = make_mock_fs(|mock_fs| {
+            mock_fs.expect_deleteextattr().never();
+        });
+
+        fusefs
+            .files
+            .lock()
+            .unwrap()
+            .insert(ino, FileDataMut::new_for_tests(None, ino));
+        let reply = fusefs
+            .removexattr(request, ino, packed_name)
+            .now_or_never()
+            .unwrap();
+        assert_eq!(reply, Err(libc::EOPNOTSUPP.into()));
+    }
+
     #[test]
     fn ok() {
         let ino = 42;
@@ -334,6 +376,7 @@ mod forget {
                     rdev: 0,
                     blksize: 4096,
                     flags: 0,
+                    change: 0,
                 }));
             mock_fs
                 .expect_inactive()
@@ -357,11 +400,166 @@ mod forget {
         fusefs.forget(request, ino, 1).now_or_never().unwrap();
         assert!(!fusefs.files.lock().unwrap().contains_key(&ino))
     }
+
+    /// batch_forget should forget every lookup of each inode
+    #[test]
+    fn batch() {
+        let parent = 42;
+        let ino0 = 43;
+        let ino1 = 44;
+        let name0 = OsStr::from_bytes(b"foo.txt");
+        let name1 = OsStr::from_bytes(b"bar.txt");
+
+        let request = Request::default();
+
+        let fusefs = make_mock_fs(|mock_fs| {
+            mock_fs
+                .expect_inactive()
+                .withf(move |fd| fd.ino() == ino0 || fd.ino() == ino1)
+                .times(2)
+                .return_const(());
+        });
+
+        {
+            let mut files_guard = fusefs.files.lock().unwrap();
+            files_guard
+                .insert(parent, FileDataMut::new_for_tests(Some(1), parent));
+            let mut fd0 = FileDataMut::new_for_tests(None, ino0);
+            fd0.lookup_count = 3;
+            files_guard.insert(ino0, fd0);
+            files_guard.insert(ino1, FileDataMut::new_for_tests(None, ino1));
+        }
+        {
+            let mut names_guard = fusefs.names.lock().unwrap();
+            names_guard.insert((parent, name0.to_owned()), ino0);
+            names_guard.insert((parent, name1.to_owned()), ino1);
+        }
+
+        fusefs
+            .batch_forget(request, &[ino0, ino1])
+            .now_or_never()
+            .unwrap();
+        assert_not_cached(&fusefs, parent, name0, Some(ino0));
+        assert_not_cached(&fusefs, parent, name1, Some(ino1));
+        assert!(fusefs.files.lock().unwrap().contains_key(&parent));
+    }
+
+    /// Forgetting an inode should evict all of its names from the name cache,
+    /// but nobody else's.
+    #[test]
+    fn evicts_names() {
+        let parent = 42;
+        let ino = 43;
+        let other_ino = 44;
+        let name0 = OsStr::from_bytes(b"foo.txt");
+        let name1 = OsStr::from_bytes(b"foo_link.txt");
+        let other_name = OsStr::from_bytes(b"bar.txt");
+
+        let request = Request::default();
+
+        let fusefs = make_mock_fs(|mock_fs| {
+            mock_fs
+                .expect_inactive()
+                .withf(move |fd| fd.ino() == ino)
+                .times(1)
+                .return_const(());
+        });
+
+        {
+            let mut files_guard = fusefs.files.lock().unwrap();
+            files_guard
+                .insert(parent, FileDataMut::new_for_tests(Some(1), parent));
+            files_guard.insert(ino, FileDataMut::new_for_tests(None, ino));
+            files_guard.insert(
+                other_ino,
+                FileDataMut::new_for_tests(None, other_ino),
+            );
+        }
+        {
+            let mut names_guard = fusefs.names.lock().unwrap();
+            names_guard.insert((parent, name0.to_owned()), ino);
+            names_guard.insert((parent, name1.to_owned()), ino);
+            names_guard.insert((parent, other_name.to_owned()), other_ino);
+        }
+
+        fusefs.forget(request, ino, 1).now_or_never().unwrap();
+        assert_not_cached(&fusefs, parent, name0, Some(ino));
+        assert_not_cached(&fusefs, parent, name1, Some(ino));
+        assert_cached(&fusefs, parent, other_name, other_ino);
+    }
+
+    /// A forget for fewer than all of an inode's lookups should leave it cached
+    #[test]
+    fn partial() {
+        let parent = 42;
+        let ino = 43;
+        let name = OsStr::from_bytes(b"foo.txt");
+
+        let request = Request::default();
+
+        let fusefs = make_mock_fs(|mock_fs| {
+            mock_fs
+                .expect_inactive()
+                .withf(move |fd| fd.ino() == ino)
+                .times(1)
+                .return_const(());
+        });
+
+        {
+            let mut files_guard = fusefs.files.lock().unwrap();
+            files_guard
+                .insert(parent, FileDataMut::new_for_tests(Some(1), parent));
+            let mut fd = FileDataMut::new_for_tests(None, ino);
+            fd.lookup_count = 3;
+            files_guard.insert(ino, fd);
+        }
+        fusefs
+            .names
+            .lock()
+            .unwrap()
+            .insert((parent, name.to_owned()), ino);
+
+        fusefs.forget(request, ino, 2).now_or_never().unwrap();
+        assert_cached(&fusefs, parent, name, ino);
+        assert_eq!(fusefs.files.lock().unwrap()[&ino].lookup_count, 1);
+
+        fusefs.forget(request, ino, 1).now_or_never().unwrap();
+        assert_not_cached(&fusefs, parent, name, Some(ino));
+    }
 }
 
 mod fsync {
     use super::*;
 
+    /// With datasync set, FuseFs should use the cheaper fdatasync
+    #[test]
+    fn datasync() {
+        let ino = 42;
+        let fh = 0xdeadbeef;
+
+        let request = Request::default();
+
+        let fusefs = make_mock_fs(|mock_fs| {
+            mock_fs.expect_fsync().never();
+            mock_fs
+                .expect_fdatasync()
+                .times(1)
+                .with(predicate::function(move |fd: &FileData| fd.ino() == ino))
+                .return_const(Ok(()));
+        });
+
+        fusefs
+            .files
+            .lock()
+            .unwrap()
+            .insert(ino, FileDataMut::new_for_tests(None, ino));
+        let reply = fusefs
+            .fsync(request, ino, fh, true)
+            .now_or_never()
+            .unwrap();
+        assert!(reply.is_ok());
+    }
+
     #[test]
     fn eio() {
         let ino = 42;
@@ -476,6 +674,7 @@ mod getattr {
                     rdev: 0,
                     blksize: 8192,
                     flags: 0,
+                    change: 0,
                 }));
         });
 
@@ -506,6 +705,43 @@ mod getxattr {
 
     use super::*;
 
+    /// The fadvise_stats pseudo-attribute should never be read from disk
+    #[test]
+    fn fadvise_stats() {
+        let ino = 42;
+        let packed_name = OsStr::from_bytes(b"system.bfffs.fadvise_stats");
+        let wantsize = 256;
+        let stats = FadviseStats {
+            sequential: 1,
+            dontneed: 2,
+            noreuse: 3,
+            readahead: 4,
+            evicted: 5,
+            uncached: 6,
+        };
+
+        let request = Request::default();
+
+        let fusefs = make_mock_fs(|mock_fs| {
+            mock_fs.expect_getextattr().never();
+            mock_fs.expect_fadvise_stats().times(1).return_const(stats);
+        });
+
+        fusefs
+            .files
+            .lock()
+            .unwrap()
+            .insert(ino, FileDataMut::new_for_tests(None, ino));
+        let reply = fusefs
+            .getxattr(request, ino, packed_name, wantsize)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        let expected = "sequential: 1\ndontneed: 2\nnoreuse: 3\n\
+                        readahead: 4\nevicted: 5\nuncached: 6\n";
+        assert_eq!(reply, ReplyXAttr::Data(Bytes::from(expected)));
+    }
+
     #[test]
     fn length_enoattr() {
         let ino = 42;
@@ -844,6 +1080,7 @@ mod link {
                     rdev: 0,
                     blksize: 16384,
                     flags: 0,
+                    change: 0,
                 }));
         });
 
@@ -1114,6 +1351,7 @@ mod lookup {
                     rdev: 0,
                     blksize: 32768,
                     flags: 0,
+                    change: 0,
                 }));
         });
 
@@ -1181,6 +1419,7 @@ mod lookup {
                     rdev: 0,
                     blksize: 0,
                     flags: 0,
+                    change: 0,
                 }));
             mock_fs
                 .expect_lookup()
@@ -1257,6 +1496,7 @@ mod lookup {
                     rdev: 0,
                     blksize: 0,
                     flags: 0,
+                    change: 0,
                 }));
             mock_fs
                 .expect_ilookup()
@@ -1320,6 +1560,7 @@ mod lookup {
                     rdev: 0,
                     blksize: 0,
                     flags: 0,
+                    change: 0,
                 }));
             mock_fs
                 .expect_lookup()
@@ -1459,6 +1700,7 @@ mod lookup {
                     rdev: 0,
                     blksize: 4096,
                     flags: 0,
+                    change: 0,
                 }));
         });
 
@@ -1532,6 +1774,7 @@ mod lookup {
                     rdev: 0,
                     blksize: 4096,
                     flags: 0,
+                    change: 0,
                 }));
         });
 
@@ -1658,6 +1901,7 @@ mod lookup {
                     rdev: 0,
                     blksize: 4096,
                     flags: 0,
+                    change: 0,
                 }));
         });
 
@@ -1893,6 +2137,7 @@ mod mkdir {
                 blksize: 4096,
                 rdev: 0,
                 flags: 0,
+                change: 0,
             }));
         });
 
@@ -1952,7 +2197,7 @@ mod mknod {
                     predicate::eq(mode),
                     predicate::eq(uid),
                     predicate::eq(gid),
-                    predicate::eq(rdev),
+                    predicate::eq(libc::dev_t::from(rdev)),
                 )
                 .returning(move |_, _, _, _, _, _| {
                     Ok(FileDataMut::new_for_tests(None, ino))
@@ -1976,6 +2221,7 @@ mod mknod {
                     rdev: rdev as libc::dev_t,
                     blksize: 4096,
                     flags: 0,
+                    change: 0,
                 }));
         });
 
@@ -2030,7 +2276,7 @@ mod mknod {
                     predicate::eq(mode),
                     predicate::eq(uid),
                     predicate::eq(gid),
-                    predicate::eq(rdev),
+                    predicate::eq(libc::dev_t::from(rdev)),
                 )
                 .returning(move |_, _, _, _, _, _| {
                     Ok(FileDataMut::new_for_tests(None, ino))
@@ -2054,6 +2300,7 @@ mod mknod {
                     rdev: rdev as libc::dev_t,
                     blksize: 4096,
                     flags: 0,
+                    change: 0,
                 }));
         });
 
@@ -2173,6 +2420,7 @@ mod mknod {
                     rdev: 0,
                     blksize: 4096,
                     flags: 0,
+                    change: 0,
                 }));
         });
 
@@ -2248,6 +2496,7 @@ mod mknod {
                     rdev: 0,
                     blksize: 4096,
                     flags: 0,
+                    change: 0,
                 }));
         });
 
@@ -2272,12 +2521,86 @@ mod mknod {
     }
 }
 
+mod open {
+    use super::*;
+
+    #[test]
+    fn ok() {
+        let ino = 42;
+        let pid = 1234u32;
+        let flags = libc::O_RDONLY as u32;
+        let fh = 5;
+
+        let request = Request {
+            pid,
+            ..Default::default()
+        };
+
+        let fusefs = make_mock_fs(|mock_fs| {
+            mock_fs
+                .expect_open()
+                .times(1)
+                .with(
+                    predicate::function(move |fd: &FileData| fd.ino() == ino),
+                    predicate::eq(pid),
+                    predicate::eq(flags),
+                )
+                .return_const(fh);
+        });
+
+        fusefs
+            .files
+            .lock()
+            .unwrap()
+            .insert(ino, FileDataMut::new_for_tests(None, ino));
+        let reply = fusefs
+            .open(request, ino, flags)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply.fh, fh);
+    }
+}
+
 mod read {
     use bfffs_core::SGList;
     use divbuf::*;
 
     use super::*;
 
+    /// Reading through a handle that was force-closed should fail
+    #[test]
+    fn ebadf() {
+        let fh = 0xdeadbeef;
+        let ino = 42;
+
+        let request = Request::default();
+
+        let mut mock_fs = Fs::default();
+        mock_fs
+            .expect_root()
+            .returning(|| FileDataMut::new_for_tests(None, 1));
+        mock_fs
+            .expect_check_handle()
+            .with(predicate::eq(fh))
+            .return_const(Err(libc::EBADF));
+        mock_fs.expect_read().never();
+        let fusefs = FuseFs::from(Arc::new(mock_fs));
+
+        fusefs
+            .files
+            .lock()
+            .unwrap()
+            .insert(ino, FileDataMut::new_for_tests(None, ino));
+        let reply = fusefs
+            .read(request, ino, fh, 0, 1024)
+            .now_or_never()
+            .unwrap()
+            .err()
+            .unwrap();
+        assert_eq!(reply, libc::EBADF.into());
+    }
+
     #[test]
     fn eio() {
         let fh = 0xdeadbeef;
@@ -2434,6 +2757,113 @@ mod read {
         assert_eq!(&reply.data[0..6], DATA0);
         assert_eq!(&reply.data[6..12], DATA1);
     }
+
+    fn make_small_mock_fs(ino: u64) -> FuseFs {
+        let fusefs = make_mock_fs(|mock_fs| {
+            mock_fs
+                .expect_read()
+                .times(1)
+                .returning(|_ino, _ofs, _len| {
+                    let dbs = DivBufShared::from(vec![0u8; 1024]);
+                    let db = dbs.try_const().unwrap();
+                    Ok(vec![db])
+                });
+        });
+        fusefs
+            .files
+            .lock()
+            .unwrap()
+            .insert(ino, FileDataMut::new_for_tests(None, ino));
+        fusefs
+    }
+
+    /// A read must wait while the daemon-wide budget is exhausted
+    #[test]
+    fn global_budget() {
+        let ino = 42;
+        let budget = ReadBudget::new(4096);
+        let mut fusefs = make_small_mock_fs(ino);
+        fusefs.global_read_budget(budget.clone());
+
+        let permit = budget.acquire(4096).now_or_never().unwrap();
+        assert!(fusefs
+            .read(Request::default(), ino, 0, 0, 1024)
+            .now_or_never()
+            .is_none());
+        drop(permit);
+        let reply = fusefs
+            .read(Request::default(), ino, 0, 0, 1024)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply.data.len(), 1024);
+    }
+
+    /// A read must wait while other subsystems need the memory budget
+    #[test]
+    fn memory_budget() {
+        let ino = 42;
+        let budget = MemoryBudget::new(4096);
+        let mut fusefs = make_small_mock_fs(ino);
+        fusefs.memory_budget(budget.clone());
+
+        budget.charge(Consumer::Cache, 4096);
+        let reservation = budget
+            .reserve(Consumer::Fuse, 1024)
+            .now_or_never()
+            .unwrap();
+        assert!(fusefs
+            .read(Request::default(), ino, 0, 0, 1024)
+            .now_or_never()
+            .is_none());
+        drop(reservation);
+        let reply = fusefs
+            .read(Request::default(), ino, 0, 0, 1024)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply.data.len(), 1024);
+        assert_eq!(budget.used(Consumer::Fuse), 0);
+    }
+
+    /// A read larger than the entire budget is allowed on its own
+    #[test]
+    fn oversize() {
+        let ino = 42;
+        let mut fusefs = make_small_mock_fs(ino);
+        fusefs.session_read_limit(512);
+
+        let reply = fusefs
+            .read(Request::default(), ino, 0, 0, 1024)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply.data.len(), 1024);
+    }
+
+    /// A read must wait while its session's budget is exhausted
+    #[test]
+    fn session_budget() {
+        let ino = 42;
+        let mut fusefs = make_small_mock_fs(ino);
+        fusefs.session_read_limit(4096);
+
+        let budget = fusefs.session_budget.clone().unwrap();
+        let permit = budget.acquire(3072).now_or_never().unwrap();
+        assert!(fusefs
+            .read(Request::default(), ino, 0, 0, 2048)
+            .now_or_never()
+            .is_none());
+        drop(permit);
+        let reply = fusefs
+            .read(Request::default(), ino, 0, 0, 2048)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply.data.len(), 1024);
+        // The permits should be released after the reply
+        assert_eq!(budget.sem.available_permits(), 4096);
+    }
 }
 
 mod readdir {
@@ -2826,6 +3256,113 @@ mod readlink {
     }
 }
 
+mod release {
+    use super::*;
+
+    #[test]
+    fn ok() {
+        let ino = 42;
+        let fh = 5;
+
+        let request = Request::default();
+
+        let fusefs = make_mock_fs(|mock_fs| {
+            mock_fs
+                .expect_release()
+                .times(1)
+                .with(predicate::eq(fh))
+                .return_const(());
+        });
+
+        fusefs
+            .release(request, ino, fh, 0, 0, false)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+    }
+
+    /// Releasing a handle that was written through should call the close hook
+    /// with the file's path
+    #[test]
+    fn written() {
+        let dir_ino = 41;
+        let ino = 42;
+        let fh = 5;
+        const DATA: &[u8] = &[0u8, 1, 2, 3];
+
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let calls2 = calls.clone();
+        let mut fusefs = make_mock_fs(|mock_fs| {
+            mock_fs
+                .expect_write()
+                .times(1)
+                .return_const(Ok(DATA.len() as u32));
+            mock_fs
+                .expect_release()
+                .times(1)
+                .with(predicate::eq(fh))
+                .return_const(());
+        });
+        fusefs.close_hook(Arc::new(move |ino: u64, path: Option<PathBuf>| {
+            calls2.lock().unwrap().push((ino, path));
+            futures::future::ready(()).boxed()
+        }));
+        {
+            let mut names = fusefs.names.lock().unwrap();
+            names.insert((1, OsString::from("foo")), dir_ino);
+            names.insert((dir_ino, OsString::from("..")), 1);
+            names.insert((dir_ino, OsString::from("bar")), ino);
+        }
+        fusefs
+            .files
+            .lock()
+            .unwrap()
+            .insert(ino, FileDataMut::new_for_tests(None, ino));
+
+        fusefs
+            .write(Request::default(), ino, fh, 0, DATA, 0)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        fusefs
+            .release(Request::default(), ino, fh, 0, 0, false)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            *calls.lock().unwrap(),
+            vec![(ino, Some(PathBuf::from("foo/bar")))]
+        );
+    }
+
+    /// Releasing a handle that was never written through should not call the
+    /// close hook
+    #[test]
+    fn unwritten() {
+        let ino = 42;
+        let fh = 5;
+
+        let mut fusefs = make_mock_fs(|mock_fs| {
+            mock_fs
+                .expect_release()
+                .times(1)
+                .with(predicate::eq(fh))
+                .return_const(());
+        });
+        fusefs.close_hook(Arc::new(
+            |_: u64, _: Option<PathBuf>| -> BoxFuture<'static, ()> {
+                panic!("Unexpected close hook")
+            },
+        ));
+
+        fusefs
+            .release(Request::default(), ino, fh, 0, 0, false)
+            .now_or_never()
+            .unwrap()
+            .unwrap();
+    }
+}
+
 mod rename {
     use super::*;
 
@@ -3267,6 +3804,7 @@ mod setattr {
                     rdev: 0,
                     blksize: 16384,
                     flags: 0,
+                    change: 0,
                 }));
         });
 
@@ -3298,6 +3836,91 @@ mod setattr {
 mod setxattr {
     use super::*;
 
+    #[test]
+    fn fadvise() {
+        let ino = 42;
+        let packed_name = OsStr::from_bytes(b"system.bfffs.fadvise");
+        let v = b"sequential";
+
+        let request = Request::default();
+
+        let fusefs = make_mock_fs(|mock_fs| {
+            mock_fs.expect_setextattr().never();
+            mock_fs
+                .expect_fadvise()
+                .times(1)
+                .withf(move |fd: &FileData, advice: &Advice| {
+                    fd.ino() == ino &&
+                        fd.advice() == Advice::Sequential &&
+                        *advice == Advice::Sequential
+                })
+                .return_const(Ok(()));
+        });
+
+        fusefs
+            .files
+            .lock()
+            .unwrap()
+            .insert(ino, FileDataMut::new_for_tests(None, ino));
+        let reply = fusefs
+            .setxattr(request, ino, packed_name, v, 0, 0)
+            .now_or_never()
+            .unwrap();
+        assert!(reply.is_ok());
+        let advice = fusefs.files.lock().unwrap()[&ino].handle().advice();
+        assert_eq!(advice, Advice::Sequential);
+    }
+
+    /// Linux's trusted namespace isn't supported
+    #[test]
+    fn eopnotsupp() {
+        let ino = 42;
+        let packed_name = OsStr::from_bytes(b"trusted.overlay.opaque");
+        let v = b"y";
+
+        let request = Request::default();
+
+        let fusefs = make_mock_fs(|mock_fs| {
+            mock_fs.expect_setextattr().never();
+        });
+
+        fusefs
+            .files
+            .lock()
+            .unwrap()
+            .insert(ino, FileDataMut::new_for_tests(None, ino));
+        let reply = fusefs
+            .setxattr(request, ino, packed_name, v, 0, 0)
+            .now_or_never()
+            .unwrap();
+        assert_eq!(reply, Err(libc::EOPNOTSUPP.into()));
+    }
+
+    #[test]
+    fn fadvise_einval() {
+        let ino = 42;
+        let packed_name = OsStr::from_bytes(b"system.bfffs.fadvise");
+        let v = b"sometimes";
+
+        let request = Request::default();
+
+        let fusefs = make_mock_fs(|mock_fs| {
+            mock_fs.expect_setextattr().never();
+            mock_fs.expect_fadvise().never();
+        });
+
+        fusefs
+            .files
+            .lock()
+            .unwrap()
+            .insert(ino, FileDataMut::new_for_tests(None, ino));
+        let reply = fusefs
+            .setxattr(request, ino, packed_name, v, 0, 0)
+            .now_or_never()
+            .unwrap();
+        assert_eq!(reply, Err(libc::EINVAL.into()));
+    }
+
     #[test]
     fn value_erofs() {
         let ino = 42;
@@ -3518,6 +4141,7 @@ mod symlink {
                     blksize: 4096,
                     rdev: 0,
                     flags: 0,
+                    change: 0,
                 }));
         });
 
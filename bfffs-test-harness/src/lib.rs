@@ -0,0 +1,375 @@
+// vim: tw=80
+//! Test scaffolding for BFFFS functional tests
+//!
+//! Creates ephemeral pools backed by temporary files, serves them with
+//! `bfffsd`, and tears everything down again on drop.  It's meant for tests
+//! that exercise BFFFS end-to-end, through the `bfffs` and `bfffsd` binaries.
+//!
+//! ```no_run
+//! use assert_cmd::prelude::*;
+//! use bfffs_test_harness::PoolBuilder;
+//!
+//! let harness = PoolBuilder::new("mypool").mountpoint().start();
+//! harness
+//!     .bfffs()
+//!     .args(["fs", "create", "mypool/foo"])
+//!     .assert()
+//!     .success();
+//! ```
+//!
+//! The binaries are located with [`assert_cmd::cargo::cargo_bin`], so they
+//! must already be built in the same target directory as the test.  That's
+//! automatic for tests within the `bfffs` crate.  Tests elsewhere in the
+//! workspace should be run after `cargo build --workspace`.
+
+use std::{
+    fmt,
+    fs,
+    os::unix::fs::FileTypeExt,
+    path::{Path, PathBuf},
+    process::{Child, Command},
+    thread::sleep,
+    time::{Duration, Instant},
+};
+
+use assert_cmd::prelude::*;
+use nix::mount::{unmount, MntFlags};
+use tempfile::{Builder, TempDir};
+
+/// Default size of each backing file
+const VDEV_SIZE: u64 = 1 << 30; // 1 GB
+
+/// Return a `Command` that will run the `bfffs` CLI
+pub fn bfffs() -> Command {
+    Command::cargo_bin("bfffs").unwrap()
+}
+
+/// Return a `Command` that will run the `bfffsd` daemon
+pub fn bfffsd() -> Command {
+    Command::cargo_bin("bfffsd").unwrap()
+}
+
+/// A wrapper for the bfffsd process that kills on Drop
+pub struct Bfffsd(Child);
+
+impl Bfffsd {
+    /// Return the daemon's pid
+    pub fn id(&self) -> u32 {
+        self.0.id()
+    }
+}
+
+impl Drop for Bfffsd {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+impl From<Child> for Bfffsd {
+    fn from(child: Child) -> Self {
+        Self(child)
+    }
+}
+
+/// Can the current user mount fusefs file systems?
+pub fn have_fusefs() -> bool {
+    may_usermount() && Path::new("/dev/fuse").exists()
+}
+
+#[cfg(target_os = "freebsd")]
+fn may_usermount() -> bool {
+    use nix::unistd::Uid;
+    use sysctl::CtlValue;
+
+    Uid::current().is_root() ||
+        CtlValue::Int(0) != sysctl::value("vfs.usermount").unwrap()
+}
+
+#[cfg(not(target_os = "freebsd"))]
+fn may_usermount() -> bool {
+    true
+}
+
+/// Skip a test.
+// Copied from nix.  Sure would be nice if the test harness knew about "skipped"
+// tests as opposed to "passed" or "failed".
+#[macro_export]
+macro_rules! skip {
+    ($($reason: expr),+) => {
+        use ::std::io::{self, Write};
+
+        let stderr = io::stderr();
+        let mut handle = stderr.lock();
+        writeln!(handle, $($reason),+).unwrap();
+        return;
+    }
+}
+
+/// Skip the test if we don't have the ability to mount fuse file systems.
+///
+/// The calling function must be annotated with `#[function_name::named]`.
+// Copied from nix.
+#[macro_export]
+macro_rules! require_fusefs {
+    () => {
+        if !$crate::have_fusefs() {
+            $crate::skip!(
+                "{} requires the ability to mount fusefs. Skipping test.",
+                concat!(::std::module_path!(), "::", function_name!())
+            );
+        }
+    };
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct WaitForError;
+
+impl fmt::Display for WaitForError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "timeout waiting for condition")
+    }
+}
+
+impl std::error::Error for WaitForError {}
+
+/// Wait for a limited amount of time for the given condition to be true.
+pub fn waitfor<C>(timeout: Duration, condition: C) -> Result<(), WaitForError>
+where
+    C: Fn() -> bool,
+{
+    let start = Instant::now();
+    loop {
+        if condition() {
+            break Ok(());
+        }
+        if start.elapsed() > timeout {
+            break (Err(WaitForError));
+        }
+        sleep(Duration::from_millis(50));
+    }
+}
+
+/// Describes an ephemeral pool, before it gets created.
+#[derive(Clone, Debug)]
+pub struct PoolBuilder {
+    name:       String,
+    nvdevs:     usize,
+    vdev_size:  u64,
+    mountpoint: bool,
+    properties: Vec<String>,
+    options:    Vec<String>,
+    seed:       Option<u64>,
+}
+
+impl PoolBuilder {
+    /// A single-disk pool named `name`, backed by one 1 GB file.
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        PoolBuilder {
+            name:       name.into(),
+            nvdevs:     1,
+            vdev_size:  VDEV_SIZE,
+            mountpoint: false,
+            properties: Vec::new(),
+            options:    Vec::new(),
+            seed:       None,
+        }
+    }
+
+    /// Create the pool, without starting a daemon.
+    ///
+    /// # Panics
+    ///
+    /// If `bfffs pool create` fails.
+    pub fn create(self) -> Pool {
+        let tempdir = Builder::new().prefix("bfffs-test.").tempdir().unwrap();
+        let vdevs = (0..self.nvdevs)
+            .map(|i| {
+                let filename = tempdir.path().join(format!("vdev.{i}"));
+                let file = fs::File::create(&filename).unwrap();
+                file.set_len(self.vdev_size).unwrap();
+                filename
+            })
+            .collect::<Vec<_>>();
+
+        let mut cmd = bfffs();
+        cmd.args(["pool", "create"]);
+        let mountpoint = if self.mountpoint {
+            let mountpoint = tempdir.path().join("mnt");
+            fs::create_dir(&mountpoint).unwrap();
+            cmd.arg("-p").arg(format!("mountpoint={}", mountpoint.display()));
+            Some(mountpoint)
+        } else {
+            None
+        };
+        for prop in self.properties.iter() {
+            cmd.arg("-p").arg(prop);
+        }
+        cmd.arg(&self.name).args(&vdevs).assert().success();
+
+        Pool {
+            name: self.name,
+            vdevs,
+            mountpoint,
+            options: self.options,
+            seed: self.seed,
+            tempdir,
+        }
+    }
+
+    /// Run `bfffsd` in deterministic mode, so a failure can be reproduced by
+    /// rerunning with the same seed.
+    pub fn deterministic(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Set the pool's root file system's mountpoint to a directory within the
+    /// pool's temporary directory.
+    pub fn mountpoint(mut self) -> Self {
+        self.mountpoint = true;
+        self
+    }
+
+    /// Pass an option, like `cache_size=1048576`, to `bfffsd -o`.
+    pub fn option<S: Into<String>>(mut self, option: S) -> Self {
+        self.options.push(option.into());
+        self
+    }
+
+    /// Set a property, like `atime=off`, at creation time.
+    pub fn property<S: Into<String>>(mut self, property: S) -> Self {
+        self.properties.push(property.into());
+        self
+    }
+
+    /// Create the pool and start a daemon to serve it.
+    pub fn start(self) -> Harness {
+        self.create().start()
+    }
+
+    /// Back the pool with `size` bytes per file.
+    pub fn vdev_size(mut self, size: u64) -> Self {
+        self.vdev_size = size;
+        self
+    }
+
+    /// Back the pool with `n` files, each a separate single-disk cluster.
+    pub fn vdevs(mut self, n: usize) -> Self {
+        assert!(n > 0, "A pool needs at least one disk");
+        self.nvdevs = n;
+        self
+    }
+}
+
+/// A pool that exists on disk, but isn't necessarily imported.
+///
+/// Its backing files are deleted on Drop.
+#[derive(Debug)]
+pub struct Pool {
+    name:       String,
+    vdevs:      Vec<PathBuf>,
+    mountpoint: Option<PathBuf>,
+    options:    Vec<String>,
+    seed:       Option<u64>,
+    tempdir:    TempDir,
+}
+
+impl Pool {
+    /// The root file system's mountpoint, if [`PoolBuilder::mountpoint`] was
+    /// used.
+    pub fn mountpoint(&self) -> Option<&Path> {
+        self.mountpoint.as_deref()
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// A scratch directory that will be deleted along with the pool.
+    pub fn path(&self) -> &Path {
+        self.tempdir.path()
+    }
+
+    /// Start `bfffsd` and wait until it's ready to receive commands.
+    ///
+    /// # Panics
+    ///
+    /// If the daemon doesn't start listening within a few seconds.
+    pub fn start(self) -> Harness {
+        let sockpath = self.tempdir.path().join("bfffsd.sock");
+        let mut cmd = bfffsd();
+        cmd.arg("--sock").arg(&sockpath);
+        if !self.options.is_empty() {
+            cmd.arg("-o").arg(self.options.join(","));
+        }
+        if let Some(seed) = self.seed {
+            cmd.arg("--deterministic").arg(seed.to_string());
+        }
+        let bfffsd = cmd
+            .arg(&self.name)
+            .args(&self.vdevs)
+            .spawn()
+            .unwrap()
+            .into();
+
+        waitfor(Duration::from_secs(5), || {
+            fs::metadata(&sockpath)
+                .map(|md| md.file_type().is_socket())
+                .unwrap_or(false)
+        })
+        .expect("Timeout waiting for bfffsd to listen");
+
+        Harness {
+            bfffsd,
+            sockpath,
+            pool: self,
+        }
+    }
+
+    /// Paths to the pool's backing files
+    pub fn vdevs(&self) -> &[PathBuf] {
+        &self.vdevs
+    }
+}
+
+/// A running `bfffsd` serving an ephemeral pool.
+///
+/// On Drop, the root file system is unmounted, the daemon is killed, and the
+/// backing files are deleted, in that order.
+pub struct Harness {
+    bfffsd:   Bfffsd,
+    sockpath: PathBuf,
+    pool:     Pool,
+}
+
+impl Harness {
+    /// Return a `bfffs` command already connected to this harness's daemon.
+    pub fn bfffs(&self) -> Command {
+        let mut cmd = bfffs();
+        cmd.arg("--sock").arg(&self.sockpath);
+        cmd
+    }
+
+    /// Return the daemon's pid
+    pub fn pid(&self) -> u32 {
+        self.bfffsd.id()
+    }
+
+    pub fn pool(&self) -> &Pool {
+        &self.pool
+    }
+
+    /// The daemon's control socket
+    pub fn sockpath(&self) -> &Path {
+        &self.sockpath
+    }
+}
+
+impl Drop for Harness {
+    fn drop(&mut self) {
+        if let Some(mountpoint) = self.pool.mountpoint() {
+            let _ignore_errors = unmount(mountpoint, MntFlags::empty());
+        }
+    }
+}
@@ -10,7 +10,7 @@ use async_trait::async_trait;
 use clap::{crate_version, Parser};
 use si_scale::helpers::bibytes;
 
-use super::{util::bfffs, Benchmark, Harness};
+use super::{bfffs, Benchmark, Harness};
 
 /// Measure file system destruction speed
 #[derive(Parser, Clone, Debug, Default)]
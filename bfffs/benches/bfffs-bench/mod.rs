@@ -12,6 +12,8 @@ use std::{
 use assert_cmd::prelude::*;
 use async_trait::async_trait;
 use bfffs::Result;
+pub use bfffs_test_harness::bfffs;
+use bfffs_test_harness::{bfffsd, waitfor, Bfffsd};
 use clap::{crate_version, Parser};
 use freebsd_libgeom as geom;
 use nix::{
@@ -22,11 +24,6 @@ use nix::{
 use regex::Regex;
 use tempfile::{Builder, TempDir};
 use tracing_subscriber::EnvFilter;
-pub mod util {
-    include!("../../tests/integration/util.rs");
-}
-pub use util::bfffs;
-use util::{bfffsd, waitfor, Bfffsd};
 
 mod fs_create;
 mod fs_destroy;
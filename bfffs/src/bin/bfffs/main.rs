@@ -3,28 +3,95 @@ use std::{
     fmt,
     io::{self, Write},
     mem,
+    ops::Bound,
     path::{Path, PathBuf},
     process::exit,
     str::FromStr,
     sync::Arc,
 };
 
-use bfffs::{Bfffs, Error, Result};
+use bfffs::{
+    Bfffs,
+    Change,
+    CleanPolicy,
+    DefragStats,
+    Error,
+    Feature,
+    PoolProperty,
+    Result,
+};
 use bfffs_core::{
     controller::Controller,
     database::{Database, TreeID},
     device_manager::DevManager,
-    property::{Property, PropertyName, PropertySource},
+    fs_tree::FSKey,
+    property::{
+        ParsePropertyError,
+        ParsePropertyNameError,
+        Property,
+        PropertyName,
+        PropertySource,
+        UserProperty,
+    },
+    Uuid,
 };
-use clap::{crate_version, Parser};
+use clap::{crate_version, CommandFactory, Parser};
 use futures::{future, TryStreamExt};
 use tracing_subscriber::EnvFilter;
 
 mod pool_create_ast;
 
+/// Taste `disks` for an offline debugging command, tolerating any that can't
+/// be read.
+///
+/// Every unavailable device is reported on stderr.  Returns a `DevManager`
+/// ready to import the pool in degraded mode, the pool's UUID, and whether it
+/// is in fact degraded.
+async fn probe(
+    pool_name: &str,
+    disks: &[PathBuf],
+) -> (DevManager, Uuid, bool) {
+    let mut dev_manager = DevManager::default();
+    dev_manager.degraded(true);
+    for (path, e) in dev_manager.taste_all(disks).await {
+        eprintln!("Warning: cannot read {}: {:?}", path.display(), e);
+    }
+    let uuid = dev_manager
+        .importable_pools()
+        .into_iter()
+        .find(|(name, _uuid)| name == pool_name)
+        .unwrap_or_else(|| {
+            eprintln!("Error: pool not found");
+            exit(1);
+        })
+        .1;
+    let missing = dev_manager.missing(uuid);
+    for dev in missing.iter() {
+        eprintln!("Warning: device {dev} is unavailable");
+    }
+    let degraded = !missing.is_empty();
+    if degraded {
+        eprintln!("Warning: {pool_name} is degraded.  Importing read-only.");
+    }
+    (dev_manager, uuid, degraded)
+}
+
 #[derive(Parser, Clone, Debug)]
 /// Consistency check
 struct Check {
+    /// Correct any directory link counts that are wrong
+    #[clap(long)]
+    repair:    bool,
+    /// Also recompute every record's reference count, and compare it to the
+    /// one stored in the Record Indirection Table
+    #[clap(long)]
+    refcounts: bool,
+    /// Also verify the checksum of every record
+    #[clap(long)]
+    scrub:     bool,
+    /// Maximum number of records to verify at once during a scrub
+    #[clap(long, default_value_t = 16)]
+    inflight:  usize,
     #[clap(required(true))]
     /// Pool name
     pool_name: String,
@@ -39,38 +106,222 @@ impl Check {
     // * RIDT and AllocT are exact inverses
     // * RIDT contains no orphan entries not found in the FSTrees
     // * Spacemaps match actual usage
+    // * Directories' link counts match their numbers of subdirectories
     pub async fn main(self) -> Result<()> {
-        let dev_manager = DevManager::default();
-        for dev in self.disks.iter() {
-            dev_manager.taste(dev).await.unwrap();
+        let (dev_manager, uuid, degraded) =
+            probe(&self.pool_name, &self.disks).await;
+        if degraded && self.repair {
+            eprintln!("Error: cannot repair a pool with unavailable devices");
+            exit(1);
         }
 
         let db = Arc::new(
-            dev_manager
-                .import_by_name(self.pool_name)
-                .await
-                .unwrap_or_else(|_e| {
-                    eprintln!("Error: pool not found");
-                    exit(1);
-                }),
+            dev_manager.import_by_uuid(uuid).await.unwrap_or_else(|e| {
+                eprintln!("Error: cannot import pool: {e:?}");
+                exit(1);
+            }),
         );
         db.check().await.unwrap();
+        db.check_dir_nlinks(self.repair).await.unwrap();
+        if self.refcounts {
+            db.check_refcounts().await.unwrap();
+        }
+        if self.scrub {
+            db.scrub(self.inflight).await.unwrap();
+        }
+        if self.repair {
+            db.sync_transaction().await.unwrap();
+        }
         // TODO: the other checks
         Ok(())
     }
 }
 
+/// Forcibly close a leaked file handle.
+///
+/// Further operations on the handle will fail with EBADF.  The owning process
+/// must still close its file descriptor before the file system can be
+/// unmounted.
+#[derive(Parser, Clone, Debug)]
+#[clap(after_help = "EXAMPLES:
+    bfffs debug open-files mypool/home
+    bfffs debug close-file mypool/home 42")]
+struct CloseFile {
+    /// File system name, including the pool
+    name: String,
+    /// Handle number, as reported by "bfffs debug open-files"
+    fh:   u64,
+}
+
+impl CloseFile {
+    async fn main(self, conn: &Connection) -> Result<()> {
+        let bfffs = conn.connect().await;
+        bfffs.close_file(&self.name, self.fh).await
+    }
+}
+
+/// Print a shell completion script to stdout
+#[derive(Parser, Clone, Debug)]
+#[clap(after_help = "EXAMPLES:
+    bfffs completions bash > /usr/local/etc/bash_completion.d/bfffs
+    bfffs completions zsh > ~/.zfunc/_bfffs
+    bfffs completions fish > ~/.config/fish/completions/bfffs.fish")]
+struct Completions {
+    /// Shell to generate completions for
+    #[clap(value_enum)]
+    shell: clap_complete::Shell,
+}
+
+impl Completions {
+    fn main(self) -> Result<()> {
+        clap_complete::generate(
+            self.shell,
+            &mut Cli::command(),
+            "bfffs",
+            &mut io::stdout(),
+        );
+        Ok(())
+    }
+}
+
+#[derive(Parser, Clone, Debug)]
+/// Merge under-filled nodes in a file system's metadata tree.
+struct Compact {
+    /// File system name, including the pool
+    name: String,
+}
+
+impl Compact {
+    async fn main(self, conn: &Connection) -> Result<()> {
+        let bfffs = conn.connect().await;
+        let merged = bfffs.compact(&self.name).await?;
+        println!("Coalesced {merged} nodes");
+        Ok(())
+    }
+}
+
 #[derive(Parser, Clone, Debug)]
 /// Drop all in-memory caches, for testing or benchmark purposes.
 struct DropCache {}
 
 impl DropCache {
-    async fn main(self, sock: &Path) -> Result<()> {
-        let bfffs = Bfffs::new(sock).await.unwrap();
+    async fn main(self, conn: &Connection) -> Result<()> {
+        let bfffs = conn.connect().await;
         bfffs.drop_cache().await
     }
 }
 
+#[derive(Parser, Clone, Debug)]
+/// List the file handles that clients have open on a file system.
+///
+/// Useful for finding out why a file system can't be unmounted.
+struct OpenFiles {
+    /// Scriptable output
+    #[clap(short = 'p', long)]
+    parseable: bool,
+    /// File system name, including the pool
+    name:      String,
+}
+
+impl OpenFiles {
+    async fn main(self, conn: &Connection) -> Result<()> {
+        let bfffs = conn.connect().await;
+        let files = bfffs.open_files(&self.name).await?;
+        if self.parseable {
+            for f in files {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    f.fh,
+                    f.ino,
+                    f.pid,
+                    open_mode(f.flags),
+                    f.age.as_secs()
+                );
+            }
+        } else {
+            let mut table = tabular::Table::new("{:>} {:>} {:>} {:<} {:>}");
+            table.add_row(
+                tabular::Row::new()
+                    .with_cell("FH")
+                    .with_cell("INODE")
+                    .with_cell("PID")
+                    .with_cell("MODE")
+                    .with_cell("AGE"),
+            );
+            for f in files {
+                let pid = if f.pid == 0 {
+                    String::from("-")
+                } else {
+                    f.pid.to_string()
+                };
+                table.add_row(
+                    tabular::Row::new()
+                        .with_cell(f.fh)
+                        .with_cell(f.ino)
+                        .with_cell(pid)
+                        .with_cell(open_mode(f.flags))
+                        .with_cell(format!("{}s", f.age.as_secs())),
+                );
+            }
+            print!("{table}");
+        }
+        Ok(())
+    }
+}
+
+/// Print every label layer stored on a single device.
+///
+/// Reads both of the device's labels, without importing anything, and prints
+/// them in YAML.  Useful for finding out why a pool won't import.
+#[derive(Parser, Clone, Debug)]
+#[clap(after_help = "EXAMPLES:
+    bfffs debug taste /dev/da0")]
+struct Taste {
+    /// Path to a disk, partition, or file
+    device: PathBuf,
+}
+
+impl Taste {
+    fn main(self) -> Result<()> {
+        DevManager::dump_labels(&self.device, &mut io::stdout())
+            .unwrap_or_else(|e| {
+                eprintln!(
+                    "Error: cannot read {}: {:?}",
+                    self.device.display(),
+                    e
+                );
+                exit(1);
+            });
+        Ok(())
+    }
+}
+
+/// Describe the access mode of `open(2)` flags
+fn open_mode(flags: u32) -> &'static str {
+    match flags as i32 & libc::O_ACCMODE {
+        libc::O_RDONLY => "r",
+        libc::O_WRONLY => "w",
+        libc::O_RDWR => "rw",
+        _ => "?",
+    }
+}
+
+/// Output format for `bfffs debug dump --tree --format`
+#[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
+enum DumpFormat {
+    Json,
+    Yaml,
+}
+
+impl From<DumpFormat> for bfffs_core::tree::DumpFormat {
+    fn from(f: DumpFormat) -> Self {
+        match f {
+            DumpFormat::Json => Self::Json,
+            DumpFormat::Yaml => Self::Yaml,
+        }
+    }
+}
+
 #[derive(Parser, Clone, Debug)]
 /// Dump internal filesystem information
 struct Dump {
@@ -89,6 +340,18 @@ struct Dump {
     /// Dump the file system tree
     #[clap(short, long)]
     tree:      bool,
+    /// Stream the file system tree one node at a time, in the given format,
+    /// instead of all at once.
+    #[clap(long, requires("tree"), value_enum)]
+    format:    Option<DumpFormat>,
+    /// With --format, only dump nodes that may contain objects at or above
+    /// this inode number.
+    #[clap(long, requires("format"))]
+    start_ino: Option<u64>,
+    /// With --format, only dump nodes that may contain objects below this
+    /// inode number.
+    #[clap(long, requires("format"))]
+    end_ino:   Option<u64>,
     #[clap(required(true))]
     /// Pool name
     pool_name: String,
@@ -108,16 +371,7 @@ impl Dump {
     }
 
     async fn dump_fsm(self) {
-        let dev_manager = DevManager::default();
-        for disk in self.disks.iter() {
-            dev_manager.taste(disk).await.unwrap();
-        }
-        let uuid = dev_manager
-            .importable_pools()
-            .iter()
-            .find(|(name, _uuid)| *name == self.pool_name)
-            .unwrap()
-            .1;
+        let (dev_manager, uuid, _) = probe(&self.pool_name, &self.disks).await;
         let clusters = dev_manager.import_clusters(uuid).await.unwrap();
         for c in clusters {
             println!("{}", c.dump_fsm());
@@ -133,21 +387,31 @@ impl Dump {
         let db = self.load_db().await;
         // For now, hardcode tree_id to 0
         let tree_id = TreeID(0);
-        db.dump_fs(&mut io::stdout(), tree_id).await.unwrap()
+        if let Some(format) = self.format {
+            let start = match self.start_ino {
+                Some(ino) => Bound::Included(FSKey::obj_range(ino).start),
+                None => Bound::Unbounded,
+            };
+            let end = match self.end_ino {
+                Some(ino) => Bound::Excluded(FSKey::obj_range(ino).start),
+                None => Bound::Unbounded,
+            };
+            let mut stdout = io::stdout();
+            let range = (start, end);
+            db.dump_fs_nodes(&mut stdout, tree_id, format.into(), range)
+                .await
+                .unwrap()
+        } else {
+            db.dump_fs(&mut io::stdout(), tree_id).await.unwrap()
+        }
     }
 
     async fn load_db(&self) -> Arc<Database> {
-        let dev_manager = DevManager::default();
-        for disk in self.disks.iter() {
-            dev_manager.taste(disk).await.unwrap();
-        }
-        let db = dev_manager
-            .import_by_name(&self.pool_name)
-            .await
-            .unwrap_or_else(|_e| {
-                eprintln!("Error: pool not found");
-                exit(1);
-            });
+        let (dev_manager, uuid, _) = probe(&self.pool_name, &self.disks).await;
+        let db = dev_manager.import_by_uuid(uuid).await.unwrap_or_else(|e| {
+            eprintln!("Error: cannot import pool: {e:?}");
+            exit(1);
+        });
         Arc::new(db)
     }
 
@@ -170,15 +434,51 @@ impl Dump {
 #[derive(Parser, Clone, Debug)]
 /// Debugging tools
 enum DebugCmd {
+    CloseFile(CloseFile),
+    Compact(Compact),
     DropCache(DropCache),
     Dump(Dump),
+    OpenFiles(OpenFiles),
+    Taste(Taste),
+}
+
+mod daemon {
+    use super::*;
+
+    /// Check that bfffsd is alive and processing requests
+    #[derive(Parser, Clone, Debug)]
+    pub(super) struct Ping {}
+
+    impl Ping {
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
+            let bfffs = conn.connect().await;
+            bfffs.daemon_ping().await?;
+            println!("bfffsd is alive");
+            Ok(())
+        }
+    }
+
+    #[derive(Parser, Clone, Debug)]
+    /// Query the bfffsd daemon itself
+    pub(super) enum DaemonCmd {
+        Ping(Ping),
+    }
 }
 
 mod fs {
+    use std::path::Component;
+
+    use bfffs_core::fs::Fs;
+    use nix::errno::Errno;
+    use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
     use super::*;
 
     /// Create a new file system
     #[derive(Parser, Clone, Debug)]
+    #[clap(after_help = "EXAMPLES:
+        bfffs fs create mypool/home
+        bfffs fs create -o atime=off,recsize=65536 mypool/db")]
     pub(super) struct Create {
         /// File system name
         pub(super) name:       String,
@@ -193,8 +493,8 @@ mod fs {
     }
 
     impl Create {
-        pub(super) async fn main(self, sock: &Path) -> Result<()> {
-            let bfffs = Bfffs::new(sock).await.unwrap();
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
+            let bfffs = conn.connect().await;
             let props = self
                 .properties
                 .iter()
@@ -211,15 +511,56 @@ mod fs {
 
     /// Destroy a file system
     #[derive(Parser, Clone, Debug)]
+    #[clap(after_help = "EXAMPLES:
+        bfffs fs destroy -nv mypool/home
+        bfffs fs destroy mypool/home")]
     pub(super) struct Destroy {
+        /// Dry run.  Check whether the file system can be destroyed, but
+        /// don't destroy it.
+        #[clap(short = 'n', long)]
+        pub(super) dry_run: bool,
+        /// Print the name of every file system destroyed
+        #[clap(short, long)]
+        pub(super) verbose: bool,
         /// File system name
-        pub(super) name: String,
+        pub(super) name:    String,
     }
 
     impl Destroy {
-        pub(super) async fn main(self, sock: &Path) -> Result<()> {
-            let bfffs = Bfffs::new(sock).await.unwrap();
-            bfffs.fs_destroy(self.name).await
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
+            let bfffs = conn.connect().await;
+            let names = bfffs.fs_destroy(self.name, self.dry_run).await?;
+            if self.verbose {
+                let verb = if self.dry_run {
+                    "would destroy"
+                } else {
+                    "destroyed"
+                };
+                for name in names {
+                    println!("{verb} {name}");
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Block modifications to a file system and sync it to disk, so that
+    /// external tools can back up the underlying devices.  It stays frozen
+    /// until thawed, even if this command exits.
+    #[derive(Parser, Clone, Debug)]
+    #[clap(after_help = "EXAMPLES:
+        bfffs fs freeze mypool/home
+        dd if=/dev/da0 of=/backup/da0.img bs=1m
+        bfffs fs thaw mypool/home")]
+    pub(super) struct Freeze {
+        /// File system name, including the pool.
+        pub(super) name: String,
+    }
+
+    impl Freeze {
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
+            let bfffs = conn.connect().await;
+            bfffs.fs_freeze(self.name).await
         }
     }
 
@@ -265,8 +606,67 @@ mod fs {
         }
     }
 
+    /// A property argument to `bfffs fs get`
+    #[derive(Clone, Debug, Eq, PartialEq)]
+    pub(super) enum GetProp {
+        /// Every native property, and every user property
+        All,
+        Native(PropertyName),
+        User(String),
+    }
+
+    impl GetProp {
+        /// The native properties displayed by `all`
+        const ALL_NATIVE: [PropertyName; 21] = [
+            PropertyName::Name,
+            PropertyName::Atime,
+            PropertyName::CanMount,
+            PropertyName::CleanUnmount,
+            PropertyName::Coalesce,
+            PropertyName::Copies,
+            PropertyName::Devices,
+            PropertyName::DirSync,
+            PropertyName::DirtyLimit,
+            PropertyName::Exec,
+            PropertyName::LastMounted,
+            PropertyName::MountCount,
+            PropertyName::Mounted,
+            PropertyName::Mountpoint,
+            PropertyName::RecordSize,
+            PropertyName::Setuid,
+            PropertyName::Share9p,
+            PropertyName::ShareIscsi,
+            PropertyName::Sync,
+            PropertyName::Utf8Only,
+            PropertyName::Volsize,
+        ];
+    }
+
+    impl FromStr for GetProp {
+        type Err = ParsePropertyError;
+
+        fn from_str(s: &str) -> std::result::Result<Self, ParsePropertyError> {
+            if s == "all" {
+                Ok(GetProp::All)
+            } else if s.contains(':') {
+                if UserProperty::is_valid_name(s) {
+                    Ok(GetProp::User(s.to_owned()))
+                } else {
+                    Err(ParsePropertyError::Name(ParsePropertyNameError {}))
+                }
+            } else {
+                PropertyName::from_str(s)
+                    .map(GetProp::Native)
+                    .map_err(ParsePropertyError::Name)
+            }
+        }
+    }
+
     /// Get dataset properties
     #[derive(Parser, Clone, Debug)]
+    #[clap(after_help = "EXAMPLES:
+        bfffs fs get all mypool
+        bfffs fs get -r -p -o name,value atime mypool")]
     pub(super) struct Get {
         #[clap(short = 'p', long, help = "Scriptable output")]
         pub(super) parseable:  bool,
@@ -296,42 +696,61 @@ mod fs {
         /// Recursively display children up to this many levels deep
         #[clap(short = 'd', long)]
         pub(super) depth:      Option<usize>,
-        /// Dataset properties to display, comma delimited
+        /// Dataset properties to display, comma delimited.  "all" means
+        /// every native and user property.
         #[clap(
             require_value_delimiter(true),
             value_delimiter(','),
             multiple_occurrences = false,
             required(true)
         )]
-        pub(super) properties: Vec<PropertyName>,
+        pub(super) properties: Vec<GetProp>,
         /// Datasets to inspect, comma delimited
         #[clap(multiple_values(true), required(true))]
         pub(super) datasets:   Vec<String>,
     }
 
     impl Get {
-        pub(super) async fn main(self, sock: &Path) -> Result<()> {
-            let bfffs = Bfffs::new(sock).await.unwrap();
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
+            let bfffs = conn.connect().await;
             let depth = self.depth.unwrap_or(if self.recursive {
                 usize::MAX
             } else {
                 0
             });
+            let all_props = self.properties.contains(&GetProp::All);
+            let mut natives = Vec::new();
+            let mut users = Vec::new();
+            for gp in self.properties.iter() {
+                match gp {
+                    GetProp::All => natives.extend(GetProp::ALL_NATIVE),
+                    GetProp::Native(propname) => natives.push(*propname),
+                    GetProp::User(name) => users.push(name.clone()),
+                }
+            }
+            let want_user = all_props || !users.is_empty();
+            // We use FROM_PARENT as a shorthand for "any level of
+            // inheritance".
+            let want_source = |source: &PropertySource| {
+                let eff_source = if source.is_inherited() {
+                    &PropertySource::FROM_PARENT
+                } else {
+                    source
+                };
+                self.sources.contains(eff_source)
+            };
+
             let mut all = Vec::new();
-            for ds in self.datasets.into_iter() {
+            for ds in self.datasets.iter() {
                 bfffs
-                    .fs_list(ds, self.properties.clone(), None, depth)
-                    .try_for_each(|mut dsinfo| {
-                        dsinfo.props.retain(|(_prop, source)| {
-                            // We use FROM_PARENT as a shorthand for "any
-                            // level of inheritance".
-                            let eff_source = if source.is_inherited() {
-                                &PropertySource::FROM_PARENT
-                            } else {
-                                source
-                            };
-                            self.sources.contains(eff_source)
-                        });
+                    .fs_list(
+                        ds.clone(),
+                        natives.clone(),
+                        want_user,
+                        None,
+                        depth,
+                    )
+                    .try_for_each(|dsinfo| {
                         all.push(dsinfo);
                         future::ok(())
                     })
@@ -340,27 +759,51 @@ mod fs {
             // Sort datasets by name, until other sort options are added
             all.sort_unstable_by(|x, y| x.name.cmp(&y.name));
 
+            // Each row is (dataset, property, value, humanized value, source)
+            let mut rows = Vec::new();
+            for dsinfo in all {
+                for (prop, source) in dsinfo.props {
+                    if want_source(&source) {
+                        rows.push((
+                            dsinfo.name.clone(),
+                            prop.name().to_string(),
+                            prop.to_string(),
+                            humanize_property(&prop),
+                            source,
+                        ));
+                    }
+                }
+                for (uprop, source) in dsinfo.user_props {
+                    if want_source(&source) &&
+                        (all_props || users.contains(&uprop.name))
+                    {
+                        let value = uprop.value;
+                        rows.push((
+                            dsinfo.name.clone(),
+                            uprop.name,
+                            value.clone(),
+                            value,
+                            source,
+                        ));
+                    }
+                }
+            }
+
             if self.parseable {
                 let stdout = io::stdout();
                 let lock = stdout.lock();
                 let mut buf = io::BufWriter::new(lock);
-                for dsinfo in all {
-                    for (prop, source) in dsinfo.props {
-                        let mut row = Vec::new();
-                        for field in &self.fields {
-                            match field {
-                                GetField::Name => row.push(dsinfo.name.clone()),
-                                GetField::Property => {
-                                    row.push(prop.name().to_string())
-                                }
-                                GetField::Value => row.push(prop.to_string()),
-                                GetField::Source => {
-                                    row.push(source.to_string())
-                                }
-                            };
-                        }
-                        writeln!(buf, "{}", row.join("\t")).unwrap();
+                for (dsname, propname, value, _hvalue, source) in rows {
+                    let mut row = Vec::new();
+                    for field in &self.fields {
+                        match field {
+                            GetField::Name => row.push(dsname.clone()),
+                            GetField::Property => row.push(propname.clone()),
+                            GetField::Value => row.push(value.clone()),
+                            GetField::Source => row.push(source.to_string()),
+                        };
                     }
+                    writeln!(buf, "{}", row.join("\t")).unwrap();
                 }
                 buf.flush().unwrap();
             } else {
@@ -372,22 +815,17 @@ mod fs {
                 }
                 table.add_row(hrow);
 
-                for dsinfo in all {
-                    for (prop, source) in dsinfo.props {
-                        let mut row = tabular::Row::new();
-                        for field in &self.fields {
-                            match field {
-                                GetField::Name => row.add_cell(&dsinfo.name),
-                                GetField::Property => row.add_cell(prop.name()),
-                                GetField::Value => {
-                                    let hprop = humanize_property(&prop);
-                                    row.add_cell(hprop)
-                                }
-                                GetField::Source => row.add_cell(source),
-                            };
-                        }
-                        table.add_row(row);
+                for (dsname, propname, _value, hvalue, source) in rows {
+                    let mut row = tabular::Row::new();
+                    for field in &self.fields {
+                        match field {
+                            GetField::Name => row.add_cell(&dsname),
+                            GetField::Property => row.add_cell(&propname),
+                            GetField::Value => row.add_cell(&hvalue),
+                            GetField::Source => row.add_cell(source),
+                        };
                     }
+                    table.add_row(row);
                 }
                 print!("{table}");
             }
@@ -395,6 +833,178 @@ mod fs {
         }
     }
 
+    /// Display a file system's read and write counters
+    ///
+    /// The counters accumulate from when the file system was mounted, or from
+    /// when they were last reset.
+    #[derive(Parser, Clone, Debug)]
+    #[clap(after_help = "EXAMPLES:
+        bfffs fs iostat -l mypool/home
+        bfffs fs iostat -pz mypool/home")]
+    pub(super) struct Iostat {
+        /// Also display latency histograms
+        #[clap(short = 'l', long)]
+        pub(super) latency:   bool,
+        /// Scriptable output
+        #[clap(short = 'p', long)]
+        pub(super) parseable: bool,
+        /// Reset the counters after displaying them
+        #[clap(short = 'z', long)]
+        pub(super) reset:     bool,
+        /// File system name, including the pool.
+        pub(super) name:      String,
+    }
+
+    impl Iostat {
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
+            let bfffs = conn.connect().await;
+            let stats = bfffs.fs_iostat(self.name, self.reset).await?;
+            if self.parseable {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    stats.reads,
+                    stats.writes,
+                    stats.bytes_read,
+                    stats.bytes_written,
+                    stats.age.as_secs()
+                );
+            } else {
+                let mut table = tabular::Table::new("{:>} {:>} {:>} {:>} {:>}");
+                table.add_row(
+                    tabular::Row::new()
+                        .with_cell("READS")
+                        .with_cell("WRITES")
+                        .with_cell("READ")
+                        .with_cell("WRITTEN")
+                        .with_cell("AGE"),
+                );
+                table.add_row(
+                    tabular::Row::new()
+                        .with_cell(stats.reads)
+                        .with_cell(stats.writes)
+                        .with_cell(bibytes0(stats.bytes_read as f64))
+                        .with_cell(bibytes0(stats.bytes_written as f64))
+                        .with_cell(format!("{}s", stats.age.as_secs())),
+                );
+                print!("{table}");
+            }
+            if self.latency {
+                let nbuckets = stats.read_latency.len();
+                let mut table = tabular::Table::new("{:>} {:>} {:>}");
+                if !self.parseable {
+                    println!();
+                    table.add_row(
+                        tabular::Row::new()
+                            .with_cell("LATENCY")
+                            .with_cell("READS")
+                            .with_cell("WRITES"),
+                    );
+                }
+                let buckets =
+                    stats.read_latency.iter().zip(stats.write_latency.iter());
+                for (i, (r, w)) in buckets.enumerate() {
+                    if self.parseable {
+                        // The bucket's upper bound in microseconds, or 0 for
+                        // the unbounded last bucket
+                        let bound = if i == nbuckets - 1 { 0 } else { 1 << i };
+                        println!("{bound}\t{r}\t{w}");
+                    } else if *r > 0 || *w > 0 {
+                        table.add_row(
+                            tabular::Row::new()
+                                .with_cell(latency_label(i, nbuckets))
+                                .with_cell(r)
+                                .with_cell(w),
+                        );
+                    }
+                }
+                if !self.parseable {
+                    print!("{table}");
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Label a latency histogram bucket, as described by
+    /// `IoStats::read_latency`
+    fn latency_label(bucket: usize, nbuckets: usize) -> String {
+        let usecs = |us: u64| {
+            if us < 1_000 {
+                format!("{us}us")
+            } else if us < 1_000_000 {
+                format!("{}ms", us / 1_000)
+            } else {
+                format!("{}s", us / 1_000_000)
+            }
+        };
+        if bucket == nbuckets - 1 {
+            format!(">={}", usecs(1 << (bucket - 1)))
+        } else {
+            format!("<{}", usecs(1 << bucket))
+        }
+    }
+
+    /// Delegate a file system to a jail
+    ///
+    /// Processes within the jail may then create and destroy the file
+    /// system's descendants and set their properties, except for those that
+    /// affect the host, like mountpoint.  The delegation lasts until
+    /// `bfffs fs unjail` or until bfffsd restarts.
+    #[derive(Parser, Clone, Debug)]
+    #[clap(after_help = "EXAMPLES:
+        bfffs fs jail www mypool/jails/www")]
+    pub(super) struct Jail {
+        /// Jail ID or name
+        pub(super) jail: String,
+        /// File system name, including the pool.
+        pub(super) name: String,
+    }
+
+    impl Jail {
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
+            let jid = jail_id(&self.jail)?;
+            let bfffs = conn.connect().await;
+            bfffs.fs_jail(self.name, jid).await
+        }
+    }
+
+    /// Look up a jail's numeric ID from its ID or name
+    fn jail_id(jail: &str) -> Result<i32> {
+        match jail.parse() {
+            Ok(jid) => Ok(jid),
+            Err(_) => jail_by_name(jail),
+        }
+    }
+
+    #[cfg(target_os = "freebsd")]
+    fn jail_by_name(name: &str) -> Result<i32> {
+        use std::ffi::CString;
+
+        let key = CString::new("name").unwrap();
+        let value = CString::new(name).map_err(|_| Error::EINVAL)?;
+        let mut iov = [
+            libc::iovec {
+                iov_base: key.as_ptr() as *mut libc::c_void,
+                iov_len:  key.as_bytes_with_nul().len(),
+            },
+            libc::iovec {
+                iov_base: value.as_ptr() as *mut libc::c_void,
+                iov_len:  value.as_bytes_with_nul().len(),
+            },
+        ];
+        // Safe because jail_get only reads input parameters, and we supply no
+        // output parameters.
+        let jid =
+            unsafe { libc::jail_get(iov.as_mut_ptr(), iov.len() as u32, 0) };
+        Errno::result(jid).map_err(Error::from)
+    }
+
+    /// Only FreeBSD has jails
+    #[cfg(not(target_os = "freebsd"))]
+    fn jail_by_name(_name: &str) -> Result<i32> {
+        Err(Error::ENOENT)
+    }
+
     /// List file systems
     #[derive(Parser, Clone, Debug)]
     pub(super) struct List {
@@ -431,7 +1041,7 @@ mod fs {
     }
 
     impl List {
-        pub(super) async fn main(self, sock: &Path) -> Result<()> {
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
             let depth = self.depth.unwrap_or(if self.recursive {
                 usize::MAX
             } else {
@@ -452,11 +1062,11 @@ mod fs {
                 }
             }
 
-            let bfffs = Bfffs::new(sock).await.unwrap();
+            let bfffs = conn.connect().await;
             let mut all = Vec::new();
             for ds in self.datasets.into_iter() {
                 bfffs
-                    .fs_list(ds, self.properties.clone(), None, depth)
+                    .fs_list(ds, self.properties.clone(), false, None, depth)
                     .try_for_each(|dsinfo| {
                         all.push(dsinfo);
                         future::ok(())
@@ -509,9 +1119,33 @@ mod fs {
         }
     }
 
+    /// Print the result of a bulk operation for each file system.  Fail if
+    /// any of them failed.
+    pub(super) fn report(results: Vec<(String, Result<()>)>) -> Result<()> {
+        let mut r = Ok(());
+        for (name, result) in results.into_iter() {
+            match result {
+                Ok(()) => println!("{name}\tok"),
+                Err(e) => {
+                    println!("{name}\t{e:?}");
+                    r = r.and(Err(e));
+                }
+            }
+        }
+        r
+    }
+
     /// Mount a file system
     #[derive(Parser, Clone, Debug)]
+    #[clap(after_help = "EXAMPLES:
+        bfffs fs mount mypool/home
+        bfffs fs mount -m /mnt mypool/home
+        bfffs fs mount -a mypool")]
     pub(super) struct Mount {
+        /// Mount every file system in the pool, parents before children,
+        /// except those with canmount=off.
+        #[clap(short = 'a', long, conflicts_with = "mountpoint")]
+        pub(super) all:        bool,
         /// Mount options, comma delimited
         #[clap(
             short = 'o',
@@ -519,71 +1153,346 @@ mod fs {
             require_value_delimiter(true),
             value_delimiter(',')
         )]
-        pub(super) options: Vec<String>,
-        /// File system name, including the pool.
-        pub(super) name:    String,
+        pub(super) options:    Vec<String>,
+        /// Mount here, overriding the file system's mountpoint property
+        #[clap(short = 'm', long)]
+        pub(super) mountpoint: Option<String>,
+        /// File system name, including the pool.  With --all, just the pool.
+        pub(super) name:       String,
     }
 
     impl Mount {
-        pub(super) async fn main(self, sock: &Path) -> Result<()> {
-            let bfffs = Bfffs::new(sock).await.unwrap();
-            bfffs.fs_mount(self.name).await
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
+            let bfffs = conn.connect().await;
+            if self.all {
+                report(bfffs.fs_mount_all(self.name, self.options).await?)
+            } else {
+                bfffs.fs_mount(self.name, self.mountpoint, self.options).await
+            }
+        }
+    }
+
+    /// List mounted file systems
+    #[derive(Parser, Clone, Debug)]
+    pub(super) struct Mounts {
+        #[clap(short = 'p', long, help = "Scriptable output")]
+        pub(super) parseable: bool,
+    }
+
+    impl Mounts {
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
+            let bfffs = conn.connect().await;
+            let mounts = bfffs.fs_mounts().await?;
+            if self.parseable {
+                for m in mounts {
+                    println!(
+                        "{}\t{}\t{}\t{}",
+                        m.name,
+                        m.mountpoint,
+                        m.opts.join(","),
+                        m.uid
+                    );
+                }
+            } else {
+                let mut table = tabular::Table::new("{:<} {:<} {:<} {:>}");
+                table.add_row(
+                    tabular::Row::new()
+                        .with_cell("NAME")
+                        .with_cell("MOUNTPOINT")
+                        .with_cell("OPTIONS")
+                        .with_cell("UID"),
+                );
+                for m in mounts {
+                    let opts = if m.opts.is_empty() {
+                        String::from("-")
+                    } else {
+                        m.opts.join(",")
+                    };
+                    table.add_row(
+                        tabular::Row::new()
+                            .with_cell(m.name)
+                            .with_cell(m.mountpoint)
+                            .with_cell(opts)
+                            .with_cell(m.uid),
+                    );
+                }
+                print!("{table}");
+            }
+            Ok(())
+        }
+    }
+
+    /// Move a file to a different file system in the same pool
+    ///
+    /// File data is shared rather than copied, so this is fast even for large
+    /// files.  Directories cannot be moved.
+    #[derive(Parser, Clone, Debug)]
+    pub(super) struct Mv {
+        /// Source file system name, including the pool
+        pub(super) src_ds:   String,
+        /// Path of the file, relative to the source file system's root
+        pub(super) src_path: String,
+        /// Destination file system name, including the pool
+        pub(super) dst_ds:   String,
+        /// New path of the file, relative to the destination's root
+        pub(super) dst_path: String,
+    }
+
+    impl Mv {
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
+            let bfffs = conn.connect().await;
+            bfffs
+                .fs_move(self.src_ds, self.src_path, self.dst_ds, self.dst_path)
+                .await
+        }
+    }
+
+    /// Restore a single file from an unmounted file system
+    ///
+    /// The pool is imported read-only, so it must not be in use by bfffsd.
+    #[derive(Parser, Clone, Debug)]
+    pub(super) struct Restore {
+        /// File system name, including the pool.
+        pub(super) name:   String,
+        /// Path of the file to restore, relative to the file system's root
+        pub(super) path:   PathBuf,
+        /// Where to write the restored file
+        pub(super) target: PathBuf,
+        #[clap(required(true))]
+        pub(super) disks:  Vec<PathBuf>,
+    }
+
+    impl Restore {
+        /// Read the file's contents straight from the file system's tree
+        async fn copy_out(fs: &Fs, path: &Path, target: &Path) -> Result<()> {
+            // Chosen to amortize per-read overhead without using too much RAM
+            const CHUNKSIZE: u64 = 1 << 20;
+            let errno = |e: i32| Error::from(Errno::from_i32(e));
+
+            let mut fd = fs.root();
+            for component in path.components() {
+                match component {
+                    Component::RootDir | Component::CurDir => continue,
+                    Component::Normal(name) => {
+                        fd = fs
+                            .lookup(None, &fd.handle(), name)
+                            .await
+                            .map_err(errno)?;
+                    }
+                    _ => return Err(Error::EINVAL),
+                }
+            }
+            let attr = fs.getattr(&fd.handle()).await.map_err(errno)?;
+            if attr.mode.file_type() != libc::S_IFREG {
+                return Err(Error::EINVAL);
+            }
+            let mut f = std::fs::File::create(target)?;
+            let mut offset = 0;
+            while offset < attr.size {
+                let len = CHUNKSIZE.min(attr.size - offset) as usize;
+                let sglist = fs
+                    .read(&fd.handle(), offset, len)
+                    .await
+                    .map_err(errno)?;
+                for iovec in sglist.iter() {
+                    f.write_all(&iovec[..])?;
+                    offset += iovec.len() as u64;
+                }
+            }
+            Ok(())
+        }
+
+        pub(super) async fn main(self) -> Result<()> {
+            let pool_name = self.name.split('/').next().unwrap();
+            let mut dev_manager = DevManager::default();
+            dev_manager.readonly(true);
+            for dev in self.disks.iter() {
+                dev_manager.taste(dev).await?;
+            }
+            let db = dev_manager.import_by_name(pool_name).await
+                .unwrap_or_else(|_e| {
+                    eprintln!("Error: pool not found");
+                    exit(1);
+                });
+            let controller = Controller::new(db);
+            let fs = controller.new_fs(&self.name).await?;
+            Restore::copy_out(&fs, &self.path, &self.target).await
+        }
+    }
+
+    /// A property argument to `bfffs fs set`
+    #[derive(Clone, Debug, Eq, PartialEq)]
+    pub(super) enum SetProp {
+        Native(Property),
+        User(UserProperty),
+    }
+
+    impl FromStr for SetProp {
+        type Err = ParsePropertyError;
+
+        fn from_str(s: &str) -> std::result::Result<Self, ParsePropertyError> {
+            let propname = s.split('=').next().unwrap();
+            if propname.contains(':') {
+                UserProperty::from_str(s).map(SetProp::User)
+            } else {
+                Property::from_str(s).map(SetProp::Native)
+            }
         }
     }
 
     /// Set dataset properties
     #[derive(Parser, Clone, Debug)]
+    #[clap(after_help = "EXAMPLES:
+        bfffs fs set atime=off mypool/home
+        bfffs fs set com.example:owner=alice mypool/home mypool/db")]
     pub(super) struct Set {
-        /// Dataset properties to set, comma delimited
+        /// Dataset properties to set, comma delimited.  User properties'
+        /// names must contain a ':'.  Setting a user property to the empty
+        /// string clears it.
         #[clap(
             require_value_delimiter(true),
             value_delimiter(','),
             multiple_occurrences = false,
             required(true)
         )]
-        pub(super) properties: Vec<Property>,
+        pub(super) properties: Vec<SetProp>,
         /// Datasets to modify, comma delimited
         #[clap(multiple_values(true), required(true))]
         pub(super) datasets:   Vec<String>,
     }
 
     impl Set {
-        pub(super) async fn main(self, sock: &Path) -> Result<()> {
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
+            let mut props = Vec::new();
+            let mut user_props = Vec::new();
+            for prop in self.properties.into_iter() {
+                match prop {
+                    SetProp::Native(p) => props.push(p),
+                    SetProp::User(up) => user_props.push(up),
+                }
+            }
             for ds in self.datasets.into_iter() {
-                let bfffs = Bfffs::new(sock).await.unwrap();
-                bfffs.fs_set(ds, self.properties.clone()).await?
+                let bfffs = conn.connect().await;
+                bfffs.fs_set(ds, props.clone(), user_props.clone()).await?
             }
             Ok(())
         }
     }
 
-    /// Unmount a file system
+    /// Allow modifications to a frozen file system
     #[derive(Parser, Clone, Debug)]
-    pub(super) struct Unmount {
-        /// Focibly unmount the file system even if files are still active.
-        #[clap(short, long)]
-        pub(super) force: bool,
+    pub(super) struct Thaw {
         /// File system name, including the pool.
-        pub(super) name:  String,
+        pub(super) name: String,
     }
 
-    impl Unmount {
-        pub(super) async fn main(self, sock: &Path) -> Result<()> {
-            let bfffs = Bfffs::new(sock).await.unwrap();
-            bfffs.fs_unmount(&self.name, self.force).await
+    impl Thaw {
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
+            let bfffs = conn.connect().await;
+            bfffs.fs_thaw(self.name).await
         }
     }
 
+    /// Revoke a file system's delegation to a jail
     #[derive(Parser, Clone, Debug)]
-    /// Create, destroy, and modify file systems
+    pub(super) struct Unjail {
+        /// Jail ID or name
+        pub(super) jail: String,
+        /// File system name, including the pool.
+        pub(super) name: String,
+    }
+
+    impl Unjail {
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
+            let jid = jail_id(&self.jail)?;
+            let bfffs = conn.connect().await;
+            bfffs.fs_unjail(self.name, jid).await
+        }
+    }
+
+    /// Unmount a file system
+    #[derive(Parser, Clone, Debug)]
+    #[clap(after_help = "EXAMPLES:
+        bfffs fs unmount mypool/home
+        bfffs fs unmount -a mypool")]
+    pub(super) struct Unmount {
+        /// Unmount every mounted file system in the pool, children before
+        /// parents.
+        #[clap(short = 'a', long)]
+        pub(super) all:   bool,
+        /// Focibly unmount the file system even if files are still active.
+        #[clap(short, long)]
+        pub(super) force: bool,
+        /// File system name, including the pool.  With --all, just the pool.
+        pub(super) name:  String,
+    }
+
+    impl Unmount {
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
+            let bfffs = conn.connect().await;
+            if self.all {
+                report(bfffs.fs_unmount_all(self.name, self.force).await?)
+            } else {
+                bfffs.fs_unmount(&self.name, self.force).await
+            }
+        }
+    }
+
+    /// Report files that are closed after being written
+    ///
+    /// Prints one line per close, containing the file system, the inode
+    /// number, and the file's path, or "-" if bfffsd doesn't know it.  Runs
+    /// until killed.
+    #[derive(Parser, Clone, Debug)]
+    #[clap(after_help = "EXAMPLES:
+        bfffs fs watch mypool/home
+        bfffs fs watch -s mypool/home | indexer")]
+    pub(super) struct Watch {
+        /// Make each close wait until its line has been written.  Useful when
+        /// stdout is a pipe to a helper that reads slowly.
+        #[clap(short = 's', long)]
+        pub(super) sync: bool,
+        /// File system name, including the pool.  Its descendants are
+        /// watched too.
+        pub(super) name: String,
+    }
+
+    impl Watch {
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
+            let bfffs = conn.connect().await;
+            let id = bfffs.fs_watch(self.name, self.sync).await?;
+            let mut stdout = io::stdout();
+            loop {
+                // Fetching the next batch acknowledges the previous one
+                for event in bfffs.fs_events(id).await? {
+                    let path = event.path.as_deref().unwrap_or("-");
+                    let (ds, ino) = (&event.dataset, event.ino);
+                    writeln!(stdout, "{ds}\t{ino}\t{path}")?;
+                }
+                stdout.flush()?;
+            }
+        }
+    }
+
+    #[derive(Parser, Clone, Debug)]
+    /// Create, destroy, and modify file systems
     pub(super) enum FsCmd {
         Create(Create),
         Destroy(Destroy),
+        Freeze(Freeze),
         Get(Get),
+        Iostat(Iostat),
+        Jail(Jail),
         List(List),
         Mount(Mount),
+        Mounts(Mounts),
+        Mv(Mv),
+        Restore(Restore),
         Set(Set),
+        Thaw(Thaw),
+        Unjail(Unjail),
         Unmount(Unmount),
+        Watch(Watch),
     }
 
     fn hname(propname: PropertyName) -> &'static str {
@@ -591,8 +1500,25 @@ mod fs {
             PropertyName::Atime => "ATIME",
             PropertyName::BaseMountpoint => "BASEMOUNTPOINT",
             PropertyName::Mountpoint => "MOUNTPOINT",
+            PropertyName::Mounted => "MOUNTED",
             PropertyName::Name => "NAME",
             PropertyName::RecordSize => "RECSIZE",
+            PropertyName::Sync => "SYNC",
+            PropertyName::Devices => "DEVICES",
+            PropertyName::Exec => "EXEC",
+            PropertyName::Setuid => "SETUID",
+            PropertyName::Utf8Only => "UTF8ONLY",
+            PropertyName::Share9p => "SHARE9P",
+            PropertyName::Volsize => "VOLSIZE",
+            PropertyName::ShareIscsi => "SHAREISCSI",
+            PropertyName::DirtyLimit => "DIRTYLIMIT",
+            PropertyName::Coalesce => "COALESCE",
+            PropertyName::LastMounted => "LASTMOUNTED",
+            PropertyName::MountCount => "MOUNTCOUNT",
+            PropertyName::CleanUnmount => "CLEAN",
+            PropertyName::DirSync => "DIRSYNC",
+            PropertyName::Copies => "COPIES",
+            PropertyName::CanMount => "CANMOUNT",
         }
     }
 
@@ -605,7 +1531,13 @@ mod fs {
 
     fn humanize_property(prop: &Property) -> String {
         match prop {
-            Property::Atime(b) => {
+            Property::Atime(b) |
+            Property::CanMount(b) |
+            Property::Devices(b) |
+            Property::DirSync(b) |
+            Property::Exec(b) |
+            Property::Setuid(b) |
+            Property::Utf8Only(b) => {
                 match b {
                     true => String::from("on"),
                     false => String::from("off"),
@@ -613,10 +1545,127 @@ mod fs {
             }
             Property::BaseMountpoint(s) => s.to_owned(),
             Property::Mountpoint(s) => s.to_owned(),
+            Property::Mounted(b) | Property::CleanUnmount(b) => {
+                match b {
+                    true => String::from("yes"),
+                    false => String::from("no"),
+                }
+            }
             Property::Name(s) => s.to_owned(),
             Property::RecordSize(i) => bibytes0(1 << i),
+            Property::Sync(sp) => sp.to_string(),
+            Property::Share9p(s) => s.to_owned(),
+            // File systems have no size
+            Property::Volsize(0) => String::from("-"),
+            Property::Volsize(size) => bibytes0(*size as f64),
+            Property::ShareIscsi(s) => s.to_owned(),
+            Property::DirtyLimit(0) => String::from("none"),
+            Property::DirtyLimit(limit) => bibytes0(*limit as f64),
+            Property::Coalesce(0) => String::from("off"),
+            Property::Coalesce(window) => bibytes0(*window as f64),
+            Property::LastMounted(0) => String::from("never"),
+            Property::LastMounted(secs) => {
+                OffsetDateTime::from_unix_timestamp(*secs as i64)
+                    .ok()
+                    .and_then(|odt| odt.format(&Rfc3339).ok())
+                    .unwrap_or_else(|| secs.to_string())
+            }
+            Property::MountCount(count) => count.to_string(),
+            Property::Copies(copies) => copies.to_string(),
+        }
+    }
+}
+
+mod job {
+    use std::time::Duration;
+
+    use bfffs::{JobID, JobState, JobStatus};
+
+    use super::*;
+
+    /// Width of a progress bar, not counting the brackets and percentage
+    const BAR_WIDTH: usize = 40;
+
+    /// List running and recently finished jobs
+    #[derive(Parser, Clone, Debug)]
+    pub(super) struct List {}
+
+    impl List {
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
+            let bfffs = conn.connect().await;
+            let jobs = bfffs.job_list().await?;
+            let mut table = tabular::Table::new("{:>} {:<} {:<} {:<} {:<}");
+            table.add_row(
+                tabular::Row::new()
+                    .with_cell("ID")
+                    .with_cell("KIND")
+                    .with_cell("TARGET")
+                    .with_cell("PROGRESS")
+                    .with_cell("STATE"),
+            );
+            for status in jobs {
+                table.add_row(
+                    tabular::Row::new()
+                        .with_cell(status.id)
+                        .with_cell(status.kind)
+                        .with_cell(&status.target)
+                        .with_cell(progress_bar(&status, BAR_WIDTH))
+                        .with_cell(status.state),
+                );
+            }
+            print!("{table}");
+            Ok(())
+        }
+    }
+
+    /// Render a job's progress as a text progress bar, like "[####    ]  50%"
+    pub(super) fn progress_bar(status: &JobStatus, width: usize) -> String {
+        let frac = if status.state == JobState::Done {
+            1.0
+        } else if status.total == 0 {
+            // The total isn't known yet
+            0.0
+        } else {
+            (status.processed as f64 / status.total as f64).min(1.0)
+        };
+        let filled = (frac * width as f64).round() as usize;
+        format!(
+            "[{}{}] {:>3}%",
+            "#".repeat(filled),
+            " ".repeat(width - filled),
+            (frac * 100.0).round() as u64
+        )
+    }
+
+    /// Poll a job until it finishes, drawing a progress bar on stderr
+    pub(super) async fn wait(bfffs: &Bfffs, id: JobID) -> Result<()> {
+        let mut stderr = io::stderr();
+        loop {
+            let status = bfffs.job_status(id).await?;
+            // Failing to draw the progress bar is no reason to stop waiting
+            let _ = write!(stderr, "\r{}", progress_bar(&status, BAR_WIDTH));
+            let _ = stderr.flush();
+            match status.state {
+                JobState::Running => {
+                    tokio::time::sleep(Duration::from_secs(1)).await
+                }
+                JobState::Done => {
+                    eprintln!();
+                    return Ok(());
+                }
+                JobState::Failed(e) => {
+                    eprintln!();
+                    return Err(e);
+                }
+            }
         }
     }
+
+    #[derive(Parser, Clone, Debug)]
+    /// Monitor long-running operations
+    pub(super) enum JobCmd {
+        List(List),
+    }
 }
 
 mod pool {
@@ -631,27 +1680,255 @@ mod pool {
         mirror::Mirror,
         pool::Pool,
         raid,
+        TxgT,
         BYTES_PER_LBA,
     };
 
     use super::*;
 
+    /// Add a new cluster to a pool, increasing its capacity
+    ///
+    /// The cluster may be a single disk, a mirror, or a raid, specified with
+    /// the same syntax as "bfffs pool create".  The disks must be unused, and
+    /// all of a raid's mirrors must be the same size.  Once added, a cluster
+    /// can't be removed.
+    #[derive(Parser, Clone, Debug)]
+    #[clap(after_help = "EXAMPLES:
+        bfffs pool add mypool /dev/da2
+        bfffs pool add mypool mirror /dev/da2 /dev/da3
+        bfffs pool add mypool raid 3 1 /dev/da2 /dev/da3 /dev/da4")]
+    pub(super) struct Add {
+        /// Pool name
+        pub(super) pool_name: String,
+        /// Cluster specification
+        #[clap(required(true))]
+        pub(super) vdev:      Vec<String>,
+    }
+
+    impl Add {
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
+            let (disks_per_stripe, redundancy, mirrors) =
+                parse_cluster(&self.vdev, |s| Ok(PathBuf::from(s)))
+                    .unwrap_or_else(|e| {
+                        eprintln!("{e}");
+                        exit(2);
+                    });
+            let bfffs = conn.connect().await;
+            bfffs
+                .pool_add(self.pool_name, disks_per_stripe, redundancy, mirrors)
+                .await
+        }
+    }
+
+    /// Take a checkpoint of a pool
+    ///
+    /// Until the checkpoint is discarded, the pool may be rewound to it by
+    /// importing with `-o rewind_to_checkpoint=on`.  While a checkpoint
+    /// exists, no freed space will be reclaimed.
+    #[derive(Parser, Clone, Debug)]
+    pub(super) struct Checkpoint {
+        /// Discard the existing checkpoint instead of taking a new one
+        #[clap(short, long)]
+        pub(super) discard:   bool,
+        /// Pool name
+        pub(super) pool_name: String,
+    }
+
+    impl Checkpoint {
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
+            let bfffs = conn.connect().await;
+            bfffs.pool_checkpoint(self.pool_name, self.discard).await
+        }
+    }
+
     /// Clean freed space on a pool
     #[derive(Parser, Clone, Debug)]
     pub(super) struct Clean {
+        /// Segregate long-lived data.  Records from zones closed at least
+        /// this many transactions ago will be moved into dedicated cold
+        /// zones, instead of alongside newly written data.
+        #[clap(long, value_name = "TXGS")]
+        pub(super) cold_age:  Option<u32>,
+        /// Dry run.  Check which zones would be cleaned, but don't clean
+        /// them.
+        #[clap(short = 'n', long)]
+        pub(super) dry_run:   bool,
+        /// Print how much data will be moved and freed, both in total and for
+        /// each zone, and the write amplification measured so far
+        #[clap(short, long)]
+        pub(super) verbose:   bool,
+        /// Wait for cleaning to finish, displaying its progress
+        #[clap(short, long)]
+        pub(super) wait:      bool,
         /// Pool name
         pub(super) pool_name: String,
     }
 
+    si_scale::scale_fn!(bibytes1,
+                                 base: B1024,
+                                 constraint: UnitAndAbove,
+                                 mantissa_fmt: "{:.1}",
+                                 unit: "B");
+
     impl Clean {
-        pub(super) async fn main(self, sock: &Path) -> Result<()> {
-            let bfffs = Bfffs::new(sock).await.unwrap();
-            bfffs.pool_clean(self.pool_name).await
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
+            let bfffs = conn.connect().await;
+            let policy = match self.cold_age {
+                Some(cold_age) => CleanPolicy::Generational { cold_age },
+                None => CleanPolicy::Mixed,
+            };
+            let (stats, job) = bfffs
+                .pool_clean(self.pool_name, self.dry_run, policy)
+                .await?;
+            if self.verbose {
+                let verb = if self.dry_run {
+                    "would clean"
+                } else {
+                    "cleaning"
+                };
+                println!(
+                    "{verb} {} zones, moving {} and freeing {}",
+                    stats.zones,
+                    bibytes1(stats.moved as f64),
+                    bibytes1(stats.reclaimable as f64)
+                );
+                if stats.pinned > 0 {
+                    println!(
+                        "{} of freed space is pinned by the pool's checkpoint, \
+                         and can't be reclaimed until it is discarded",
+                        bibytes1(stats.pinned as f64)
+                    );
+                }
+                if self.cold_age.is_some() {
+                    println!(
+                        "{} of the moved data will go to cold zones",
+                        bibytes1(stats.cold as f64)
+                    );
+                }
+                println!(
+                    "{} of the moved data is metadata, and will stay in \
+                     metadata zones",
+                    bibytes1(stats.metadata as f64)
+                );
+                println!(
+                    "write amplification so far: {:.2}",
+                    stats.write_amplification()
+                );
+                if !stats.by_zone.is_empty() {
+                    let mut table =
+                        tabular::Table::new("{:>} {:>} {:>} {:>} {:>}");
+                    table.add_row(
+                        tabular::Row::new()
+                            .with_cell("CLUSTER")
+                            .with_cell("ZONE")
+                            .with_cell("MOVED")
+                            .with_cell("RECLAIMABLE")
+                            .with_cell("PINNED"),
+                    );
+                    for z in stats.by_zone.iter() {
+                        table.add_row(
+                            tabular::Row::new()
+                                .with_cell(z.cluster)
+                                .with_cell(z.zid)
+                                .with_cell(bibytes1(z.moved as f64))
+                                .with_cell(bibytes1(z.reclaimable as f64))
+                                .with_cell(bibytes1(z.pinned as f64)),
+                        );
+                    }
+                    print!("{table}");
+                }
+            }
+            match job {
+                Some(id) if self.wait => job::wait(&bfffs, id).await,
+                _ => Ok(()),
+            }
+        }
+    }
+
+    /// Rewrite fragmented files contiguously
+    ///
+    /// Finds files whose records are scattered across the pool, and rewrites
+    /// each one's records adjacently.  Defragmentation runs in the
+    /// background, yielding to foreground I/O.
+    #[derive(Parser, Clone, Debug)]
+    pub(super) struct Defrag {
+        /// Dry run.  Measure fragmentation, but don't rewrite anything.
+        #[clap(short = 'n', long)]
+        pub(super) dry_run:   bool,
+        /// Wait for defragmentation to finish, displaying its progress, then
+        /// measure fragmentation again
+        #[clap(short, long)]
+        pub(super) wait:      bool,
+        /// Pool name
+        pub(super) pool_name: String,
+    }
+
+    impl Defrag {
+        fn describe(stats: &DefragStats) -> String {
+            format!(
+                "{:.1}% fragmented ({} records in {} runs across {} files)",
+                stats.fragmentation() * 100.0,
+                stats.records,
+                stats.fragments,
+                stats.files
+            )
+        }
+
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
+            let bfffs = conn.connect().await;
+            let (before, job) = bfffs
+                .pool_defrag(self.pool_name.clone(), self.dry_run)
+                .await?;
+            let verb = if self.dry_run {
+                "would rewrite"
+            } else {
+                "rewriting"
+            };
+            println!("before: {}", Defrag::describe(&before));
+            println!(
+                "{verb} {} files, moving {}",
+                before.fragmented,
+                bibytes1(before.moved as f64)
+            );
+            match job {
+                Some(id) if self.wait => {
+                    job::wait(&bfffs, id).await?;
+                    let (after, _) =
+                        bfffs.pool_defrag(self.pool_name, true).await?;
+                    println!("after: {}", Defrag::describe(&after));
+                    Ok(())
+                }
+                _ => Ok(()),
+            }
+        }
+    }
+
+    /// Unmount all of a pool's file systems and stop bfffsd
+    ///
+    /// The file systems are unmounted children first.  If any of them can't
+    /// be, the pool remains imported.
+    #[derive(Parser, Clone, Debug)]
+    pub(super) struct Export {
+        /// Focibly unmount file systems even if files are still active.
+        #[clap(short, long)]
+        pub(super) force:     bool,
+        /// Pool name
+        pub(super) pool_name: String,
+    }
+
+    impl Export {
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
+            let bfffs = conn.connect().await;
+            fs::report(bfffs.pool_export(self.pool_name, self.force).await?)
         }
     }
 
     /// Create a new storage pool
     #[derive(Parser, Clone, Debug)]
+    #[clap(after_help = "EXAMPLES:
+        bfffs pool create mypool /dev/da0
+        bfffs pool create mypool mirror /dev/da0 /dev/da1
+        bfffs pool create mypool raid 3 1 /dev/da0 /dev/da1 /dev/da2")]
     pub(super) struct Create {
         /// Dataset properties, comma delimited
         #[clap(
@@ -660,15 +1937,22 @@ mod pool {
             require_value_delimiter(true),
             value_delimiter(',')
         )]
-        pub(super) properties: Vec<String>,
+        pub(super) properties:   Vec<String>,
         /// Simulated zone size in MB
         #[clap(long)]
-        pub(super) zone_size:  Option<u64>,
+        pub(super) zone_size:    Option<u64>,
+        /// Mark this mirror child as write-mostly.  Reads will go to the
+        /// mirror's other children unless they can't satisfy them.  May be
+        /// repeated.
+        #[clap(long, number_of_values = 1, value_name = "DEV")]
+        pub(super) write_mostly: Vec<String>,
         #[clap(required(true))]
         /// Pool name
-        pub(super) pool_name:  String,
+        pub(super) pool_name:    String,
+        /// Vdev specification, like "mirror /dev/da0 /dev/da1" or
+        /// "raid 3 1 /dev/da0 /dev/da1 /dev/da2"
         #[clap(required(true))]
-        pub(super) vdev:       Vec<String>,
+        pub(super) vdev:         Vec<String>,
     }
 
     use lalrpop_util::lalrpop_mod;
@@ -688,6 +1972,21 @@ mod pool {
             let mut builder = Builder::new(self.pool_name, props, zone_size);
             let all_vdevs = self.vdev.join(" ");
             let spec = PoolParser::new().parse(&all_vdevs).unwrap();
+            for dev in self.write_mostly.iter() {
+                let in_mirror = spec.0.iter().any(|tvd| match tvd {
+                    Tlv::Raid(r) => r.vdevs.iter().any(|child| match child {
+                        RaidChild::Mirror(m) => m.0.contains(&dev.as_str()),
+                        RaidChild::Disk(_) => false,
+                    }),
+                    Tlv::Mirror(m) => m.0.contains(&dev.as_str()),
+                    Tlv::Disk(_) => false,
+                });
+                if !in_mirror {
+                    eprintln!("{dev} is not a mirror child");
+                    std::process::exit(2);
+                }
+            }
+            builder.write_mostly(self.write_mostly);
             for tvd in spec.0 {
                 match tvd {
                     Tlv::Raid(r) => {
@@ -712,12 +2011,332 @@ mod pool {
         }
     }
 
+    /// Bring a removed or faulted disk back online
+    ///
+    /// A disk that disappears while the pool is imported is marked as removed,
+    /// and the pool continues without it if it has enough redundancy.  Once
+    /// the disk reappears, this reattaches it and resilvers whatever writes it
+    /// missed.  Likewise, a disk that's faulted because a write to it failed
+    /// will be resilvered and used again.
+    #[derive(Parser, Clone, Debug)]
+    #[clap(after_help = "EXAMPLES:
+        bfffs pool online mypool /dev/da1")]
+    pub(super) struct Online {
+        /// Pool name
+        pub(super) pool_name: String,
+        /// Path to the reattached or faulted disk
+        pub(super) device:    PathBuf,
+    }
+
+    impl Online {
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
+            let bfffs = conn.connect().await;
+            bfffs.pool_online(self.pool_name, self.device).await
+        }
+    }
+
+    /// Parse a cluster specification, like "mirror A B" or "raid 3 1 A B C",
+    /// using `f` to parse each disk.
+    ///
+    /// Returns the cluster's disks per stripe, its redundancy, and the disks
+    /// of each of its mirrors.
+    pub(super) fn parse_cluster<T, F>(
+        spec: &[String],
+        f: F,
+    ) -> std::result::Result<(i16, i16, Vec<Vec<T>>), String>
+    where
+        F: Fn(&str) -> std::result::Result<T, String>,
+    {
+        let disks = |ss: &[String]| {
+            ss.iter()
+                .map(|s| f(s))
+                .collect::<std::result::Result<Vec<_>, _>>()
+        };
+        match spec.first().map(String::as_str) {
+            Some("mirror") => Ok((1, 0, vec![disks(&spec[1..])?])),
+            Some("raid") => {
+                let num = |i: usize| {
+                    spec.get(i).and_then(|s| s.parse::<i16>().ok()).ok_or_else(
+                        || "raid requires disks per stripe and redundancy",
+                    )
+                };
+                let disks_per_stripe = num(1)?;
+                let redundancy = num(2)?;
+                let mut mirrors = Vec::new();
+                let mut rest = &spec[3..];
+                while let Some(first) = rest.first() {
+                    if first == "mirror" {
+                        let end = rest[1..]
+                            .iter()
+                            .position(|s| s == "mirror")
+                            .map(|i| i + 1)
+                            .unwrap_or(rest.len());
+                        mirrors.push(disks(&rest[1..end])?);
+                        rest = &rest[end..];
+                    } else {
+                        mirrors.push(vec![f(first)?]);
+                        rest = &rest[1..];
+                    }
+                }
+                Ok((disks_per_stripe, redundancy, mirrors))
+            }
+            Some(disk) if spec.len() == 1 => Ok((1, 0, vec![vec![f(disk)?]])),
+            _ => Err("Expected a single disk, a mirror, or a raid".to_owned()),
+        }
+    }
+
+    /// Parse a hypothetical cluster specification, like "mirror 4T 4T" or
+    /// "raid 3 1 4T 4T 4T".  It uses the same syntax as "bfffs pool add",
+    /// but with disk sizes in place of disk names.
+    pub(super) fn parse_cluster_spec(
+        spec: &[String],
+    ) -> std::result::Result<Change, String> {
+        parse_cluster(spec, volume::parse_size).map(
+            |(disks_per_stripe, redundancy, mirrors)| Change::AddCluster {
+                disks_per_stripe,
+                redundancy,
+                mirrors,
+            },
+        )
+    }
+
+    /// Predict the effect of changing a pool's disks, without changing them
+    ///
+    /// Reports the pool's capacity and redundancy before and after the
+    /// change, and how much data would have to be copied to carry it out.
+    /// Redundancy is the number of disks that could fail without losing the
+    /// pool.
+    #[derive(Parser, Clone, Debug)]
+    #[clap(after_help = "EXAMPLES:
+        bfffs pool plan mypool add mirror 4T 4T
+        bfffs pool plan mypool add raid 5 2 4T 4T 4T 4T 4T
+        bfffs pool plan mypool remove 3b9b9f3e-5b09-4d4e-a5bf-0b5e3e0a6d22")]
+    pub(super) struct Plan {
+        /// Pool name
+        pub(super) pool_name: String,
+        #[clap(subcommand)]
+        pub(super) change:    PlanCmd,
+    }
+
+    impl Plan {
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
+            let change = match self.change {
+                PlanCmd::Add { spec } => {
+                    parse_cluster_spec(&spec).unwrap_or_else(|e| {
+                        eprintln!("{e}");
+                        exit(2);
+                    })
+                }
+                PlanCmd::Remove { disk } => Change::RemoveCluster(disk),
+                PlanCmd::Replace { disk, size } => {
+                    Change::ReplaceDisk { uuid: disk, size }
+                }
+            };
+            let bfffs = conn.connect().await;
+            let plan = bfffs.pool_plan(self.pool_name, change).await?;
+            println!(
+                "size:       {} -> {}",
+                bibytes1(plan.size_before as f64),
+                bibytes1(plan.size_after as f64)
+            );
+            println!("used:       {}", bibytes1(plan.used as f64));
+            println!(
+                "redundancy: {} -> {}",
+                plan.redundancy_before, plan.redundancy_after
+            );
+            println!("moved:      {}", bibytes1(plan.moved as f64));
+            Ok(())
+        }
+    }
+
+    /// A hypothetical change to a pool's disks
+    #[derive(Parser, Clone, Debug)]
+    pub(super) enum PlanCmd {
+        /// Add a new cluster
+        Add {
+            /// Cluster specification, like "mirror 4T 4T" or
+            /// "raid 3 1 4T 4T 4T"
+            #[clap(required(true))]
+            spec: Vec<String>,
+        },
+        /// Remove the cluster containing a disk, moving its data elsewhere
+        Remove {
+            /// UUID of any disk in the cluster, as shown by "bfffs pool
+            /// status"
+            disk: Uuid,
+        },
+        /// Replace a disk with a new one
+        Replace {
+            /// UUID of the disk to replace, as shown by "bfffs pool status"
+            disk: Uuid,
+            /// Size of the replacement disk, like "8T"
+            #[clap(value_parser = volume::parse_size)]
+            size: u64,
+        },
+    }
+
+    /// Set pool properties
+    ///
+    /// Unlike dataset properties, these apply to the whole pool and are never
+    /// inherited.
+    #[derive(Parser, Clone, Debug)]
+    #[clap(after_help = "EXAMPLES:
+        bfffs pool set autotrim=on mypool")]
+    pub(super) struct Set {
+        /// Pool properties to set, comma delimited.  Valid properties are
+        /// autoreplace, autotrim, cachefile, and failmode.
+        #[clap(
+            require_value_delimiter(true),
+            value_delimiter(','),
+            multiple_occurrences = false,
+            required(true)
+        )]
+        pub(super) properties: Vec<PoolProperty>,
+        /// Pool name
+        pub(super) pool_name:  String,
+    }
+
+    impl Set {
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
+            let bfffs = conn.connect().await;
+            bfffs.pool_set(self.pool_name, self.properties).await
+        }
+    }
+
+    /// Show the lifetime I/O error counts of every disk in a pool
+    ///
+    /// The counts are stored on the disks themselves, so they accumulate
+    /// across reboots.
+    #[derive(Parser, Clone, Debug)]
+    pub(super) struct Status {
+        /// Also show how many zones have been opened and finished since
+        /// import, and list files with unrecoverable read errors.  This may be
+        /// slow.
+        #[clap(short = 'v', long)]
+        pub(super) verbose:   bool,
+        /// Pool name
+        pub(super) pool_name: String,
+    }
+
+    impl Status {
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
+            let bfffs = conn.connect().await;
+            let status = bfffs.pool_status(self.pool_name.clone()).await?;
+            let mut table = tabular::Table::new("{:<} {:>} {:>} {:>}");
+            table.add_row(
+                tabular::Row::new()
+                    .with_cell("DISK")
+                    .with_cell("READ")
+                    .with_cell("WRITE")
+                    .with_cell("CKSUM"),
+            );
+            for leaf in status.leaves {
+                let mut disk = leaf.uuid.to_string();
+                if leaf.write_mostly {
+                    disk.push_str(" (write-mostly)");
+                }
+                if leaf.removed {
+                    disk.push_str(" (removed)");
+                }
+                table.add_row(
+                    tabular::Row::new()
+                        .with_cell(disk)
+                        .with_cell(leaf.errors.read)
+                        .with_cell(leaf.errors.write)
+                        .with_cell(leaf.errors.checksum),
+                );
+            }
+            print!("{table}");
+            if self.verbose {
+                let zones = status.zones;
+                println!(
+                    "zones: {} opened, {} finished, {} finished early",
+                    zones.opened, zones.closed, zones.evicted
+                );
+                let errors = bfffs.pool_errors(self.pool_name).await?;
+                if errors.is_empty() {
+                    println!("errors: No known data errors");
+                } else {
+                    println!(
+                        "errors: Permanent errors in the following files:"
+                    );
+                    for e in errors {
+                        match e.path {
+                            Some(path) => {
+                                println!("    {}:/{}", e.dataset, path)
+                            }
+                            None => {
+                                println!("    {}:<0x{:x}>", e.dataset, e.ino)
+                            }
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    /// Show a pool's transaction group numbers
+    ///
+    /// Useful for correlating snapshots, labels, and logs.
+    #[derive(Parser, Clone, Debug)]
+    pub(super) struct Txgs {
+        /// Pool name
+        pub(super) pool_name: String,
+    }
+
+    impl Txgs {
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
+            let bfffs = conn.connect().await;
+            let txgs = bfffs.pool_txgs(self.pool_name).await?;
+            let fmt = |txg: Option<TxgT>| {
+                txg.map(|t| t.0.to_string()).unwrap_or_else(|| "-".to_string())
+            };
+            println!("current:    {}", txgs.current.0);
+            println!("synced:     {}", fmt(txgs.synced));
+            println!("checkpoint: {}", fmt(txgs.checkpoint));
+            Ok(())
+        }
+    }
+
+    /// Enable new on-disk format features on a pool
+    ///
+    /// Once enabled, a feature can't be disabled, and older versions of BFFFS
+    /// may be unable to import the pool.
+    #[derive(Parser, Clone, Debug)]
+    pub(super) struct Upgrade {
+        /// Features to enable, comma delimited.  If omitted, enable every
+        /// feature supported by this version.
+        #[clap(
+            short,
+            long,
+            require_value_delimiter(true),
+            value_delimiter(',')
+        )]
+        pub(super) features:  Vec<Feature>,
+        /// Pool name
+        pub(super) pool_name: String,
+    }
+
+    impl Upgrade {
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
+            let bfffs = conn.connect().await;
+            let enabled =
+                bfffs.pool_upgrade(self.pool_name, self.features).await?;
+            for feature in enabled {
+                println!("Enabled feature {feature}");
+            }
+            Ok(())
+        }
+    }
+
     struct Builder {
-        clusters:   Vec<Cluster>,
-        mirrors:    Vec<Mirror>,
-        name:       String,
-        properties: Vec<Property>,
-        zone_size:  Option<NonZeroU64>,
+        clusters:     Vec<Cluster>,
+        mirrors:      Vec<Mirror>,
+        name:         String,
+        properties:   Vec<Property>,
+        write_mostly: Vec<String>,
+        zone_size:    Option<NonZeroU64>,
     }
 
     impl Builder {
@@ -744,13 +2363,19 @@ mod pool {
                 mirrors,
                 name,
                 properties,
+                write_mostly: Vec::new(),
                 zone_size,
             }
         }
 
         pub fn create_mirror(&mut self, devs: &[&str]) {
-            self.mirrors
-                .push(Mirror::create(devs, self.zone_size).unwrap());
+            let mut mirror = Mirror::create(devs, self.zone_size).unwrap();
+            for (i, dev) in devs.iter().enumerate() {
+                if self.write_mostly.iter().any(|wm| wm == dev) {
+                    mirror.set_write_mostly(i, true);
+                }
+            }
+            self.mirrors.push(mirror);
         }
 
         pub fn create_mirror_tlv(&mut self, devs: &[&str]) {
@@ -758,6 +2383,12 @@ mod pool {
             self.create_cluster(1, 0);
         }
 
+        /// Mark these devices as write-mostly, in whichever mirrors they're
+        /// later added to.
+        pub fn write_mostly(&mut self, devs: Vec<String>) {
+            self.write_mostly = devs;
+        }
+
         pub fn create_single(&mut self, dev: &str) {
             self.create_mirror(&[dev]);
             self.create_cluster(1, 0)
@@ -792,59 +2423,274 @@ mod pool {
     #[derive(Parser, Clone, Debug)]
     /// Create, destroy, and modify storage pools
     pub(super) enum PoolCmd {
+        Add(Add),
+        Checkpoint(Checkpoint),
         Clean(Clean),
         Create(Create),
+        Defrag(Defrag),
+        Export(Export),
+        Online(Online),
+        Plan(Plan),
+        Set(Set),
+        Status(Status),
+        Txgs(Txgs),
+        Upgrade(Upgrade),
     }
 }
 
-#[derive(Parser, Clone, Debug)]
-enum SubCommand {
-    Check(Check),
-    #[clap(subcommand)]
-    Debug(DebugCmd),
-    #[clap(subcommand)]
-    Fs(fs::FsCmd),
-    #[clap(subcommand)]
-    Pool(pool::PoolCmd),
-}
+mod volume {
+    use super::*;
 
-#[derive(Parser, Clone, Debug)]
-#[clap(version = crate_version!())]
-struct Cli {
-    /// Path to the bfffsd socket
-    #[clap(long, default_value = "/var/run/bfffsd.sock")]
-    sock: PathBuf,
-    #[clap(subcommand)]
-    cmd:  SubCommand,
-}
+    /// Parse a size like "10G".  The K, M, G, and T suffixes are powers of
+    /// 1024.
+    pub(super) fn parse_size(s: &str) -> std::result::Result<u64, String> {
+        let (digits, shift) = match s.char_indices().last() {
+            Some((i, 'k' | 'K')) => (&s[..i], 10),
+            Some((i, 'm' | 'M')) => (&s[..i], 20),
+            Some((i, 'g' | 'G')) => (&s[..i], 30),
+            Some((i, 't' | 'T')) => (&s[..i], 40),
+            _ => (s, 0),
+        };
+        digits
+            .parse::<u64>()
+            .ok()
+            .and_then(|n| n.checked_mul(1 << shift))
+            .ok_or_else(|| format!("{s} is not a valid size"))
+    }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<()> {
-    tracing_subscriber::fmt()
-        .pretty()
-        .with_env_filter(EnvFilter::from_default_env())
-        .init();
-    let cli: Cli = Cli::parse();
-    match cli.cmd {
-        SubCommand::Check(check) => check.main().await,
-        SubCommand::Fs(fs::FsCmd::Create(create)) => {
-            create.main(&cli.sock).await
-        }
-        SubCommand::Fs(fs::FsCmd::Destroy(destroy)) => {
-            destroy.main(&cli.sock).await
-        }
-        SubCommand::Fs(fs::FsCmd::Get(get)) => get.main(&cli.sock).await,
-        SubCommand::Fs(fs::FsCmd::List(list)) => list.main(&cli.sock).await,
-        SubCommand::Fs(fs::FsCmd::Mount(mount)) => mount.main(&cli.sock).await,
-        SubCommand::Fs(fs::FsCmd::Set(set)) => set.main(&cli.sock).await,
-        SubCommand::Fs(fs::FsCmd::Unmount(unmount)) => {
-            unmount.main(&cli.sock).await
+    /// Create a new volume
+    ///
+    /// A volume is a dataset that holds a single, fixed-size block device
+    /// instead of a file system.  To export it with ctld(8), mount it with
+    /// "bfffs fs mount" and use the "volume" file in its mountpoint as the
+    /// LUN's path.  Or, export it directly from bfffsd by setting the
+    /// shareiscsi property.
+    #[derive(Parser, Clone, Debug)]
+    #[clap(after_help = "EXAMPLES:
+        bfffs volume create -s 10G mypool/vol")]
+    pub(super) struct Create {
+        /// Volume size, like "10G".  Must be a multiple of 512 bytes.
+        #[clap(short, long, value_parser = parse_size)]
+        pub(super) size:       u64,
+        /// Volume properties, comma delimited
+        #[clap(
+            short = 'o',
+            long,
+            require_value_delimiter(true),
+            value_delimiter(',')
+        )]
+        pub(super) properties: Vec<String>,
+        /// Volume name
+        pub(super) name:       String,
+    }
+
+    impl Create {
+        pub(super) async fn main(self, conn: &Connection) -> Result<()> {
+            let bfffs = conn.connect().await;
+            let props = self
+                .properties
+                .iter()
+                .map(|ps| {
+                    Property::from_str(ps.as_str()).unwrap_or_else(|_e| {
+                        eprintln!("Invalid property specification {ps}");
+                        std::process::exit(2);
+                    })
+                })
+                .collect::<Vec<_>>();
+            bfffs
+                .volume_create(self.name, self.size, props)
+                .await
+                .map(drop)
+        }
+    }
+
+    #[derive(Parser, Clone, Debug)]
+    /// Create block volumes
+    pub(super) enum VolumeCmd {
+        Create(Create),
+    }
+}
+
+#[derive(Parser, Clone, Debug)]
+enum SubCommand {
+    Check(Check),
+    Completions(Completions),
+    #[clap(subcommand)]
+    Daemon(daemon::DaemonCmd),
+    #[clap(subcommand)]
+    Debug(DebugCmd),
+    #[clap(subcommand)]
+    Fs(fs::FsCmd),
+    #[clap(subcommand)]
+    Job(job::JobCmd),
+    #[clap(subcommand)]
+    Pool(pool::PoolCmd),
+    #[clap(subcommand)]
+    Volume(volume::VolumeCmd),
+}
+
+#[derive(Parser, Clone, Debug)]
+#[clap(version = crate_version!())]
+#[clap(after_help = "EXAMPLES:
+    bfffs pool create mypool mirror /dev/da0 /dev/da1
+    bfffs fs create -o atime=off mypool/home
+    bfffs fs get all mypool/home
+    bfffs help fs set")]
+/// Administer BFFFS pools and file systems
+struct Cli {
+    /// File containing bfffsd's authentication token, for privileged requests
+    #[clap(long)]
+    auth_token: Option<PathBuf>,
+    /// Connect to a remote bfffsd over TLS, instead of the local socket
+    #[clap(
+        short = 'H',
+        long,
+        value_name = "HOST:PORT",
+        requires_all(&["tls-ca", "tls-cert", "tls-key"])
+    )]
+    host:       Option<String>,
+    /// Path to the bfffsd socket
+    #[clap(long, default_value = "/var/run/bfffsd.sock")]
+    sock:       PathBuf,
+    /// PEM file of the certificate authorities that sign bfffsd's certificate
+    #[clap(long)]
+    tls_ca:     Option<PathBuf>,
+    /// PEM file containing this client's TLS certificate chain
+    #[clap(long)]
+    tls_cert:   Option<PathBuf>,
+    /// PEM file containing this client's TLS private key
+    #[clap(long)]
+    tls_key:    Option<PathBuf>,
+    #[clap(subcommand)]
+    cmd:        SubCommand,
+}
+
+/// Everything needed to connect to bfffsd
+#[derive(Clone, Debug)]
+struct Connection {
+    auth_token: Option<PathBuf>,
+    host:       Option<String>,
+    sock:       PathBuf,
+    tls_ca:     Option<PathBuf>,
+    tls_cert:   Option<PathBuf>,
+    tls_key:    Option<PathBuf>,
+}
+
+impl Connection {
+    #[cfg(feature = "tls")]
+    async fn connect_remote(&self, host: &str) -> Bfffs {
+        // Clap ensures that the TLS options are present
+        let ca = self.tls_ca.as_deref().unwrap();
+        let cert = self.tls_cert.as_deref().unwrap();
+        let key = self.tls_key.as_deref().unwrap();
+        Bfffs::new_tls(host, ca, cert, key)
+            .await
+            .unwrap_or_else(|e| {
+                eprintln!("Cannot connect to {host}: {e:?}");
+                exit(1);
+            })
+    }
+
+    #[cfg(not(feature = "tls"))]
+    async fn connect_remote(&self, _host: &str) -> Bfffs {
+        eprintln!("bfffs was built without TLS support");
+        exit(1);
+    }
+
+    async fn connect(&self) -> Bfffs {
+        let mut bfffs = match &self.host {
+            Some(host) => self.connect_remote(host).await,
+            None => Bfffs::new(&self.sock).await.unwrap(),
+        };
+        if let Some(path) = &self.auth_token {
+            let token = std::fs::read(path).unwrap_or_else(|e| {
+                eprintln!("Cannot read auth token {}: {e}", path.display());
+                exit(1);
+            });
+            bfffs.auth_token(&token);
+        }
+        bfffs
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt()
+        .pretty()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
+    let cli: Cli = Cli::parse();
+    let conn = Connection {
+        auth_token: cli.auth_token,
+        host:       cli.host,
+        sock:       cli.sock,
+        tls_ca:     cli.tls_ca,
+        tls_cert:   cli.tls_cert,
+        tls_key:    cli.tls_key,
+    };
+    match cli.cmd {
+        SubCommand::Check(check) => check.main().await,
+        SubCommand::Completions(completions) => completions.main(),
+        SubCommand::Daemon(daemon::DaemonCmd::Ping(ping)) => {
+            ping.main(&conn).await
+        }
+        SubCommand::Fs(fs::FsCmd::Create(create)) => create.main(&conn).await,
+        SubCommand::Fs(fs::FsCmd::Destroy(destroy)) => {
+            destroy.main(&conn).await
         }
-        SubCommand::Debug(DebugCmd::DropCache(dc)) => dc.main(&cli.sock).await,
+        SubCommand::Fs(fs::FsCmd::Freeze(freeze)) => freeze.main(&conn).await,
+        SubCommand::Fs(fs::FsCmd::Get(get)) => get.main(&conn).await,
+        SubCommand::Fs(fs::FsCmd::Iostat(iostat)) => iostat.main(&conn).await,
+        SubCommand::Fs(fs::FsCmd::Jail(jail)) => jail.main(&conn).await,
+        SubCommand::Fs(fs::FsCmd::List(list)) => list.main(&conn).await,
+        SubCommand::Fs(fs::FsCmd::Mount(mount)) => mount.main(&conn).await,
+        SubCommand::Fs(fs::FsCmd::Mounts(mounts)) => mounts.main(&conn).await,
+        SubCommand::Fs(fs::FsCmd::Mv(mv)) => mv.main(&conn).await,
+        SubCommand::Fs(fs::FsCmd::Restore(restore)) => restore.main().await,
+        SubCommand::Fs(fs::FsCmd::Set(set)) => set.main(&conn).await,
+        SubCommand::Fs(fs::FsCmd::Thaw(thaw)) => thaw.main(&conn).await,
+        SubCommand::Fs(fs::FsCmd::Unjail(unjail)) => unjail.main(&conn).await,
+        SubCommand::Fs(fs::FsCmd::Unmount(unmount)) => {
+            unmount.main(&conn).await
+        }
+        SubCommand::Fs(fs::FsCmd::Watch(watch)) => watch.main(&conn).await,
+        SubCommand::Debug(DebugCmd::CloseFile(cf)) => cf.main(&conn).await,
+        SubCommand::Debug(DebugCmd::Compact(compact)) => {
+            compact.main(&conn).await
+        }
+        SubCommand::Debug(DebugCmd::DropCache(dc)) => dc.main(&conn).await,
         SubCommand::Debug(DebugCmd::Dump(dump)) => dump.main().await,
+        SubCommand::Debug(DebugCmd::OpenFiles(of)) => of.main(&conn).await,
+        SubCommand::Debug(DebugCmd::Taste(taste)) => taste.main(),
+        SubCommand::Job(job::JobCmd::List(list)) => list.main(&conn).await,
+        SubCommand::Pool(pool::PoolCmd::Add(add)) => add.main(&conn).await,
         SubCommand::Pool(pool::PoolCmd::Create(create)) => create.main().await,
+        SubCommand::Pool(pool::PoolCmd::Checkpoint(checkpoint)) => {
+            checkpoint.main(&conn).await
+        }
         SubCommand::Pool(pool::PoolCmd::Clean(clean)) => {
-            clean.main(&cli.sock).await
+            clean.main(&conn).await
+        }
+        SubCommand::Pool(pool::PoolCmd::Defrag(defrag)) => {
+            defrag.main(&conn).await
+        }
+        SubCommand::Pool(pool::PoolCmd::Export(export)) => {
+            export.main(&conn).await
+        }
+        SubCommand::Pool(pool::PoolCmd::Online(online)) => {
+            online.main(&conn).await
+        }
+        SubCommand::Pool(pool::PoolCmd::Plan(plan)) => plan.main(&conn).await,
+        SubCommand::Pool(pool::PoolCmd::Set(set)) => set.main(&conn).await,
+        SubCommand::Pool(pool::PoolCmd::Status(status)) => {
+            status.main(&conn).await
+        }
+        SubCommand::Pool(pool::PoolCmd::Txgs(txgs)) => txgs.main(&conn).await,
+        SubCommand::Pool(pool::PoolCmd::Upgrade(upgrade)) => {
+            upgrade.main(&conn).await
+        }
+        SubCommand::Volume(volume::VolumeCmd::Create(create)) => {
+            create.main(&conn).await
         }
     }
 }
@@ -861,13 +2707,16 @@ mod t {
     #[case(vec!["bfffs"])]
     #[case(vec!["bfffs", "check"])]
     #[case(vec!["bfffs", "check", "testpool"])]
+    #[case(vec!["bfffs", "completions"])]
     #[case(vec!["bfffs", "debug"])]
     #[case(vec!["bfffs", "debug", "dump"])]
     #[case(vec!["bfffs", "debug", "dump", "testpool"])]
+    #[case(vec!["bfffs", "debug", "taste"])]
     #[case(vec!["bfffs", "fs", "create"])]
     #[case(vec!["bfffs", "pool"])]
     #[case(vec!["bfffs", "pool", "create"])]
     #[case(vec!["bfffs", "pool", "create", "testpool"])]
+    #[case(vec!["bfffs", "volume", "create", "testpool/vol"])]
     fn missing_arg(#[case] args: Vec<&str>) {
         let e = Cli::try_parse_from(args).unwrap_err();
         assert!(
@@ -876,6 +2725,104 @@ mod t {
         );
     }
 
+    #[test]
+    fn host() {
+        let args = vec![
+            "bfffs",
+            "-H",
+            "storage.example.com:4820",
+            "--tls-ca",
+            "ca.pem",
+            "--tls-cert",
+            "cert.pem",
+            "--tls-key",
+            "key.pem",
+            "pool",
+            "status",
+            "testpool",
+        ];
+        let cli = Cli::try_parse_from(args).unwrap();
+        assert_eq!(cli.host.as_deref(), Some("storage.example.com:4820"));
+        assert_eq!(cli.tls_ca.as_deref(), Some(Path::new("ca.pem")));
+        assert_eq!(cli.tls_cert.as_deref(), Some(Path::new("cert.pem")));
+        assert_eq!(cli.tls_key.as_deref(), Some(Path::new("key.pem")));
+    }
+
+    /// A remote connection requires a client certificate
+    #[test]
+    fn host_without_cert() {
+        let args = vec![
+            "bfffs",
+            "-H",
+            "storage.example.com:4820",
+            "pool",
+            "status",
+            "testpool",
+        ];
+        let e = Cli::try_parse_from(args).unwrap_err();
+        assert_eq!(e.kind(), MissingRequiredArgument);
+    }
+
+    #[test]
+    fn auth_token() {
+        let args = vec![
+            "bfffs",
+            "--auth-token",
+            "/etc/bfffs.token",
+            "fs",
+            "mount",
+            "testpool",
+        ];
+        let cli = Cli::try_parse_from(args).unwrap();
+        assert_eq!(
+            cli.auth_token.as_deref(),
+            Some(Path::new("/etc/bfffs.token"))
+        );
+        assert!(matches!(cli.cmd, SubCommand::Fs(fs::FsCmd::Mount(_))));
+    }
+
+    /// Check the whole command tree for inconsistencies, such as conflicting
+    /// argument names.
+    #[test]
+    fn command_tree() {
+        Cli::command().debug_assert();
+    }
+
+    mod completions {
+        use super::*;
+
+        #[rstest]
+        #[case("bash")]
+        #[case("fish")]
+        #[case("zsh")]
+        fn generate(#[case] shell: &str) {
+            let args = vec!["bfffs", "completions", shell];
+            let cli = Cli::try_parse_from(args).unwrap();
+            let completions = match cli.cmd {
+                SubCommand::Completions(completions) => completions,
+                _ => panic!("Wrong subcommand"),
+            };
+            let mut buf = Vec::new();
+            clap_complete::generate(
+                completions.shell,
+                &mut Cli::command(),
+                "bfffs",
+                &mut buf,
+            );
+            let script = String::from_utf8(buf).unwrap();
+            // Subcommands that are deeply nested should still be completed
+            assert!(script.contains("freeze"));
+            assert!(script.contains("upgrade"));
+        }
+
+        #[test]
+        fn unknown_shell() {
+            let args = vec!["bfffs", "completions", "csh"];
+            let e = Cli::try_parse_from(args).unwrap_err();
+            assert_eq!(e.kind(), InvalidValue);
+        }
+    }
+
     #[test]
     fn check() {
         let args = vec!["bfffs", "check", "testpool", "/dev/da0", "/dev/da1"];
@@ -885,12 +2832,95 @@ mod t {
             assert_eq!(check.pool_name, "testpool");
             assert_eq!(check.disks[0], Path::new("/dev/da0"));
             assert_eq!(check.disks[1], Path::new("/dev/da1"));
+            assert!(!check.repair);
+            assert!(!check.refcounts);
+            assert!(!check.scrub);
+        }
+    }
+
+    #[test]
+    fn check_repair() {
+        let args = vec!["bfffs", "check", "--repair", "testpool", "/dev/da0"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        assert!(matches!(cli.cmd, SubCommand::Check(_)));
+        if let SubCommand::Check(check) = cli.cmd {
+            assert_eq!(check.pool_name, "testpool");
+            assert!(check.repair);
+        }
+    }
+
+    #[test]
+    fn check_refcounts() {
+        let args =
+            vec!["bfffs", "check", "--refcounts", "testpool", "/dev/da0"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        assert!(matches!(cli.cmd, SubCommand::Check(_)));
+        if let SubCommand::Check(check) = cli.cmd {
+            assert!(check.refcounts);
+        }
+    }
+
+    #[test]
+    fn check_scrub() {
+        let args = vec![
+            "bfffs",
+            "check",
+            "--scrub",
+            "--inflight",
+            "64",
+            "testpool",
+            "/dev/da0",
+        ];
+        let cli = Cli::try_parse_from(args).unwrap();
+        assert!(matches!(cli.cmd, SubCommand::Check(_)));
+        if let SubCommand::Check(check) = cli.cmd {
+            assert!(check.scrub);
+            assert_eq!(check.inflight, 64);
+        }
+    }
+
+    mod daemon {
+        use super::*;
+
+        #[test]
+        fn ping() {
+            let args = vec!["bfffs", "daemon", "ping"];
+            let cli = Cli::try_parse_from(args).unwrap();
+            assert!(matches!(
+                cli.cmd,
+                SubCommand::Daemon(daemon::DaemonCmd::Ping(_))
+            ));
         }
     }
 
     mod debug {
         use super::*;
 
+        #[test]
+        fn close_file() {
+            let args =
+                vec!["bfffs", "debug", "close-file", "mypool/myfs", "42"];
+            let cli = Cli::try_parse_from(args).unwrap();
+            assert!(matches!(
+                cli.cmd,
+                SubCommand::Debug(DebugCmd::CloseFile(_))
+            ));
+            if let SubCommand::Debug(DebugCmd::CloseFile(cf)) = cli.cmd {
+                assert_eq!(cf.name, "mypool/myfs");
+                assert_eq!(cf.fh, 42);
+            }
+        }
+
+        #[test]
+        fn compact() {
+            let args = vec!["bfffs", "debug", "compact", "mypool/myfs"];
+            let cli = Cli::try_parse_from(args).unwrap();
+            assert!(matches!(cli.cmd, SubCommand::Debug(_)));
+            if let SubCommand::Debug(DebugCmd::Compact(compact)) = cli.cmd {
+                assert_eq!(compact.name, "mypool/myfs");
+            }
+        }
+
         #[test]
         fn drop_cache() {
             let args = vec!["bfffs", "debug", "drop-cache"];
@@ -915,6 +2945,41 @@ mod t {
             }
         }
 
+        #[test]
+        fn dump_tree_format() {
+            let args = vec![
+                "bfffs",
+                "debug",
+                "dump",
+                "-t",
+                "--format",
+                "json",
+                "--start-ino",
+                "2",
+                "--end-ino",
+                "10",
+                "testpool",
+                "/dev/da0",
+            ];
+            let cli = Cli::try_parse_from(args).unwrap();
+            assert!(matches!(cli.cmd, SubCommand::Debug(_)));
+            if let SubCommand::Debug(DebugCmd::Dump(debug)) = cli.cmd {
+                assert!(debug.tree);
+                assert_eq!(debug.format, Some(DumpFormat::Json));
+                assert_eq!(debug.start_ino, Some(2));
+                assert_eq!(debug.end_ino, Some(10));
+            }
+        }
+
+        #[test]
+        fn dump_tree_format_without_tree() {
+            let args = vec![
+                "bfffs", "debug", "dump", "--format", "json", "testpool",
+                "/dev/da0",
+            ];
+            assert!(Cli::try_parse_from(args).is_err());
+        }
+
         #[test]
         fn dump_tree() {
             let args = vec![
@@ -931,6 +2996,43 @@ mod t {
                 assert_eq!(debug.disks[1], Path::new("/dev/da1"));
             }
         }
+
+        #[test]
+        fn open_files() {
+            let args = vec!["bfffs", "debug", "open-files", "mypool/myfs"];
+            let cli = Cli::try_parse_from(args).unwrap();
+            assert!(matches!(
+                cli.cmd,
+                SubCommand::Debug(DebugCmd::OpenFiles(_))
+            ));
+            if let SubCommand::Debug(DebugCmd::OpenFiles(of)) = cli.cmd {
+                assert_eq!(of.name, "mypool/myfs");
+                assert!(!of.parseable);
+            }
+        }
+
+        #[test]
+        fn open_files_parseable() {
+            let args =
+                vec!["bfffs", "debug", "open-files", "-p", "mypool/myfs"];
+            let cli = Cli::try_parse_from(args).unwrap();
+            if let SubCommand::Debug(DebugCmd::OpenFiles(of)) = cli.cmd {
+                assert!(of.parseable);
+            } else {
+                panic!("Wrong subcommand");
+            }
+        }
+
+        #[test]
+        fn taste() {
+            let args = vec!["bfffs", "debug", "taste", "/dev/da0"];
+            let cli = Cli::try_parse_from(args).unwrap();
+            if let SubCommand::Debug(DebugCmd::Taste(taste)) = cli.cmd {
+                assert_eq!(taste.device, Path::new("/dev/da0"));
+            } else {
+                panic!("Wrong subcommand");
+            }
+        }
     }
 
     mod fs {
@@ -982,6 +3084,36 @@ mod t {
                 assert!(matches!(cli.cmd, SubCommand::Fs(FsCmd::Destroy(_))));
                 if let SubCommand::Fs(FsCmd::Destroy(destroy)) = cli.cmd {
                     assert_eq!(destroy.name, "testpool/foo");
+                    assert!(!destroy.dry_run);
+                    assert!(!destroy.verbose);
+                }
+            }
+
+            #[test]
+            fn dry_run_verbose() {
+                let args =
+                    vec!["bfffs", "fs", "destroy", "-nv", "testpool/foo"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                if let SubCommand::Fs(FsCmd::Destroy(destroy)) = cli.cmd {
+                    assert_eq!(destroy.name, "testpool/foo");
+                    assert!(destroy.dry_run);
+                    assert!(destroy.verbose);
+                } else {
+                    panic!("Wrong subcommand");
+                }
+            }
+        }
+
+        mod freeze {
+            use super::*;
+
+            #[test]
+            fn plain() {
+                let args = vec!["bfffs", "fs", "freeze", "testpool/foo"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                assert!(matches!(cli.cmd, SubCommand::Fs(FsCmd::Freeze(_))));
+                if let SubCommand::Fs(FsCmd::Freeze(freeze)) = cli.cmd {
+                    assert_eq!(freeze.name, "testpool/foo");
                 }
             }
         }
@@ -999,7 +3131,10 @@ mod t {
                 if let SubCommand::Fs(FsCmd::Get(get)) = cli.cmd {
                     assert_eq!(get.depth, Some(42));
                     assert_eq!(&get.datasets[..], &["testpool"][..]);
-                    assert_eq!(&get.properties[..], &[PropertyName::Atime][..]);
+                    assert_eq!(
+                        &get.properties[..],
+                        &[GetProp::Native(PropertyName::Atime)][..]
+                    );
                 }
             }
 
@@ -1022,7 +3157,10 @@ mod t {
                         &[fs::GetField::Name, fs::GetField::Property]
                     );
                     assert_eq!(&get.datasets[..], &["testpool"][..]);
-                    assert_eq!(&get.properties[..], &[PropertyName::Atime][..]);
+                    assert_eq!(
+                        &get.properties[..],
+                        &[GetProp::Native(PropertyName::Atime)][..]
+                    );
                 }
             }
 
@@ -1035,7 +3173,10 @@ mod t {
                 if let SubCommand::Fs(FsCmd::Get(get)) = cli.cmd {
                     assert!(get.parseable);
                     assert_eq!(&get.datasets[..], &["testpool"][..]);
-                    assert_eq!(&get.properties[..], &[PropertyName::Atime][..]);
+                    assert_eq!(
+                        &get.properties[..],
+                        &[GetProp::Native(PropertyName::Atime)][..]
+                    );
                 }
             }
 
@@ -1048,7 +3189,7 @@ mod t {
                     assert_eq!(&get.datasets[..], &["testpool"][..]);
                     assert_eq!(
                         &get.properties[..],
-                        &[PropertyName::RecordSize][..]
+                        &[GetProp::Native(PropertyName::RecordSize)][..]
                     );
                 }
             }
@@ -1062,7 +3203,10 @@ mod t {
                 if let SubCommand::Fs(FsCmd::Get(get)) = cli.cmd {
                     assert!(get.recursive);
                     assert_eq!(&get.datasets[..], &["testpool"][..]);
-                    assert_eq!(&get.properties[..], &[PropertyName::Atime][..]);
+                    assert_eq!(
+                        &get.properties[..],
+                        &[GetProp::Native(PropertyName::Atime)][..]
+                    );
                 }
             }
 
@@ -1084,7 +3228,7 @@ mod t {
                     assert_eq!(&get.datasets[..], &["testpool"][..]);
                     assert_eq!(
                         &get.properties[..],
-                        &[PropertyName::RecordSize][..]
+                        &[GetProp::Native(PropertyName::RecordSize)][..]
                     );
                 }
             }
@@ -1096,7 +3240,10 @@ mod t {
                 assert!(matches!(cli.cmd, SubCommand::Fs(FsCmd::Get(_))));
                 if let SubCommand::Fs(FsCmd::Get(get)) = cli.cmd {
                     assert_eq!(&get.datasets[..], &["foo", "bar"][..]);
-                    assert_eq!(&get.properties[..], &[PropertyName::Atime][..]);
+                    assert_eq!(
+                        &get.properties[..],
+                        &[GetProp::Native(PropertyName::Atime)][..]
+                    );
                 }
             }
 
@@ -1110,40 +3257,157 @@ mod t {
                     assert_eq!(&get.datasets[..], &["testpool"][..]);
                     assert_eq!(
                         &get.properties[..],
-                        &[PropertyName::RecordSize, PropertyName::Atime][..]
+                        &[
+                            GetProp::Native(PropertyName::RecordSize),
+                            GetProp::Native(PropertyName::Atime)
+                        ][..]
                     );
                 }
             }
-        }
 
-        mod list {
-            use super::*;
+            #[test]
+            fn all() {
+                let args = vec!["bfffs", "fs", "get", "all", "testpool"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                if let SubCommand::Fs(FsCmd::Get(get)) = cli.cmd {
+                    assert_eq!(&get.properties[..], &[GetProp::All][..]);
+                } else {
+                    panic!("Wrong subcommand");
+                }
+            }
 
             #[test]
-            fn sort() {
+            fn user() {
                 let args = vec![
-                    "bfffs", "fs", "list", "-s", "recsize", "-s", "atime",
-                    "-s", "name", "testpool",
+                    "bfffs",
+                    "fs",
+                    "get",
+                    "atime,user:backup-policy",
+                    "testpool",
                 ];
                 let cli = Cli::try_parse_from(args).unwrap();
-                assert!(matches!(cli.cmd, SubCommand::Fs(FsCmd::List(_))));
-                if let SubCommand::Fs(FsCmd::List(list)) = cli.cmd {
-                    assert_eq!(list.datasets, &["testpool"]);
+                if let SubCommand::Fs(FsCmd::Get(get)) = cli.cmd {
                     assert_eq!(
-                        list.sort,
+                        &get.properties[..],
                         &[
-                            PropertyName::RecordSize,
-                            PropertyName::Atime,
-                            PropertyName::Name
-                        ]
+                            GetProp::Native(PropertyName::Atime),
+                            GetProp::User("user:backup-policy".to_string())
+                        ][..]
                     );
+                } else {
+                    panic!("Wrong subcommand");
                 }
             }
+
+            #[test]
+            fn user_invalid() {
+                let args = vec!["bfffs", "fs", "get", "user:Foo", "testpool"];
+                assert!(Cli::try_parse_from(args).is_err());
+            }
         }
 
-        mod mount {
+        mod iostat {
             use super::*;
 
+            #[test]
+            fn plain() {
+                let args = vec!["bfffs", "fs", "iostat", "testpool/foo"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                assert!(matches!(cli.cmd, SubCommand::Fs(FsCmd::Iostat(_))));
+                if let SubCommand::Fs(FsCmd::Iostat(iostat)) = cli.cmd {
+                    assert_eq!(iostat.name, "testpool/foo");
+                    assert!(!iostat.latency);
+                    assert!(!iostat.parseable);
+                    assert!(!iostat.reset);
+                }
+            }
+
+            #[test]
+            fn flags() {
+                let args =
+                    vec!["bfffs", "fs", "iostat", "-lpz", "testpool/foo"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                assert!(matches!(cli.cmd, SubCommand::Fs(FsCmd::Iostat(_))));
+                if let SubCommand::Fs(FsCmd::Iostat(iostat)) = cli.cmd {
+                    assert_eq!(iostat.name, "testpool/foo");
+                    assert!(iostat.latency);
+                    assert!(iostat.parseable);
+                    assert!(iostat.reset);
+                }
+            }
+        }
+
+        mod jail {
+            use super::*;
+
+            #[test]
+            fn by_id() {
+                let args = vec!["bfffs", "fs", "jail", "42", "testpool/foo"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                assert!(matches!(cli.cmd, SubCommand::Fs(FsCmd::Jail(_))));
+                if let SubCommand::Fs(FsCmd::Jail(jail)) = cli.cmd {
+                    assert_eq!(jail.jail, "42");
+                    assert_eq!(jail.name, "testpool/foo");
+                }
+            }
+
+            #[test]
+            fn missing_name() {
+                let args = vec!["bfffs", "fs", "jail", "www"];
+                let e = Cli::try_parse_from(args).unwrap_err();
+                assert_eq!(e.kind(), MissingRequiredArgument);
+            }
+        }
+
+        mod list {
+            use super::*;
+
+            #[test]
+            fn sort() {
+                let args = vec![
+                    "bfffs", "fs", "list", "-s", "recsize", "-s", "atime",
+                    "-s", "name", "testpool",
+                ];
+                let cli = Cli::try_parse_from(args).unwrap();
+                assert!(matches!(cli.cmd, SubCommand::Fs(FsCmd::List(_))));
+                if let SubCommand::Fs(FsCmd::List(list)) = cli.cmd {
+                    assert_eq!(list.datasets, &["testpool"]);
+                    assert_eq!(
+                        list.sort,
+                        &[
+                            PropertyName::RecordSize,
+                            PropertyName::Atime,
+                            PropertyName::Name
+                        ]
+                    );
+                }
+            }
+        }
+
+        mod mount {
+            use super::*;
+
+            #[test]
+            fn all() {
+                let args = vec!["bfffs", "fs", "mount", "-a", "testpool"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                assert!(matches!(cli.cmd, SubCommand::Fs(FsCmd::Mount(_))));
+                if let SubCommand::Fs(FsCmd::Mount(mount)) = cli.cmd {
+                    assert_eq!(mount.name, "testpool");
+                    assert!(mount.all);
+                }
+            }
+
+            /// A single mountpoint makes no sense for several file systems
+            #[test]
+            fn all_mountpoint() {
+                let args = vec![
+                    "bfffs", "fs", "mount", "-a", "-m", "/mnt", "testpool",
+                ];
+                let e = Cli::try_parse_from(args).unwrap_err();
+                assert_eq!(e.kind(), ArgumentConflict);
+            }
+
             #[test]
             fn plain() {
                 let args = vec!["bfffs", "fs", "mount", "testpool"];
@@ -1151,7 +3415,9 @@ mod t {
                 assert!(matches!(cli.cmd, SubCommand::Fs(FsCmd::Mount(_))));
                 if let SubCommand::Fs(FsCmd::Mount(mount)) = cli.cmd {
                     assert_eq!(mount.name, "testpool");
+                    assert!(!mount.all);
                     assert!(mount.options.is_empty());
+                    assert!(mount.mountpoint.is_none());
                 }
             }
 
@@ -1165,6 +3431,108 @@ mod t {
                     assert!(mount.options.is_empty());
                 }
             }
+
+            #[test]
+            fn mountpoint() {
+                let args =
+                    vec!["bfffs", "fs", "mount", "-m", "/mnt", "testpool/foo"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                assert!(matches!(cli.cmd, SubCommand::Fs(FsCmd::Mount(_))));
+                if let SubCommand::Fs(FsCmd::Mount(mount)) = cli.cmd {
+                    assert_eq!(mount.name, "testpool/foo");
+                    assert_eq!(mount.mountpoint.as_deref(), Some("/mnt"));
+                }
+            }
+
+            #[test]
+            fn remount() {
+                let args =
+                    vec!["bfffs", "fs", "mount", "-o", "remount", "testpool"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                assert!(matches!(cli.cmd, SubCommand::Fs(FsCmd::Mount(_))));
+                if let SubCommand::Fs(FsCmd::Mount(mount)) = cli.cmd {
+                    assert_eq!(mount.name, "testpool");
+                    assert_eq!(mount.options, vec!["remount"]);
+                }
+            }
+        }
+
+        mod mounts {
+            use super::*;
+
+            #[test]
+            fn plain() {
+                let args = vec!["bfffs", "fs", "mounts"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                assert!(matches!(cli.cmd, SubCommand::Fs(FsCmd::Mounts(_))));
+                if let SubCommand::Fs(FsCmd::Mounts(mounts)) = cli.cmd {
+                    assert!(!mounts.parseable);
+                }
+            }
+        }
+
+        mod mv {
+            use super::*;
+
+            #[test]
+            fn plain() {
+                let args = vec![
+                    "bfffs", "fs", "mv", "mypool/a", "x/y", "mypool/b", "z",
+                ];
+                let cli = Cli::try_parse_from(args).unwrap();
+                assert!(matches!(cli.cmd, SubCommand::Fs(FsCmd::Mv(_))));
+                if let SubCommand::Fs(FsCmd::Mv(mv)) = cli.cmd {
+                    assert_eq!(mv.src_ds, "mypool/a");
+                    assert_eq!(mv.src_path, "x/y");
+                    assert_eq!(mv.dst_ds, "mypool/b");
+                    assert_eq!(mv.dst_path, "z");
+                }
+            }
+
+            #[test]
+            fn missing_dst() {
+                let args = vec!["bfffs", "fs", "mv", "mypool/a", "x/y"];
+                assert!(Cli::try_parse_from(args).is_err());
+            }
+        }
+
+        mod restore {
+            use super::*;
+
+            #[test]
+            fn plain() {
+                let args = vec![
+                    "bfffs",
+                    "fs",
+                    "restore",
+                    "testpool/foo",
+                    "/dir/file",
+                    "/tmp/file",
+                    "/dev/da0",
+                ];
+                let cli = Cli::try_parse_from(args).unwrap();
+                assert!(matches!(cli.cmd, SubCommand::Fs(FsCmd::Restore(_))));
+                if let SubCommand::Fs(FsCmd::Restore(restore)) = cli.cmd {
+                    assert_eq!(restore.name, "testpool/foo");
+                    assert_eq!(restore.path, PathBuf::from("/dir/file"));
+                    assert_eq!(restore.target, PathBuf::from("/tmp/file"));
+                    assert_eq!(restore.disks, vec![PathBuf::from("/dev/da0")]);
+                }
+            }
+
+            /// The disks are mandatory
+            #[test]
+            fn no_disks() {
+                let args = vec![
+                    "bfffs",
+                    "fs",
+                    "restore",
+                    "testpool/foo",
+                    "/dir/file",
+                    "/tmp/file",
+                ];
+                assert!(Cli::try_parse_from(args).is_err());
+            }
         }
 
         mod set {
@@ -1179,53 +3547,409 @@ mod t {
                     assert_eq!(&set.datasets[..], &["mypool"][..]);
                     assert_eq!(
                         &set.properties[..],
-                        &[Property::Atime(false)][..]
+                        &[SetProp::Native(Property::Atime(false))][..]
+                    );
+                }
+            }
+
+            #[test]
+            fn user() {
+                let args = vec![
+                    "bfffs",
+                    "fs",
+                    "set",
+                    "atime=off,user:backup-policy=daily",
+                    "mypool",
+                ];
+                let cli = Cli::try_parse_from(args).unwrap();
+                if let SubCommand::Fs(FsCmd::Set(set)) = cli.cmd {
+                    assert_eq!(&set.datasets[..], &["mypool"][..]);
+                    assert_eq!(
+                        &set.properties[..],
+                        &[
+                            SetProp::Native(Property::Atime(false)),
+                            SetProp::User(UserProperty {
+                                name:  "user:backup-policy".to_string(),
+                                value: "daily".to_string(),
+                            })
+                        ][..]
+                    );
+                } else {
+                    panic!("Wrong subcommand");
+                }
+            }
+        }
+
+        mod thaw {
+            use super::*;
+
+            #[test]
+            fn plain() {
+                let args = vec!["bfffs", "fs", "thaw", "testpool/foo"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                assert!(matches!(cli.cmd, SubCommand::Fs(FsCmd::Thaw(_))));
+                if let SubCommand::Fs(FsCmd::Thaw(thaw)) = cli.cmd {
+                    assert_eq!(thaw.name, "testpool/foo");
+                }
+            }
+        }
+
+        mod unjail {
+            use super::*;
+
+            #[test]
+            fn by_name() {
+                let args = vec!["bfffs", "fs", "unjail", "www", "testpool/foo"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                assert!(matches!(cli.cmd, SubCommand::Fs(FsCmd::Unjail(_))));
+                if let SubCommand::Fs(FsCmd::Unjail(unjail)) = cli.cmd {
+                    assert_eq!(unjail.jail, "www");
+                    assert_eq!(unjail.name, "testpool/foo");
+                }
+            }
+        }
+
+        mod unmount {
+            use super::*;
+
+            #[test]
+            fn all() {
+                let args = vec!["bfffs", "fs", "unmount", "-a", "testpool"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                assert!(matches!(cli.cmd, SubCommand::Fs(FsCmd::Unmount(_))));
+                if let SubCommand::Fs(FsCmd::Unmount(unmount)) = cli.cmd {
+                    assert_eq!(unmount.name, "testpool");
+                    assert!(unmount.all);
+                    assert!(!unmount.force);
+                }
+            }
+
+            #[test]
+            fn force() {
+                let args = vec!["bfffs", "fs", "unmount", "-f", "testpool"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                assert!(matches!(cli.cmd, SubCommand::Fs(FsCmd::Unmount(_))));
+                if let SubCommand::Fs(FsCmd::Unmount(unmount)) = cli.cmd {
+                    assert_eq!(unmount.name, "testpool");
+                    assert!(unmount.force);
+                }
+            }
+
+            #[test]
+            fn plain() {
+                let args = vec!["bfffs", "fs", "unmount", "testpool"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                assert!(matches!(cli.cmd, SubCommand::Fs(FsCmd::Unmount(_))));
+                if let SubCommand::Fs(FsCmd::Unmount(unmount)) = cli.cmd {
+                    assert_eq!(unmount.name, "testpool");
+                    assert!(!unmount.force);
+                }
+            }
+
+            #[test]
+            fn subfs() {
+                let args = vec!["bfffs", "fs", "unmount", "testpool/foo"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                assert!(matches!(cli.cmd, SubCommand::Fs(FsCmd::Unmount(_))));
+                if let SubCommand::Fs(FsCmd::Unmount(unmount)) = cli.cmd {
+                    assert_eq!(unmount.name, "testpool/foo");
+                    assert!(!unmount.force);
+                }
+            }
+        }
+
+        mod watch {
+            use super::*;
+
+            #[test]
+            fn plain() {
+                let args = vec!["bfffs", "fs", "watch", "testpool/foo"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                assert!(matches!(cli.cmd, SubCommand::Fs(FsCmd::Watch(_))));
+                if let SubCommand::Fs(FsCmd::Watch(watch)) = cli.cmd {
+                    assert_eq!(watch.name, "testpool/foo");
+                    assert!(!watch.sync);
+                }
+            }
+
+            #[test]
+            fn sync() {
+                let args = vec!["bfffs", "fs", "watch", "-s", "testpool/foo"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                assert!(matches!(cli.cmd, SubCommand::Fs(FsCmd::Watch(_))));
+                if let SubCommand::Fs(FsCmd::Watch(watch)) = cli.cmd {
+                    assert_eq!(watch.name, "testpool/foo");
+                    assert!(watch.sync);
+                }
+            }
+        }
+    }
+
+    mod job {
+        use bfffs::{JobKind, JobState, JobStatus};
+
+        use super::*;
+        use crate::job::JobCmd;
+
+        #[test]
+        fn list() {
+            let args = vec!["bfffs", "job", "list"];
+            let cli = Cli::try_parse_from(args).unwrap();
+            assert!(matches!(cli.cmd, SubCommand::Job(JobCmd::List(_))));
+        }
+
+        #[rstest]
+        #[case(0, 0, JobState::Running, "[          ]   0%")]
+        #[case(0, 1000, JobState::Running, "[          ]   0%")]
+        #[case(450, 1000, JobState::Running, "[#####     ]  45%")]
+        #[case(1200, 1000, JobState::Running, "[##########] 100%")]
+        #[case(0, 0, JobState::Done, "[##########] 100%")]
+        #[case(500, 1000, JobState::Failed(Error::EIO), "[#####     ]  50%")]
+        fn progress_bar(
+            #[case] processed: u64,
+            #[case] total: u64,
+            #[case] state: JobState,
+            #[case] expected: &str,
+        ) {
+            let status = JobStatus {
+                id: 0,
+                kind: JobKind::Clean,
+                target: "testpool".to_owned(),
+                processed,
+                total,
+                state,
+            };
+            assert_eq!(crate::job::progress_bar(&status, 10), expected);
+        }
+    }
+
+    mod pool {
+        use super::*;
+        use crate::pool::*;
+
+        mod add {
+            use super::*;
+
+            #[test]
+            fn mirror() {
+                let args = vec![
+                    "bfffs", "pool", "add", "testpool", "mirror", "/dev/da2",
+                    "/dev/da3",
+                ];
+                let cli = Cli::try_parse_from(args).unwrap();
+                if let SubCommand::Pool(PoolCmd::Add(add)) = cli.cmd {
+                    assert_eq!(add.pool_name, "testpool");
+                    assert_eq!(
+                        add.vdev,
+                        vec!["mirror", "/dev/da2", "/dev/da3"]
                     );
+                } else {
+                    panic!("Wrong subcommand");
+                }
+            }
+
+            #[test]
+            fn missing_vdev() {
+                let args = vec!["bfffs", "pool", "add", "testpool"];
+                let e = Cli::try_parse_from(args).unwrap_err();
+                assert_eq!(e.kind(), MissingRequiredArgument);
+            }
+
+            #[test]
+            fn parse_raid() {
+                let spec = "raid 3 1 /dev/da2 /dev/da3 /dev/da4"
+                    .split(' ')
+                    .map(str::to_owned)
+                    .collect::<Vec<_>>();
+                let (k, f, mirrors) =
+                    parse_cluster(&spec, |s| Ok(PathBuf::from(s))).unwrap();
+                assert_eq!(k, 3);
+                assert_eq!(f, 1);
+                assert_eq!(
+                    mirrors,
+                    vec![
+                        vec![PathBuf::from("/dev/da2")],
+                        vec![PathBuf::from("/dev/da3")],
+                        vec![PathBuf::from("/dev/da4")],
+                    ]
+                );
+            }
+        }
+
+        mod checkpoint {
+            use super::*;
+
+            #[test]
+            fn plain() {
+                let args = vec!["bfffs", "pool", "checkpoint", "testpool"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                assert!(matches!(
+                    cli.cmd,
+                    SubCommand::Pool(PoolCmd::Checkpoint(_))
+                ));
+                if let SubCommand::Pool(PoolCmd::Checkpoint(cp)) = cli.cmd {
+                    assert_eq!(cp.pool_name, "testpool");
+                    assert!(!cp.discard);
+                }
+            }
+
+            #[test]
+            fn discard() {
+                let args = vec![
+                    "bfffs",
+                    "pool",
+                    "checkpoint",
+                    "--discard",
+                    "testpool",
+                ];
+                let cli = Cli::try_parse_from(args).unwrap();
+                if let SubCommand::Pool(PoolCmd::Checkpoint(cp)) = cli.cmd {
+                    assert_eq!(cp.pool_name, "testpool");
+                    assert!(cp.discard);
+                } else {
+                    panic!("Wrong subcommand");
+                }
+            }
+        }
+
+        mod clean {
+            use super::*;
+
+            #[test]
+            fn plain() {
+                let args = vec!["bfffs", "pool", "clean", "testpool"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                if let SubCommand::Pool(PoolCmd::Clean(clean)) = cli.cmd {
+                    assert_eq!(clean.pool_name, "testpool");
+                    assert!(!clean.dry_run);
+                    assert!(!clean.verbose);
+                    assert!(!clean.wait);
+                    assert_eq!(clean.cold_age, None);
+                } else {
+                    panic!("Wrong subcommand");
+                }
+            }
+
+            #[test]
+            fn cold_age() {
+                let args = vec![
+                    "bfffs",
+                    "pool",
+                    "clean",
+                    "--cold-age",
+                    "100",
+                    "testpool",
+                ];
+                let cli = Cli::try_parse_from(args).unwrap();
+                if let SubCommand::Pool(PoolCmd::Clean(clean)) = cli.cmd {
+                    assert_eq!(clean.pool_name, "testpool");
+                    assert_eq!(clean.cold_age, Some(100));
+                } else {
+                    panic!("Wrong subcommand");
+                }
+            }
+
+            #[test]
+            fn wait() {
+                let args = vec!["bfffs", "pool", "clean", "-w", "testpool"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                if let SubCommand::Pool(PoolCmd::Clean(clean)) = cli.cmd {
+                    assert_eq!(clean.pool_name, "testpool");
+                    assert!(clean.wait);
+                } else {
+                    panic!("Wrong subcommand");
+                }
+            }
+
+            #[test]
+            fn dry_run_verbose() {
+                let args = vec![
+                    "bfffs",
+                    "pool",
+                    "clean",
+                    "--dry-run",
+                    "--verbose",
+                    "testpool",
+                ];
+                let cli = Cli::try_parse_from(args).unwrap();
+                if let SubCommand::Pool(PoolCmd::Clean(clean)) = cli.cmd {
+                    assert_eq!(clean.pool_name, "testpool");
+                    assert!(clean.dry_run);
+                    assert!(clean.verbose);
+                } else {
+                    panic!("Wrong subcommand");
+                }
+            }
+        }
+
+        mod defrag {
+            use super::*;
+
+            #[test]
+            fn plain() {
+                let args = vec!["bfffs", "pool", "defrag", "testpool"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                if let SubCommand::Pool(PoolCmd::Defrag(defrag)) = cli.cmd {
+                    assert_eq!(defrag.pool_name, "testpool");
+                    assert!(!defrag.dry_run);
+                    assert!(!defrag.wait);
+                } else {
+                    panic!("Wrong subcommand");
                 }
             }
-        }
 
-        mod unmount {
-            use super::*;
+            #[test]
+            fn dry_run() {
+                let args = vec!["bfffs", "pool", "defrag", "-n", "testpool"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                if let SubCommand::Pool(PoolCmd::Defrag(defrag)) = cli.cmd {
+                    assert_eq!(defrag.pool_name, "testpool");
+                    assert!(defrag.dry_run);
+                } else {
+                    panic!("Wrong subcommand");
+                }
+            }
 
             #[test]
-            fn force() {
-                let args = vec!["bfffs", "fs", "unmount", "-f", "testpool"];
+            fn wait() {
+                let args = vec!["bfffs", "pool", "defrag", "-w", "testpool"];
                 let cli = Cli::try_parse_from(args).unwrap();
-                assert!(matches!(cli.cmd, SubCommand::Fs(FsCmd::Unmount(_))));
-                if let SubCommand::Fs(FsCmd::Unmount(unmount)) = cli.cmd {
-                    assert_eq!(unmount.name, "testpool");
-                    assert!(unmount.force);
+                if let SubCommand::Pool(PoolCmd::Defrag(defrag)) = cli.cmd {
+                    assert_eq!(defrag.pool_name, "testpool");
+                    assert!(defrag.wait);
+                } else {
+                    panic!("Wrong subcommand");
                 }
             }
+        }
+
+        mod export {
+            use super::*;
 
             #[test]
             fn plain() {
-                let args = vec!["bfffs", "fs", "unmount", "testpool"];
+                let args = vec!["bfffs", "pool", "export", "testpool"];
                 let cli = Cli::try_parse_from(args).unwrap();
-                assert!(matches!(cli.cmd, SubCommand::Fs(FsCmd::Unmount(_))));
-                if let SubCommand::Fs(FsCmd::Unmount(unmount)) = cli.cmd {
-                    assert_eq!(unmount.name, "testpool");
-                    assert!(!unmount.force);
+                if let SubCommand::Pool(PoolCmd::Export(export)) = cli.cmd {
+                    assert_eq!(export.pool_name, "testpool");
+                    assert!(!export.force);
+                } else {
+                    panic!("Wrong subcommand");
                 }
             }
 
             #[test]
-            fn subfs() {
-                let args = vec!["bfffs", "fs", "unmount", "testpool/foo"];
+            fn force() {
+                let args = vec!["bfffs", "pool", "export", "-f", "testpool"];
                 let cli = Cli::try_parse_from(args).unwrap();
-                assert!(matches!(cli.cmd, SubCommand::Fs(FsCmd::Unmount(_))));
-                if let SubCommand::Fs(FsCmd::Unmount(unmount)) = cli.cmd {
-                    assert_eq!(unmount.name, "testpool/foo");
-                    assert!(!unmount.force);
+                if let SubCommand::Pool(PoolCmd::Export(export)) = cli.cmd {
+                    assert_eq!(export.pool_name, "testpool");
+                    assert!(export.force);
+                } else {
+                    panic!("Wrong subcommand");
                 }
             }
         }
-    }
-
-    mod pool {
-        use super::*;
-        use crate::pool::*;
 
         mod create {
             use super::*;
@@ -1336,6 +4060,7 @@ mod t {
                     assert_eq!(create.pool_name, "testpool");
                     assert!(create.properties.is_empty());
                     assert!(create.zone_size.is_none());
+                    assert!(create.write_mostly.is_empty());
                     assert_eq!(create.vdev[0], "/dev/da0");
                 }
             }
@@ -1384,6 +4109,407 @@ mod t {
                     assert_eq!(create.zone_size, Some(128));
                 }
             }
+
+            #[test]
+            fn write_mostly() {
+                let args = vec![
+                    "bfffs",
+                    "pool",
+                    "create",
+                    "--write-mostly",
+                    "/dev/da1",
+                    "testpool",
+                    "mirror",
+                    "/dev/da0",
+                    "/dev/da1",
+                ];
+                let cli = Cli::try_parse_from(args).unwrap();
+                assert!(matches!(
+                    cli.cmd,
+                    SubCommand::Pool(PoolCmd::Create(_))
+                ));
+                if let SubCommand::Pool(PoolCmd::Create(create)) = cli.cmd {
+                    assert_eq!(create.write_mostly, vec!["/dev/da1"]);
+                    assert_eq!(create.pool_name, "testpool");
+                    assert_eq!(
+                        create.vdev,
+                        vec!["mirror", "/dev/da0", "/dev/da1"]
+                    );
+                }
+            }
+        }
+
+        mod online {
+            use super::*;
+
+            #[test]
+            fn plain() {
+                let args =
+                    vec!["bfffs", "pool", "online", "testpool", "/dev/da1"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                if let SubCommand::Pool(PoolCmd::Online(online)) = cli.cmd {
+                    assert_eq!(online.pool_name, "testpool");
+                    assert_eq!(online.device, PathBuf::from("/dev/da1"));
+                } else {
+                    panic!("Wrong subcommand");
+                }
+            }
+
+            #[test]
+            fn missing_device() {
+                let args = vec!["bfffs", "pool", "online", "testpool"];
+                let e = Cli::try_parse_from(args).unwrap_err();
+                assert_eq!(e.kind(), MissingRequiredArgument);
+            }
+        }
+
+        mod plan {
+            use super::*;
+
+            const UUID: &str = "3b9b9f3e-5b09-4d4e-a5bf-0b5e3e0a6d22";
+
+            fn spec(s: &str) -> Vec<String> {
+                s.split(' ').map(str::to_owned).collect()
+            }
+
+            #[test]
+            fn add() {
+                let args =
+                    vec!["bfffs", "pool", "plan", "testpool", "add", "4T"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                if let SubCommand::Pool(PoolCmd::Plan(plan)) = cli.cmd {
+                    assert_eq!(plan.pool_name, "testpool");
+                    if let PlanCmd::Add { spec } = plan.change {
+                        assert_eq!(spec, vec!["4T"]);
+                    } else {
+                        panic!("Wrong change");
+                    }
+                } else {
+                    panic!("Wrong subcommand");
+                }
+            }
+
+            #[test]
+            fn add_missing_spec() {
+                let args = vec!["bfffs", "pool", "plan", "testpool", "add"];
+                let e = Cli::try_parse_from(args).unwrap_err();
+                assert_eq!(e.kind(), MissingRequiredArgument);
+            }
+
+            #[test]
+            fn remove() {
+                let args =
+                    vec!["bfffs", "pool", "plan", "testpool", "remove", UUID];
+                let cli = Cli::try_parse_from(args).unwrap();
+                if let SubCommand::Pool(PoolCmd::Plan(plan)) = cli.cmd {
+                    if let PlanCmd::Remove { disk } = plan.change {
+                        assert_eq!(disk, Uuid::parse_str(UUID).unwrap());
+                    } else {
+                        panic!("Wrong change");
+                    }
+                } else {
+                    panic!("Wrong subcommand");
+                }
+            }
+
+            #[test]
+            fn replace() {
+                let args = vec![
+                    "bfffs", "pool", "plan", "testpool", "replace", UUID, "8T",
+                ];
+                let cli = Cli::try_parse_from(args).unwrap();
+                if let SubCommand::Pool(PoolCmd::Plan(plan)) = cli.cmd {
+                    if let PlanCmd::Replace { disk, size } = plan.change {
+                        assert_eq!(disk, Uuid::parse_str(UUID).unwrap());
+                        assert_eq!(size, 8 << 40);
+                    } else {
+                        panic!("Wrong change");
+                    }
+                } else {
+                    panic!("Wrong subcommand");
+                }
+            }
+
+            #[test]
+            fn spec_disk() {
+                assert_eq!(
+                    parse_cluster_spec(&spec("1G")),
+                    Ok(Change::AddCluster {
+                        disks_per_stripe: 1,
+                        redundancy:       0,
+                        mirrors:          vec![vec![1 << 30]],
+                    })
+                );
+            }
+
+            #[test]
+            fn spec_invalid() {
+                assert!(parse_cluster_spec(&spec("1G 2G")).is_err());
+                assert!(parse_cluster_spec(&spec("mirror 1G 2X")).is_err());
+                assert!(parse_cluster_spec(&spec("raid 3 1G")).is_err());
+            }
+
+            #[test]
+            fn spec_mirror() {
+                assert_eq!(
+                    parse_cluster_spec(&spec("mirror 1G 2G")),
+                    Ok(Change::AddCluster {
+                        disks_per_stripe: 1,
+                        redundancy:       0,
+                        mirrors:          vec![vec![1 << 30, 2 << 30]],
+                    })
+                );
+            }
+
+            #[test]
+            fn spec_raid() {
+                assert_eq!(
+                    parse_cluster_spec(&spec("raid 3 1 1G 1G 1G")),
+                    Ok(Change::AddCluster {
+                        disks_per_stripe: 3,
+                        redundancy:       1,
+                        mirrors:          vec![vec![1 << 30]; 3],
+                    })
+                );
+            }
+
+            #[test]
+            fn spec_raid_of_mirrors() {
+                assert_eq!(
+                    parse_cluster_spec(&spec(
+                        "raid 2 1 mirror 1G 1G mirror 2G 2G"
+                    )),
+                    Ok(Change::AddCluster {
+                        disks_per_stripe: 2,
+                        redundancy:       1,
+                        mirrors:          vec![
+                            vec![1 << 30, 1 << 30],
+                            vec![2 << 30, 2 << 30],
+                        ],
+                    })
+                );
+            }
+        }
+
+        mod set {
+            use bfffs::FailMode;
+
+            use super::*;
+
+            #[test]
+            fn plain() {
+                let args = vec![
+                    "bfffs",
+                    "pool",
+                    "set",
+                    "failmode=wait,autotrim=on",
+                    "testpool",
+                ];
+                let cli = Cli::try_parse_from(args).unwrap();
+                assert!(matches!(cli.cmd, SubCommand::Pool(PoolCmd::Set(_))));
+                if let SubCommand::Pool(PoolCmd::Set(set)) = cli.cmd {
+                    assert_eq!(set.pool_name, "testpool");
+                    assert_eq!(
+                        set.properties,
+                        vec![
+                            PoolProperty::Failmode(FailMode::Wait),
+                            PoolProperty::Autotrim(true)
+                        ]
+                    );
+                }
+            }
+
+            #[test]
+            fn dataset_property() {
+                let args =
+                    vec!["bfffs", "pool", "set", "atime=off", "testpool"];
+                let e = Cli::try_parse_from(args).unwrap_err();
+                assert_eq!(e.kind(), ValueValidation);
+            }
+
+            #[test]
+            fn missing_pool() {
+                let args = vec!["bfffs", "pool", "set", "autotrim=on"];
+                let e = Cli::try_parse_from(args).unwrap_err();
+                assert_eq!(e.kind(), MissingRequiredArgument);
+            }
+        }
+
+        mod status {
+            use super::*;
+
+            #[test]
+            fn plain() {
+                let args = vec!["bfffs", "pool", "status", "testpool"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                assert!(matches!(
+                    cli.cmd,
+                    SubCommand::Pool(PoolCmd::Status(_))
+                ));
+                if let SubCommand::Pool(PoolCmd::Status(status)) = cli.cmd {
+                    assert_eq!(status.pool_name, "testpool");
+                    assert!(!status.verbose);
+                }
+            }
+
+            #[test]
+            fn verbose() {
+                let args = vec!["bfffs", "pool", "status", "-v", "testpool"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                assert!(matches!(
+                    cli.cmd,
+                    SubCommand::Pool(PoolCmd::Status(_))
+                ));
+                if let SubCommand::Pool(PoolCmd::Status(status)) = cli.cmd {
+                    assert_eq!(status.pool_name, "testpool");
+                    assert!(status.verbose);
+                }
+            }
+
+            #[test]
+            fn missing_pool() {
+                let args = vec!["bfffs", "pool", "status"];
+                let e = Cli::try_parse_from(args).unwrap_err();
+                assert_eq!(e.kind(), MissingRequiredArgument);
+            }
+        }
+
+        mod txgs {
+            use super::*;
+
+            #[test]
+            fn plain() {
+                let args = vec!["bfffs", "pool", "txgs", "testpool"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                assert!(matches!(cli.cmd, SubCommand::Pool(PoolCmd::Txgs(_))));
+                if let SubCommand::Pool(PoolCmd::Txgs(txgs)) = cli.cmd {
+                    assert_eq!(txgs.pool_name, "testpool");
+                }
+            }
+        }
+
+        mod upgrade {
+            use super::*;
+
+            #[test]
+            fn all() {
+                let args = vec!["bfffs", "pool", "upgrade", "testpool"];
+                let cli = Cli::try_parse_from(args).unwrap();
+                assert!(matches!(
+                    cli.cmd,
+                    SubCommand::Pool(PoolCmd::Upgrade(_))
+                ));
+                if let SubCommand::Pool(PoolCmd::Upgrade(upgrade)) = cli.cmd {
+                    assert_eq!(upgrade.pool_name, "testpool");
+                    assert!(upgrade.features.is_empty());
+                }
+            }
+
+            #[test]
+            fn features() {
+                let args = vec![
+                    "bfffs",
+                    "pool",
+                    "upgrade",
+                    "-f",
+                    "large_records",
+                    "testpool",
+                ];
+                let cli = Cli::try_parse_from(args).unwrap();
+                if let SubCommand::Pool(PoolCmd::Upgrade(upgrade)) = cli.cmd {
+                    assert_eq!(upgrade.pool_name, "testpool");
+                    assert_eq!(upgrade.features, vec![Feature::LargeRecords]);
+                } else {
+                    panic!("Wrong subcommand");
+                }
+            }
+
+            #[test]
+            fn unknown_feature() {
+                let args =
+                    vec!["bfffs", "pool", "upgrade", "-f", "bogus", "testpool"];
+                let e = Cli::try_parse_from(args).unwrap_err();
+                assert_eq!(e.kind(), ValueValidation);
+            }
+        }
+    }
+
+    mod volume {
+        use super::*;
+        use crate::volume::*;
+
+        mod create {
+            use super::*;
+
+            #[test]
+            fn plain() {
+                let args = vec![
+                    "bfffs",
+                    "volume",
+                    "create",
+                    "-s",
+                    "10G",
+                    "testpool/vol",
+                ];
+                let cli = Cli::try_parse_from(args).unwrap();
+                if let SubCommand::Volume(VolumeCmd::Create(create)) = cli.cmd {
+                    assert_eq!(create.name, "testpool/vol");
+                    assert_eq!(create.size, 10 << 30);
+                    assert!(create.properties.is_empty());
+                } else {
+                    panic!("Wrong subcommand");
+                }
+            }
+
+            #[test]
+            fn props() {
+                let args = vec![
+                    "bfffs",
+                    "volume",
+                    "create",
+                    "-s",
+                    "1048576",
+                    "-o",
+                    "recsize=4096,shareiscsi=0.0.0.0:3260",
+                    "testpool/vol",
+                ];
+                let cli = Cli::try_parse_from(args).unwrap();
+                if let SubCommand::Volume(VolumeCmd::Create(create)) = cli.cmd {
+                    assert_eq!(create.size, 1 << 20);
+                    assert_eq!(
+                        create.properties,
+                        vec!["recsize=4096", "shareiscsi=0.0.0.0:3260"]
+                    );
+                } else {
+                    panic!("Wrong subcommand");
+                }
+            }
+
+            #[test]
+            fn bad_size() {
+                let args = vec![
+                    "bfffs",
+                    "volume",
+                    "create",
+                    "-s",
+                    "10X",
+                    "testpool/vol",
+                ];
+                let e = Cli::try_parse_from(args).unwrap_err();
+                assert_eq!(e.kind(), ValueValidation);
+            }
+
+            #[test]
+            fn parse_sizes() {
+                assert_eq!(parse_size("512"), Ok(512));
+                assert_eq!(parse_size("4k"), Ok(4096));
+                assert_eq!(parse_size("3M"), Ok(3 << 20));
+                assert_eq!(parse_size("2T"), Ok(2 << 40));
+                assert!(parse_size("G").is_err());
+                assert!(parse_size("").is_err());
+                // Overflow
+                assert!(parse_size("99999999999T").is_err());
+            }
         }
     }
 }
@@ -0,0 +1,945 @@
+// vim: tw=80
+//! iSCSI frontend for BFFFS
+//!
+//! Exports volumes as iSCSI targets, for initiators that lack a native BFFFS
+//! client.  Each volume whose `shareiscsi` property is set is a separate
+//! target at that TCP address, with a single LUN, named like
+//! `iqn.2018-01.org.bfffs:mypool:vol` for the volume `mypool/vol`.  Initiators
+//! may find them with a SendTargets discovery session.
+//!
+//! This is a minimal target.  It supports no authentication, only error
+//! recovery level 0, only one connection per session, and no digests.  Each
+//! command is executed in order, before reading the next PDU.
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ffi::OsStr,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc,
+        Mutex,
+    },
+};
+
+use bfffs_core::{
+    controller::VOLUME_FILE,
+    fs::{FileData, FileDataMut},
+    vfs::Vfs,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    sync::watch,
+    task::JoinHandle,
+};
+use tracing::{error, warn};
+
+use super::p9::OpenFn;
+
+mod proto;
+mod scsi;
+#[cfg(test)]
+mod tests;
+
+use proto::*;
+use scsi::{Command, Sense, BLOCK_SIZE};
+
+/// Every target's name begins with this
+const IQN_PREFIX: &str = "iqn.2018-01.org.bfffs:";
+/// Largest data segment that we'll accept
+const MAX_RECV_DATA_SEGMENT_LENGTH: u32 = 1 << 18;
+/// Largest burst of solicited or unsolicited data that we'll negotiate
+const MAX_BURST_LENGTH: u32 = 1 << 18;
+/// MaxRecvDataSegmentLength's default, until the initiator declares its own
+const DEFAULT_RECV_DATA_SEGMENT_LENGTH: u32 = 8192;
+/// How many commands the initiator may queue beyond the one we expect next
+const CMD_WINDOW: u32 = 32;
+
+/// Login status classes and details
+const LOGIN_SUCCESS: u16 = 0x0000;
+const LOGIN_INITIATOR_ERROR: u16 = 0x0200;
+const LOGIN_AUTH_FAILURE: u16 = 0x0201;
+const LOGIN_NOT_FOUND: u16 = 0x0203;
+const LOGIN_UNSUPPORTED_VERSION: u16 = 0x0205;
+const LOGIN_SESSION_DOES_NOT_EXIST: u16 = 0x020a;
+const LOGIN_SERVICE_UNAVAILABLE: u16 = 0x0301;
+
+/// Login stages
+const SECURITY_NEGOTIATION: u8 = 0;
+const FULL_FEATURE_PHASE: u8 = 3;
+
+/// Task Management Functions
+const TMF_ABORT_TASK: u8 = 1;
+const TMF_TARGET_COLD_RESET: u8 = 7;
+
+/// Target Session Identifying Handles, unique for the life of the process
+static NEXT_TSIH: AtomicU16 = AtomicU16::new(1);
+
+/// Get a unique, stable serial number for a volume
+fn serial(name: &str) -> u64 {
+    // FNV-1a
+    name.bytes().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+        (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// The iSCSI name for a volume
+fn target_name(dataset: &str) -> String {
+    format!("{IQN_PREFIX}{}", dataset.replace('/', ":")).to_lowercase()
+}
+
+/// An open volume, shared by every session logged into it
+pub struct Export<V: Vfs> {
+    fs:      Arc<V>,
+    /// The volume's backing file
+    fd:      FileData,
+    nblocks: u64,
+    serial:  u64,
+}
+
+/// An open volume and its backing file's `FileDataMut`, which must outlive
+/// every copy of `Export::fd`.
+struct Held<V: Vfs> {
+    export:   Arc<Export<V>>,
+    fdm:      FileDataMut,
+    sessions: usize,
+}
+
+/// Every exported volume, across all addresses.
+struct Exports<V: Vfs> {
+    open:    OpenFn<V>,
+    exports: tokio::sync::Mutex<HashMap<String, Held<V>>>,
+}
+
+impl<V: Vfs> Exports<V> {
+    /// Get the named volume's export, opening the volume if no session is
+    /// currently logged into it.  Every call must be paired with a `put`.
+    async fn get(&self, name: &str) -> Result<Arc<Export<V>>, i32> {
+        let mut guard = self.exports.lock().await;
+        if let Some(held) = guard.get_mut(name) {
+            held.sessions += 1;
+            return Ok(held.export.clone());
+        }
+        let fs = (self.open)(name.to_owned()).await?;
+        let root = fs.root();
+        let r = fs.lookup(None, &root.handle(), OsStr::new(VOLUME_FILE)).await;
+        fs.inactive(root).await;
+        let fdm = r?;
+        let fd = fdm.handle();
+        let size = match fs.getattr(&fd).await {
+            Ok(attr) if attr.size >= u64::from(BLOCK_SIZE) => Ok(attr.size),
+            Ok(_) => Err(libc::ENXIO),
+            Err(e) => Err(e),
+        };
+        let size = match size {
+            Ok(size) => size,
+            Err(e) => {
+                fs.inactive(fdm).await;
+                return Err(e);
+            }
+        };
+        let export = Arc::new(Export {
+            fs,
+            fd,
+            nblocks: size / u64::from(BLOCK_SIZE),
+            serial: serial(name),
+        });
+        guard.insert(name.to_owned(), Held {
+            export: export.clone(),
+            fdm,
+            sessions: 1,
+        });
+        Ok(export)
+    }
+
+    /// Release a session's reference to a volume.  Once none remain, close it.
+    async fn put(&self, name: &str) {
+        let mut guard = self.exports.lock().await;
+        let held = guard.get_mut(name).expect("put of an unopened volume");
+        held.sessions -= 1;
+        if held.sessions == 0 {
+            let held = guard.remove(name).unwrap();
+            held.export.fs.inactive(held.fdm).await;
+        }
+    }
+}
+
+/// The volumes shared at a single address
+pub struct Share<V: Vfs> {
+    datasets: Mutex<BTreeSet<String>>,
+    exports:  Arc<Exports<V>>,
+}
+
+impl<V: Vfs> Share<V> {
+    /// Find the volume named by a login's TargetName.
+    fn lookup(&self, target: &str) -> Option<String> {
+        self.datasets
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|ds| target_name(ds).eq_ignore_ascii_case(target))
+            .cloned()
+    }
+}
+
+/// What to do with the data of a write-like command, once it's all arrived
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    Write { lba: u64 },
+    Unmap,
+}
+
+/// A command waiting for Data-Out PDUs
+struct Transfer {
+    op:       Op,
+    lun:      u64,
+    /// Bytes that the command needs
+    len:      u32,
+    /// Bytes by which the initiator's Expected Data Transfer Length exceeds
+    /// `len`
+    overflow: u32,
+    data:     Vec<u8>,
+    /// Target Transfer Tag of the outstanding R2T
+    ttt:      u32,
+    /// Number of R2Ts sent so far
+    r2tsn:    u32,
+}
+
+/// A single initiator connection
+pub struct Session<V: Vfs> {
+    share:          Arc<Share<V>>,
+    /// Our own address, to advertise in SendTargets responses
+    local_addr:     SocketAddr,
+    /// The volume that a normal session is logged into
+    export:         Option<(String, Arc<Export<V>>)>,
+    discovery:      bool,
+    /// Is the next login request the session's first?
+    leading:        bool,
+    /// Login text that the initiator continued into another PDU
+    login_text:     Vec<u8>,
+    full_feature:   bool,
+    closing:        bool,
+    tsih:           u16,
+    stat_sn:        u32,
+    exp_cmd_sn:     u32,
+    /// The initiator's MaxRecvDataSegmentLength
+    max_send_dsl:   u32,
+    max_burst:      u32,
+    first_burst:    u32,
+    immediate_data: bool,
+    transfers:      HashMap<u32, Transfer>,
+    next_ttt:       u32,
+    /// Sense data from the most recent failed command, for REQUEST SENSE
+    sense:          Sense,
+}
+
+impl<V: Vfs> Session<V> {
+    pub fn new(share: Arc<Share<V>>, local_addr: SocketAddr) -> Self {
+        Session {
+            share,
+            local_addr,
+            export: None,
+            discovery: false,
+            leading: true,
+            login_text: Vec::new(),
+            full_feature: false,
+            closing: false,
+            tsih: 0,
+            stat_sn: 0,
+            exp_cmd_sn: 0,
+            max_send_dsl: DEFAULT_RECV_DATA_SEGMENT_LENGTH,
+            max_burst: MAX_BURST_LENGTH,
+            first_burst: 1 << 16,
+            immediate_data: true,
+            transfers: HashMap::new(),
+            next_ttt: 0,
+            sense: Sense::NO_SENSE,
+        }
+    }
+
+    /// Finish a command with a CHECK CONDITION status
+    fn check_condition(&mut self, itt: u32, sense: Sense) -> Vec<Pdu> {
+        self.sense = sense;
+        let mut rsp = self.scsi_response(itt, scsi::CHECK_CONDITION, 0);
+        let sense = sense.to_bytes();
+        rsp.data = (sense.len() as u16).to_be_bytes().to_vec();
+        rsp.data.extend_from_slice(&sense);
+        vec![rsp]
+    }
+
+    /// Finish a command that returns data to the initiator
+    fn data_in(&mut self, pdu: &Pdu, mut data: Vec<u8>) -> Vec<Pdu> {
+        let edtl = pdu.u32_at(20) as usize;
+        let residual = edtl as i64 - data.len() as i64;
+        data.truncate(edtl);
+        let chunks = data.chunks(self.max_send_dsl as usize);
+        let nchunks = chunks.len();
+        let mut pdus = Vec::with_capacity(nchunks + 1);
+        let mut offset = 0;
+        for (datasn, chunk) in chunks.enumerate() {
+            let mut din = Pdu::new(OP_DATA_IN);
+            if datasn + 1 < nchunks {
+                din.bhs[1] = 0;
+            }
+            din.set_u64(8, pdu.lun());
+            din.set_u32(16, pdu.itt());
+            din.set_u32(20, RESERVED_TAG);
+            self.set_cmd_sn(&mut din);
+            din.set_u32(36, datasn as u32);
+            din.set_u32(40, offset);
+            din.data = chunk.to_vec();
+            offset += chunk.len() as u32;
+            pdus.push(din);
+        }
+        let mut rsp = self.scsi_response(pdu.itt(), scsi::GOOD, residual);
+        rsp.set_u32(36, nchunks as u32);
+        pdus.push(rsp);
+        pdus
+    }
+
+    /// Decide what to do with one key in a login request's operational
+    /// parameter negotiation.  Returns the value to answer with, if any.
+    fn negotiate(&mut self, key: &str, value: &str) -> Option<String> {
+        let number = || value.parse::<u32>().ok();
+        let answer = match key {
+            "InitiatorName" | "InitiatorAlias" | "SessionType"
+            | "TargetName" | "AuthMethod" => return None,
+            "HeaderDigest" | "DataDigest" => "None".to_owned(),
+            "MaxConnections" | "MaxOutstandingR2T" => "1".to_owned(),
+            "ErrorRecoveryLevel" | "DefaultTime2Retain" => "0".to_owned(),
+            "InitialR2T" | "DataPDUInOrder" | "DataSequenceInOrder" => {
+                "Yes".to_owned()
+            }
+            "IFMarker" | "OFMarker" => "No".to_owned(),
+            "ImmediateData" => {
+                self.immediate_data = value == "Yes";
+                value.to_owned()
+            }
+            "MaxRecvDataSegmentLength" => match number() {
+                Some(n) => {
+                    self.max_send_dsl = n.clamp(512, (1 << 24) - 1);
+                    MAX_RECV_DATA_SEGMENT_LENGTH.to_string()
+                }
+                None => "Reject".to_owned(),
+            },
+            "MaxBurstLength" => match number() {
+                Some(n) => {
+                    self.max_burst = n.clamp(512, MAX_BURST_LENGTH);
+                    self.max_burst.to_string()
+                }
+                None => "Reject".to_owned(),
+            },
+            "FirstBurstLength" => match number() {
+                Some(n) => {
+                    self.first_burst = n.clamp(512, MAX_BURST_LENGTH);
+                    self.first_burst.to_string()
+                }
+                None => "Reject".to_owned(),
+            },
+            "DefaultTime2Wait" => value.to_owned(),
+            _ => "NotUnderstood".to_owned(),
+        };
+        Some(answer)
+    }
+
+    /// Send the next R2T for a transfer
+    fn r2t(&mut self, itt: u32) -> Pdu {
+        let mut r2t = Pdu::new(OP_R2T);
+        let t = self.transfers.get_mut(&itt).unwrap();
+        let offset = t.data.len() as u32;
+        r2t.set_u64(8, t.lun);
+        r2t.set_u32(16, itt);
+        r2t.set_u32(20, t.ttt);
+        r2t.set_u32(36, t.r2tsn);
+        r2t.set_u32(40, offset);
+        r2t.set_u32(44, (t.len - offset).min(self.max_burst));
+        t.r2tsn += 1;
+        r2t.set_u32(24, self.stat_sn);
+        self.set_cmd_sn(&mut r2t);
+        r2t
+    }
+
+    async fn data_out(&mut self, pdu: Pdu) -> Vec<Pdu> {
+        let itt = pdu.itt();
+        let t = match self.transfers.get_mut(&itt) {
+            Some(t) => t,
+            // Probably a task that was aborted
+            None => return Vec::new(),
+        };
+        if pdu.u32_at(20) != t.ttt || pdu.u32_at(40) as usize != t.data.len()
+        {
+            return self.reject(&pdu, REJECT_PROTOCOL_ERROR);
+        }
+        let room = (t.len as usize - t.data.len()).min(pdu.data.len());
+        t.data.extend_from_slice(&pdu.data[..room]);
+        if !pdu.is_final() {
+            Vec::new()
+        } else if t.data.len() < t.len as usize {
+            vec![self.r2t(itt)]
+        } else {
+            let t = self.transfers.remove(&itt).unwrap();
+            self.finish_transfer(itt, t).await
+        }
+    }
+
+    /// Execute a write-like command, now that all of its data has arrived
+    async fn finish_transfer(&mut self, itt: u32, t: Transfer) -> Vec<Pdu> {
+        let export = self.export.as_ref().unwrap().1.clone();
+        let bs = u64::from(BLOCK_SIZE);
+        let r = match t.op {
+            Op::Write { lba } => export
+                .fs
+                .write(&export.fd, lba * bs, &t.data, 0)
+                .await
+                .map(drop)
+                .map_err(|e| Sense::from_errno(e, true)),
+            Op::Unmap => {
+                match scsi::unmap_descriptors(&t.data, export.nblocks) {
+                    Ok(descs) => {
+                        let mut r = Ok(());
+                        for (lba, blocks) in descs.into_iter() {
+                            let len = u64::from(blocks) * bs;
+                            r = export
+                                .fs
+                                .deallocate(&export.fd, lba * bs, len)
+                                .await
+                                .map_err(|e| Sense::from_errno(e, true));
+                            if r.is_err() {
+                                break;
+                            }
+                        }
+                        r
+                    }
+                    Err(sense) => Err(sense),
+                }
+            }
+        };
+        match r {
+            Ok(()) => {
+                let residual = -i64::from(t.overflow);
+                vec![self.scsi_response(itt, scsi::GOOD, residual)]
+            }
+            Err(sense) => self.check_condition(itt, sense),
+        }
+    }
+
+    async fn login(&mut self, pdu: Pdu) -> Vec<Pdu> {
+        let mut rsp = Pdu::new(OP_LOGIN_RSP);
+        rsp.bhs[1] = pdu.flags() & !LOGIN_C;
+        rsp.bhs[8..16].copy_from_slice(&pdu.bhs[8..16]);
+        rsp.set_u32(16, pdu.itt());
+        self.exp_cmd_sn = pdu.u32_at(24);
+        if self.full_feature {
+            return self.reject(&pdu, REJECT_PROTOCOL_ERROR);
+        }
+
+        let status = self.login_status(&pdu, &mut rsp).await;
+        if status != LOGIN_SUCCESS {
+            rsp.bhs[1] = 0;
+            rsp.data.clear();
+            self.closing = true;
+        }
+        rsp.set_u16(14, self.tsih);
+        rsp.set_u16(36, status);
+        self.status(&mut rsp);
+        vec![rsp]
+    }
+
+    /// Process a login request's text and stage transition, filling in `rsp`.
+    /// Returns the login status.
+    async fn login_status(&mut self, pdu: &Pdu, rsp: &mut Pdu) -> u16 {
+        let flags = pdu.flags();
+        let csg = (flags >> 2) & 3;
+        let mut nsg = flags & 3;
+        let transit = flags & LOGIN_T != 0;
+        // Version-min
+        if pdu.bhs[3] > 0 {
+            return LOGIN_UNSUPPORTED_VERSION;
+        }
+        // We don't support adding connections to existing sessions
+        if pdu.u16_at(14) != 0 {
+            return LOGIN_SESSION_DOES_NOT_EXIST;
+        }
+        self.login_text.extend_from_slice(&pdu.data);
+        if flags & LOGIN_C != 0 {
+            // Wait for the rest of the text
+            rsp.bhs[1] = flags & 0x0c;
+            return LOGIN_SUCCESS;
+        }
+        let text = std::mem::take(&mut self.login_text);
+        let pairs = match decode_text(&text) {
+            Some(pairs) => pairs,
+            None => return LOGIN_INITIATOR_ERROR,
+        };
+        let get = |key: &str| {
+            pairs
+                .iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v.as_str())
+        };
+        let mut answers = Vec::<(String, String)>::new();
+        if self.leading {
+            self.leading = false;
+            if get("InitiatorName").is_none() {
+                return LOGIN_INITIATOR_ERROR;
+            }
+            self.discovery = get("SessionType") == Some("Discovery");
+            if !self.discovery {
+                let target = get("TargetName");
+                let name = match target.and_then(|t| self.share.lookup(t)) {
+                    Some(name) => name,
+                    None => return LOGIN_NOT_FOUND,
+                };
+                match self.share.exports.get(&name).await {
+                    Ok(export) => self.export = Some((name, export)),
+                    Err(e) => {
+                        warn!("Cannot open volume {name}: {e}");
+                        return LOGIN_SERVICE_UNAVAILABLE;
+                    }
+                }
+                answers
+                    .push(("TargetPortalGroupTag".to_owned(), "1".to_owned()));
+            }
+        }
+        if let Some(methods) = get("AuthMethod") {
+            if csg != SECURITY_NEGOTIATION {
+                return LOGIN_INITIATOR_ERROR;
+            }
+            if !methods.split(',').any(|m| m == "None") {
+                return LOGIN_AUTH_FAILURE;
+            }
+            answers.push(("AuthMethod".to_owned(), "None".to_owned()));
+        }
+        for (k, v) in pairs.iter() {
+            if let Some(answer) = self.negotiate(k, v) {
+                answers.push((k.clone(), answer));
+            }
+        }
+        if csg == FULL_FEATURE_PHASE || (transit && (nsg <= csg || nsg == 2)) {
+            return LOGIN_INITIATOR_ERROR;
+        }
+        if !transit {
+            nsg = 0;
+        }
+        rsp.bhs[1] = flags & (LOGIN_T | 0x0c) | nsg;
+        rsp.data = encode_text(&answers);
+        if transit && nsg == FULL_FEATURE_PHASE {
+            self.tsih = loop {
+                let tsih = NEXT_TSIH.fetch_add(1, Ordering::Relaxed);
+                if tsih != 0 {
+                    break tsih;
+                }
+            };
+            self.full_feature = true;
+        }
+        LOGIN_SUCCESS
+    }
+
+    fn logout(&mut self, pdu: &Pdu) -> Vec<Pdu> {
+        let mut rsp = Pdu::new(OP_LOGOUT_RSP);
+        rsp.set_u32(16, pdu.itt());
+        self.status(&mut rsp);
+        self.closing = true;
+        vec![rsp]
+    }
+
+    fn nop(&mut self, pdu: Pdu) -> Vec<Pdu> {
+        if pdu.itt() == RESERVED_TAG {
+            // A reply to a NOP-In of ours, but we never send any.
+            return Vec::new();
+        }
+        let mut rsp = Pdu::new(OP_NOP_IN);
+        rsp.set_u64(8, pdu.lun());
+        rsp.set_u32(16, pdu.itt());
+        rsp.set_u32(20, RESERVED_TAG);
+        self.status(&mut rsp);
+        rsp.data = pdu.data;
+        vec![rsp]
+    }
+
+    /// Handle one PDU, returning the responses to send.
+    pub async fn process(&mut self, pdu: Pdu) -> Vec<Pdu> {
+        if !pdu.immediate() &&
+            pdu.opcode() != OP_DATA_OUT &&
+            pdu.opcode() != OP_LOGIN_REQ
+        {
+            self.exp_cmd_sn = pdu.u32_at(24).wrapping_add(1);
+        }
+        if !self.full_feature && pdu.opcode() != OP_LOGIN_REQ {
+            self.closing = true;
+            return self.reject(&pdu, REJECT_PROTOCOL_ERROR);
+        }
+        match pdu.opcode() {
+            OP_LOGIN_REQ => self.login(pdu).await,
+            OP_NOP_OUT => self.nop(pdu),
+            OP_SCSI_CMD => self.scsi_command(pdu).await,
+            OP_DATA_OUT => self.data_out(pdu).await,
+            OP_TASK_MGMT_REQ => self.task_mgmt(&pdu),
+            OP_TEXT_REQ => self.text(&pdu),
+            OP_LOGOUT_REQ => self.logout(&pdu),
+            _ => self.reject(&pdu, REJECT_NOT_SUPPORTED),
+        }
+    }
+
+    fn reject(&mut self, pdu: &Pdu, reason: u8) -> Vec<Pdu> {
+        let mut rsp = Pdu::new(OP_REJECT);
+        rsp.bhs[2] = reason;
+        rsp.set_u32(16, RESERVED_TAG);
+        self.status(&mut rsp);
+        rsp.data = pdu.bhs.to_vec();
+        vec![rsp]
+    }
+
+    /// Serve requests until the initiator logs out or disconnects, or the
+    /// share is stopped
+    pub async fn run<S>(mut self, stream: S, mut shutdown: watch::Receiver<()>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (mut rd, mut wr) = tokio::io::split(stream);
+        let max = MAX_RECV_DATA_SEGMENT_LENGTH as usize;
+        'session: while !self.closing {
+            let r = tokio::select! {
+                r = read_pdu(&mut rd, max) => r,
+                _ = shutdown.changed() => break,
+            };
+            let pdu = match r {
+                Ok(Some(pdu)) => pdu,
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Error receiving iSCSI PDU: {e}");
+                    break;
+                }
+            };
+            for rsp in self.process(pdu).await.into_iter() {
+                if let Err(e) = wr.write_all(&rsp.encode()).await {
+                    warn!("Error sending iSCSI PDU: {e}");
+                    break 'session;
+                }
+            }
+        }
+        if let Some((name, _export)) = self.export.take() {
+            self.share.exports.put(&name).await;
+        }
+    }
+
+    async fn scsi_command(&mut self, pdu: Pdu) -> Vec<Pdu> {
+        let export = match self.export.as_ref() {
+            Some((_, export)) => export.clone(),
+            // Discovery sessions may not issue SCSI commands
+            None => return self.reject(&pdu, REJECT_PROTOCOL_ERROR),
+        };
+        let cmd = match Command::parse(&pdu.bhs[32..48]) {
+            Ok(cmd) => cmd,
+            Err(sense) => return self.check_condition(pdu.itt(), sense),
+        };
+        let lun0 = pdu.lun() == 0;
+        if !lun0 &&
+            !matches!(
+                cmd,
+                Command::Inquiry { .. } |
+                    Command::ReportLuns { .. } |
+                    Command::RequestSense { .. }
+            )
+        {
+            return self.check_condition(pdu.itt(), Sense::LUN_NOT_SUPPORTED);
+        }
+        let bs = u64::from(BLOCK_SIZE);
+        let r = match cmd {
+            Command::TestUnitReady => Ok(Vec::new()),
+            Command::RequestSense { alloc } => {
+                let sense = std::mem::replace(&mut self.sense, Sense::NO_SENSE);
+                Ok(scsi::truncate(sense.to_bytes(), alloc))
+            }
+            Command::Inquiry { evpd, page, alloc } => {
+                scsi::inquiry(evpd, page, export.serial, lun0)
+                    .map(|data| scsi::truncate(data, alloc))
+            }
+            Command::ModeSense {
+                ten,
+                dbd,
+                page,
+                alloc,
+            } => scsi::mode_sense(ten, dbd, page, export.nblocks)
+                .map(|data| scsi::truncate(data, alloc)),
+            Command::ReadCapacity10 => {
+                Ok(scsi::read_capacity10(export.nblocks))
+            }
+            Command::ReadCapacity16 { alloc } => Ok(scsi::truncate(
+                scsi::read_capacity16(export.nblocks),
+                alloc,
+            )),
+            Command::ReportLuns { alloc } => {
+                Ok(scsi::truncate(scsi::report_luns(), alloc))
+            }
+            Command::SynchronizeCache => export
+                .fs
+                .fsync(&export.fd)
+                .await
+                .map(|_| Vec::new())
+                .map_err(|e| Sense::from_errno(e, true)),
+            Command::Read { lba, blocks } => {
+                match scsi::check_range(lba, blocks, export.nblocks) {
+                    Ok(()) => {
+                        let len = blocks as usize * BLOCK_SIZE as usize;
+                        export
+                            .fs
+                            .read(&export.fd, lba * bs, len)
+                            .await
+                            .map(|sglist| {
+                                let mut data = Vec::with_capacity(len);
+                                for iovec in sglist.iter() {
+                                    data.extend_from_slice(&iovec[..]);
+                                }
+                                // Reads beyond EOF return short, but the
+                                // volume's size never changes.
+                                data.resize(len, 0);
+                                data
+                            })
+                            .map_err(|e| Sense::from_errno(e, false))
+                    }
+                    Err(sense) => Err(sense),
+                }
+            }
+            Command::Write { lba, blocks } => {
+                if let Err(sense) =
+                    scsi::check_range(lba, blocks, export.nblocks)
+                {
+                    return self.check_condition(pdu.itt(), sense);
+                }
+                let op = Op::Write { lba };
+                return self.start_transfer(pdu, op, blocks * BLOCK_SIZE).await;
+            }
+            Command::Unmap { len } => {
+                return self.start_transfer(pdu, Op::Unmap, len).await;
+            }
+        };
+        match r {
+            Ok(data) => self.data_in(&pdu, data),
+            Err(sense) => self.check_condition(pdu.itt(), sense),
+        }
+    }
+
+    fn scsi_response(&mut self, itt: u32, status: u8, residual: i64) -> Pdu {
+        let mut rsp = Pdu::new(OP_SCSI_RSP);
+        if residual > 0 {
+            rsp.bhs[1] |= RSP_UNDERFLOW;
+        } else if residual < 0 {
+            rsp.bhs[1] |= RSP_OVERFLOW;
+        }
+        rsp.bhs[3] = status;
+        rsp.set_u32(16, itt);
+        self.status(&mut rsp);
+        rsp.set_u32(44, residual.unsigned_abs() as u32);
+        rsp
+    }
+
+    /// Fill in a PDU's ExpCmdSN and MaxCmdSN
+    fn set_cmd_sn(&self, pdu: &mut Pdu) {
+        pdu.set_u32(28, self.exp_cmd_sn);
+        pdu.set_u32(32, self.exp_cmd_sn.wrapping_add(CMD_WINDOW));
+    }
+
+    /// Begin a command that needs `len` bytes of data from the initiator.
+    async fn start_transfer(&mut self, pdu: Pdu, op: Op, len: u32) -> Vec<Pdu> {
+        let itt = pdu.itt();
+        let edtl = pdu.u32_at(20);
+        if (pdu.flags() & CMD_WRITE == 0 && len > 0) || edtl < len {
+            return self.check_condition(pdu.itt(), Sense::INVALID_FIELD_IN_CDB);
+        }
+        if len == 0 {
+            let residual = -i64::from(edtl);
+            return vec![self.scsi_response(itt, scsi::GOOD, residual)];
+        }
+        let mut data = Vec::with_capacity(len as usize);
+        if self.immediate_data {
+            let n = pdu.data.len().min(len.min(self.first_burst) as usize);
+            data.extend_from_slice(&pdu.data[..n]);
+        }
+        let ttt = self.next_ttt;
+        self.next_ttt = self.next_ttt.wrapping_add(1) % RESERVED_TAG;
+        let t = Transfer {
+            op,
+            lun: pdu.lun(),
+            len,
+            overflow: edtl - len,
+            data,
+            ttt,
+            r2tsn: 0,
+        };
+        if t.data.len() == len as usize {
+            self.finish_transfer(itt, t).await
+        } else {
+            self.transfers.insert(itt, t);
+            vec![self.r2t(itt)]
+        }
+    }
+
+    /// Fill in a PDU's StatSN, ExpCmdSN, and MaxCmdSN, consuming a StatSN
+    fn status(&mut self, pdu: &mut Pdu) {
+        pdu.set_u32(24, self.stat_sn);
+        self.stat_sn = self.stat_sn.wrapping_add(1);
+        self.set_cmd_sn(pdu);
+    }
+
+    fn task_mgmt(&mut self, pdu: &Pdu) -> Vec<Pdu> {
+        // Every command has already finished, except for those still waiting
+        // for data.
+        let response = match pdu.flags() & 0x7f {
+            TMF_ABORT_TASK => {
+                self.transfers.remove(&pdu.u32_at(20));
+                TMF_COMPLETE
+            }
+            // Abort task set, clear task set, LUN reset, target warm reset
+            2 | 4..=6 => {
+                self.transfers.clear();
+                TMF_COMPLETE
+            }
+            TMF_TARGET_COLD_RESET => {
+                self.transfers.clear();
+                self.closing = true;
+                TMF_COMPLETE
+            }
+            _ => TMF_NOT_SUPPORTED,
+        };
+        let mut rsp = Pdu::new(OP_TASK_MGMT_RSP);
+        rsp.bhs[2] = response;
+        rsp.set_u32(16, pdu.itt());
+        self.status(&mut rsp);
+        vec![rsp]
+    }
+
+    fn text(&mut self, pdu: &Pdu) -> Vec<Pdu> {
+        let pairs = match decode_text(&pdu.data) {
+            Some(pairs) => pairs,
+            None => return self.reject(pdu, REJECT_INVALID_FIELD),
+        };
+        let mut answers = Vec::<(String, String)>::new();
+        for (k, v) in pairs.into_iter() {
+            if k != "SendTargets" {
+                answers.push((k, "NotUnderstood".to_owned()));
+                continue;
+            }
+            let datasets = match (v.as_str(), &self.export) {
+                ("All", _) if self.discovery => {
+                    self.share.datasets.lock().unwrap().clone()
+                }
+                ("", Some((name, _))) => BTreeSet::from([name.clone()]),
+                ("", None) => BTreeSet::new(),
+                (t, _) => self.share.lookup(t).into_iter().collect(),
+            };
+            for ds in datasets.into_iter() {
+                answers.push(("TargetName".to_owned(), target_name(&ds)));
+                answers.push((
+                    "TargetAddress".to_owned(),
+                    format!("{},1", self.local_addr),
+                ));
+            }
+        }
+        let mut rsp = Pdu::new(OP_TEXT_RSP);
+        rsp.set_u32(16, pdu.itt());
+        rsp.set_u32(20, RESERVED_TAG);
+        self.status(&mut rsp);
+        rsp.data = encode_text(&answers);
+        vec![rsp]
+    }
+}
+
+/// A TCP listener for one address
+struct Listener<V: Vfs> {
+    share:     Arc<Share<V>>,
+    task:      JoinHandle<()>,
+    /// Dropping this tells the listener's sessions to shut down
+    _shutdown: watch::Sender<()>,
+}
+
+impl<V: Vfs> Drop for Listener<V> {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Serves every volume that has the `shareiscsi` property set
+pub struct Server<V: Vfs> {
+    exports:   Arc<Exports<V>>,
+    listeners: tokio::sync::Mutex<HashMap<SocketAddr, Listener<V>>>,
+}
+
+impl<V: Vfs> Server<V> {
+    async fn listen(
+        listener: TcpListener,
+        share: Arc<Share<V>>,
+        shutdown: watch::Receiver<()>,
+    ) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _peer)) => {
+                    let _ignore = stream.set_nodelay(true);
+                    let local_addr = match stream.local_addr() {
+                        Ok(addr) => addr,
+                        Err(e) => {
+                            error!("Cannot get iSCSI local address: {e}");
+                            continue;
+                        }
+                    };
+                    let session = Session::new(share.clone(), local_addr);
+                    tokio::spawn(session.run(stream, shutdown.clone()));
+                }
+                Err(e) => error!("Error accepting iSCSI connection: {e}"),
+            }
+        }
+    }
+
+    pub fn new(open: OpenFn<V>) -> Self {
+        let exports = Arc::new(Exports {
+            open,
+            exports: Default::default(),
+        });
+        Server {
+            exports,
+            listeners: Default::default(),
+        }
+    }
+
+    /// Share exactly the given volumes, at the given addresses.
+    ///
+    /// Listeners that are no longer needed are stopped, along with their
+    /// sessions.  Sessions on a listener that remains stay logged in, even if
+    /// their volume is no longer shared there.
+    pub async fn reshare(
+        &self,
+        shares: BTreeMap<SocketAddr, BTreeSet<String>>,
+    ) -> io::Result<()> {
+        let mut listeners = self.listeners.lock().await;
+        listeners.retain(|addr, _| shares.contains_key(addr));
+        let mut r = Ok(());
+        for (addr, datasets) in shares.into_iter() {
+            if let Some(listener) = listeners.get(&addr) {
+                *listener.share.datasets.lock().unwrap() = datasets;
+                continue;
+            }
+            let tcp = match TcpListener::bind(addr).await {
+                Ok(tcp) => tcp,
+                Err(e) => {
+                    error!("Cannot listen for iSCSI on {addr}: {e}");
+                    r = Err(e);
+                    continue;
+                }
+            };
+            let share = Arc::new(Share {
+                datasets: Mutex::new(datasets),
+                exports:  self.exports.clone(),
+            });
+            let (tx, rx) = watch::channel(());
+            let task = tokio::spawn(Self::listen(tcp, share.clone(), rx));
+            listeners.insert(addr, Listener {
+                share,
+                task,
+                _shutdown: tx,
+            });
+        }
+        r
+    }
+}
@@ -0,0 +1,214 @@
+// vim: tw=80
+//! iSCSI wire format
+//!
+//! Every PDU begins with a 48-byte Basic Header Segment, followed by any
+//! Additional Header Segments and a data segment padded to a 4-byte boundary.
+//! All integers are big-endian.  See RFC 7143 for the meaning of each field.
+//! We never negotiate header or data digests, so PDUs never include them.
+
+use std::io;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Size of the Basic Header Segment
+pub const BHS_SIZE: usize = 48;
+
+/// Opcodes sent by the initiator
+pub const OP_NOP_OUT: u8 = 0x00;
+pub const OP_SCSI_CMD: u8 = 0x01;
+pub const OP_TASK_MGMT_REQ: u8 = 0x02;
+pub const OP_LOGIN_REQ: u8 = 0x03;
+pub const OP_TEXT_REQ: u8 = 0x04;
+pub const OP_DATA_OUT: u8 = 0x05;
+pub const OP_LOGOUT_REQ: u8 = 0x06;
+
+/// Opcodes sent by the target
+pub const OP_NOP_IN: u8 = 0x20;
+pub const OP_SCSI_RSP: u8 = 0x21;
+pub const OP_TASK_MGMT_RSP: u8 = 0x22;
+pub const OP_LOGIN_RSP: u8 = 0x23;
+pub const OP_TEXT_RSP: u8 = 0x24;
+pub const OP_DATA_IN: u8 = 0x25;
+pub const OP_LOGOUT_RSP: u8 = 0x26;
+pub const OP_R2T: u8 = 0x31;
+pub const OP_REJECT: u8 = 0x3f;
+
+/// The immediate delivery bit, in the opcode byte
+const IMMEDIATE: u8 = 0x40;
+/// The final bit, in the flags byte
+pub const F_BIT: u8 = 0x80;
+
+/// Login flags: transit to the next stage
+pub const LOGIN_T: u8 = 0x80;
+/// Login flags: the text continues in the next PDU
+pub const LOGIN_C: u8 = 0x40;
+
+/// SCSI Command flags: the command will send data to the target
+pub const CMD_WRITE: u8 = 0x20;
+
+/// SCSI Response flags: residual overflow and underflow
+pub const RSP_OVERFLOW: u8 = 0x04;
+pub const RSP_UNDERFLOW: u8 = 0x02;
+
+/// Reject reasons
+pub const REJECT_PROTOCOL_ERROR: u8 = 0x04;
+pub const REJECT_NOT_SUPPORTED: u8 = 0x05;
+pub const REJECT_INVALID_FIELD: u8 = 0x09;
+
+/// Task Management Function Response codes
+pub const TMF_COMPLETE: u8 = 0;
+pub const TMF_NOT_SUPPORTED: u8 = 5;
+
+/// Initiator and Target Task Tags use this when there is no task
+pub const RESERVED_TAG: u32 = 0xffff_ffff;
+
+/// A single Protocol Data Unit.
+///
+/// Fields are accessed by their byte offset in the BHS.  The DataSegmentLength
+/// field is filled in when the PDU is encoded.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Pdu {
+    pub bhs:  [u8; BHS_SIZE],
+    pub data: Vec<u8>,
+}
+
+impl Pdu {
+    /// Create a PDU with the given opcode and the final bit set
+    pub fn new(opcode: u8) -> Self {
+        let mut bhs = [0u8; BHS_SIZE];
+        bhs[0] = opcode;
+        bhs[1] = F_BIT;
+        Pdu {
+            bhs,
+            data: Vec::new(),
+        }
+    }
+
+    /// Encode the PDU for transmission
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(BHS_SIZE + padded(self.data.len()));
+        buf.extend_from_slice(&self.bhs);
+        // We never send Additional Header Segments
+        buf[4] = 0;
+        let dsl = (self.data.len() as u32).to_be_bytes();
+        buf[5..8].copy_from_slice(&dsl[1..]);
+        buf.extend_from_slice(&self.data);
+        buf.resize(BHS_SIZE + padded(self.data.len()), 0);
+        buf
+    }
+
+    pub fn flags(&self) -> u8 {
+        self.bhs[1]
+    }
+
+    pub fn immediate(&self) -> bool {
+        self.bhs[0] & IMMEDIATE != 0
+    }
+
+    pub fn is_final(&self) -> bool {
+        self.bhs[1] & F_BIT != 0
+    }
+
+    /// Initiator Task Tag
+    pub fn itt(&self) -> u32 {
+        self.u32_at(16)
+    }
+
+    pub fn lun(&self) -> u64 {
+        self.u64_at(8)
+    }
+
+    pub fn opcode(&self) -> u8 {
+        self.bhs[0] & 0x3f
+    }
+
+    pub fn set_u16(&mut self, offset: usize, v: u16) {
+        self.bhs[offset..offset + 2].copy_from_slice(&v.to_be_bytes());
+    }
+
+    pub fn set_u32(&mut self, offset: usize, v: u32) {
+        self.bhs[offset..offset + 4].copy_from_slice(&v.to_be_bytes());
+    }
+
+    pub fn set_u64(&mut self, offset: usize, v: u64) {
+        self.bhs[offset..offset + 8].copy_from_slice(&v.to_be_bytes());
+    }
+
+    pub fn u16_at(&self, offset: usize) -> u16 {
+        u16::from_be_bytes(self.bhs[offset..offset + 2].try_into().unwrap())
+    }
+
+    pub fn u32_at(&self, offset: usize) -> u32 {
+        u32::from_be_bytes(self.bhs[offset..offset + 4].try_into().unwrap())
+    }
+
+    pub fn u64_at(&self, offset: usize) -> u64 {
+        u64::from_be_bytes(self.bhs[offset..offset + 8].try_into().unwrap())
+    }
+}
+
+/// Length of a data segment, including its padding
+fn padded(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Read one PDU.  Returns `None` if the initiator disconnected between PDUs.
+///
+/// Data segments longer than `max_data` are an error.
+pub async fn read_pdu<R>(rd: &mut R, max_data: usize) -> io::Result<Option<Pdu>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut bhs = [0u8; BHS_SIZE];
+    match rd.read_exact(&mut bhs).await {
+        Ok(_) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let dsl = u32::from_be_bytes([0, bhs[5], bhs[6], bhs[7]]) as usize;
+    if dsl > max_data {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("data segment too long: {dsl} bytes"),
+        ));
+    }
+    // We never negotiate anything that uses AHSs, so just skip them
+    let mut ahs = vec![0u8; usize::from(bhs[4]) * 4];
+    rd.read_exact(&mut ahs).await?;
+    let mut data = vec![0u8; padded(dsl)];
+    rd.read_exact(&mut data).await?;
+    data.truncate(dsl);
+    Ok(Some(Pdu { bhs, data }))
+}
+
+/// Decode a text or login data segment: a list of NUL-terminated key=value
+/// pairs.
+pub fn decode_text(data: &[u8]) -> Option<Vec<(String, String)>> {
+    let data = data.strip_suffix(b"\0").unwrap_or(data);
+    if data.is_empty() {
+        return Some(Vec::new());
+    }
+    data.split(|b| *b == 0)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).ok()?;
+            let (k, v) = pair.split_once('=')?;
+            Some((k.to_owned(), v.to_owned()))
+        })
+        .collect()
+}
+
+/// Encode key=value pairs for a text or login data segment
+pub fn encode_text<K, V>(pairs: &[(K, V)]) -> Vec<u8>
+where
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    let mut buf = Vec::new();
+    for (k, v) in pairs.iter() {
+        buf.extend_from_slice(k.as_ref().as_bytes());
+        buf.push(b'=');
+        buf.extend_from_slice(v.as_ref().as_bytes());
+        buf.push(0);
+    }
+    buf
+}
@@ -0,0 +1,324 @@
+// vim: tw=80
+//! The subset of SCSI Block Commands that a volume needs
+//!
+//! This module only parses CDBs and builds response data.  It never touches
+//! the volume itself.  See SPC-4 and SBC-3 for the meaning of each field.
+
+/// Logical block size of every volume
+pub const BLOCK_SIZE: u32 = 512;
+/// Most blocks that a single READ, WRITE, or UNMAP descriptor may cover
+pub const MAX_XFER_BLOCKS: u32 = 2048;
+/// Most descriptors that a single UNMAP may contain
+const MAX_UNMAP_DESCRIPTORS: u32 = 256;
+
+/// SCSI status codes
+pub const GOOD: u8 = 0x00;
+pub const CHECK_CONDITION: u8 = 0x02;
+
+const VENDOR: &[u8; 8] = b"BFFFS   ";
+const PRODUCT: &[u8; 16] = b"Volume          ";
+const REVISION: &[u8; 4] = b"0001";
+
+/// Sense data, describing why a command failed
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Sense {
+    pub key:  u8,
+    pub asc:  u8,
+    pub ascq: u8,
+}
+
+impl Sense {
+    pub const NO_SENSE: Sense = Sense::new(0x00, 0x00, 0x00);
+    pub const INVALID_OPCODE: Sense = Sense::new(0x05, 0x20, 0x00);
+    pub const LBA_OUT_OF_RANGE: Sense = Sense::new(0x05, 0x21, 0x00);
+    pub const INVALID_FIELD_IN_CDB: Sense = Sense::new(0x05, 0x24, 0x00);
+    pub const LUN_NOT_SUPPORTED: Sense = Sense::new(0x05, 0x25, 0x00);
+    pub const INVALID_FIELD_IN_PARAMETERS: Sense =
+        Sense::new(0x05, 0x26, 0x00);
+    pub const READ_ERROR: Sense = Sense::new(0x03, 0x11, 0x00);
+    pub const WRITE_ERROR: Sense = Sense::new(0x03, 0x0c, 0x00);
+    pub const WRITE_PROTECTED: Sense = Sense::new(0x07, 0x27, 0x00);
+    pub const SPACE_EXHAUSTED: Sense = Sense::new(0x07, 0x27, 0x07);
+
+    const fn new(key: u8, asc: u8, ascq: u8) -> Self {
+        Sense { key, asc, ascq }
+    }
+
+    /// Translate an error from the `Vfs`.  `write` says whether the failed
+    /// operation was modifying the volume.
+    pub fn from_errno(e: i32, write: bool) -> Self {
+        match e {
+            libc::EROFS => Sense::WRITE_PROTECTED,
+            libc::ENOSPC => Sense::SPACE_EXHAUSTED,
+            _ if write => Sense::WRITE_ERROR,
+            _ => Sense::READ_ERROR,
+        }
+    }
+
+    /// Encode as fixed format sense data
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut buf = vec![0u8; 18];
+        buf[0] = 0x70; // Current error, fixed format
+        buf[2] = self.key;
+        buf[7] = 10; // Additional sense length
+        buf[12] = self.asc;
+        buf[13] = self.ascq;
+        buf
+    }
+}
+
+/// A parsed Command Descriptor Block
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Command {
+    TestUnitReady,
+    RequestSense { alloc: u32 },
+    Inquiry { evpd: bool, page: u8, alloc: u32 },
+    ModeSense { ten: bool, dbd: bool, page: u8, alloc: u32 },
+    ReadCapacity10,
+    ReadCapacity16 { alloc: u32 },
+    Read { lba: u64, blocks: u32 },
+    Write { lba: u64, blocks: u32 },
+    SynchronizeCache,
+    /// `len` is the length of the parameter list that follows
+    Unmap { len: u32 },
+    ReportLuns { alloc: u32 },
+}
+
+impl Command {
+    pub fn parse(cdb: &[u8]) -> Result<Self, Sense> {
+        let cdb_len = match cdb.first() {
+            Some(op) if op >> 5 == 0 => 6,
+            Some(op) if op >> 5 <= 2 => 10,
+            Some(op) if op >> 5 == 4 => 16,
+            Some(op) if op >> 5 == 5 => 12,
+            _ => return Err(Sense::INVALID_OPCODE),
+        };
+        if cdb.len() < cdb_len {
+            return Err(Sense::INVALID_FIELD_IN_CDB);
+        }
+        let u16_at =
+            |i: usize| u32::from(u16::from_be_bytes([cdb[i], cdb[i + 1]]));
+        let u32_at =
+            |i: usize| u32::from_be_bytes(cdb[i..i + 4].try_into().unwrap());
+        let u64_at =
+            |i: usize| u64::from_be_bytes(cdb[i..i + 8].try_into().unwrap());
+        match cdb[0] {
+            0x00 => Ok(Command::TestUnitReady),
+            0x03 => Ok(Command::RequestSense {
+                alloc: u32::from(cdb[4]),
+            }),
+            0x12 => {
+                let evpd = cdb[1] & 1 != 0;
+                if !evpd && cdb[2] != 0 {
+                    return Err(Sense::INVALID_FIELD_IN_CDB);
+                }
+                Ok(Command::Inquiry {
+                    evpd,
+                    page: cdb[2],
+                    alloc: u16_at(3),
+                })
+            }
+            0x1a => Ok(Command::ModeSense {
+                ten:   false,
+                dbd:   cdb[1] & 0x08 != 0,
+                page:  cdb[2] & 0x3f,
+                alloc: u32::from(cdb[4]),
+            }),
+            0x5a => Ok(Command::ModeSense {
+                ten:   true,
+                dbd:   cdb[1] & 0x08 != 0,
+                page:  cdb[2] & 0x3f,
+                alloc: u16_at(7),
+            }),
+            0x25 => Ok(Command::ReadCapacity10),
+            0x9e if cdb[1] & 0x1f == 0x10 => Ok(Command::ReadCapacity16 {
+                alloc: u32_at(10),
+            }),
+            0x28 => Ok(Command::Read {
+                lba:    u64::from(u32_at(2)),
+                blocks: u16_at(7),
+            }),
+            0x88 => Ok(Command::Read {
+                lba:    u64_at(2),
+                blocks: u32_at(10),
+            }),
+            0x2a => Ok(Command::Write {
+                lba:    u64::from(u32_at(2)),
+                blocks: u16_at(7),
+            }),
+            0x8a => Ok(Command::Write {
+                lba:    u64_at(2),
+                blocks: u32_at(10),
+            }),
+            0x35 | 0x91 => Ok(Command::SynchronizeCache),
+            0x42 => Ok(Command::Unmap { len: u16_at(7) }),
+            0xa0 => Ok(Command::ReportLuns { alloc: u32_at(6) }),
+            _ => Err(Sense::INVALID_OPCODE),
+        }
+    }
+}
+
+/// Check that a range of blocks lies within the volume and may be transferred
+/// by a single command
+pub fn check_range(lba: u64, blocks: u32, nblocks: u64) -> Result<(), Sense> {
+    if lba.checked_add(u64::from(blocks)).map_or(true, |end| end > nblocks) {
+        Err(Sense::LBA_OUT_OF_RANGE)
+    } else if blocks > MAX_XFER_BLOCKS {
+        Err(Sense::INVALID_FIELD_IN_CDB)
+    } else {
+        Ok(())
+    }
+}
+
+/// Build the response to an INQUIRY.
+///
+/// `serial` uniquely identifies the volume.  `lun0` says whether the command
+/// was addressed to the only LUN that we have.
+pub fn inquiry(
+    evpd: bool,
+    page: u8,
+    serial: u64,
+    lun0: bool,
+) -> Result<Vec<u8>, Sense> {
+    // Direct access block device, or no device at all
+    let pdt = if lun0 { 0x00 } else { 0x7f };
+    if !evpd {
+        let mut buf = vec![0u8; 36];
+        buf[0] = pdt;
+        buf[2] = 0x06; // SPC-4
+        buf[3] = 0x02; // Response data format
+        buf[4] = 36 - 5;
+        buf[7] = 0x02; // CmdQue
+        buf[8..16].copy_from_slice(VENDOR);
+        buf[16..32].copy_from_slice(PRODUCT);
+        buf[32..36].copy_from_slice(REVISION);
+        return Ok(buf);
+    }
+    let payload = match page {
+        // Supported VPD pages
+        0x00 => vec![0x00, 0x80, 0x83, 0xb0, 0xb2],
+        // Unit serial number
+        0x80 => format!("{serial:016x}").into_bytes(),
+        // Device identification: a single vendor-specific EUI-64
+        0x83 => {
+            let mut desc = vec![0x01, 0x02, 0x00, 0x08];
+            desc.extend_from_slice(&serial.to_be_bytes());
+            desc
+        }
+        // Block limits
+        0xb0 => {
+            let mut buf = vec![0u8; 60];
+            // Maximum transfer length
+            buf[4..8].copy_from_slice(&MAX_XFER_BLOCKS.to_be_bytes());
+            // Optimal transfer length
+            buf[8..12].copy_from_slice(&MAX_XFER_BLOCKS.to_be_bytes());
+            // Maximum unmap LBA count and block descriptor count
+            buf[16..20].copy_from_slice(&MAX_XFER_BLOCKS.to_be_bytes());
+            buf[20..24].copy_from_slice(&MAX_UNMAP_DESCRIPTORS.to_be_bytes());
+            buf
+        }
+        // Logical block provisioning: thin, with UNMAP
+        0xb2 => vec![0x00, 0x80, 0x02, 0x00],
+        _ => return Err(Sense::INVALID_FIELD_IN_CDB),
+    };
+    let mut buf = vec![pdt, page];
+    buf.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&payload);
+    Ok(buf)
+}
+
+/// Build the response to a MODE SENSE(6) or MODE SENSE(10).
+///
+/// The only page we have is the caching page, which says that the volume
+/// has a write cache.  It really does: writes aren't durable until the next
+/// SYNCHRONIZE CACHE.
+pub fn mode_sense(
+    ten: bool,
+    dbd: bool,
+    page: u8,
+    nblocks: u64,
+) -> Result<Vec<u8>, Sense> {
+    let mut caching = vec![0u8; 20];
+    caching[0] = 0x08;
+    caching[1] = 0x12;
+    caching[2] = 0x04; // WCE
+    let pages = match page {
+        0x08 | 0x3f => caching,
+        _ => return Err(Sense::INVALID_FIELD_IN_CDB),
+    };
+    let mut bd = Vec::new();
+    if !dbd {
+        let blocks = u32::try_from(nblocks).unwrap_or(u32::MAX);
+        bd.extend_from_slice(&blocks.to_be_bytes());
+        bd.extend_from_slice(&BLOCK_SIZE.to_be_bytes());
+    }
+    let mut buf = if ten {
+        let len = (6 + bd.len() + pages.len()) as u16;
+        let mut buf = len.to_be_bytes().to_vec();
+        buf.extend_from_slice(&[0, 0, 0, 0]);
+        buf.extend_from_slice(&(bd.len() as u16).to_be_bytes());
+        buf
+    } else {
+        vec![(3 + bd.len() + pages.len()) as u8, 0, 0, bd.len() as u8]
+    };
+    buf.extend_from_slice(&bd);
+    buf.extend_from_slice(&pages);
+    Ok(buf)
+}
+
+pub fn read_capacity10(nblocks: u64) -> Vec<u8> {
+    // Volumes too large for READ CAPACITY(10) report the maximum, telling the
+    // initiator to use READ CAPACITY(16) instead.
+    let last = u32::try_from(nblocks - 1).unwrap_or(u32::MAX);
+    let mut buf = last.to_be_bytes().to_vec();
+    buf.extend_from_slice(&BLOCK_SIZE.to_be_bytes());
+    buf
+}
+
+pub fn read_capacity16(nblocks: u64) -> Vec<u8> {
+    let mut buf = vec![0u8; 32];
+    buf[0..8].copy_from_slice(&(nblocks - 1).to_be_bytes());
+    buf[8..12].copy_from_slice(&BLOCK_SIZE.to_be_bytes());
+    buf[14] = 0x80; // LBPME
+    buf
+}
+
+pub fn report_luns() -> Vec<u8> {
+    // Just LUN 0
+    let mut buf = vec![0u8; 16];
+    buf[3] = 8;
+    buf
+}
+
+/// Parse UNMAP's parameter list into (lba, blocks) pairs.
+pub fn unmap_descriptors(
+    data: &[u8],
+    nblocks: u64,
+) -> Result<Vec<(u64, u32)>, Sense> {
+    if data.len() < 8 {
+        // A zero-length parameter list is not an error; it does nothing.
+        return Ok(Vec::new());
+    }
+    let bdl = usize::from(u16::from_be_bytes([data[2], data[3]]));
+    let descs = data[8..]
+        .get(..bdl)
+        .ok_or(Sense::INVALID_FIELD_IN_PARAMETERS)?;
+    if descs.len() / 16 > MAX_UNMAP_DESCRIPTORS as usize {
+        return Err(Sense::INVALID_FIELD_IN_PARAMETERS);
+    }
+    descs
+        .chunks_exact(16)
+        .map(|d| {
+            let lba = u64::from_be_bytes(d[0..8].try_into().unwrap());
+            let blocks = u32::from_be_bytes(d[8..12].try_into().unwrap());
+            check_range(lba, blocks, nblocks)?;
+            Ok((lba, blocks))
+        })
+        .collect()
+}
+
+/// Truncate response data to the initiator's allocation length
+pub fn truncate(mut data: Vec<u8>, alloc: u32) -> Vec<u8> {
+    data.truncate(alloc as usize);
+    data
+}
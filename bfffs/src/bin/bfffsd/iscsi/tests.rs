@@ -0,0 +1,464 @@
+// vim: tw=80
+use bfffs_core::fs::{GetAttr, Mode, Timespec};
+use bfffs_fuse::mock::MockFs as Fs;
+use futures::{future, FutureExt};
+use tokio::io::AsyncReadExt;
+
+use super::*;
+
+const DSNAME: &str = "mypool/vol";
+const TARGET: &str = "iqn.2018-01.org.bfffs:mypool:vol";
+/// Inode number of the root directory
+const ROOT: u64 = 1;
+/// Inode number of the volume's backing file
+const VOL: u64 = 2;
+/// Size of the volume in blocks
+const NBLOCKS: u64 = 2048;
+
+fn attr(ino: u64, size: u64) -> GetAttr {
+    GetAttr {
+        ino,
+        size,
+        bytes: 0,
+        atime: Timespec { sec: 0, nsec: 0 },
+        mtime: Timespec { sec: 0, nsec: 0 },
+        ctime: Timespec { sec: 0, nsec: 0 },
+        birthtime: Timespec { sec: 0, nsec: 0 },
+        mode: Mode(libc::S_IFREG | 0o600),
+        nlink: 1,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        blksize: 16384,
+        flags: 0,
+        change: 0,
+    }
+}
+
+/// Build a session sharing a single volume, backed by a mock.
+///
+/// The mock always knows how to open the volume's backing file.
+fn make_session<F>(f: F) -> Session<Fs>
+where
+    F: FnOnce(&mut Fs),
+{
+    let mut mock_fs = Fs::default();
+    mock_fs
+        .expect_root()
+        .returning(|| FileDataMut::new_for_tests(None, ROOT));
+    mock_fs
+        .expect_lookup()
+        .withf(|_, fd, name| fd.ino() == ROOT && name == VOLUME_FILE)
+        .returning(|_, _, _| Ok(FileDataMut::new_for_tests(None, VOL)));
+    mock_fs
+        .expect_getattr()
+        .withf(|fd| fd.ino() == VOL)
+        .return_const(Ok(attr(VOL, NBLOCKS * u64::from(BLOCK_SIZE))));
+    mock_fs.expect_inactive().return_const(());
+    f(&mut mock_fs);
+    let fs = Arc::new(mock_fs);
+    let exports = Arc::new(Exports {
+        open:    Box::new(move |_: String| future::ok(fs.clone()).boxed()),
+        exports: Default::default(),
+    });
+    let share = Arc::new(Share {
+        datasets: Mutex::new(BTreeSet::from([DSNAME.to_owned()])),
+        exports,
+    });
+    Session::new(share, "127.0.0.1:3260".parse().unwrap())
+}
+
+/// Build a Login Request that transits from operational negotiation straight
+/// to the full feature phase
+fn login_req(pairs: &[(&str, &str)]) -> Pdu {
+    let mut pdu = Pdu::new(OP_LOGIN_REQ);
+    pdu.bhs[0] |= 0x40;
+    pdu.bhs[1] = LOGIN_T | 0x04 | FULL_FEATURE_PHASE;
+    pdu.bhs[8] = 0x80; // ISID
+    pdu.set_u32(16, 0x1234);
+    pdu.data = encode_text(pairs);
+    pdu
+}
+
+/// Log in to the shared volume
+fn login(session: &mut Session<Fs>) {
+    let pdu = login_req(&[
+        ("InitiatorName", "iqn.2000-01.org.example:host"),
+        ("SessionType", "Normal"),
+        ("TargetName", TARGET),
+    ]);
+    let rsp = session.process(pdu).now_or_never().unwrap();
+    assert_eq!(rsp.len(), 1);
+    assert_eq!(rsp[0].u16_at(36), LOGIN_SUCCESS);
+    assert!(session.full_feature);
+}
+
+fn scsi_cmd(cdb: &[u8], edtl: u32, flags: u8) -> Pdu {
+    let mut pdu = Pdu::new(OP_SCSI_CMD);
+    pdu.bhs[1] |= flags;
+    pdu.set_u32(16, 42);
+    pdu.set_u32(20, edtl);
+    pdu.bhs[32..32 + cdb.len()].copy_from_slice(cdb);
+    pdu
+}
+
+/// Get a CHECK CONDITION response's sense key, ASC, and ASCQ
+fn sense(rsp: &Pdu) -> (u8, u8, u8) {
+    assert_eq!(rsp.opcode(), OP_SCSI_RSP);
+    assert_eq!(rsp.bhs[3], scsi::CHECK_CONDITION);
+    (rsp.data[4], rsp.data[14], rsp.data[15])
+}
+
+mod login {
+    use super::*;
+
+    /// CHAP isn't supported
+    #[test]
+    fn auth_failure() {
+        let mut session = make_session(|_| ());
+        let mut pdu = login_req(&[
+            ("InitiatorName", "iqn.2000-01.org.example:host"),
+            ("TargetName", TARGET),
+            ("AuthMethod", "CHAP"),
+        ]);
+        pdu.bhs[1] = LOGIN_T | 0x01;
+        let rsp = session.process(pdu).now_or_never().unwrap();
+        assert_eq!(rsp[0].u16_at(36), LOGIN_AUTH_FAILURE);
+        assert!(session.closing);
+    }
+
+    #[test]
+    fn not_found() {
+        let mut session = make_session(|_| ());
+        let pdu = login_req(&[
+            ("InitiatorName", "iqn.2000-01.org.example:host"),
+            ("TargetName", "iqn.2018-01.org.bfffs:mypool:other"),
+        ]);
+        let rsp = session.process(pdu).now_or_never().unwrap();
+        assert_eq!(rsp[0].u16_at(36), LOGIN_NOT_FOUND);
+        assert!(session.closing);
+        assert!(session.export.is_none());
+    }
+
+    #[test]
+    fn ok() {
+        let mut session = make_session(|_| ());
+        let pdu = login_req(&[
+            ("InitiatorName", "iqn.2000-01.org.example:host"),
+            ("TargetName", TARGET),
+            ("MaxRecvDataSegmentLength", "65536"),
+            ("HeaderDigest", "CRC32C,None"),
+            ("X-com.example.Bogus", "1"),
+        ]);
+        let rsp = session.process(pdu).now_or_never().unwrap();
+        assert_eq!(rsp[0].opcode(), OP_LOGIN_RSP);
+        assert_eq!(rsp[0].u16_at(36), LOGIN_SUCCESS);
+        assert_eq!(rsp[0].flags(), LOGIN_T | 0x04 | FULL_FEATURE_PHASE);
+        assert_ne!(rsp[0].u16_at(14), 0);
+        assert_eq!(rsp[0].itt(), 0x1234);
+        let pairs = decode_text(&rsp[0].data).unwrap();
+        let get = |key: &str| {
+            pairs.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+        };
+        assert_eq!(get("TargetPortalGroupTag"), Some("1"));
+        assert_eq!(get("HeaderDigest"), Some("None"));
+        assert_eq!(get("X-com.example.Bogus"), Some("NotUnderstood"));
+        assert_eq!(session.max_send_dsl, 65536);
+        assert!(session.full_feature);
+    }
+
+    /// Only login requests are allowed before the full feature phase
+    #[test]
+    fn too_early() {
+        let mut session = make_session(|_| ());
+        let pdu = scsi_cmd(&[0; 6], 0, 0);
+        let rsp = session.process(pdu).now_or_never().unwrap();
+        assert_eq!(rsp[0].opcode(), OP_REJECT);
+        assert_eq!(rsp[0].bhs[2], REJECT_PROTOCOL_ERROR);
+        assert!(session.closing);
+    }
+}
+
+mod scsi_cmd {
+    use super::*;
+
+    #[test]
+    fn inquiry() {
+        let mut session = make_session(|_| ());
+        login(&mut session);
+        let pdu = scsi_cmd(&[0x12, 0, 0, 0, 255, 0], 255, 0x40);
+        let rsp = session.process(pdu).now_or_never().unwrap();
+        assert_eq!(rsp.len(), 2);
+        assert_eq!(rsp[0].opcode(), OP_DATA_IN);
+        assert!(rsp[0].is_final());
+        assert_eq!(rsp[0].data.len(), 36);
+        assert_eq!(rsp[0].data[0], 0);
+        assert_eq!(&rsp[0].data[8..16], b"BFFFS   ");
+        assert_eq!(rsp[1].opcode(), OP_SCSI_RSP);
+        assert_eq!(rsp[1].bhs[3], scsi::GOOD);
+        assert_eq!(rsp[1].flags() & RSP_UNDERFLOW, RSP_UNDERFLOW);
+        assert_eq!(rsp[1].u32_at(44), 255 - 36);
+    }
+
+    #[test]
+    fn invalid_opcode() {
+        let mut session = make_session(|_| ());
+        login(&mut session);
+        let pdu = scsi_cmd(&[0x04, 0, 0, 0, 0, 0], 0, 0);
+        let rsp = session.process(pdu).now_or_never().unwrap();
+        assert_eq!(sense(&rsp[0]), (0x05, 0x20, 0x00));
+        // REQUEST SENSE should return the same thing
+        let pdu = scsi_cmd(&[0x03, 0, 0, 0, 18, 0], 18, 0x40);
+        let rsp = session.process(pdu).now_or_never().unwrap();
+        assert_eq!(rsp[0].data[2], 0x05);
+        assert_eq!(rsp[0].data[12], 0x20);
+    }
+
+    /// Reads should be split into Data-In PDUs no larger than the initiator's
+    /// MaxRecvDataSegmentLength
+    #[test]
+    fn read() {
+        let mut session = make_session(|mock_fs| {
+            mock_fs
+                .expect_read()
+                .withf(|fd, offset, size| {
+                    fd.ino() == VOL && *offset == 4096 && *size == 16384
+                })
+                .times(1)
+                .returning(|_, _, _| Ok(Vec::new()));
+        });
+        login(&mut session);
+        // READ(10) 32 blocks at LBA 8
+        let cdb = [0x28, 0, 0, 0, 0, 8, 0, 0, 32, 0];
+        let pdu = scsi_cmd(&cdb, 16384, 0x40);
+        let rsp = session.process(pdu).now_or_never().unwrap();
+        assert_eq!(rsp.len(), 3);
+        assert!(!rsp[0].is_final());
+        assert_eq!(rsp[0].data.len(), 8192);
+        assert_eq!(rsp[1].u32_at(36), 1);
+        assert_eq!(rsp[1].u32_at(40), 8192);
+        assert!(rsp[1].is_final());
+        assert_eq!(rsp[2].bhs[3], scsi::GOOD);
+        assert_eq!(rsp[2].flags(), F_BIT);
+    }
+
+    #[test]
+    fn read_capacity16() {
+        let mut session = make_session(|_| ());
+        login(&mut session);
+        let mut cdb = [0u8; 16];
+        cdb[0] = 0x9e;
+        cdb[1] = 0x10;
+        cdb[13] = 32;
+        let pdu = scsi_cmd(&cdb, 32, 0x40);
+        let rsp = session.process(pdu).now_or_never().unwrap();
+        assert_eq!(
+            u64::from_be_bytes(rsp[0].data[0..8].try_into().unwrap()),
+            NBLOCKS - 1
+        );
+        assert_eq!(
+            u32::from_be_bytes(rsp[0].data[8..12].try_into().unwrap()),
+            BLOCK_SIZE
+        );
+    }
+
+    #[test]
+    fn read_out_of_range() {
+        let mut session = make_session(|_| ());
+        login(&mut session);
+        // READ(10) 2 blocks at the last LBA
+        let cdb = [0x28, 0, 0, 0, 0x07, 0xff, 0, 0, 2, 0];
+        let pdu = scsi_cmd(&cdb, 1024, 0x40);
+        let rsp = session.process(pdu).now_or_never().unwrap();
+        assert_eq!(sense(&rsp[0]), (0x05, 0x21, 0x00));
+    }
+
+    #[test]
+    fn unmap() {
+        let mut session = make_session(|mock_fs| {
+            mock_fs
+                .expect_deallocate()
+                .withf(|fd, offset, len| {
+                    fd.ino() == VOL && *offset == 512 && *len == 1024
+                })
+                .times(1)
+                .return_const(Ok(()));
+        });
+        login(&mut session);
+        let cdb = [0x42, 0, 0, 0, 0, 0, 0, 0, 24, 0];
+        let mut pdu = scsi_cmd(&cdb, 24, CMD_WRITE);
+        let mut params = vec![0, 22, 0, 16, 0, 0, 0, 0];
+        params.extend_from_slice(&1u64.to_be_bytes());
+        params.extend_from_slice(&2u32.to_be_bytes());
+        params.extend_from_slice(&[0; 4]);
+        pdu.data = params;
+        let rsp = session.process(pdu).now_or_never().unwrap();
+        assert_eq!(rsp[0].bhs[3], scsi::GOOD);
+    }
+
+    /// A write that fits entirely in immediate data needs no R2T
+    #[test]
+    fn write_immediate() {
+        let mut session = make_session(|mock_fs| {
+            mock_fs
+                .expect_write()
+                .withf(|fd, offset, data, _| {
+                    fd.ino() == VOL && *offset == 1024 && data == [0x55; 512]
+                })
+                .times(1)
+                .return_const(Ok(512));
+        });
+        login(&mut session);
+        let cdb = [0x2a, 0, 0, 0, 0, 2, 0, 0, 1, 0];
+        let mut pdu = scsi_cmd(&cdb, 512, CMD_WRITE);
+        pdu.data = vec![0x55; 512];
+        let rsp = session.process(pdu).now_or_never().unwrap();
+        assert_eq!(rsp.len(), 1);
+        assert_eq!(rsp[0].bhs[3], scsi::GOOD);
+    }
+
+    /// A write without immediate data must solicit it with an R2T
+    #[test]
+    fn write_r2t() {
+        let mut session = make_session(|mock_fs| {
+            mock_fs
+                .expect_write()
+                .withf(|fd, offset, data, _| {
+                    fd.ino() == VOL && *offset == 0 && data.len() == 1024
+                })
+                .times(1)
+                .return_const(Ok(1024));
+        });
+        login(&mut session);
+        let cdb = [0x2a, 0, 0, 0, 0, 0, 0, 0, 2, 0];
+        let pdu = scsi_cmd(&cdb, 1024, CMD_WRITE);
+        let rsp = session.process(pdu).now_or_never().unwrap();
+        assert_eq!(rsp.len(), 1);
+        assert_eq!(rsp[0].opcode(), OP_R2T);
+        assert_eq!(rsp[0].itt(), 42);
+        assert_eq!(rsp[0].u32_at(40), 0);
+        assert_eq!(rsp[0].u32_at(44), 1024);
+        let ttt = rsp[0].u32_at(20);
+
+        let mut dout = Pdu::new(OP_DATA_OUT);
+        dout.set_u32(16, 42);
+        dout.set_u32(20, ttt);
+        dout.data = vec![0; 1024];
+        let rsp = session.process(dout).now_or_never().unwrap();
+        assert_eq!(rsp.len(), 1);
+        assert_eq!(rsp[0].opcode(), OP_SCSI_RSP);
+        assert_eq!(rsp[0].bhs[3], scsi::GOOD);
+    }
+
+    #[test]
+    fn write_erofs() {
+        let mut session = make_session(|mock_fs| {
+            mock_fs.expect_write().return_const(Err(libc::EROFS));
+        });
+        login(&mut session);
+        let cdb = [0x2a, 0, 0, 0, 0, 0, 0, 0, 1, 0];
+        let mut pdu = scsi_cmd(&cdb, 512, CMD_WRITE);
+        pdu.data = vec![0; 512];
+        let rsp = session.process(pdu).now_or_never().unwrap();
+        assert_eq!(sense(&rsp[0]), (0x07, 0x27, 0x00));
+    }
+}
+
+/// Discovery sessions should list every target shared at this address
+#[test]
+fn send_targets() {
+    let mut session = make_session(|_| ());
+    let pdu = login_req(&[
+        ("InitiatorName", "iqn.2000-01.org.example:host"),
+        ("SessionType", "Discovery"),
+    ]);
+    let rsp = session.process(pdu).now_or_never().unwrap();
+    assert_eq!(rsp[0].u16_at(36), LOGIN_SUCCESS);
+    assert!(session.export.is_none());
+
+    let mut pdu = Pdu::new(OP_TEXT_REQ);
+    pdu.set_u32(16, 7);
+    pdu.set_u32(20, RESERVED_TAG);
+    pdu.data = encode_text(&[("SendTargets", "All")]);
+    let rsp = session.process(pdu).now_or_never().unwrap();
+    assert_eq!(rsp[0].opcode(), OP_TEXT_RSP);
+    assert_eq!(
+        decode_text(&rsp[0].data).unwrap(),
+        vec![
+            ("TargetName".to_owned(), TARGET.to_owned()),
+            ("TargetAddress".to_owned(), "127.0.0.1:3260,1".to_owned()),
+        ]
+    );
+}
+
+mod wire {
+    use super::*;
+
+    #[test]
+    fn decode_text_bad() {
+        assert_eq!(decode_text(b"foo\0"), None);
+    }
+
+    #[test]
+    fn decode_text_empty() {
+        assert_eq!(decode_text(b""), Some(Vec::new()));
+    }
+
+    #[test]
+    fn encode_pads() {
+        let mut pdu = Pdu::new(OP_NOP_IN);
+        pdu.data = vec![1, 2, 3, 4, 5];
+        let buf = pdu.encode();
+        assert_eq!(buf.len(), BHS_SIZE + 8);
+        assert_eq!(&buf[5..8], &[0, 0, 5]);
+        assert_eq!(&buf[BHS_SIZE..], &[1, 2, 3, 4, 5, 0, 0, 0]);
+    }
+
+    #[test]
+    fn parse_read16() {
+        let mut cdb = [0u8; 16];
+        cdb[0] = 0x88;
+        cdb[2..10].copy_from_slice(&0x1_0000_0000u64.to_be_bytes());
+        cdb[10..14].copy_from_slice(&8u32.to_be_bytes());
+        assert_eq!(
+            Command::parse(&cdb),
+            Ok(Command::Read {
+                lba:    0x1_0000_0000,
+                blocks: 8,
+            })
+        );
+    }
+}
+
+/// The session should frame PDUs on a byte stream, close after logout, and
+/// release its volume
+#[tokio::test]
+async fn run() {
+    let session = make_session(|_| ());
+    let exports = session.share.exports.clone();
+    let (mut client, server) = tokio::io::duplex(4096);
+    let (_tx, rx) = watch::channel(());
+    let task = tokio::spawn(session.run(server, rx));
+
+    let pdu = login_req(&[
+        ("InitiatorName", "iqn.2000-01.org.example:host"),
+        ("TargetName", TARGET),
+    ]);
+    client.write_all(&pdu.encode()).await.unwrap();
+    let rsp = read_pdu(&mut client, 8192).await.unwrap().unwrap();
+    assert_eq!(rsp.u16_at(36), LOGIN_SUCCESS);
+    assert_eq!(exports.exports.lock().await[DSNAME].sessions, 1);
+
+    let mut pdu = Pdu::new(OP_LOGOUT_REQ);
+    pdu.bhs[0] |= 0x40;
+    pdu.set_u32(16, 2);
+    client.write_all(&pdu.encode()).await.unwrap();
+    let rsp = read_pdu(&mut client, 8192).await.unwrap().unwrap();
+    assert_eq!(rsp.opcode(), OP_LOGOUT_RSP);
+    assert_eq!(rsp.bhs[2], 0);
+
+    task.await.unwrap();
+    let mut buf = [0u8; 1];
+    assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+    assert!(exports.exports.lock().await.is_empty());
+}
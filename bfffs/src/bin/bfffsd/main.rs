@@ -1,49 +1,94 @@
 // vim: tw=80
 
 use std::{
-    fs::Permissions,
-    os::unix::{fs::PermissionsExt, io::RawFd},
+    collections::{btree_map::Entry, BTreeMap, BTreeSet, HashMap},
+    ffi::OsStr,
+    fs::{DirBuilder, Permissions},
+    net::SocketAddr,
+    os::unix::{
+        fs::{DirBuilderExt, PermissionsExt},
+        io::RawFd,
+        net::UnixDatagram,
+    },
     path::{Path, PathBuf},
     process::exit,
-    sync::Arc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+        Mutex,
+    },
 };
 
+use bfffs::transport::Peer;
 use bfffs_core::{
     controller::Controller,
     device_manager::DevManager,
-    property::{Property, PropertyName},
+    fs::Fs,
+    memory::{Consumer, MemoryBudget},
+    property::{Property, PropertyName, PropertySource, UserProperty},
     rpc,
     Error,
     Result,
 };
+use bfffs_fuse::{CloseHook, FuseFs, ReadBudget};
 use cfg_if::cfg_if;
 use clap::{crate_version, Parser};
-use fuse3::{
-    raw::{MountHandle, Session},
-    MountOptions,
-};
+use fuse3::{raw::Session, MountOptions};
 use futures::{
+    future::{self, AbortHandle, BoxFuture},
     stream::{FuturesOrdered, FuturesUnordered},
+    FutureExt,
     TryFutureExt,
     TryStreamExt,
 };
 use nix::{
     fcntl::{open, OFlag},
+    mount::{MntFlags, Nmount},
     sys::stat::Mode,
     unistd,
 };
-use tokio_seqpacket::{UCred, UnixSeqpacket, UnixSeqpacketListener};
+use serde_derive::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_seqpacket::{UnixSeqpacket, UnixSeqpacketListener};
 use tracing::{error, warn};
-use tracing_subscriber::EnvFilter;
-
-mod fs;
+use tracing_subscriber::{prelude::*, EnvFilter};
 
-use crate::fs::FuseFs;
+mod iscsi;
+mod p9;
+mod ratelimit;
+mod watch;
+mod worker;
 
 #[derive(Parser, Clone, Debug)]
 #[clap(version = crate_version!())]
 struct Cli {
     // TODO: configurable log level
+    /// File containing a secret token.  Clients who can read it may make
+    /// privileged requests, even if they run as a different user than bfffsd.
+    #[clap(long)]
+    auth_token:    Option<PathBuf>,
+    /// Records the devices of each imported pool, so later imports need not
+    /// taste every device.
+    #[clap(long, default_value = "/var/db/bfffs.cache")]
+    cachefile:     PathBuf,
+    /// Make every otherwise arbitrary or timing-dependent decision depend
+    /// only on this seed, so that failures can be reproduced.  For testing
+    /// only; it hurts performance.
+    #[clap(long, value_name = "SEED")]
+    deterministic: Option<u64>,
+    /// Import the pool even if another host seems to be using it.  If it
+    /// really is, the pool will be corrupted.
+    #[clap(long)]
+    force:         bool,
+    /// Also accept TLS connections on this TCP address.  Any client whose
+    /// certificate is signed by --tls-ca may make privileged requests.
+    #[clap(
+        long,
+        value_name = "ADDR",
+        requires_all(&["tls-ca", "tls-cert", "tls-key"])
+    )]
+    listen:        Option<SocketAddr>,
     /// Mount options, comma delimited.  Apply to all BFFFS mounts
     #[clap(
         short = 'o',
@@ -51,13 +96,26 @@ struct Cli {
         require_value_delimiter(true),
         value_delimiter(',')
     )]
-    options:   Vec<String>,
+    options:       Vec<String>,
+    /// Write bfffsd's PID to this file once it is ready to serve requests
+    #[clap(long)]
+    pidfile:       Option<PathBuf>,
     #[clap(long, default_value = "/var/run/bfffsd.sock")]
-    sock:      PathBuf,
+    sock:          PathBuf,
+    /// PEM file of the certificate authorities that sign client certificates
+    #[clap(long)]
+    tls_ca:        Option<PathBuf>,
+    /// PEM file containing bfffsd's TLS certificate chain
+    #[clap(long)]
+    tls_cert:      Option<PathBuf>,
+    /// PEM file containing bfffsd's TLS private key
+    #[clap(long)]
+    tls_key:       Option<PathBuf>,
     /// Pool name
-    pool_name: String,
-    #[clap(required(true))]
-    devices:   Vec<String>,
+    pool_name:     String,
+    /// Devices to taste if the cache file doesn't list the pool's devices, or
+    /// if it's stale.
+    devices:       Vec<String>,
 }
 
 /// bfffsd's communications socket
@@ -102,66 +160,454 @@ impl Socket {
     }
 }
 
+/// Size of each client's receive buffer.  Larger requests are rejected.
+const BUFSIZ: usize = 4096;
+
+/// Abort handles for a single client's in-progress requests
+type Inflight = Arc<Mutex<HashMap<rpc::RequestId, AbortHandle>>>;
+
+/// FUSE mount options, in a form that can be sent to a worker process
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct MountOpts {
+    allow_other: bool,
+    read_only:   bool,
+    /// Passed straight through to mount_fusefs
+    custom:      Vec<String>,
+}
+
+impl MountOpts {
+    fn custom_options(&mut self, o: &str) {
+        self.custom.push(o.to_owned());
+    }
+
+    fn to_fuse(&self) -> MountOptions {
+        let mut mo = MountOptions::default();
+        mo.fs_name("bfffs");
+        if self.allow_other {
+            mo.allow_other(true);
+            mo.default_permissions(true);
+        }
+        mo.no_open_support(true);
+        mo.no_open_dir_support(true);
+        for o in self.custom.iter() {
+            mo.custom_options(o);
+        }
+        if self.read_only {
+            mo.read_only(true);
+        }
+        mo
+    }
+}
+
+/// A file system that bfffsd has mounted, or is in the process of mounting
+struct Mounted {
+    mountpoint: PathBuf,
+    /// Mount options derived from the file system's properties
+    opts:       Vec<&'static str>,
+    /// User ID of the client that mounted it
+    uid:        u32,
+    /// Distinguishes this mount from any earlier or later mounts of the same
+    /// file system
+    generation: u64,
+}
+
+impl Mounted {
+    fn info(&self, name: &str) -> rpc::fs::MountInfo {
+        rpc::fs::MountInfo {
+            name:       name.to_owned(),
+            mountpoint: self.mountpoint.to_string_lossy().into_owned(),
+            opts:       self.opts.iter().map(|o| o.to_string()).collect(),
+            uid:        self.uid,
+        }
+    }
+}
+
+/// Identity of a connected client
+#[derive(Clone, Copy, Debug)]
+struct Client {
+    uid: u32,
+    /// The client's jail, or 0 for the host
+    jid: i32,
+}
+
+impl Client {
+    /// Identify a client connected to the local socket
+    fn local(peer: &UnixSeqpacket) -> Result<Self> {
+        let creds = peer.peer_cred().map_err(|e| {
+            warn!("Cannot get client credentials: {e}");
+            Error::from(e)
+        })?;
+        let jid = peer_jid(peer).map_err(|e| {
+            warn!("Cannot get client's jail: {e}");
+            e
+        })?;
+        Ok(Client {
+            uid: creds.uid(),
+            jid,
+        })
+    }
+
+    /// Identify a client that authenticated with a TLS certificate.
+    ///
+    /// It's treated like a process on the host running as bfffsd's user.
+    #[cfg_attr(not(feature = "tls"), allow(dead_code))]
+    fn remote() -> Self {
+        Client {
+            uid: unistd::geteuid().as_raw(),
+            jid: 0,
+        }
+    }
+}
+
+/// Does this property affect the host, rather than just the file system?
+///
+/// Jailed clients may not set such properties, even on delegated file
+/// systems.
+fn host_property(prop: &Property) -> bool {
+    matches!(
+        prop.name(),
+        PropertyName::Mountpoint |
+            PropertyName::Share9p |
+            PropertyName::ShareIscsi
+    )
+}
+
+/// All mounted file systems, by name
+type Mounts = Arc<Mutex<BTreeMap<String, Mounted>>>;
+
+/// Forget about a mount, unless the file system has been mounted again since
+fn forget_mount(mounts: &Mounts, name: &str, generation: u64) {
+    let mut guard = mounts.lock().unwrap();
+    if guard.get(name).map(|m| m.generation) == Some(generation) {
+        guard.remove(name);
+    }
+}
+
 struct Bfffsd {
-    controller:   Controller,
-    _dev_manager: DevManager,
-    mount_opts:   MountOptions,
+    /// Hash of the authentication token, if bfffsd was started with one
+    auth:            Option<rpc::AuthHash>,
+    controller:      Arc<Controller>,
+    dev_manager:     DevManager,
+    /// Limits the read data buffered by all FUSE mounts combined
+    fuse_budget:     Option<ReadBudget>,
+    /// Limits the read data buffered by each FUSE mount
+    fuse_limit:      Option<usize>,
+    /// Serves volumes that have the `shareiscsi` property set
+    iscsi:           iscsi::Server<Fs>,
+    /// File systems delegated with `bfffs fs jail`, and their jail IDs
+    jails:           Mutex<BTreeMap<String, i32>>,
+    /// Shared by the cache, the writeback cache, FUSE mounts, and RPC buffers
+    memory_budget:   Option<MemoryBudget>,
+    mount_opts:      MountOpts,
+    /// Generation number for the next mount
+    mount_gen:       AtomicU64,
+    mounts:          Mounts,
+    /// Permissions for mountpoint directories that bfffsd creates
+    mountpoint_mode: u32,
+    /// Serves file systems that have the `share9p` property set
+    p9:              p9::Server<Fs>,
+    pool_name:       String,
+    readonly:        bool,
+    /// Notified when bfffsd should unmount everything and exit
+    shutdown:        tokio::sync::Notify,
+    /// Helpers waiting for close-after-write events
+    watches:         Arc<watch::Watches>,
+    /// Runs FUSE sessions in worker processes, if `fuse_workers` is on
+    workers:         Option<worker::Workers<Fs>>,
 }
 
 impl Bfffsd {
-    async fn handle_client(self: Arc<Self>, peer: UnixSeqpacket) {
-        const BUFSIZ: usize = 4096;
+    /// Look up the requested properties of a single dataset
+    async fn dsinfo(
+        &self,
+        name: String,
+        propnames: &[PropertyName],
+        user_props: bool,
+        offset: u64,
+    ) -> Result<rpc::fs::DsInfo> {
+        let props = propnames
+            .iter()
+            .map(|propname| self.get_prop(name.clone(), *propname))
+            .collect::<FuturesOrdered<_>>()
+            .try_collect::<Vec<_>>()
+            .await?;
+        let user_props = if user_props {
+            self.controller.get_user_props(&name).await?
+        } else {
+            Vec::new()
+        };
+        Ok(rpc::fs::DsInfo {
+            name,
+            props,
+            user_props,
+            offset,
+        })
+    }
+
+    /// Like `Controller::get_prop`, but also handles the `mounted`
+    /// pseudoproperty, which only bfffsd knows.
+    async fn get_prop(&self, name: String, propname: PropertyName)
+        -> Result<(Property, PropertySource)>
+    {
+        if propname == PropertyName::Mounted {
+            let mounted = self.mounts.lock().unwrap().contains_key(&name);
+            Ok((Property::Mounted(mounted), PropertySource::None))
+        } else {
+            self.controller.get_prop(name, propname).await
+        }
+    }
+
+    /// May this client make privileged requests?
+    ///
+    /// It may if it runs on the host as the same user as bfffsd, or if it
+    /// supplies the hash of the authentication token.  The hashes are compared
+    /// in constant time, so the response time doesn't leak how much of a
+    /// guess was right.
+    fn authorized(&self, client: &Client, auth: Option<rpc::AuthHash>) -> bool {
+        if client.jid == 0 && client.uid == unistd::geteuid().as_raw() {
+            return true;
+        }
+        match (&self.auth, auth) {
+            (Some(expected), Some(auth)) => {
+                expected[..].ct_eq(&auth[..]).into()
+            }
+            _ => false,
+        }
+    }
+
+    /// May this jailed client manage the named file system?
+    ///
+    /// It may if it runs as the same user as bfffsd, and the file system was
+    /// delegated to its jail.  If `strict`, then only the delegated file
+    /// system's descendants qualify, not the file system itself.
+    fn delegated(&self, client: &Client, name: &str, strict: bool) -> bool {
+        if client.jid == 0 || client.uid != unistd::geteuid().as_raw() {
+            return false;
+        }
+        self.jails.lock().unwrap().iter().any(|(ds, jid)| {
+            *jid == client.jid &&
+                ((!strict && name == ds) ||
+                 name.strip_prefix(ds.as_str())
+                     .map_or(false, |rest| rest.starts_with('/')))
+        })
+    }
+
+    /// Cancel an in-progress request from the same client.
+    async fn cancel(
+        peer: &dyn Peer,
+        inflight: &Inflight,
+        victim: rpc::RequestId,
+    ) -> Result<()> {
+        let handle = inflight.lock().unwrap().remove(&victim);
+        match handle {
+            Some(handle) => {
+                handle.abort();
+                let resp = rpc::Response::Error(Error::ECANCELED);
+                Bfffsd::respond(peer, victim, resp).await;
+                Ok(())
+            }
+            None => Err(Error::ENOENT),
+        }
+    }
+
+    async fn handle_client(
+        self: Arc<Self>,
+        peer: Arc<dyn Peer>,
+        client: Result<Client>,
+    ) {
+        let _reservation = match &self.memory_budget {
+            Some(budget) => Some(budget.reserve(Consumer::Rpc, BUFSIZ).await),
+            None => None,
+        };
         let mut buf = vec![0u8; BUFSIZ];
+        let inflight = Inflight::default();
 
         loop {
-            let nread = peer.recv(&mut buf).await.unwrap();
+            let nread = match peer.recv(&mut buf).await {
+                Ok(nread) => nread,
+                Err(e) => {
+                    warn!("Error receiving client request: {e}");
+                    break;
+                }
+            };
             if nread == 0 {
                 // Client disconnected normally
                 break;
             } else if nread >= BUFSIZ {
                 warn!("Client sent unexpectedly large request");
                 break;
-            } else {
-                buf.truncate(nread);
-                let req: rpc::Request = bincode::deserialize(&buf[..]).unwrap();
-                let creds = peer.peer_cred().unwrap();
-                let resp = self.process_rpc(req, creds).await;
-                let encoded: Vec<u8> = bincode::serialize(&resp).unwrap();
-                let nwrite = peer.send(&encoded).await;
-                if nwrite.is_err() || nwrite.unwrap() != encoded.len() {
-                    warn!("Client disconnected before reading response");
-                    break;
+            }
+            let packet = &buf[..nread];
+            type Packet = (rpc::RequestId, Option<rpc::AuthHash>, rpc::Request);
+            match rpc::decode::<Packet>(packet) {
+                Ok((id, _auth, rpc::Request::Cancel(victim))) => {
+                    let r = Bfffsd::cancel(&peer, &inflight, victim).await;
+                    Bfffsd::respond(&peer, id, rpc::Response::Cancel(r)).await;
+                }
+                Ok((id, auth, req)) => match client {
+                    Ok(client) => {
+                        self.spawn_rpc(&peer, &inflight, id, req, client, auth);
+                    }
+                    Err(e) => {
+                        let resp = rpc::Response::Error(e);
+                        Bfffsd::respond(&peer, id, resp).await;
+                    }
+                },
+                Err(e) => {
+                    warn!("Client sent malformed request: {e:?}");
+                    // Reply to whatever ID the packet seems to have
+                    let id = bincode::deserialize(packet).unwrap_or(0);
+                    let resp = rpc::Response::Error(e);
+                    Bfffsd::respond(&peer, id, resp).await;
                 }
             }
-            // XXX The resize operation can be eliminated after
-            // tokio-seqpacket-rs gains support for Rust's read_buf feature.
-            buf.resize(BUFSIZ, 0);
+        }
+        // The client can't receive any more responses, so don't bother
+        // finishing its requests.
+        for (_, handle) in inflight.lock().unwrap().iter() {
+            handle.abort();
         }
     }
 
     async fn new(cli: Cli) -> Self {
+        let mut background_rate: Option<u64> = None;
         let mut cache_size: Option<usize> = None;
+        let mut fuse_inflight: Option<usize> = None;
+        let mut fuse_session_inflight: Option<usize> = None;
+        let mut fuse_workers = false;
+        let mut memory_limit: Option<usize> = None;
+        let mut metadata_reserve: Option<f32> = None;
+        let mut mountpoint_mode = 0o755;
+        let mut open_zone_budget: Option<u32> = None;
+        let mut readonly = false;
+        let mut rewind = false;
         let mut writeback_size: Option<usize> = None;
 
-        let mut mount_opts = MountOptions::default();
-        mount_opts.fs_name("bfffs");
-        if nix::unistd::getuid().is_root() {
-            mount_opts.allow_other(true);
-            mount_opts.default_permissions(true);
-        }
-        mount_opts.no_open_support(true);
-        mount_opts.no_open_dir_support(true);
+        let auth = cli.auth_token.as_ref().map(|path| {
+            let md = std::fs::metadata(path).unwrap_or_else(|e| {
+                eprintln!("Cannot read auth token {}: {e}", path.display());
+                exit(1);
+            });
+            if md.permissions().mode() & 0o004 != 0 {
+                warn!("Auth token {} is world-readable", path.display());
+            }
+            let token = std::fs::read(path).unwrap_or_else(|e| {
+                eprintln!("Cannot read auth token {}: {e}", path.display());
+                exit(1);
+            });
+            rpc::auth_hash(&token)
+        });
+
+        let mut mount_opts = MountOpts {
+            allow_other: nix::unistd::getuid().is_root(),
+            ..Default::default()
+        };
         // Unconditionally disable the kernel's buffer cache; BFFFS has its own
         mount_opts.custom_options("direct_io");
         for o in cli.options.iter() {
             if let Some((name, value)) = o.split_once('=') {
-                if name == "cache_size" {
+                if name == "background_rate" {
+                    let v = value.parse().unwrap_or_else(|_| {
+                        eprintln!("background_rate must be numeric");
+                        exit(2);
+                    });
+                    background_rate = Some(v);
+                    continue;
+                } else if name == "cache_size" {
                     let v = value.parse().unwrap_or_else(|_| {
                         eprintln!("cache_size must be numeric");
                         exit(2);
                     });
                     cache_size = Some(v);
                     continue;
+                } else if name == "fuse_inflight" {
+                    let v = value.parse().unwrap_or_else(|_| {
+                        eprintln!("fuse_inflight must be numeric");
+                        exit(2);
+                    });
+                    fuse_inflight = Some(v);
+                    continue;
+                } else if name == "fuse_session_inflight" {
+                    let v = value.parse().unwrap_or_else(|_| {
+                        eprintln!("fuse_session_inflight must be numeric");
+                        exit(2);
+                    });
+                    fuse_session_inflight = Some(v);
+                    continue;
+                } else if name == "fuse_workers" {
+                    fuse_workers = match value {
+                        "on" => true,
+                        "off" => false,
+                        _ => {
+                            eprintln!("fuse_workers must be \"on\" or \"off\"");
+                            exit(2);
+                        }
+                    };
+                    continue;
+                } else if name == "memory_limit" {
+                    let v = value.parse().unwrap_or_else(|_| {
+                        eprintln!("memory_limit must be numeric");
+                        exit(2);
+                    });
+                    memory_limit = Some(v);
+                    continue;
+                } else if name == "metadata_reserve" {
+                    let v = value
+                        .parse()
+                        .ok()
+                        .filter(|f| (0.0..=1.0).contains(f))
+                        .unwrap_or_else(|| {
+                            eprintln!(
+                                "metadata_reserve must be between 0 and 1"
+                            );
+                            exit(2);
+                        });
+                    metadata_reserve = Some(v);
+                    continue;
+                } else if name == "mountpoint_mode" {
+                    mountpoint_mode = u32::from_str_radix(value, 8)
+                        .ok()
+                        .filter(|m| *m <= 0o7777)
+                        .unwrap_or_else(|| {
+                            eprintln!("mountpoint_mode must be an octal mode");
+                            exit(2);
+                        });
+                    continue;
+                } else if name == "open_zone_budget" {
+                    let v = value
+                        .parse()
+                        .ok()
+                        .filter(|n| *n > 0)
+                        .unwrap_or_else(|| {
+                            eprintln!(
+                                "open_zone_budget must be a positive integer"
+                            );
+                            exit(2);
+                        });
+                    open_zone_budget = Some(v);
+                    continue;
+                } else if name == "readonly" {
+                    readonly = match value {
+                        "on" => true,
+                        "off" => false,
+                        _ => {
+                            eprintln!("readonly must be \"on\" or \"off\"");
+                            exit(2);
+                        }
+                    };
+                    continue;
+                } else if name == "rewind_to_checkpoint" {
+                    rewind = match value {
+                        "on" => true,
+                        "off" => false,
+                        _ => {
+                            eprintln!(
+                                "rewind_to_checkpoint must be \"on\" or \"off\""
+                            );
+                            exit(2);
+                        }
+                    };
+                    continue;
                 } else if name == "writeback_size" {
                     let v = value.parse().unwrap_or_else(|_| {
                         eprintln!("writeback_size must be numeric");
@@ -176,17 +622,42 @@ impl Bfffsd {
             mount_opts.custom_options(o);
         }
 
+        if readonly {
+            // Import without writing anything, and mount everything read-only
+            mount_opts.read_only = true;
+        }
+
         let mut dev_manager = DevManager::default();
         if let Some(cs) = cache_size {
             dev_manager.cache_size(cs);
         }
+        if let Some(rate) = background_rate {
+            dev_manager.background_rate(rate);
+        }
+        let memory_budget = memory_limit.map(MemoryBudget::new);
+        if let Some(budget) = &memory_budget {
+            dev_manager.memory_budget(budget.clone());
+        }
+        if let Some(fraction) = metadata_reserve {
+            dev_manager.metadata_reserve(fraction);
+        }
+        if let Some(budget) = open_zone_budget {
+            dev_manager.open_zone_budget(budget);
+        }
+        dev_manager.force(cli.force);
+        dev_manager.readonly(readonly);
+        dev_manager.rewind_to_checkpoint(rewind);
         if let Some(wbs) = writeback_size {
             dev_manager.writeback_size(wbs);
         }
 
-        for dev in cli.devices.iter() {
-            // TODO: taste devices in parallel
-            dev_manager.taste(dev).await.unwrap();
+        dev_manager.cachefile(&cli.cachefile);
+
+        if !dev_manager.taste_cached(&cli.pool_name).await {
+            for dev in cli.devices.iter() {
+                // TODO: taste devices in parallel
+                dev_manager.taste(dev).await.unwrap();
+            }
         }
 
         let uuid = dev_manager
@@ -198,38 +669,282 @@ impl Bfffsd {
                 std::process::exit(1);
             })
             .1;
-        let db = dev_manager.import_by_uuid(uuid).await.unwrap();
-        let controller = Controller::new(db);
+        let db = dev_manager.import_by_uuid(uuid).await.unwrap_or_else(|e| {
+            eprintln!("error: cannot import pool {}: {e:?}", cli.pool_name);
+            if e == Error::EBUSY {
+                eprintln!(
+                    "It may be in use by another host.  Use --force to \
+                     import it anyway."
+                );
+            }
+            std::process::exit(1);
+        });
+        let controller = Arc::new(Controller::new(db));
+        let controller2 = controller.clone();
+        let p9 = p9::Server::new(Box::new(move |name: String| {
+            let controller = controller2.clone();
+            async move { controller.new_fs(&name).await.map_err(i32::from) }
+                .boxed()
+        }));
+        let controller2 = controller.clone();
+        let iscsi = iscsi::Server::new(Box::new(move |name: String| {
+            let controller = controller2.clone();
+            async move { controller.new_fs(&name).await.map_err(i32::from) }
+                .boxed()
+        }));
+        let workers = fuse_workers.then(|| {
+            let controller2 = controller.clone();
+            let open = Box::new(move |name: String| {
+                let controller = controller2.clone();
+                async move {
+                    controller.new_fs(&name).await.map_err(i32::from)
+                }
+                .boxed()
+            });
+            worker::Workers::new(open, fuse_session_inflight)
+        });
 
         Bfffsd {
+            auth,
             controller,
-            _dev_manager: dev_manager,
+            dev_manager,
+            fuse_budget: fuse_inflight.map(ReadBudget::new),
+            fuse_limit: fuse_session_inflight,
+            iscsi,
+            jails: Mutex::default(),
+            memory_budget,
             mount_opts,
+            mount_gen: AtomicU64::new(0),
+            mounts: Mounts::default(),
+            mountpoint_mode,
+            p9,
+            pool_name: cli.pool_name,
+            readonly,
+            shutdown: tokio::sync::Notify::new(),
+            watches: Arc::default(),
+            workers,
         }
     }
 
+    /// List every dataset in the pool, parents before children
+    async fn datasets(&self) -> Result<Vec<String>> {
+        let mut names = vec![self.pool_name.clone()];
+        let mut i = 0;
+        while i < names.len() {
+            let children = self
+                .controller
+                .list_fs(&names[i], None)
+                .map_ok(|de| de.name)
+                .try_collect::<Vec<_>>()
+                .await?;
+            names.extend(children);
+            i += 1;
+        }
+        Ok(names)
+    }
+
+    /// Delegate a file system to a jail
+    async fn jail(&self, name: String, jid: i32) -> Result<()> {
+        if jid <= 0 {
+            return Err(Error::EINVAL);
+        }
+        // Fail if the file system doesn't exist
+        self.controller.get_prop(name.clone(), PropertyName::Atime).await?;
+        match self.jails.lock().unwrap().entry(name) {
+            Entry::Occupied(_) => Err(Error::EEXIST),
+            Entry::Vacant(v) => {
+                v.insert(jid);
+                Ok(())
+            }
+        }
+    }
+
+    /// Mount a file system.  It may not already be mounted, unless `opts`
+    /// includes "remount".
     #[tracing::instrument(skip(self))]
-    #[cfg_attr(test, allow(unused_variables))]
-    async fn mount(&self, name: String) -> Result<MountHandle> {
-        let mo2 = self.mount_opts.clone();
-        let mp = self
+    async fn mount(
+        &self,
+        name: String,
+        mountpoint: Option<String>,
+        opts: &str,
+        uid: u32,
+    ) -> Result<()> {
+        if opts.split(',').any(|o| o == "remount") {
+            return self.remount_one(&name, mountpoint).await;
+        }
+        let mut mo2 = self.mount_opts.clone();
+        let mp = match mountpoint {
+            Some(mp) => PathBuf::from(mp),
+            None => self.mountpoint(&name).await?,
+        };
+        let popts = self.prop_mount_options(&name).await?;
+        for o in popts.iter() {
+            mo2.custom_options(*o);
+        }
+
+        // Claim the name before mounting, so two clients can't both mount it
+        let generation = self.mount_gen.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut guard = self.mounts.lock().unwrap();
+            if guard.contains_key(&name) {
+                return Err(Error::EBUSY);
+            }
+            let mounted = Mounted {
+                mountpoint: mp.clone(),
+                opts: popts,
+                uid,
+                generation,
+            };
+            guard.insert(name.clone(), mounted);
+        }
+
+        let r = async {
+            if !mp.exists() {
+                DirBuilder::new()
+                    .recursive(true)
+                    .mode(self.mountpoint_mode)
+                    .create(&mp)?;
+            }
+            tracing::debug!("mounting {:?}", mp);
+            self.mount_fs(&name, mo2, mp).await
+        }
+        .await;
+        match r {
+            Ok(unmounted) => {
+                match self.controller.record_mount(&name).await {
+                    Ok(true) => (),
+                    Ok(false) => {
+                        warn!("{name} was not cleanly unmounted last time");
+                    }
+                    Err(e) => error!("Cannot record mount of {name}: {e:?}"),
+                }
+                // Completes once the file system gets unmounted, whether by
+                // bfffsd or by somebody else.
+                let controller = self.controller.clone();
+                let mounts = self.mounts.clone();
+                tokio::spawn(async move {
+                    unmounted.await;
+                    forget_mount(&mounts, &name, generation);
+                    if let Err(e) = controller.record_unmount(&name).await {
+                        error!("Cannot record unmount of {name}: {e:?}");
+                    }
+                });
+                Ok(())
+            }
+            Err(e) => {
+                forget_mount(&self.mounts, &name, generation);
+                Err(e)
+            }
+        }
+    }
+
+    /// Mount every file system in the pool that isn't already mounted, parents
+    /// before children.  Those with `canmount=off` are skipped.
+    async fn mount_all(&self, opts: &str, uid: u32) -> rpc::fs::BulkResult {
+        let names = match self.datasets().await {
+            Ok(names) => names,
+            Err(e) => return vec![(self.pool_name.clone(), Err(e))],
+        };
+        let mut results = Vec::new();
+        for name in names.into_iter() {
+            if self.mounts.lock().unwrap().contains_key(&name) {
+                continue;
+            }
+            let r = async {
+                let (volsize, _) = self
+                    .controller
+                    .get_prop(name.clone(), PropertyName::Volsize)
+                    .await?;
+                let (canmount, _) = self
+                    .controller
+                    .get_prop(name.clone(), PropertyName::CanMount)
+                    .await?;
+                Ok::<_, Error>(volsize.as_u64() == 0 && canmount.as_bool())
+            }
+            .await;
+            let r = match r {
+                Ok(true) => self.mount(name.clone(), None, opts, uid).await,
+                Ok(false) => continue,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = &r {
+                error!("mount {name}: {e:?}");
+            }
+            results.push((name, r));
+        }
+        results
+    }
+
+    /// Look up a dataset's mountpoint property.  If it has none, default to
+    /// "/" plus the dataset's name, which includes the pool.
+    async fn mountpoint(&self, name: &str) -> Result<PathBuf> {
+        let r = self
             .controller
-            .get_prop(name.clone(), PropertyName::Mountpoint)
-            .map_ok(|(prop, _source)| PathBuf::from(prop.as_str()))
-            .await?;
-        tracing::debug!("mounting {:?}", mp);
+            .get_prop(name.to_owned(), PropertyName::Mountpoint)
+            .await;
+        match r {
+            Ok((prop, _source)) => Ok(PathBuf::from(prop.as_str())),
+            Err(Error::ENOENT) => Err(Error::ENOENT),
+            Err(e) => {
+                tracing::debug!("no mountpoint for {name}: {e:?}");
+                Ok(Path::new("/").join(name))
+            }
+        }
+    }
+
+    /// Build a hook that reports close-after-write events for a file system
+    /// mounted at `root`.
+    fn close_hook(&self, name: &str, root: &Path) -> CloseHook {
+        let dataset = name.to_owned();
+        let root = root.to_owned();
+        let watches = self.watches.clone();
+        Arc::new(move |ino: u64, path: Option<PathBuf>| {
+            let event = rpc::fs::CloseEvent {
+                dataset: dataset.clone(),
+                ino,
+                path: path
+                    .map(|p| root.join(p).to_string_lossy().into_owned()),
+            };
+            let watches = watches.clone();
+            async move { watches.closed(event).await }.boxed()
+        })
+    }
+
+    /// Mount a file system.  The returned future completes once it's
+    /// unmounted.
+    #[cfg_attr(test, allow(unused_variables))]
+    async fn mount_fs(&self, name: &str, mo2: MountOpts, mp: PathBuf)
+        -> Result<BoxFuture<'static, ()>>
+    {
+        if let Some(workers) = &self.workers {
+            let hook = self.close_hook(name, &mp);
+            return workers.mount(name, mo2, mp, hook).await;
+        }
         cfg_if! {
             if #[cfg(test)] {
-                Session::new(mo2).mount(FuseFs::default(), mp)
+                let fusefs = FuseFs::<bfffs_fuse::mock::MockFs>::default();
+                Session::new(mo2.to_fuse()).mount(fusefs, mp)
+                    .map_ok(|handle| handle.map(drop).boxed())
                     .map_err(Error::from)
                     .await
             } else {
-                self.controller.new_fs(&name)
+                self.controller.new_fs(name)
                     .and_then(|fs| {
-                        let fusefs = FuseFs::new(fs);
-                        Session::new(mo2).mount(fusefs, mp)
+                        let mut fusefs = FuseFs::new(fs);
+                        if let Some(limit) = self.fuse_limit {
+                            fusefs.session_read_limit(limit);
+                        }
+                        if let Some(budget) = &self.fuse_budget {
+                            fusefs.global_read_budget(budget.clone());
+                        }
+                        if let Some(budget) = &self.memory_budget {
+                            fusefs.memory_budget(budget.clone());
+                        }
+                        fusefs.close_hook(self.close_hook(name, &mp));
+                        Session::new(mo2.to_fuse()).mount(fusefs, mp)
+                            .map_ok(|handle| handle.map(drop).boxed())
                             .map_err(|e| {
-                                tracing::debug!("mount failed: {}", e);
+                                tracing::debug!("mount failed: {e}");
                                 Error::from(e)
                             })
                     })
@@ -238,22 +953,68 @@ impl Bfffsd {
         }
     }
 
+    /// Format the requested devices as a new cluster, and add it to the pool.
+    async fn pool_add(&self, req: rpc::pool::Add) -> Result<()> {
+        let cluster = self
+            .dev_manager
+            .create_cluster(
+                &req.pool,
+                req.disks_per_stripe,
+                req.redundancy,
+                &req.mirrors,
+            )
+            .await?;
+        self.controller.add_cluster(&req.pool, cluster).await?;
+        let devices = req.mirrors.into_iter().flatten().collect();
+        self.dev_manager.record_cluster(&req.pool, devices)
+    }
+
     async fn process_rpc(
         &self,
         req: rpc::Request,
-        creds: UCred,
+        client: Client,
+        auth: Option<rpc::AuthHash>,
     ) -> rpc::Response {
+        let privileged = self.authorized(&client, auth);
         match req {
+            rpc::Request::Cancel(_) => {
+                // handle_client takes care of these, since it must know about
+                // the client's other requests.
+                rpc::Response::Cancel(Err(Error::EINVAL))
+            }
+            rpc::Request::DaemonPing => rpc::Response::DaemonPing(Ok(())),
+            rpc::Request::DebugCloseFile(req) => {
+                if !privileged {
+                    rpc::Response::DebugCloseFile(Err(Error::EPERM))
+                } else {
+                    let r = self.controller.close_file(&req.name, req.fh).await;
+                    rpc::Response::DebugCloseFile(r)
+                }
+            }
+            rpc::Request::DebugCompact(req) => {
+                if !privileged {
+                    rpc::Response::DebugCompact(Err(Error::EPERM))
+                } else {
+                    let r = self.controller.compact_fs(&req.name).await;
+                    rpc::Response::DebugCompact(r)
+                }
+            }
             rpc::Request::DebugDropCache => {
-                if creds.uid() != unistd::geteuid().as_raw() {
+                if !privileged {
                     rpc::Response::FsMount(Err(Error::EPERM))
                 } else {
                     self.controller.drop_cache();
                     rpc::Response::DebugDropCache(Ok(()))
                 }
             }
+            rpc::Request::DebugOpenFiles(req) => {
+                let r = self.controller.open_files(&req.name).await;
+                rpc::Response::DebugOpenFiles(r)
+            }
             rpc::Request::FsCreate(req) => {
-                if creds.uid() != unistd::geteuid().as_raw() {
+                let delegated = self.delegated(&client, &req.name, true) &&
+                    !req.props.iter().any(host_property);
+                if !privileged && !delegated {
                     rpc::Response::FsMount(Err(Error::EPERM))
                 } else {
                     let r = self
@@ -270,17 +1031,73 @@ impl Bfffsd {
                                 .map_ok(move |_| tree_id)
                         })
                         .await;
+                    if r.is_ok() {
+                        // The new file system may have inherited share9p
+                        if let Err(e) = self.reshare().await {
+                            error!("reshare: {:?}", e);
+                        }
+                    }
                     rpc::Response::FsCreate(r)
                 }
             }
             rpc::Request::FsDestroy(req) => {
-                if creds.uid() != unistd::geteuid().as_raw() {
+                if !privileged && !self.delegated(&client, &req.name, true) {
                     rpc::Response::FsMount(Err(Error::EPERM))
+                } else if req.dry_run {
+                    let r = self.controller.destroy_fs_plan(&req.name).await;
+                    rpc::Response::FsDestroy(r)
                 } else {
-                    let r = self.controller.destroy_fs(&req.name).await;
+                    let r = self
+                        .controller
+                        .destroy_fs(&req.name)
+                        .await
+                        .map(|_| vec![req.name]);
+                    if let Ok(v) = &r {
+                        // Forget delegations of anything that was destroyed
+                        let prefix = format!("{}/", v[0]);
+                        self.jails.lock().unwrap().retain(|ds, _| {
+                            *ds != v[0] && !ds.starts_with(&prefix)
+                        });
+                        if let Err(e) = self.reshare().await {
+                            error!("reshare: {:?}", e);
+                        }
+                    }
                     rpc::Response::FsDestroy(r)
                 }
             }
+            rpc::Request::FsEvents(req) => {
+                if !privileged {
+                    rpc::Response::FsEvents(Err(Error::EPERM))
+                } else {
+                    let r = self.watches.events(req.id).await;
+                    rpc::Response::FsEvents(r)
+                }
+            }
+            rpc::Request::FsFreeze(req) => {
+                if !privileged {
+                    rpc::Response::FsFreeze(Err(Error::EPERM))
+                } else {
+                    let r = self.controller.freeze_fs(&req.name).await;
+                    rpc::Response::FsFreeze(r)
+                }
+            }
+            rpc::Request::FsIostat(req) => {
+                if req.reset && !privileged {
+                    rpc::Response::FsIostat(Err(Error::EPERM))
+                } else {
+                    let r =
+                        self.controller.io_stats(&req.name, req.reset).await;
+                    rpc::Response::FsIostat(r)
+                }
+            }
+            rpc::Request::FsJail(req) => {
+                if !privileged {
+                    rpc::Response::FsJail(Err(Error::EPERM))
+                } else {
+                    let r = self.jail(req.name, req.jid).await;
+                    rpc::Response::FsJail(r)
+                }
+            }
             rpc::Request::FsList(req) => {
                 // this value of chunkqty is a guess, not well-calculated
                 const CHUNKQTY: usize = 64;
@@ -299,22 +1116,12 @@ impl Bfffsd {
                         // https://github.com/rust-lang/rust/issues/64552
                         v.into_iter()
                             .map(|de| {
-                                req.props
-                                    .iter()
-                                    .map(|propname| {
-                                        let name = de.name.clone();
-                                        self.controller
-                                            .get_prop(name, *propname)
-                                    })
-                                    .collect::<FuturesOrdered<_>>()
-                                    .try_collect::<Vec<_>>()
-                                    .map_ok(move |props| {
-                                        rpc::fs::DsInfo {
-                                            name: de.name,
-                                            props,
-                                            offset: de.offs,
-                                        }
-                                    })
+                                self.dsinfo(
+                                    de.name,
+                                    &req.props,
+                                    req.user_props,
+                                    de.offs,
+                                )
                             })
                             .collect::<FuturesOrdered<_>>()
                             .try_collect::<Vec<_>>()
@@ -326,10 +1133,18 @@ impl Bfffsd {
                 rpc::Response::FsList(r)
             }
             rpc::Request::FsMount(req) => {
-                if creds.uid() != unistd::geteuid().as_raw() {
+                if !privileged {
                     rpc::Response::FsMount(Err(Error::EPERM))
                 } else {
-                    match self.mount(req.name).await {
+                    let r = self
+                        .mount(
+                            req.name,
+                            req.mountpoint,
+                            &req.opts,
+                            client.uid,
+                        )
+                        .await;
+                    match r {
                         Ok(_) => rpc::Response::FsMount(Ok(())),
                         Err(e) => {
                             error!("mount: {:?}", e);
@@ -338,11 +1153,49 @@ impl Bfffsd {
                     }
                 }
             }
+            rpc::Request::FsMountAll(req) => {
+                let r = if !privileged {
+                    Err(Error::EPERM)
+                } else if req.pool != self.pool_name {
+                    Err(Error::ENOENT)
+                } else {
+                    Ok(self.mount_all(&req.opts, client.uid).await)
+                };
+                rpc::Response::FsMountAll(r)
+            }
+            rpc::Request::FsMounts => {
+                let mounts = self
+                    .mounts
+                    .lock()
+                    .unwrap()
+                    .iter()
+                    .map(|(name, m)| m.info(name))
+                    .collect::<Vec<_>>();
+                rpc::Response::FsMounts(Ok(mounts))
+            }
+            rpc::Request::FsMove(req) => {
+                if !privileged {
+                    rpc::Response::FsMove(Err(Error::EPERM))
+                } else {
+                    let r = self
+                        .controller
+                        .move_file(
+                            &req.src_ds,
+                            &req.src_path,
+                            &req.dst_ds,
+                            &req.dst_path,
+                        )
+                        .await;
+                    rpc::Response::FsMove(r)
+                }
+            }
             rpc::Request::FsSet(req) => {
-                if creds.uid() != unistd::geteuid().as_raw() {
+                let delegated = self.delegated(&client, &req.name, false) &&
+                    !req.props.iter().any(host_property);
+                if !privileged && !delegated {
                     rpc::Response::FsSet(Err(Error::EPERM))
                 } else {
-                    match self.set(&req.name, req.props).await {
+                    match self.set(&req.name, req.props, req.user_props).await {
                         Ok(_) => rpc::Response::FsSet(Ok(())),
                         Err(e) => {
                             error!("set: {:?}", e);
@@ -352,27 +1205,29 @@ impl Bfffsd {
                 }
             }
             rpc::Request::FsStat(req) => {
-                let r = req
-                    .props
-                    .iter()
-                    .map(|propname| {
-                        let name = req.name.clone();
-                        self.controller.get_prop(name, *propname)
-                    })
-                    .collect::<FuturesOrdered<_>>()
-                    .try_collect::<Vec<_>>()
-                    .map_ok(move |props| {
-                        rpc::fs::DsInfo {
-                            name: req.name,
-                            props,
-                            offset: 0,
-                        }
-                    })
+                let r = self
+                    .dsinfo(req.name, &req.props, req.user_props, 0)
                     .await;
                 rpc::Response::FsStat(r)
             }
+            rpc::Request::FsThaw(req) => {
+                if !privileged {
+                    rpc::Response::FsThaw(Err(Error::EPERM))
+                } else {
+                    let r = self.controller.thaw_fs(&req.name).await;
+                    rpc::Response::FsThaw(r)
+                }
+            }
+            rpc::Request::FsUnjail(req) => {
+                if !privileged {
+                    rpc::Response::FsUnjail(Err(Error::EPERM))
+                } else {
+                    let r = self.unjail(&req.name, req.jid);
+                    rpc::Response::FsUnjail(r)
+                }
+            }
             rpc::Request::FsUnmount(req) => {
-                if creds.uid() != unistd::geteuid().as_raw() {
+                if !privileged {
                     rpc::Response::FsUnmount(Err(Error::EPERM))
                 } else {
                     match self.unmount(&req.name, req.force).await {
@@ -384,48 +1239,658 @@ impl Bfffsd {
                     }
                 }
             }
+            rpc::Request::FsUnmountAll(req) => {
+                let r = if !privileged {
+                    Err(Error::EPERM)
+                } else if req.pool != self.pool_name {
+                    Err(Error::ENOENT)
+                } else {
+                    Ok(self.unmount_all(req.force).await)
+                };
+                rpc::Response::FsUnmountAll(r)
+            }
+            rpc::Request::FsUnwatch(req) => {
+                if !privileged {
+                    rpc::Response::FsUnwatch(Err(Error::EPERM))
+                } else {
+                    rpc::Response::FsUnwatch(self.watches.unwatch(req.id))
+                }
+            }
+            rpc::Request::FsWatch(req) => {
+                if !privileged {
+                    rpc::Response::FsWatch(Err(Error::EPERM))
+                } else {
+                    // Fail if the file system doesn't exist
+                    let r = self
+                        .controller
+                        .get_prop(req.name.clone(), PropertyName::Atime)
+                        .await
+                        .map(|_| self.watches.watch(req.name, req.sync));
+                    rpc::Response::FsWatch(r)
+                }
+            }
+            rpc::Request::JobList => {
+                rpc::Response::JobList(Ok(self.controller.job_list()))
+            }
+            rpc::Request::JobStatus(req) => {
+                rpc::Response::JobStatus(self.controller.job_status(req.id))
+            }
+            rpc::Request::PoolAdd(req) => {
+                let r = if !privileged {
+                    Err(Error::EPERM)
+                } else {
+                    self.pool_add(req).await
+                };
+                rpc::Response::PoolAdd(r)
+            }
+            rpc::Request::PoolCheckpoint(req) => {
+                if !privileged {
+                    rpc::Response::PoolCheckpoint(Err(Error::EPERM))
+                } else {
+                    let r = self
+                        .controller
+                        .checkpoint(&req.pool, req.discard)
+                        .await;
+                    rpc::Response::PoolCheckpoint(r)
+                }
+            }
             rpc::Request::PoolClean(req) => {
-                if creds.uid() != unistd::geteuid().as_raw() {
+                if !privileged {
                     rpc::Response::PoolClean(Err(Error::EPERM))
                 } else {
-                    let r = self.controller.clean(&req.pool).map(drop);
+                    let r = self.controller.clean_plan(&req.pool, req.policy);
+                    let r = if req.dry_run {
+                        r.map(|stats| (stats, None))
+                    } else {
+                        r.and_then(|stats| {
+                            self.controller
+                                .clean(&req.pool, req.policy)
+                                .map(|(id, _rx)| (stats, Some(id)))
+                        })
+                    };
                     rpc::Response::PoolClean(r)
                 }
             }
+            rpc::Request::PoolDefrag(req) => {
+                if !privileged {
+                    rpc::Response::PoolDefrag(Err(Error::EPERM))
+                } else {
+                    let r = self.controller.defrag_plan(&req.pool).await;
+                    let r = if req.dry_run {
+                        r.map(|stats| (stats, None))
+                    } else {
+                        r.and_then(|stats| {
+                            self.controller
+                                .defrag(&req.pool)
+                                .map(|id| (stats, Some(id)))
+                        })
+                    };
+                    rpc::Response::PoolDefrag(r)
+                }
+            }
+            rpc::Request::PoolErrors(req) => {
+                let r = self.controller.data_errors(&req.pool).await;
+                rpc::Response::PoolErrors(r)
+            }
+            rpc::Request::PoolExport(req) => {
+                let r = if !privileged {
+                    Err(Error::EPERM)
+                } else if req.pool != self.pool_name {
+                    Err(Error::ENOENT)
+                } else {
+                    let results = self.unmount_all(req.force).await;
+                    if results.iter().all(|(_, r)| r.is_ok()) {
+                        // spawn_rpc will shut down once the response is sent
+                        self.controller
+                            .sync_transaction()
+                            .await
+                            .map(|_| results)
+                    } else {
+                        Ok(results)
+                    }
+                };
+                rpc::Response::PoolExport(r)
+            }
+            rpc::Request::PoolOnline(req) => {
+                if !privileged {
+                    rpc::Response::PoolOnline(Err(Error::EPERM))
+                } else {
+                    let r = self.controller.online(&req.pool, req.dev).await;
+                    rpc::Response::PoolOnline(r)
+                }
+            }
+            rpc::Request::PoolPlan(req) => {
+                let r = self.controller.capacity_plan(&req.pool, &req.change);
+                rpc::Response::PoolPlan(r)
+            }
+            rpc::Request::PoolSet(req) => {
+                if !privileged {
+                    rpc::Response::PoolSet(Err(Error::EPERM))
+                } else {
+                    let r = self
+                        .controller
+                        .set_pool_props(&req.pool, req.props)
+                        .await;
+                    rpc::Response::PoolSet(r)
+                }
+            }
+            rpc::Request::PoolStatus(req) => {
+                let r = self.controller.status(&req.pool);
+                rpc::Response::PoolStatus(r)
+            }
+            rpc::Request::PoolTxgs(req) => {
+                let r = self.controller.txgs(&req.pool).await;
+                rpc::Response::PoolTxgs(r)
+            }
+            rpc::Request::PoolUpgrade(req) => {
+                if !privileged {
+                    rpc::Response::PoolUpgrade(Err(Error::EPERM))
+                } else {
+                    let r =
+                        self.controller.upgrade(&req.pool, &req.features).await;
+                    rpc::Response::PoolUpgrade(r)
+                }
+            }
+            rpc::Request::VolumeCreate(req) => {
+                if !privileged {
+                    rpc::Response::VolumeCreate(Err(Error::EPERM))
+                } else {
+                    let r = self
+                        .controller
+                        .create_volume(&req.name, req.size, req.props)
+                        .await;
+                    if r.is_ok() {
+                        // The new volume may have set or inherited shareiscsi
+                        if let Err(e) = self.reshare().await {
+                            error!("reshare: {:?}", e);
+                        }
+                    }
+                    rpc::Response::VolumeCreate(r)
+                }
+            }
         }
     }
 
     async fn run(self: Arc<Self>, mut sock: Socket) {
         loop {
-            let peer = sock.listener.accept().await.unwrap();
-            tokio::spawn(self.clone().handle_client(peer));
+            match sock.listener.accept().await {
+                Ok(peer) => {
+                    let client = Client::local(&peer);
+                    let peer = Arc::new(peer);
+                    tokio::spawn(self.clone().handle_client(peer, client));
+                }
+                Err(e) => error!("Error accepting client connection: {e}"),
+            }
+        }
+    }
+
+    /// Serve clients who connect over TLS.
+    ///
+    /// The handshake verifies each client's certificate, so any client that
+    /// completes it may make privileged requests.
+    #[cfg(feature = "tls")]
+    async fn run_tls(
+        self: Arc<Self>,
+        listener: tokio::net::TcpListener,
+        acceptor: tokio_rustls::TlsAcceptor,
+    ) {
+        loop {
+            let (stream, addr) = match listener.accept().await {
+                Ok(conn) => conn,
+                Err(e) => {
+                    error!("Error accepting TLS connection: {e}");
+                    continue;
+                }
+            };
+            let bfffsd = self.clone();
+            let acceptor = acceptor.clone();
+            // Handshake in the background, so a slow client can't block
+            // others.
+            tokio::spawn(async move {
+                match acceptor.accept(stream).await {
+                    Ok(stream) => {
+                        let peer = bfffs::transport::FramedPeer::new(stream);
+                        let peer = Arc::new(peer);
+                        let client = Ok(Client::remote());
+                        bfffsd.handle_client(peer, client).await;
+                    }
+                    Err(e) => warn!("TLS handshake with {addr} failed: {e}"),
+                }
+            });
+        }
+    }
+
+    /// Mount options implied by the dataset's properties
+    async fn prop_mount_options(&self, name: &str)
+        -> Result<Vec<&'static str>>
+    {
+        let mut opts = Vec::new();
+        for (propname, opt) in [
+            (PropertyName::Devices, "nodev"),
+            (PropertyName::Exec, "noexec"),
+            (PropertyName::Setuid, "nosuid"),
+        ] {
+            let (prop, _source) =
+                self.controller.get_prop(name.to_owned(), propname).await?;
+            if !prop.as_bool() {
+                opts.push(opt);
+            }
         }
+        Ok(opts)
     }
 
-    async fn set(&self, name: &str, props: Vec<Property>) -> Result<()> {
+    /// Reapply property-derived mount options to the named file system and
+    /// all of its mounted descendants, which may have inherited them.
+    async fn remount(&self, name: &str) -> Result<()> {
+        let prefix = format!("{name}/");
+        let mounts = self
+            .mounts
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(dsname, _)| *dsname == name || dsname.starts_with(&prefix))
+            .map(|(dsname, m)| (dsname.clone(), m.mountpoint.clone()))
+            .collect::<Vec<_>>();
+        for (dsname, mp) in mounts.into_iter() {
+            let opts = self.prop_mount_options(&dsname).await?;
+            let opts2 = opts.clone();
+            let mut flags = MntFlags::MNT_UPDATE;
+            if self.readonly {
+                flags.insert(MntFlags::MNT_RDONLY);
+            }
+            // Like unmount(2), nmount(2) may block waiting for the daemon.
+            tokio::task::spawn_blocking(move || {
+                let mut nmount = Nmount::new();
+                nmount.str_opt_owned("fspath", mp.as_path());
+                for o in opts.into_iter() {
+                    nmount.null_opt_owned(o);
+                }
+                nmount.nmount(flags).map_err(|e| Error::from(e.errno()))
+            })
+            .await
+            .unwrap()?;
+            if let Some(m) = self.mounts.lock().unwrap().get_mut(&dsname) {
+                m.opts = opts2;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reapply property-derived mount options to a single mounted file
+    /// system, and its mounted descendants.
+    async fn remount_one(&self, name: &str, mountpoint: Option<String>)
+        -> Result<()>
+    {
+        let current = self
+            .mounts
+            .lock()
+            .unwrap()
+            .get(name)
+            .map(|m| m.mountpoint.clone());
+        let current = match current {
+            Some(current) => current,
+            // Can't remount something that isn't mounted
+            None => return Err(Error::EINVAL),
+        };
+        if let Some(mp) = mountpoint {
+            if Path::new(&mp) != current {
+                // Moving a mount isn't supported
+                return Err(Error::EINVAL);
+            }
+        }
+        self.remount(name).await
+    }
+
+    /// Share every file system whose `share9p` property is set, and every
+    /// volume whose `shareiscsi` property is set, and stop sharing any others.
+    async fn reshare(&self) -> Result<()> {
+        let names = self.datasets().await?;
+        let mut p9_shares = BTreeMap::<SocketAddr, BTreeSet<String>>::new();
+        let mut iscsi_shares = BTreeMap::<SocketAddr, BTreeSet<String>>::new();
+        for name in names.into_iter() {
+            let (volsize, _source) = self
+                .controller
+                .get_prop(name.clone(), PropertyName::Volsize)
+                .await?;
+            let (propname, shares) = if volsize.as_u64() > 0 {
+                (PropertyName::ShareIscsi, &mut iscsi_shares)
+            } else {
+                (PropertyName::Share9p, &mut p9_shares)
+            };
+            let (prop, _source) =
+                self.controller.get_prop(name.clone(), propname).await?;
+            // Anything other than an address must be "off"
+            if let Ok(addr) = prop.as_str().parse() {
+                shares.entry(addr).or_default().insert(name);
+            }
+        }
+        let r = self.p9.reshare(p9_shares).await;
+        let r2 = self.iscsi.reshare(iscsi_shares).await;
+        r.and(r2).map_err(Error::from)
+    }
+
+    /// Send a response to the client.  If that fails, the client must have
+    /// disconnected, which handle_client will notice.
+    async fn respond(
+        peer: &dyn Peer,
+        id: rpc::RequestId,
+        resp: rpc::Response,
+    ) {
+        let encoded = match rpc::encode(&(id, &resp)) {
+            Ok(encoded) => encoded,
+            Err(e) => {
+                error!("Cannot serialize response {resp:?}: {e:?}");
+                return;
+            }
+        };
+        match peer.send(&encoded).await {
+            Ok(nwrite) if nwrite == encoded.len() => (),
+            _ => warn!("Client disconnected before reading response"),
+        }
+    }
+
+    async fn set(
+        &self,
+        name: &str,
+        props: Vec<Property>,
+        user_props: Vec<UserProperty>,
+    ) -> Result<()> {
+        let mut remount = false;
+        let mut reshare = false;
         for prop in props.into_iter() {
+            remount |= matches!(
+                prop.name(),
+                PropertyName::Devices | PropertyName::Exec | PropertyName::Setuid
+            );
+            reshare |= matches!(
+                prop.name(),
+                PropertyName::Share9p | PropertyName::ShareIscsi
+            );
             self.controller.set_prop(name, prop).await?;
         }
+        for prop in user_props.into_iter() {
+            self.controller.set_user_prop(name, prop).await?;
+        }
+        if remount {
+            self.remount(name).await?;
+        }
+        if reshare {
+            self.reshare().await?;
+        }
         Ok(())
     }
 
+    /// Process a request in its own task, so the client may pipeline
+    /// several, and cancel any of them.
+    fn spawn_rpc(
+        self: &Arc<Self>,
+        peer: &Arc<dyn Peer>,
+        inflight: &Inflight,
+        id: rpc::RequestId,
+        req: rpc::Request,
+        client: Client,
+        auth: Option<rpc::AuthHash>,
+    ) {
+        let mut guard = inflight.lock().unwrap();
+        if guard.contains_key(&id) {
+            drop(guard);
+            warn!("Client reused request ID {id}");
+            let peer = peer.clone();
+            let resp = rpc::Response::Error(Error::EEXIST);
+            tokio::spawn(async move {
+                Bfffsd::respond(&peer, id, resp).await;
+            });
+            return;
+        }
+        let bfffsd = self.clone();
+        let peer = peer.clone();
+        let inflight2 = inflight.clone();
+        let (fut, handle) = future::abortable(async move {
+            // Account for the request and its response, until the response has
+            // been sent.
+            let _reservation = match &bfffsd.memory_budget {
+                Some(budget) => {
+                    Some(budget.reserve(Consumer::Rpc, BUFSIZ).await)
+                }
+                None => None,
+            };
+            let resp = bfffsd.process_rpc(req, client, auth).await;
+            let exported = matches!(
+                &resp,
+                rpc::Response::PoolExport(Ok(results))
+                    if results.iter().all(|(_, r)| r.is_ok())
+            );
+            // If the request was cancelled, the client has already been told.
+            let cancelled = inflight2.lock().unwrap().remove(&id).is_none();
+            if !cancelled {
+                Bfffsd::respond(&peer, id, resp).await;
+            }
+            if exported {
+                bfffsd.shutdown.notify_one();
+            }
+        });
+        guard.insert(id, handle);
+        tokio::spawn(fut);
+    }
+
+    /// Revoke a file system's delegation to a jail
+    fn unjail(&self, name: &str, jid: i32) -> Result<()> {
+        let mut jails = self.jails.lock().unwrap();
+        if jails.get(name) == Some(&jid) {
+            jails.remove(name);
+            Ok(())
+        } else {
+            Err(Error::ENOENT)
+        }
+    }
+
     async fn unmount(&self, name: &str, force: bool) -> Result<()> {
-        self.controller.unmount(name, force).await
+        self.controller.unmount(name, force).await?;
+        self.mounts.lock().unwrap().remove(name);
+        Ok(())
+    }
+
+    /// Unmount every mounted file system, children before parents.
+    async fn unmount_all(&self, force: bool) -> rpc::fs::BulkResult {
+        let names = self.mounts.lock().unwrap().keys().cloned().collect();
+        let mut results = Vec::new();
+        for name in unmount_order(names).into_iter() {
+            let r = self.unmount(&name, force).await;
+            if let Err(e) = &r {
+                error!("unmount {name}: {e:?}");
+            }
+            results.push((name, r));
+        }
+        results
     }
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() {
-    tracing_subscriber::fmt()
-        .pretty()
-        .with_env_filter(EnvFilter::from_default_env())
+/// Sort file systems so that each one comes before its parent
+fn unmount_order(mut names: Vec<String>) -> Vec<String> {
+    names.sort_by(|a, b| {
+        let depth = |s: &str| s.matches('/').count();
+        depth(b).cmp(&depth(a)).then_with(|| b.cmp(a))
+    });
+    names
+}
+
+/// Return the ID of the jail that the client was in when it connected, or 0
+/// for the host.
+#[cfg(target_os = "freebsd")]
+fn peer_jid(peer: &UnixSeqpacket) -> Result<i32> {
+    use std::{mem, os::unix::io::AsRawFd, ptr};
+
+    // libc's xucred lacks cr_pid, which was added in FreeBSD 13.0.
+    #[repr(C)]
+    union CrPid {
+        pid:     libc::pid_t,
+        _unused: *mut libc::c_void,
+    }
+    #[repr(C)]
+    struct XuCred {
+        cr_version: libc::c_uint,
+        cr_uid:     libc::uid_t,
+        cr_ngroups: libc::c_short,
+        cr_groups:  [libc::gid_t; libc::XU_NGROUPS as usize],
+        cr_pid:     CrPid,
+    }
+
+    let mut xucred = mem::MaybeUninit::<XuCred>::zeroed();
+    let mut len = mem::size_of::<XuCred>() as libc::socklen_t;
+    // Safe because xucred is large enough for the kernel's struct xucred
+    let r = unsafe {
+        libc::getsockopt(
+            peer.as_raw_fd(),
+            0, // SOL_LOCAL
+            libc::LOCAL_PEERCRED,
+            xucred.as_mut_ptr().cast(),
+            &mut len,
+        )
+    };
+    if r != 0 {
+        return Err(Error::from(nix::Error::last()));
+    }
+    // Safe because the kernel initialized it, and zeroed() did the rest
+    let xucred = unsafe { xucred.assume_init() };
+    if xucred.cr_version != libc::XUCRED_VERSION {
+        return Err(Error::EINVAL);
+    }
+    // Safe because the kernel always fills in cr_pid
+    let pid = unsafe { xucred.cr_pid.pid };
+
+    let mib = [libc::CTL_KERN, libc::KERN_PROC, libc::KERN_PROC_PID, pid];
+    let mut kp = mem::MaybeUninit::<libc::kinfo_proc>::zeroed();
+    let mut len = mem::size_of::<libc::kinfo_proc>();
+    // Safe because kp is large enough for one kinfo_proc
+    let r = unsafe {
+        libc::sysctl(
+            mib.as_ptr(),
+            mib.len() as libc::c_uint,
+            kp.as_mut_ptr().cast(),
+            &mut len,
+            ptr::null(),
+            0,
+        )
+    };
+    if r != 0 {
+        return Err(Error::from(nix::Error::last()));
+    }
+    if len != mem::size_of::<libc::kinfo_proc>() {
+        // The client already exited
+        return Err(Error::ESRCH);
+    }
+    // Safe because the kernel initialized it
+    Ok(unsafe { kp.assume_init() }.ki_jid)
+}
+
+/// Only FreeBSD has jails, so every client is on the host.
+#[cfg(not(target_os = "freebsd"))]
+fn peer_jid(_peer: &UnixSeqpacket) -> Result<i32> {
+    Ok(0)
+}
+
+/// Tell any supervisor that bfffsd is ready to serve requests.
+///
+/// Writes the pidfile, if requested, and sends "READY=1" to the socket named
+/// by `$NOTIFY_SOCKET`, if set, following the sd_notify(3) protocol.
+fn notify_ready(pidfile: Option<&Path>) {
+    let pid = unistd::getpid();
+    if let Some(path) = pidfile {
+        if let Err(e) = std::fs::write(path, format!("{pid}\n")) {
+            error!("Cannot write pidfile {}: {e}", path.display());
+        }
+    }
+    if let Some(addr) = std::env::var_os("NOTIFY_SOCKET") {
+        let msg = format!("READY=1\nMAINPID={pid}\n");
+        if let Err(e) = UnixDatagram::unbound()
+            .and_then(|sock| sock.send_to(msg.as_bytes(), &addr))
+        {
+            warn!("Cannot notify supervisor: {e}");
+        }
+    }
+}
+
+/// Bind the TCP socket for remote clients, if requested.
+#[cfg(feature = "tls")]
+async fn tls_listener(
+    cli: &Cli,
+) -> Option<(tokio::net::TcpListener, tokio_rustls::TlsAcceptor)> {
+    let addr = cli.listen?;
+    // Clap ensures that the other TLS options are present
+    let config = bfffs::transport::tls::server_config(
+        cli.tls_ca.as_deref().unwrap(),
+        cli.tls_cert.as_deref().unwrap(),
+        cli.tls_key.as_deref().unwrap(),
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("Cannot load TLS configuration: {e}");
+        exit(1);
+    });
+    let listener =
+        tokio::net::TcpListener::bind(addr).await.unwrap_or_else(|e| {
+            eprintln!("Cannot listen on {addr}: {e}");
+            exit(1);
+        });
+    Some((listener, tokio_rustls::TlsAcceptor::from(config)))
+}
+
+fn main() {
+    tracing_subscriber::registry()
+        .with(EnvFilter::from_default_env())
+        .with(ratelimit::RateLimit::new())
+        .with(tracing_subscriber::fmt::layer().pretty())
         .init();
+    if std::env::args_os().nth(1).as_deref() == Some(OsStr::new(worker::ARG)) {
+        worker::main();
+    } else {
+        daemon();
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn daemon() {
     let cli: Cli = Cli::parse();
+    if let Some(seed) = cli.deterministic {
+        bfffs_core::determinism::set_seed(seed);
+        warn!(seed, "Running in deterministic mode");
+    }
 
     let sock = Socket::new(&cli.sock);
+    #[cfg(feature = "tls")]
+    let tls = tls_listener(&cli).await;
+    #[cfg(not(feature = "tls"))]
+    if cli.listen.is_some() {
+        eprintln!("bfffsd was built without TLS support");
+        exit(1);
+    }
+    let pidfile = cli.pidfile.clone();
     let bfffsd = Arc::new(Bfffsd::new(cli).await);
+    if let Err(e) = bfffsd.reshare().await {
+        error!("Cannot share datasets: {:?}", e);
+    }
+    #[cfg(feature = "tls")]
+    if let Some((listener, acceptor)) = tls {
+        tokio::spawn(bfffsd.clone().run_tls(listener, acceptor));
+    }
+    notify_ready(pidfile.as_deref());
 
-    bfffsd.run(sock).await;
+    let mut sigterm = signal(SignalKind::terminate()).unwrap();
+    let mut sigint = signal(SignalKind::interrupt()).unwrap();
+    tokio::select! {
+        _ = bfffsd.clone().run(sock) => (),
+        _ = bfffsd.shutdown.notified() => (),
+        _ = sigterm.recv() => (),
+        _ = sigint.recv() => (),
+    }
+    // Unmount children before their parents, then flush everything to disk.
+    for (name, r) in bfffsd.unmount_all(false).await.into_iter() {
+        if let Err(e) = r {
+            error!("Cannot unmount {name} at shutdown: {e:?}");
+        }
+    }
+    if let Err(e) = bfffsd.controller.sync_transaction().await {
+        error!("Cannot sync pool at shutdown: {e:?}");
+    }
 }
 
 #[cfg(test)]
@@ -438,7 +1903,6 @@ mod t {
     #[rstest]
     #[case(Vec::new())]
     #[case(vec!["bfffsd"])]
-    #[case(vec!["bfffsd", "testpool"])]
     fn missing_arg(#[case] args: Vec<&str>) {
         let e = Cli::try_parse_from(args).unwrap_err();
         assert!(
@@ -471,7 +1935,110 @@ mod t {
         let cli = Cli::try_parse_from(args).unwrap();
         assert_eq!(cli.pool_name, "testpool");
         assert_eq!(cli.sock, Path::new("/var/run/bfffsd.sock"));
+        assert_eq!(cli.cachefile, Path::new("/var/db/bfffs.cache"));
+        assert!(cli.auth_token.is_none());
+        assert!(cli.deterministic.is_none());
+        assert!(!cli.force);
+        assert!(cli.listen.is_none());
         assert!(cli.options.is_empty());
+        assert!(cli.pidfile.is_none());
         assert_eq!(cli.devices[0], "/dev/da0");
     }
+
+    #[test]
+    fn auth_token() {
+        let args =
+            vec!["bfffsd", "--auth-token", "/etc/bfffs.token", "testpool"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        assert_eq!(
+            cli.auth_token.as_deref(),
+            Some(Path::new("/etc/bfffs.token"))
+        );
+    }
+
+    #[test]
+    fn deterministic() {
+        let args = vec!["bfffsd", "--deterministic", "12345", "testpool"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        assert_eq!(cli.deterministic, Some(12345));
+    }
+
+    #[test]
+    fn force() {
+        let args = vec!["bfffsd", "--force", "testpool", "/dev/da0"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        assert!(cli.force);
+    }
+
+    #[test]
+    fn listen() {
+        let args = vec![
+            "bfffsd",
+            "--listen",
+            "0.0.0.0:4820",
+            "--tls-ca",
+            "/etc/bfffs/ca.pem",
+            "--tls-cert",
+            "/etc/bfffs/cert.pem",
+            "--tls-key",
+            "/etc/bfffs/key.pem",
+            "testpool",
+        ];
+        let cli = Cli::try_parse_from(args).unwrap();
+        assert_eq!(cli.listen, Some("0.0.0.0:4820".parse().unwrap()));
+        assert_eq!(cli.tls_ca.as_deref(), Some(Path::new("/etc/bfffs/ca.pem")));
+        assert_eq!(
+            cli.tls_cert.as_deref(),
+            Some(Path::new("/etc/bfffs/cert.pem"))
+        );
+        assert_eq!(
+            cli.tls_key.as_deref(),
+            Some(Path::new("/etc/bfffs/key.pem"))
+        );
+    }
+
+    /// Listening for TLS connections requires a certificate and key
+    #[test]
+    fn listen_without_cert() {
+        let args = vec!["bfffsd", "--listen", "0.0.0.0:4820", "testpool"];
+        let e = Cli::try_parse_from(args).unwrap_err();
+        assert_eq!(e.kind(), MissingRequiredArgument);
+    }
+
+    #[test]
+    fn pidfile() {
+        let args =
+            vec!["bfffsd", "--pidfile", "/var/run/bfffsd.pid", "testpool"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        assert_eq!(
+            cli.pidfile.as_deref(),
+            Some(Path::new("/var/run/bfffsd.pid"))
+        );
+    }
+
+    /// With a cache file, no devices need be listed
+    #[test]
+    fn cachefile() {
+        let args =
+            vec!["bfffsd", "--cachefile", "/tmp/bfffs.cache", "testpool"];
+        let cli = Cli::try_parse_from(args).unwrap();
+        assert_eq!(cli.pool_name, "testpool");
+        assert_eq!(cli.cachefile, Path::new("/tmp/bfffs.cache"));
+        assert!(cli.devices.is_empty());
+    }
+
+    /// Children must be unmounted before their parents
+    #[test]
+    fn unmount_order() {
+        let names = vec![
+            "pool".to_owned(),
+            "pool/a".to_owned(),
+            "pool/a/x".to_owned(),
+            "pool/b".to_owned(),
+        ];
+        assert_eq!(
+            super::unmount_order(names),
+            vec!["pool/a/x", "pool/b", "pool/a", "pool"]
+        );
+    }
 }
@@ -0,0 +1,938 @@
+// vim: tw=80
+//! 9P2000.L frontend for BFFFS
+//!
+//! Lets virtual machine guests mount BFFFS datasets directly, without NFS.
+//! Each dataset whose `share9p` property is set is exported at that TCP
+//! address.  For example, a Linux guest could mount a dataset shared at
+//! `10.0.0.1:564` with
+//! `mount -t 9p -o trans=tcp,version=9p2000.L,aname=mypool/vm 10.0.0.1 /mnt`.
+//!
+//! Requests are translated into operations on a [`Vfs`], just like the FUSE
+//! frontend.
+// Some conversions are only necessary for one of FreeBSD 11 or 12.  After libc
+// makes the switchover, delete this line and clean them up.
+#![allow(clippy::useless_conversion)]
+#![allow(clippy::unnecessary_cast)]
+
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ffi::{OsStr, OsString},
+    io,
+    net::SocketAddr,
+    os::unix::ffi::OsStrExt,
+    slice,
+    sync::{Arc, Mutex, Weak},
+    time::SystemTime,
+};
+
+use bfffs_core::{
+    div_roundup,
+    fs::{FileData, FileDataMut, GetAttr, SetAttr, Timespec},
+    vfs::Vfs,
+};
+use futures::{future::BoxFuture, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    sync::watch,
+    task::JoinHandle,
+};
+use tracing::{error, warn};
+
+mod proto;
+#[cfg(test)]
+mod tests;
+
+use proto::*;
+
+/// Largest message size that we'll negotiate
+const MAX_MSIZE: u32 = 1 << 20;
+/// Size of Rread's and Rreaddir's header, including the count field
+const RREAD_HEADER_SIZE: u32 = HEADER_SIZE as u32 + 4;
+/// Tattach's n_uname when the client didn't send a numeric uid
+const NONUNAME: u32 = !0;
+/// Most path components that a single Twalk may contain
+const MAXWELEM: usize = 16;
+
+/// Opens a dataset by name, for exporting.
+pub type OpenFn<V> =
+    Box<dyn Fn(String) -> BoxFuture<'static, Result<Arc<V>, i32>> + Send + Sync>;
+
+fn now() -> Timespec {
+    let d = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap();
+    Timespec::new(d.as_secs() as i64, d.subsec_nanos())
+}
+
+fn qid(attr: &GetAttr) -> Qid {
+    let ty = match attr.mode.file_type() {
+        libc::S_IFDIR => QTDIR,
+        libc::S_IFLNK => QTSYMLINK,
+        _ => QTFILE,
+    };
+    Qid {
+        ty,
+        version: 0,
+        path: attr.ino,
+    }
+}
+
+fn timespec(ts: Timespec) -> (u64, u64) {
+    (ts.sec as u64, u64::from(ts.nsec))
+}
+
+/// Encode a device number the way Linux does
+fn linux_rdev(rdev: libc::dev_t) -> u64 {
+    let major = libc::major(rdev) as u64;
+    let minor = libc::minor(rdev) as u64;
+    ((major & 0xffff_f000) << 32) |
+        ((major & 0xfff) << 8) |
+        ((minor & 0xffff_ff00) << 12) |
+        (minor & 0xff)
+}
+
+/// A single dataset, as exported to all 9P clients.
+///
+/// Its files table is shared by every session that attaches to the dataset,
+/// because the `Vfs` must see at most one `FileDataMut` for each inode.
+pub struct Export<V: Vfs> {
+    fs:    Arc<V>,
+    /// Every inode referenced by any fid, or by an operation in progress.
+    /// Each holds one `lookup_count` per reference.
+    files: Mutex<HashMap<u64, FileDataMut>>,
+}
+
+impl<V: Vfs> Export<V> {
+    fn new(fs: Arc<V>) -> Self {
+        Export {
+            fs,
+            files: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take another reference on an inode that is already held
+    fn dup(&self, ino: u64) -> FileData {
+        let mut files = self.files.lock().unwrap();
+        let fd = files.get_mut(&ino).expect("dup of an unheld inode");
+        fd.lookup_count += 1;
+        fd.handle()
+    }
+
+    /// Take a reference on a newly looked up file
+    fn hold(&self, fd: FileDataMut) -> FileData {
+        let ino = fd.ino();
+        let mut files = self.files.lock().unwrap();
+        files
+            .entry(ino)
+            .and_modify(|ofd| ofd.lookup_count += fd.lookup_count)
+            .or_insert(fd)
+            .handle()
+    }
+
+    /// Find a directory's parent, without taking a reference on anything.
+    async fn parent_of(&self, ino: u64) -> Result<Option<u64>, i32> {
+        let parent = self.files.lock().unwrap().get(&ino).map(|fd| fd.parent());
+        match parent {
+            Some(parent) => Ok(parent),
+            None => {
+                let fd = self.fs.ilookup(ino).await?;
+                let parent = fd.parent();
+                self.fs.inactive(fd).await;
+                Ok(parent)
+            }
+        }
+    }
+
+    /// Drop one reference to `ino`.  Once none remain, release the `Vfs`'s
+    /// state for it.
+    async fn release(&self, ino: u64) {
+        let ofd = {
+            let mut files = self.files.lock().unwrap();
+            let fd = files.get_mut(&ino).expect("release of an unheld inode");
+            fd.lookup_count -= 1;
+            if fd.lookup_count == 0 {
+                files.remove(&ino)
+            } else {
+                None
+            }
+        };
+        if let Some(fd) = ofd {
+            self.fs.inactive(fd).await;
+        }
+    }
+
+    fn reparent(&self, ino: u64, parent: u64) {
+        if let Some(fd) = self.files.lock().unwrap().get_mut(&ino) {
+            fd.reparent(parent);
+        }
+    }
+}
+
+/// Every exported dataset, across all addresses.
+struct Exports<V: Vfs> {
+    open:    OpenFn<V>,
+    exports: tokio::sync::Mutex<HashMap<String, Weak<Export<V>>>>,
+}
+
+impl<V: Vfs> Exports<V> {
+    /// Get the named dataset's export, opening the dataset if no session
+    /// currently has it attached.
+    async fn get(&self, name: &str) -> Result<Arc<Export<V>>, i32> {
+        let mut guard = self.exports.lock().await;
+        if let Some(export) = guard.get(name).and_then(Weak::upgrade) {
+            return Ok(export);
+        }
+        let fs = (self.open)(name.to_owned()).await?;
+        let export = Arc::new(Export::new(fs));
+        guard.retain(|_, weak| weak.strong_count() > 0);
+        guard.insert(name.to_owned(), Arc::downgrade(&export));
+        Ok(export)
+    }
+}
+
+/// The datasets shared at a single address
+pub struct Share<V: Vfs> {
+    datasets: Mutex<BTreeSet<String>>,
+    exports:  Arc<Exports<V>>,
+}
+
+impl<V: Vfs> Share<V> {
+    /// Get the export for a Tattach's `aname`.  An empty `aname` is allowed
+    /// if only one dataset is shared at this address.
+    async fn attach(&self, aname: &OsStr) -> Result<Arc<Export<V>>, i32> {
+        let name = {
+            let datasets = self.datasets.lock().unwrap();
+            match aname.to_str() {
+                Some("") if datasets.len() == 1 => {
+                    datasets.iter().next().unwrap().clone()
+                }
+                Some(name) if datasets.contains(name) => name.to_owned(),
+                _ => return Err(libc::ENOENT),
+            }
+        };
+        self.exports.get(&name).await
+    }
+}
+
+/// One client's reference to a file
+struct Fid<V: Vfs> {
+    export: Arc<Export<V>>,
+    ino:    u64,
+    /// Owner of any files created through this fid
+    uid:    u32,
+}
+
+/// A single client connection
+pub struct Session<V: Vfs> {
+    share: Arc<Share<V>>,
+    fids:  HashMap<u32, Fid<V>>,
+    msize: u32,
+}
+
+impl<V: Vfs> Session<V> {
+    pub fn new(share: Arc<Share<V>>) -> Self {
+        Session {
+            share,
+            fids: HashMap::new(),
+            msize: MAX_MSIZE,
+        }
+    }
+
+    async fn attach(
+        &mut self,
+        fid: u32,
+        afid: u32,
+        aname: &OsStr,
+        n_uname: u32,
+    ) -> Result<Rmsg, i32> {
+        if afid != NOFID {
+            // We don't support authentication
+            return Err(libc::EINVAL);
+        }
+        if n_uname == NONUNAME {
+            // 9P2000.L clients always send a numeric uid
+            return Err(libc::EINVAL);
+        }
+        if self.fids.contains_key(&fid) {
+            return Err(libc::EBADF);
+        }
+        let export = self.share.attach(aname).await?;
+        let fd = export.hold(export.fs.root());
+        let attr = match export.fs.getattr(&fd).await {
+            Ok(attr) => attr,
+            Err(e) => {
+                export.release(fd.ino()).await;
+                return Err(e);
+            }
+        };
+        self.fids.insert(fid, Fid {
+            export,
+            ino: fd.ino(),
+            uid: n_uname,
+        });
+        Ok(Rmsg::Attach { qid: qid(&attr) })
+    }
+
+    /// Release every fid, as when the client disconnects
+    async fn clunk_all(&mut self) {
+        for (_, fid) in self.fids.drain() {
+            fid.export.release(fid.ino).await;
+        }
+    }
+
+    async fn dispatch(&mut self, msg: Tmsg) -> Result<Rmsg, i32> {
+        match msg {
+            Tmsg::Attach {
+                fid,
+                afid,
+                aname,
+                n_uname,
+            } => self.attach(fid, afid, &aname, n_uname).await,
+            Tmsg::Auth => Err(libc::EOPNOTSUPP),
+            Tmsg::Clunk { fid } => {
+                let fid = self.fids.remove(&fid).ok_or(libc::EBADF)?;
+                fid.export.release(fid.ino).await;
+                Ok(Rmsg::Clunk)
+            }
+            Tmsg::Flush => {
+                // Requests are processed in order, so whatever request is being
+                // flushed has already been answered.
+                Ok(Rmsg::Flush)
+            }
+            Tmsg::Fsync { fid } => {
+                let (export, fd, _) = self.get(fid)?;
+                export.fs.fsync(&fd).await?;
+                Ok(Rmsg::Fsync)
+            }
+            Tmsg::Getattr { fid } => self.getattr(fid).await,
+            Tmsg::Lcreate {
+                fid,
+                name,
+                mode,
+                gid,
+            } => self.lcreate(fid, &name, mode, gid).await,
+            Tmsg::Link { dfid, fid, name } => {
+                let (export, parent, _) = self.get(dfid)?;
+                let (export2, fd, _) = self.get(fid)?;
+                if !Arc::ptr_eq(&export, &export2) {
+                    return Err(libc::EXDEV);
+                }
+                export.fs.link(&parent, &fd, &name).await?;
+                Ok(Rmsg::Link)
+            }
+            Tmsg::Lopen { fid, flags } => {
+                let (export, fd, _) = self.get(fid)?;
+                let attr = export.fs.getattr(&fd).await?;
+                if flags & L_O_TRUNC != 0 &&
+                    attr.mode.file_type() == libc::S_IFREG
+                {
+                    let trunc = SetAttr {
+                        size: Some(0),
+                        ..Default::default()
+                    };
+                    export.fs.setattr(&fd, trunc).await?;
+                }
+                Ok(Rmsg::Lopen {
+                    qid:    qid(&attr),
+                    iounit: 0,
+                })
+            }
+            Tmsg::Mkdir {
+                dfid,
+                name,
+                mode,
+                gid,
+            } => {
+                let (export, parent, uid) = self.get(dfid)?;
+                let perm = (mode & 0o7777) as u16;
+                let r = export.fs.mkdir(&parent, &name, perm, uid, gid).await;
+                let qid = Self::new_qid(&export, r).await?;
+                Ok(Rmsg::Mkdir { qid })
+            }
+            Tmsg::Mknod {
+                dfid,
+                name,
+                mode,
+                major,
+                minor,
+                gid,
+            } => {
+                let (export, parent, uid) = self.get(dfid)?;
+                let fs = &export.fs;
+                let perm = (mode & 0o7777) as u16;
+                let rdev = libc::makedev(major, minor);
+                let r = match mode as u16 & libc::S_IFMT {
+                    libc::S_IFIFO => {
+                        fs.mkfifo(&parent, &name, perm, uid, gid).await
+                    }
+                    libc::S_IFCHR => {
+                        fs.mkchar(&parent, &name, perm, uid, gid, rdev).await
+                    }
+                    libc::S_IFBLK => {
+                        fs.mkblock(&parent, &name, perm, uid, gid, rdev).await
+                    }
+                    libc::S_IFSOCK => {
+                        fs.mksock(&parent, &name, perm, uid, gid).await
+                    }
+                    _ => Err(libc::EOPNOTSUPP),
+                };
+                let qid = Self::new_qid(&export, r).await?;
+                Ok(Rmsg::Mknod { qid })
+            }
+            Tmsg::Read { fid, offset, count } => {
+                let (export, fd, _) = self.get(fid)?;
+                let count = count.min(self.msize - RREAD_HEADER_SIZE);
+                let sglist = export.fs.read(&fd, offset, count as usize).await?;
+                let mut data = Vec::with_capacity(count as usize);
+                for iovec in sglist.iter() {
+                    data.extend_from_slice(&iovec[..]);
+                }
+                Ok(Rmsg::Read { data })
+            }
+            Tmsg::Readdir { fid, offset, count } => {
+                self.readdir(fid, offset, count).await
+            }
+            Tmsg::Readlink { fid } => {
+                let (export, fd, _) = self.get(fid)?;
+                let target = export.fs.readlink(&fd).await?;
+                Ok(Rmsg::Readlink { target })
+            }
+            Tmsg::Remove { fid } => {
+                // The fid must be clunked even though the remove fails.
+                // Clients should use Tunlinkat instead, because Tremove can't
+                // tell us the file's parent.
+                let fid = self.fids.remove(&fid).ok_or(libc::EBADF)?;
+                fid.export.release(fid.ino).await;
+                Err(libc::EOPNOTSUPP)
+            }
+            Tmsg::Renameat {
+                olddirfid,
+                oldname,
+                newdirfid,
+                newname,
+            } => self.renameat(olddirfid, &oldname, newdirfid, &newname).await,
+            Tmsg::Setattr {
+                fid,
+                valid,
+                mode,
+                uid,
+                gid,
+                size,
+                atime_sec,
+                atime_nsec,
+                mtime_sec,
+                mtime_nsec,
+            } => {
+                let (export, fd, _) = self.get(fid)?;
+                let time = |set: u32, sec: u64, nsec: u64| {
+                    if valid & set != 0 {
+                        Timespec::new(sec as i64, nsec as u32)
+                    } else {
+                        now()
+                    }
+                };
+                let attr = SetAttr {
+                    perm: (valid & SETATTR_MODE != 0)
+                        .then_some((mode & 0o7777) as u16),
+                    uid: (valid & SETATTR_UID != 0).then_some(uid),
+                    gid: (valid & SETATTR_GID != 0).then_some(gid),
+                    size: (valid & SETATTR_SIZE != 0).then_some(size),
+                    atime: (valid & SETATTR_ATIME != 0)
+                        .then(|| time(SETATTR_ATIME_SET, atime_sec, atime_nsec)),
+                    mtime: (valid & SETATTR_MTIME != 0)
+                        .then(|| time(SETATTR_MTIME_SET, mtime_sec, mtime_nsec)),
+                    ..Default::default()
+                };
+                export.fs.setattr(&fd, attr).await?;
+                Ok(Rmsg::Setattr)
+            }
+            Tmsg::Statfs { fid } => {
+                let (export, _, _) = self.get(fid)?;
+                let st = export.fs.statvfs().await?;
+                Ok(Rmsg::Statfs {
+                    bsize:   st.f_frsize as u32,
+                    blocks:  st.f_blocks as u64,
+                    bfree:   st.f_bfree as u64,
+                    bavail:  st.f_bavail as u64,
+                    files:   st.f_files as u64,
+                    ffree:   st.f_ffree as u64,
+                    fsid:    st.f_fsid as u64,
+                    namelen: st.f_namemax as u32,
+                })
+            }
+            Tmsg::Symlink {
+                fid,
+                name,
+                symtgt,
+                gid,
+            } => {
+                let (export, parent, uid) = self.get(fid)?;
+                let r = export
+                    .fs
+                    .symlink(&parent, &name, 0o777, uid, gid, &symtgt)
+                    .await;
+                let qid = Self::new_qid(&export, r).await?;
+                Ok(Rmsg::Symlink { qid })
+            }
+            Tmsg::Unlinkat {
+                dirfid,
+                name,
+                flags,
+            } => {
+                let (export, parent, _) = self.get(dirfid)?;
+                if flags & L_AT_REMOVEDIR != 0 {
+                    export.fs.rmdir(&parent, &name).await?;
+                } else {
+                    // The Vfs needs the file's handle, in case some fid still
+                    // references it.
+                    let fd = export.fs.lookup(None, &parent, &name).await?;
+                    let fd = export.hold(fd);
+                    let r = export.fs.unlink(&parent, Some(&fd), &name).await;
+                    export.release(fd.ino()).await;
+                    r?;
+                }
+                Ok(Rmsg::Unlinkat)
+            }
+            Tmsg::Unsupported(msgtype) => {
+                warn!("Unsupported 9P message type {msgtype}");
+                Err(libc::EOPNOTSUPP)
+            }
+            Tmsg::Version { msize, version } => {
+                // Tversion aborts any existing session
+                self.clunk_all().await;
+                if msize < RREAD_HEADER_SIZE + 1 {
+                    return Err(libc::EINVAL);
+                }
+                self.msize = msize.min(MAX_MSIZE);
+                let version = if version.as_bytes() == VERSION {
+                    VERSION
+                } else {
+                    &b"unknown"[..]
+                };
+                Ok(Rmsg::Version {
+                    msize:   self.msize,
+                    version: version.to_vec(),
+                })
+            }
+            Tmsg::Walk {
+                fid,
+                newfid,
+                wnames,
+            } => self.walk(fid, newfid, &wnames).await,
+            Tmsg::Write { fid, offset, data } => {
+                let (export, fd, _) = self.get(fid)?;
+                let count = export.fs.write(&fd, offset, &data, 0).await?;
+                Ok(Rmsg::Write { count })
+            }
+        }
+    }
+
+    /// Look up a fid's export, handle, and owner
+    fn get(&self, fid: u32) -> Result<(Arc<Export<V>>, FileData, u32), i32> {
+        let fid = self.fids.get(&fid).ok_or(libc::EBADF)?;
+        let fd = fid
+            .export
+            .files
+            .lock()
+            .unwrap()
+            .get(&fid.ino)
+            .expect("fid references an unheld inode")
+            .handle();
+        Ok((fid.export.clone(), fd, fid.uid))
+    }
+
+    async fn getattr(&self, fid: u32) -> Result<Rmsg, i32> {
+        let (export, fd, _) = self.get(fid)?;
+        let attr = export.fs.getattr(&fd).await?;
+        Ok(Rmsg::Getattr {
+            valid:        GETATTR_BASIC | GETATTR_BTIME | GETATTR_DATA_VERSION,
+            qid:          qid(&attr),
+            mode:         u32::from(attr.mode.file_type() | attr.mode.perm()),
+            uid:          attr.uid,
+            gid:          attr.gid,
+            nlink:        attr.nlink,
+            rdev:         linux_rdev(attr.rdev),
+            size:         attr.size,
+            blksize:      u64::from(attr.blksize),
+            blocks:       div_roundup(attr.bytes, 512),
+            atime:        timespec(attr.atime),
+            mtime:        timespec(attr.mtime),
+            ctime:        timespec(attr.ctime),
+            btime:        timespec(attr.birthtime),
+            data_version: attr.change,
+        })
+    }
+
+    /// Create a regular file.  Afterwards, the fid refers to the new file
+    /// instead of its parent.
+    async fn lcreate(
+        &mut self,
+        fid: u32,
+        name: &OsStr,
+        mode: u32,
+        gid: u32,
+    ) -> Result<Rmsg, i32> {
+        let (export, parent, uid) = self.get(fid)?;
+        let perm = (mode & 0o7777) as u16;
+        let fd = export.fs.create(&parent, name, perm, uid, gid).await?;
+        let fd = export.hold(fd);
+        let attr = match export.fs.getattr(&fd).await {
+            Ok(attr) => attr,
+            Err(e) => {
+                export.release(fd.ino()).await;
+                return Err(e);
+            }
+        };
+        self.fids.get_mut(&fid).unwrap().ino = fd.ino();
+        export.release(parent.ino()).await;
+        Ok(Rmsg::Lcreate {
+            qid:    qid(&attr),
+            iounit: 0,
+        })
+    }
+
+    /// Get the qid of a newly created file that won't be referenced by any fid
+    async fn new_qid(
+        export: &Export<V>,
+        r: Result<FileDataMut, i32>,
+    ) -> Result<Qid, i32> {
+        let fd = r?;
+        let r = export.fs.getattr(&fd.handle()).await;
+        export.fs.inactive(fd).await;
+        r.map(|attr| qid(&attr))
+    }
+
+    /// Process a single request
+    pub async fn process(&mut self, msg: Tmsg) -> Rmsg {
+        match self.dispatch(msg).await {
+            Ok(rmsg) => rmsg,
+            Err(e) => Rmsg::Lerror(linux_errno(e)),
+        }
+    }
+
+    async fn readdir(
+        &self,
+        fid: u32,
+        offset: u64,
+        count: u32,
+    ) -> Result<Rmsg, i32> {
+        let (export, fd, _) = self.get(fid)?;
+        let limit = count.min(self.msize - RREAD_HEADER_SIZE) as usize;
+        let mut enc = Encoder::default();
+        let mut stream = export.fs.readdir(&fd, offset as i64);
+        while let Some(r) = stream.next().await {
+            let (dirent, next) = match r {
+                Ok(x) => x,
+                // Report errors only if there's nothing else to report
+                Err(e) if enc.is_empty() => return Err(e),
+                Err(_) => break,
+            };
+            let nameptr = dirent.d_name.as_ptr() as *const u8;
+            let namelen = usize::from(dirent.d_namlen);
+            let name = unsafe { slice::from_raw_parts(nameptr, namelen) };
+            if enc.len() + dirent_size(name) > limit {
+                break;
+            }
+            let qid = Qid {
+                ty:      if dirent.d_type == libc::DT_DIR {
+                    QTDIR
+                } else if dirent.d_type == libc::DT_LNK {
+                    QTSYMLINK
+                } else {
+                    QTFILE
+                },
+                version: 0,
+                path:    dirent.d_fileno.into(),
+            };
+            // Linux and FreeBSD use the same DT_* values
+            encode_dirent(&mut enc, &qid, next as u64, dirent.d_type, name);
+        }
+        Ok(Rmsg::Readdir {
+            data: enc.into_inner(),
+        })
+    }
+
+    /// Read one request.  Returns `None` if the client disconnected.
+    ///
+    /// A request that can't be decoded is returned as an error, along with its
+    /// tag, so the client may be told about it.
+    async fn recv<R>(
+        &self,
+        rd: &mut R,
+    ) -> io::Result<Option<(u16, Result<Tmsg, i32>)>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut sizebuf = [0u8; 4];
+        match rd.read_exact(&mut sizebuf).await {
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        }
+        let size = u32::from_le_bytes(sizebuf);
+        if size < HEADER_SIZE as u32 || size > self.msize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid message size {size}"),
+            ));
+        }
+        let mut buf = vec![0u8; size as usize - 4];
+        rd.read_exact(&mut buf).await?;
+        let msgtype = buf[0];
+        let tag = u16::from_le_bytes([buf[1], buf[2]]);
+        Ok(Some((tag, Tmsg::decode(msgtype, &buf[3..]))))
+    }
+
+    async fn renameat(
+        &self,
+        olddirfid: u32,
+        oldname: &OsStr,
+        newdirfid: u32,
+        newname: &OsStr,
+    ) -> Result<Rmsg, i32> {
+        let (export, parent, _) = self.get(olddirfid)?;
+        let (export2, newparent, _) = self.get(newdirfid)?;
+        if !Arc::ptr_eq(&export, &export2) {
+            return Err(libc::EXDEV);
+        }
+        let fd = export.fs.lookup(None, &parent, oldname).await?;
+        let fd = export.hold(fd);
+        let newfd = match export.fs.lookup(None, &newparent, newname).await {
+            Ok(newfd) => Some(export.hold(newfd)),
+            Err(libc::ENOENT) => None,
+            Err(e) => {
+                export.release(fd.ino()).await;
+                return Err(e);
+            }
+        };
+        let mut r = Ok(());
+        if fd.parent().is_some() {
+            // Dirloop check: the destination may not be within the source
+            let mut ino = newparent.ino();
+            let mut oparent = newparent.parent();
+            loop {
+                if ino == fd.ino() {
+                    r = Err(libc::EINVAL);
+                    break;
+                }
+                match oparent {
+                    None => break,
+                    Some(p) => {
+                        ino = p;
+                        match export.parent_of(p).await {
+                            Ok(pp) => oparent = pp,
+                            Err(e) => {
+                                r = Err(e);
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        if r.is_ok() {
+            let newino = newfd.map(|newfd| newfd.ino());
+            r = export
+                .fs
+                .rename(&parent, &fd, oldname, &newparent, newino, newname)
+                .await
+                .map(|_| export.reparent(fd.ino(), newparent.ino()));
+        }
+        export.release(fd.ino()).await;
+        if let Some(newfd) = newfd {
+            export.release(newfd.ino()).await;
+        }
+        r.map(|_| Rmsg::Renameat)
+    }
+
+    /// Serve requests until the client disconnects, or the share is stopped
+    pub async fn run<S>(mut self, stream: S, mut shutdown: watch::Receiver<()>)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (mut rd, mut wr) = tokio::io::split(stream);
+        loop {
+            let r = tokio::select! {
+                r = self.recv(&mut rd) => r,
+                _ = shutdown.changed() => break,
+            };
+            let (tag, rmsg) = match r {
+                Ok(Some((tag, Ok(tmsg)))) => (tag, self.process(tmsg).await),
+                Ok(Some((tag, Err(e)))) => {
+                    warn!("Malformed 9P request");
+                    (tag, Rmsg::Lerror(linux_errno(e)))
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Error receiving 9P request: {e}");
+                    break;
+                }
+            };
+            if let Err(e) = wr.write_all(&rmsg.encode(tag)).await {
+                warn!("Error sending 9P response: {e}");
+                break;
+            }
+        }
+        self.clunk_all().await;
+    }
+
+    async fn walk(
+        &mut self,
+        fid: u32,
+        newfid: u32,
+        wnames: &[OsString],
+    ) -> Result<Rmsg, i32> {
+        let (export, fd, uid) = self.get(fid)?;
+        if newfid != fid && self.fids.contains_key(&newfid) {
+            return Err(libc::EBADF);
+        }
+        if wnames.len() > MAXWELEM {
+            return Err(libc::EINVAL);
+        }
+        // newfid gets its own reference
+        let mut cur = export.dup(fd.ino());
+        let mut qids = Vec::with_capacity(wnames.len());
+        for name in wnames.iter() {
+            let r = Self::walk1(&export, &cur, name).await;
+            export.release(cur.ino()).await;
+            match r {
+                Ok((next, qid)) => {
+                    cur = next;
+                    qids.push(qid);
+                }
+                Err(e) if qids.is_empty() => return Err(e),
+                // A partial walk succeeds, but doesn't create newfid
+                Err(_) => return Ok(Rmsg::Walk { qids }),
+            }
+        }
+        let fid = Fid {
+            export,
+            ino: cur.ino(),
+            uid,
+        };
+        if let Some(old) = self.fids.insert(newfid, fid) {
+            // newfid == fid
+            old.export.release(old.ino).await;
+        }
+        Ok(Rmsg::Walk { qids })
+    }
+
+    /// Walk a single path component, returning a new reference
+    async fn walk1(
+        export: &Export<V>,
+        dir: &FileData,
+        name: &OsStr,
+    ) -> Result<(FileData, Qid), i32> {
+        let fd = if name == "." {
+            export.dup(dir.ino())
+        } else if name == ".." {
+            match dir.parent() {
+                // The root is its own parent
+                None => export.dup(dir.ino()),
+                Some(p) => export.hold(export.fs.ilookup(p).await?),
+            }
+        } else {
+            export.hold(export.fs.lookup(None, dir, name).await?)
+        };
+        match export.fs.getattr(&fd).await {
+            Ok(attr) => Ok((fd, qid(&attr))),
+            Err(e) => {
+                export.release(fd.ino()).await;
+                Err(e)
+            }
+        }
+    }
+}
+
+/// A TCP listener for one address
+struct Listener<V: Vfs> {
+    share:     Arc<Share<V>>,
+    task:      JoinHandle<()>,
+    /// Dropping this tells the listener's sessions to shut down
+    _shutdown: watch::Sender<()>,
+}
+
+impl<V: Vfs> Drop for Listener<V> {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Serves every dataset that has the `share9p` property set
+pub struct Server<V: Vfs> {
+    exports:   Arc<Exports<V>>,
+    listeners: tokio::sync::Mutex<HashMap<SocketAddr, Listener<V>>>,
+}
+
+impl<V: Vfs> Server<V> {
+    async fn listen(
+        listener: TcpListener,
+        share: Arc<Share<V>>,
+        shutdown: watch::Receiver<()>,
+    ) {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _peer)) => {
+                    let _ignore = stream.set_nodelay(true);
+                    let session = Session::new(share.clone());
+                    tokio::spawn(session.run(stream, shutdown.clone()));
+                }
+                Err(e) => error!("Error accepting 9P connection: {e}"),
+            }
+        }
+    }
+
+    pub fn new(open: OpenFn<V>) -> Self {
+        let exports = Arc::new(Exports {
+            open,
+            exports: Default::default(),
+        });
+        Server {
+            exports,
+            listeners: Default::default(),
+        }
+    }
+
+    /// Share exactly the given datasets, at the given addresses.
+    ///
+    /// Listeners that are no longer needed are stopped, along with their
+    /// sessions.  Sessions on a listener that remains will keep any datasets
+    /// that they already have attached.
+    pub async fn reshare(
+        &self,
+        shares: BTreeMap<SocketAddr, BTreeSet<String>>,
+    ) -> io::Result<()> {
+        let mut listeners = self.listeners.lock().await;
+        listeners.retain(|addr, _| shares.contains_key(addr));
+        let mut r = Ok(());
+        for (addr, datasets) in shares.into_iter() {
+            if let Some(listener) = listeners.get(&addr) {
+                *listener.share.datasets.lock().unwrap() = datasets;
+                continue;
+            }
+            let tcp = match TcpListener::bind(addr).await {
+                Ok(tcp) => tcp,
+                Err(e) => {
+                    error!("Cannot listen for 9P on {addr}: {e}");
+                    r = Err(e);
+                    continue;
+                }
+            };
+            let share = Arc::new(Share {
+                datasets: Mutex::new(datasets),
+                exports:  self.exports.clone(),
+            });
+            let (tx, rx) = watch::channel(());
+            let task = tokio::spawn(Self::listen(tcp, share.clone(), rx));
+            listeners.insert(addr, Listener {
+                share,
+                task,
+                _shutdown: tx,
+            });
+        }
+        r
+    }
+}
@@ -0,0 +1,738 @@
+// vim: tw=80
+//! 9P2000.L wire format
+//!
+//! Every message begins with a 4-byte size, which includes itself, a 1-byte
+//! type, and a 2-byte tag.  All integers are little-endian, and strings are
+//! prefixed by a 2-byte length.  See
+//! <https://github.com/chaos/diod/blob/master/protocol.md> for the meaning of
+//! each message.
+
+use std::{
+    ffi::OsString,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+};
+
+/// The only protocol version that we speak
+pub const VERSION: &[u8] = b"9P2000.L";
+/// Size of every message's size, type, and tag fields
+pub const HEADER_SIZE: usize = 7;
+/// Fid used by Tattach when there is no authentication fid
+pub const NOFID: u32 = !0;
+
+/// Qid types
+pub const QTDIR: u8 = 0x80;
+pub const QTSYMLINK: u8 = 0x02;
+pub const QTFILE: u8 = 0x00;
+
+/// Tgetattr's request mask for all fields of a stat(2) structure
+pub const GETATTR_BASIC: u64 = 0x0000_07ff;
+pub const GETATTR_BTIME: u64 = 0x0000_0800;
+pub const GETATTR_DATA_VERSION: u64 = 0x0000_2000;
+
+/// Tsetattr's valid bits
+pub const SETATTR_MODE: u32 = 0x0000_0001;
+pub const SETATTR_UID: u32 = 0x0000_0002;
+pub const SETATTR_GID: u32 = 0x0000_0004;
+pub const SETATTR_SIZE: u32 = 0x0000_0008;
+pub const SETATTR_ATIME: u32 = 0x0000_0010;
+pub const SETATTR_MTIME: u32 = 0x0000_0020;
+pub const SETATTR_ATIME_SET: u32 = 0x0000_0080;
+pub const SETATTR_MTIME_SET: u32 = 0x0000_0100;
+
+/// Linux's open(2) flags, as used by Tlopen and Tlcreate
+pub const L_O_TRUNC: u32 = 0o1000;
+/// Linux's unlinkat(2) flag, as used by Tunlinkat
+pub const L_AT_REMOVEDIR: u32 = 0x200;
+
+/// Filesystem type reported by Rstatfs
+pub const V9FS_MAGIC: u32 = 0x0102_1997;
+
+/// Message types
+mod ty {
+    pub const RLERROR: u8 = 7;
+    pub const TSTATFS: u8 = 8;
+    pub const TLOPEN: u8 = 12;
+    pub const TLCREATE: u8 = 14;
+    pub const TSYMLINK: u8 = 16;
+    pub const TMKNOD: u8 = 18;
+    pub const TREADLINK: u8 = 22;
+    pub const TGETATTR: u8 = 24;
+    pub const TSETATTR: u8 = 26;
+    pub const TREADDIR: u8 = 40;
+    pub const TFSYNC: u8 = 50;
+    pub const TLINK: u8 = 70;
+    pub const TMKDIR: u8 = 72;
+    pub const TRENAMEAT: u8 = 74;
+    pub const TUNLINKAT: u8 = 76;
+    pub const TVERSION: u8 = 100;
+    pub const TAUTH: u8 = 102;
+    pub const TATTACH: u8 = 104;
+    pub const TFLUSH: u8 = 108;
+    pub const TWALK: u8 = 110;
+    pub const TREAD: u8 = 116;
+    pub const TWRITE: u8 = 118;
+    pub const TCLUNK: u8 = 120;
+    pub const TREMOVE: u8 = 122;
+}
+
+/// A 9P file identifier, as seen by the client
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Qid {
+    pub ty:      u8,
+    pub version: u32,
+    pub path:    u64,
+}
+
+impl Qid {
+    pub const SIZE: usize = 13;
+}
+
+/// Decodes the fields of a single message
+struct Decoder<'a>(&'a [u8]);
+
+impl<'a> Decoder<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], i32> {
+        if self.0.len() < len {
+            return Err(libc::EINVAL);
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn str(&mut self) -> Result<OsString, i32> {
+        let len = self.u16()?;
+        self.bytes(usize::from(len))
+            .map(|b| OsString::from_vec(b.to_vec()))
+    }
+
+    fn u16(&mut self) -> Result<u16, i32> {
+        self.bytes(2)
+            .map(|b| u16::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, i32> {
+        self.bytes(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, i32> {
+        self.bytes(8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+    }
+}
+
+/// Encodes the fields of a single message
+#[derive(Debug, Default)]
+pub struct Encoder(Vec<u8>);
+
+impl Encoder {
+    pub fn bytes(&mut self, b: &[u8]) {
+        self.0.extend_from_slice(b);
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn qid(&mut self, qid: &Qid) {
+        self.u8(qid.ty);
+        self.u32(qid.version);
+        self.u64(qid.path);
+    }
+
+    /// Encode a string.  Names longer than 64kB cannot be represented, but
+    /// BFFFS doesn't allow those anyway.
+    pub fn str(&mut self, s: &[u8]) {
+        let len = u16::try_from(s.len()).expect("string too long for 9P");
+        self.u16(len);
+        self.bytes(s);
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.bytes(&v.to_le_bytes());
+    }
+
+    pub fn u8(&mut self, v: u8) {
+        self.0.push(v);
+    }
+}
+
+/// Size of a directory entry, as encoded in Rreaddir
+pub fn dirent_size(name: &[u8]) -> usize {
+    Qid::SIZE + 8 + 1 + 2 + name.len()
+}
+
+/// Encode one directory entry, for Rreaddir
+pub fn encode_dirent(
+    enc: &mut Encoder,
+    qid: &Qid,
+    offset: u64,
+    dtype: u8,
+    name: &[u8],
+) {
+    enc.qid(qid);
+    enc.u64(offset);
+    enc.u8(dtype);
+    enc.str(name);
+}
+
+/// Requests, sent from client to server
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Tmsg {
+    Attach {
+        fid:     u32,
+        afid:    u32,
+        aname:   OsString,
+        n_uname: u32,
+    },
+    Auth,
+    Clunk {
+        fid: u32,
+    },
+    Flush,
+    Fsync {
+        fid: u32,
+    },
+    /// We always return every attribute, regardless of the request mask
+    Getattr {
+        fid: u32,
+    },
+    /// Flags are ignored, since we don't track open files
+    Lcreate {
+        fid:  u32,
+        name: OsString,
+        mode: u32,
+        gid:  u32,
+    },
+    Link {
+        dfid: u32,
+        fid:  u32,
+        name: OsString,
+    },
+    Lopen {
+        fid:   u32,
+        flags: u32,
+    },
+    Mkdir {
+        dfid: u32,
+        name: OsString,
+        mode: u32,
+        gid:  u32,
+    },
+    Mknod {
+        dfid:  u32,
+        name:  OsString,
+        mode:  u32,
+        major: u32,
+        minor: u32,
+        gid:   u32,
+    },
+    Read {
+        fid:    u32,
+        offset: u64,
+        count:  u32,
+    },
+    Readdir {
+        fid:    u32,
+        offset: u64,
+        count:  u32,
+    },
+    Readlink {
+        fid: u32,
+    },
+    Remove {
+        fid: u32,
+    },
+    Renameat {
+        olddirfid: u32,
+        oldname:   OsString,
+        newdirfid: u32,
+        newname:   OsString,
+    },
+    Setattr {
+        fid:        u32,
+        valid:      u32,
+        mode:       u32,
+        uid:        u32,
+        gid:        u32,
+        size:       u64,
+        atime_sec:  u64,
+        atime_nsec: u64,
+        mtime_sec:  u64,
+        mtime_nsec: u64,
+    },
+    Statfs {
+        fid: u32,
+    },
+    Symlink {
+        fid:    u32,
+        name:   OsString,
+        symtgt: OsString,
+        gid:    u32,
+    },
+    Unlinkat {
+        dirfid: u32,
+        name:   OsString,
+        flags:  u32,
+    },
+    /// A message type that we don't implement, like Txattrwalk, Tlock, or
+    /// Trename.  Linux clients only send Trename if Trenameat fails.
+    Unsupported(u8),
+    Version {
+        msize:   u32,
+        version: OsString,
+    },
+    Walk {
+        fid:    u32,
+        newfid: u32,
+        wnames: Vec<OsString>,
+    },
+    Write {
+        fid:    u32,
+        offset: u64,
+        data:   Vec<u8>,
+    },
+}
+
+impl Tmsg {
+    /// Decode a message's body, given its type.
+    ///
+    /// Trailing bytes are ignored, since some clients append optional fields
+    /// to certain messages, like Tfsync's datasync flag.
+    pub fn decode(msgtype: u8, body: &[u8]) -> Result<Self, i32> {
+        let mut d = Decoder(body);
+        let msg = match msgtype {
+            ty::TATTACH => {
+                let fid = d.u32()?;
+                let afid = d.u32()?;
+                let _uname = d.str()?;
+                let aname = d.str()?;
+                let n_uname = d.u32()?;
+                Tmsg::Attach {
+                    fid,
+                    afid,
+                    aname,
+                    n_uname,
+                }
+            }
+            ty::TAUTH => Tmsg::Auth,
+            ty::TCLUNK => Tmsg::Clunk { fid: d.u32()? },
+            ty::TFLUSH => {
+                let _oldtag = d.u16()?;
+                Tmsg::Flush
+            }
+            ty::TFSYNC => Tmsg::Fsync { fid: d.u32()? },
+            ty::TGETATTR => {
+                let fid = d.u32()?;
+                let _request_mask = d.u64()?;
+                Tmsg::Getattr { fid }
+            }
+            ty::TLCREATE => {
+                let fid = d.u32()?;
+                let name = d.str()?;
+                let _flags = d.u32()?;
+                let mode = d.u32()?;
+                let gid = d.u32()?;
+                Tmsg::Lcreate {
+                    fid,
+                    name,
+                    mode,
+                    gid,
+                }
+            }
+            ty::TLINK => {
+                Tmsg::Link {
+                    dfid: d.u32()?,
+                    fid:  d.u32()?,
+                    name: d.str()?,
+                }
+            }
+            ty::TLOPEN => {
+                Tmsg::Lopen {
+                    fid:   d.u32()?,
+                    flags: d.u32()?,
+                }
+            }
+            ty::TMKDIR => {
+                Tmsg::Mkdir {
+                    dfid: d.u32()?,
+                    name: d.str()?,
+                    mode: d.u32()?,
+                    gid:  d.u32()?,
+                }
+            }
+            ty::TMKNOD => {
+                Tmsg::Mknod {
+                    dfid:  d.u32()?,
+                    name:  d.str()?,
+                    mode:  d.u32()?,
+                    major: d.u32()?,
+                    minor: d.u32()?,
+                    gid:   d.u32()?,
+                }
+            }
+            ty::TREAD => {
+                Tmsg::Read {
+                    fid:    d.u32()?,
+                    offset: d.u64()?,
+                    count:  d.u32()?,
+                }
+            }
+            ty::TREADDIR => {
+                Tmsg::Readdir {
+                    fid:    d.u32()?,
+                    offset: d.u64()?,
+                    count:  d.u32()?,
+                }
+            }
+            ty::TREADLINK => Tmsg::Readlink { fid: d.u32()? },
+            ty::TREMOVE => Tmsg::Remove { fid: d.u32()? },
+            ty::TRENAMEAT => {
+                Tmsg::Renameat {
+                    olddirfid: d.u32()?,
+                    oldname:   d.str()?,
+                    newdirfid: d.u32()?,
+                    newname:   d.str()?,
+                }
+            }
+            ty::TSETATTR => {
+                Tmsg::Setattr {
+                    fid:        d.u32()?,
+                    valid:      d.u32()?,
+                    mode:       d.u32()?,
+                    uid:        d.u32()?,
+                    gid:        d.u32()?,
+                    size:       d.u64()?,
+                    atime_sec:  d.u64()?,
+                    atime_nsec: d.u64()?,
+                    mtime_sec:  d.u64()?,
+                    mtime_nsec: d.u64()?,
+                }
+            }
+            ty::TSTATFS => Tmsg::Statfs { fid: d.u32()? },
+            ty::TSYMLINK => {
+                Tmsg::Symlink {
+                    fid:    d.u32()?,
+                    name:   d.str()?,
+                    symtgt: d.str()?,
+                    gid:    d.u32()?,
+                }
+            }
+            ty::TUNLINKAT => {
+                Tmsg::Unlinkat {
+                    dirfid: d.u32()?,
+                    name:   d.str()?,
+                    flags:  d.u32()?,
+                }
+            }
+            ty::TVERSION => {
+                Tmsg::Version {
+                    msize:   d.u32()?,
+                    version: d.str()?,
+                }
+            }
+            ty::TWALK => {
+                let fid = d.u32()?;
+                let newfid = d.u32()?;
+                let nwname = d.u16()?;
+                let wnames = (0..nwname)
+                    .map(|_| d.str())
+                    .collect::<Result<Vec<_>, _>>()?;
+                Tmsg::Walk {
+                    fid,
+                    newfid,
+                    wnames,
+                }
+            }
+            ty::TWRITE => {
+                let fid = d.u32()?;
+                let offset = d.u64()?;
+                let count = d.u32()?;
+                let data = d.bytes(count as usize)?.to_vec();
+                Tmsg::Write { fid, offset, data }
+            }
+            t => Tmsg::Unsupported(t),
+        };
+        Ok(msg)
+    }
+}
+
+/// Responses, sent from server to client
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Rmsg {
+    Attach {
+        qid: Qid,
+    },
+    Clunk,
+    Flush,
+    Fsync,
+    Getattr {
+        valid:        u64,
+        qid:          Qid,
+        mode:         u32,
+        uid:          u32,
+        gid:          u32,
+        nlink:        u64,
+        rdev:         u64,
+        size:         u64,
+        blksize:      u64,
+        blocks:       u64,
+        atime:        (u64, u64),
+        mtime:        (u64, u64),
+        ctime:        (u64, u64),
+        btime:        (u64, u64),
+        /// Incremented whenever the file's data or metadata change
+        data_version: u64,
+    },
+    Lcreate {
+        qid:    Qid,
+        iounit: u32,
+    },
+    /// An error.  The value is a Linux errno.
+    Lerror(u32),
+    Link,
+    Lopen {
+        qid:    Qid,
+        iounit: u32,
+    },
+    Mkdir {
+        qid: Qid,
+    },
+    Mknod {
+        qid: Qid,
+    },
+    Read {
+        data: Vec<u8>,
+    },
+    /// Directory entries, already encoded by [`encode_dirent`]
+    Readdir {
+        data: Vec<u8>,
+    },
+    Readlink {
+        target: OsString,
+    },
+    Remove,
+    Renameat,
+    Setattr,
+    Statfs {
+        bsize:   u32,
+        blocks:  u64,
+        bfree:   u64,
+        bavail:  u64,
+        files:   u64,
+        ffree:   u64,
+        fsid:    u64,
+        namelen: u32,
+    },
+    Symlink {
+        qid: Qid,
+    },
+    Unlinkat,
+    Version {
+        msize:   u32,
+        version: Vec<u8>,
+    },
+    Walk {
+        qids: Vec<Qid>,
+    },
+    Write {
+        count: u32,
+    },
+}
+
+impl Rmsg {
+    /// Encode a complete message, including its header
+    pub fn encode(&self, tag: u16) -> Vec<u8> {
+        let mut e = Encoder::default();
+        // Placeholder for the size
+        e.u32(0);
+        e.u8(self.msgtype());
+        e.u16(tag);
+        match self {
+            Rmsg::Attach { qid } |
+            Rmsg::Mkdir { qid } |
+            Rmsg::Mknod { qid } |
+            Rmsg::Symlink { qid } => e.qid(qid),
+            Rmsg::Clunk |
+            Rmsg::Flush |
+            Rmsg::Fsync |
+            Rmsg::Link |
+            Rmsg::Remove |
+            Rmsg::Renameat |
+            Rmsg::Setattr |
+            Rmsg::Unlinkat => (),
+            Rmsg::Getattr {
+                valid,
+                qid,
+                mode,
+                uid,
+                gid,
+                nlink,
+                rdev,
+                size,
+                blksize,
+                blocks,
+                atime,
+                mtime,
+                ctime,
+                btime,
+                data_version,
+            } => {
+                e.u64(*valid);
+                e.qid(qid);
+                e.u32(*mode);
+                e.u32(*uid);
+                e.u32(*gid);
+                e.u64(*nlink);
+                e.u64(*rdev);
+                e.u64(*size);
+                e.u64(*blksize);
+                e.u64(*blocks);
+                for (sec, nsec) in [atime, mtime, ctime, btime] {
+                    e.u64(*sec);
+                    e.u64(*nsec);
+                }
+                // gen is reserved for future use
+                e.u64(0);
+                e.u64(*data_version);
+            }
+            Rmsg::Lcreate { qid, iounit } | Rmsg::Lopen { qid, iounit } => {
+                e.qid(qid);
+                e.u32(*iounit);
+            }
+            Rmsg::Lerror(ecode) => e.u32(*ecode),
+            Rmsg::Read { data } | Rmsg::Readdir { data } => {
+                e.u32(u32::try_from(data.len()).unwrap());
+                e.bytes(data);
+            }
+            Rmsg::Readlink { target } => e.str(target.as_bytes()),
+            Rmsg::Statfs {
+                bsize,
+                blocks,
+                bfree,
+                bavail,
+                files,
+                ffree,
+                fsid,
+                namelen,
+            } => {
+                e.u32(V9FS_MAGIC);
+                e.u32(*bsize);
+                e.u64(*blocks);
+                e.u64(*bfree);
+                e.u64(*bavail);
+                e.u64(*files);
+                e.u64(*ffree);
+                e.u64(*fsid);
+                e.u32(*namelen);
+            }
+            Rmsg::Version { msize, version } => {
+                e.u32(*msize);
+                e.str(version);
+            }
+            Rmsg::Walk { qids } => {
+                e.u16(u16::try_from(qids.len()).unwrap());
+                for qid in qids.iter() {
+                    e.qid(qid);
+                }
+            }
+            Rmsg::Write { count } => e.u32(*count),
+        }
+        let mut buf = e.into_inner();
+        let size = u32::try_from(buf.len()).unwrap();
+        buf[0..4].copy_from_slice(&size.to_le_bytes());
+        buf
+    }
+
+    fn msgtype(&self) -> u8 {
+        // Every R-message's type is one greater than its T-message's
+        match self {
+            Rmsg::Attach { .. } => ty::TATTACH + 1,
+            Rmsg::Clunk => ty::TCLUNK + 1,
+            Rmsg::Flush => ty::TFLUSH + 1,
+            Rmsg::Fsync => ty::TFSYNC + 1,
+            Rmsg::Getattr { .. } => ty::TGETATTR + 1,
+            Rmsg::Lcreate { .. } => ty::TLCREATE + 1,
+            Rmsg::Lerror(_) => ty::RLERROR,
+            Rmsg::Link => ty::TLINK + 1,
+            Rmsg::Lopen { .. } => ty::TLOPEN + 1,
+            Rmsg::Mkdir { .. } => ty::TMKDIR + 1,
+            Rmsg::Mknod { .. } => ty::TMKNOD + 1,
+            Rmsg::Read { .. } => ty::TREAD + 1,
+            Rmsg::Readdir { .. } => ty::TREADDIR + 1,
+            Rmsg::Readlink { .. } => ty::TREADLINK + 1,
+            Rmsg::Remove => ty::TREMOVE + 1,
+            Rmsg::Renameat => ty::TRENAMEAT + 1,
+            Rmsg::Setattr => ty::TSETATTR + 1,
+            Rmsg::Statfs { .. } => ty::TSTATFS + 1,
+            Rmsg::Symlink { .. } => ty::TSYMLINK + 1,
+            Rmsg::Unlinkat => ty::TUNLINKAT + 1,
+            Rmsg::Version { .. } => ty::TVERSION + 1,
+            Rmsg::Walk { .. } => ty::TWALK + 1,
+            Rmsg::Write { .. } => ty::TWRITE + 1,
+        }
+    }
+}
+
+/// Translate a native errno into a Linux one, as 9P2000.L requires.
+///
+/// Errors that Linux lacks, like `EINTEGRITY`, become `EIO`.
+pub fn linux_errno(e: i32) -> u32 {
+    match e {
+        libc::EPERM => 1,
+        libc::ENOENT => 2,
+        libc::EINTR => 4,
+        libc::EIO => 5,
+        libc::ENXIO => 6,
+        libc::E2BIG => 7,
+        libc::EBADF => 9,
+        libc::EAGAIN => 11,
+        libc::ENOMEM => 12,
+        libc::EACCES => 13,
+        libc::EFAULT => 14,
+        libc::EBUSY => 16,
+        libc::EEXIST => 17,
+        libc::EXDEV => 18,
+        libc::ENODEV => 19,
+        libc::ENOTDIR => 20,
+        libc::EISDIR => 21,
+        libc::EINVAL => 22,
+        libc::ENFILE => 23,
+        libc::EMFILE => 24,
+        libc::ETXTBSY => 26,
+        libc::EFBIG => 27,
+        libc::ENOSPC => 28,
+        libc::ESPIPE => 29,
+        libc::EROFS => 30,
+        libc::EMLINK => 31,
+        libc::ERANGE => 34,
+        libc::ENAMETOOLONG => 36,
+        libc::ENOSYS => 38,
+        libc::ENOTEMPTY => 39,
+        libc::ELOOP => 40,
+        libc::ENOATTR => 61,
+        libc::EOVERFLOW => 75,
+        libc::EILSEQ => 84,
+        libc::EOPNOTSUPP => 95,
+        libc::EDQUOT => 122,
+        libc::ECANCELED => 125,
+        _ => 5,
+    }
+}
@@ -0,0 +1,522 @@
+// vim: tw=80
+use std::mem;
+
+use bfffs_core::fs::Mode;
+use bfffs_fuse::mock::MockFs as Fs;
+use futures::{future, stream, FutureExt};
+
+use super::*;
+
+const DSNAME: &str = "mypool/vm";
+/// Inode number of the root directory
+const ROOT: u64 = 1;
+
+fn attr(ino: u64, mode: u16) -> GetAttr {
+    GetAttr {
+        ino,
+        size: 0,
+        bytes: 0,
+        atime: Timespec { sec: 0, nsec: 0 },
+        mtime: Timespec { sec: 0, nsec: 0 },
+        ctime: Timespec { sec: 0, nsec: 0 },
+        birthtime: Timespec { sec: 0, nsec: 0 },
+        mode: Mode(mode),
+        nlink: 1,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        blksize: 131072,
+        flags: 0,
+        change: 0,
+    }
+}
+
+/// Build a session sharing a single dataset, backed by a mock.
+///
+/// The mock always knows how to get the root directory's attributes.
+fn make_session<F>(f: F) -> Session<Fs>
+where
+    F: FnOnce(&mut Fs),
+{
+    let mut mock_fs = Fs::default();
+    mock_fs
+        .expect_root()
+        .returning(|| FileDataMut::new_for_tests(None, ROOT));
+    mock_fs
+        .expect_getattr()
+        .withf(|fd| fd.ino() == ROOT)
+        .return_const(Ok(attr(ROOT, libc::S_IFDIR | 0o755)));
+    f(&mut mock_fs);
+    let fs = Arc::new(mock_fs);
+    let exports = Arc::new(Exports {
+        open:    Box::new(move |_: String| future::ok(fs.clone()).boxed()),
+        exports: Default::default(),
+    });
+    let share = Arc::new(Share {
+        datasets: Mutex::new(BTreeSet::from([DSNAME.to_owned()])),
+        exports,
+    });
+    Session::new(share)
+}
+
+/// Attach fid 0 to the shared dataset's root
+fn attach(session: &mut Session<Fs>) {
+    let msg = Tmsg::Attach {
+        fid:     0,
+        afid:    NOFID,
+        aname:   OsString::from(DSNAME),
+        n_uname: 1000,
+    };
+    let qid = Qid {
+        ty:      QTDIR,
+        version: 0,
+        path:    ROOT,
+    };
+    assert_eq!(
+        session.process(msg).now_or_never().unwrap(),
+        Rmsg::Attach { qid }
+    );
+}
+
+fn dirent(ino: u32, dtype: u8, name: &[u8]) -> libc::dirent {
+    let mut dirent: libc::dirent = unsafe { mem::zeroed() };
+    dirent.d_fileno = ino.into();
+    dirent.d_reclen = mem::size_of::<libc::dirent>() as u16;
+    dirent.d_type = dtype;
+    for (i, c) in name.iter().enumerate() {
+        dirent.d_name[i] = *c as libc::c_char;
+    }
+    dirent.d_namlen = name.len() as _;
+    dirent
+}
+
+mod attach {
+    use super::*;
+
+    /// An empty aname is allowed when there's only one dataset to choose
+    #[test]
+    fn empty_aname() {
+        let mut session = make_session(|_| ());
+        let msg = Tmsg::Attach {
+            fid:     0,
+            afid:    NOFID,
+            aname:   OsString::new(),
+            n_uname: 1000,
+        };
+        let r = session.process(msg).now_or_never().unwrap();
+        assert!(matches!(r, Rmsg::Attach { .. }));
+    }
+
+    /// Datasets that aren't shared at this address may not be attached
+    #[test]
+    fn enoent() {
+        let mut session = make_session(|_| ());
+        let msg = Tmsg::Attach {
+            fid:     0,
+            afid:    NOFID,
+            aname:   OsString::from("mypool/other"),
+            n_uname: 1000,
+        };
+        let r = session.process(msg).now_or_never().unwrap();
+        assert_eq!(r, Rmsg::Lerror(2));
+        assert!(session.fids.is_empty());
+    }
+
+    #[test]
+    fn ok() {
+        let mut session = make_session(|_| ());
+        attach(&mut session);
+        assert_eq!(session.fids[&0].uid, 1000);
+    }
+}
+
+/// Clunking the last fid referencing a file should release it
+#[test]
+fn clunk() {
+    let mut session = make_session(|mock_fs| {
+        mock_fs
+            .expect_inactive()
+            .withf(|fd| fd.ino() == ROOT)
+            .times(1)
+            .return_const(());
+    });
+    attach(&mut session);
+    let msg = Tmsg::Clunk { fid: 0 };
+    assert_eq!(session.process(msg).now_or_never().unwrap(), Rmsg::Clunk);
+    let msg = Tmsg::Clunk { fid: 0 };
+    assert_eq!(session.process(msg).now_or_never().unwrap(), Rmsg::Lerror(9));
+}
+
+/// Reads may not exceed the negotiated message size
+#[test]
+fn read_msize() {
+    let msize = 8192;
+    let mut session = make_session(|mock_fs| {
+        mock_fs
+            .expect_read()
+            .withf(move |fd, offset, size| {
+                fd.ino() == ROOT &&
+                    *offset == 4096 &&
+                    *size == (msize - RREAD_HEADER_SIZE) as usize
+            })
+            .times(1)
+            .returning(|_, _, _| Ok(Vec::new()));
+    });
+    let msg = Tmsg::Version {
+        msize,
+        version: OsString::from("9P2000.L"),
+    };
+    session.process(msg).now_or_never().unwrap();
+    attach(&mut session);
+    let msg = Tmsg::Read {
+        fid:    0,
+        offset: 4096,
+        count:  65536,
+    };
+    let r = session.process(msg).now_or_never().unwrap();
+    assert_eq!(r, Rmsg::Read { data: Vec::new() });
+}
+
+/// Readdir should return only as many entries as fit
+#[test]
+fn readdir() {
+    let mut session = make_session(|mock_fs| {
+        mock_fs
+            .expect_readdir()
+            .withf(|fd, soffs| fd.ino() == ROOT && *soffs == 0)
+            .times(1)
+            .returning(|_, _| {
+                stream::iter(vec![
+                    Ok((dirent(1, libc::DT_DIR, b"."), 1)),
+                    Ok((dirent(2, libc::DT_REG, b"foo"), 2)),
+                ])
+                .boxed()
+            });
+    });
+    attach(&mut session);
+    // Only room for the first entry
+    let msg = Tmsg::Readdir {
+        fid:    0,
+        offset: 0,
+        count:  dirent_size(b".") as u32 + 1,
+    };
+    let r = session.process(msg).now_or_never().unwrap();
+    let mut expected = Encoder::default();
+    let qid = Qid {
+        ty:      QTDIR,
+        version: 0,
+        path:    1,
+    };
+    encode_dirent(&mut expected, &qid, 1, libc::DT_DIR, b".");
+    assert_eq!(
+        r,
+        Rmsg::Readdir {
+            data: expected.into_inner(),
+        }
+    );
+}
+
+mod renameat {
+    use super::*;
+
+    /// Don't allow a directory to be moved within itself
+    #[test]
+    fn dirloop() {
+        let src_ino = 2;
+        let dst_ino = 3;
+        let mut session = make_session(|mock_fs| {
+            mock_fs
+                .expect_lookup()
+                .withf(|_, fd, name| fd.ino() == ROOT && name == "src")
+                .returning(move |_, _, _| {
+                    Ok(FileDataMut::new_for_tests(Some(ROOT), src_ino))
+                });
+            mock_fs
+                .expect_getattr()
+                .withf(move |fd| fd.ino() == src_ino)
+                .return_const(Ok(attr(src_ino, libc::S_IFDIR | 0o755)));
+            mock_fs
+                .expect_lookup()
+                .withf(move |_, fd, name| fd.ino() == src_ino && name == "dst")
+                .returning(move |_, _, _| {
+                    Ok(FileDataMut::new_for_tests(Some(src_ino), dst_ino))
+                });
+            mock_fs
+                .expect_getattr()
+                .withf(move |fd| fd.ino() == dst_ino)
+                .return_const(Ok(attr(dst_ino, libc::S_IFDIR | 0o755)));
+            mock_fs
+                .expect_lookup()
+                .withf(move |_, fd, name| fd.ino() == dst_ino && name == "src")
+                .returning(|_, _, _| Err(libc::ENOENT));
+            mock_fs.expect_rename().never();
+            mock_fs.expect_inactive().return_const(());
+        });
+        attach(&mut session);
+        // Walk fid 1 to src/dst
+        let msg = Tmsg::Walk {
+            fid:    0,
+            newfid: 1,
+            wnames: vec![OsString::from("src"), OsString::from("dst")],
+        };
+        let r = session.process(msg).now_or_never().unwrap();
+        assert!(matches!(r, Rmsg::Walk { qids } if qids.len() == 2));
+
+        // Try to move src to src/dst/src
+        let msg = Tmsg::Renameat {
+            olddirfid: 0,
+            oldname:   OsString::from("src"),
+            newdirfid: 1,
+            newname:   OsString::from("src"),
+        };
+        let r = session.process(msg).now_or_never().unwrap();
+        assert_eq!(r, Rmsg::Lerror(22));
+    }
+}
+
+/// Unlinking a file that some fid still references must pass its handle to
+/// the Vfs, and must not release it.
+#[test]
+fn unlinkat_held() {
+    let ino = 2;
+    let mut session = make_session(|mock_fs| {
+        mock_fs
+            .expect_lookup()
+            .withf(|_, fd, name| fd.ino() == ROOT && name == "foo")
+            .returning(move |_, _, _| {
+                Ok(FileDataMut::new_for_tests(None, ino))
+            });
+        mock_fs
+            .expect_getattr()
+            .withf(move |fd| fd.ino() == ino)
+            .return_const(Ok(attr(ino, libc::S_IFREG | 0o644)));
+        mock_fs
+            .expect_unlink()
+            .withf(move |parent, fd, name| {
+                parent.ino() == ROOT &&
+                    fd.map(FileData::ino) == Some(ino) &&
+                    name == "foo"
+            })
+            .times(1)
+            .return_const(Ok(()));
+        mock_fs.expect_inactive().never();
+    });
+    attach(&mut session);
+    let msg = Tmsg::Walk {
+        fid:    0,
+        newfid: 1,
+        wnames: vec![OsString::from("foo")],
+    };
+    session.process(msg).now_or_never().unwrap();
+    let msg = Tmsg::Unlinkat {
+        dirfid: 0,
+        name:   OsString::from("foo"),
+        flags:  0,
+    };
+    let r = session.process(msg).now_or_never().unwrap();
+    assert_eq!(r, Rmsg::Unlinkat);
+    let export = &session.fids[&1].export;
+    assert_eq!(export.files.lock().unwrap()[&ino].lookup_count, 1);
+}
+
+mod version {
+    use super::*;
+
+    #[test]
+    fn msize_too_large() {
+        let mut session = make_session(|_| ());
+        let msg = Tmsg::Version {
+            msize:   u32::MAX,
+            version: OsString::from("9P2000.L"),
+        };
+        let r = session.process(msg).now_or_never().unwrap();
+        assert_eq!(
+            r,
+            Rmsg::Version {
+                msize:   MAX_MSIZE,
+                version: VERSION.to_vec(),
+            }
+        );
+    }
+
+    #[test]
+    fn unknown() {
+        let mut session = make_session(|_| ());
+        let msg = Tmsg::Version {
+            msize:   8192,
+            version: OsString::from("9P2000.u"),
+        };
+        let r = session.process(msg).now_or_never().unwrap();
+        assert_eq!(
+            r,
+            Rmsg::Version {
+                msize:   8192,
+                version: b"unknown".to_vec(),
+            }
+        );
+    }
+}
+
+mod walk {
+    use super::*;
+
+    /// A walk that fails partway should return the qids found so far, and not
+    /// create newfid.
+    #[test]
+    fn partial() {
+        let ino = 2;
+        let mut session = make_session(|mock_fs| {
+            mock_fs
+                .expect_lookup()
+                .withf(|_, fd, name| fd.ino() == ROOT && name == "dir")
+                .returning(move |_, _, _| {
+                    Ok(FileDataMut::new_for_tests(Some(ROOT), ino))
+                });
+            mock_fs
+                .expect_lookup()
+                .withf(move |_, fd, name| fd.ino() == ino && name == "nonexist")
+                .returning(|_, _, _| Err(libc::ENOENT));
+            mock_fs
+                .expect_getattr()
+                .withf(move |fd| fd.ino() == ino)
+                .return_const(Ok(attr(ino, libc::S_IFDIR | 0o755)));
+            mock_fs
+                .expect_inactive()
+                .withf(move |fd| fd.ino() == ino)
+                .times(1)
+                .return_const(());
+        });
+        attach(&mut session);
+        let msg = Tmsg::Walk {
+            fid:    0,
+            newfid: 1,
+            wnames: vec![OsString::from("dir"), OsString::from("nonexist")],
+        };
+        let r = session.process(msg).now_or_never().unwrap();
+        let qid = Qid {
+            ty:      QTDIR,
+            version: 0,
+            path:    ino,
+        };
+        assert_eq!(r, Rmsg::Walk { qids: vec![qid] });
+        assert!(!session.fids.contains_key(&1));
+    }
+
+    /// ".." at the root should stay at the root
+    #[test]
+    fn root_dotdot() {
+        let mut session = make_session(|_| ());
+        attach(&mut session);
+        let msg = Tmsg::Walk {
+            fid:    0,
+            newfid: 1,
+            wnames: vec![OsString::from("..")],
+        };
+        let r = session.process(msg).now_or_never().unwrap();
+        let qid = Qid {
+            ty:      QTDIR,
+            version: 0,
+            path:    ROOT,
+        };
+        assert_eq!(r, Rmsg::Walk { qids: vec![qid] });
+        assert_eq!(session.fids[&1].ino, ROOT);
+        let export = &session.fids[&1].export;
+        assert_eq!(export.files.lock().unwrap()[&ROOT].lookup_count, 2);
+    }
+}
+
+mod wire {
+    use super::*;
+
+    #[test]
+    fn decode_truncated() {
+        // A Twalk that claims two names, but only has one
+        let mut body = Vec::new();
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&1u32.to_le_bytes());
+        body.extend_from_slice(&2u16.to_le_bytes());
+        body.extend_from_slice(&3u16.to_le_bytes());
+        body.extend_from_slice(b"foo");
+        assert_eq!(Tmsg::decode(110, &body), Err(libc::EINVAL));
+    }
+
+    #[test]
+    fn decode_walk() {
+        let mut body = Vec::new();
+        body.extend_from_slice(&0u32.to_le_bytes());
+        body.extend_from_slice(&1u32.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes());
+        body.extend_from_slice(&3u16.to_le_bytes());
+        body.extend_from_slice(b"foo");
+        assert_eq!(
+            Tmsg::decode(110, &body),
+            Ok(Tmsg::Walk {
+                fid:    0,
+                newfid: 1,
+                wnames: vec![OsString::from("foo")],
+            })
+        );
+    }
+
+    #[test]
+    fn encode_lerror() {
+        let buf = Rmsg::Lerror(2).encode(0x1234);
+        assert_eq!(buf, [11, 0, 0, 0, 7, 0x34, 0x12, 2, 0, 0, 0]);
+    }
+
+    #[test]
+    fn encode_version() {
+        let rmsg = Rmsg::Version {
+            msize:   8192,
+            version: VERSION.to_vec(),
+        };
+        let buf = rmsg.encode(!0);
+        let mut expected = vec![21, 0, 0, 0, 101, 0xff, 0xff, 0, 0x20, 0, 0];
+        expected.extend_from_slice(&8u16.to_le_bytes());
+        expected.extend_from_slice(b"9P2000.L");
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn errno() {
+        assert_eq!(linux_errno(libc::ENOATTR), 61);
+        assert_eq!(linux_errno(libc::EOPNOTSUPP), 95);
+        assert_eq!(linux_errno(libc::EINTEGRITY), 5);
+    }
+
+    /// Unimplemented messages should decode, so we can reply with an error
+    #[test]
+    fn unsupported() {
+        // Txattrwalk
+        assert_eq!(Tmsg::decode(30, &[]), Ok(Tmsg::Unsupported(30)));
+    }
+}
+
+/// The session should frame messages on a byte stream, and reply to garbage
+/// with an error
+#[tokio::test]
+async fn run() {
+    let session = make_session(|_| ());
+    let (mut client, server) = tokio::io::duplex(4096);
+    let (_tx, rx) = watch::channel(());
+    let task = tokio::spawn(session.run(server, rx));
+
+    // A Tclunk that's too short
+    client.write_all(&[8, 0, 0, 0, 120, 1, 0, 0]).await.unwrap();
+    let mut buf = [0u8; 11];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf, [11, 0, 0, 0, 7, 1, 0, 22, 0, 0, 0]);
+
+    // A Tversion
+    let mut tversion = vec![21, 0, 0, 0, 100, 0xff, 0xff, 0, 0x20, 0, 0];
+    tversion.extend_from_slice(&8u16.to_le_bytes());
+    tversion.extend_from_slice(b"9P2000.L");
+    client.write_all(&tversion).await.unwrap();
+    let mut buf = [0u8; 21];
+    client.read_exact(&mut buf).await.unwrap();
+    assert_eq!(buf[4], 101);
+    assert_eq!(&buf[13..], b"9P2000.L");
+
+    drop(client);
+    task.await.unwrap();
+}
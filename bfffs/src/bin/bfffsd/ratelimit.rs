@@ -0,0 +1,274 @@
+// vim: tw=80
+//! Rate limiting for repeated log messages
+//!
+//! A dying disk can produce the same warning thousands of times per second.
+//! [`RateLimit`] passes the first of each kind of warning or error through,
+//! and then suppresses repeats for a while.  When the next one is finally
+//! allowed, it is preceded by a summary like "previous message repeated 3120
+//! times".
+//!
+//! Messages are of the same kind if they come from the same callsite, and have
+//! the same value for the `vdev` field, if any.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, Write},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tracing::{
+    callsite::Identifier,
+    field::{Field, Visit},
+    Event,
+    Level,
+    Subscriber,
+};
+use tracing_subscriber::{
+    fmt::MakeWriter,
+    layer::{Context, Layer},
+};
+
+/// Repeats of the same kind of message are suppressed for this long.
+const INTERVAL: Duration = Duration::from_secs(10);
+
+/// Identifies a kind of message
+#[derive(Eq, Hash, PartialEq)]
+struct Key {
+    callsite: Identifier,
+    /// The value of the event's `vdev` field, if any
+    vdev:     Option<String>,
+}
+
+struct Window {
+    start:      Instant,
+    /// How many messages have been suppressed since `start`
+    suppressed: u64,
+}
+
+/// Extracts an event's `vdev` field
+#[derive(Default)]
+struct VdevVisitor(Option<String>);
+
+impl Visit for VdevVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "vdev" {
+            self.0 = Some(format!("{value:?}"));
+        }
+    }
+}
+
+/// A [`Layer`] that suppresses repeated warnings and errors.
+///
+/// Summaries of suppressed messages are written to `make_writer`, which
+/// should be the same destination that the formatting layer uses.
+pub struct RateLimit<W> {
+    interval:    Duration,
+    make_writer: W,
+    windows:     Mutex<HashMap<Key, Window>>,
+}
+
+impl RateLimit<fn() -> io::Stdout> {
+    /// Write summaries to stdout, like the default formatting layer does.
+    pub fn new() -> Self {
+        RateLimit::with_writer(io::stdout)
+    }
+}
+
+impl<W> RateLimit<W> {
+    pub fn with_writer(make_writer: W) -> Self {
+        RateLimit {
+            interval: INTERVAL,
+            make_writer,
+            windows: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Suppress repeats for `interval` instead of the default.
+    #[cfg(test)]
+    fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+}
+
+impl<S, W> Layer<S> for RateLimit<W>
+where
+    S: Subscriber,
+    W: for<'w> MakeWriter<'w> + 'static,
+{
+    fn event_enabled(&self, event: &Event<'_>, _ctx: Context<'_, S>) -> bool {
+        let meta = event.metadata();
+        if *meta.level() > Level::WARN {
+            // Less severe messages are only enabled while debugging, when
+            // every one of them may matter.
+            return true;
+        }
+        let mut visitor = VdevVisitor::default();
+        event.record(&mut visitor);
+        let key = Key {
+            callsite: meta.callsite(),
+            vdev:     visitor.0,
+        };
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+        match windows.get_mut(&key) {
+            Some(w) if now.duration_since(w.start) < self.interval => {
+                w.suppressed += 1;
+                false
+            }
+            Some(w) => {
+                if w.suppressed > 0 {
+                    // Nothing useful can be done if logging fails.
+                    let _ = writeln!(
+                        self.make_writer.make_writer(),
+                        "{} {}: previous message repeated {} times",
+                        meta.level(),
+                        meta.target(),
+                        w.suppressed
+                    );
+                }
+                *w = Window {
+                    start:      now,
+                    suppressed: 0,
+                };
+                true
+            }
+            None => {
+                windows.insert(
+                    key,
+                    Window {
+                        start:      now,
+                        suppressed: 0,
+                    },
+                );
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use tracing_subscriber::prelude::*;
+
+    use super::*;
+
+    /// Captures the summaries
+    #[derive(Clone, Default)]
+    struct Buf(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> MakeWriter<'a> for Buf {
+        type Writer = Buf;
+
+        fn make_writer(&'a self) -> Buf {
+            self.clone()
+        }
+    }
+
+    impl Buf {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    /// Counts the events that get past the rate limiter
+    #[derive(Clone, Default)]
+    struct Counter(Arc<AtomicUsize>);
+
+    impl<S: Subscriber> Layer<S> for Counter {
+        fn on_event(&self, _event: &Event<'_>, _ctx: Context<'_, S>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    impl Counter {
+        fn get(&self) -> usize {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    fn warn_n(n: usize, vdev: u32) {
+        for _ in 0..n {
+            tracing::warn!(vdev, "Checksum mismatch");
+        }
+    }
+
+    #[test]
+    fn debug_is_not_limited() {
+        let counter = Counter::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(RateLimit::with_writer(Buf::default()))
+            .with(counter.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            for _ in 0..10 {
+                tracing::debug!("Something");
+            }
+        });
+        assert_eq!(counter.get(), 10);
+    }
+
+    #[test]
+    fn different_vdevs() {
+        let counter = Counter::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(RateLimit::with_writer(Buf::default()))
+            .with(counter.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            for vdev in 0..3 {
+                warn_n(10, vdev);
+            }
+        });
+        assert_eq!(counter.get(), 3);
+    }
+
+    #[test]
+    fn repeats_are_suppressed() {
+        let buf = Buf::default();
+        let counter = Counter::default();
+        let subscriber = tracing_subscriber::registry()
+            .with(RateLimit::with_writer(buf.clone()))
+            .with(counter.clone());
+        tracing::subscriber::with_default(subscriber, || warn_n(100, 0));
+        assert_eq!(counter.get(), 1);
+        assert_eq!(buf.contents(), "");
+    }
+
+    #[test]
+    fn summary() {
+        let buf = Buf::default();
+        let counter = Counter::default();
+        let ratelimit = RateLimit::with_writer(buf.clone())
+            .interval(Duration::from_millis(50));
+        let subscriber = tracing_subscriber::registry()
+            .with(ratelimit)
+            .with(counter.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            warn_n(3, 0);
+            std::thread::sleep(Duration::from_millis(100));
+            warn_n(1, 0);
+        });
+        assert_eq!(counter.get(), 2);
+        let contents = buf.contents();
+        assert!(
+            contents.ends_with(": previous message repeated 2 times\n"),
+            "{contents}"
+        );
+    }
+}
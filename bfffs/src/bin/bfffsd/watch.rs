@@ -0,0 +1,271 @@
+// vim: tw=80
+//! Close-after-write notifications for helper processes
+//!
+//! A helper, like a virus scanner or an indexer, registers a watch on a file
+//! system and then repeatedly polls it for events.  Each poll acknowledges the
+//! events returned by the previous one.  If the watch is synchronous, then
+//! every close of a written file waits until the helper acknowledges it, or
+//! until [`ACK_TIMEOUT`] passes.
+//!
+//! The control socket has no way to tell bfffsd when a helper goes away.
+//! Instead, a watch that goes unpolled for [`IDLE_TIMEOUT`] gets dropped.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use bfffs_core::{
+    rpc::fs::{CloseEvent, WatchId},
+    Error,
+    Result,
+};
+use futures::future;
+use tokio::sync::{oneshot, Notify};
+use tracing::warn;
+
+/// A synchronous watch must acknowledge each event within this long
+const ACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Drop any watch that goes this long without being polled
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Most events that may be queued for a single watch.  Any more are discarded.
+const MAX_QUEUED: usize = 1024;
+
+/// Most events returned by a single poll
+const MAX_BATCH: usize = 64;
+
+struct Watch {
+    /// Name of the watched file system
+    name:      String,
+    sync:      bool,
+    /// Events not yet returned by a poll, with their acknowledgement channels
+    /// if the watch is synchronous
+    queue:     VecDeque<(CloseEvent, Option<oneshot::Sender<()>>)>,
+    /// Acknowledgement channels for the events returned by the last poll
+    unacked:   Vec<oneshot::Sender<()>>,
+    /// Is a poll waiting for events right now?
+    polling:   bool,
+    last_poll: Instant,
+    notify:    Arc<Notify>,
+}
+
+impl Watch {
+    /// Does this watch cover the named file system?
+    fn covers(&self, dataset: &str) -> bool {
+        dataset
+            .strip_prefix(self.name.as_str())
+            .map(|rest| rest.is_empty() || rest.starts_with('/'))
+            .unwrap_or(false)
+    }
+
+    fn expired(&self, now: Instant) -> bool {
+        !self.polling && now - self.last_poll > IDLE_TIMEOUT
+    }
+}
+
+/// Marks a watch as no longer polling, even if the poll gets cancelled
+struct PollGuard<'a> {
+    watches: &'a Watches,
+    id:      WatchId,
+}
+
+impl Drop for PollGuard<'_> {
+    fn drop(&mut self) {
+        let mut guard = self.watches.watches.lock().unwrap();
+        if let Some(w) = guard.get_mut(&self.id) {
+            w.polling = false;
+            w.last_poll = Instant::now();
+        }
+    }
+}
+
+/// Every registered watch
+#[derive(Default)]
+pub struct Watches {
+    next_id: AtomicU64,
+    watches: Mutex<HashMap<WatchId, Watch>>,
+}
+
+impl Watches {
+    /// Report that a file was closed after being written through.
+    ///
+    /// Returns once every synchronous watch covering the file's dataset has
+    /// acknowledged the event, or timed out.
+    pub async fn closed(&self, event: CloseEvent) {
+        let mut acks = Vec::new();
+        {
+            let now = Instant::now();
+            let mut guard = self.watches.lock().unwrap();
+            guard.retain(|_, w| !w.expired(now));
+            for w in guard.values_mut().filter(|w| w.covers(&event.dataset)) {
+                if w.queue.len() >= MAX_QUEUED {
+                    warn!("Discarding close event for {}", event.dataset);
+                    continue;
+                }
+                let tx = if w.sync {
+                    let (tx, rx) = oneshot::channel();
+                    acks.push(rx);
+                    Some(tx)
+                } else {
+                    None
+                };
+                w.queue.push_back((event.clone(), tx));
+                w.notify.notify_one();
+            }
+        }
+        if !acks.is_empty() {
+            let r =
+                tokio::time::timeout(ACK_TIMEOUT, future::join_all(acks)).await;
+            if r.is_err() {
+                warn!(
+                    "Timed out waiting to acknowledge close event for {}",
+                    event.dataset
+                );
+            }
+        }
+    }
+
+    /// Acknowledge the events returned by the previous poll, and then wait for
+    /// the next batch.
+    pub async fn events(&self, id: WatchId) -> Result<Vec<CloseEvent>> {
+        let _poll_guard = PollGuard { watches: self, id };
+        loop {
+            let notify = {
+                let mut guard = self.watches.lock().unwrap();
+                let w = guard.get_mut(&id).ok_or(Error::ENOENT)?;
+                w.polling = true;
+                for tx in w.unacked.drain(..) {
+                    let _ = tx.send(());
+                }
+                if !w.queue.is_empty() {
+                    let n = w.queue.len().min(MAX_BATCH);
+                    let mut events = Vec::with_capacity(n);
+                    for (event, tx) in w.queue.drain(..n) {
+                        events.push(event);
+                        w.unacked.extend(tx);
+                    }
+                    return Ok(events);
+                }
+                w.notify.clone()
+            };
+            // Notify stores a permit if an event arrives before we get here,
+            // so none can be missed.
+            notify.notified().await;
+        }
+    }
+
+    /// Cancel a watch.  Any closes waiting on it are released.
+    pub fn unwatch(&self, id: WatchId) -> Result<()> {
+        let w = self.watches.lock().unwrap().remove(&id).ok_or(Error::ENOENT)?;
+        // Wake any poll in progress, so it can return ENOENT
+        w.notify.notify_one();
+        Ok(())
+    }
+
+    /// Register a new watch on the named file system and its descendants
+    pub fn watch(&self, name: String, sync: bool) -> WatchId {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let w = Watch {
+            name,
+            sync,
+            queue: VecDeque::new(),
+            unacked: Vec::new(),
+            polling: false,
+            last_poll: Instant::now(),
+            notify: Arc::new(Notify::new()),
+        };
+        self.watches.lock().unwrap().insert(id, w);
+        id
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use futures::FutureExt;
+
+    use super::*;
+
+    fn event(dataset: &str) -> CloseEvent {
+        CloseEvent {
+            dataset: dataset.to_owned(),
+            ino:     42,
+            path:    Some(format!("/{dataset}/foo")),
+        }
+    }
+
+    #[test]
+    fn async_watch() {
+        let watches = Watches::default();
+        let id = watches.watch("mypool".to_owned(), false);
+        // An asynchronous watch shouldn't block the close
+        watches.closed(event("mypool")).now_or_never().unwrap();
+        let events = watches.events(id).now_or_never().unwrap().unwrap();
+        assert_eq!(events, vec![event("mypool")]);
+    }
+
+    #[test]
+    fn descendants() {
+        let watches = Watches::default();
+        let id = watches.watch("mypool/foo".to_owned(), false);
+        for ds in ["mypool", "mypool/foobar", "mypool/foo/bar"] {
+            watches.closed(event(ds)).now_or_never().unwrap();
+        }
+        let events = watches.events(id).now_or_never().unwrap().unwrap();
+        assert_eq!(events, vec![event("mypool/foo/bar")]);
+    }
+
+    #[test]
+    fn enoent() {
+        let watches = Watches::default();
+        let id = watches.watch("mypool".to_owned(), false);
+        watches.unwatch(id).unwrap();
+        assert_eq!(watches.unwatch(id), Err(Error::ENOENT));
+        let r = watches.events(id).now_or_never().unwrap();
+        assert_eq!(r, Err(Error::ENOENT));
+    }
+
+    #[test]
+    fn no_events() {
+        let watches = Watches::default();
+        let id = watches.watch("mypool".to_owned(), false);
+        assert!(watches.events(id).now_or_never().is_none());
+    }
+
+    /// A synchronous watch should block the close until the next poll
+    #[tokio::test]
+    async fn sync_watch() {
+        let watches = Arc::new(Watches::default());
+        let id = watches.watch("mypool".to_owned(), true);
+        let watches2 = watches.clone();
+        let closer =
+            tokio::spawn(async move { watches2.closed(event("mypool")).await });
+        let events = watches.events(id).await.unwrap();
+        assert_eq!(events, vec![event("mypool")]);
+        tokio::task::yield_now().await;
+        assert!(!closer.is_finished());
+        // Polling again acknowledges the event, even though no new events
+        // are available.
+        assert!(watches.events(id).now_or_never().is_none());
+        closer.await.unwrap();
+    }
+
+    /// Cancelling a synchronous watch should release any waiting closes
+    #[tokio::test]
+    async fn sync_unwatch() {
+        let watches = Arc::new(Watches::default());
+        let id = watches.watch("mypool".to_owned(), true);
+        let watches2 = watches.clone();
+        let closer =
+            tokio::spawn(async move { watches2.closed(event("mypool")).await });
+        watches.events(id).await.unwrap();
+        watches.unwatch(id).unwrap();
+        closer.await.unwrap();
+    }
+}
@@ -0,0 +1,1880 @@
+// vim: tw=80
+//! Out-of-process FUSE sessions
+//!
+//! With `-o fuse_workers=on`, each mounted file system's FUSE session runs in
+//! its own worker process: bfffsd re-executed with [`ARG`].  The worker
+//! handles the FUSE protocol and forwards every file system operation to bfffsd
+//! over a private socket, which it receives as its standard input.  bfffsd
+//! performs the operation on the real [`Vfs`] and sends back the result.
+//!
+//! So a crash while handling FUSE requests only takes down one worker.  bfffsd
+//! notices, releases whatever the worker had open, forcibly unmounts the dead
+//! session, and mounts the file system again in a fresh worker.  Conversely, if
+//! bfffsd goes away, each worker forcibly unmounts its file system and exits.
+//!
+//! Every message on the socket is a little-endian `u32` length followed by that
+//! many bytes of bincode.  bfffsd first sends a [`Setup`].  After that the
+//! worker sends [`Up`] messages, and bfffsd answers each request with its id
+//! and the bincode of the corresponding [`Vfs`] method's result.
+// Some conversions are only necessary for one of FreeBSD 11 or 12.  After libc
+// makes the switchover, delete this line and clean them up.
+#![allow(clippy::useless_conversion)]
+#![allow(clippy::unnecessary_cast)]
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    io,
+    mem,
+    os::unix::{
+        ffi::OsStrExt,
+        io::{AsFd, OwnedFd},
+    },
+    path::PathBuf,
+    process::{exit, Stdio},
+    slice,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use bfffs_core::{
+    fs::{
+        Advice,
+        ExtAttr,
+        ExtAttrNamespace,
+        FadviseStats,
+        FileData,
+        FileDataMut,
+        GetAttr,
+        SeekWhence,
+        SetAttr,
+    },
+    vfs::Vfs,
+    Error,
+    SGList,
+};
+use bfffs_fuse::{CloseHook, FuseFs};
+use divbuf::{DivBuf, DivBufShared};
+use fuse3::raw::Session;
+use futures::{
+    future::BoxFuture,
+    stream::{self, BoxStream},
+    FutureExt,
+    StreamExt,
+    TryStreamExt,
+};
+use libc::dev_t;
+use nix::{
+    errno::Errno,
+    mount::{unmount, MntFlags},
+};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_derive::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{
+        unix::{OwnedReadHalf, OwnedWriteHalf},
+        UnixStream,
+    },
+    process::{Child, Command},
+    runtime::RuntimeFlavor,
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+use tracing::{error, info, warn};
+
+use super::{p9::OpenFn, MountOpts};
+
+/// bfffsd's first argument when it should run as a FUSE worker
+pub const ARG: &str = "--fuse-worker";
+
+/// Largest read or write that FUSE will ever request.  It's the `max_write`
+/// that fuse3 negotiates with the kernel, and the kernel never reads more than
+/// that at once, either.
+const MAX_IO: usize = 1 << 24;
+
+/// Largest message that either side will accept.  Big enough for any FUSE
+/// read or write, plus the rest of its request or reply.
+const MAX_FRAME: u32 = MAX_IO as u32 + 4096;
+
+/// Most directory entries returned by a single readdir request
+const READDIR_BATCH: usize = 128;
+
+/// Give up on a file system whose worker crashes this many times in a row
+const MAX_RESTARTS: u32 = 5;
+
+/// A worker that stays up this long is considered healthy again, and its
+/// restart count is reset.
+const RESTART_WINDOW: Duration = Duration::from_secs(60);
+
+/// Sent by bfffsd as soon as the worker starts
+#[derive(Debug, Deserialize, Serialize)]
+struct Setup {
+    mountpoint:         PathBuf,
+    opts:               MountOpts,
+    /// Limits the read data buffered by the worker's session
+    session_read_limit: Option<usize>,
+}
+
+/// Messages from the worker to bfffsd
+#[derive(Debug, Deserialize, Serialize)]
+enum Up {
+    /// The result of mounting the file system.  Sent exactly once.
+    Mounted(Result<(), i32>),
+    Request(u64, Request),
+}
+
+/// File system operations forwarded by the worker.  Each corresponds to one
+/// [`Vfs`] method, except for `Closed` which runs the close hook.
+#[derive(Debug, Deserialize, Serialize)]
+enum Request {
+    CheckHandle(u64),
+    Closed {
+        ino:  u64,
+        path: Option<PathBuf>,
+    },
+    Create {
+        parent: FileData,
+        name:   OsString,
+        perm:   u16,
+        uid:    u32,
+        gid:    u32,
+    },
+    Deallocate {
+        fd:     FileData,
+        offset: u64,
+        len:    u64,
+    },
+    DeleteExtAttr {
+        fd:   FileData,
+        ns:   ExtAttrNamespace,
+        name: OsString,
+    },
+    Fadvise {
+        fd:     FileData,
+        advice: Advice,
+    },
+    FadviseStats,
+    Fdatasync(FileData),
+    Fsync(FileData),
+    Getattr(FileData),
+    GetExtAttr {
+        fd:   FileData,
+        ns:   ExtAttrNamespace,
+        name: OsString,
+    },
+    GetExtAttrLen {
+        fd:   FileData,
+        ns:   ExtAttrNamespace,
+        name: OsString,
+    },
+    Ilookup(u64),
+    Inactive(FileDataMut),
+    Link {
+        parent: FileData,
+        fd:     FileData,
+        name:   OsString,
+    },
+    /// Returns every extended attribute, each bincode-encoded in turn, so the
+    /// worker can apply its own callback.
+    ListExtAttr(FileData),
+    Lookup {
+        grandparent: Option<FileData>,
+        parent:      FileData,
+        name:        OsString,
+    },
+    Lseek {
+        fd:     FileData,
+        offset: u64,
+        whence: SeekWhence,
+    },
+    Mkblock {
+        parent: FileData,
+        name:   OsString,
+        perm:   u16,
+        uid:    u32,
+        gid:    u32,
+        rdev:   dev_t,
+    },
+    Mkchar {
+        parent: FileData,
+        name:   OsString,
+        perm:   u16,
+        uid:    u32,
+        gid:    u32,
+        rdev:   dev_t,
+    },
+    Mkdir {
+        parent: FileData,
+        name:   OsString,
+        perm:   u16,
+        uid:    u32,
+        gid:    u32,
+    },
+    Mkfifo {
+        parent: FileData,
+        name:   OsString,
+        perm:   u16,
+        uid:    u32,
+        gid:    u32,
+    },
+    Mksock {
+        parent: FileData,
+        name:   OsString,
+        perm:   u16,
+        uid:    u32,
+        gid:    u32,
+    },
+    Open {
+        fd:    FileData,
+        pid:   u32,
+        flags: u32,
+    },
+    Read {
+        fd:     FileData,
+        offset: u64,
+        size:   usize,
+    },
+    /// Returns at most [`READDIR_BATCH`] entries, starting at `soffs`
+    Readdir {
+        fd:    FileData,
+        soffs: i64,
+    },
+    Readlink(FileData),
+    Release(u64),
+    Rename {
+        parent:    FileData,
+        fd:        FileData,
+        name:      OsString,
+        newparent: FileData,
+        newino:    Option<u64>,
+        newname:   OsString,
+    },
+    Rmdir {
+        parent: FileData,
+        name:   OsString,
+    },
+    Root,
+    Setattr {
+        fd:   FileData,
+        attr: SetAttr,
+    },
+    SetExtAttr {
+        fd:   FileData,
+        ns:   ExtAttrNamespace,
+        name: OsString,
+        data: Vec<u8>,
+    },
+    Statvfs,
+    Symlink {
+        parent: FileData,
+        name:   OsString,
+        perm:   u16,
+        uid:    u32,
+        gid:    u32,
+        link:   OsString,
+    },
+    Sync,
+    Unlink {
+        parent: FileData,
+        fd:     Option<FileData>,
+        name:   OsString,
+    },
+    Write {
+        fd:     FileData,
+        offset: u64,
+        data:   Vec<u8>,
+        flags:  u32,
+    },
+}
+
+/// A directory entry, as sent over the socket
+#[derive(Debug, Deserialize, Serialize)]
+struct Dirent {
+    ino:    u64,
+    dtype:  u8,
+    name:   OsString,
+    /// Offset of the next entry
+    offset: i64,
+}
+
+impl Dirent {
+    fn new(dirent: &libc::dirent, offset: i64) -> Self {
+        let nameptr = dirent.d_name.as_ptr() as *const u8;
+        let namelen = usize::from(dirent.d_namlen);
+        // Safe because the kernel guarantees that d_name contains d_namlen
+        // valid bytes.
+        let name = unsafe { slice::from_raw_parts(nameptr, namelen) };
+        Dirent {
+            ino: dirent.d_fileno as u64,
+            dtype: dirent.d_type,
+            name: OsStr::from_bytes(name).to_owned(),
+            offset,
+        }
+    }
+
+    fn to_libc(&self) -> libc::dirent {
+        // Safe because libc::dirent is POD
+        let mut dirent: libc::dirent = unsafe { mem::zeroed() };
+        dirent.d_fileno = self.ino as _;
+        dirent.d_reclen = mem::size_of::<libc::dirent>() as u16;
+        dirent.d_type = self.dtype;
+        for (dst, src) in dirent.d_name.iter_mut().zip(self.name.as_bytes()) {
+            *dst = *src as libc::c_char;
+        }
+        dirent.d_namlen = self.name.len() as _;
+        dirent
+    }
+}
+
+/// File system statistics, as sent over the socket
+#[derive(Debug, Deserialize, Serialize)]
+struct Statvfs {
+    bavail:  u64,
+    bfree:   u64,
+    blocks:  u64,
+    favail:  u64,
+    ffree:   u64,
+    files:   u64,
+    bsize:   u64,
+    frsize:  u64,
+    namemax: u64,
+}
+
+impl Statvfs {
+    fn to_libc(&self) -> libc::statvfs {
+        libc::statvfs {
+            f_bavail:  self.bavail as _,
+            f_bfree:   self.bfree as _,
+            f_blocks:  self.blocks as _,
+            f_favail:  self.favail as _,
+            f_ffree:   self.ffree as _,
+            f_files:   self.files as _,
+            f_bsize:   self.bsize as _,
+            f_flag:    0,
+            f_frsize:  self.frsize as _,
+            f_fsid:    0,
+            f_namemax: self.namemax as _,
+        }
+    }
+}
+
+impl From<libc::statvfs> for Statvfs {
+    fn from(s: libc::statvfs) -> Self {
+        Statvfs {
+            bavail:  s.f_bavail as u64,
+            bfree:   s.f_bfree as u64,
+            blocks:  s.f_blocks as u64,
+            favail:  s.f_favail as u64,
+            ffree:   s.f_ffree as u64,
+            files:   s.f_files as u64,
+            bsize:   s.f_bsize as u64,
+            frsize:  s.f_frsize as u64,
+            namemax: s.f_namemax as u64,
+        }
+    }
+}
+
+fn encode<T: Serialize>(t: &T) -> Vec<u8> {
+    bincode::serialize(t).expect("Vfs results are always serializable")
+}
+
+/// Read one message.  Returns `None` if the peer hung up.
+async fn read_frame<R, T>(r: &mut R) -> io::Result<Option<T>>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let len = match r.read_u32_le().await {
+        Ok(len) => len,
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if len > MAX_FRAME {
+        return Err(io::Error::from_raw_os_error(libc::EMSGSIZE));
+    }
+    let mut buf = vec![0; len as usize];
+    r.read_exact(&mut buf).await?;
+    bincode::deserialize(&buf)
+        .map(Some)
+        .map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))
+}
+
+async fn write_frame<W, T>(w: &mut W, msg: &T) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let payload = encode(msg);
+    let len = u32::try_from(payload.len())
+        .ok()
+        .filter(|len| *len <= MAX_FRAME)
+        .ok_or_else(|| io::Error::from_raw_os_error(libc::EMSGSIZE))?;
+    let mut buf = Vec::with_capacity(4 + payload.len());
+    buf.extend_from_slice(&len.to_le_bytes());
+    buf.extend_from_slice(&payload);
+    w.write_all(&buf).await
+}
+
+/// A worker's connection to bfffsd
+struct Conn {
+    wr:      tokio::sync::Mutex<OwnedWriteHalf>,
+    /// Requests awaiting a reply, by id.  `None` once bfffsd has hung up.
+    pending: Mutex<Option<HashMap<u64, oneshot::Sender<Vec<u8>>>>>,
+    next_id: AtomicU64,
+}
+
+impl Conn {
+    /// Send a request to bfffsd and wait for its reply.  Fails with `EIO` if
+    /// bfffsd can't be reached.
+    async fn call<T: DeserializeOwned>(&self, req: Request) -> Result<T, i32> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        match self.pending.lock().unwrap().as_mut() {
+            Some(pending) => pending.insert(id, tx),
+            None => return Err(libc::EIO),
+        };
+        if let Err(e) = self.send(&Up::Request(id, req)).await {
+            error!("Cannot send request to bfffsd: {e}");
+            if let Some(pending) = self.pending.lock().unwrap().as_mut() {
+                pending.remove(&id);
+            }
+            return Err(libc::EIO);
+        }
+        let reply = rx.await.map_err(|_| libc::EIO)?;
+        bincode::deserialize(&reply).map_err(|_| libc::EIO)
+    }
+
+    /// Like [`Conn::call`], but for the synchronous [`Vfs`] methods.
+    ///
+    /// The reply is delivered by [`Conn::dispatch`], so this needs a
+    /// multi-threaded runtime to run it while the caller blocks.  On a
+    /// current-thread runtime it fails with `EIO` rather than deadlocking.
+    fn call_blocking<T>(&self, req: Request) -> Result<T, i32>
+    where
+        T: DeserializeOwned,
+    {
+        let handle = tokio::runtime::Handle::current();
+        if handle.runtime_flavor() != RuntimeFlavor::MultiThread {
+            error!("Cannot block on bfffsd from a current-thread runtime");
+            return Err(libc::EIO);
+        }
+        tokio::task::block_in_place(|| handle.block_on(self.call(req)))
+    }
+
+    /// Route bfffsd's replies to their callers.  Returns once bfffsd hangs up,
+    /// failing anything still outstanding.
+    async fn dispatch(&self, mut rd: OwnedReadHalf) {
+        loop {
+            match read_frame::<_, (u64, Vec<u8>)>(&mut rd).await {
+                Ok(Some((id, reply))) => {
+                    let tx = self
+                        .pending
+                        .lock()
+                        .unwrap()
+                        .as_mut()
+                        .and_then(|pending| pending.remove(&id));
+                    if let Some(tx) = tx {
+                        let _ = tx.send(reply);
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Cannot read reply from bfffsd: {e}");
+                    break;
+                }
+            }
+        }
+        *self.pending.lock().unwrap() = None;
+    }
+
+    async fn send(&self, msg: &Up) -> io::Result<()> {
+        write_frame(&mut *self.wr.lock().await, msg).await
+    }
+}
+
+/// The worker's view of the file system: every operation is forwarded to
+/// bfffsd.
+struct RemoteFs {
+    conn: Arc<Conn>,
+}
+
+impl RemoteFs {
+    /// Start talking to bfffsd.  The returned task completes once bfffsd hangs
+    /// up.
+    fn connect(
+        rd: OwnedReadHalf,
+        wr: OwnedWriteHalf,
+    ) -> (Self, JoinHandle<()>) {
+        let conn = Arc::new(Conn {
+            wr:      tokio::sync::Mutex::new(wr),
+            pending: Mutex::new(Some(HashMap::new())),
+            next_id: AtomicU64::new(0),
+        });
+        let conn2 = conn.clone();
+        let dispatcher = tokio::spawn(async move { conn2.dispatch(rd).await });
+        (RemoteFs { conn }, dispatcher)
+    }
+
+    /// Fetch every extended attribute of a file
+    async fn extattrs(&self, fd: &FileData) -> Result<Vec<ExtAttr>, i32> {
+        let packed: Vec<u8> =
+            self.conn.call(Request::ListExtAttr(*fd)).await??;
+        let mut rest = &packed[..];
+        let mut extattrs = Vec::new();
+        while !rest.is_empty() {
+            let extattr = bincode::deserialize_from(&mut rest)
+                .map_err(|_| libc::EIO)?;
+            extattrs.push(extattr);
+        }
+        Ok(extattrs)
+    }
+}
+
+#[async_trait]
+impl Vfs for RemoteFs {
+    fn check_handle(&self, fh: u64) -> Result<(), i32> {
+        self.conn.call_blocking(Request::CheckHandle(fh))?
+    }
+
+    async fn create(
+        &self,
+        parent: &FileData,
+        name: &OsStr,
+        perm: u16,
+        uid: u32,
+        gid: u32,
+    ) -> Result<FileDataMut, i32> {
+        let req = Request::Create {
+            parent: *parent,
+            name: name.to_owned(),
+            perm,
+            uid,
+            gid,
+        };
+        self.conn.call(req).await?
+    }
+
+    async fn deallocate(
+        &self,
+        fd: &FileData,
+        offset: u64,
+        len: u64,
+    ) -> Result<(), i32> {
+        let req = Request::Deallocate {
+            fd: *fd,
+            offset,
+            len,
+        };
+        self.conn.call(req).await?
+    }
+
+    async fn deleteextattr(
+        &self,
+        fd: &FileData,
+        ns: ExtAttrNamespace,
+        name: &OsStr,
+    ) -> Result<(), i32> {
+        let req = Request::DeleteExtAttr {
+            fd: *fd,
+            ns,
+            name: name.to_owned(),
+        };
+        self.conn.call(req).await?
+    }
+
+    async fn fadvise(&self, fd: &FileData, advice: Advice) -> Result<(), i32> {
+        let req = Request::Fadvise { fd: *fd, advice };
+        self.conn.call(req).await?
+    }
+
+    fn fadvise_stats(&self) -> FadviseStats {
+        self.conn
+            .call_blocking(Request::FadviseStats)
+            .unwrap_or_default()
+    }
+
+    async fn fdatasync(&self, fd: &FileData) -> Result<(), i32> {
+        self.conn.call(Request::Fdatasync(*fd)).await?
+    }
+
+    async fn fsync(&self, fd: &FileData) -> Result<(), i32> {
+        self.conn.call(Request::Fsync(*fd)).await?
+    }
+
+    async fn getattr(&self, fd: &FileData) -> Result<GetAttr, i32> {
+        self.conn.call(Request::Getattr(*fd)).await?
+    }
+
+    async fn getextattr(
+        &self,
+        fd: &FileData,
+        ns: ExtAttrNamespace,
+        name: &OsStr,
+    ) -> Result<DivBuf, i32> {
+        let req = Request::GetExtAttr {
+            fd: *fd,
+            ns,
+            name: name.to_owned(),
+        };
+        let data: Vec<u8> = self.conn.call(req).await??;
+        Ok(DivBufShared::from(data).try_const().unwrap())
+    }
+
+    async fn getextattrlen(
+        &self,
+        fd: &FileData,
+        ns: ExtAttrNamespace,
+        name: &OsStr,
+    ) -> Result<u32, i32> {
+        let req = Request::GetExtAttrLen {
+            fd: *fd,
+            ns,
+            name: name.to_owned(),
+        };
+        self.conn.call(req).await?
+    }
+
+    async fn ilookup(&self, ino: u64) -> Result<FileDataMut, i32> {
+        self.conn.call(Request::Ilookup(ino)).await?
+    }
+
+    async fn inactive(&self, fd: FileDataMut) {
+        let r: Result<(), i32> = self.conn.call(Request::Inactive(fd)).await;
+        if let Err(e) = r {
+            warn!("Cannot inactivate file: {e}");
+        }
+    }
+
+    async fn link(
+        &self,
+        parent: &FileData,
+        fd: &FileData,
+        name: &OsStr,
+    ) -> Result<(), i32> {
+        let req = Request::Link {
+            parent: *parent,
+            fd:     *fd,
+            name:   name.to_owned(),
+        };
+        self.conn.call(req).await?
+    }
+
+    async fn listextattr<F>(
+        &self,
+        fd: &FileData,
+        size: u32,
+        f: F,
+    ) -> Result<Vec<u8>, i32>
+    where
+        F: Fn(&mut Vec<u8>, &ExtAttr) + Send + 'static,
+    {
+        let mut buf = Vec::with_capacity(size as usize);
+        for extattr in self.extattrs(fd).await?.iter() {
+            f(&mut buf, extattr);
+        }
+        Ok(buf)
+    }
+
+    async fn listextattrlen<F>(&self, fd: &FileData, f: F) -> Result<u32, i32>
+    where
+        F: Fn(&ExtAttr) -> u32 + Send + 'static,
+    {
+        Ok(self.extattrs(fd).await?.iter().map(f).sum())
+    }
+
+    async fn lookup<'a>(
+        &self,
+        grandparent: Option<&'a FileData>,
+        parent: &'a FileData,
+        name: &OsStr,
+    ) -> Result<FileDataMut, i32> {
+        let req = Request::Lookup {
+            grandparent: grandparent.copied(),
+            parent:      *parent,
+            name:        name.to_owned(),
+        };
+        self.conn.call(req).await?
+    }
+
+    async fn lseek(
+        &self,
+        fd: &FileData,
+        offset: u64,
+        whence: SeekWhence,
+    ) -> Result<u64, i32> {
+        let req = Request::Lseek {
+            fd: *fd,
+            offset,
+            whence,
+        };
+        self.conn.call(req).await?
+    }
+
+    async fn mkblock(
+        &self,
+        parent: &FileData,
+        name: &OsStr,
+        perm: u16,
+        uid: u32,
+        gid: u32,
+        rdev: dev_t,
+    ) -> Result<FileDataMut, i32> {
+        let req = Request::Mkblock {
+            parent: *parent,
+            name: name.to_owned(),
+            perm,
+            uid,
+            gid,
+            rdev,
+        };
+        self.conn.call(req).await?
+    }
+
+    async fn mkchar(
+        &self,
+        parent: &FileData,
+        name: &OsStr,
+        perm: u16,
+        uid: u32,
+        gid: u32,
+        rdev: dev_t,
+    ) -> Result<FileDataMut, i32> {
+        let req = Request::Mkchar {
+            parent: *parent,
+            name: name.to_owned(),
+            perm,
+            uid,
+            gid,
+            rdev,
+        };
+        self.conn.call(req).await?
+    }
+
+    async fn mkdir(
+        &self,
+        parent: &FileData,
+        name: &OsStr,
+        perm: u16,
+        uid: u32,
+        gid: u32,
+    ) -> Result<FileDataMut, i32> {
+        let req = Request::Mkdir {
+            parent: *parent,
+            name: name.to_owned(),
+            perm,
+            uid,
+            gid,
+        };
+        self.conn.call(req).await?
+    }
+
+    async fn mkfifo(
+        &self,
+        parent: &FileData,
+        name: &OsStr,
+        perm: u16,
+        uid: u32,
+        gid: u32,
+    ) -> Result<FileDataMut, i32> {
+        let req = Request::Mkfifo {
+            parent: *parent,
+            name: name.to_owned(),
+            perm,
+            uid,
+            gid,
+        };
+        self.conn.call(req).await?
+    }
+
+    async fn mksock(
+        &self,
+        parent: &FileData,
+        name: &OsStr,
+        perm: u16,
+        uid: u32,
+        gid: u32,
+    ) -> Result<FileDataMut, i32> {
+        let req = Request::Mksock {
+            parent: *parent,
+            name: name.to_owned(),
+            perm,
+            uid,
+            gid,
+        };
+        self.conn.call(req).await?
+    }
+
+    fn open(&self, fd: &FileData, pid: u32, flags: u32) -> u64 {
+        let req = Request::Open {
+            fd: *fd,
+            pid,
+            flags,
+        };
+        // A handle that bfffsd never heard of will fail check_handle, so the
+        // error will be reported by the first operation that uses it.
+        self.conn.call_blocking(req).unwrap_or(u64::MAX)
+    }
+
+    async fn read(
+        &self,
+        fd: &FileData,
+        offset: u64,
+        size: usize,
+    ) -> Result<SGList, i32> {
+        if size > MAX_IO {
+            return Err(libc::EIO);
+        }
+        let req = Request::Read {
+            fd: *fd,
+            offset,
+            size,
+        };
+        let data: Vec<u8> = self.conn.call(req).await??;
+        Ok(vec![DivBufShared::from(data).try_const().unwrap()])
+    }
+
+    fn readdir(
+        &self,
+        fd: &FileData,
+        soffs: i64,
+    ) -> BoxStream<'static, Result<(libc::dirent, i64), i32>> {
+        let conn = self.conn.clone();
+        let fd = *fd;
+        // Fetch a batch at a time, resuming after the last entry of the
+        // previous batch.  A short batch means the end of the directory.
+        stream::try_unfold(Some(soffs), move |soffs| {
+            let conn = conn.clone();
+            async move {
+                match soffs {
+                    None => Ok(None),
+                    Some(soffs) => {
+                        let req = Request::Readdir { fd, soffs };
+                        let batch: Vec<Dirent> = conn.call(req).await??;
+                        let next = if batch.len() < READDIR_BATCH {
+                            None
+                        } else {
+                            batch.last().map(|d| d.offset)
+                        };
+                        Ok(Some((batch, next)))
+                    }
+                }
+            }
+        })
+        .map_ok(|batch| {
+            stream::iter(batch.into_iter().map(|d| Ok((d.to_libc(), d.offset))))
+        })
+        .try_flatten()
+        .boxed()
+    }
+
+    async fn readlink(&self, fd: &FileData) -> Result<OsString, i32> {
+        self.conn.call(Request::Readlink(*fd)).await?
+    }
+
+    fn release(&self, fh: u64) {
+        // Nothing to report, so don't wait for the reply
+        let conn = self.conn.clone();
+        tokio::spawn(async move {
+            let r: Result<(), i32> = conn.call(Request::Release(fh)).await;
+            if let Err(e) = r {
+                warn!("Cannot release file handle {fh}: {e}");
+            }
+        });
+    }
+
+    async fn rename<'a>(
+        &self,
+        parent: &'a FileData,
+        fd: &'a FileData,
+        name: &'a OsStr,
+        newparent: &'a FileData,
+        newino: Option<u64>,
+        newname: &'a OsStr,
+    ) -> Result<u64, i32> {
+        let req = Request::Rename {
+            parent: *parent,
+            fd: *fd,
+            name: name.to_owned(),
+            newparent: *newparent,
+            newino,
+            newname: newname.to_owned(),
+        };
+        self.conn.call(req).await?
+    }
+
+    async fn rmdir(&self, parent: &FileData, name: &OsStr) -> Result<(), i32> {
+        let req = Request::Rmdir {
+            parent: *parent,
+            name:   name.to_owned(),
+        };
+        self.conn.call(req).await?
+    }
+
+    fn root(&self) -> FileDataMut {
+        self.conn
+            .call_blocking(Request::Root)
+            .expect("Cannot get root directory from bfffsd")
+    }
+
+    async fn setattr(&self, fd: &FileData, attr: SetAttr) -> Result<(), i32> {
+        let req = Request::Setattr { fd: *fd, attr };
+        self.conn.call(req).await?
+    }
+
+    async fn setextattr(
+        &self,
+        fd: &FileData,
+        ns: ExtAttrNamespace,
+        name: &OsStr,
+        data: &[u8],
+    ) -> Result<(), i32> {
+        let req = Request::SetExtAttr {
+            fd: *fd,
+            ns,
+            name: name.to_owned(),
+            data: data.to_vec(),
+        };
+        self.conn.call(req).await?
+    }
+
+    async fn statvfs(&self) -> Result<libc::statvfs, i32> {
+        let s: Statvfs = self.conn.call(Request::Statvfs).await??;
+        Ok(s.to_libc())
+    }
+
+    async fn symlink(
+        &self,
+        parent: &FileData,
+        name: &OsStr,
+        perm: u16,
+        uid: u32,
+        gid: u32,
+        link: &OsStr,
+    ) -> Result<FileDataMut, i32> {
+        let req = Request::Symlink {
+            parent: *parent,
+            name: name.to_owned(),
+            perm,
+            uid,
+            gid,
+            link: link.to_owned(),
+        };
+        self.conn.call(req).await?
+    }
+
+    async fn sync(&self) {
+        let r: Result<(), i32> = self.conn.call(Request::Sync).await;
+        if let Err(e) = r {
+            warn!("Cannot sync file system: {e}");
+        }
+    }
+
+    async fn unlink<'a>(
+        &self,
+        parent: &'a FileData,
+        fd: Option<&'a FileData>,
+        name: &'a OsStr,
+    ) -> Result<(), i32> {
+        let req = Request::Unlink {
+            parent: *parent,
+            fd:     fd.copied(),
+            name:   name.to_owned(),
+        };
+        self.conn.call(req).await?
+    }
+
+    async fn write(
+        &self,
+        fd: &FileData,
+        offset: u64,
+        data: &[u8],
+        flags: u32,
+    ) -> Result<u32, i32> {
+        if data.len() > MAX_IO {
+            return Err(libc::EIO);
+        }
+        let req = Request::Write {
+            fd: *fd,
+            offset,
+            data: data.to_vec(),
+            flags,
+        };
+        self.conn.call(req).await?
+    }
+}
+
+/// bfffsd's side of one worker's connection
+struct Server<V: Vfs> {
+    fs:      Arc<V>,
+    hook:    CloseHook,
+    /// File handles that the worker has open
+    handles: Mutex<HashSet<u64>>,
+    /// Files that the worker has looked up and not yet inactivated, by inode
+    files:   Mutex<HashMap<u64, FileDataMut>>,
+}
+
+impl<V: Vfs> Server<V> {
+    /// Encode a newly looked up file, and remember it in case the worker dies
+    /// before inactivating it.
+    fn lookedup(&self, r: Result<FileDataMut, i32>) -> Vec<u8> {
+        let reply = encode(&r);
+        if let Ok(fd) = r {
+            self.files.lock().unwrap().entry(fd.ino()).or_insert(fd);
+        }
+        reply
+    }
+
+    /// Perform one request, returning the encoded result
+    async fn handle(&self, req: Request) -> Vec<u8> {
+        let fs = &self.fs;
+        match req {
+            Request::CheckHandle(fh) => encode(&fs.check_handle(fh)),
+            Request::Closed { ino, path } => {
+                (self.hook)(ino, path).await;
+                encode(&())
+            }
+            Request::Create {
+                parent,
+                name,
+                perm,
+                uid,
+                gid,
+            } => self.lookedup(fs.create(&parent, &name, perm, uid, gid).await),
+            Request::Deallocate { fd, offset, len } => {
+                encode(&fs.deallocate(&fd, offset, len).await)
+            }
+            Request::DeleteExtAttr { fd, ns, name } => {
+                encode(&fs.deleteextattr(&fd, ns, &name).await)
+            }
+            Request::Fadvise { fd, advice } => {
+                encode(&fs.fadvise(&fd, advice).await)
+            }
+            Request::FadviseStats => encode(&fs.fadvise_stats()),
+            Request::Fdatasync(fd) => encode(&fs.fdatasync(&fd).await),
+            Request::Fsync(fd) => encode(&fs.fsync(&fd).await),
+            Request::Getattr(fd) => encode(&fs.getattr(&fd).await),
+            Request::GetExtAttr { fd, ns, name } => {
+                let r = fs.getextattr(&fd, ns, &name).await;
+                encode(&r.map(|db| db.to_vec()))
+            }
+            Request::GetExtAttrLen { fd, ns, name } => {
+                encode(&fs.getextattrlen(&fd, ns, &name).await)
+            }
+            Request::Ilookup(ino) => self.lookedup(fs.ilookup(ino).await),
+            Request::Inactive(fd) => {
+                self.files.lock().unwrap().remove(&fd.ino());
+                fs.inactive(fd).await;
+                encode(&())
+            }
+            Request::Link { parent, fd, name } => {
+                encode(&fs.link(&parent, &fd, &name).await)
+            }
+            Request::ListExtAttr(fd) => {
+                let f = |buf: &mut Vec<u8>, extattr: &ExtAttr| {
+                    bincode::serialize_into(buf, extattr)
+                        .expect("ExtAttrs are always serializable")
+                };
+                encode(&fs.listextattr(&fd, 0, f).await)
+            }
+            Request::Lookup {
+                grandparent,
+                parent,
+                name,
+            } => {
+                let r = fs.lookup(grandparent.as_ref(), &parent, &name).await;
+                self.lookedup(r)
+            }
+            Request::Lseek { fd, offset, whence } => {
+                encode(&fs.lseek(&fd, offset, whence).await)
+            }
+            Request::Mkblock {
+                parent,
+                name,
+                perm,
+                uid,
+                gid,
+                rdev,
+            } => {
+                let r = fs.mkblock(&parent, &name, perm, uid, gid, rdev).await;
+                self.lookedup(r)
+            }
+            Request::Mkchar {
+                parent,
+                name,
+                perm,
+                uid,
+                gid,
+                rdev,
+            } => {
+                let r = fs.mkchar(&parent, &name, perm, uid, gid, rdev).await;
+                self.lookedup(r)
+            }
+            Request::Mkdir {
+                parent,
+                name,
+                perm,
+                uid,
+                gid,
+            } => self.lookedup(fs.mkdir(&parent, &name, perm, uid, gid).await),
+            Request::Mkfifo {
+                parent,
+                name,
+                perm,
+                uid,
+                gid,
+            } => self.lookedup(fs.mkfifo(&parent, &name, perm, uid, gid).await),
+            Request::Mksock {
+                parent,
+                name,
+                perm,
+                uid,
+                gid,
+            } => self.lookedup(fs.mksock(&parent, &name, perm, uid, gid).await),
+            Request::Open { fd, pid, flags } => {
+                let fh = fs.open(&fd, pid, flags);
+                self.handles.lock().unwrap().insert(fh);
+                encode(&fh)
+            }
+            Request::Read { fd, offset, size } => {
+                let r = fs.read(&fd, offset, size).await.map(|sglist| {
+                    let mut data = Vec::with_capacity(size);
+                    for iovec in sglist.iter() {
+                        data.extend_from_slice(&iovec[..]);
+                    }
+                    data
+                });
+                encode(&r)
+            }
+            Request::Readdir { fd, soffs } => {
+                let r = fs
+                    .readdir(&fd, soffs)
+                    .take(READDIR_BATCH)
+                    .map_ok(|(dirent, offset)| Dirent::new(&dirent, offset))
+                    .try_collect::<Vec<_>>()
+                    .await;
+                encode(&r)
+            }
+            Request::Readlink(fd) => encode(&fs.readlink(&fd).await),
+            Request::Release(fh) => {
+                self.handles.lock().unwrap().remove(&fh);
+                fs.release(fh);
+                encode(&())
+            }
+            Request::Rename {
+                parent,
+                fd,
+                name,
+                newparent,
+                newino,
+                newname,
+            } => {
+                let r = fs
+                    .rename(&parent, &fd, &name, &newparent, newino, &newname)
+                    .await;
+                encode(&r)
+            }
+            Request::Rmdir { parent, name } => {
+                encode(&fs.rmdir(&parent, &name).await)
+            }
+            // The root directory is never inactivated, so needn't be tracked
+            Request::Root => encode(&fs.root()),
+            Request::Setattr { fd, attr } => {
+                encode(&fs.setattr(&fd, attr).await)
+            }
+            Request::SetExtAttr { fd, ns, name, data } => {
+                encode(&fs.setextattr(&fd, ns, &name, &data).await)
+            }
+            Request::Statvfs => {
+                encode(&fs.statvfs().await.map(Statvfs::from))
+            }
+            Request::Symlink {
+                parent,
+                name,
+                perm,
+                uid,
+                gid,
+                link,
+            } => {
+                let r = fs.symlink(&parent, &name, perm, uid, gid, &link).await;
+                self.lookedup(r)
+            }
+            Request::Sync => {
+                fs.sync().await;
+                encode(&())
+            }
+            Request::Unlink { parent, fd, name } => {
+                encode(&fs.unlink(&parent, fd.as_ref(), &name).await)
+            }
+            Request::Write {
+                fd,
+                offset,
+                data,
+                flags,
+            } => encode(&fs.write(&fd, offset, &data, flags).await),
+        }
+    }
+
+    /// Clean up after a worker that has gone away, whether or not it exited
+    /// cleanly.
+    async fn cleanup(&self) {
+        let handles = mem::take(&mut *self.handles.lock().unwrap());
+        for fh in handles.into_iter() {
+            self.fs.release(fh);
+        }
+        let files = mem::take(&mut *self.files.lock().unwrap());
+        for (_, fd) in files.into_iter() {
+            self.fs.inactive(fd).await;
+        }
+        self.fs.sync().await;
+    }
+}
+
+/// Serve a worker's requests on `fs` until it hangs up.
+///
+/// `mounted` gets the worker's report of whether it mounted the file system.
+/// If the worker goes away first, it gets dropped.
+async fn serve<V: Vfs>(
+    fs: Arc<V>,
+    mut rd: OwnedReadHalf,
+    wr: OwnedWriteHalf,
+    hook: CloseHook,
+    mounted: oneshot::Sender<Result<(), i32>>,
+) {
+    let server = Arc::new(Server {
+        fs,
+        hook,
+        handles: Mutex::default(),
+        files: Mutex::default(),
+    });
+    let wr = Arc::new(tokio::sync::Mutex::new(wr));
+    let mut mounted = Some(mounted);
+    // Every request task holds a clone of `alive`, so `done` will tell us when
+    // they've all finished.
+    let (alive, mut done) = mpsc::channel::<()>(1);
+    loop {
+        match read_frame(&mut rd).await {
+            Ok(Some(Up::Mounted(r))) => {
+                if let Some(tx) = mounted.take() {
+                    let _ = tx.send(r);
+                }
+            }
+            Ok(Some(Up::Request(id, req))) => {
+                let server = server.clone();
+                let wr = wr.clone();
+                let alive = alive.clone();
+                tokio::spawn(async move {
+                    let mut reply = server.handle(req).await;
+                    // Leave room for the id and the reply's length
+                    if reply.len() > MAX_FRAME as usize - 16 {
+                        // Too big to send, like an enormous extended
+                        // attribute.  Every oversized reply is a Result, and
+                        // bincode encodes the error the same way regardless of
+                        // the Ok type.
+                        reply = encode(&Result::<(), i32>::Err(libc::EIO));
+                    }
+                    // If the worker has died, there's nobody left to tell.
+                    let mut wr = wr.lock().await;
+                    let _ = write_frame(&mut *wr, &(id, reply)).await;
+                    drop(alive);
+                });
+            }
+            Ok(None) => break,
+            Err(e) => {
+                error!("Cannot read request from FUSE worker: {e}");
+                break;
+            }
+        }
+    }
+    drop(alive);
+    let _ = done.recv().await;
+    server.cleanup().await;
+}
+
+/// A running worker process
+struct Worker {
+    child:   Child,
+    server:  JoinHandle<()>,
+    started: Instant,
+}
+
+/// Decides whether a file system's crashed worker should be restarted
+#[derive(Debug, Default)]
+struct Restarts {
+    /// Consecutive restarts, not counting any before the last healthy worker
+    count: u32,
+}
+
+impl Restarts {
+    /// Record the crash of a worker that ran for `uptime`.  Returns whether to
+    /// restart it.
+    fn crashed(&mut self, uptime: Duration) -> bool {
+        if uptime >= RESTART_WINDOW {
+            self.count = 0;
+        }
+        if self.count >= MAX_RESTARTS {
+            false
+        } else {
+            self.count += 1;
+            true
+        }
+    }
+}
+
+/// Everything needed to mount one file system in a worker, again and again
+struct Job<V: Vfs> {
+    open:  Arc<OpenFn<V>>,
+    name:  String,
+    setup: Setup,
+    hook:  CloseHook,
+}
+
+impl<V: Vfs> Job<V> {
+    /// Start a worker, and wait for it to mount the file system.
+    async fn start(&self) -> Result<Worker, Error> {
+        let errno = |e: i32| Error::from(Errno::from_i32(e));
+        let fs = (self.open)(self.name.clone()).await.map_err(errno)?;
+        let (ours, theirs) = std::os::unix::net::UnixStream::pair()?;
+        let mut child = Command::new(std::env::current_exe()?)
+            .arg(ARG)
+            .stdin(Stdio::from(OwnedFd::from(theirs)))
+            .spawn()?;
+        ours.set_nonblocking(true)?;
+        let (rd, mut wr) = UnixStream::from_std(ours)?.into_split();
+        write_frame(&mut wr, &self.setup).await?;
+        let (tx, rx) = oneshot::channel();
+        let server = tokio::spawn(serve(fs, rd, wr, self.hook.clone(), tx));
+        match rx.await {
+            Ok(Ok(())) => Ok(Worker {
+                child,
+                server,
+                started: Instant::now(),
+            }),
+            r => {
+                let _ = child.wait().await;
+                let _ = server.await;
+                let e = r.ok().and_then(Result::err).unwrap_or(libc::EIO);
+                Err(errno(e))
+            }
+        }
+    }
+
+    /// Wait for the file system to be unmounted, restarting its worker and
+    /// remounting it whenever the worker dies.
+    async fn supervise(self, mut worker: Worker) {
+        let mut restarts = Restarts::default();
+        loop {
+            let status = worker.child.wait().await;
+            // Let the server finish cleaning up before remounting
+            let _ = (&mut worker.server).await;
+            match status {
+                Ok(status) if status.success() => break,
+                Ok(status) => {
+                    error!("FUSE worker for {} died: {status}", self.name)
+                }
+                Err(e) => {
+                    error!("Cannot wait for FUSE worker of {}: {e}", self.name)
+                }
+            }
+            // The dead session is still mounted, but every access fails.
+            let mp = self.setup.mountpoint.clone();
+            // Like unmount(2), nmount(2) may block waiting for the daemon.
+            let r = tokio::task::spawn_blocking(move || {
+                unmount(&mp, MntFlags::MNT_FORCE)
+            })
+            .await
+            .unwrap();
+            if let Err(e) = r {
+                // Perhaps the kernel already unmounted it
+                let mp = self.setup.mountpoint.display();
+                warn!("Cannot unmount {mp}: {e}");
+            }
+            if !restarts.crashed(worker.started.elapsed()) {
+                let n = restarts.count;
+                error!("Giving up on {} after {n} restarts", self.name);
+                break;
+            }
+            match self.start().await {
+                Ok(w) => {
+                    info!("Remounted {}", self.name);
+                    worker = w;
+                }
+                Err(e) => {
+                    error!("Cannot remount {}: {e:?}", self.name);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Mounts file systems in supervised worker processes
+pub struct Workers<V: Vfs> {
+    open:               Arc<OpenFn<V>>,
+    /// Limits the read data buffered by each worker
+    session_read_limit: Option<usize>,
+}
+
+impl<V: Vfs> Workers<V> {
+    /// Mount `name` at `mountpoint` in a new worker process.
+    ///
+    /// Returns once the file system is mounted.  The returned future completes
+    /// once it's unmounted for good.  Until then, whenever the worker dies it
+    /// will be replaced and the file system remounted.
+    pub async fn mount(
+        &self,
+        name: &str,
+        opts: MountOpts,
+        mountpoint: PathBuf,
+        hook: CloseHook,
+    ) -> Result<BoxFuture<'static, ()>, Error> {
+        let job = Job {
+            open: self.open.clone(),
+            name: name.to_owned(),
+            setup: Setup {
+                mountpoint,
+                opts,
+                session_read_limit: self.session_read_limit,
+            },
+            hook,
+        };
+        let worker = job.start().await?;
+        Ok(job.supervise(worker).boxed())
+    }
+
+    pub fn new(open: OpenFn<V>, session_read_limit: Option<usize>) -> Self {
+        Workers {
+            open: Arc::new(open),
+            session_read_limit,
+        }
+    }
+}
+
+/// Entry point for a worker process.  Never returns.
+#[tokio::main]
+pub async fn main() {
+    // bfffsd passes our end of the socket as stdin
+    let sock = io::stdin()
+        .as_fd()
+        .try_clone_to_owned()
+        .map(std::os::unix::net::UnixStream::from)
+        .and_then(|sock| {
+            sock.set_nonblocking(true)?;
+            UnixStream::from_std(sock)
+        })
+        .unwrap_or_else(|e| {
+            eprintln!("Cannot open bfffsd's socket: {e}");
+            exit(1);
+        });
+    let (mut rd, wr) = sock.into_split();
+    let setup: Setup = match read_frame(&mut rd).await {
+        Ok(Some(setup)) => setup,
+        _ => {
+            eprintln!("FUSE workers may only be started by bfffsd");
+            exit(1);
+        }
+    };
+    let (fs, dispatcher) = RemoteFs::connect(rd, wr);
+    let conn = fs.conn.clone();
+    let mut fusefs = FuseFs::new(Arc::new(fs));
+    if let Some(limit) = setup.session_read_limit {
+        fusefs.session_read_limit(limit);
+    }
+    let conn2 = conn.clone();
+    let hook = move |ino: u64, path: Option<PathBuf>| {
+        let conn = conn2.clone();
+        async move {
+            let r: Result<(), i32> =
+                conn.call(Request::Closed { ino, path }).await;
+            if let Err(e) = r {
+                warn!("Cannot report close of inode {ino}: {e}");
+            }
+        }
+        .boxed()
+    };
+    fusefs.close_hook(Arc::new(hook));
+    let r = Session::new(setup.opts.to_fuse())
+        .mount(fusefs, &setup.mountpoint)
+        .await;
+    let status = r
+        .as_ref()
+        .map(drop)
+        .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO));
+    if conn.send(&Up::Mounted(status)).await.is_err() {
+        exit(1);
+    }
+    let handle = match r {
+        Ok(handle) => handle,
+        Err(e) => {
+            error!("Cannot mount {}: {e}", setup.mountpoint.display());
+            exit(1);
+        }
+    };
+    tokio::select! {
+        r = handle => {
+            // Unmounted
+            exit(if r.is_ok() { 0 } else { 1 });
+        }
+        _ = dispatcher => {
+            error!("Lost connection to bfffsd");
+            let mp = setup.mountpoint;
+            let _ = tokio::task::spawn_blocking(move || {
+                unmount(&mp, MntFlags::MNT_FORCE)
+            })
+            .await;
+            exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use bfffs_fuse::mock::MockFs;
+    use futures::future;
+
+    use super::*;
+
+    /// A RemoteFs connected to a server
+    struct Harness {
+        fs:         RemoteFs,
+        dispatcher: JoinHandle<()>,
+        server:     JoinHandle<()>,
+    }
+
+    impl Harness {
+        /// Simulate the worker's death, and wait for the server to clean up
+        async fn hangup(self) {
+            self.dispatcher.abort();
+            let _ = self.dispatcher.await;
+            drop(self.fs);
+            self.server.await.unwrap();
+        }
+    }
+
+    /// Connect a RemoteFs to a server for `mock_fs`
+    fn connect(mock_fs: MockFs) -> Harness {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let (rd, wr) = theirs.into_split();
+        let hook: CloseHook = Arc::new(|_, _| future::ready(()).boxed());
+        let (tx, _rx) = oneshot::channel();
+        let server = tokio::spawn(serve(Arc::new(mock_fs), rd, wr, hook, tx));
+        let (rd, wr) = ours.into_split();
+        let (fs, dispatcher) = RemoteFs::connect(rd, wr);
+        Harness {
+            fs,
+            dispatcher,
+            server,
+        }
+    }
+
+    fn dirent(ino: u64, name: &str) -> libc::dirent {
+        Dirent {
+            ino,
+            dtype: libc::DT_REG,
+            name: OsString::from(name),
+            offset: 0,
+        }
+        .to_libc()
+    }
+
+    fn mock_fs() -> MockFs {
+        let mut mock_fs = MockFs::default();
+        mock_fs.expect_sync().return_const(());
+        mock_fs
+    }
+
+    /// Once the worker goes away, its open handles and files should be released
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cleanup() {
+        let mut mock_fs = MockFs::default();
+        mock_fs
+            .expect_lookup()
+            .returning(|_, _, _| Ok(FileDataMut::new_for_tests(None, 2)));
+        mock_fs.expect_open().return_const(42u64);
+        mock_fs
+            .expect_release()
+            .withf(|fh| *fh == 42)
+            .times(1)
+            .return_const(());
+        mock_fs
+            .expect_inactive()
+            .withf(|fd| fd.ino() == 2)
+            .times(1)
+            .return_const(());
+        mock_fs.expect_sync().times(1).return_const(());
+        let harness = connect(mock_fs);
+        let root = FileDataMut::new_for_tests(None, 1);
+        let fd = harness
+            .fs
+            .lookup(None, &root.handle(), OsStr::new("foo"))
+            .await
+            .unwrap();
+        assert_eq!(harness.fs.open(&fd.handle(), 0, 0), 42);
+        harness.hangup().await;
+    }
+
+    /// Even if the worker crashes mid-request, its open handles and files
+    /// should be released
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cleanup_after_crash() {
+        let mut mock_fs = MockFs::default();
+        mock_fs
+            .expect_lookup()
+            .returning(|_, _, _| Ok(FileDataMut::new_for_tests(None, 2)));
+        mock_fs.expect_open().return_const(42u64);
+        mock_fs
+            .expect_release()
+            .withf(|fh| *fh == 42)
+            .times(1)
+            .return_const(());
+        mock_fs
+            .expect_inactive()
+            .withf(|fd| fd.ino() == 2)
+            .times(1)
+            .return_const(());
+        mock_fs.expect_sync().times(1).return_const(());
+        let harness = connect(mock_fs);
+        let root = FileDataMut::new_for_tests(None, 1);
+        let fd = harness
+            .fs
+            .lookup(None, &root.handle(), OsStr::new("foo"))
+            .await
+            .unwrap();
+        assert_eq!(harness.fs.open(&fd.handle(), 0, 0), 42);
+        // Die halfway through sending a request
+        let mut wr = harness.fs.conn.wr.lock().await;
+        wr.write_all(&[100, 0, 0, 0, 1, 2, 3]).await.unwrap();
+        drop(wr);
+        harness.hangup().await;
+    }
+
+    /// Synchronous methods can't block on a current-thread runtime, so they
+    /// should fail rather than panic.
+    #[tokio::test]
+    async fn call_blocking_current_thread() {
+        let mut mock_fs = mock_fs();
+        mock_fs.expect_open().never();
+        let fs = connect(mock_fs).fs;
+        let root = FileDataMut::new_for_tests(None, 1);
+        assert_eq!(fs.open(&root.handle(), 0, 0), u64::MAX);
+    }
+
+    /// Errors should be passed through unchanged
+    #[tokio::test]
+    async fn error() {
+        let mut mock_fs = mock_fs();
+        mock_fs
+            .expect_rmdir()
+            .withf(|parent, name| parent.ino() == 1 && name == "foo")
+            .times(1)
+            .return_const(Err(libc::ENOTEMPTY));
+        let fs = connect(mock_fs).fs;
+        let root = FileDataMut::new_for_tests(None, 1);
+        let r = fs.rmdir(&root.handle(), OsStr::new("foo")).await;
+        assert_eq!(r, Err(libc::ENOTEMPTY));
+    }
+
+    /// Files inactivated by the worker should not be inactivated again
+    #[tokio::test]
+    async fn inactive() {
+        let mut mock_fs = mock_fs();
+        mock_fs
+            .expect_ilookup()
+            .returning(|ino| Ok(FileDataMut::new_for_tests(None, ino)));
+        mock_fs
+            .expect_inactive()
+            .withf(|fd| fd.ino() == 2)
+            .times(1)
+            .return_const(());
+        let harness = connect(mock_fs);
+        let fd = harness.fs.ilookup(2).await.unwrap();
+        harness.fs.inactive(fd).await;
+        harness.hangup().await;
+    }
+
+    #[tokio::test]
+    async fn listextattr() {
+        use bfffs_core::fs_tree::{InlineExtAttr, InlineExtent};
+
+        let mut mock_fs = mock_fs();
+        mock_fs.expect_listextattr().returning(|_, _, f| {
+            let mut buf = Vec::new();
+            for name in ["md5", "sha1"] {
+                let extattr = ExtAttr::Inline(InlineExtAttr {
+                    namespace: ExtAttrNamespace::User,
+                    name:      OsString::from(name),
+                    extent:    InlineExtent::default(),
+                });
+                f(&mut buf, &extattr);
+            }
+            Ok(buf)
+        });
+        let fs = connect(mock_fs).fs;
+        let root = FileDataMut::new_for_tests(None, 1);
+        let f = |buf: &mut Vec<u8>, extattr: &ExtAttr| {
+            buf.extend_from_slice(extattr.name().as_bytes());
+            buf.push(0);
+        };
+        let r = fs.listextattr(&root.handle(), 64, f).await;
+        assert_eq!(r.unwrap(), b"md5\0sha1\0");
+    }
+
+    /// Big directories should be read a batch at a time
+    #[tokio::test]
+    async fn readdir() {
+        let nentries = READDIR_BATCH as i64 + 3;
+        let mut mock_fs = mock_fs();
+        mock_fs
+            .expect_readdir()
+            .withf(|_, soffs| *soffs == 0 || *soffs == READDIR_BATCH as i64)
+            .times(2)
+            .returning(move |_, soffs| {
+                stream::iter((soffs..nentries).map(|i| {
+                    Ok((dirent(i as u64 + 2, &format!("f{i}")), i + 1))
+                }))
+                .boxed()
+            });
+        let fs = connect(mock_fs).fs;
+        let root = FileDataMut::new_for_tests(None, 1);
+        let entries = fs
+            .readdir(&root.handle(), 0)
+            .try_collect::<Vec<_>>()
+            .await
+            .unwrap();
+        assert_eq!(entries.len(), nentries as usize);
+        for (i, (dirent, offset)) in entries.iter().enumerate() {
+            assert_eq!(dirent.d_fileno as u64, i as u64 + 2);
+            assert_eq!(Dirent::new(dirent, 0).name, format!("f{i}").as_str());
+            assert_eq!(*offset, i as i64 + 1);
+        }
+    }
+
+    /// The server should run the close hook on the worker's behalf
+    #[tokio::test]
+    async fn close_hook() {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let (rd, wr) = theirs.into_split();
+        let (hook_tx, mut hook_rx) = mpsc::unbounded_channel();
+        let hook: CloseHook = Arc::new(move |ino, path| {
+            hook_tx.send((ino, path)).unwrap();
+            future::ready(()).boxed()
+        });
+        let (tx, _rx) = oneshot::channel();
+        tokio::spawn(serve(Arc::new(mock_fs()), rd, wr, hook, tx));
+        let (rd, wr) = ours.into_split();
+        let (fs, _dispatcher) = RemoteFs::connect(rd, wr);
+        let req = Request::Closed {
+            ino:  5,
+            path: Some(PathBuf::from("a/b")),
+        };
+        fs.conn.call::<()>(req).await.unwrap();
+        let (ino, path) = hook_rx.recv().await.unwrap();
+        assert_eq!(ino, 5);
+        assert_eq!(path.as_deref(), Some(std::path::Path::new("a/b")));
+    }
+
+    /// The server should report the worker's mount status
+    #[tokio::test]
+    async fn mounted() {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let (rd, wr) = theirs.into_split();
+        let hook: CloseHook = Arc::new(|_, _| future::ready(()).boxed());
+        let (tx, rx) = oneshot::channel();
+        tokio::spawn(serve(Arc::new(mock_fs()), rd, wr, hook, tx));
+        let (rd, wr) = ours.into_split();
+        let (fs, _dispatcher) = RemoteFs::connect(rd, wr);
+        fs.conn.send(&Up::Mounted(Err(libc::EPERM))).await.unwrap();
+        assert_eq!(rx.await.unwrap(), Err(libc::EPERM));
+    }
+
+    /// A reply too big to send should fail with EIO, and leave the connection
+    /// usable
+    #[tokio::test]
+    async fn reply_too_big() {
+        let mut mock_fs = mock_fs();
+        mock_fs.expect_getextattr().times(1).returning(|_, _, _| {
+            let data = vec![0u8; MAX_FRAME as usize];
+            Ok(DivBufShared::from(data).try_const().unwrap())
+        });
+        mock_fs.expect_rmdir().times(1).return_const(Ok(()));
+        let fs = connect(mock_fs).fs;
+        let root = FileDataMut::new_for_tests(None, 1);
+        let ns = ExtAttrNamespace::User;
+        let r = fs.getextattr(&root.handle(), ns, OsStr::new("big")).await;
+        assert_eq!(r.unwrap_err(), libc::EIO);
+        let r = fs.rmdir(&root.handle(), OsStr::new("foo")).await;
+        assert_eq!(r, Ok(()));
+    }
+
+    /// A worker that keeps crashing should only be restarted `MAX_RESTARTS`
+    /// times
+    #[test]
+    fn restarts_limit() {
+        let mut restarts = Restarts::default();
+        for _ in 0..MAX_RESTARTS {
+            assert!(restarts.crashed(Duration::from_secs(1)));
+        }
+        assert!(!restarts.crashed(Duration::from_secs(1)));
+        assert_eq!(restarts.count, MAX_RESTARTS);
+    }
+
+    /// A worker that stays up for `RESTART_WINDOW` has earned a fresh set of
+    /// restarts
+    #[test]
+    fn restarts_window() {
+        let mut restarts = Restarts::default();
+        for _ in 0..MAX_RESTARTS {
+            assert!(restarts.crashed(Duration::from_secs(1)));
+        }
+        assert!(restarts.crashed(RESTART_WINDOW));
+        assert_eq!(restarts.count, 1);
+    }
+
+    /// The largest FUSE write should fit in a single frame
+    #[tokio::test]
+    async fn write_max() {
+        let mut mock_fs = mock_fs();
+        mock_fs
+            .expect_write()
+            .withf(|_, _, data, _| data.len() == MAX_IO)
+            .times(1)
+            .return_const(Ok(MAX_IO as u32));
+        let fs = connect(mock_fs).fs;
+        let root = FileDataMut::new_for_tests(None, 1);
+        let data = vec![0u8; MAX_IO];
+        let r = fs.write(&root.handle(), 0, &data, 0).await;
+        assert_eq!(r, Ok(MAX_IO as u32));
+    }
+
+    /// Writes bigger than FUSE allows should fail with EIO, and leave the
+    /// connection usable
+    #[tokio::test]
+    async fn write_too_big() {
+        let mut mock_fs = mock_fs();
+        mock_fs.expect_write().never();
+        mock_fs.expect_rmdir().times(1).return_const(Ok(()));
+        let fs = connect(mock_fs).fs;
+        let root = FileDataMut::new_for_tests(None, 1);
+        let data = vec![0u8; MAX_IO + 1];
+        let r = fs.write(&root.handle(), 0, &data, 0).await;
+        assert_eq!(r, Err(libc::EIO));
+        let r = fs.rmdir(&root.handle(), OsStr::new("foo")).await;
+        assert_eq!(r, Ok(()));
+    }
+
+    /// Requests should fail, not hang, once bfffsd goes away
+    #[tokio::test]
+    async fn disconnected() {
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        drop(theirs);
+        let (rd, wr) = ours.into_split();
+        let (fs, dispatcher) = RemoteFs::connect(rd, wr);
+        dispatcher.await.unwrap();
+        assert_eq!(fs.ilookup(2).await.unwrap_err(), libc::EIO);
+    }
+}
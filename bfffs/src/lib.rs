@@ -4,25 +4,131 @@
 //! This library is for programmatic access to BFFFS.  It is intended to be A
 //! stable API.
 
-use std::{collections::VecDeque, path::Path};
+use std::{
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+        Mutex,
+    },
+};
 
 use bfffs_core::rpc;
 pub use bfffs_core::{
-    controller::TreeID,
-    property::{Property, PropertyName},
+    capacity::{Change, Plan},
+    cleaner::{CleanPolicy, CleanStats},
+    controller::{DataError, PoolStatus, TreeID},
+    database::TxgStatus,
+    defrag::DefragStats,
+    feature::Feature,
+    fs::{IoStats, OpenFile},
+    job::{JobID, JobKind, JobState, JobStatus},
+    pool::ZoneStats,
+    pool_property::{FailMode, PoolProperty},
+    property::{Property, PropertyName, UserProperty},
+    vdev::LeafStatus,
     Error,
     Result,
+    Uuid,
 };
 use futures::{stream, Stream, StreamExt, TryFutureExt};
+use tokio::{sync::oneshot, task::JoinHandle};
 use tokio_seqpacket::UnixSeqpacket;
 
+pub mod transport;
+
+use transport::Peer;
+
+/// Callers still waiting for their responses, by request ID
+type Pending =
+    Arc<Mutex<HashMap<rpc::RequestId, oneshot::Sender<rpc::Response>>>>;
+
+/// Asks the server to cancel a request if its caller loses interest
+struct CancelOnDrop<'a> {
+    bfffs: &'a Bfffs,
+    id:    rpc::RequestId,
+    armed: bool,
+}
+
+impl Drop for CancelOnDrop<'_> {
+    fn drop(&mut self) {
+        self.bfffs.pending.lock().unwrap().remove(&self.id);
+        if self.armed {
+            let cancel_id = self.bfffs.next_id.fetch_add(1, Ordering::Relaxed);
+            let req = rpc::Request::Cancel(self.id);
+            let auth = self.bfffs.auth;
+            let encoded = rpc::encode(&(cancel_id, auth, req)).unwrap();
+            let peer = self.bfffs.peer.clone();
+            // Nobody waits for the Cancel response; read_responses will
+            // discard it.
+            tokio::spawn(async move {
+                let _ = peer.send(&encoded).await;
+            });
+        }
+    }
+}
+
 /// A connection to the bfffsd server
+///
+/// Requests may be issued concurrently.  Dropping a request's future before
+/// it completes will cancel that request on the server.
 #[derive(Debug)]
 pub struct Bfffs {
-    peer: UnixSeqpacket,
+    /// Hash of the authentication token, if any, sent with every request
+    auth:    Option<rpc::AuthHash>,
+    next_id: AtomicU64,
+    peer:    Arc<dyn Peer>,
+    pending: Pending,
+    reader:  JoinHandle<()>,
+}
+
+impl Drop for Bfffs {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
 }
 
 impl Bfffs {
+    /// Authenticate every future request with this token.
+    ///
+    /// If bfffsd was started with an authentication token file, then
+    /// supplying that file's contents authorizes privileged requests even if
+    /// the caller isn't running as the same user as bfffsd.
+    pub fn auth_token(&mut self, token: &[u8]) {
+        self.auth = Some(rpc::auth_hash(token));
+    }
+
+    /// Forcibly close a client's file handle.
+    ///
+    /// Further operations on the handle will fail with `EBADF`.
+    ///
+    /// # Arguments
+    ///
+    /// `fsname`    -   Name of the file system, including the pool
+    /// `fh`        -   Handle number, as reported by [`Bfffs::open_files`]
+    pub async fn close_file(&self, fsname: &str, fh: u64) -> Result<()> {
+        let req = rpc::debug::close_file(fsname.to_owned(), fh);
+        self.call(req).await.unwrap().into_debug_close_file()
+    }
+
+    /// Merge under-filled nodes in a file system's metadata tree.
+    ///
+    /// Returns the number of nodes that were coalesced.
+    pub async fn compact(&self, fsname: &str) -> Result<usize> {
+        let req = rpc::debug::compact(fsname.to_owned());
+        self.call(req).await.unwrap().into_debug_compact()
+    }
+
+    /// Check that bfffsd is alive and processing requests.
+    ///
+    /// Unlike merely connecting to the socket, this requires a response from
+    /// the daemon.
+    pub async fn daemon_ping(&self) -> Result<()> {
+        let req = rpc::daemon::ping();
+        self.call(req).await.unwrap().into_daemon_ping()
+    }
+
     /// Connect to the server at the default address
     pub async fn default() -> Self {
         Self::new(Path::new("/var/run/bfffsd.sock")).await.unwrap()
@@ -54,17 +160,81 @@ impl Bfffs {
     /// # Arguments
     ///
     /// `fsname`    -   Name of the file system, including the pool
-    pub async fn fs_destroy(&self, fsname: String) -> Result<()> {
-        let req = rpc::fs::destroy(fsname);
+    /// `dry_run`   -   Only report what would be destroyed
+    ///
+    /// # Returns
+    ///
+    /// The names of every file system that was, or would be, destroyed.
+    pub async fn fs_destroy(
+        &self,
+        fsname: String,
+        dry_run: bool,
+    ) -> Result<Vec<String>> {
+        let req = rpc::fs::destroy(fsname, dry_run);
         self.call(req).await.unwrap().into_fs_destroy()
     }
 
+    /// Wait for the next batch of close events from a watch
+    ///
+    /// Calling this acknowledges every event returned by the previous call,
+    /// releasing any closes that were waiting on a synchronous watch.
+    ///
+    /// # Arguments
+    ///
+    /// `id`        -   As returned by [`Bfffs::fs_watch`]
+    pub async fn fs_events(
+        &self,
+        id: rpc::fs::WatchId,
+    ) -> Result<Vec<rpc::fs::CloseEvent>> {
+        let req = rpc::fs::events(id);
+        self.call(req).await.unwrap().into_fs_events()
+    }
+
+    /// Freeze a file system, blocking modifications until it's thawed
+    ///
+    /// Returns once the file system's data is on disk.
+    ///
+    /// # Arguments
+    ///
+    /// `fsname`    -   Name of the file system, including the pool
+    pub async fn fs_freeze(&self, fsname: String) -> Result<()> {
+        let req = rpc::fs::freeze(fsname);
+        self.call(req).await.unwrap().into_fs_freeze()
+    }
+
+    /// Report a file system's read and write counters
+    ///
+    /// # Arguments
+    ///
+    /// `fsname`    -   Name of the file system, including the pool
+    /// `reset`     -   Zero the counters after reading them
+    pub async fn fs_iostat(
+        &self,
+        fsname: String,
+        reset: bool,
+    ) -> Result<IoStats> {
+        let req = rpc::fs::iostat(fsname, reset);
+        self.call(req).await.unwrap().into_fs_iostat()
+    }
+
+    /// Delegate management of a file system's descendants to a jail
+    ///
+    /// # Arguments
+    ///
+    /// `fsname`    -   Name of the file system, including the pool
+    /// `jid`       -   Numeric ID of the jail
+    pub async fn fs_jail(&self, fsname: String, jid: i32) -> Result<()> {
+        let req = rpc::fs::jail(fsname, jid);
+        self.call(req).await.unwrap().into_fs_jail()
+    }
+
     /// List the given dataset and all of its children
     ///
     /// # Arguments
     ///
     /// `fsname`    -   The dataset to list, including pool name
     /// `props`     -   Properties to look up
+    /// `user_props`-   Also look up every user property
     /// `offs`      -   A stream resume token.  It must be either `None` or the
     ///                 value returned from a previous call to this function.
     ///                 Children will be returned beginning after the entry
@@ -78,6 +248,7 @@ impl Bfffs {
         &self,
         name: String,
         props: Vec<PropertyName>,
+        user_props: bool,
         offs: Option<u64>,
         depth: usize,
     ) -> impl Stream<Item = Result<rpc::fs::DsInfo>> + '_ {
@@ -90,7 +261,7 @@ impl Bfffs {
             depth:    usize,
         }
 
-        let req = rpc::fs::stat(name.clone(), props.clone());
+        let req = rpc::fs::stat(name.clone(), props.clone(), user_props);
         let parent_fut = self
             .call(req)
             .map_ok(rpc::Response::into_fs_stat)
@@ -121,7 +292,12 @@ impl Bfffs {
                                 return Ok(None);
                             };
                         let props3 = props2.clone();
-                        let req = rpc::fs::list(dsname, props3, state.offs);
+                        let req = rpc::fs::list(
+                            dsname,
+                            props3,
+                            user_props,
+                            state.offs,
+                        );
                         state.results =
                             self.call(req).await?.into_fs_list()?.into();
                         if state.results.is_empty() {
@@ -152,26 +328,96 @@ impl Bfffs {
     /// # Arguments
     ///
     /// `fsname`    -   Name of the file system to mount, including the pool
-    pub async fn fs_mount(&self, fsname: String) -> Result<()> {
-        let req = rpc::fs::mount(fsname);
+    /// `mountpoint`-   Mount here instead of at the `mountpoint` property
+    /// `opts`      -   Mount options.  Unless they include "remount", the file
+    ///                 system must not already be mounted.
+    pub async fn fs_mount(
+        &self,
+        fsname: String,
+        mountpoint: Option<String>,
+        opts: Vec<String>,
+    ) -> Result<()> {
+        let req = rpc::fs::mount(fsname, mountpoint, opts);
         self.call(req).await.unwrap().into_fs_mount()
     }
 
+    /// Mount every file system in the pool, parents before children.
+    ///
+    /// File systems with `canmount=off` and those already mounted are skipped.
+    /// Returns the result of each mount that was attempted.
+    pub async fn fs_mount_all(
+        &self,
+        pool: String,
+        opts: Vec<String>,
+    ) -> Result<rpc::fs::BulkResult> {
+        let req = rpc::fs::mount_all(pool, opts);
+        self.call(req).await.unwrap().into_fs_mount_all()
+    }
+
+    /// List every file system that bfffsd currently has mounted
+    pub async fn fs_mounts(&self) -> Result<Vec<rpc::fs::MountInfo>> {
+        let req = rpc::fs::mounts();
+        self.call(req).await.unwrap().into_fs_mounts()
+    }
+
+    /// Move a file from one file system to another in the same pool
+    ///
+    /// # Arguments
+    ///
+    /// `src_ds`    -   Source file system, including the pool
+    /// `src_path`  -   Path of the file, relative to `src_ds`'s root
+    /// `dst_ds`    -   Destination file system, including the pool
+    /// `dst_path`  -   New path of the file, relative to `dst_ds`'s root
+    pub async fn fs_move(
+        &self,
+        src_ds: String,
+        src_path: String,
+        dst_ds: String,
+        dst_path: String,
+    ) -> Result<()> {
+        let req = rpc::fs::mv(src_ds, src_path, dst_ds, dst_path);
+        self.call(req).await.unwrap().into_fs_move()
+    }
+
     /// Set properties on a file system
     ///
     /// # Arguments
     ///
     /// `fsname`    -   Name of the file system to mount, including the pool
     /// `props`     -   Properties to set
+    /// `user_props`-   User properties to set.  An empty value clears the
+    ///                 property.
     pub async fn fs_set(
         &self,
         fsname: String,
         props: Vec<Property>,
+        user_props: Vec<UserProperty>,
     ) -> Result<()> {
-        let req = rpc::fs::set(fsname, props);
+        let req = rpc::fs::set(fsname, props, user_props);
         self.call(req).await.unwrap().into_fs_set()
     }
 
+    /// Thaw a frozen file system
+    ///
+    /// # Arguments
+    ///
+    /// `fsname`    -   Name of the file system, including the pool
+    pub async fn fs_thaw(&self, fsname: String) -> Result<()> {
+        let req = rpc::fs::thaw(fsname);
+        self.call(req).await.unwrap().into_fs_thaw()
+    }
+
+    /// Revoke a jail's delegation of a file system
+    ///
+    /// # Arguments
+    ///
+    /// `fsname`    -   Name of the file system, including the pool
+    /// `jid`       -   Numeric ID of the jail
+    pub async fn fs_unjail(&self, fsname: String, jid: i32) -> Result<()> {
+        let req = rpc::fs::unjail(fsname, jid);
+        self.call(req).await.unwrap().into_fs_unjail()
+    }
+
     /// Unmount a file system
     ///
     /// # Arguments
@@ -183,39 +429,316 @@ impl Bfffs {
         self.call(req).await.unwrap().into_fs_unmount()
     }
 
+    /// Unmount every mounted file system in the pool, children before parents.
+    ///
+    /// Returns the result of each unmount.
+    pub async fn fs_unmount_all(
+        &self,
+        pool: String,
+        force: bool,
+    ) -> Result<rpc::fs::BulkResult> {
+        let req = rpc::fs::unmount_all(pool, force);
+        self.call(req).await.unwrap().into_fs_unmount_all()
+    }
+
+    /// Cancel a watch, releasing any closes that are still waiting on it
+    pub async fn fs_unwatch(&self, id: rpc::fs::WatchId) -> Result<()> {
+        let req = rpc::fs::unwatch(id);
+        self.call(req).await.unwrap().into_fs_unwatch()
+    }
+
+    /// Register to be notified whenever a file is closed after being written
+    ///
+    /// Retrieve the events with [`Bfffs::fs_events`].  A watch that goes
+    /// unpolled for too long is dropped.
+    ///
+    /// # Arguments
+    ///
+    /// `fsname`    -   Name of the file system, including the pool.  Its
+    ///                 descendants are watched too.
+    /// `sync`      -   Make each close wait until the event is acknowledged
+    pub async fn fs_watch(
+        &self,
+        fsname: String,
+        sync: bool,
+    ) -> Result<rpc::fs::WatchId> {
+        let req = rpc::fs::watch(fsname, sync);
+        self.call(req).await.unwrap().into_fs_watch()
+    }
+
+    /// List all running and recently finished jobs
+    pub async fn job_list(&self) -> Result<Vec<JobStatus>> {
+        let req = rpc::job::list();
+        self.call(req).await.unwrap().into_job_list()
+    }
+
+    /// Get the progress of a single running or recently finished job
+    pub async fn job_status(&self, id: JobID) -> Result<JobStatus> {
+        let req = rpc::job::status(id);
+        self.call(req).await.unwrap().into_job_status()
+    }
+
     /// Connect to the server whose socket is at this path
     pub async fn new(sock: &Path) -> Result<Self> {
         let peer = UnixSeqpacket::connect(sock).await.map_err(Error::from)?;
-        Ok(Self { peer })
+        Ok(Self::from_peer(Arc::new(peer)))
+    }
+
+    /// Connect to a remote server over TLS.
+    ///
+    /// The server must be listening on `addr`, given as `host:port`, and
+    /// present a certificate signed by an authority in the PEM file `ca`.
+    /// `cert` and `key` are PEM files containing this client's own
+    /// certificate and private key.  Since the server authenticates the
+    /// client by its certificate, every request is privileged.
+    #[cfg(feature = "tls")]
+    pub async fn new_tls(
+        addr: &str,
+        ca: &Path,
+        cert: &Path,
+        key: &Path,
+    ) -> Result<Self> {
+        use tokio::net::TcpStream;
+        use tokio_rustls::{rustls::ServerName, TlsConnector};
+
+        let config = transport::tls::client_config(ca, cert, key)?;
+        let host = addr
+            .rsplit_once(':')
+            .map(|(host, _port)| host)
+            .unwrap_or(addr)
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let name = ServerName::try_from(host).map_err(|_| Error::EINVAL)?;
+        let stream = TcpStream::connect(addr).await?;
+        let stream = TlsConnector::from(config).connect(name, stream).await?;
+        Ok(Self::from_peer(Arc::new(transport::FramedPeer::new(stream))))
+    }
+
+    fn from_peer(peer: Arc<dyn Peer>) -> Self {
+        let pending = Pending::default();
+        let reader =
+            tokio::spawn(Self::read_responses(peer.clone(), pending.clone()));
+        Self {
+            auth: None,
+            next_id: AtomicU64::new(0),
+            peer,
+            pending,
+            reader,
+        }
+    }
+
+    /// List the file handles that clients have open on a file system
+    pub async fn open_files(&self, fsname: &str) -> Result<Vec<OpenFile>> {
+        let req = rpc::debug::open_files(fsname.to_owned());
+        self.call(req).await.unwrap().into_debug_open_files()
+    }
+
+    /// Grow a pool by adding a new cluster, built from unused devices.
+    ///
+    /// Each element of `mirrors` lists the devices of one of the cluster's
+    /// mirrors.  For a single disk, use one mirror with one device and set
+    /// `disks_per_stripe` to 1 and `redundancy` to 0.
+    pub async fn pool_add(
+        &self,
+        pool: String,
+        disks_per_stripe: i16,
+        redundancy: i16,
+        mirrors: Vec<Vec<PathBuf>>,
+    ) -> Result<()> {
+        let req = rpc::pool::add(pool, disks_per_stripe, redundancy, mirrors);
+        self.call(req).await.unwrap().into_pool_add()
+    }
+
+    /// Take a checkpoint of a pool, or discard its existing one
+    pub async fn pool_checkpoint(
+        &self,
+        pool: String,
+        discard: bool,
+    ) -> Result<()> {
+        let req = rpc::pool::checkpoint(pool, discard);
+        self.call(req).await.unwrap().into_pool_checkpoint()
     }
 
     /// Clean freed space on a pool
-    pub async fn pool_clean(&self, pool: String) -> Result<()> {
-        let req = rpc::pool::clean(pool);
+    ///
+    /// Returns a summary of the work that was started, or, if `dry_run` is
+    /// set, of the work that would be done.  Also returns the ID of the job
+    /// doing the cleaning, if one was started.  `policy` controls where the
+    /// cleaner places the records that it moves.
+    pub async fn pool_clean(
+        &self,
+        pool: String,
+        dry_run: bool,
+        policy: CleanPolicy,
+    ) -> Result<(CleanStats, Option<JobID>)> {
+        let req = rpc::pool::clean(pool, dry_run, policy);
         self.call(req).await.unwrap().into_pool_clean()
     }
 
+    /// Rewrite a pool's fragmented files contiguously, in the background
+    ///
+    /// Returns the pool's fragmentation, and, unless `dry_run` is set, the ID
+    /// of the job doing the defragmentation.
+    pub async fn pool_defrag(
+        &self,
+        pool: String,
+        dry_run: bool,
+    ) -> Result<(DefragStats, Option<JobID>)> {
+        let req = rpc::pool::defrag(pool, dry_run);
+        self.call(req).await.unwrap().into_pool_defrag()
+    }
+
+    /// Unmount all of the pool's file systems, sync it, and stop bfffsd.
+    ///
+    /// If any file system can't be unmounted, the pool stays imported.  Either
+    /// way, the result of each unmount is returned.
+    pub async fn pool_export(
+        &self,
+        pool: String,
+        force: bool,
+    ) -> Result<rpc::fs::BulkResult> {
+        let req = rpc::pool::export(pool, force);
+        self.call(req).await.unwrap().into_pool_export()
+    }
+
+    /// List every file in a pool that has suffered an unrecoverable read
+    /// error.
+    pub async fn pool_errors(&self, pool: String) -> Result<Vec<DataError>> {
+        let req = rpc::pool::errors(pool);
+        self.call(req).await.unwrap().into_pool_errors()
+    }
+
+    /// Bring a removed or faulted device back online, and resilver whatever
+    /// writes it missed.
+    pub async fn pool_online(&self, pool: String, dev: PathBuf) -> Result<()> {
+        let req = rpc::pool::online(pool, dev);
+        self.call(req).await.unwrap().into_pool_online()
+    }
+
+    /// Predict how a change to a pool's vdev configuration would affect its
+    /// capacity and redundancy, without making the change.
+    pub async fn pool_plan(
+        &self,
+        pool: String,
+        change: Change,
+    ) -> Result<Plan> {
+        let req = rpc::pool::plan(pool, change);
+        self.call(req).await.unwrap().into_pool_plan()
+    }
+
+    /// Change pool properties
+    pub async fn pool_set(
+        &self,
+        pool: String,
+        props: Vec<PoolProperty>,
+    ) -> Result<()> {
+        let req = rpc::pool::set(pool, props);
+        self.call(req).await.unwrap().into_pool_set()
+    }
+
+    /// Get the status of every leaf device in a pool, including its lifetime
+    /// I/O error counts, plus the pool's zone statistics.
+    pub async fn pool_status(&self, pool: String) -> Result<PoolStatus> {
+        let req = rpc::pool::status(pool);
+        self.call(req).await.unwrap().into_pool_status()
+    }
+
+    /// Get a pool's current, last synced, and checkpoint transaction groups.
+    pub async fn pool_txgs(&self, pool: String) -> Result<TxgStatus> {
+        let req = rpc::pool::txgs(pool);
+        self.call(req).await.unwrap().into_pool_txgs()
+    }
+
+    /// Enable on-disk format features on a pool.
+    ///
+    /// If `features` is empty, enable every feature supported by the server.
+    /// Returns the features that weren't already enabled.
+    pub async fn pool_upgrade(
+        &self,
+        pool: String,
+        features: Vec<Feature>,
+    ) -> Result<Vec<Feature>> {
+        let req = rpc::pool::upgrade(pool, features);
+        self.call(req).await.unwrap().into_pool_upgrade()
+    }
+
+    /// Create a new volume
+    ///
+    /// # Arguments
+    ///
+    /// `name`      -   Name of the new volume, including the pool
+    /// `size`      -   Size of the volume in bytes
+    /// `props`     -   Any non-default properties to set on the volume
+    pub async fn volume_create(
+        &self,
+        name: String,
+        size: u64,
+        props: Vec<Property>,
+    ) -> Result<TreeID> {
+        let req = rpc::volume::create(name, size, props);
+        self.call(req).await.unwrap().into_volume_create()
+    }
+
+    /// Read responses from the server and deliver them to their callers
+    async fn read_responses(peer: Arc<dyn Peer>, pending: Pending) {
+        const BUFSIZ: usize = 4096;
+
+        let mut buf = vec![0u8; BUFSIZ];
+        loop {
+            let nread = match peer.recv(&mut buf).await {
+                Ok(nread) => nread,
+                Err(e) => {
+                    eprintln!("Error receiving response from server: {e}");
+                    break;
+                }
+            };
+            if nread == 0 {
+                break;
+            } else if nread >= BUFSIZ {
+                eprintln!(
+                    "Server sent unexpectedly large response {nread} bytes"
+                );
+                break;
+            }
+            let packet = &buf[..nread];
+            match rpc::decode::<(rpc::RequestId, rpc::Response)>(packet) {
+                Ok((id, resp)) => {
+                    // The caller may have already given up on it
+                    if let Some(tx) = pending.lock().unwrap().remove(&id) {
+                        let _ = tx.send(resp);
+                    }
+                }
+                Err(_) => {
+                    eprintln!("Corrupt response from server");
+                    break;
+                }
+            }
+        }
+        // Wake every remaining caller with an error
+        pending.lock().unwrap().clear();
+    }
+
     /// Submit an RPC request to the server
     async fn call(&self, req: rpc::Request) -> Result<rpc::Response> {
-        const BUFSIZ: usize = 4096;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, tx);
+        let mut guard = CancelOnDrop {
+            bfffs: self,
+            id,
+            armed: false,
+        };
 
-        let encoded: Vec<u8> = bincode::serialize(&req).unwrap();
+        let encoded = rpc::encode(&(id, self.auth, req)).unwrap();
         let nwrite = self.peer.send(&encoded).await.map_err(Error::from)?;
         assert_eq!(nwrite, encoded.len());
+        guard.armed = true;
 
-        let mut buf = vec![0u8; BUFSIZ];
-        let nread = self.peer.recv(&mut buf).await.map_err(Error::from)?;
-        if nread == 0 {
+        let r = rx.await.map_err(|_| {
             eprintln!("Server did not send response");
-            Err(Error::EIO)
-        } else if nread >= BUFSIZ {
-            eprintln!("Server sent unexpectedly large response {nread} bytes");
-            Err(Error::EIO)
-        } else {
-            buf.truncate(nread);
-            let resp = bincode::deserialize::<rpc::Response>(&buf[..])
-                .expect("Corrupt response from server");
-            Ok(resp)
-        }
+            Error::EIO
+        });
+        guard.armed = false;
+        r
     }
 }
@@ -0,0 +1,259 @@
+// vim: tw=80
+//! Transports that can carry bfffsd's RPC protocol
+//!
+//! Locally, bfffsd listens on a `SOCK_SEQPACKET` Unix-domain socket, which
+//! preserves message boundaries on its own.  Remotely, it may listen on a TLS
+//! stream, which doesn't.  So on streams, every packet is preceded by its
+//! length as a big-endian `u32`.
+
+use std::{fmt, io};
+
+use async_trait::async_trait;
+use tokio::{
+    io::{
+        AsyncRead,
+        AsyncReadExt,
+        AsyncWrite,
+        AsyncWriteExt,
+        ReadHalf,
+        WriteHalf,
+    },
+    sync::Mutex,
+};
+use tokio_seqpacket::UnixSeqpacket;
+
+/// One end of a connection that exchanges whole packets.
+#[async_trait]
+pub trait Peer: fmt::Debug + Send + Sync {
+    /// Receive a single packet.
+    ///
+    /// Returns the packet's length, or 0 if the other end disconnected.
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Send a single packet.
+    async fn send(&self, buf: &[u8]) -> io::Result<usize>;
+}
+
+#[async_trait]
+impl Peer for UnixSeqpacket {
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        UnixSeqpacket::recv(self, buf).await
+    }
+
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        UnixSeqpacket::send(self, buf).await
+    }
+}
+
+/// Adapts a byte stream to a [`Peer`] by prefixing each packet with its
+/// length.
+pub struct FramedPeer<S> {
+    reader: Mutex<ReadHalf<S>>,
+    writer: Mutex<WriteHalf<S>>,
+}
+
+impl<S> FramedPeer<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    pub fn new(stream: S) -> Self {
+        let (reader, writer) = tokio::io::split(stream);
+        FramedPeer {
+            reader: Mutex::new(reader),
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+impl<S> fmt::Debug for FramedPeer<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FramedPeer").finish_non_exhaustive()
+    }
+}
+
+#[async_trait]
+impl<S> Peer for FramedPeer<S>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin,
+{
+    async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut reader = self.reader.lock().await;
+        let len = match reader.read_u32().await {
+            Ok(len) => len as usize,
+            // Disconnected between packets
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        if len == 0 || len > buf.len() {
+            // Unlike a seqpacket socket, we can't discard the rest of an
+            // oversized packet and carry on.
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid packet length {len}"),
+            ));
+        }
+        reader.read_exact(&mut buf[..len]).await
+    }
+
+    async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let len = u32::try_from(buf.len())
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let mut framed = Vec::with_capacity(4 + buf.len());
+        framed.extend_from_slice(&len.to_be_bytes());
+        framed.extend_from_slice(buf);
+        // Hold the lock across the whole packet, so concurrent senders can't
+        // interleave.
+        let mut writer = self.writer.lock().await;
+        writer.write_all(&framed).await?;
+        writer.flush().await?;
+        Ok(buf.len())
+    }
+}
+
+/// Loading TLS certificates and keys
+#[cfg(feature = "tls")]
+pub mod tls {
+    use std::{fs::File, io::BufReader, path::Path, sync::Arc};
+
+    use rustls_pemfile::Item;
+    use tokio_rustls::rustls::{
+        server::AllowAnyAuthenticatedClient,
+        Certificate,
+        ClientConfig,
+        PrivateKey,
+        RootCertStore,
+        ServerConfig,
+    };
+
+    use super::*;
+
+    fn invalid<E: fmt::Display>(path: &Path, e: E) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {e}", path.display()),
+        )
+    }
+
+    /// Read every certificate from a PEM file
+    fn load_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+        let mut rdr = BufReader::new(File::open(path)?);
+        let certs = rustls_pemfile::certs(&mut rdr)?;
+        if certs.is_empty() {
+            return Err(invalid(path, "no certificates found"));
+        }
+        Ok(certs.into_iter().map(Certificate).collect())
+    }
+
+    /// Read the first private key from a PEM file
+    fn load_key(path: &Path) -> io::Result<PrivateKey> {
+        let mut rdr = BufReader::new(File::open(path)?);
+        for item in rustls_pemfile::read_all(&mut rdr)? {
+            match item {
+                Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => {
+                    return Ok(PrivateKey(key))
+                }
+                _ => (),
+            }
+        }
+        Err(invalid(path, "no private key found"))
+    }
+
+    /// Build a trust store from the certificate authorities in a PEM file
+    fn load_roots(path: &Path) -> io::Result<RootCertStore> {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(path)? {
+            roots.add(&cert).map_err(|e| invalid(path, e))?;
+        }
+        Ok(roots)
+    }
+
+    /// TLS configuration for bfffsd.
+    ///
+    /// Clients must present a certificate signed by one of the authorities
+    /// in `ca`.
+    pub fn server_config(
+        ca: &Path,
+        cert: &Path,
+        key: &Path,
+    ) -> io::Result<Arc<ServerConfig>> {
+        let verifier = AllowAnyAuthenticatedClient::new(load_roots(ca)?);
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(|e| invalid(cert, e))?;
+        Ok(Arc::new(config))
+    }
+
+    /// TLS configuration for a client of bfffsd.
+    ///
+    /// The server must present a certificate signed by one of the
+    /// authorities in `ca`.
+    pub fn client_config(
+        ca: &Path,
+        cert: &Path,
+        key: &Path,
+    ) -> io::Result<Arc<ClientConfig>> {
+        let config = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(load_roots(ca)?)
+            .with_single_cert(load_certs(cert)?, load_key(key)?)
+            .map_err(|e| invalid(cert, e))?;
+        Ok(Arc::new(config))
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use tokio::io::duplex;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn roundtrip() {
+        let (a, b) = duplex(64);
+        let a = FramedPeer::new(a);
+        let b = FramedPeer::new(b);
+        let mut buf = [0u8; 16];
+
+        assert_eq!(a.send(b"hello").await.unwrap(), 5);
+        assert_eq!(a.send(b"world!").await.unwrap(), 6);
+        assert_eq!(b.recv(&mut buf).await.unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(b.recv(&mut buf).await.unwrap(), 6);
+        assert_eq!(&buf[..6], b"world!");
+    }
+
+    /// A clean disconnect between packets looks like a 0-length read
+    #[tokio::test]
+    async fn eof() {
+        let (a, b) = duplex(64);
+        let b = FramedPeer::new(b);
+        drop(a);
+        let mut buf = [0u8; 16];
+        assert_eq!(b.recv(&mut buf).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn oversized() {
+        let (a, b) = duplex(64);
+        let a = FramedPeer::new(a);
+        let b = FramedPeer::new(b);
+        let mut buf = [0u8; 4];
+        a.send(b"too long").await.unwrap();
+        let e = b.recv(&mut buf).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    /// Disconnecting in the middle of a packet is an error
+    #[tokio::test]
+    async fn truncated() {
+        let (mut a, b) = duplex(64);
+        let b = FramedPeer::new(b);
+        a.write_all(&[0, 0, 0, 8, b'a', b'b']).await.unwrap();
+        drop(a);
+        let mut buf = [0u8; 16];
+        let e = b.recv(&mut buf).await.unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -40,3 +40,15 @@ async fn ok(harness: Harness) {
         .assert()
         .success();
 }
+
+#[rstest]
+#[tokio::test]
+async fn refcounts(harness: Harness) {
+    let (filename, _tempdir) = harness;
+
+    bfffs()
+        .args(["check", "--refcounts", "mypool"])
+        .arg(filename)
+        .assert()
+        .success();
+}
@@ -106,3 +106,42 @@ async fn tree(harness: Harness) {
 ",
         ));
 }
+
+#[rstest]
+#[tokio::test]
+async fn tree_json(harness: Harness) {
+    let (filename, _tempdir) = harness;
+
+    // A freshly created file system has a single leaf node
+    bfffs()
+        .args(["debug", "dump", "-t", "--format", "json", "mypool"])
+        .arg(filename)
+        .assert()
+        .success()
+        .stdout(predicates::str::starts_with(
+            r#"{"addr":1,"height":0,"node":{"Leaf":[["#,
+        ));
+}
+
+#[rstest]
+#[tokio::test]
+async fn tree_yaml_range(harness: Harness) {
+    let (filename, _tempdir) = harness;
+
+    // There are no objects with inode numbers this large, so no leaf items
+    // should be printed.
+    bfffs()
+        .args(["debug", "dump", "-t", "--format", "yaml"])
+        .args(["--start-ino", "1000000", "mypool"])
+        .arg(filename)
+        .assert()
+        .success()
+        .stdout(
+            r"---
+addr: 1
+height: 0
+node:
+  Leaf: []
+",
+        );
+}
@@ -1 +1,3 @@
+mod compact;
 mod dump;
+mod taste;
@@ -0,0 +1,86 @@
+use std::{fs, os::unix::fs::FileExt, path::PathBuf};
+
+use assert_cmd::prelude::*;
+use rstest::{fixture, rstest};
+use tempfile::{Builder, TempDir};
+
+use super::super::super::bfffs;
+
+type Harness = (PathBuf, TempDir);
+
+/// Create a pool for backing store
+#[fixture]
+fn harness() -> Harness {
+    let len = 1 << 30; // 1 GB
+    let tempdir = Builder::new()
+        .prefix(concat!(module_path!(), "."))
+        .tempdir()
+        .unwrap();
+    let filename = tempdir.path().join("vdev");
+    let file = fs::File::create(&filename).unwrap();
+    file.set_len(len).unwrap();
+
+    bfffs()
+        .args(["pool", "create", "mypool"])
+        .arg(&filename)
+        .assert()
+        .success();
+
+    (filename, tempdir)
+}
+
+/// A corrupt label should be reported, but the other should still be printed
+#[rstest]
+fn corrupt(harness: Harness) {
+    let (filename, _tempdir) = harness;
+    let f = fs::OpenOptions::new().write(true).open(&filename).unwrap();
+    f.write_all_at(&[0xba; 4096], 0).unwrap();
+
+    bfffs()
+        .args(["debug", "taste"])
+        .arg(filename)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("label: 0\nerror: EINVAL"))
+        .stdout(predicates::str::contains("label: 1\nleaf:"))
+        .stdout(predicates::str::contains("name: mypool"));
+}
+
+#[rstest]
+fn enoent(harness: Harness) {
+    let (_filename, tempdir) = harness;
+
+    bfffs()
+        .args(["debug", "taste"])
+        .arg(tempdir.path().join("does_not_exist"))
+        .assert()
+        .failure();
+}
+
+#[test]
+fn help() {
+    bfffs().args(["debug", "taste", "-h"]).assert().success();
+}
+
+#[rstest]
+fn ok(harness: Harness) {
+    let (filename, _tempdir) = harness;
+
+    // Just check that every layer of both labels is present.  The layers'
+    // formats are determined by serde.
+    let output = bfffs()
+        .args(["debug", "taste"])
+        .arg(filename)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    for layer in ["leaf", "mirror", "raid", "pool", "idml", "database"] {
+        let pat = format!("\n{layer}:");
+        assert_eq!(stdout.matches(&pat).count(), 2, "{layer} missing");
+    }
+    assert!(stdout.contains("label: 0\n"));
+    assert!(stdout.contains("label: 1\n"));
+    assert!(stdout.contains("name: mypool"));
+    assert!(!stdout.contains("error:"));
+}
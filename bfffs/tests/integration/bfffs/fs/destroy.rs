@@ -109,6 +109,34 @@ async fn grandchild(harness: Harness) {
         .success();
 }
 
+/// A dry run should report what would be destroyed, but not destroy it
+#[rstest]
+#[tokio::test]
+async fn dry_run(harness: Harness) {
+    bfffs()
+        .arg("--sock")
+        .arg(harness.sockpath.as_os_str())
+        .args(["fs", "create", "mypool/foo"])
+        .assert()
+        .success();
+
+    bfffs()
+        .arg("--sock")
+        .arg(harness.sockpath.as_os_str())
+        .args(["fs", "destroy", "-nv", "mypool/foo"])
+        .assert()
+        .success()
+        .stdout("would destroy mypool/foo\n");
+
+    bfffs()
+        .arg("--sock")
+        .arg(harness.sockpath.as_os_str())
+        .args(["fs", "destroy", "-v", "mypool/foo"])
+        .assert()
+        .success()
+        .stdout("destroyed mypool/foo\n");
+}
+
 #[rstest]
 #[tokio::test]
 async fn enoent(harness: Harness) {
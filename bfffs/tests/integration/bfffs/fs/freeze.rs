@@ -0,0 +1,121 @@
+use std::{
+    fs,
+    os::unix::fs::FileTypeExt,
+    path::PathBuf,
+    process::Command,
+    time::Duration,
+};
+
+use assert_cmd::{cargo::cargo_bin, prelude::*};
+use rstest::{fixture, rstest};
+use tempfile::{Builder, TempDir};
+
+use super::super::super::*;
+
+struct Harness {
+    _bfffsd:      Bfffsd,
+    _tempdir:     TempDir,
+    pub sockpath: PathBuf,
+}
+
+/// Create a pool for backing store
+#[fixture]
+fn harness() -> Harness {
+    let len = 1 << 30; // 1 GB
+    let tempdir = Builder::new()
+        .prefix(concat!(module_path!(), "."))
+        .tempdir()
+        .unwrap();
+    let filename = tempdir.path().join("vdev");
+    let file = fs::File::create(&filename).unwrap();
+    file.set_len(len).unwrap();
+
+    let mountpoint = tempdir.path().join("mnt");
+    fs::create_dir(&mountpoint).unwrap();
+    bfffs()
+        .args(["pool", "create", "-p"])
+        .arg(format!("mountpoint={}", mountpoint.display()))
+        .arg("mypool")
+        .arg(&filename)
+        .assert()
+        .success();
+
+    let sockpath = tempdir.path().join("bfffsd.sock");
+    let bfffsd: Bfffsd = Command::new(cargo_bin("bfffsd"))
+        .arg("--sock")
+        .arg(sockpath.as_os_str())
+        .arg("mypool")
+        .arg(filename.as_os_str())
+        .spawn()
+        .unwrap()
+        .into();
+
+    // We must wait for bfffsd to be ready to receive commands
+    waitfor(Duration::from_secs(5), || {
+        fs::metadata(&sockpath)
+            .map(|md| md.file_type().is_socket())
+            .unwrap_or(false)
+    })
+    .expect("Timeout waiting for bfffsd to listen");
+
+    Harness {
+        _bfffsd: bfffsd,
+        sockpath,
+        _tempdir: tempdir,
+    }
+
+#[rstest]
+#[tokio::test]
+async fn freeze_and_thaw(harness: Harness) {
+    bfffs()
+        .arg("--sock")
+        .arg(harness.sockpath.as_os_str())
+        .args(["fs", "create", "mypool/foo"])
+        .assert()
+        .success();
+
+    bfffs()
+        .arg("--sock")
+        .arg(harness.sockpath.as_os_str())
+        .args(["fs", "freeze", "mypool/foo"])
+        .assert()
+        .success();
+
+    bfffs()
+        .arg("--sock")
+        .arg(harness.sockpath.as_os_str())
+        .args(["fs", "thaw", "mypool/foo"])
+        .assert()
+        .success();
+}
+
+/// Freezing a file system twice is an error
+#[rstest]
+#[tokio::test]
+async fn freeze_twice(harness: Harness) {
+    bfffs()
+        .arg("--sock")
+        .arg(harness.sockpath.as_os_str())
+        .args(["fs", "freeze", "mypool"])
+        .assert()
+        .success();
+
+    bfffs()
+        .arg("--sock")
+        .arg(harness.sockpath.as_os_str())
+        .args(["fs", "freeze", "mypool"])
+        .assert()
+        .failure();
+}
+
+/// Thawing a file system that isn't frozen is an error
+#[rstest]
+#[tokio::test]
+async fn thaw_unfrozen(harness: Harness) {
+    bfffs()
+        .arg("--sock")
+        .arg(harness.sockpath.as_os_str())
+        .args(["fs", "thaw", "mypool"])
+        .assert()
+        .failure();
+}
@@ -70,6 +70,49 @@ fn harness<S: AsRef<str>>(dsnames: &[S]) -> Harness {
     }
 }
 
+/// "all" should list every native property, and any user properties
+#[rstest]
+#[tokio::test]
+async fn all() {
+    let h = harness::<&'static str>(&[]);
+    bfffs()
+        .arg("--sock")
+        .arg(h.sockpath.as_os_str())
+        .args(["fs", "set", "user:backup-policy=daily", "mypool"])
+        .assert()
+        .success();
+    bfffs()
+        .arg("--sock")
+        .arg(h.sockpath.as_os_str())
+        .args(["fs", "get", "-p", "-o", "property", "all", "mypool"])
+        .assert()
+        .success()
+        .stdout(
+            "name\n\
+             atime\n\
+             canmount\n\
+             cleanunmount\n\
+             coalesce\n\
+             copies\n\
+             devices\n\
+             dirsync\n\
+             dirtylimit\n\
+             exec\n\
+             lastmounted\n\
+             mountcount\n\
+             mounted\n\
+             mountpoint\n\
+             recordsize\n\
+             setuid\n\
+             share9p\n\
+             shareiscsi\n\
+             sync\n\
+             utf8only\n\
+             volsize\n\
+             user:backup-policy\n",
+        );
+}
+
 #[rstest]
 #[tokio::test]
 async fn depth() {
@@ -1,7 +1,9 @@
 mod create;
 mod destroy;
+mod freeze;
 mod get;
 mod list;
 mod mount;
+mod restore;
 mod set;
 mod unmount;
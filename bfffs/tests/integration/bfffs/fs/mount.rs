@@ -1,78 +1,17 @@
-use std::{
-    fs,
-    os::unix::fs::FileTypeExt,
-    path::PathBuf,
-    process::Command,
-    time::Duration,
-};
-
-use assert_cmd::{cargo::cargo_bin, prelude::*};
+use std::fs;
+
+use assert_cmd::prelude::*;
+use bfffs_test_harness::{Harness, PoolBuilder};
 use function_name::named;
 use nix::mount::{unmount, MntFlags};
 use rstest::{fixture, rstest};
-use tempfile::{Builder, TempDir};
 
 use super::super::super::*;
 
-struct Harness {
-    _bfffsd:        Bfffsd,
-    pub tempdir:    TempDir,
-    pub mountpoint: PathBuf,
-    pub sockpath:   PathBuf,
-}
-
-impl Drop for Harness {
-    fn drop(&mut self) {
-        let _ignore_errors = unmount(&self.mountpoint, MntFlags::empty());
-    }
-}
-
-/// Create a pool for backing store
+/// Serve a single-disk pool with a mountpoint
 #[fixture]
 fn harness() -> Harness {
-    let len = 1 << 30; // 1 GB
-    let tempdir = Builder::new()
-        .prefix(concat!(module_path!(), "."))
-        .tempdir()
-        .unwrap();
-    let filename = tempdir.path().join("vdev");
-    let file = fs::File::create(&filename).unwrap();
-    file.set_len(len).unwrap();
-
-    let mountpoint = tempdir.path().join("mnt");
-    fs::create_dir(&mountpoint).unwrap();
-    bfffs()
-        .args(["pool", "create", "-p"])
-        .arg(format!("mountpoint={}", mountpoint.display()))
-        .arg("mypool")
-        .arg(&filename)
-        .assert()
-        .success();
-
-    let sockpath = tempdir.path().join("bfffsd.sock");
-    let bfffsd: Bfffsd = Command::new(cargo_bin("bfffsd"))
-        .arg("--sock")
-        .arg(sockpath.as_os_str())
-        .arg("mypool")
-        .arg(filename.as_os_str())
-        .spawn()
-        .unwrap()
-        .into();
-
-    // We must wait for bfffsd to be ready to receive commands
-    waitfor(Duration::from_secs(5), || {
-        fs::metadata(&sockpath)
-            .map(|md| md.file_type().is_socket())
-            .unwrap_or(false)
-    })
-    .expect("Timeout waiting for bfffsd to listen");
-
-    Harness {
-        _bfffsd: bfffsd,
-        sockpath,
-        mountpoint,
-        tempdir,
-    }
+    PoolBuilder::new("mypool").mountpoint().start()
 }
 
 // Unmount the file system and remount it in the same location
@@ -82,18 +21,16 @@ fn harness() -> Harness {
 async fn mount_again(harness: Harness) {
     require_fusefs!();
 
-    bfffs()
-        .arg("--sock")
-        .arg(harness.sockpath.as_os_str())
+    harness
+        .bfffs()
         .args(["fs", "mount", "mypool"])
         .assert()
         .success();
 
-    unmount(&harness.mountpoint, MntFlags::empty()).unwrap();
+    unmount(harness.pool().mountpoint().unwrap(), MntFlags::empty()).unwrap();
 
-    bfffs()
-        .arg("--sock")
-        .arg(harness.sockpath.as_os_str())
+    harness
+        .bfffs()
         .args(["fs", "mount", "mypool"])
         .assert()
         .success();
@@ -105,9 +42,8 @@ async fn mount_again(harness: Harness) {
 async fn ok(harness: Harness) {
     require_fusefs!();
 
-    bfffs()
-        .arg("--sock")
-        .arg(harness.sockpath.as_os_str())
+    harness
+        .bfffs()
         .args(["fs", "mount", "mypool"])
         .assert()
         .success();
@@ -119,9 +55,8 @@ async fn ok(harness: Harness) {
 async fn options(harness: Harness) {
     require_fusefs!();
 
-    bfffs()
-        .arg("--sock")
-        .arg(harness.sockpath.as_os_str())
+    harness
+        .bfffs()
         .args(["fs", "mount", "-o", "atime=off", "mypool"])
         .assert()
         .success();
@@ -136,19 +71,17 @@ async fn options(harness: Harness) {
 async fn subfs(harness: Harness) {
     require_fusefs!();
 
-    let submp = harness.tempdir.path().join("mnt").join("foo");
+    let submp = harness.pool().mountpoint().unwrap().join("foo");
     fs::create_dir(&submp).unwrap();
 
-    bfffs()
-        .arg("--sock")
-        .arg(harness.sockpath.as_os_str())
+    harness
+        .bfffs()
         .args(["fs", "create", "mypool/foo"])
         .assert()
         .success();
 
-    bfffs()
-        .arg("--sock")
-        .arg(harness.sockpath.as_os_str())
+    harness
+        .bfffs()
         .args(["fs", "mount", "mypool/foo"])
         .assert()
         .success();
@@ -156,10 +89,170 @@ async fn subfs(harness: Harness) {
     unmount(&submp, MntFlags::empty()).unwrap();
 }
 
-// TODO: test with overridden mountpoint, once that is possible.
+/// If the mountpoint doesn't exist yet, bfffsd should create it
+#[named]
+#[rstest]
+#[tokio::test]
+async fn mkdir(harness: Harness) {
+    require_fusefs!();
+
+    let submp = harness.pool().mountpoint().unwrap().join("foo").join("bar");
+
+    harness
+        .bfffs()
+        .args(["fs", "create", "mypool/foo"])
+        .assert()
+        .success();
+    harness
+        .bfffs()
+        .args(["fs", "create", "mypool/foo/bar"])
+        .assert()
+        .success();
+
+    // Mount the child without mounting its parent first, so neither directory
+    // exists.
+    harness
+        .bfffs()
+        .args(["fs", "mount", "mypool/foo/bar"])
+        .assert()
+        .success();
+
+    assert!(fs::metadata(&submp).unwrap().is_dir());
+    unmount(&submp, MntFlags::empty()).unwrap();
+}
+
+/// Mount somewhere other than the mountpoint property
+#[named]
+#[rstest]
+#[tokio::test]
+async fn mountpoint(harness: Harness) {
+    require_fusefs!();
+
+    let altmp = harness.pool().path().join("alt");
+
+    harness
+        .bfffs()
+        .args(["fs", "mount", "-m"])
+        .arg(altmp.as_os_str())
+        .arg("mypool")
+        .assert()
+        .success();
+
+    unmount(&altmp, MntFlags::empty()).unwrap();
+}
+
+/// It should not be possible to mount the same file system twice
+#[named]
+#[rstest]
+#[tokio::test]
+async fn ebusy(harness: Harness) {
+    require_fusefs!();
+
+    harness
+        .bfffs()
+        .args(["fs", "mount", "mypool"])
+        .assert()
+        .success();
+
+    harness
+        .bfffs()
+        .args(["fs", "mount", "mypool"])
+        .assert()
+        .failure()
+        .stderr("Error: EBUSY\n");
+}
+
+/// A mounted file system should be listed by "fs mounts" and "fs list"
+#[named]
+#[rstest]
+#[tokio::test]
+async fn mounts(harness: Harness) {
+    require_fusefs!();
+
+    harness
+        .bfffs()
+        .args(["fs", "mount", "mypool"])
+        .assert()
+        .success();
 
-// TODO: once the mountpoint can be overridden, check that it is not be possible
-// to mount the same file system twice, similar to the old ebusy test, from
-// before the mountpoint property was introduced.
+    let uid = nix::unistd::getuid();
+    harness
+        .bfffs()
+        .args(["fs", "mounts", "-p"])
+        .assert()
+        .success()
+        .stdout(format!(
+            "mypool\t{}\t\t{}\n",
+            harness.pool().mountpoint().unwrap().display(),
+            uid
+        ));
 
-// TODO: test with alternate mountpoint
+    harness
+        .bfffs()
+        .args(["fs", "list", "-p", "-o", "name,mounted", "mypool"])
+        .assert()
+        .success()
+        .stdout("mypool\tyes\n");
+}
+
+/// Mounting a file system should be recorded in its mount history
+#[named]
+#[rstest]
+#[tokio::test]
+async fn mount_history(harness: Harness) {
+    require_fusefs!();
+
+    harness
+        .bfffs()
+        .args(["fs", "list", "-p", "-o", "name,mountcount,cleanunmount"])
+        .arg("mypool")
+        .assert()
+        .success()
+        .stdout("mypool\t0\tyes\n");
+
+    harness
+        .bfffs()
+        .args(["fs", "mount", "mypool"])
+        .assert()
+        .success();
+
+    harness
+        .bfffs()
+        .args(["fs", "list", "-p", "-o", "name,mountcount,cleanunmount"])
+        .arg("mypool")
+        .assert()
+        .success()
+        .stdout("mypool\t1\tno\n");
+}
+
+/// With "-o remount", an already mounted file system may be mounted again
+#[named]
+#[rstest]
+#[tokio::test]
+async fn remount(harness: Harness) {
+    require_fusefs!();
+
+    harness
+        .bfffs()
+        .args(["fs", "mount", "mypool"])
+        .assert()
+        .success();
+
+    harness
+        .bfffs()
+        .args(["fs", "mount", "-o", "remount", "mypool"])
+        .assert()
+        .success();
+}
+
+/// But a file system that isn't mounted can't be remounted
+#[rstest]
+#[tokio::test]
+async fn remount_unmounted(harness: Harness) {
+    harness
+        .bfffs()
+        .args(["fs", "mount", "-o", "remount", "mypool"])
+        .assert()
+        .failure()
+        .stderr("Error: EINVAL\n");
+}
@@ -0,0 +1,101 @@
+use std::{
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use assert_cmd::prelude::*;
+use bfffs_core::{controller::Controller, device_manager::DevManager};
+use tempfile::{Builder, TempDir};
+
+use super::super::super::*;
+
+type Harness = (PathBuf, TempDir, Vec<u8>);
+
+/// Create a pool containing "dir/file", without using bfffsd
+async fn harness() -> Harness {
+    let len = 1 << 30; // 1 GB
+    let tempdir = Builder::new()
+        .prefix(concat!(module_path!(), "."))
+        .tempdir()
+        .unwrap();
+    let filename = tempdir.path().join("vdev");
+    let file = fs::File::create(&filename).unwrap();
+    file.set_len(len).unwrap();
+
+    bfffs()
+        .args(["pool", "create", "mypool"])
+        .arg(&filename)
+        .assert()
+        .success();
+
+    // Several records' worth, and not a multiple of the record size
+    let data = (0..300_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    populate(&filename, &data).await;
+
+    (filename, tempdir, data)
+}
+
+async fn populate(filename: &Path, data: &[u8]) {
+    let dev_manager = DevManager::default();
+    dev_manager.taste(filename).await.unwrap();
+    let db = dev_manager.import_by_name("mypool").await.unwrap();
+    let controller = Controller::new(db);
+    let fs = controller.new_fs("mypool").await.unwrap();
+    let root = fs.root();
+    let dir = fs
+        .mkdir(&root.handle(), OsStr::new("dir"), 0o755, 0, 0)
+        .await
+        .unwrap();
+    let fd = fs
+        .create(&dir.handle(), OsStr::new("file"), 0o644, 0, 0)
+        .await
+        .unwrap();
+    let r = fs.write(&fd.handle(), 0, data, 0).await;
+    assert_eq!(Ok(data.len() as u32), r);
+    fs.sync().await;
+}
+
+/// Restoring a directory is not supported
+#[tokio::test]
+async fn directory() {
+    let (filename, tempdir, _data) = harness().await;
+    let target = tempdir.path().join("restored");
+
+    bfffs()
+        .args(["fs", "restore", "mypool", "dir"])
+        .arg(&target)
+        .arg(&filename)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("EINVAL"));
+}
+
+#[tokio::test]
+async fn enoent() {
+    let (filename, tempdir, _data) = harness().await;
+    let target = tempdir.path().join("restored");
+
+    bfffs()
+        .args(["fs", "restore", "mypool", "dir/nonexistent"])
+        .arg(&target)
+        .arg(&filename)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains("ENOENT"));
+    assert!(!target.exists());
+}
+
+#[tokio::test]
+async fn ok() {
+    let (filename, tempdir, data) = harness().await;
+    let target = tempdir.path().join("restored");
+
+    bfffs()
+        .args(["fs", "restore", "mypool", "/dir/file"])
+        .arg(&target)
+        .arg(&filename)
+        .assert()
+        .success();
+    assert_eq!(fs::read(&target).unwrap(), data);
+}
@@ -80,6 +80,46 @@ async fn atime() {
         .stdout("off\tlocal\n");
 }
 
+#[rstest]
+#[tokio::test]
+async fn coalesce() {
+    let h = harness();
+    bfffs()
+        .arg("--sock")
+        .arg(h.sockpath.as_os_str())
+        .args(["fs", "set", "coalesce=65536", "mypool"])
+        .assert()
+        .success()
+        .stdout("");
+    bfffs()
+        .arg("--sock")
+        .arg(h.sockpath.as_os_str())
+        .args(["fs", "get", "-p", "-o", "value,source", "coalesce", "mypool"])
+        .assert()
+        .success()
+        .stdout("65536\tlocal\n");
+}
+
+#[rstest]
+#[tokio::test]
+async fn dirtylimit() {
+    let h = harness();
+    bfffs()
+        .arg("--sock")
+        .arg(h.sockpath.as_os_str())
+        .args(["fs", "set", "dirtylimit=1048576", "mypool"])
+        .assert()
+        .success()
+        .stdout("");
+    bfffs()
+        .arg("--sock")
+        .arg(h.sockpath.as_os_str())
+        .args(["fs", "get", "-p", "-o", "value,source", "dirtylimit", "mypool"])
+        .assert()
+        .success()
+        .stdout("1048576\tlocal\n");
+}
+
 #[rstest]
 #[tokio::test]
 async fn mountpoint() {
@@ -196,3 +236,33 @@ async fn recsize() {
         .success()
         .stdout("16384\tlocal\n");
 }
+
+/// User properties are inherited by children
+#[rstest]
+#[tokio::test]
+async fn user() {
+    let h = harness();
+    bfffs()
+        .arg("--sock")
+        .arg(h.sockpath.as_os_str())
+        .args(["fs", "create", "mypool/foo"])
+        .assert()
+        .success();
+    bfffs()
+        .arg("--sock")
+        .arg(h.sockpath.as_os_str())
+        .args(["fs", "set", "user:backup-policy=daily", "mypool"])
+        .assert()
+        .success()
+        .stdout("");
+    bfffs()
+        .arg("--sock")
+        .arg(h.sockpath.as_os_str())
+        .args(["fs", "get", "-r", "-p", "user:backup-policy", "mypool"])
+        .assert()
+        .success()
+        .stdout(
+            "mypool\tuser:backup-policy\tdaily\tlocal\n\
+             mypool/foo\tuser:backup-policy\tdaily\tinherited\n",
+        );
+}
@@ -1,4 +1,6 @@
 mod check;
+mod daemon;
 mod debug;
 mod fs;
 mod pool;
+mod volume;
@@ -75,6 +75,40 @@ async fn ok(harness: Harness) {
         .success();
 }
 
+/// A dry run should report what would be cleaned.  A fresh pool has no dirty
+/// zones.
+#[rstest]
+#[tokio::test]
+async fn dry_run(harness: Harness) {
+    bfffs()
+        .arg("--sock")
+        .arg(harness.sockpath.as_os_str())
+        .args(["pool", "clean", "-nv", "mypool"])
+        .assert()
+        .success()
+        .stdout(predicates::str::starts_with("would clean 0 zones"))
+        .stdout(predicates::str::contains(
+            "of the moved data is metadata, and will stay in metadata zones",
+        ));
+}
+
+/// A generational dry run should also report how much data would go to cold
+/// zones.
+#[rstest]
+#[tokio::test]
+async fn dry_run_generational(harness: Harness) {
+    bfffs()
+        .arg("--sock")
+        .arg(harness.sockpath.as_os_str())
+        .args(["pool", "clean", "-nv", "--cold-age", "10", "mypool"])
+        .assert()
+        .success()
+        .stdout(predicates::str::contains(
+            "of the moved data will go to cold zones",
+        ))
+        .stdout(predicates::str::contains("write amplification so far: "));
+}
+
 /// No such pool
 #[rstest]
 #[tokio::test]
@@ -87,3 +121,26 @@ async fn enoent(harness: Harness) {
         .failure()
         .stderr("Error: ENOENT\n");
 }
+
+/// With --wait, bfffs should wait for the cleaner to finish, and the finished
+/// job should afterwards be listed.
+#[rstest]
+#[tokio::test]
+async fn wait(harness: Harness) {
+    bfffs()
+        .arg("--sock")
+        .arg(harness.sockpath.as_os_str())
+        .args(["pool", "clean", "-w", "mypool"])
+        .assert()
+        .success()
+        .stderr(predicates::str::contains("100%"));
+
+    bfffs()
+        .arg("--sock")
+        .arg(harness.sockpath.as_os_str())
+        .args(["job", "list"])
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("clean"))
+        .stdout(predicates::str::contains("done"));
+}
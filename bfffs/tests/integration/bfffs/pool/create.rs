@@ -288,3 +288,42 @@ async fn zone_size(harness: Harness) {
     let (vdev, _) = VdevFile::open(filenames[0].clone()).await.unwrap();
     assert_eq!(2, vdev.zones());
 }
+
+/// A write-mostly mirror child should be reported as such by the pool
+#[rstest]
+#[tokio::test]
+async fn write_mostly(harness: Harness) {
+    let (filenames, _tempdir) = harness;
+
+    bfffs()
+        .args(["pool", "create", "--write-mostly"])
+        .arg(&filenames[1])
+        .args(["mypool", "mirror"])
+        .args([&filenames[0], &filenames[1]])
+        .assert()
+        .success();
+
+    let uuid = VdevFile::open(filenames[1].clone()).await.unwrap().0.uuid();
+    let controller = open("mypool", &filenames[0..2]).await;
+    let leaves = controller.leaf_status("mypool").unwrap();
+    assert_eq!(leaves.len(), 2);
+    for leaf in leaves {
+        assert_eq!(leaf.write_mostly, leaf.uuid == uuid);
+    }
+}
+
+/// Only mirror children may be write-mostly
+#[rstest]
+#[tokio::test]
+async fn write_mostly_not_mirror(harness: Harness) {
+    let (filenames, _tempdir) = harness;
+
+    bfffs()
+        .args(["pool", "create", "--write-mostly"])
+        .arg(&filenames[0])
+        .arg("mypool")
+        .arg(&filenames[0])
+        .assert()
+        .failure()
+        .code(2);
+}
@@ -1,2 +1,4 @@
 mod clean;
 mod create;
+mod online;
+mod txgs;
@@ -0,0 +1,35 @@
+use assert_cmd::prelude::*;
+use bfffs_test_harness::{Harness, PoolBuilder};
+use rstest::{fixture, rstest};
+
+/// Serve a single-disk pool
+#[fixture]
+fn harness() -> Harness {
+    PoolBuilder::new("mypool").start()
+}
+
+/// A disk that was never removed can't be brought online
+#[rstest]
+#[tokio::test]
+async fn ebusy(harness: Harness) {
+    harness
+        .bfffs()
+        .args(["pool", "online", "mypool"])
+        .arg(&harness.pool().vdevs()[0])
+        .assert()
+        .failure()
+        .stderr("Error: EBUSY\n");
+}
+
+/// No such pool
+#[rstest]
+#[tokio::test]
+async fn enoent(harness: Harness) {
+    harness
+        .bfffs()
+        .args(["pool", "online", "does_not_exist_pool"])
+        .arg(&harness.pool().vdevs()[0])
+        .assert()
+        .failure()
+        .stderr("Error: ENOENT\n");
+}
@@ -0,0 +1,35 @@
+use assert_cmd::prelude::*;
+use bfffs_test_harness::{Harness, PoolBuilder};
+use rstest::{fixture, rstest};
+
+/// Serve a single-disk pool
+#[fixture]
+fn harness() -> Harness {
+    PoolBuilder::new("mypool").start()
+}
+
+/// A freshly imported pool has synced its label, but has no checkpoint.
+#[rstest]
+#[tokio::test]
+async fn ok(harness: Harness) {
+    harness
+        .bfffs()
+        .args(["pool", "txgs", "mypool"])
+        .assert()
+        .success()
+        .stdout(predicates::str::starts_with("current:    "))
+        .stdout(predicates::str::is_match("synced: +[0-9]+\n").unwrap())
+        .stdout(predicates::str::contains("checkpoint: -\n"));
+}
+
+/// No such pool
+#[rstest]
+#[tokio::test]
+async fn enoent(harness: Harness) {
+    harness
+        .bfffs()
+        .args(["pool", "txgs", "does_not_exist_pool"])
+        .assert()
+        .failure()
+        .stderr("Error: ENOENT\n");
+}
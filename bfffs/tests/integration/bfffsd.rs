@@ -1,8 +1,277 @@
-use assert_cmd::prelude::*;
+use std::{
+    fs,
+    os::unix::fs::FileTypeExt,
+    path::PathBuf,
+    process::Command,
+    time::Duration,
+};
 
-use super::bfffsd;
+use assert_cmd::{cargo::cargo_bin, prelude::*};
+use bfffs_core::{rpc, Error};
+use rstest::{fixture, rstest};
+use tempfile::{Builder, TempDir};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_seqpacket::UnixSeqpacket;
+
+use super::*;
+
+struct Harness {
+    _bfffsd:      Bfffsd,
+    pub _tempdir: TempDir,
+    pub sockpath: PathBuf,
+}
+
+/// Create a single temporary file for backing store
+#[fixture]
+fn harness() -> Harness {
+    let len = 1 << 30; // 1 GB
+    let tempdir = Builder::new()
+        .prefix(concat!(module_path!(), "."))
+        .tempdir()
+        .unwrap();
+    let filename = tempdir.path().join("vdev");
+    let file = fs::File::create(&filename).unwrap();
+    file.set_len(len).unwrap();
+
+    bfffs()
+        .args(["pool", "create", "mypool"])
+        .arg(&filename)
+        .assert()
+        .success();
+
+    let sockpath = tempdir.path().join("bfffsd.sock");
+    let bfffsd: Bfffsd = Command::new(cargo_bin("bfffsd"))
+        .arg("--sock")
+        .arg(sockpath.as_os_str())
+        .arg("mypool")
+        .arg(filename.as_os_str())
+        .spawn()
+        .unwrap()
+        .into();
+
+    // We must wait for bfffsd to be ready to receive commands
+    waitfor(Duration::from_secs(5), || {
+        fs::metadata(&sockpath)
+            .map(|md| md.file_type().is_socket())
+            .unwrap_or(false)
+    })
+    .expect("Timeout waiting for bfffsd to listen");
+
+    Harness {
+        _bfffsd: bfffsd,
+        sockpath,
+        _tempdir: tempdir,
+    }
+}
+
+/// Send a raw packet to the server
+async fn send(peer: &UnixSeqpacket, packet: &[u8]) {
+    let nwrite = peer.send(packet).await.unwrap();
+    assert_eq!(nwrite, packet.len());
+}
+
+/// Receive and decode one response from the server
+async fn recv(peer: &UnixSeqpacket) -> (rpc::RequestId, rpc::Response) {
+    let mut buf = vec![0u8; 4096];
+    let nread = peer.recv(&mut buf).await.unwrap();
+    rpc::decode(&buf[..nread]).unwrap()
+}
+
+/// Encode a request with no authentication token
+fn encode(id: rpc::RequestId, req: rpc::Request) -> Vec<u8> {
+    rpc::encode(&(id, None::<rpc::AuthHash>, req)).unwrap()
+}
+
+fn status(id: rpc::RequestId) -> Vec<u8> {
+    encode(id, rpc::pool::status("mypool".into()))
+}
+
+/// Cancelling a request that isn't in progress should fail
+#[rstest]
+#[tokio::test]
+async fn cancel_enoent(harness: Harness) {
+    let peer = UnixSeqpacket::connect(&harness.sockpath).await.unwrap();
+    let req = encode(1, rpc::Request::Cancel(42));
+
+    send(&peer, &req).await;
+    let (id, resp) = recv(&peer).await;
+    assert_eq!(id, 1);
+    assert_eq!(resp.into_cancel(), Err(Error::ENOENT));
+}
+
+/// A request with a bad checksum should get an error response, and the server
+/// should keep handling requests on the same connection.
+#[rstest]
+#[tokio::test]
+async fn garbage(harness: Harness) {
+    let peer = UnixSeqpacket::connect(&harness.sockpath).await.unwrap();
+
+    send(&peer, &[0xff; 16]).await;
+    let (id, resp) = recv(&peer).await;
+    assert_eq!(id, u64::MAX);
+    assert!(matches!(resp, rpc::Response::Error(Error::EINTEGRITY)));
+
+    send(&peer, &status(1)).await;
+    let (id, resp) = recv(&peer).await;
+    assert_eq!(id, 1);
+    resp.into_pool_status().unwrap();
+}
+
+/// After one client sends garbage, others should still be served
+#[rstest]
+#[tokio::test]
+async fn garbage_other_client(harness: Harness) {
+    let peer = UnixSeqpacket::connect(&harness.sockpath).await.unwrap();
+    send(&peer, b"garbage").await;
+    let (_id, resp) = recv(&peer).await;
+    assert!(matches!(resp, rpc::Response::Error(Error::EINVAL)));
+    drop(peer);
+
+    bfffs()
+        .arg("--sock")
+        .arg(harness.sockpath.as_os_str())
+        .args(["pool", "clean", "mypool"])
+        .assert()
+        .success();
+}
+
+/// bfffsd should write its pidfile only once it's ready to serve requests
+#[tokio::test]
+async fn pidfile() {
+    let len = 1 << 30; // 1 GB
+    let tempdir = Builder::new()
+        .prefix(concat!(module_path!(), "."))
+        .tempdir()
+        .unwrap();
+    let filename = tempdir.path().join("vdev");
+    let file = fs::File::create(&filename).unwrap();
+    file.set_len(len).unwrap();
+    bfffs()
+        .args(["pool", "create", "mypool"])
+        .arg(&filename)
+        .assert()
+        .success();
+
+    let sockpath = tempdir.path().join("bfffsd.sock");
+    let pidfile = tempdir.path().join("bfffsd.pid");
+    let bfffsd: Bfffsd = Command::new(cargo_bin("bfffsd"))
+        .arg("--sock")
+        .arg(sockpath.as_os_str())
+        .arg("--pidfile")
+        .arg(pidfile.as_os_str())
+        .arg("mypool")
+        .arg(filename.as_os_str())
+        .spawn()
+        .unwrap()
+        .into();
+
+    waitfor(Duration::from_secs(5), || {
+        fs::read_to_string(&pidfile)
+            .map(|s| s.ends_with('\n'))
+            .unwrap_or(false)
+    })
+    .expect("Timeout waiting for bfffsd to become ready");
+    let contents = fs::read_to_string(&pidfile).unwrap();
+    assert_eq!(contents, format!("{}\n", bfffsd.id()));
+
+    // Once ready, bfffsd must immediately respond to requests
+    let peer = UnixSeqpacket::connect(&sockpath).await.unwrap();
+    send(&peer, &encode(1, rpc::daemon::ping())).await;
+    let (id, resp) = recv(&peer).await;
+    assert_eq!(id, 1);
+    resp.into_daemon_ping().unwrap();
+}
 
 #[test]
 fn help() {
     bfffsd().arg("-h").assert().success();
 }
+
+/// A client may send several requests before reading any responses
+#[rstest]
+#[tokio::test]
+async fn pipeline(harness: Harness) {
+    let peer = UnixSeqpacket::connect(&harness.sockpath).await.unwrap();
+    send(&peer, &status(1)).await;
+    send(&peer, &status(2)).await;
+
+    let mut ids = Vec::new();
+    for _ in 0..2 {
+        let (id, resp) = recv(&peer).await;
+        resp.into_pool_status().unwrap();
+        ids.push(id);
+    }
+    ids.sort_unstable();
+    assert_eq!(ids, vec![1, 2]);
+}
+
+/// Once share9p is set, bfffsd should serve the dataset over 9P
+#[rstest]
+#[tokio::test]
+async fn share9p(harness: Harness) {
+    const ADDR: &str = "127.0.0.1:56401";
+
+    bfffs()
+        .arg("--sock")
+        .arg(harness.sockpath.as_os_str())
+        .args(["fs", "set", &format!("share9p={ADDR}"), "mypool"])
+        .assert()
+        .success();
+    let mut stream = TcpStream::connect(ADDR).await.unwrap();
+
+    // Tversion, msize=8192
+    let mut tversion = vec![21, 0, 0, 0, 100, 0xff, 0xff, 0, 0x20, 0, 0, 8, 0];
+    tversion.extend_from_slice(b"9P2000.L");
+    stream.write_all(&tversion).await.unwrap();
+    let mut rversion = [0u8; 21];
+    stream.read_exact(&mut rversion).await.unwrap();
+    assert_eq!(rversion[4], 101);
+    assert_eq!(&rversion[13..], b"9P2000.L");
+
+    // Tattach fid=0, afid=NOFID, uname="", aname="mypool", n_uname=0
+    let mut tattach = vec![0, 0, 0, 0, 104, 1, 0];
+    tattach.extend_from_slice(&0u32.to_le_bytes());
+    tattach.extend_from_slice(&u32::MAX.to_le_bytes());
+    tattach.extend_from_slice(&0u16.to_le_bytes());
+    tattach.extend_from_slice(&6u16.to_le_bytes());
+    tattach.extend_from_slice(b"mypool");
+    tattach.extend_from_slice(&0u32.to_le_bytes());
+    let len = tattach.len() as u32;
+    tattach[0..4].copy_from_slice(&len.to_le_bytes());
+    stream.write_all(&tattach).await.unwrap();
+    let mut rattach = [0u8; 20];
+    stream.read_exact(&mut rattach).await.unwrap();
+    assert_eq!(rattach[4], 105);
+    // The root directory's qid
+    assert_eq!(rattach[7], 0x80);
+    assert_eq!(&rattach[12..], &1u64.to_le_bytes());
+}
+
+/// A request with a valid checksum that still can't be deserialized should
+/// get an error response.
+#[rstest]
+#[tokio::test]
+async fn malformed(harness: Harness) {
+    let peer = UnixSeqpacket::connect(&harness.sockpath).await.unwrap();
+
+    send(&peer, &rpc::encode(&[0xffu8; 16]).unwrap()).await;
+    let (id, resp) = recv(&peer).await;
+    assert_eq!(id, u64::MAX);
+    assert!(matches!(resp, rpc::Response::Error(Error::EINVAL)));
+}
+
+/// A request that was truncated in the middle should fail its checksum
+#[rstest]
+#[tokio::test]
+async fn truncated(harness: Harness) {
+    let peer = UnixSeqpacket::connect(&harness.sockpath).await.unwrap();
+    let req = status(1);
+
+    send(&peer, &req[..req.len() - 2]).await;
+    let (id, resp) = recv(&peer).await;
+    assert_eq!(id, 1);
+    assert!(matches!(resp, rpc::Response::Error(Error::EINTEGRITY)));
+}
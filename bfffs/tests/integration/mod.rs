@@ -1,5 +1,4 @@
 mod bfffs;
 mod bfffsd;
-mod util;
 
-use util::{bfffs, bfffsd, waitfor, Bfffsd};
+use bfffs_test_harness::{bfffs, bfffsd, require_fusefs, waitfor, Bfffsd};
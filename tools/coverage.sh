@@ -31,7 +31,7 @@ grcov . --binary-path $PWD/target/debug -s . -t html --branch \
 	--excl-stop LCOV_EXCL_STOP \
 	--ignore "*/tests/*" \
 	--ignore "*/src/*/tests.rs" \
-	--ignore bfffs/src/bin/bfffsd/fs/mock.rs \
+	--ignore bfffs-fuse/src/mock.rs \
 	--ignore bfffs-core/src/dataset/dataset_mock.rs \
 	--ignore bfffs-core/src/tree/tree_mock.rs \
 	--ignore "*/examples/*" \